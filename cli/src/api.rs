@@ -0,0 +1,337 @@
+//! Wire types and a blocking HTTP client for the replica API.
+
+use reqwest::blocking::{Client as HttpClient, RequestBuilder};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io::Write;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Header carrying the session token of the user making the request.
+pub const SESSION_TOKEN_HEADER: &str = "X-Session-Token";
+
+/// Identifies a node of a document.
+/// Written on the command line as `ssn:sum:sid:seq`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct S4Vector {
+    pub ssn: u64,
+    pub sum: u64,
+    pub sid: u64,
+    pub seq: u64,
+}
+
+impl Display for S4Vector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}:{}", self.ssn, self.sum, self.sid, self.seq)
+    }
+}
+
+impl FromStr for S4Vector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<u64> = match s.split(':').map(|p| p.parse::<u64>()).collect() {
+            Ok(parts) => parts,
+            Err(_) => return Err(format!("invalid s4vector '{}'", s)),
+        };
+
+        match parts[..] {
+            [ssn, sum, sid, seq] => Ok(S4Vector { ssn, sum, sid, seq }),
+            _ => Err(format!("expected ssn:sum:sid:seq, got '{}'", s)),
+        }
+    }
+}
+
+/// A visible node of a document.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContentNode {
+    pub s4vector: S4Vector,
+    pub value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DocumentContent {
+    nodes: Vec<ContentNode>,
+}
+
+#[derive(Debug, Serialize)]
+struct CreateDocumentRequest<'a> {
+    title: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateDocumentResponse {
+    document_id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+struct AppliedOperation {
+    s4vector: S4Vector,
+}
+
+#[derive(Debug, Serialize)]
+struct OperationRequest {
+    value: Option<String>,
+    s4vector: Option<S4Vector>,
+    tombstone: bool,
+    left: Option<S4Vector>,
+    right: Option<S4Vector>,
+}
+
+/// An entry in a document's audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub user_id: Option<Uuid>,
+    pub client_ip: Option<String>,
+    pub operation: String,
+    pub ssn: i64,
+    pub sum: i64,
+    pub sid: i64,
+    pub seq: i64,
+    pub timestamp: String,
+}
+
+/// The JSON body returned by the replica for failed requests.
+#[derive(Debug, Deserialize)]
+pub struct ErrorResponse {
+    pub code: String,
+    pub message: String,
+    pub details: Option<String>,
+    pub request_id: Option<String>,
+    #[serde(default)]
+    pub errors: Vec<FieldError>,
+}
+
+/// An invalid field reported by the replica.
+#[derive(Debug, Deserialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// Errors returned by the client.
+/// `Http`: The replica could not be reached or returned an unreadable response.
+/// `Api`: The replica rejected the request.
+/// `Io`: A local file could not be read or written.
+#[derive(Debug)]
+pub enum CliError {
+    Http(String),
+    Api(u16, Box<ErrorResponse>),
+    Io(String),
+}
+
+impl Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Http(e) => write!(f, "Request failed: {}", e),
+            CliError::Io(e) => write!(f, "{}", e),
+            CliError::Api(status, e) => {
+                write!(f, "{} ({}): {}", e.code, status, e.message)?;
+                for error in &e.errors {
+                    write!(f, "\n  {}: {}", error.field, error.message)?;
+                }
+                if let Some(details) = &e.details {
+                    write!(f, "\n  help: {}", details)?;
+                }
+                if let Some(request_id) = &e.request_id {
+                    write!(f, "\n  request id: {}", request_id)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+/// A client for a single replica (or the load balancer in front of the replicas).
+pub struct Client {
+    base_url: String,
+    session_token: Option<String>,
+    http: HttpClient,
+}
+
+impl Client {
+    pub fn new(base_url: &str, session_token: Option<String>) -> Self {
+        Client {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            session_token,
+            http: HttpClient::new(),
+        }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        let builder = self
+            .http
+            .request(method, format!("{}{}", self.base_url, path));
+        match &self.session_token {
+            Some(token) => builder.header(SESSION_TOKEN_HEADER, token),
+            None => builder,
+        }
+    }
+
+    /// Sends the request, turning error responses into `CliError::Api`.
+    fn send(&self, builder: RequestBuilder) -> Result<reqwest::blocking::Response, CliError> {
+        let response = match builder.send() {
+            Ok(r) => r,
+            Err(e) => return Err(CliError::Http(e.to_string())),
+        };
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        match response.json::<ErrorResponse>() {
+            Ok(e) => Err(CliError::Api(status.as_u16(), Box::new(e))),
+            Err(_) => Err(CliError::Http(format!("replica returned {}", status))),
+        }
+    }
+
+    fn send_json<T: DeserializeOwned>(&self, builder: RequestBuilder) -> Result<T, CliError> {
+        match self.send(builder)?.json::<T>() {
+            Ok(body) => Ok(body),
+            Err(e) => Err(CliError::Http(e.to_string())),
+        }
+    }
+
+    /// Creates a document owned by the logged in user and returns its id.
+    pub fn create_document(&self, title: &str) -> Result<Uuid, CliError> {
+        let request = self
+            .request(reqwest::Method::POST, "/create_document")
+            .json(&CreateDocumentRequest { title });
+        let response: CreateDocumentResponse = self.send_json(request)?;
+        Ok(response.document_id)
+    }
+
+    /// Loads the document on the replica, which is required before reading or editing it.
+    pub fn load(&self, document_id: Uuid) -> Result<(), CliError> {
+        self.send(self.request(reqwest::Method::GET, &format!("/document/{}", document_id)))?;
+        Ok(())
+    }
+
+    /// Loads the document and returns its visible nodes in document order.
+    pub fn content(&self, document_id: Uuid) -> Result<Vec<ContentNode>, CliError> {
+        self.load(document_id)?;
+        let content: DocumentContent = self.send_json(self.request(
+            reqwest::Method::GET,
+            &format!("/document/{}/content", document_id),
+        ))?;
+        Ok(content.nodes)
+    }
+
+    /// Loads the document and copies its text to `output` as the replica streams it.
+    /// Returns the number of bytes written.
+    pub fn export(&self, document_id: Uuid, output: &mut impl Write) -> Result<u64, CliError> {
+        self.load(document_id)?;
+        let mut response = self.send(self.request(
+            reqwest::Method::GET,
+            &format!("/document/{}/content?format=text", document_id),
+        ))?;
+        match response.copy_to(output) {
+            Ok(written) => Ok(written),
+            Err(e) => Err(CliError::Http(e.to_string())),
+        }
+    }
+
+    /// Inserts `value` between `left` and `right` and returns the s4vector of the new node.
+    pub fn insert(
+        &self,
+        document_id: Uuid,
+        value: &str,
+        left: Option<S4Vector>,
+        right: Option<S4Vector>,
+    ) -> Result<S4Vector, CliError> {
+        let applied: AppliedOperation = self.send_json(
+            self.request(
+                reqwest::Method::POST,
+                &format!("/document/{}/insert", document_id),
+            )
+            .json(&OperationRequest {
+                value: Some(value.to_string()),
+                s4vector: None,
+                tombstone: false,
+                left,
+                right,
+            }),
+        )?;
+        Ok(applied.s4vector)
+    }
+
+    /// Replaces the value of a node.
+    pub fn update(
+        &self,
+        document_id: Uuid,
+        s4vector: S4Vector,
+        value: &str,
+    ) -> Result<(), CliError> {
+        self.send(
+            self.request(
+                reqwest::Method::POST,
+                &format!("/document/{}/update", document_id),
+            )
+            .json(&OperationRequest {
+                value: Some(value.to_string()),
+                s4vector: Some(s4vector),
+                tombstone: false,
+                left: None,
+                right: None,
+            }),
+        )?;
+        Ok(())
+    }
+
+    /// Deletes a node.
+    pub fn delete(&self, document_id: Uuid, s4vector: S4Vector) -> Result<(), CliError> {
+        self.send(
+            self.request(
+                reqwest::Method::POST,
+                &format!("/document/{}/delete", document_id),
+            )
+            .json(&OperationRequest {
+                value: None,
+                s4vector: Some(s4vector),
+                tombstone: true,
+                left: None,
+                right: None,
+            }),
+        )?;
+        Ok(())
+    }
+
+    /// Returns the document's audit log. Only the document owner can read it.
+    pub fn audit(&self, document_id: Uuid) -> Result<Vec<AuditEntry>, CliError> {
+        self.send_json(self.request(
+            reqwest::Method::GET,
+            &format!("/document/{}/audit", document_id),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_s4vector() {
+        let s4: S4Vector = "1:4:2:3".parse().unwrap();
+        assert_eq!(
+            s4,
+            S4Vector {
+                ssn: 1,
+                sum: 4,
+                sid: 2,
+                seq: 3
+            }
+        );
+        assert_eq!(s4.to_string(), "1:4:2:3");
+
+        assert!("1:4:2".parse::<S4Vector>().is_err());
+        assert!("1:a:2:3".parse::<S4Vector>().is_err());
+    }
+}
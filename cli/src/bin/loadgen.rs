@@ -0,0 +1,277 @@
+//! `loadgen` simulates concurrent editors typing into documents through the load balancer,
+//! exercising the load balancer, rate limiter, replicas and broadcasts together.
+//!
+//! Each editor loads its document and appends one node per keystroke after the last node
+//! it typed. At the end it reports throughput, latency percentiles and error rates, and,
+//! when `--replica` URLs are given, checks that every replica has the same content.
+
+use clap::Parser;
+use nimble_cli::api::{CliError, Client, S4Vector};
+use nimble_cli::diff;
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "loadgen",
+    about = "Simulates concurrent editors against the nimble API"
+)]
+struct Args {
+    /// Load balancer (or replica) URL the editors send requests to.
+    #[arg(long, env = "NIMBLE_URL", default_value = "http://127.0.0.1:3000")]
+    url: String,
+
+    /// Number of concurrent editors.
+    #[arg(long, default_value_t = 10)]
+    editors: usize,
+
+    /// Number of documents, editors are spread evenly across them.
+    #[arg(long, default_value_t = 2)]
+    documents: usize,
+
+    /// Keystrokes typed by each editor.
+    #[arg(long, default_value_t = 100)]
+    keystrokes: usize,
+
+    /// Pause between keystrokes in milliseconds.
+    #[arg(long, default_value_t = 50)]
+    think_time_ms: u64,
+
+    /// Session token of the user the documents are created and edited as.
+    #[arg(long, env = "NIMBLE_SESSION_TOKEN")]
+    token: Option<String>,
+
+    /// Replica URLs to compare for divergence once the run finishes (repeatable).
+    #[arg(long = "replica")]
+    replicas: Vec<String>,
+
+    /// Seconds to wait for broadcasts to settle before checking for divergence.
+    #[arg(long, default_value_t = 5)]
+    settle_secs: u64,
+}
+
+/// The outcome of a request.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Outcome {
+    Ok,
+    Status(u16),
+    Transport,
+}
+
+impl Outcome {
+    fn of<T>(result: &Result<T, CliError>) -> Outcome {
+        match result {
+            Ok(_) => Outcome::Ok,
+            Err(CliError::Api(status, _)) => Outcome::Status(*status),
+            Err(_) => Outcome::Transport,
+        }
+    }
+}
+
+/// Latencies and outcomes recorded by every editor.
+#[derive(Default)]
+struct Results {
+    latencies: Vec<Duration>,
+    outcomes: BTreeMap<Outcome, usize>,
+}
+
+impl Results {
+    fn record<T>(&mut self, latency: Duration, result: &Result<T, CliError>) {
+        self.latencies.push(latency);
+        *self.outcomes.entry(Outcome::of(result)).or_insert(0) += 1;
+    }
+}
+
+/// The latency below which `percentile` percent of the requests completed.
+fn percentile(sorted: &[Duration], percentile: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((percentile / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Types `keystrokes` characters into the document, one insert per keystroke.
+fn edit(args: &Args, editor: usize, document_id: Uuid, results: &Mutex<Results>) {
+    let client = Client::new(&args.url, args.token.clone());
+    let mut last: Option<S4Vector> = match client.content(document_id) {
+        Ok(nodes) => nodes.last().map(|node| node.s4vector),
+        Err(e) => {
+            eprintln!("editor {} failed to load {}: {}", editor, document_id, e);
+            None
+        }
+    };
+
+    for keystroke in 0..args.keystrokes {
+        let value = ((b'a' + (keystroke % 26) as u8) as char).to_string();
+
+        let start = Instant::now();
+        let result = client.insert(document_id, &value, last, None);
+        let latency = start.elapsed();
+
+        if let Ok(s4vector) = &result {
+            last = Some(*s4vector);
+        }
+        results.lock().unwrap().record(latency, &result);
+
+        std::thread::sleep(Duration::from_millis(args.think_time_ms));
+    }
+}
+
+/// Compares each document on every replica with the first one and returns the number
+/// of documents that diverged.
+fn check_divergence(args: &Args, documents: &[Uuid]) -> usize {
+    let clients: Vec<Client> = args
+        .replicas
+        .iter()
+        .map(|url| Client::new(url, args.token.clone()))
+        .collect();
+    let mut diverged: usize = 0;
+
+    for document_id in documents {
+        let reference = match clients[0].content(*document_id) {
+            Ok(nodes) => nodes,
+            Err(e) => {
+                println!(
+                    "  {}: failed to read from {}: {}",
+                    document_id,
+                    clients[0].base_url(),
+                    e
+                );
+                diverged += 1;
+                continue;
+            }
+        };
+
+        for client in &clients[1..] {
+            match client.content(*document_id) {
+                Ok(nodes) => {
+                    let differences = diff::diff(&reference, &nodes);
+                    if !differences.is_empty() {
+                        println!(
+                            "  {}: {} differs from {} ({} differences)",
+                            document_id,
+                            client.base_url(),
+                            clients[0].base_url(),
+                            differences.len()
+                        );
+                        diverged += 1;
+                    }
+                }
+                Err(e) => {
+                    println!(
+                        "  {}: failed to read from {}: {}",
+                        document_id,
+                        client.base_url(),
+                        e
+                    );
+                    diverged += 1;
+                }
+            }
+        }
+    }
+
+    diverged
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    if args.editors == 0 || args.documents == 0 {
+        eprintln!("--editors and --documents must be greater than 0");
+        return ExitCode::FAILURE;
+    }
+
+    let client = Client::new(&args.url, args.token.clone());
+    let mut documents: Vec<Uuid> = Vec::with_capacity(args.documents);
+    for i in 0..args.documents {
+        match client.create_document(&format!("loadgen {}", i)) {
+            Ok(id) => documents.push(id),
+            Err(e) => {
+                eprintln!("Failed to create a document: {}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    println!(
+        "{} editors typing {} keystrokes into {} documents through {}",
+        args.editors, args.keystrokes, args.documents, args.url
+    );
+
+    let results = Mutex::new(Results::default());
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for editor in 0..args.editors {
+            let document_id = documents[editor % documents.len()];
+            let (args, results) = (&args, &results);
+            scope.spawn(move || edit(args, editor, document_id, results));
+        }
+    });
+    let elapsed = start.elapsed();
+
+    let mut results = results.into_inner().unwrap();
+    results.latencies.sort();
+    let total = results.latencies.len();
+    let ok = results.outcomes.get(&Outcome::Ok).copied().unwrap_or(0);
+
+    println!(
+        "\nrequests:   {} in {:.1}s ({:.1}/s)",
+        total,
+        elapsed.as_secs_f64(),
+        total as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "latency:    p50 {:?}  p90 {:?}  p99 {:?}  max {:?}",
+        percentile(&results.latencies, 50.0),
+        percentile(&results.latencies, 90.0),
+        percentile(&results.latencies, 99.0),
+        results.latencies.last().copied().unwrap_or_default(),
+    );
+    println!(
+        "errors:     {:.2}%",
+        100.0 * (total - ok) as f64 / total.max(1) as f64
+    );
+    for (outcome, count) in &results.outcomes {
+        match outcome {
+            Outcome::Ok => println!("  ok         {}", count),
+            Outcome::Status(status) => println!("  {}        {}", status, count),
+            Outcome::Transport => println!("  transport  {}", count),
+        }
+    }
+
+    if args.replicas.len() < 2 {
+        println!("\ndivergence: skipped, pass --replica at least twice to compare replicas");
+        return ExitCode::SUCCESS;
+    }
+
+    std::thread::sleep(Duration::from_secs(args.settle_secs));
+    println!("\ndivergence:");
+    let diverged = check_divergence(&args, &documents);
+    if diverged > 0 {
+        println!("  {} of {} documents diverged", diverged, documents.len());
+        return ExitCode::FAILURE;
+    }
+    println!(
+        "  all {} documents converged on {} replicas",
+        documents.len(),
+        args.replicas.len()
+    );
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&latencies, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&latencies, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+}
@@ -0,0 +1,92 @@
+//! Compares two replicas' views of a document.
+
+use crate::api::{ContentNode, S4Vector};
+use std::collections::HashMap;
+
+/// A difference between two views of a document.
+/// `Missing`: The node is only visible on the first replica.
+/// `Extra`: The node is only visible on the second replica.
+/// `Changed`: The node is visible on both replicas with different values.
+/// `Reordered`: Both replicas have the same nodes in a different order.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Difference {
+    Missing(S4Vector, String),
+    Extra(S4Vector, String),
+    Changed(S4Vector, String, String),
+    Reordered,
+}
+
+/// Lists the differences between two views, an empty list means the replicas have converged.
+pub fn diff(left: &[ContentNode], right: &[ContentNode]) -> Vec<Difference> {
+    let mut differences: Vec<Difference> = Vec::new();
+    let right_values: HashMap<S4Vector, &str> = right
+        .iter()
+        .map(|node| (node.s4vector, node.value.as_str()))
+        .collect();
+    let left_values: HashMap<S4Vector, &str> = left
+        .iter()
+        .map(|node| (node.s4vector, node.value.as_str()))
+        .collect();
+
+    for node in left {
+        match right_values.get(&node.s4vector) {
+            None => differences.push(Difference::Missing(node.s4vector, node.value.clone())),
+            Some(value) if *value != node.value => differences.push(Difference::Changed(
+                node.s4vector,
+                node.value.clone(),
+                value.to_string(),
+            )),
+            Some(_) => (),
+        }
+    }
+    for node in right {
+        if !left_values.contains_key(&node.s4vector) {
+            differences.push(Difference::Extra(node.s4vector, node.value.clone()));
+        }
+    }
+
+    if differences.is_empty() {
+        let order = |nodes: &[ContentNode]| nodes.iter().map(|n| n.s4vector).collect::<Vec<_>>();
+        if order(left) != order(right) {
+            differences.push(Difference::Reordered);
+        }
+    }
+
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(seq: u64, value: &str) -> ContentNode {
+        ContentNode {
+            s4vector: S4Vector {
+                ssn: 1,
+                sum: seq,
+                sid: 1,
+                seq,
+            },
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let left = vec![node(1, "a"), node(2, "b"), node(3, "c")];
+        assert!(diff(&left, &left).is_empty());
+
+        let right = vec![node(1, "a"), node(2, "B"), node(4, "d")];
+        assert_eq!(
+            diff(&left, &right),
+            vec![
+                Difference::Changed(left[1].s4vector, "b".to_string(), "B".to_string()),
+                Difference::Missing(left[2].s4vector, "c".to_string()),
+                Difference::Extra(right[2].s4vector, "d".to_string()),
+            ]
+        );
+
+        let reordered = vec![node(2, "b"), node(1, "a"), node(3, "c")];
+        assert_eq!(diff(&left, &reordered), vec![Difference::Reordered]);
+    }
+}
@@ -0,0 +1,4 @@
+//! Client library shared by the `nimble-cli` and `loadgen` binaries.
+
+pub mod api;
+pub mod diff;
@@ -0,0 +1,225 @@
+//! `nimble-cli` is a command line client for the replica API, for scripting, smoke tests
+//! and demos. Documents are imported with one node per line, so exporting concatenates
+//! the node values.
+
+use clap::{Parser, Subcommand};
+use nimble_cli::api::{CliError, Client, S4Vector};
+use nimble_cli::diff::{self, Difference};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "nimble-cli",
+    version,
+    about = "Command line client for the nimble replica API"
+)]
+struct Cli {
+    /// Replica (or load balancer) URL.
+    #[arg(
+        long,
+        env = "NIMBLE_URL",
+        default_value = "http://127.0.0.1:8000",
+        global = true
+    )]
+    url: String,
+
+    /// Session token from `POST /users/login`, sent in the X-Session-Token header.
+    #[arg(long, env = "NIMBLE_SESSION_TOKEN", global = true)]
+    token: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Creates an empty document owned by the logged in user and prints its id.
+    Create {
+        #[arg(long, default_value = "")]
+        title: String,
+    },
+    /// Creates a document from a file (one node per line) and prints its id.
+    Import {
+        file: PathBuf,
+        /// Defaults to the file name.
+        #[arg(long)]
+        title: Option<String>,
+    },
+    /// Writes the document's content to a file, or stdout.
+    Export {
+        document: Uuid,
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Prints each visible node with its s4vector.
+    Show { document: Uuid },
+    /// Inserts a node after `--after` (or at the start) and prints its s4vector.
+    Insert {
+        document: Uuid,
+        value: String,
+        /// s4vector (ssn:sum:sid:seq) of the node to insert after.
+        #[arg(long)]
+        after: Option<S4Vector>,
+        /// s4vector (ssn:sum:sid:seq) of the node to insert before.
+        #[arg(long)]
+        before: Option<S4Vector>,
+    },
+    /// Replaces the value of a node.
+    Update {
+        document: Uuid,
+        s4vector: S4Vector,
+        value: String,
+    },
+    /// Deletes a node.
+    Delete { document: Uuid, s4vector: S4Vector },
+    /// Follows the document's audit log, printing operations as they are applied (owner only).
+    Tail {
+        document: Uuid,
+        /// Seconds between polls.
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+    },
+    /// Compares the document on `--url` with another replica and exits non-zero if they differ.
+    Diff {
+        document: Uuid,
+        /// URL of the replica to compare against.
+        #[arg(long)]
+        against: String,
+    },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let client = Client::new(&cli.url, cli.token.clone());
+
+    match run(&client, cli.token, cli.command) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(client: &Client, token: Option<String>, command: Command) -> Result<ExitCode, CliError> {
+    match command {
+        Command::Create { title } => {
+            println!("{}", client.create_document(&title)?);
+        }
+        Command::Import { file, title } => {
+            let text = match std::fs::read_to_string(&file) {
+                Ok(t) => t,
+                Err(e) => return Err(CliError::Io(format!("{}: {}", file.display(), e))),
+            };
+            let title = title.unwrap_or_else(|| {
+                file.file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            });
+
+            let document_id = client.create_document(&title)?;
+            client.load(document_id)?;
+
+            let mut left: Option<S4Vector> = None;
+            for line in text.split_inclusive('\n') {
+                left = Some(client.insert(document_id, line, left, None)?);
+            }
+            println!("{}", document_id);
+        }
+        Command::Export { document, output } => match output {
+            Some(path) => {
+                let mut file = match std::fs::File::create(&path) {
+                    Ok(file) => file,
+                    Err(e) => return Err(CliError::Io(format!("{}: {}", path.display(), e))),
+                };
+                client.export(document, &mut file)?;
+            }
+            None => {
+                client.export(document, &mut std::io::stdout().lock())?;
+            }
+        },
+        Command::Show { document } => {
+            for node in client.content(document)? {
+                println!("{}\t{:?}", node.s4vector, node.value);
+            }
+        }
+        Command::Insert {
+            document,
+            value,
+            after,
+            before,
+        } => {
+            client.load(document)?;
+            println!("{}", client.insert(document, &value, after, before)?);
+        }
+        Command::Update {
+            document,
+            s4vector,
+            value,
+        } => {
+            client.load(document)?;
+            client.update(document, s4vector, &value)?;
+        }
+        Command::Delete { document, s4vector } => {
+            client.load(document)?;
+            client.delete(document, s4vector)?;
+        }
+        Command::Tail { document, interval } => {
+            if token.is_none() {
+                eprintln!("warning: the audit log is only available to the owner, set --token");
+            }
+            let mut seen: usize = 0;
+            loop {
+                let entries = client.audit(document)?;
+                for entry in entries.iter().skip(seen) {
+                    println!(
+                        "{}\t{}\t{}:{}:{}:{}\t{}",
+                        entry.timestamp,
+                        entry.operation,
+                        entry.ssn,
+                        entry.sum,
+                        entry.sid,
+                        entry.seq,
+                        entry
+                            .user_id
+                            .map(|id| id.to_string())
+                            .unwrap_or("-".to_string()),
+                    );
+                }
+                seen = seen.max(entries.len());
+                std::thread::sleep(Duration::from_secs(interval));
+            }
+        }
+        Command::Diff { document, against } => {
+            let other = Client::new(&against, token);
+            let differences = diff::diff(&client.content(document)?, &other.content(document)?);
+
+            if differences.is_empty() {
+                println!(
+                    "{} and {} have converged",
+                    client.base_url(),
+                    other.base_url()
+                );
+                return Ok(ExitCode::SUCCESS);
+            }
+            for difference in &differences {
+                match difference {
+                    Difference::Missing(s4, value) => {
+                        println!("- {}\t{:?}", s4, value)
+                    }
+                    Difference::Extra(s4, value) => println!("+ {}\t{:?}", s4, value),
+                    Difference::Changed(s4, left, right) => {
+                        println!("~ {}\t{:?} -> {:?}", s4, left, right)
+                    }
+                    Difference::Reordered => println!("nodes are in a different order"),
+                }
+            }
+            return Ok(ExitCode::FAILURE);
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
@@ -6,11 +6,12 @@ fn main() -> Result<(), Box<dyn Error>> {
     let mut config = Config::new();
     config.out_dir("src/proto");
 
-    let file_path = "proto/rate_limiter.proto";
+    // the service definition is owned by the rate limiter
+    let file_path = "../rate_limiter/proto/rate_limiter.proto";
 
     tonic_build::configure()
         .out_dir("src/proto")
-        .compile_protos(&[file_path], &["proto"])
+        .compile_protos(&[file_path], &["../rate_limiter/proto"])
         .expect("Failed to complie");
     Ok(())
 }
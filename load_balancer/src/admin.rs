@@ -0,0 +1,378 @@
+use crate::ip_filter::Cidr;
+use crate::limits;
+use crate::load_balancer::consistent_hashing::{LoadBalancer, Node};
+use crate::reload;
+use crate::traffic_split::TrafficSplit;
+use std::collections::BTreeMap;
+use std::env;
+use tracing::info;
+
+/// Path prefix for the load balancer admin API.
+/// Requests under this prefix are handled by the load balancer and never proxied.
+pub const ADMIN_PREFIX: &str = "/lb/admin";
+
+/// Returns true if the request path targets the admin API.
+pub fn is_admin_request(path: &str) -> bool {
+    path == ADMIN_PREFIX || path.starts_with(&format!("{}/", ADMIN_PREFIX))
+}
+
+/// Handles an admin API request.
+/// The admin API is disabled unless `LB_ADMIN_TOKEN` is set, and every request must carry
+/// the token in an `Authorization: Bearer <token>` header.
+///
+/// Routes
+/// `GET /lb/admin/ip_filter`: Lists the allow and deny lists.
+/// `POST /lb/admin/ip_filter/allow`: Adds the CIDR blocks in the body (one per line) to the allowlist.
+/// `POST /lb/admin/ip_filter/deny`: Adds the CIDR blocks in the body (one per line) to the blocklist.
+/// `GET /lb/admin/nodes`: Lists the replicas with their regions and whether requests prefer them.
+/// `POST /lb/admin/nodes/dry_run`: Reports the keys that would change owner if the nodes were
+/// replaced by the `NODE<n>` variables in the body (in the format of the .env file).
+/// `GET /lb/admin/rebalance`: Reports the keys that changed owner the last time the nodes changed.
+/// `GET /lb/admin/traffic_split`: Shows the share of clients routed to another pool.
+/// `POST /lb/admin/traffic_split`: Routes the share of clients in the body (`<pool>:<percent>`)
+/// to the pool.
+/// `DELETE /lb/admin/traffic_split`: Routes every client over every node again.
+/// `GET /lb/admin/response_cache`: Shows the size of the response cache and its hits and misses.
+/// `DELETE /lb/admin/response_cache`: Evicts every cached response.
+/// `GET /lb/admin/routes`: Lists the path prefixes routed to pools or rejected.
+/// `GET /lb/admin/connections`: Counts the connections closed for sending their headers or body
+/// too slowly.
+/// `GET /lb/admin/canary`: Lists the canary rules with the requests of each cohort sent to the
+/// canary nodes and left on the ring.
+pub fn handle_admin(request: &http::Request<Vec<u8>>, state: &mut LoadBalancer) -> Vec<u8> {
+    let token = match env::var("LB_ADMIN_TOKEN") {
+        Ok(token) if !token.is_empty() => token,
+        _ => return json_response(404, "Not Found", "{\"error\":\"admin API disabled\"}"),
+    };
+
+    if !authorized(request, &token) {
+        return json_response(401, "Unauthorized", "{\"error\":\"invalid admin token\"}");
+    }
+
+    let route = &request.uri().path()[ADMIN_PREFIX.len()..];
+
+    match (request.method().as_str(), route) {
+        ("GET", "/ip_filter") => json_response(200, "OK", &ip_filter_json(state)),
+        ("GET", "/nodes") => json_response(200, "OK", &nodes_json(state)),
+        ("POST", "/nodes/dry_run") => match parse_nodes(request.body()) {
+            Ok(nodes) => json_response(200, "OK", &state.dry_run(&nodes).to_json()),
+            Err(e) => json_response(400, "Bad Request", &format!("{{\"error\":\"{}\"}}", e)),
+        },
+        ("GET", "/traffic_split") => json_response(200, "OK", &split_json(state)),
+        ("POST", "/traffic_split") => {
+            let split = match std::str::from_utf8(request.body()) {
+                Ok(body) => body.parse::<TrafficSplit>(),
+                Err(_) => Err("body must be UTF-8".to_string()),
+            };
+            match split {
+                Ok(split) => {
+                    info!(
+                        "Shifting {}% of clients to pool {} through the admin API",
+                        split.percent, split.pool
+                    );
+                    state.set_split(Some(split));
+                    json_response(200, "OK", &split_json(state))
+                }
+                Err(e) => json_response(400, "Bad Request", &format!("{{\"error\":\"{}\"}}", e)),
+            }
+        }
+        ("DELETE", "/traffic_split") => {
+            info!("Traffic split cleared through the admin API");
+            state.set_split(None);
+            json_response(200, "OK", &split_json(state))
+        }
+        ("GET", "/response_cache") => json_response(200, "OK", &response_cache_json(state)),
+        ("DELETE", "/response_cache") => {
+            info!("Response cache cleared through the admin API");
+            state.response_cache.clear();
+            json_response(200, "OK", &response_cache_json(state))
+        }
+        ("GET", "/routes") => json_response(200, "OK", &routes_json(state)),
+        ("GET", "/canary") => json_response(200, "OK", &canary_json(state)),
+        ("GET", "/connections") => json_response(200, "OK", &connections_json()),
+        ("GET", "/rebalance") => {
+            let body = match &state.last_rebalance {
+                Some(rebalance) => rebalance.to_json(),
+                None => "null".to_string(),
+            };
+            json_response(200, "OK", &body)
+        }
+        ("POST", "/ip_filter/allow") | ("POST", "/ip_filter/deny") => {
+            let cidrs = match parse_cidrs(request.body()) {
+                Ok(c) => c,
+                Err(e) => {
+                    return json_response(400, "Bad Request", &format!("{{\"error\":\"{}\"}}", e))
+                }
+            };
+
+            for cidr in cidrs {
+                if route.ends_with("allow") {
+                    state.ip_filter.add_allow(cidr);
+                } else {
+                    state.ip_filter.add_deny(cidr);
+                }
+            }
+
+            info!("IP filter updated through the admin API");
+            json_response(200, "OK", &ip_filter_json(state))
+        }
+        _ => json_response(404, "Not Found", "{\"error\":\"unknown admin route\"}"),
+    }
+}
+
+/// Builds a HTTP/1.1 response with a JSON body.
+pub fn json_response(code: u16, reason: &str, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        code,
+        reason,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+fn ip_filter_json(state: &LoadBalancer) -> String {
+    let list = |cidrs: &Vec<Cidr>| {
+        cidrs
+            .iter()
+            .map(|cidr| format!("\"{}\"", cidr))
+            .collect::<Vec<String>>()
+            .join(",")
+    };
+
+    format!(
+        "{{\"allow\":[{}],\"deny\":[{}]}}",
+        list(&state.ip_filter.allow),
+        list(&state.ip_filter.deny)
+    )
+}
+
+fn nodes_json(state: &LoadBalancer) -> String {
+    let string = |value: &Option<String>| match value {
+        Some(value) => format!("\"{}\"", value),
+        None => "null".to_string(),
+    };
+    // nodes are preferred within the ring of their pool
+    let preferred = |node: &Node| {
+        let canary_ring = node
+            .pool
+            .as_ref()
+            .filter(|pool| state.canary.pools().contains(pool))
+            .and_then(|pool| state.pool_rings.get(pool));
+        let local_ring = match (&state.split, canary_ring) {
+            (_, Some((_, canary_local_ring))) => canary_local_ring,
+            (Some(split), _) if node.pool.as_ref() == Some(&split.pool) => &state.split_local_ring,
+            _ => &state.local_ring,
+        };
+        local_ring.is_empty() || local_ring.values().any(|local| *local == node.address)
+    };
+
+    let nodes = state
+        .nodes
+        .iter()
+        .map(|node| {
+            format!(
+                "{{\"address\":\"{}\",\"region\":{},\"weight\":{},\"pool\":{},\"preferred\":{}}}",
+                node.address,
+                string(&node.region),
+                node.weight,
+                string(&node.pool),
+                preferred(node)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+
+    format!(
+        "{{\"region\":{},\"nodes\":[{}]}}",
+        string(&state.region),
+        nodes
+    )
+}
+
+fn response_cache_json(state: &LoadBalancer) -> String {
+    let cache = &state.response_cache;
+    format!(
+        "{{\"enabled\":{},\"entries\":{},\"bytes\":{},\"hits\":{},\"misses\":{}}}",
+        cache.is_enabled(),
+        cache.len(),
+        cache.bytes(),
+        cache.hits,
+        cache.misses
+    )
+}
+
+fn connections_json() -> String {
+    let slow = limits::slow_clients();
+    format!(
+        "{{\"slow_clients\":{{\"headers\":{},\"body\":{}}}}}",
+        slow.headers, slow.body
+    )
+}
+
+fn routes_json(state: &LoadBalancer) -> String {
+    let routes = state
+        .routes
+        .rules
+        .iter()
+        .map(|rule| {
+            format!(
+                "{{\"prefix\":\"{}\",\"action\":\"{}\"}}",
+                rule.prefix, rule.action
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+
+    format!("{{\"routes\":[{}]}}", routes)
+}
+
+fn canary_json(state: &LoadBalancer) -> String {
+    let rules = state
+        .canary
+        .rules
+        .iter()
+        .map(|rule| {
+            format!(
+                "{{\"name\":\"{}\",\"header\":\"{}\",\"value\":{},\"percent\":{},\"pool\":\"{}\",\"nodes\":{},\"canary_requests\":{},\"control_requests\":{}}}",
+                rule.name,
+                rule.header,
+                match &rule.value {
+                    Some(value) => format!("\"{}\"", value),
+                    None => "null".to_string(),
+                },
+                rule.percent,
+                rule.pool,
+                state
+                    .nodes
+                    .iter()
+                    .filter(|node| node.pool.as_ref() == Some(&rule.pool))
+                    .count(),
+                rule.canary_requests,
+                rule.control_requests
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+
+    format!("{{\"rules\":[{}]}}", rules)
+}
+
+fn split_json(state: &LoadBalancer) -> String {
+    match &state.split {
+        Some(split) => format!(
+            "{{\"pool\":\"{}\",\"percent\":{}}}",
+            split.pool, split.percent
+        ),
+        None => "null".to_string(),
+    }
+}
+
+/// Parses the nodes from `KEY=VALUE` lines, ignoring blank lines and `#` comments.
+/// Whether the request carries the admin token in an `Authorization: Bearer <token>` header.
+fn authorized(request: &http::Request<Vec<u8>>, token: &str) -> bool {
+    request
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "))
+        .is_some_and(|sent| constant_time_eq(sent, token.as_bytes()))
+}
+
+/// Compares the tokens without returning early on the first mismatch.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn parse_nodes(body: &[u8]) -> Result<Vec<Node>, String> {
+    let body = match std::str::from_utf8(body) {
+        Ok(b) => b,
+        Err(_) => return Err("body must be UTF-8".to_string()),
+    };
+
+    let mut vars: BTreeMap<String, String> = BTreeMap::new();
+    for line in body.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, value)) => vars.insert(key.trim().to_string(), value.trim().to_string()),
+            None => return Err(format!("expected KEY=VALUE, got {}", line)),
+        };
+    }
+
+    let nodes: Vec<Node> = reload::parse_nodes(&vars)?;
+    if nodes.is_empty() {
+        return Err("no nodes provided".to_string());
+    }
+
+    Ok(nodes)
+}
+
+fn parse_cidrs(body: &[u8]) -> Result<Vec<Cidr>, String> {
+    let body = match std::str::from_utf8(body) {
+        Ok(b) => b,
+        Err(_) => return Err("body must be UTF-8".to_string()),
+    };
+
+    let cidrs: Vec<Cidr> = body
+        .split(['\n', ','])
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.parse::<Cidr>())
+        .collect::<Result<Vec<Cidr>, String>>()?;
+
+    if cidrs.is_empty() {
+        return Err("no CIDR blocks provided".to_string());
+    }
+
+    Ok(cidrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_admin_request() {
+        assert!(is_admin_request("/lb/admin/ip_filter"));
+        assert!(is_admin_request("/lb/admin"));
+        assert!(!is_admin_request("/lb/administrator"));
+        assert!(!is_admin_request("/document/1"));
+    }
+
+    #[test]
+    fn test_authorized() {
+        let request = |authorization: &str| {
+            http::Request::get("/lb/admin/nodes")
+                .header("Authorization", authorization)
+                .body(Vec::new())
+                .unwrap()
+        };
+        assert!(authorized(&request("Bearer admin-token"), "admin-token"));
+        assert!(!authorized(&request("Bearer admin-tokem"), "admin-token"));
+        assert!(!authorized(&request("Bearer admin"), "admin-token"));
+        assert!(!authorized(&request("admin-token"), "admin-token"));
+        let without = http::Request::get("/lb/admin").body(Vec::new()).unwrap();
+        assert!(!authorized(&without, "admin-token"));
+    }
+
+    #[test]
+    fn test_parse_cidrs() {
+        let cidrs = parse_cidrs(b"10.0.0.0/8\n192.168.0.1, ::1").unwrap();
+        assert_eq!(cidrs.len(), 3);
+        assert!(parse_cidrs(b"").is_err());
+        assert!(parse_cidrs(b"10.0.0.0/99").is_err());
+    }
+
+    #[test]
+    fn test_parse_nodes() {
+        let nodes = parse_nodes(
+            b"# scale out\nNODE1=10.0.0.1:8000\nNODE2 = 10.0.0.2:8000\nNODE2_WEIGHT=2\n",
+        )
+        .unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[1].weight, 2);
+        assert!(parse_nodes(b"").is_err());
+        assert!(parse_nodes(b"NODE1").is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use http::HeaderMap;
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use tracing::{error, info};
+
+const DEFAULT_CANARY_FILE: &str = "canary_rules.conf";
+
+/// A rule sending a cohort of requests to the canary nodes of a pool instead of the ring.
+/// `name`: The cohort, reported in the logs and the admin API.
+/// `header`: The header requests of the cohort carry.
+/// `value`: The value the header must have (compared ignoring case), None for any value.
+/// `percent`: The percentage of the cohort's clients sent to the canary nodes.
+/// `pool`: The pool of the canary nodes (`NODE<n>_POOL`). Its nodes only receive requests
+/// through canary rules.
+/// `canary_requests`: Requests of the cohort sent to the canary nodes.
+/// `control_requests`: Requests of the cohort left on the ring, outside the percentage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanaryRule {
+    pub name: String,
+    pub header: String,
+    pub value: Option<String>,
+    pub percent: u8,
+    pub pool: String,
+    pub canary_requests: u64,
+    pub control_requests: u64,
+}
+
+impl CanaryRule {
+    /// Returns true if the request carries the rule's header.
+    pub fn matches(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get_all(self.header.as_str())
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| match &self.value {
+                Some(expected) => value.trim().eq_ignore_ascii_case(expected),
+                None => true,
+            })
+    }
+
+    /// Returns true if the client is in the percentage of the cohort sent to the canary nodes.
+    /// A client stays in or out of it while the percentage does not change.
+    pub fn selects<H: Hash>(&self, client: &H) -> bool {
+        let mut hasher = DefaultHasher::new();
+        (self.name.as_str(), client).hash(&mut hasher);
+        hasher.finish() % 100 < self.percent as u64
+    }
+}
+
+/// Canary rules evaluated in order, the first rule matching the request's headers wins.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CanaryTable {
+    pub rules: Vec<CanaryRule>,
+}
+
+impl CanaryTable {
+    pub fn new(rules: Vec<CanaryRule>) -> Self {
+        CanaryTable { rules }
+    }
+
+    /// The pools of the canary nodes.
+    pub fn pools(&self) -> BTreeSet<&String> {
+        self.rules.iter().map(|rule| &rule.pool).collect()
+    }
+
+    /// Parses a canary table, one rule per line:
+    /// `<name> <header>[=<value>] <percent>% <pool>`
+    ///
+    /// Example
+    /// ```text
+    /// # name   header          percent  pool
+    /// testers  X-Canary=true   100%     canary
+    /// beta     X-Cohort=beta   20%      canary
+    /// ```
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut rules: Vec<CanaryRule> = Vec::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 4 {
+                return Err(format!(
+                    "Line {}: expected <name> <header>[=<value>] <percent> <pool>",
+                    number + 1
+                ));
+            }
+
+            let (header, value) = match fields[1].split_once('=') {
+                Some((header, value)) => (header, Some(value.to_string())),
+                None => (fields[1], None),
+            };
+            if http::HeaderName::from_bytes(header.as_bytes()).is_err() {
+                return Err(format!("Line {}: Invalid header {}", number + 1, header));
+            }
+            let percent: u8 = match fields[2].trim_end_matches('%').parse::<u8>() {
+                Ok(percent) if percent <= 100 => percent,
+                _ => {
+                    return Err(format!(
+                        "Line {}: Invalid percent {}",
+                        number + 1,
+                        fields[2]
+                    ))
+                }
+            };
+
+            rules.push(CanaryRule {
+                name: fields[0].to_string(),
+                header: header.to_lowercase(),
+                value,
+                percent,
+                pool: fields[3].to_string(),
+                canary_requests: 0,
+                control_requests: 0,
+            });
+        }
+
+        Ok(CanaryTable::new(rules))
+    }
+}
+
+/// Loads the canary table from the file set in `LB_CANARY_FILE` (defaults to
+/// canary_rules.conf). A missing file results in no canary rules.
+pub fn load_canary_table() -> CanaryTable {
+    let path = env::var("LB_CANARY_FILE").unwrap_or(DEFAULT_CANARY_FILE.to_string());
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(_) => {
+            info!("No canary file found at {}, not routing canaries", path);
+            return CanaryTable::default();
+        }
+    };
+
+    match CanaryTable::parse(&contents) {
+        Ok(table) => {
+            info!("Loaded {} canary rules", table.rules.len());
+            table
+        }
+        Err(e) => {
+            error!("Failed to parse {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_canary_table() {
+        let table = CanaryTable::parse(
+            "# name header percent pool\ntesters X-Canary=true 100% canary\nbeta X-Cohort 20 v2 # any value\n",
+        )
+        .unwrap();
+        assert_eq!(table.rules.len(), 2);
+        assert_eq!(table.rules[0].header, "x-canary");
+        assert_eq!(table.rules[0].value.as_deref(), Some("true"));
+        assert_eq!(table.rules[1].value, None);
+        assert_eq!(table.rules[1].percent, 20);
+        assert_eq!(table.pools().len(), 2);
+
+        assert!(CanaryTable::parse("testers X-Canary 101% canary").is_err());
+        assert!(CanaryTable::parse("testers X-Canary 10%").is_err());
+        assert!(CanaryTable::parse("testers X:Canary 10% canary").is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let table = CanaryTable::parse("testers X-Canary=true 100% canary").unwrap();
+        let rule = &table.rules[0];
+
+        let mut headers = HeaderMap::new();
+        assert!(!rule.matches(&headers));
+        headers.insert("X-Canary", "false".parse().unwrap());
+        assert!(!rule.matches(&headers));
+        headers.insert("X-Canary", "TRUE".parse().unwrap());
+        assert!(rule.matches(&headers));
+
+        assert!(rule.selects(&"127.0.0.1:1"));
+    }
+}
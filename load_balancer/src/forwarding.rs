@@ -0,0 +1,144 @@
+use http::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, FORWARDED, HOST};
+use std::net::IpAddr;
+
+/// Headers that only apply to a single connection and are never forwarded (RFC 9110).
+/// `Transfer-Encoding` is hop-by-hop as well, but the body is forwarded exactly as received so
+/// its framing header is kept.
+pub const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// The scheme the load balancer accepts requests on.
+const PROTO: &str = "http";
+
+/// Prepares the headers of a request before it is forwarded to a replica.
+/// Hop-by-hop headers are removed, the client address is appended to `X-Forwarded-For` and
+/// `Forwarded`, and the replica is asked to close the connection once it has responded.
+pub fn prepare_headers(headers: &mut HeaderMap, client_ip: IpAddr) {
+    strip_hop_by_hop(headers);
+    append_forwarded(headers, client_ip);
+
+    // the replica's response is read until it closes the connection
+    headers.insert(CONNECTION, HeaderValue::from_static("close"));
+}
+
+/// Removes the hop-by-hop headers, along with any header named in `Connection`.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+
+    for name in named {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+/// Appends the client to `X-Forwarded-For` and `Forwarded`, keeping the entries added by
+/// proxies in front of the load balancer. `X-Forwarded-Proto` is only set if no proxy did.
+pub fn append_forwarded(headers: &mut HeaderMap, client_ip: IpAddr) {
+    append(
+        headers,
+        HeaderName::from_static(X_FORWARDED_FOR),
+        client_ip.to_string(),
+    );
+
+    let node = match client_ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    };
+    let mut element = format!("for={};proto={}", node, PROTO);
+    if let Some(host) = headers.get(HOST).and_then(|host| host.to_str().ok()) {
+        element.push_str(&format!(";host=\"{}\"", host.replace(['"', '\\'], "")));
+    }
+    append(headers, FORWARDED, element);
+
+    if !headers.contains_key(X_FORWARDED_PROTO) {
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(PROTO));
+    }
+}
+
+/// Joins the existing values of a list header and appends `value` to them.
+/// Existing values that can't be kept are dropped, so `value` is always the last entry.
+fn append(headers: &mut HeaderMap, name: HeaderName, value: String) {
+    let mut values: Vec<String> = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|existing| existing.to_str().ok())
+        .map(str::to_string)
+        .collect();
+    values.push(value.clone());
+
+    let value = HeaderValue::from_str(&values.join(", "))
+        .or_else(|_| HeaderValue::from_str(&value))
+        .unwrap_or_else(|_| HeaderValue::from_static("unknown"));
+    headers.insert(name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepare_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, "localhost:3000".parse().unwrap());
+        headers.insert(CONNECTION, "keep-alive, X-Secret".parse().unwrap());
+        headers.insert("keep-alive", "timeout=5".parse().unwrap());
+        headers.insert("x-secret", "1".parse().unwrap());
+        headers.insert("upgrade", "websocket".parse().unwrap());
+        headers.insert("content-length", "2".parse().unwrap());
+
+        prepare_headers(&mut headers, "10.0.0.12".parse().unwrap());
+
+        assert_eq!(headers.get(CONNECTION).unwrap(), "close");
+        assert!(!headers.contains_key("keep-alive"));
+        assert!(!headers.contains_key("x-secret"));
+        assert!(!headers.contains_key("upgrade"));
+        assert_eq!(headers.get("content-length").unwrap(), "2");
+
+        assert_eq!(headers.get(X_FORWARDED_FOR).unwrap(), "10.0.0.12");
+        assert_eq!(headers.get(X_FORWARDED_PROTO).unwrap(), "http");
+        assert_eq!(
+            headers.get(FORWARDED).unwrap(),
+            "for=10.0.0.12;proto=http;host=\"localhost:3000\""
+        );
+    }
+
+    #[test]
+    fn test_append_forwarded_keeps_existing_entries() {
+        let mut headers = HeaderMap::new();
+        headers.append(X_FORWARDED_FOR, "1.2.3.4".parse().unwrap());
+        headers.append(X_FORWARDED_FOR, "5.6.7.8".parse().unwrap());
+        headers.insert(FORWARDED, "for=1.2.3.4".parse().unwrap());
+        headers.insert(X_FORWARDED_PROTO, "https".parse().unwrap());
+
+        append_forwarded(&mut headers, "::1".parse().unwrap());
+
+        assert_eq!(
+            headers.get(X_FORWARDED_FOR).unwrap(),
+            "1.2.3.4, 5.6.7.8, ::1"
+        );
+        assert_eq!(
+            headers.get(FORWARDED).unwrap(),
+            "for=1.2.3.4, for=\"[::1]\";proto=http"
+        );
+        assert_eq!(headers.get(X_FORWARDED_PROTO).unwrap(), "https");
+    }
+}
@@ -0,0 +1,223 @@
+use std::env;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tracing::warn;
+
+/// A CIDR block such as `10.0.0.0/8` or `2001:db8::/32`.
+/// A bare address is treated as a single host block (/32 or /128).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    pub network: IpAddr,
+    pub prefix: u8,
+}
+
+impl Cidr {
+    /// Returns true if the address falls inside the block.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, normalize(*ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+
+        let network: IpAddr = match address.parse() {
+            Ok(ip) => normalize(ip),
+            Err(_) => return Err(format!("Invalid IP address: {}", address)),
+        };
+
+        let max_prefix: u8 = if network.is_ipv4() { 32 } else { 128 };
+        let prefix: u8 = match prefix {
+            Some(p) => match p.parse::<u8>() {
+                Ok(p) if p <= max_prefix => p,
+                _ => return Err(format!("Invalid prefix length: {}", p)),
+            },
+            None => max_prefix,
+        };
+
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// The result of checking a client address against the IP filter.
+/// `Allowed`: The client is allowlisted and skips rate limiting.
+/// `Denied`: The client is blocklisted and must be rejected.
+/// `Unlisted`: The client is in neither list and is rate limited as normal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterDecision {
+    Allowed,
+    Denied,
+    Unlisted,
+}
+
+/// CIDR based allow and deny lists checked before the rate limiter.
+/// The deny list takes precedence when an address appears in both.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IpFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        IpFilter { allow, deny }
+    }
+
+    /// Builds the filter from the comma separated CIDR lists in `IP_ALLOWLIST` and `IP_DENYLIST`.
+    pub fn from_env() -> Self {
+        IpFilter::new(
+            cidrs_from_env("IP_ALLOWLIST"),
+            cidrs_from_env("IP_DENYLIST"),
+        )
+    }
+
+    /// Checks a client address against the deny list and then the allow list.
+    pub fn check(&self, ip: &IpAddr) -> FilterDecision {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            FilterDecision::Denied
+        } else if self.allow.iter().any(|cidr| cidr.contains(ip)) {
+            FilterDecision::Allowed
+        } else {
+            FilterDecision::Unlisted
+        }
+    }
+
+    pub fn add_allow(&mut self, cidr: Cidr) {
+        if !self.allow.contains(&cidr) {
+            self.allow.push(cidr);
+        }
+    }
+
+    pub fn add_deny(&mut self, cidr: Cidr) {
+        if !self.deny.contains(&cidr) {
+            self.deny.push(cidr);
+        }
+    }
+}
+
+/// Extracts the IP address from a client address which may include a port.
+pub fn parse_client_ip(address: &str) -> Option<IpAddr> {
+    if let Ok(socket) = address.parse::<SocketAddr>() {
+        return Some(normalize(socket.ip()));
+    }
+    address.parse::<IpAddr>().ok().map(normalize)
+}
+
+/// Treats IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) as IPv4.
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        v4 => v4,
+    }
+}
+
+fn cidrs_from_env(key: &str) -> Vec<Cidr> {
+    let value = match env::var(key) {
+        Ok(v) => v,
+        Err(_) => return Vec::new(),
+    };
+
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| match entry.parse::<Cidr>() {
+            Ok(cidr) => Some(cidr),
+            Err(e) => {
+                warn!("Ignoring {} entry: {}", key, e);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr_contains() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(cidr.contains(&"::ffff:10.0.0.1".parse().unwrap()));
+
+        let host: Cidr = "192.168.1.7".parse().unwrap();
+        assert_eq!(host.prefix, 32);
+        assert!(host.contains(&"192.168.1.7".parse().unwrap()));
+        assert!(!host.contains(&"192.168.1.8".parse().unwrap()));
+
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!v6.contains(&"10.0.0.1".parse().unwrap()));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&"8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_cidr_rejects_invalid() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("not-an-ip/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_filter_deny_takes_precedence() {
+        let mut filter = IpFilter::default();
+        filter.add_allow("10.0.0.0/8".parse().unwrap());
+        filter.add_deny("10.0.0.66".parse().unwrap());
+
+        assert_eq!(
+            filter.check(&"10.0.0.1".parse().unwrap()),
+            FilterDecision::Allowed
+        );
+        assert_eq!(
+            filter.check(&"10.0.0.66".parse().unwrap()),
+            FilterDecision::Denied
+        );
+        assert_eq!(
+            filter.check(&"172.16.0.1".parse().unwrap()),
+            FilterDecision::Unlisted
+        );
+    }
+
+    #[test]
+    fn test_parse_client_ip() {
+        assert_eq!(
+            parse_client_ip("127.0.0.1:54321"),
+            Some("127.0.0.1".parse().unwrap())
+        );
+        assert_eq!(parse_client_ip("[::1]:80"), Some("::1".parse().unwrap()));
+        assert_eq!(
+            parse_client_ip("10.0.0.1"),
+            Some("10.0.0.1".parse().unwrap())
+        );
+        assert_eq!(parse_client_ip("nonsense"), None);
+    }
+}
@@ -1,5 +1,22 @@
+pub mod admin;
+pub mod canary;
+pub mod forwarding;
+pub mod ip_filter;
+pub mod limiter;
+pub mod limits;
+pub mod listen;
 pub mod load_balancer;
+pub mod policy;
+pub mod rebalance;
+pub mod reload;
 pub mod request;
+pub mod response;
+pub mod response_cache;
+pub mod routing;
+pub mod telemetry;
+pub mod traffic_split;
+pub mod upstream;
+pub mod verdict_cache;
 
 pub mod rate_limiter_proto {
     include!("proto/rate_limiter.rs");
@@ -0,0 +1,181 @@
+use crate::rate_limiter_proto::rate_limiter_client::RateLimiterClient;
+use crate::rate_limiter_proto::{Algorithm, RateLimitPolicy, RateLimitResponse};
+use crate::verdict_cache::VerdictCache;
+use rate_limiter::{AlgorithmConfig, Limiter};
+use std::env;
+use std::time::{Duration, Instant};
+use tonic::transport::{Channel, Endpoint};
+use tracing::{error, info, warn};
+
+const DEFAULT_RATE_LIMITER_ADDRESS: &str = "http://127.0.0.1:50051";
+
+const DEFAULT_RATE_LIMITER_TIMEOUT_MS: u64 = 10;
+
+const DEFAULT_RATE_LIMITER_CACHE_TTL_MS: u64 = 50;
+
+/// Where rate limiting decisions are made.
+/// `Remote`: The rate limiter service is called over gRPC at `address`, through `client`'s
+/// channel, giving up after `timeout`. Its verdicts are reused from `cache` while they last.
+/// `Embedded`: The rate limiting algorithms run inside the load balancer process.
+#[allow(clippy::large_enum_variant)]
+pub enum RateLimiterMode {
+    Remote {
+        address: String,
+        client: RateLimiterClient<Channel>,
+        timeout: Duration,
+        cache: VerdictCache,
+    },
+    Embedded(Limiter),
+}
+
+impl RateLimiterMode {
+    /// Calls the rate limiter service at `address`.
+    /// The channel is created once and shared by every request. It connects on first use and
+    /// reconnects on its own after the connection is lost, so a rate limiter started after the
+    /// load balancer is picked up without a restart.
+    /// Verdicts are cached for up to `cache_ttl`, zero disables the cache.
+    pub fn remote(
+        address: String,
+        timeout: Duration,
+        cache_ttl: Duration,
+    ) -> Result<Self, tonic::transport::Error> {
+        let channel: Channel = Endpoint::from_shared(address.clone())?
+            .connect_timeout(timeout.max(Duration::from_millis(100)))
+            .tcp_nodelay(true)
+            .http2_keep_alive_interval(Duration::from_secs(30))
+            .keep_alive_while_idle(true)
+            .connect_lazy();
+
+        Ok(RateLimiterMode::Remote {
+            address,
+            client: RateLimiterClient::new(channel),
+            timeout,
+            cache: VerdictCache::new(cache_ttl),
+        })
+    }
+
+    /// Reads the mode from the environment (.env file).
+    /// `RATE_LIMITER_MODE`: `remote` (default) or `embedded`.
+    /// `RATE_LIMITER_ADDRESS`: The rate limiter service address in remote mode.
+    /// `RATE_LIMITER_TIMEOUT_MS`: How long a request waits for the rate limiter service.
+    /// `RATE_LIMITER_CACHE_TTL_MS`: How long allowed verdicts are reused, 0 disables the cache.
+    pub fn from_env() -> Self {
+        let mode = env::var("RATE_LIMITER_MODE").unwrap_or("remote".to_string());
+
+        match mode.trim().to_lowercase().as_str() {
+            "embedded" | "in_process" => {
+                info!("Rate limiting in process");
+                RateLimiterMode::Embedded(Limiter::new())
+            }
+            other => {
+                if other != "remote" {
+                    warn!("Unknown RATE_LIMITER_MODE {}, using remote", other);
+                }
+                let address = env::var("RATE_LIMITER_ADDRESS")
+                    .unwrap_or(DEFAULT_RATE_LIMITER_ADDRESS.to_string());
+                let timeout_ms: u64 =
+                    env_millis("RATE_LIMITER_TIMEOUT_MS", DEFAULT_RATE_LIMITER_TIMEOUT_MS);
+                let cache_ttl_ms: u64 = env_millis(
+                    "RATE_LIMITER_CACHE_TTL_MS",
+                    DEFAULT_RATE_LIMITER_CACHE_TTL_MS,
+                );
+
+                match RateLimiterMode::remote(
+                    address.clone(),
+                    Duration::from_millis(timeout_ms),
+                    Duration::from_millis(cache_ttl_ms),
+                ) {
+                    Ok(mode) => {
+                        info!(
+                            "Using rate limiter service at {} ({}ms timeout, {}ms verdict cache)",
+                            address, timeout_ms, cache_ttl_ms
+                        );
+                        mode
+                    }
+                    Err(_) => {
+                        error!(
+                            "Invalid RATE_LIMITER_ADDRESS {}, rate limiting in process",
+                            address
+                        );
+                        RateLimiterMode::Embedded(Limiter::new())
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Reads a number of milliseconds from the environment, `default` if unset or invalid.
+fn env_millis(name: &str, default: u64) -> u64 {
+    match env::var(name) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            warn!("Invalid {} {}, using the default", name, value);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Converts the policy hint into the configuration used by the rate limiting algorithms.
+pub fn algorithm_config(policy: &RateLimitPolicy) -> AlgorithmConfig {
+    let algorithm = match policy.algorithm() {
+        Algorithm::TokenBucket => rate_limiter::Algorithm::TokenBucket,
+        Algorithm::SlidingWindowLog => rate_limiter::Algorithm::SlidingWindowLog,
+        Algorithm::FixedWindow => rate_limiter::Algorithm::FixedWindow,
+    };
+
+    AlgorithmConfig::new(
+        algorithm,
+        policy.limit,
+        Duration::from_millis(policy.window_ms),
+    )
+}
+
+/// Makes a rate limiting decision in process, keyed by client IP address and policy name.
+/// The verdict has the same shape as the one returned by the rate limiter service.
+pub fn check_embedded(
+    limiter: &mut Limiter,
+    ip_address: &str,
+    policy: &RateLimitPolicy,
+    request_id: String,
+) -> RateLimitResponse {
+    let key = format!("{}|{}", ip_address, policy.name);
+    let decision = limiter.check(&key, &algorithm_config(policy), Instant::now());
+
+    RateLimitResponse {
+        request_id,
+        allowed: decision.allowed,
+        limit: decision.limit,
+        remaining: decision.remaining,
+        retry_after_ms: decision
+            .retry_after
+            .map(|d| d.as_millis().min(u64::MAX as u128) as u64)
+            .unwrap_or(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_embedded() {
+        let policy = RateLimitPolicy {
+            name: "create_document".to_string(),
+            algorithm: Algorithm::FixedWindow.into(),
+            limit: 1,
+            window_ms: 60_000,
+        };
+        let mut limiter = Limiter::new();
+
+        let verdict = check_embedded(&mut limiter, "10.0.0.1", &policy, "1".to_string());
+        assert!(verdict.allowed);
+        assert_eq!(verdict.limit, 1);
+        assert_eq!(verdict.remaining, 0);
+
+        let verdict = check_embedded(&mut limiter, "10.0.0.1", &policy, "2".to_string());
+        assert!(!verdict.allowed);
+        assert!(verdict.retry_after_ms > 0);
+        assert_eq!(verdict.request_id, "2");
+    }
+}
@@ -0,0 +1,367 @@
+use crate::admin::json_response;
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{timeout_at, Instant};
+use tracing::{info, warn};
+
+const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_HEADER_BYTES: usize = 16 * 1024;
+const DEFAULT_HEADER_TIMEOUT_MS: usize = 10_000;
+const DEFAULT_BODY_TIMEOUT_MS: usize = 10_000;
+const DEFAULT_MIN_BODY_RATE: usize = 1024;
+
+/// Connections closed because the client was too slow sending its headers.
+static HEADER_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+/// Connections closed because the client was too slow sending its body.
+static BODY_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+/// Size limits of the requests the load balancer accepts, checked before anything is sent to
+/// a replica.
+/// `max_header_bytes`: The largest request line and headers, larger requests are rejected with
+/// `431 Request Header Fields Too Large`.
+/// `max_body_bytes`: The largest body, larger bodies are rejected with
+/// `413 Payload Too Large`.
+/// `header_timeout`: How long a client has from connecting to send the request line and
+/// headers.
+/// `body_timeout`: How long a client may go without sending any of its body.
+/// `min_body_rate`: The slowest a body may be sent in bytes per second, 0 for no minimum. A
+/// body of `n` bytes must arrive within `body_timeout` plus `n / min_body_rate` seconds.
+///
+/// Clients over a timeout are sent `408 Request Timeout` and disconnected, so slow clients
+/// can't hold connections and tasks open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestLimits {
+    pub max_header_bytes: usize,
+    pub max_body_bytes: usize,
+    pub header_timeout: Duration,
+    pub body_timeout: Duration,
+    pub min_body_rate: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            header_timeout: Duration::from_millis(DEFAULT_HEADER_TIMEOUT_MS as u64),
+            body_timeout: Duration::from_millis(DEFAULT_BODY_TIMEOUT_MS as u64),
+            min_body_rate: DEFAULT_MIN_BODY_RATE,
+        }
+    }
+}
+
+impl RequestLimits {
+    /// Reads the limits from the environment (.env file).
+    /// `LB_MAX_HEADER_BYTES`: 16 KiB by default.
+    /// `LB_MAX_BODY_BYTES`: 1 MiB by default.
+    /// `LB_HEADER_TIMEOUT_MS`: 10 seconds by default.
+    /// `LB_BODY_TIMEOUT_MS`: 10 seconds by default.
+    /// `LB_MIN_BODY_RATE`: 1024 bytes per second by default, 0 for no minimum.
+    pub fn from_env() -> Self {
+        let limits = RequestLimits {
+            max_header_bytes: env_usize("LB_MAX_HEADER_BYTES", DEFAULT_MAX_HEADER_BYTES),
+            max_body_bytes: env_usize("LB_MAX_BODY_BYTES", DEFAULT_MAX_BODY_BYTES),
+            header_timeout: Duration::from_millis(env_usize(
+                "LB_HEADER_TIMEOUT_MS",
+                DEFAULT_HEADER_TIMEOUT_MS,
+            ) as u64),
+            body_timeout: Duration::from_millis(env_usize(
+                "LB_BODY_TIMEOUT_MS",
+                DEFAULT_BODY_TIMEOUT_MS,
+            ) as u64),
+            min_body_rate: env_usize("LB_MIN_BODY_RATE", DEFAULT_MIN_BODY_RATE),
+        };
+        info!(
+            "Accepting headers up to {} bytes and bodies up to {} bytes",
+            limits.max_header_bytes, limits.max_body_bytes
+        );
+        limits
+    }
+
+    /// When the body of `content_length` bytes must have arrived, counted from `start`.
+    fn body_deadline(&self, start: Instant, content_length: usize) -> Instant {
+        let transfer = match self.min_body_rate {
+            0 => Duration::ZERO,
+            rate => Duration::from_secs_f64(content_length as f64 / rate as f64),
+        };
+        start + self.body_timeout + transfer
+    }
+}
+
+/// Connections closed for being too slow since the load balancer started.
+/// `headers`: Clients that didn't send their headers within the timeout.
+/// `body`: Clients that sent their body too slowly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowClients {
+    pub headers: u64,
+    pub body: u64,
+}
+
+pub fn slow_clients() -> SlowClients {
+    SlowClients {
+        headers: HEADER_TIMEOUTS.load(Ordering::Relaxed),
+        body: BODY_TIMEOUTS.load(Ordering::Relaxed),
+    }
+}
+
+/// Why a request could not be read.
+/// `Closed`: The client closed the connection before sending a request.
+/// `HeadersTooLarge`: The request line and headers are larger than the limit.
+/// `PayloadTooLarge`: The body is larger than the limit.
+/// `LengthRequired`: The body is sent with `Transfer-Encoding` instead of a `Content-Length`,
+/// so its size isn't known before it is read.
+/// `TimedOut`: The client was too slow sending the request.
+/// `Malformed`: The request has an invalid `Content-Length`, or ends before its body does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadError {
+    Closed,
+    HeadersTooLarge,
+    PayloadTooLarge,
+    LengthRequired,
+    TimedOut,
+    Malformed,
+}
+
+impl ReadError {
+    /// The response sent to the client, None if the connection is gone.
+    pub fn response(&self, limits: &RequestLimits) -> Option<Vec<u8>> {
+        let (code, reason, error, message) = match self {
+            ReadError::Closed => return None,
+            ReadError::HeadersTooLarge => (
+                431,
+                "Request Header Fields Too Large",
+                "headers_too_large",
+                format!("Request headers exceed {} bytes", limits.max_header_bytes),
+            ),
+            ReadError::PayloadTooLarge => (
+                413,
+                "Payload Too Large",
+                "payload_too_large",
+                format!("Request body exceeds {} bytes", limits.max_body_bytes),
+            ),
+            ReadError::LengthRequired => (
+                411,
+                "Length Required",
+                "length_required",
+                "Request bodies must be sent with a Content-Length".to_string(),
+            ),
+            ReadError::TimedOut => (
+                408,
+                "Request Timeout",
+                "request_timeout",
+                "Request was not received in time".to_string(),
+            ),
+            ReadError::Malformed => (
+                400,
+                "Bad Request",
+                "malformed_request",
+                "Request body does not match its Content-Length".to_string(),
+            ),
+        };
+        Some(json_response(
+            code,
+            reason,
+            &format!("{{\"code\":\"lb::{}\",\"message\":\"{}\"}}", error, message),
+        ))
+    }
+}
+
+/// Reads a request from the client, stopping as soon as it is known to be over the limits so
+/// oversized bodies are never buffered or forwarded. The body is read up to its
+/// `Content-Length`, requests without one have no body.
+pub async fn read_request<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    limits: &RequestLimits,
+) -> Result<Vec<u8>, ReadError> {
+    let mut buffer: Vec<u8> = Vec::with_capacity(4096);
+    let mut chunk: [u8; 4096] = [0; 4096];
+    let header_deadline: Instant = Instant::now() + limits.header_timeout;
+
+    let headers_end: usize = loop {
+        if let Some(index) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break index + 4;
+        }
+        if buffer.len() > limits.max_header_bytes {
+            return Err(ReadError::HeadersTooLarge);
+        }
+        match timeout_at(header_deadline, stream.read(&mut chunk)).await {
+            Err(_) => {
+                HEADER_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+                warn!("Closed a connection that didn't send its headers in time");
+                return Err(ReadError::TimedOut);
+            }
+            Ok(Ok(0) | Err(_)) if buffer.is_empty() => return Err(ReadError::Closed),
+            Ok(Ok(0) | Err(_)) => return Err(ReadError::Malformed),
+            Ok(Ok(read)) => buffer.extend_from_slice(&chunk[..read]),
+        }
+    };
+    if headers_end > limits.max_header_bytes {
+        return Err(ReadError::HeadersTooLarge);
+    }
+
+    let content_length: usize = content_length(&buffer[..headers_end])?;
+    if content_length > limits.max_body_bytes {
+        warn!(
+            "Rejected a body of {} bytes, the limit is {}",
+            content_length, limits.max_body_bytes
+        );
+        return Err(ReadError::PayloadTooLarge);
+    }
+
+    let total: usize = headers_end + content_length;
+    let body_deadline: Instant = limits.body_deadline(Instant::now(), content_length);
+    while buffer.len() < total {
+        // every read must arrive within the body timeout, and the whole body by the deadline
+        let deadline: Instant = body_deadline.min(Instant::now() + limits.body_timeout);
+        match timeout_at(deadline, stream.read(&mut chunk)).await {
+            Err(_) => {
+                BODY_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Closed a connection that sent {} of {} body bytes in time",
+                    buffer.len().saturating_sub(headers_end),
+                    content_length
+                );
+                return Err(ReadError::TimedOut);
+            }
+            Ok(Ok(0) | Err(_)) => return Err(ReadError::Malformed),
+            Ok(Ok(read)) => buffer.extend_from_slice(&chunk[..read]),
+        }
+    }
+    // anything after the body is not part of this request
+    buffer.truncate(total);
+
+    Ok(buffer)
+}
+
+/// Reads the `Content-Length` header, 0 if the request has none.
+fn content_length(headers: &[u8]) -> Result<usize, ReadError> {
+    let headers = String::from_utf8_lossy(headers);
+    let header = |name: &str| {
+        headers
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+    };
+    if header("transfer-encoding").is_some() {
+        return Err(ReadError::LengthRequired);
+    }
+    match header("content-length") {
+        Some((_, value)) => value.trim().parse().map_err(|_| ReadError::Malformed),
+        None => Ok(0),
+    }
+}
+
+fn env_usize(key: &str, default: usize) -> usize {
+    match env::var(key) {
+        Ok(value) => match value.trim().parse::<usize>() {
+            Ok(v) if v > 0 => v,
+            _ => {
+                warn!("Invalid value for {}: {}, using {}", key, value, default);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: RequestLimits = RequestLimits {
+        max_header_bytes: 64,
+        max_body_bytes: 8,
+        header_timeout: Duration::from_millis(100),
+        body_timeout: Duration::from_millis(100),
+        min_body_rate: 0,
+    };
+
+    async fn read(request: &[u8]) -> Result<Vec<u8>, ReadError> {
+        let mut stream = request;
+        read_request(&mut stream, &LIMITS).await
+    }
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let request = b"POST /a HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody";
+        assert_eq!(read(request).await.unwrap(), request.to_vec());
+        let request = b"GET /a HTTP/1.1\r\nHost: lb\r\n\r\n";
+        assert_eq!(read(request).await.unwrap(), request.to_vec());
+
+        assert_eq!(read(b"").await, Err(ReadError::Closed));
+        assert_eq!(
+            read(b"POST /a HTTP/1.1\r\nContent-Length: 9\r\n\r\nnine bytes").await,
+            Err(ReadError::PayloadTooLarge)
+        );
+        assert_eq!(
+            read(format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64)).as_bytes()).await,
+            Err(ReadError::HeadersTooLarge)
+        );
+        assert_eq!(
+            read(b"POST /a HTTP/1.1\r\nContent-Length: 4\r\n\r\nbo").await,
+            Err(ReadError::Malformed)
+        );
+        assert_eq!(
+            read(b"POST /a HTTP/1.1\r\nContent-Length: x\r\n\r\n").await,
+            Err(ReadError::Malformed)
+        );
+        assert_eq!(
+            read(b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nbody\r\n0\r\n\r\n")
+                .await,
+            Err(ReadError::LengthRequired)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_clients() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let read = tokio::spawn(async move { read_request(&mut server, &LIMITS).await });
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"POST /a HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        assert_eq!(read.await.unwrap(), Err(ReadError::TimedOut));
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let read = tokio::spawn(async move { read_request(&mut server, &LIMITS).await });
+        tokio::io::AsyncWriteExt::write_all(
+            &mut client,
+            b"POST /a HTTP/1.1\r\nContent-Length: 4\r\n\r\nbo",
+        )
+        .await
+        .unwrap();
+        assert_eq!(read.await.unwrap(), Err(ReadError::TimedOut));
+        assert!(slow_clients().headers >= 1 && slow_clients().body >= 1);
+    }
+
+    #[test]
+    fn test_body_deadline() {
+        let start = Instant::now();
+        let limits = RequestLimits {
+            min_body_rate: 1000,
+            ..LIMITS
+        };
+        assert_eq!(
+            limits.body_deadline(start, 2000),
+            start + Duration::from_millis(2100)
+        );
+        assert_eq!(
+            LIMITS.body_deadline(start, 2000),
+            start + Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn test_error_response() {
+        let response = ReadError::PayloadTooLarge.response(&LIMITS).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(response.ends_with(
+            "{\"code\":\"lb::payload_too_large\",\"message\":\"Request body exceeds 8 bytes\"}"
+        ));
+        assert_eq!(ReadError::Closed.response(&LIMITS), None);
+    }
+}
@@ -0,0 +1,176 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tracing::error;
+
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:3000";
+
+/// The traffic a listener serves.
+/// `Combined`: Proxied requests and the admin API, on loopback addresses when no admin address
+/// is configured.
+/// `Public`: Proxied requests only, admin API requests get `404 Not Found`.
+/// `Admin`: The admin API only, every other request gets `404 Not Found`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerRole {
+    Combined,
+    Public,
+    Admin,
+}
+
+impl ListenerRole {
+    pub fn serves_admin(&self) -> bool {
+        *self != ListenerRole::Public
+    }
+
+    pub fn serves_proxy(&self) -> bool {
+        *self != ListenerRole::Admin
+    }
+}
+
+/// The addresses the load balancer listens on.
+/// `public`: The addresses clients connect to.
+/// `admin`: The addresses the admin API is served on, empty to serve it with the proxied
+/// requests on the public addresses that are loopback addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenConfig {
+    pub public: Vec<SocketAddr>,
+    pub admin: Vec<SocketAddr>,
+}
+
+impl ListenConfig {
+    /// Reads the addresses from the environment (.env file), exiting if they are invalid.
+    /// `LB_LISTEN_ADDRESS`: Comma separated addresses, `127.0.0.1:3000` by default. `[::]:3000`
+    /// accepts both IPv6 and IPv4 clients.
+    /// `LB_ADMIN_LISTEN_ADDRESS`: Comma separated addresses serving only the admin API.
+    pub fn from_env() -> Self {
+        let public = env::var("LB_LISTEN_ADDRESS").unwrap_or(DEFAULT_LISTEN_ADDRESS.to_string());
+        let admin = env::var("LB_ADMIN_LISTEN_ADDRESS").unwrap_or_default();
+
+        match (parse_addresses(&public), parse_addresses(&admin)) {
+            (Ok(public), _) if public.is_empty() => {
+                error!("LB_LISTEN_ADDRESS must not be empty");
+                std::process::exit(1);
+            }
+            (Ok(public), Ok(admin)) => ListenConfig { public, admin },
+            (Err(e), _) | (_, Err(e)) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /// Every address with the traffic it serves. Without admin addresses the admin API is only
+    /// served on the public addresses clients on other hosts can't reach.
+    pub fn listeners(&self) -> Vec<(SocketAddr, ListenerRole)> {
+        let role = |address: &SocketAddr| {
+            if self.admin.is_empty() && address.ip().is_loopback() {
+                ListenerRole::Combined
+            } else {
+                ListenerRole::Public
+            }
+        };
+        self.public
+            .iter()
+            .map(|address| (*address, role(address)))
+            .chain(
+                self.admin
+                    .iter()
+                    .map(|address| (*address, ListenerRole::Admin)),
+            )
+            .collect()
+    }
+}
+
+/// Parses comma separated socket addresses such as `127.0.0.1:3000,[::1]:3000`.
+pub fn parse_addresses(addresses: &str) -> Result<Vec<SocketAddr>, String> {
+    addresses
+        .split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(|address| {
+            address
+                .parse::<SocketAddr>()
+                .map_err(|_| format!("Invalid listen address: {}", address))
+        })
+        .collect()
+}
+
+/// Binds a listener to the address. IPv6 listeners also accept IPv4 clients, so `[::]` listens
+/// on every interface whatever the system's default.
+pub fn bind(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_addresses() {
+        assert_eq!(
+            parse_addresses("127.0.0.1:3000, [::]:3000,").unwrap(),
+            vec![
+                "127.0.0.1:3000".parse::<SocketAddr>().unwrap(),
+                "[::]:3000".parse().unwrap()
+            ]
+        );
+        assert_eq!(parse_addresses("").unwrap(), vec![]);
+        assert!(parse_addresses("127.0.0.1").is_err());
+        assert!(parse_addresses("::1:3000").is_err());
+    }
+
+    #[test]
+    fn test_listeners() {
+        let mut config = ListenConfig {
+            public: parse_addresses("127.0.0.1:3000,[::1]:3000,0.0.0.0:3000").unwrap(),
+            admin: Vec::new(),
+        };
+        // without admin addresses the admin API is only served on loopback addresses
+        let roles: Vec<ListenerRole> = config.listeners().iter().map(|(_, r)| *r).collect();
+        assert_eq!(
+            roles,
+            vec![
+                ListenerRole::Combined,
+                ListenerRole::Combined,
+                ListenerRole::Public
+            ]
+        );
+
+        config.public = parse_addresses("0.0.0.0:3000").unwrap();
+        config.admin = parse_addresses("127.0.0.1:3001").unwrap();
+        let roles: Vec<ListenerRole> = config.listeners().iter().map(|(_, r)| *r).collect();
+        assert_eq!(roles, vec![ListenerRole::Public, ListenerRole::Admin]);
+        assert!(!ListenerRole::Public.serves_admin() && ListenerRole::Public.serves_proxy());
+        assert!(ListenerRole::Admin.serves_admin() && !ListenerRole::Admin.serves_proxy());
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        let listener = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::net::TcpStream::connect(address).await.unwrap();
+        listener.accept().await.unwrap();
+
+        // IPv4 clients reach a dual-stack listener, where the host has IPv6
+        if let Ok(listener) = bind("[::]:0".parse().unwrap()) {
+            let port = listener.local_addr().unwrap().port();
+            tokio::net::TcpStream::connect(("127.0.0.1", port))
+                .await
+                .unwrap();
+        }
+    }
+}
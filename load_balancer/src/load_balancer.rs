@@ -1,28 +1,99 @@
 pub mod consistent_hashing {
+    use crate::canary::CanaryTable;
+    use crate::ip_filter::{parse_client_ip, FilterDecision, IpFilter};
+    use crate::limiter::{self, RateLimiterMode};
+    use crate::policy::{self, PolicyTable};
     use crate::rate_limiter_proto::rate_limiter_client::RateLimiterClient;
-    use crate::rate_limiter_proto::RateLimitRequest;
-    use std::collections::{BTreeMap, VecDeque};
+    use crate::rate_limiter_proto::{RateLimitPolicy, RateLimitRequest, RateLimitResponse};
+    use crate::rebalance::Rebalance;
+    use crate::request::REQUEST_ID_HEADER;
+    use crate::response;
+    use crate::response_cache::{CacheKey, ResponseCache};
+    use crate::routing::{self, RouteAction, RoutingTable};
+    use crate::traffic_split::TrafficSplit;
+    use crate::upstream::{self, UpstreamTls};
+    use crate::verdict_cache::CacheLookup;
+    use std::collections::{BTreeMap, BTreeSet, VecDeque};
     use std::hash::{DefaultHasher, Hash, Hasher};
-    use std::time::Duration;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    use tokio::net::TcpStream;
+    use std::time::{Duration, Instant};
+    use tokio::sync::Mutex;
     use tokio::time::timeout;
     use tonic::transport::Channel;
-
-    const RATELIMITERADDRESS: &str = "http://127.0.0.1:50051";
+    use tracing::{error, info, instrument, warn, Span};
 
     /// Node represents a replica in the distributed system.
     /// `address` is a url address for the replica
+    /// `region` is the region the replica runs in (None if unlabelled)
+    /// `weight` is the number of points the replica has on the ring, a replica with twice the
+    /// weight of another receives about twice the clients
+    /// `pool` is the deployment the replica belongs to, such as `v2` (None if unlabelled)
     #[derive(Debug, PartialEq, Eq, Clone)]
     pub struct Node {
         pub address: String,
+        pub region: Option<String>,
+        pub weight: u32,
+        pub pool: Option<String>,
     }
 
     impl Node {
-        /// Returns a new node based on the input parameters
-        pub fn new(address: String) -> Self {
-            Node { address }
+        /// Returns a new node based on the input parameters, with a weight of 1
+        pub fn new(address: String, region: Option<String>) -> Self {
+            Node {
+                address,
+                region,
+                weight: 1,
+                pool: None,
+            }
+        }
+
+        pub fn with_weight(mut self, weight: u32) -> Self {
+            self.weight = weight;
+            self
+        }
+
+        pub fn with_pool(mut self, pool: Option<String>) -> Self {
+            self.pool = pool;
+            self
         }
+
+        /// The positions of the node on the ring. The first point is the hash of the address
+        /// alone, so a node with a weight of 1 keeps the position it had before weights.
+        pub fn points(&self) -> Vec<u64> {
+            (0..self.weight.max(1))
+                .map(|point| match point {
+                    0 => LoadBalancer::add_node(&self.address),
+                    _ => LoadBalancer::add_node(&(self.address.as_str(), point)),
+                })
+                .collect()
+        }
+    }
+
+    /// A ring of nodes and the ring of those in the load balancer's region.
+    pub type Rings = (BTreeMap<u64, String>, BTreeMap<u64, String>);
+
+    /// What checking a request's rate limit takes.
+    /// `Decided`: The verdict of the embedded limiter, or a cached one.
+    /// `Remote`: The request to send to the rate limiter service.
+    #[allow(clippy::large_enum_variant)]
+    enum RateLimitCheck {
+        Decided(RateLimitResponse),
+        Remote {
+            client: RateLimiterClient<Channel>,
+            deadline: Duration,
+            request: RateLimitRequest,
+        },
+    }
+
+    /// The replica a request is forwarded to, chosen while the load balancer is locked.
+    /// `address`: The replica's address.
+    /// `tls`: The TLS settings of the connection to it, None for plain TCP.
+    /// `cache_key`: The key the response is cached under, None if it isn't cached.
+    /// `invalidates`: The path whose cached responses a write changes, None for reads.
+    struct Upstream {
+        address: String,
+        tls: Option<UpstreamTls>,
+        cache_key: Option<CacheKey>,
+        invalidates: Option<String>,
     }
 
     pub struct LoadBalancer {
@@ -30,6 +101,31 @@ pub mod consistent_hashing {
         pub nodes: Vec<Node>,
         pub lamport_timestamp: u64,
         pub ring: std::collections::BTreeMap<u64, String>,
+        /// The nodes in the load balancer's region, preferred over the full ring when not empty.
+        pub local_ring: std::collections::BTreeMap<u64, String>,
+        /// The share of clients routed to another pool, None to route every client over `ring`.
+        pub split: Option<TrafficSplit>,
+        /// The nodes in the pool of `split`, left out of `ring` while it is set.
+        pub split_ring: std::collections::BTreeMap<u64, String>,
+        /// The nodes in the pool of `split` and the load balancer's region.
+        pub split_local_ring: std::collections::BTreeMap<u64, String>,
+        /// The rules sending cohorts of requests to canary nodes.
+        pub canary: CanaryTable,
+        /// The path prefixes routed to pools or rejected.
+        pub routes: RoutingTable,
+        /// The rings of the nodes of every pool. Nodes in canary pools are left out of the
+        /// other rings.
+        pub pool_rings: BTreeMap<String, Rings>,
+        pub region: Option<String>,
+        pub rate_limit_policies: PolicyTable,
+        pub ip_filter: IpFilter,
+        pub rate_limiter: RateLimiterMode,
+        /// GET responses reused for later requests, disabled unless configured.
+        pub response_cache: ResponseCache,
+        /// The keys that changed owner the last time the nodes changed, None until they do.
+        pub last_rebalance: Option<Rebalance>,
+        /// The TLS settings of the connections to the replicas, None to connect over plain TCP.
+        pub upstream_tls: Option<UpstreamTls>,
     }
 
     impl LoadBalancer {
@@ -39,27 +135,157 @@ pub mod consistent_hashing {
             temp
         }
 
-        pub async fn new(addresses: &mut Vec<String>) -> Self {
-            let mut ring = BTreeMap::new();
-
-            // gets the hash for each node
-            for node in addresses.clone() {
-                let hash = Self::add_node(&node);
-                ring.insert(hash, node.clone());
-            }
-
-            let mut nodes: Vec<Node> = Vec::new();
-            for node in addresses {
-                nodes.push(Node {
-                    address: node.to_string(),
-                });
-            }
+        pub async fn new(
+            nodes: Vec<Node>,
+            region: Option<String>,
+            rate_limit_policies: PolicyTable,
+            ip_filter: IpFilter,
+            rate_limiter: RateLimiterMode,
+        ) -> Self {
+            let (ring, local_ring) = Self::build_rings(nodes.iter(), &region);
 
             LoadBalancer {
                 buffer: VecDeque::new(),
                 nodes,
                 lamport_timestamp: 0,
                 ring,
+                local_ring,
+                split: None,
+                split_ring: BTreeMap::new(),
+                split_local_ring: BTreeMap::new(),
+                canary: CanaryTable::default(),
+                routes: RoutingTable::default(),
+                pool_rings: BTreeMap::new(),
+                region,
+                rate_limit_policies,
+                ip_filter,
+                rate_limiter,
+                response_cache: ResponseCache::new(Duration::ZERO, 0, 0),
+                last_rebalance: None,
+                upstream_tls: None,
+            }
+        }
+
+        /// Builds the ring of the nodes and the ring of the nodes in `region`.
+        fn build_rings<'a>(
+            nodes: impl Iterator<Item = &'a Node>,
+            region: &Option<String>,
+        ) -> Rings {
+            let mut ring = BTreeMap::new();
+            let mut local_ring = BTreeMap::new();
+
+            // gets the hashes for each node
+            for node in nodes {
+                for hash in node.points() {
+                    ring.insert(hash, node.address.clone());
+                    if region.is_some() && node.region == *region {
+                        local_ring.insert(hash, node.address.clone());
+                    }
+                }
+            }
+            (ring, local_ring)
+        }
+
+        /// Replaces the nodes and rebuilds the rings. Returns the keys that changed owner, or
+        /// None, leaving the rings as they are, if the nodes did not change.
+        pub fn set_nodes(&mut self, nodes: Vec<Node>) -> Option<Rebalance> {
+            if nodes == self.nodes {
+                return None;
+            }
+            let rebalance = self.dry_run(&nodes);
+            self.nodes = nodes;
+            self.rebuild_rings();
+            self.last_rebalance = Some(rebalance.clone());
+            Some(rebalance)
+        }
+
+        /// Sets the share of clients routed to another pool, None to route every client over
+        /// every node, and rebuilds the rings.
+        pub fn set_split(&mut self, split: Option<TrafficSplit>) {
+            self.split = split;
+            self.rebuild_rings();
+        }
+
+        /// Sets the canary rules and rebuilds the rings.
+        pub fn set_canary(&mut self, canary: CanaryTable) {
+            self.canary = canary;
+            self.rebuild_rings();
+        }
+
+        /// Sets the routing table.
+        pub fn set_routes(&mut self, routes: RoutingTable) {
+            self.routes = routes;
+        }
+
+        /// Builds the rings from the nodes, putting the nodes in the pool of the split in the
+        /// split rings and building the rings of every pool.
+        fn rebuild_rings(&mut self) {
+            let pool: Option<&String> = self.split.as_ref().map(|split| &split.pool);
+            let canary_pools = self.canary.pools();
+            let is_canary = |node: &&Node| match &node.pool {
+                Some(pool) => canary_pools.contains(pool),
+                None => false,
+            };
+            let in_pool = |node: &&Node| pool.is_some() && node.pool.as_ref() == pool;
+            let routed = || self.nodes.iter().filter(|n| !is_canary(n));
+
+            let (ring, local_ring) =
+                Self::build_rings(routed().filter(|n| !in_pool(n)), &self.region);
+            let (split_ring, split_local_ring) =
+                Self::build_rings(routed().filter(in_pool), &self.region);
+            let pools: BTreeSet<&String> = self
+                .nodes
+                .iter()
+                .filter_map(|node| node.pool.as_ref())
+                .collect();
+            let pool_rings = pools
+                .into_iter()
+                .map(|pool| {
+                    let nodes = self
+                        .nodes
+                        .iter()
+                        .filter(|node| node.pool.as_ref() == Some(pool));
+                    (pool.clone(), Self::build_rings(nodes, &self.region))
+                })
+                .collect();
+            self.ring = ring;
+            self.local_ring = local_ring;
+            self.split_ring = split_ring;
+            self.split_local_ring = split_local_ring;
+            self.pool_rings = pool_rings;
+        }
+
+        /// Reports the keys that would change owner if the nodes were replaced, without
+        /// replacing them. Only the clients that are not shifted to another pool are compared,
+        /// and canaries are left out.
+        pub fn dry_run(&self, nodes: &[Node]) -> Rebalance {
+            let pool: Option<&String> = self.split.as_ref().map(|split| &split.pool);
+            let canary_pools = self.canary.pools();
+            let (ring, local_ring) = Self::build_rings(
+                nodes.iter().filter(|node| match &node.pool {
+                    Some(node_pool) => Some(node_pool) != pool && !canary_pools.contains(node_pool),
+                    None => true,
+                }),
+                &self.region,
+            );
+            Rebalance::compare(self.active_ring(), Self::preferred_ring(&ring, &local_ring))
+        }
+
+        /// The ring requests are routed with, apart from the clients shifted to another pool.
+        pub fn active_ring(&self) -> &BTreeMap<u64, String> {
+            Self::preferred_ring(&self.ring, &self.local_ring)
+        }
+
+        /// Nodes in the load balancer's region are preferred, the other regions are only used
+        /// when it has none.
+        fn preferred_ring<'a>(
+            ring: &'a BTreeMap<u64, String>,
+            local_ring: &'a BTreeMap<u64, String>,
+        ) -> &'a BTreeMap<u64, String> {
+            if local_ring.is_empty() {
+                ring
+            } else {
+                local_ring
             }
         }
 
@@ -70,45 +296,83 @@ pub mod consistent_hashing {
             hasher.finish()
         }
 
+        /// Answers a request. The load balancer is only locked while its state is read or
+        /// updated, never while the rate limiter or the replica is waited on, so a slow
+        /// replica doesn't hold up other clients or the admin API.
+        #[instrument(
+            name = "lb.distribute",
+            skip_all,
+            fields(request_id = %request.request_id, client_ip = %request.client_ip, uri = %request.uri, cohort = tracing::field::Empty)
+        )]
         pub async fn distribute(
-            &mut self,
+            state: &Mutex<LoadBalancer>,
+            request: crate::request::Request,
+        ) -> Result<Vec<u8>, hyper::Error> {
+            let request_id = request.request_id.to_string();
+            info!("{} {}", request.request.method(), request.uri);
+
+            // every response carries the request id, so clients can report it
+            let response = Self::forward(state, request).await?;
+            info!(
+                "Responded {}",
+                response::status_code(&response).unwrap_or_default()
+            );
+            Ok(response::set_header(
+                response,
+                REQUEST_ID_HEADER,
+                &request_id,
+            ))
+        }
+
+        /// Checks the request against the IP filter and rate limiter, then proxies it to the
+        /// replica chosen for the client.
+        async fn forward(
+            state: &Mutex<LoadBalancer>,
             request: crate::request::Request,
         ) -> Result<Vec<u8>, hyper::Error> {
-            let rate_limit_request = RateLimitRequest {
-                ip_address: request.client_ip.clone(),
-                endpoint: request.uri.clone(),
-                request_id: request.request_id.to_string(),
-            };
-
-            // send request to rate limiter
-            let mut client: RateLimiterClient<Channel> =
-                match RateLimiterClient::connect(RATELIMITERADDRESS.to_string().clone()).await {
-                    Ok(c) => c,
-                    Err(_) => {
-                        eprintln!("Connection to rate limiter could not be esablished");
-                        return Ok(
-                            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n"
-                                .to_string()
-                                .into_bytes(),
-                        );
+            let check = match state.lock().await.admit(&request) {
+                Ok(check) => check,
+                Err(response) => return Ok(response),
+            };
+            let verdict: Option<RateLimitResponse> = match check {
+                // allowlisted clients are trusted and skip rate limiting
+                None => None,
+                Some(check) => match Self::check_rate_limit(state, check).await {
+                    Ok(verdict) if !verdict.allowed => {
+                        return Ok(response::too_many_requests(&verdict));
                     }
-                };
+                    Ok(verdict) => Some(verdict),
+                    Err(response) => return Ok(response),
+                },
+            };
 
-            let response = match timeout(
-                Duration::from_millis(10),
-                client.check_request(rate_limit_request),
-            )
-            .await
-            {
-                Ok(Ok(value)) => value,
-                Ok(Err(_)) => {
+            let upstream: Upstream = match state.lock().await.choose_upstream(&request, &verdict) {
+                Ok(upstream) => upstream,
+                Err(response) => return Ok(response),
+            };
+
+            let request = match serialize_request(request.request).await {
+                Ok(r) => r,
+                _ => {
                     return Ok(
                         "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n"
                             .to_string()
                             .into_bytes(),
                     );
                 }
-                Err(_) => {
+            };
+
+            info!("Forwarding to {}", upstream.address);
+            let server_response = match upstream::exchange(
+                &upstream.address,
+                upstream.tls.as_ref(),
+                &request,
+            )
+            .await
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("{}", e);
                     return Ok(
                         "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n"
                             .to_string()
@@ -117,18 +381,87 @@ pub mod consistent_hashing {
                 }
             };
 
-            if !response.into_inner().allowed {
-                return Ok(
-                    "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\n\r\n"
+            let server_response = state.lock().await.cache_response(upstream, server_response);
+            Ok(with_rate_limit_headers(server_response, &verdict))
+        }
+
+        /// Checks the request against the IP filter and the route's rules.
+        /// Returns what the rate limiter has to check (None for allowlisted clients), or the
+        /// response to send to the client if the request is rejected.
+        fn admit(
+            &mut self,
+            request: &crate::request::Request,
+        ) -> Result<Option<RateLimitCheck>, Vec<u8>> {
+            let client_ip = parse_client_ip(&request.client_ip);
+            let decision = match &client_ip {
+                Some(ip) => self.ip_filter.check(ip),
+                None => FilterDecision::Unlisted,
+            };
+
+            let action: &RouteAction = self.routes.resolve(&request.uri);
+            if decision != FilterDecision::Denied && !routing::permits(action, client_ip.as_ref()) {
+                info!("Route {} rejected the request", action);
+                return Err("HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n"
+                    .to_string()
+                    .into_bytes());
+            }
+
+            match decision {
+                FilterDecision::Denied => {
+                    Err("HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n"
                         .to_string()
-                        .into_bytes(),
-                );
+                        .into_bytes())
+                }
+                FilterDecision::Allowed => Ok(None),
+                FilterDecision::Unlisted => Ok(Some(self.rate_limit_check(request))),
+            }
+        }
+
+        /// Chooses the replica the request is forwarded to, unless it is answered from the
+        /// response cache. Returns the response to send to the client if it isn't forwarded.
+        fn choose_upstream(
+            &mut self,
+            request: &crate::request::Request,
+            verdict: &Option<RateLimitResponse>,
+        ) -> Result<Upstream, Vec<u8>> {
+            let cache_key = if self.response_cache.is_enabled() {
+                ResponseCache::key(&request.request)
+            } else {
+                None
+            };
+            if let Some(key) = &cache_key {
+                if let Some(cached) = self.response_cache.lookup(key, Instant::now()) {
+                    info!("Responding from the cache");
+                    let cached = response::set_header(cached, "X-Cache", "HIT");
+                    return Err(with_rate_limit_headers(cached, verdict));
+                }
             }
+            let is_write: bool = request.request.method() != http::Method::GET
+                && request.request.method() != http::Method::HEAD;
 
-            let node_address = match self.get_node(&request.client_ip) {
-                Some(address) => address.clone(),
+            // canary rules take precedence over the routes
+            let action: RouteAction = self.routes.resolve(&request.uri).clone();
+            let node_address = match (self.get_canary_node(request), &action) {
+                (Some(address), _) => Some(address),
+                (None, RouteAction::Pool(pool)) => {
+                    match self.get_pool_node(pool, &request.client_ip) {
+                        Some(address) => Some(address.clone()),
+                        None => {
+                            error!("Pool {} has no nodes", pool);
+                            return Err(
+                                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n"
+                                    .to_string()
+                                    .into_bytes(),
+                            );
+                        }
+                    }
+                }
+                (None, _) => self.get_node(&request.client_ip).cloned(),
+            };
+            let node_address = match node_address {
+                Some(address) => address,
                 _ => {
-                    return Ok(
+                    return Err(
                         "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n"
                             .to_string()
                             .into_bytes(),
@@ -138,21 +471,117 @@ pub mod consistent_hashing {
 
             self.increment_time();
 
-            let request = match serialize_request(request.request).await {
-                Ok(r) => r,
-                _ => {
-                    return Ok(
+            Ok(Upstream {
+                address: node_address,
+                tls: self.upstream_tls.clone(),
+                cache_key,
+                invalidates: is_write.then(|| request.uri.clone()),
+            })
+        }
+
+        /// Caches the replica's response to a forwarded request, and drops the cached
+        /// responses a write changed.
+        fn cache_response(&mut self, upstream: Upstream, mut response: Vec<u8>) -> Vec<u8> {
+            if let Some(path) = &upstream.invalidates {
+                self.response_cache.invalidate(path);
+            }
+            if let Some(key) = upstream.cache_key {
+                self.response_cache.store(key, &response, Instant::now());
+                response = response::set_header(response, "X-Cache", "MISS");
+            }
+            response
+        }
+
+        /// Decides what the rate limiter needs to check the request: the verdict of the
+        /// embedded limiter or a cached one, or a request to the rate limiter service.
+        fn rate_limit_check(&mut self, request: &crate::request::Request) -> RateLimitCheck {
+            let method = request.request.method().as_str();
+            let accept = request
+                .request
+                .headers()
+                .get(http::header::ACCEPT)
+                .and_then(|value| value.to_str().ok());
+            let operation_class = policy::operation_class(method, &request.uri, accept);
+            let policy = self
+                .rate_limit_policies
+                .resolve(method, &request.uri, operation_class)
+                .clone();
+
+            // limit by client IP address rather than by connection
+            let ip_address = match parse_client_ip(&request.client_ip) {
+                Some(ip) => ip.to_string(),
+                None => request.client_ip.clone(),
+            };
+
+            let (client, deadline, hits) = match &mut self.rate_limiter {
+                RateLimiterMode::Embedded(limiter) => {
+                    return RateLimitCheck::Decided(limiter::check_embedded(
+                        limiter,
+                        &ip_address,
+                        &policy,
+                        request.request_id.to_string(),
+                    ));
+                }
+                RateLimiterMode::Remote {
+                    client,
+                    timeout,
+                    cache,
+                    ..
+                } => {
+                    let request_id = request.request_id.to_string();
+                    match cache.lookup(&ip_address, &policy, &request_id, Instant::now()) {
+                        CacheLookup::Hit(verdict) => return RateLimitCheck::Decided(verdict),
+                        CacheLookup::Miss { hits } => (client.clone(), *timeout, hits),
+                    }
+                }
+            };
+
+            RateLimitCheck::Remote {
+                client,
+                deadline,
+                request: RateLimitRequest {
+                    ip_address,
+                    endpoint: request.uri.clone(),
+                    request_id: request.request_id.to_string(),
+                    policy: Some(policy),
+                    hits,
+                    operation_class: operation_class.into(),
+                },
+            }
+        }
+
+        /// Asks the rate limiter if the request may proceed, caching its verdict.
+        /// Returns the rate limiter verdict, or the response to send to the client if the
+        /// rate limiter could not be reached.
+        async fn check_rate_limit(
+            state: &Mutex<LoadBalancer>,
+            check: RateLimitCheck,
+        ) -> Result<RateLimitResponse, Vec<u8>> {
+            let (mut client, deadline, rate_limit_request) = match check {
+                RateLimitCheck::Decided(verdict) => return Ok(verdict),
+                RateLimitCheck::Remote {
+                    client,
+                    deadline,
+                    request,
+                } => (client, deadline, request),
+            };
+            let ip_address: String = rate_limit_request.ip_address.clone();
+            let policy: RateLimitPolicy = rate_limit_request.policy.clone().unwrap_or_default();
+
+            // send request to rate limiter over the shared channel
+            let response = match timeout(deadline, client.check_request(rate_limit_request)).await {
+                Ok(Ok(value)) => value,
+                Ok(Err(status)) => {
+                    error!("Rate limiter failed: {}", status.message());
+                    return Err(
                         "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n"
                             .to_string()
                             .into_bytes(),
                     );
                 }
-            };
-
-            let mut stream = match TcpStream::connect(node_address).await {
-                Ok(s) => s,
                 Err(_) => {
-                    return Ok(
+                    error!("Rate limiter did not answer within {:?}", deadline);
+                    return Err(
                         "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n"
                             .to_string()
                             .into_bytes(),
@@ -160,37 +589,87 @@ pub mod consistent_hashing {
                 }
             };
 
-            if (stream.write_all(&request).await).is_err() {
-                eprintln!("Failed to write to server");
-                return Ok(
-                    "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n"
-                        .to_string()
-                        .into_bytes(),
-                );
+            let verdict = response.into_inner();
+            if let RateLimiterMode::Remote { cache, .. } = &mut state.lock().await.rate_limiter {
+                cache.insert(&ip_address, &policy, &verdict, Instant::now());
             }
 
-            let mut server_response = Vec::new();
-            if (stream.read_to_end(&mut server_response).await).is_err() {
-                eprintln!("Failed to read from server");
-                return Ok(
-                    "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n"
-                        .to_string()
-                        .into_bytes(),
-                );
-            }
-
-            Ok(server_response)
+            Ok(verdict)
         }
 
         /// Calculate the hash for a node using hasher instance
+        /// Nodes in the load balancer's region are preferred, the other regions are only used
+        /// when it has none. Clients in the share of the split go to its pool, and every client
+        /// goes to the other pool when one of them has no nodes.
         pub fn get_node<H: Hash>(&self, node: &H) -> Option<&String> {
             let key = Self::add_node(node);
+            let shifted: bool = match &self.split {
+                Some(split) => !self.split_ring.is_empty() && split.shifts(node),
+                None => false,
+            };
+            let ring = if shifted || self.ring.is_empty() {
+                Self::preferred_ring(&self.split_ring, &self.split_local_ring)
+            } else {
+                self.active_ring()
+            };
+
+            Self::lookup(ring, key)
+        }
 
-            self.ring
-                .range(key..)
+        /// Calculate the hash for a node of the pool, None if the pool has no nodes.
+        pub fn get_pool_node<H: Hash>(&self, pool: &str, node: &H) -> Option<&String> {
+            let (ring, local_ring) = self.pool_rings.get(pool)?;
+            Self::lookup(Self::preferred_ring(ring, local_ring), Self::add_node(node))
+        }
+
+        /// The node owning `key`: the first point at or after it, wrapping around to the first
+        /// point.
+        fn lookup(ring: &BTreeMap<u64, String>, key: u64) -> Option<&String> {
+            ring.range(key..)
                 .next()
                 .map(|(_, node)| node)
-                .or_else(|| self.ring.iter().next().map(|(_, node)| node))
+                .or_else(|| ring.iter().next().map(|(_, node)| node))
+        }
+
+        /// Returns the canary node for the request, or None to route it over the ring.
+        /// Requests of a cohort are counted whether or not they are sent to the canary nodes,
+        /// and the cohort is recorded on the request's span.
+        pub fn get_canary_node(&mut self, request: &crate::request::Request) -> Option<String> {
+            let rule = self
+                .canary
+                .rules
+                .iter_mut()
+                .find(|rule| rule.matches(request.request.headers()))?;
+            Span::current().record("cohort", rule.name.as_str());
+
+            let ring = match self.pool_rings.get(&rule.pool) {
+                Some((ring, local_ring)) if !ring.is_empty() => {
+                    Self::preferred_ring(ring, local_ring)
+                }
+                _ => {
+                    warn!("Canary pool {} has no nodes", rule.pool);
+                    rule.control_requests += 1;
+                    return None;
+                }
+            };
+            if !rule.selects(&request.client_ip) {
+                rule.control_requests += 1;
+                return None;
+            }
+
+            rule.canary_requests += 1;
+            info!("Routing cohort {} to canary pool {}", rule.name, rule.pool);
+            Self::lookup(ring, Self::add_node(&request.client_ip)).cloned()
+        }
+    }
+
+    /// Adds the rate limit headers of the verdict, if the request was rate limited.
+    fn with_rate_limit_headers(response: Vec<u8>, verdict: &Option<RateLimitResponse>) -> Vec<u8> {
+        match verdict {
+            Some(verdict) => {
+                response::insert_headers(response, &response::rate_limit_headers(verdict))
+            }
+            None => response,
         }
     }
 
@@ -219,10 +698,273 @@ pub mod consistent_hashing {
             request_bytes.extend_from_slice(value.as_bytes());
             request_bytes.extend_from_slice(b"\r\n");
         }
+        request_bytes.extend_from_slice(b"\r\n");
 
         // Add the body
         request_bytes.extend_from_slice(&body);
 
         Ok(request_bytes)
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        async fn new_balancer(region: Option<&str>) -> LoadBalancer {
+            let nodes = vec![
+                Node::new("10.0.0.1:8000".to_string(), Some("af-south-1".to_string())),
+                Node::new("10.0.0.2:8000".to_string(), Some("eu-west-1".to_string())),
+                Node::new("10.0.0.3:8000".to_string(), None),
+            ];
+            LoadBalancer::new(
+                nodes,
+                region.map(str::to_string),
+                PolicyTable::new(Vec::new(), crate::policy::default_policy()),
+                IpFilter::default(),
+                RateLimiterMode::remote(
+                    "http://127.0.0.1:50051".to_string(),
+                    Duration::from_millis(10),
+                    Duration::from_millis(50),
+                )
+                .unwrap(),
+            )
+            .await
+        }
+
+        #[tokio::test]
+        async fn test_prefers_same_region() {
+            let balancer = new_balancer(Some("eu-west-1")).await;
+            for client in ["127.0.0.1:1", "127.0.0.2:2", "192.168.0.1:3"] {
+                assert_eq!(balancer.get_node(&client).unwrap(), "10.0.0.2:8000");
+            }
+
+            // without replicas in its region the load balancer uses every region
+            let balancer = new_balancer(Some("us-east-1")).await;
+            assert_eq!(balancer.local_ring.len(), 0);
+            assert!(balancer.get_node(&"127.0.0.1:1").is_some());
+
+            let balancer = new_balancer(None).await;
+            assert_eq!(balancer.ring.len(), 3);
+            assert!(balancer.local_ring.is_empty());
+        }
+
+        #[tokio::test]
+        async fn test_set_nodes() {
+            let mut balancer = new_balancer(Some("af-south-1")).await;
+            let before = balancer.get_node(&"127.0.0.1:1").cloned();
+            assert!(balancer.set_nodes(balancer.nodes.clone()).is_none());
+
+            let mut nodes = balancer.nodes.clone();
+            nodes[0] = nodes[0].clone().with_weight(4);
+            nodes.push(Node::new(
+                "10.0.0.4:8000".to_string(),
+                Some("af-south-1".to_string()),
+            ));
+            let dry_run = balancer.dry_run(&nodes);
+            assert_eq!(balancer.ring.len(), 3);
+            let rebalance = balancer.set_nodes(nodes).unwrap();
+            assert_eq!(rebalance, dry_run);
+            assert!(rebalance.moved_fraction > 0.0 && rebalance.moved_fraction < 1.0);
+            assert!(rebalance
+                .ranges
+                .iter()
+                .all(|range| range.from.as_deref() == Some("10.0.0.1:8000")));
+            assert_eq!(balancer.ring.len(), 4 + 1 + 1 + 1);
+            assert_eq!(balancer.local_ring.len(), 4 + 1);
+
+            // a weight of 1 keeps the node where it was
+            balancer.set_nodes(vec![Node::new(
+                "10.0.0.1:8000".to_string(),
+                Some("af-south-1".to_string()),
+            )]);
+            assert_eq!(balancer.get_node(&"127.0.0.1:1").cloned(), before);
+        }
+
+        #[tokio::test]
+        async fn test_traffic_split() {
+            let mut balancer = new_balancer(None).await;
+            let mut nodes = balancer.nodes.clone();
+            nodes.push(
+                Node::new("10.0.1.1:8000".to_string(), None).with_pool(Some("v2".to_string())),
+            );
+            balancer.set_nodes(nodes);
+
+            let clients: Vec<String> = (0..200)
+                .map(|i| format!("10.1.0.{}:{}", i % 250, i))
+                .collect();
+            let on_v2 = |balancer: &LoadBalancer| {
+                clients
+                    .iter()
+                    .filter(|c| balancer.get_node(c).unwrap() == "10.0.1.1:8000")
+                    .count()
+            };
+
+            // without a split the pool is part of the ring
+            assert_eq!(balancer.ring.len(), 4);
+            let before = on_v2(&balancer);
+            assert!(before > 0 && before < clients.len());
+
+            balancer.set_split(Some(TrafficSplit::new("v2".to_string(), 0).unwrap()));
+            assert_eq!(balancer.ring.len(), 3);
+            assert_eq!(on_v2(&balancer), 0);
+
+            balancer.set_split(Some(TrafficSplit::new("v2".to_string(), 100).unwrap()));
+            assert_eq!(on_v2(&balancer), clients.len());
+
+            // a pool without nodes leaves every client on the other nodes
+            balancer.set_split(Some(TrafficSplit::new("v3".to_string(), 100).unwrap()));
+            assert_eq!(on_v2(&balancer), before);
+        }
+
+        #[tokio::test]
+        async fn test_canary() {
+            let mut balancer = new_balancer(None).await;
+            let mut nodes = balancer.nodes.clone();
+            nodes.push(
+                Node::new("10.0.9.1:8000".to_string(), None).with_pool(Some("canary".to_string())),
+            );
+            balancer.set_nodes(nodes);
+            balancer.set_canary(
+                CanaryTable::parse("testers X-Canary=true 100% canary\nnobody X-None 0% canary")
+                    .unwrap(),
+            );
+            assert_eq!(balancer.ring.len(), 3);
+
+            let request = |headers: &[(&str, &str)]| {
+                let mut builder = http::Request::builder().uri("/document/1");
+                for (name, value) in headers {
+                    builder = builder.header(*name, *value);
+                }
+                crate::request::Request::new(
+                    "/document/1".to_string(),
+                    "127.0.0.1:1".to_string(),
+                    builder.body(Vec::new()).unwrap(),
+                )
+            };
+
+            assert_eq!(balancer.get_canary_node(&request(&[])), None);
+            assert_eq!(
+                balancer.get_canary_node(&request(&[("X-Canary", "true")])),
+                Some("10.0.9.1:8000".to_string())
+            );
+            assert_eq!(balancer.get_canary_node(&request(&[("X-None", "1")])), None);
+            assert_eq!(balancer.canary.rules[0].canary_requests, 1);
+            assert_eq!(balancer.canary.rules[1].control_requests, 1);
+        }
+
+        #[tokio::test]
+        async fn test_get_pool_node() {
+            let mut balancer = new_balancer(None).await;
+            let mut nodes = balancer.nodes.clone();
+            nodes.push(
+                Node::new("10.0.5.1:8000".to_string(), None).with_pool(Some("archive".to_string())),
+            );
+            balancer.set_nodes(nodes);
+
+            // pools that aren't canaries stay on the ring
+            assert_eq!(balancer.ring.len(), 4);
+            for client in ["127.0.0.1:1", "127.0.0.2:2", "192.168.0.1:3"] {
+                assert_eq!(
+                    balancer.get_pool_node("archive", &client).unwrap(),
+                    "10.0.5.1:8000"
+                );
+            }
+            assert_eq!(balancer.get_pool_node("replicas", &"127.0.0.1:1"), None);
+        }
+
+        #[tokio::test]
+        async fn test_unlocked_while_forwarding() {
+            // a replica that takes the request and only answers when told to
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut balancer = new_balancer(None).await;
+            balancer.set_nodes(vec![Node::new(
+                listener.local_addr().unwrap().to_string(),
+                None,
+            )]);
+            balancer
+                .ip_filter
+                .add_allow("127.0.0.1/32".parse().unwrap());
+            let state = Arc::new(Mutex::new(balancer));
+
+            let forwarding = tokio::spawn({
+                let state = state.clone();
+                async move {
+                    let request = crate::request::Request::new(
+                        "/document/1".to_string(),
+                        "127.0.0.1:1".to_string(),
+                        http::Request::get("/document/1").body(Vec::new()).unwrap(),
+                    );
+                    LoadBalancer::distribute(&state, request).await.unwrap()
+                }
+            });
+            let (mut replica, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = replica.read(&mut request).await.unwrap();
+
+            // other clients and the admin API can use the load balancer in the meantime
+            assert!(state.try_lock().is_ok());
+
+            replica
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            replica.shutdown().await.unwrap();
+            let response = forwarding.await.unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+        }
+
+        #[tokio::test]
+        async fn test_repeated_document_get_is_cached() {
+            // a replica answering document reads as the replicas do, marked private
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            let replica = tokio::spawn(async move {
+                for _ in 0..2 {
+                    let (mut connection, _) = listener.accept().await.unwrap();
+                    let mut request = [0; 1024];
+                    let _ = connection.read(&mut request).await.unwrap();
+                    connection
+                        .write_all(b"HTTP/1.1 200 OK\r\nCache-Control: private\r\nContent-Length: 2\r\n\r\nok")
+                        .await
+                        .unwrap();
+                    connection.shutdown().await.unwrap();
+                }
+            });
+
+            let mut balancer = new_balancer(None).await;
+            balancer.set_nodes(vec![Node::new(address, None)]);
+            balancer
+                .ip_filter
+                .add_allow("127.0.0.1/32".parse().unwrap());
+            balancer.response_cache = ResponseCache::new(Duration::from_secs(60), 10, 1024);
+            let state = Arc::new(Mutex::new(balancer));
+            let get = |token: &str| {
+                crate::request::Request::new(
+                    "/document/1".to_string(),
+                    "127.0.0.1:1".to_string(),
+                    http::Request::get("/document/1")
+                        .header("X-Session-Token", token)
+                        .body(Vec::new())
+                        .unwrap(),
+                )
+            };
+            let cache = |response: &[u8]| response::header(response, "X-Cache");
+
+            let first = LoadBalancer::distribute(&state, get("a")).await.unwrap();
+            assert_eq!(cache(&first).as_deref(), Some("MISS"));
+            let second = LoadBalancer::distribute(&state, get("a")).await.unwrap();
+            assert_eq!(cache(&second).as_deref(), Some("HIT"));
+            assert!(second.ends_with(b"ok"));
+
+            // another user's read reaches the replica
+            let other = LoadBalancer::distribute(&state, get("b")).await.unwrap();
+            assert_eq!(cache(&other).as_deref(), Some("MISS"));
+            replica.await.unwrap();
+            assert_eq!(state.lock().await.response_cache.hits, 1);
+        }
+    }
 }
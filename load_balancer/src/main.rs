@@ -1,53 +1,105 @@
 use dotenv::dotenv;
-use load_balancer::load_balancer::consistent_hashing::LoadBalancer;
+use load_balancer::admin;
+use load_balancer::canary;
+use load_balancer::forwarding;
+use load_balancer::ip_filter::IpFilter;
+use load_balancer::limiter::RateLimiterMode;
+use load_balancer::limits::{self, RequestLimits};
+use load_balancer::listen::{self, ListenConfig, ListenerRole};
+use load_balancer::load_balancer::consistent_hashing::{LoadBalancer, Node};
+use load_balancer::policy;
+use load_balancer::reload::{self, NodeSource};
 use load_balancer::request::buffer_to_request;
+use load_balancer::response_cache::ResponseCache;
+use load_balancer::routing;
+use load_balancer::telemetry;
+use load_balancer::traffic_split::TrafficSplit;
+use load_balancer::upstream::UpstreamTls;
+use std::collections::BTreeMap;
 use std::env;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
-    let mut nodes: Vec<String> = get_nodes();
-
-    // Listen on port 3000
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-
-    let listener: TcpListener = match TcpListener::bind(addr).await {
-        Ok(l) => l,
-        Err(_) => {
-            eprintln!("Failed to bind to socket");
+    telemetry::init_tracing();
+
+    // the environment is captured before the .env file is loaded into it, so a reload can
+    // tell which variables came from the file
+    let environment: BTreeMap<String, String> = env::vars().collect();
+    let source: NodeSource = NodeSource::new(environment, dotenv().ok());
+    let nodes: Vec<Node> = match source.load() {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            error!("{}", e);
             std::process::exit(1);
         }
     };
+    info!("Loaded nodes");
+    let region: Option<String> = env::var("LB_REGION").ok().filter(|r| !r.is_empty());
+
+    let mut listeners: Vec<(TcpListener, ListenerRole)> = Vec::new();
+    for (addr, role) in ListenConfig::from_env().listeners() {
+        match listen::bind(addr) {
+            Ok(listener) => listeners.push((listener, role)),
+            Err(e) => {
+                error!("Failed to bind to {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        }
+        info!("Listening on http://{} ({:?})", addr, role);
+    }
 
-    println!("Listening on http://{}", addr);
-
-    let state: Arc<Mutex<LoadBalancer>> = Arc::new(Mutex::new(LoadBalancer::new(&mut nodes).await));
+    let mut balancer: LoadBalancer = LoadBalancer::new(
+        nodes,
+        region,
+        policy::load_policy_table(),
+        IpFilter::from_env(),
+        RateLimiterMode::from_env(),
+    )
+    .await;
+    balancer.set_split(TrafficSplit::from_env());
+    balancer.set_canary(canary::load_canary_table());
+    balancer.set_routes(routing::load_routing_table());
+    balancer.response_cache = ResponseCache::from_env();
+    balancer.upstream_tls = UpstreamTls::from_env();
+    let state: Arc<Mutex<LoadBalancer>> = Arc::new(Mutex::new(balancer));
+
+    tokio::spawn(reload::watch(state.clone(), source));
 
     let shutdown: Arc<Notify> = Arc::new(Notify::new());
 
     let shutdown_signal = shutdown.clone();
 
     tokio::spawn(async move {
-        if let Err(_) = tokio::signal::ctrl_c().await {
-            eprintln!("Failed to listen for shutdown signal");
+        if tokio::signal::ctrl_c().await.is_err() {
+            error!("Failed to listen for shutdown signal");
             std::process::exit(1);
         } else {
             shutdown_signal.notify_one();
-            println!("Tasks complete, server shutdown started");
+            info!("Tasks complete, server shutdown started");
+            telemetry::shutdown_tracing();
             std::process::exit(0);
         }
     });
 
+    let request_limits: RequestLimits = RequestLimits::from_env();
+    let mut proxies: JoinSet<()> = JoinSet::new();
+    for (listener, role) in listeners {
+        proxies.spawn(reverse_proxy(listener, state.clone(), request_limits, role));
+    }
+
     tokio::select! {
-        _ = reverse_proxy(listener,state.clone()) => {
-            println!("loop ended");
+        _ = proxies.join_next() => {
+            info!("loop ended");
         },
         _ = shutdown.notified() => {
-                eprintln!("Graceful shutdown initiated");
+                error!("Graceful shutdown initiated");
                 std::process::exit(0);
             }
     }
@@ -55,97 +107,86 @@ async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     Ok(())
 }
 
-async fn reverse_proxy(listener: TcpListener, state: Arc<Mutex<LoadBalancer>>) {
+async fn reverse_proxy(
+    listener: TcpListener,
+    state: Arc<Mutex<LoadBalancer>>,
+    request_limits: RequestLimits,
+    role: ListenerRole,
+) {
     loop {
         let state = state.clone();
         if let Ok((mut stream, client_address)) = listener.accept().await {
+            // IPv4 clients of a dual-stack listener arrive as IPv4-mapped IPv6 addresses
+            let client_address =
+                SocketAddr::new(client_address.ip().to_canonical(), client_address.port());
             tokio::spawn(async move {
-                let mut buffer: [u8; 4096] = [0; 4096];
-
-                if let Ok(bytes_read) = stream.read(&mut buffer).await {
-                    if bytes_read == 0 {
+                // oversized requests are rejected before anything is sent to a replica
+                let buffer: Vec<u8> = match limits::read_request(&mut stream, &request_limits).await
+                {
+                    Ok(buffer) => buffer,
+                    Err(e) => {
+                        if let Some(response) = e.response(&request_limits) {
+                            let _ = stream.write_all(&response).await;
+                        }
                         return;
                     }
+                };
 
-                    println!(
-                        "{}",
-                        String::from_utf8(buffer[..bytes_read].to_vec()).unwrap()
-                    );
-
-                    let mut request: http::Request<Vec<u8>> = match buffer_to_request(
-                        buffer[..bytes_read].to_vec(),
-                        client_address.to_string(),
-                        0,
-                    ) {
+                let request: http::Request<Vec<u8>> =
+                    match buffer_to_request(buffer, client_address.to_string(), 0) {
                         Ok(request) => request,
                         Err(e) => {
-                            eprintln!("Failed to parse request: {}", e);
+                            error!("Failed to parse request: {}", e);
                             send_error_response(400, &mut stream).await;
                             return;
                         }
                     };
 
-                    // Ignore favicon.ico requests
-                    if request.uri().path() == "/favicon.ico" {
-                        send_error_response(404, &mut stream).await;
-                        return;
-                    }
-
-                    // add the client IP address custom header
-                    request
-                        .headers_mut()
-                        .insert("X-Client-IP", client_address.to_string().parse().unwrap());
+                // Ignore favicon.ico requests
+                if request.uri().path() == "/favicon.ico" {
+                    send_error_response(404, &mut stream).await;
+                    return;
+                }
 
-                    let uri = request.uri().path().to_string();
+                // each listener only serves the traffic of its role
+                let is_admin: bool = admin::is_admin_request(request.uri().path());
+                if (is_admin && !role.serves_admin()) || (!is_admin && !role.serves_proxy()) {
+                    send_error_response(404, &mut stream).await;
+                    return;
+                }
 
-                    let request: load_balancer::request::Request =
-                        load_balancer::request::Request::new(
-                            uri,
-                            client_address.to_string(),
-                            request,
-                        );
+                let uri = request.uri().path().to_string();
 
-                    let mut state = state.lock().await;
+                let mut request: load_balancer::request::Request =
+                    load_balancer::request::Request::new(uri, client_address.to_string(), request);
 
-                    let response = match state.distribute(request).await {
-                        Ok(r) => r,
-                        Err(_) => "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\n\r\n"
-                            .to_string()
-                            .into_bytes(),
-                    };
+                debug!("{}", request.describe());
 
+                // the admin API is served by the load balancer itself
+                if is_admin {
+                    let response = admin::handle_admin(&request.request, &mut *state.lock().await);
                     if (stream.write_all(&response).await).is_err() {
-                        eprintln!("Failed to responed to client");
+                        error!("Failed to responed to client");
                     };
+                    return;
                 }
-            });
-        }
-    }
-}
-
-async fn shutdown_signal() {
-    // Wait for the CTRL+C signal
-    tokio::signal::ctrl_c()
-        .await
-        .expect("failed to install CTRL+C signal handler");
-    eprintln!("Shutdown signal received...");
-}
 
-fn get_nodes() -> Vec<String> {
-    // Load the .env file
-    dotenv().ok();
+                // tell the replica who the client is, and drop headers meant for this hop
+                forwarding::prepare_headers(request.request.headers_mut(), client_address.ip());
 
-    let mut nodes: Vec<String> = Vec::new();
+                let response = match LoadBalancer::distribute(&state, request).await {
+                    Ok(r) => r,
+                    Err(_) => "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\n\r\n"
+                        .to_string()
+                        .into_bytes(),
+                };
 
-    for (key, value) in env::vars() {
-        if key.starts_with("NODE") {
-            nodes.push(value);
+                if (stream.write_all(&response).await).is_err() {
+                    error!("Failed to responed to client");
+                };
+            });
         }
     }
-
-    println!("Loaded nodes");
-
-    nodes
 }
 
 async fn send_error_response(code: u64, stream: &mut TcpStream) {
@@ -0,0 +1,428 @@
+use crate::rate_limiter_proto::{Algorithm, OperationClass, RateLimitPolicy};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use tracing::{error, info, warn};
+
+const DEFAULT_LIMIT: u64 = 100;
+const DEFAULT_WINDOW_MS: u64 = 1000;
+const DEFAULT_POLICY_FILE: &str = "rate_limit_policies.conf";
+const DEFAULT_WRITE_RATE: &str = "20/s";
+const DEFAULT_CREATE_RATE: &str = "5/min";
+
+/// A rule mapping an HTTP method and endpoint pattern to a rate limiting policy.
+/// `method`: The HTTP method the rule applies to (`*` for any method).
+/// `pattern`: The endpoint pattern, `*` matches a single path segment.
+/// `policy`: The policy sent to the rate limiter for matching requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyRule {
+    pub method: String,
+    pub pattern: String,
+    pub policy: RateLimitPolicy,
+}
+
+impl PolicyRule {
+    /// Returns true if the rule applies to the request method and path.
+    pub fn matches(&self, method: &str, path: &str) -> bool {
+        if self.method != "*" && !self.method.eq_ignore_ascii_case(method) {
+            return false;
+        }
+
+        // ignore the query string
+        let path = path.split('?').next().unwrap_or(path);
+
+        let pattern: Vec<&str> = self.pattern.trim_matches('/').split('/').collect();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        pattern.len() == segments.len()
+            && pattern
+                .iter()
+                .zip(segments.iter())
+                .all(|(p, s)| *p == "*" || p == s)
+    }
+}
+
+/// Policy table evaluated in order, the first matching rule wins.
+/// Requests that match no rule use the policy of their operation class in `classes`, or the
+/// `default` policy when their class has none.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyTable {
+    pub rules: Vec<PolicyRule>,
+    pub classes: HashMap<OperationClass, RateLimitPolicy>,
+    pub default: RateLimitPolicy,
+}
+
+impl PolicyTable {
+    pub fn new(rules: Vec<PolicyRule>, default: RateLimitPolicy) -> Self {
+        PolicyTable {
+            rules,
+            classes: HashMap::new(),
+            default,
+        }
+    }
+
+    /// Finds the policy for a request of the operation class.
+    pub fn resolve(&self, method: &str, path: &str, class: OperationClass) -> &RateLimitPolicy {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(method, path))
+            .map(|rule| &rule.policy)
+            .or_else(|| self.classes.get(&class))
+            .unwrap_or(&self.default)
+    }
+
+    /// Parses a policy table, one rule per line:
+    /// `<name> <method> <endpoint pattern> <algorithm> <limit>/<period>`
+    ///
+    /// Example
+    /// ```text
+    /// # name          method  endpoint             algorithm     rate
+    /// document_insert POST    /document/*/insert   token_bucket  100/s
+    /// create_document POST    /create_document     fixed_window  5/min
+    /// ```
+    pub fn parse(contents: &str, default: RateLimitPolicy) -> Result<Self, String> {
+        let mut rules: Vec<PolicyRule> = Vec::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 5 {
+                return Err(format!(
+                    "Line {}: expected <name> <method> <endpoint> <algorithm> <rate>",
+                    number + 1
+                ));
+            }
+
+            let algorithm =
+                parse_algorithm(fields[3]).map_err(|e| format!("Line {}: {}", number + 1, e))?;
+            let (limit, window_ms) =
+                parse_rate(fields[4]).map_err(|e| format!("Line {}: {}", number + 1, e))?;
+
+            rules.push(PolicyRule {
+                method: fields[1].to_uppercase(),
+                pattern: fields[2].to_string(),
+                policy: RateLimitPolicy {
+                    name: fields[0].to_string(),
+                    algorithm: algorithm.into(),
+                    limit,
+                    window_ms,
+                },
+            });
+        }
+
+        Ok(PolicyTable::new(rules, default))
+    }
+}
+
+/// Returns the operation class of a request, given its `Accept` header.
+/// - GET requests accepting `text/event-stream` are `Stream`.
+/// - GET, HEAD and OPTIONS requests are `Read`.
+/// - POST requests to `/create_document` are `Create`.
+/// - Every other request is a `Write`.
+pub fn operation_class(method: &str, path: &str, accept: Option<&str>) -> OperationClass {
+    let method = method.to_uppercase();
+    let path = path.split('?').next().unwrap_or(path).trim_end_matches('/');
+
+    match method.as_str() {
+        "GET" if accept.is_some_and(|accept| accept.contains("text/event-stream")) => {
+            OperationClass::Stream
+        }
+        "GET" | "HEAD" | "OPTIONS" => OperationClass::Read,
+        "POST" if path == "/create_document" => OperationClass::Create,
+        _ => OperationClass::Write,
+    }
+}
+
+/// Parses an algorithm name such as `token_bucket` or `sliding-window-log`.
+pub fn parse_algorithm(name: &str) -> Result<Algorithm, String> {
+    let name = name.trim().to_uppercase().replace('-', "_");
+    match Algorithm::from_str_name(&name) {
+        Some(algorithm) => Ok(algorithm),
+        None => Err(format!("Unknown rate limiting algorithm: {}", name)),
+    }
+}
+
+/// Parses a rate such as `100/s`, `5/min` or `1000/h` into a limit and window in milliseconds.
+pub fn parse_rate(rate: &str) -> Result<(u64, u64), String> {
+    let (limit, period) = match rate.split_once('/') {
+        Some(parts) => parts,
+        None => return Err(format!("Invalid rate: {}", rate)),
+    };
+
+    let limit: u64 = limit
+        .parse()
+        .map_err(|_| format!("Invalid rate limit: {}", limit))?;
+
+    let window_ms: u64 = match period {
+        "ms" => 1,
+        "s" | "sec" => 1000,
+        "m" | "min" => 60 * 1000,
+        "h" | "hour" => 60 * 60 * 1000,
+        _ => return Err(format!("Invalid rate period: {}", period)),
+    };
+
+    Ok((limit, window_ms))
+}
+
+/// Builds the default rate limiting policy from the environment (.env file).
+/// `RATE_LIMIT_ALGORITHM`: One of token_bucket, sliding_window_log or fixed_window.
+/// `RATE_LIMIT_LIMIT`: Bucket capacity or maximum requests per window.
+/// `RATE_LIMIT_WINDOW_MS`: Window length in milliseconds.
+pub fn default_policy() -> RateLimitPolicy {
+    let algorithm = match env::var("RATE_LIMIT_ALGORITHM") {
+        Ok(name) => match parse_algorithm(&name) {
+            Ok(algorithm) => algorithm,
+            Err(e) => {
+                warn!("{}, falling back to token_bucket", e);
+                Algorithm::TokenBucket
+            }
+        },
+        Err(_) => Algorithm::TokenBucket,
+    };
+
+    RateLimitPolicy {
+        name: "default".to_string(),
+        algorithm: algorithm.into(),
+        limit: env_u64("RATE_LIMIT_LIMIT", DEFAULT_LIMIT),
+        window_ms: env_u64("RATE_LIMIT_WINDOW_MS", DEFAULT_WINDOW_MS),
+    }
+}
+
+/// Builds the policy of every operation class from the environment (.env file), each named
+/// after its class and limited independently. The classes use the default policy's algorithm.
+/// `RATE_LIMIT_READ_RATE`: Rate of content reads, the default policy's rate by default.
+/// `RATE_LIMIT_STREAM_RATE`: Rate of event streams opened, the default policy's rate by default.
+/// `RATE_LIMIT_WRITE_RATE`: Rate of document changes, `20/s` by default.
+/// `RATE_LIMIT_CREATE_RATE`: Rate of documents created, `5/min` by default.
+pub fn class_policies(default: &RateLimitPolicy) -> HashMap<OperationClass, RateLimitPolicy> {
+    let default_rate = (default.limit, default.window_ms);
+    let classes = [
+        (OperationClass::Read, "RATE_LIMIT_READ_RATE", default_rate),
+        (
+            OperationClass::Stream,
+            "RATE_LIMIT_STREAM_RATE",
+            default_rate,
+        ),
+        (
+            OperationClass::Write,
+            "RATE_LIMIT_WRITE_RATE",
+            parse_rate(DEFAULT_WRITE_RATE).unwrap_or(default_rate),
+        ),
+        (
+            OperationClass::Create,
+            "RATE_LIMIT_CREATE_RATE",
+            parse_rate(DEFAULT_CREATE_RATE).unwrap_or(default_rate),
+        ),
+    ];
+
+    classes
+        .into_iter()
+        .map(|(class, key, rate)| {
+            let (limit, window_ms) = match env::var(key) {
+                Ok(value) => parse_rate(value.trim()).unwrap_or_else(|e| {
+                    warn!("{} for {}, using {}/{}ms", e, key, rate.0, rate.1);
+                    rate
+                }),
+                Err(_) => rate,
+            };
+            let policy = RateLimitPolicy {
+                name: class.as_str_name().to_lowercase(),
+                algorithm: default.algorithm,
+                limit,
+                window_ms,
+            };
+            (class, policy)
+        })
+        .collect()
+}
+
+/// Loads the policy table from the file set in `RATE_LIMIT_POLICY_FILE`
+/// (defaults to rate_limit_policies.conf), with the policies of the operation classes.
+/// A missing file results in only the class policies.
+pub fn load_policy_table() -> PolicyTable {
+    let path = env::var("RATE_LIMIT_POLICY_FILE").unwrap_or(DEFAULT_POLICY_FILE.to_string());
+    let default = default_policy();
+    let classes = class_policies(&default);
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(_) => {
+            info!(
+                "No rate limit policy file found at {}, using the operation class policies",
+                path
+            );
+            let mut table = PolicyTable::new(Vec::new(), default);
+            table.classes = classes;
+            return table;
+        }
+    };
+
+    match PolicyTable::parse(&contents, default) {
+        Ok(mut table) => {
+            info!("Loaded {} rate limit policies", table.rules.len());
+            table.classes = classes;
+            table
+        }
+        Err(e) => {
+            error!("Failed to parse {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn env_u64(key: &str, default: u64) -> u64 {
+    match env::var(key) {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(v) => v,
+            Err(_) => {
+                warn!("Invalid value for {}: {}, using {}", key, value, default);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default() -> RateLimitPolicy {
+        RateLimitPolicy {
+            name: "default".to_string(),
+            algorithm: Algorithm::TokenBucket.into(),
+            limit: 10,
+            window_ms: 1000,
+        }
+    }
+
+    #[test]
+    fn test_parse_algorithm() {
+        assert_eq!(parse_algorithm("token_bucket"), Ok(Algorithm::TokenBucket));
+        assert_eq!(
+            parse_algorithm("sliding-window-log"),
+            Ok(Algorithm::SlidingWindowLog)
+        );
+        assert_eq!(parse_algorithm("FIXED_WINDOW"), Ok(Algorithm::FixedWindow));
+        assert!(parse_algorithm("leaky_bucket").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("100/s"), Ok((100, 1000)));
+        assert_eq!(parse_rate("5/min"), Ok((5, 60_000)));
+        assert!(parse_rate("5/fortnight").is_err());
+        assert!(parse_rate("5").is_err());
+    }
+
+    #[test]
+    fn test_resolve_policy() {
+        let table = PolicyTable::parse(
+            "# comment\n\
+             document_insert POST /document/*/insert token_bucket 100/s\n\
+             create_document POST /create_document fixed_window 5/min\n\
+             document_read * /document/* sliding_window_log 50/s\n",
+            default(),
+        )
+        .unwrap();
+
+        let write = OperationClass::Write;
+        assert_eq!(table.rules.len(), 3);
+        assert_eq!(
+            table.resolve("POST", "/document/abc/insert", write).name,
+            "document_insert"
+        );
+        assert_eq!(
+            table
+                .resolve("post", "/create_document", OperationClass::Create)
+                .limit,
+            5
+        );
+        assert_eq!(
+            table
+                .resolve("GET", "/document/abc?x=1", OperationClass::Read)
+                .name,
+            "document_read"
+        );
+        assert_eq!(
+            table
+                .resolve("GET", "/create_document", OperationClass::Read)
+                .name,
+            "default"
+        );
+        assert_eq!(
+            table.resolve("POST", "/document/abc/update", write).name,
+            "default"
+        );
+    }
+
+    #[test]
+    fn test_operation_class() {
+        assert_eq!(
+            operation_class("GET", "/document/abc", None),
+            OperationClass::Read
+        );
+        assert_eq!(
+            operation_class("head", "/document/abc", None),
+            OperationClass::Read
+        );
+        assert_eq!(
+            operation_class("GET", "/document/abc/runs/1", Some("text/event-stream")),
+            OperationClass::Stream
+        );
+        assert_eq!(
+            operation_class("POST", "/create_document?x=1", None),
+            OperationClass::Create
+        );
+        assert_eq!(
+            operation_class("POST", "/document/abc/insert", None),
+            OperationClass::Write
+        );
+        assert_eq!(
+            operation_class("DELETE", "/document/abc", None),
+            OperationClass::Write
+        );
+    }
+
+    #[test]
+    fn test_class_policies() {
+        let mut table = PolicyTable::parse(
+            "document_insert POST /document/*/insert token_bucket 100/s\n",
+            default(),
+        )
+        .unwrap();
+        table.classes = class_policies(&default());
+
+        // the classes are limited under their own names, so each has its own quota
+        let read = table.resolve("GET", "/document/abc", OperationClass::Read);
+        assert_eq!((read.name.as_str(), read.limit), ("read", 10));
+        let create = table.resolve("POST", "/create_document", OperationClass::Create);
+        assert_eq!(
+            (create.name.as_str(), create.limit, create.window_ms),
+            ("create", 5, 60_000)
+        );
+        assert_eq!(
+            table
+                .resolve("POST", "/document/abc/delete", OperationClass::Write)
+                .name,
+            "write"
+        );
+        // rules take precedence over the classes
+        assert_eq!(
+            table
+                .resolve("POST", "/document/abc/insert", OperationClass::Write)
+                .name,
+            "document_insert"
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_lines() {
+        assert!(PolicyTable::parse("insert POST /document/*/insert", default()).is_err());
+        assert!(PolicyTable::parse("insert POST / magic 1/s", default()).is_err());
+    }
+}
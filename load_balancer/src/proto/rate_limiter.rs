@@ -1,5 +1,21 @@
 // This file is @generated by prost-build.
-/// Request message containing IP address, target endpoint and request ID.
+/// Policy hint describing how a request should be limited.
+/// `limit` is the bucket capacity or the maximum number of requests per window.
+/// `window_ms` is the window length, for the token bucket the time taken to refill `limit` tokens.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RateLimitPolicy {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(enumeration = "Algorithm", tag = "2")]
+    pub algorithm: i32,
+    #[prost(uint64, tag = "3")]
+    pub limit: u64,
+    #[prost(uint64, tag = "4")]
+    pub window_ms: u64,
+}
+/// Request message containing IP address, target endpoint, request ID and policy hint.
+/// `hits` is the number of requests the load balancer allowed from its verdict cache since its
+/// last check of the same client and policy, they are recorded before this request.
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct RateLimitRequest {
     #[prost(string, tag = "1")]
@@ -8,14 +24,146 @@ pub struct RateLimitRequest {
     pub endpoint: ::prost::alloc::string::String,
     #[prost(string, tag = "3")]
     pub request_id: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "4")]
+    pub policy: ::core::option::Option<RateLimitPolicy>,
+    #[prost(uint64, tag = "5")]
+    pub hits: u64,
+    #[prost(enumeration = "OperationClass", tag = "6")]
+    pub operation_class: i32,
 }
-/// Response message containing the request ID and if the request can proceed
+/// Response message containing the request ID and if the request can proceed.
+/// `limit` and `remaining` describe the policy quota, `retry_after_ms` is set when rejected.
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct RateLimitResponse {
     #[prost(string, tag = "1")]
     pub request_id: ::prost::alloc::string::String,
     #[prost(bool, tag = "2")]
     pub allowed: bool,
+    #[prost(uint64, tag = "3")]
+    pub limit: u64,
+    #[prost(uint64, tag = "4")]
+    pub remaining: u64,
+    #[prost(uint64, tag = "5")]
+    pub retry_after_ms: u64,
+}
+/// Request naming a client's quotas, of every policy when `policy_name` is empty.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct QuotaRequest {
+    #[prost(string, tag = "1")]
+    pub ip_address: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub policy_name: ::prost::alloc::string::String,
+}
+/// The state of a client's quota under a policy, read without recording a request.
+/// `remaining` is the number of requests the client can still make, `retry_after_ms` is set when
+/// it has none left. `rejected` counts the client's rejected requests under the policy.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Quota {
+    #[prost(string, tag = "1")]
+    pub ip_address: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub policy: ::core::option::Option<RateLimitPolicy>,
+    #[prost(bool, tag = "3")]
+    pub allowed: bool,
+    #[prost(uint64, tag = "4")]
+    pub remaining: u64,
+    #[prost(uint64, tag = "5")]
+    pub retry_after_ms: u64,
+    #[prost(uint64, tag = "6")]
+    pub rejected: u64,
+}
+/// The client's quotas, sorted by policy name.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetQuotaResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub quotas: ::prost::alloc::vec::Vec<Quota>,
+}
+/// `reset` is the number of quotas reset.
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct ResetQuotaResponse {
+    #[prost(uint64, tag = "1")]
+    pub reset: u64,
+}
+/// `limit` is the most offenders returned, 10 when 0.
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct ListOffendersRequest {
+    #[prost(uint32, tag = "1")]
+    pub limit: u32,
+}
+/// The quotas with the most rejected requests, most rejected first.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListOffendersResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub offenders: ::prost::alloc::vec::Vec<Quota>,
+}
+/// Rate limiting algorithm requested by the load balancer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Algorithm {
+    TokenBucket = 0,
+    SlidingWindowLog = 1,
+    FixedWindow = 2,
+}
+impl Algorithm {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::TokenBucket => "TOKEN_BUCKET",
+            Self::SlidingWindowLog => "SLIDING_WINDOW_LOG",
+            Self::FixedWindow => "FIXED_WINDOW",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "TOKEN_BUCKET" => Some(Self::TokenBucket),
+            "SLIDING_WINDOW_LOG" => Some(Self::SlidingWindowLog),
+            "FIXED_WINDOW" => Some(Self::FixedWindow),
+            _ => None,
+        }
+    }
+}
+/// The kind of operation a request performs, derived by the load balancer from its method and
+/// path. Each class has its own default policy, so a client reading documents doesn't spend the
+/// quota of its edits.
+/// `READ`: Requests for content, such as GET and HEAD.
+/// `STREAM`: Requests for a server-sent event stream, such as following a run.
+/// `WRITE`: Requests changing a document.
+/// `CREATE`: Requests creating a document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum OperationClass {
+    Read = 0,
+    Stream = 1,
+    Write = 2,
+    Create = 3,
+}
+impl OperationClass {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Read => "READ",
+            Self::Stream => "STREAM",
+            Self::Write => "WRITE",
+            Self::Create => "CREATE",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "READ" => Some(Self::Read),
+            "STREAM" => Some(Self::Stream),
+            "WRITE" => Some(Self::Write),
+            "CREATE" => Some(Self::Create),
+            _ => None,
+        }
+    }
 }
 /// Generated client implementations.
 pub mod rate_limiter_client {
@@ -24,11 +172,13 @@ pub mod rate_limiter_client {
         dead_code,
         missing_docs,
         clippy::wildcard_imports,
-        clippy::let_unit_value
+        clippy::let_unit_value,
     )]
-    use tonic::codegen::http::Uri;
     use tonic::codegen::*;
+    use tonic::codegen::http::Uri;
     /// Service definition for rate limiting
+    /// `GetQuota`, `ResetQuota` and `ListOffenders` let operators inspect the limits and unblock
+    /// clients without restarting the rate limiter.
     #[derive(Debug, Clone)]
     pub struct RateLimiterClient<T> {
         inner: tonic::client::Grpc<T>,
@@ -72,8 +222,9 @@ pub mod rate_limiter_client {
                     <T as tonic::client::GrpcService<tonic::body::BoxBody>>::ResponseBody,
                 >,
             >,
-            <T as tonic::codegen::Service<http::Request<tonic::body::BoxBody>>>::Error:
-                Into<StdError> + std::marker::Send + std::marker::Sync,
+            <T as tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+            >>::Error: Into<StdError> + std::marker::Send + std::marker::Sync,
         {
             RateLimiterClient::new(InterceptedService::new(inner, interceptor))
         }
@@ -111,18 +262,99 @@ pub mod rate_limiter_client {
         pub async fn check_request(
             &mut self,
             request: impl tonic::IntoRequest<super::RateLimitRequest>,
-        ) -> std::result::Result<tonic::Response<super::RateLimitResponse>, tonic::Status> {
-            self.inner.ready().await.map_err(|e| {
-                tonic::Status::unknown(format!("Service was not ready: {}", e.into()))
-            })?;
+        ) -> std::result::Result<
+            tonic::Response<super::RateLimitResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
             let codec = tonic::codec::ProstCodec::default();
-            let path =
-                http::uri::PathAndQuery::from_static("/rateLimiter.RateLimiter/CheckRequest");
+            let path = http::uri::PathAndQuery::from_static(
+                "/rateLimiter.RateLimiter/CheckRequest",
+            );
             let mut req = request.into_request();
             req.extensions_mut()
                 .insert(GrpcMethod::new("rateLimiter.RateLimiter", "CheckRequest"));
             self.inner.unary(req, path, codec).await
         }
+        pub async fn get_quota(
+            &mut self,
+            request: impl tonic::IntoRequest<super::QuotaRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::GetQuotaResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/rateLimiter.RateLimiter/GetQuota",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("rateLimiter.RateLimiter", "GetQuota"));
+            self.inner.unary(req, path, codec).await
+        }
+        pub async fn reset_quota(
+            &mut self,
+            request: impl tonic::IntoRequest<super::QuotaRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::ResetQuotaResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/rateLimiter.RateLimiter/ResetQuota",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("rateLimiter.RateLimiter", "ResetQuota"));
+            self.inner.unary(req, path, codec).await
+        }
+        pub async fn list_offenders(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ListOffendersRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::ListOffendersResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/rateLimiter.RateLimiter/ListOffenders",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("rateLimiter.RateLimiter", "ListOffenders"));
+            self.inner.unary(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -132,7 +364,7 @@ pub mod rate_limiter_server {
         dead_code,
         missing_docs,
         clippy::wildcard_imports,
-        clippy::let_unit_value
+        clippy::let_unit_value,
     )]
     use tonic::codegen::*;
     /// Generated trait containing gRPC methods that should be implemented for use with RateLimiterServer.
@@ -141,9 +373,35 @@ pub mod rate_limiter_server {
         async fn check_request(
             &self,
             request: tonic::Request<super::RateLimitRequest>,
-        ) -> std::result::Result<tonic::Response<super::RateLimitResponse>, tonic::Status>;
+        ) -> std::result::Result<
+            tonic::Response<super::RateLimitResponse>,
+            tonic::Status,
+        >;
+        async fn get_quota(
+            &self,
+            request: tonic::Request<super::QuotaRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::GetQuotaResponse>,
+            tonic::Status,
+        >;
+        async fn reset_quota(
+            &self,
+            request: tonic::Request<super::QuotaRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::ResetQuotaResponse>,
+            tonic::Status,
+        >;
+        async fn list_offenders(
+            &self,
+            request: tonic::Request<super::ListOffendersRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::ListOffendersResponse>,
+            tonic::Status,
+        >;
     }
     /// Service definition for rate limiting
+    /// `GetQuota`, `ResetQuota` and `ListOffenders` let operators inspect the limits and unblock
+    /// clients without restarting the rate limiter.
     #[derive(Debug)]
     pub struct RateLimiterServer<T> {
         inner: Arc<T>,
@@ -165,7 +423,10 @@ pub mod rate_limiter_server {
                 max_encoding_message_size: None,
             }
         }
-        pub fn with_interceptor<F>(inner: T, interceptor: F) -> InterceptedService<Self, F>
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> InterceptedService<Self, F>
         where
             F: tonic::service::Interceptor,
         {
@@ -220,9 +481,15 @@ pub mod rate_limiter_server {
                 "/rateLimiter.RateLimiter/CheckRequest" => {
                     #[allow(non_camel_case_types)]
                     struct CheckRequestSvc<T: RateLimiter>(pub Arc<T>);
-                    impl<T: RateLimiter> tonic::server::UnaryService<super::RateLimitRequest> for CheckRequestSvc<T> {
+                    impl<
+                        T: RateLimiter,
+                    > tonic::server::UnaryService<super::RateLimitRequest>
+                    for CheckRequestSvc<T> {
                         type Response = super::RateLimitResponse;
-                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
                         fn call(
                             &mut self,
                             request: tonic::Request<super::RateLimitRequest>,
@@ -256,19 +523,154 @@ pub mod rate_limiter_server {
                     };
                     Box::pin(fut)
                 }
-                _ => Box::pin(async move {
-                    let mut response = http::Response::new(empty_body());
-                    let headers = response.headers_mut();
-                    headers.insert(
-                        tonic::Status::GRPC_STATUS,
-                        (tonic::Code::Unimplemented as i32).into(),
-                    );
-                    headers.insert(
-                        http::header::CONTENT_TYPE,
-                        tonic::metadata::GRPC_CONTENT_TYPE,
-                    );
-                    Ok(response)
-                }),
+                "/rateLimiter.RateLimiter/GetQuota" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetQuotaSvc<T: RateLimiter>(pub Arc<T>);
+                    impl<T: RateLimiter> tonic::server::UnaryService<super::QuotaRequest>
+                    for GetQuotaSvc<T> {
+                        type Response = super::GetQuotaResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::QuotaRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as RateLimiter>::get_quota(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = GetQuotaSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/rateLimiter.RateLimiter/ResetQuota" => {
+                    #[allow(non_camel_case_types)]
+                    struct ResetQuotaSvc<T: RateLimiter>(pub Arc<T>);
+                    impl<T: RateLimiter> tonic::server::UnaryService<super::QuotaRequest>
+                    for ResetQuotaSvc<T> {
+                        type Response = super::ResetQuotaResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::QuotaRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as RateLimiter>::reset_quota(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = ResetQuotaSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/rateLimiter.RateLimiter/ListOffenders" => {
+                    #[allow(non_camel_case_types)]
+                    struct ListOffendersSvc<T: RateLimiter>(pub Arc<T>);
+                    impl<
+                        T: RateLimiter,
+                    > tonic::server::UnaryService<super::ListOffendersRequest>
+                    for ListOffendersSvc<T> {
+                        type Response = super::ListOffendersResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ListOffendersRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as RateLimiter>::list_offenders(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = ListOffendersSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => {
+                    Box::pin(async move {
+                        let mut response = http::Response::new(empty_body());
+                        let headers = response.headers_mut();
+                        headers
+                            .insert(
+                                tonic::Status::GRPC_STATUS,
+                                (tonic::Code::Unimplemented as i32).into(),
+                            );
+                        headers
+                            .insert(
+                                http::header::CONTENT_TYPE,
+                                tonic::metadata::GRPC_CONTENT_TYPE,
+                            );
+                        Ok(response)
+                    })
+                }
             }
         }
     }
@@ -0,0 +1,187 @@
+//! Reporting how much of the keyspace changes owner when the nodes change.
+//!
+//! Clients are hashed onto the ring by their address (`LoadBalancer::get_node`), each key being
+//! owned by the first node point at or after it. Comparing two rings point by point gives the
+//! ranges of keys that would be sent to a different replica, and the fraction of the keyspace
+//! they cover, which is logged on every reload and returned by the dry run admin endpoint.
+
+use std::collections::BTreeMap;
+
+/// Number of keys on the ring (every `u64` hash).
+const KEYSPACE: f64 = u64::MAX as f64 + 1.0;
+
+/// A range of keys that changes owner.
+/// `start` and `end` are the first and last hash of the range, inclusive. A range with `start`
+/// greater than `end` wraps around the end of the ring.
+/// `from`: The replica the keys are sent to now, None if the ring is empty.
+/// `to`: The replica the keys would be sent to, None if the ring would be empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRange {
+    pub start: u64,
+    pub end: u64,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+/// The keys that change owner between two rings.
+/// `moved_fraction`: The fraction of the keyspace in `ranges`, between 0 and 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rebalance {
+    pub ranges: Vec<KeyRange>,
+    pub moved_fraction: f64,
+}
+
+impl Rebalance {
+    /// Compares the ring requests are routed with now to the one they would be routed with.
+    pub fn compare(before: &BTreeMap<u64, String>, after: &BTreeMap<u64, String>) -> Self {
+        // ownership only changes at the points of either ring
+        let mut points: Vec<u64> = before.keys().chain(after.keys()).copied().collect();
+        points.sort_unstable();
+        points.dedup();
+
+        if points.is_empty() {
+            return Rebalance {
+                ranges: Vec::new(),
+                moved_fraction: 0.0,
+            };
+        }
+
+        let mut ranges: Vec<KeyRange> = Vec::new();
+        let mut moved: f64 = 0.0;
+        for (i, &end) in points.iter().enumerate() {
+            // the keys after the previous point, up to and including this one, share an owner.
+            // The first range wraps around from the last point.
+            let previous: u64 = points[(i + points.len() - 1) % points.len()];
+            let from = owner(before, end);
+            let to = owner(after, end);
+            if from == to {
+                continue;
+            }
+
+            moved += match end.wrapping_sub(previous) {
+                // a single point owns the whole ring
+                0 => KEYSPACE,
+                size => size as f64,
+            };
+            let start: u64 = previous.wrapping_add(1);
+            match ranges.last_mut() {
+                Some(last)
+                    if last.end.wrapping_add(1) == start && last.from == from && last.to == to =>
+                {
+                    last.end = end;
+                }
+                _ => ranges.push(KeyRange {
+                    start,
+                    end,
+                    from,
+                    to,
+                }),
+            }
+        }
+
+        // the wrapping range and the last range are adjacent around the end of the ring
+        if ranges.len() > 1 {
+            let last = ranges[ranges.len() - 1].clone();
+            let first = &mut ranges[0];
+            if last.end.wrapping_add(1) == first.start
+                && last.from == first.from
+                && last.to == first.to
+            {
+                first.start = last.start;
+                ranges.pop();
+            }
+        }
+
+        Rebalance {
+            ranges,
+            moved_fraction: moved / KEYSPACE,
+        }
+    }
+
+    /// Renders the rebalance as a JSON object. Hashes are hexadecimal strings, as JSON numbers
+    /// lose precision above 2^53.
+    pub fn to_json(&self) -> String {
+        let string = |value: &Option<String>| match value {
+            Some(value) => format!("\"{}\"", value),
+            None => "null".to_string(),
+        };
+        let ranges = self
+            .ranges
+            .iter()
+            .map(|range| {
+                format!(
+                    "{{\"start\":\"{:016x}\",\"end\":\"{:016x}\",\"from\":{},\"to\":{}}}",
+                    range.start,
+                    range.end,
+                    string(&range.from),
+                    string(&range.to)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+
+        format!(
+            "{{\"moved_fraction\":{:.6},\"ranges\":[{}]}}",
+            self.moved_fraction, ranges
+        )
+    }
+}
+
+/// The node owning `key`: the first point at or after it, wrapping around to the first point.
+fn owner(ring: &BTreeMap<u64, String>, key: u64) -> Option<String> {
+    ring.range(key..)
+        .next()
+        .or_else(|| ring.iter().next())
+        .map(|(_, node)| node.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(points: &[(u64, &str)]) -> BTreeMap<u64, String> {
+        points
+            .iter()
+            .map(|(point, node)| (*point, node.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_compare() {
+        let before = ring(&[(100, "a"), (u64::MAX / 2, "b")]);
+        assert!(Rebalance::compare(&before, &before).ranges.is_empty());
+
+        // c takes the keys after 100 up to its point from b
+        let after = ring(&[(100, "a"), (1000, "c"), (u64::MAX / 2, "b")]);
+        let rebalance = Rebalance::compare(&before, &after);
+        assert_eq!(
+            rebalance.ranges,
+            vec![KeyRange {
+                start: 101,
+                end: 1000,
+                from: Some("b".to_string()),
+                to: Some("c".to_string()),
+            }]
+        );
+        assert!(rebalance.moved_fraction > 0.0 && rebalance.moved_fraction < 0.001);
+
+        // removing a hands its keys, which wrap around the end of the ring, to b
+        let after = ring(&[(u64::MAX / 2, "b")]);
+        let rebalance = Rebalance::compare(&before, &after);
+        assert_eq!(
+            rebalance.ranges,
+            vec![KeyRange {
+                start: u64::MAX / 2 + 1,
+                end: 100,
+                from: Some("a".to_string()),
+                to: Some("b".to_string()),
+            }]
+        );
+        assert!((rebalance.moved_fraction - 0.5).abs() < 0.001);
+
+        // everything moves when the ring is emptied
+        let rebalance = Rebalance::compare(&after, &BTreeMap::new());
+        assert_eq!(rebalance.ranges.len(), 1);
+        assert_eq!(rebalance.moved_fraction, 1.0);
+    }
+}
@@ -0,0 +1,245 @@
+//! Reloading of the replicas requests are distributed to, without restarting.
+//!
+//! Nodes are configured with `NODE<n>` variables (with optional `NODE<n>_REGION`,
+//! `NODE<n>_WEIGHT` and `NODE<n>_POOL`) in the environment or the `.env` file, the environment winning like it does
+//! for every other setting. On `SIGHUP`, and when the file's modification time changes, the file
+//! is read again and the rings are rebuilt. A request being proxied holds the load balancer
+//! until it is answered, so the rings are swapped between requests and no connection is
+//! dropped. An invalid file, or one without nodes, leaves the current nodes in place.
+
+use crate::load_balancer::consistent_hashing::{LoadBalancer, Node};
+use std::collections::BTreeMap;
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, warn};
+
+const DEFAULT_RELOAD_INTERVAL_SECS: u64 = 5;
+
+/// Where the nodes are read from.
+/// `environment`: The variables the process was started with, before the `.env` file was
+/// loaded into it.
+/// `path`: The `.env` file, None if there is none.
+pub struct NodeSource {
+    environment: BTreeMap<String, String>,
+    path: Option<PathBuf>,
+}
+
+impl NodeSource {
+    pub fn new(environment: BTreeMap<String, String>, path: Option<PathBuf>) -> Self {
+        NodeSource { environment, path }
+    }
+
+    /// Reads the nodes from the file and the environment.
+    // from_path would load the file into the process environment, which can't tell a removed
+    // node from one still set there
+    #[allow(deprecated)]
+    pub fn load(&self) -> Result<Vec<Node>, String> {
+        let mut vars: BTreeMap<String, String> = BTreeMap::new();
+        if let Some(path) = &self.path {
+            let entries = dotenv::from_path_iter(path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            for entry in entries {
+                let (key, value) =
+                    entry.map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+                vars.insert(key, value);
+            }
+        }
+        vars.extend(self.environment.clone());
+        parse_nodes(&vars)
+    }
+
+    /// When the file was last modified, None without a file.
+    fn modified(&self) -> Option<SystemTime> {
+        let path = self.path.as_ref()?;
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+}
+
+/// Parses the nodes from `NODE<n>`, `NODE<n>_REGION`, `NODE<n>_WEIGHT` and `NODE<n>_POOL`
+/// variables, in the order of their names.
+pub fn parse_nodes(vars: &BTreeMap<String, String>) -> Result<Vec<Node>, String> {
+    let mut nodes: Vec<Node> = Vec::new();
+
+    for (key, value) in vars {
+        let suffixes = ["_REGION", "_WEIGHT", "_POOL"];
+        if !key.starts_with("NODE") || suffixes.iter().any(|suffix| key.ends_with(suffix)) {
+            continue;
+        }
+        let label = |suffix: &str| {
+            vars.get(&format!("{}{}", key, suffix))
+                .filter(|label| !label.is_empty())
+                .cloned()
+        };
+        let weight: u32 = match vars.get(&format!("{}_WEIGHT", key)) {
+            None => 1,
+            Some(weight) => match weight.trim().parse() {
+                Ok(weight) if weight > 0 => weight,
+                _ => return Err(format!("Invalid {}_WEIGHT {}", key, weight)),
+            },
+        };
+        nodes.push(
+            Node::new(value.clone(), label("_REGION"))
+                .with_weight(weight)
+                .with_pool(label("_POOL")),
+        );
+    }
+
+    Ok(nodes)
+}
+
+/// Reloads the nodes, keeping the current ones if the new ones can't be read.
+pub async fn reload(state: &Mutex<LoadBalancer>, source: &NodeSource) {
+    let nodes: Vec<Node> = match source.load() {
+        Ok(nodes) if nodes.is_empty() => {
+            error!("No nodes configured, keeping the current nodes");
+            return;
+        }
+        Ok(nodes) => nodes,
+        Err(e) => {
+            error!("{}, keeping the current nodes", e);
+            return;
+        }
+    };
+
+    let count: usize = nodes.len();
+    match state.lock().await.set_nodes(nodes) {
+        Some(rebalance) => {
+            info!(
+                moved_fraction = rebalance.moved_fraction,
+                moved_ranges = rebalance.ranges.len(),
+                "Reloaded {} nodes and rebuilt the ring, {:.2}% of the keyspace moved",
+                count,
+                rebalance.moved_fraction * 100.0
+            );
+            for range in &rebalance.ranges {
+                debug!(
+                    "Keys {:016x}..={:016x} moved from {:?} to {:?}",
+                    range.start, range.end, range.from, range.to
+                );
+            }
+        }
+        None => info!("Nodes unchanged"),
+    }
+}
+
+/// Reloads the nodes on `SIGHUP` and when the `.env` file changes, checked every
+/// `LB_RELOAD_INTERVAL_SECS` (5 by default, 0 only reloads on `SIGHUP`).
+pub async fn watch(state: Arc<Mutex<LoadBalancer>>, source: NodeSource) {
+    let interval: Option<Duration> = match env::var("LB_RELOAD_INTERVAL_SECS") {
+        Err(_) => Some(Duration::from_secs(DEFAULT_RELOAD_INTERVAL_SECS)),
+        Ok(value) => match value.trim().parse::<u64>() {
+            Ok(0) => None,
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => {
+                warn!(
+                    "Invalid LB_RELOAD_INTERVAL_SECS {}, using the default",
+                    value
+                );
+                Some(Duration::from_secs(DEFAULT_RELOAD_INTERVAL_SECS))
+            }
+        },
+    };
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(_) => {
+            error!("Failed to listen for SIGHUP, nodes are only reloaded when the file changes");
+            None
+        }
+    };
+
+    let mut modified: Option<SystemTime> = source.modified();
+    loop {
+        tokio::select! {
+            _ = hung_up(&mut hangup) => {
+                info!("SIGHUP received, reloading the nodes");
+            }
+            _ = tick(interval) => {
+                if source.modified() == modified {
+                    continue;
+                }
+                info!("Configuration file changed, reloading the nodes");
+            }
+        }
+        modified = source.modified();
+        reload(&state, &source).await;
+    }
+}
+
+/// Waits for the next `SIGHUP`, forever if it can't be received.
+async fn hung_up(hangup: &mut Option<Signal>) {
+    match hangup {
+        Some(hangup) => {
+            hangup.recv().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the next check of the file, forever without an interval.
+async fn tick(interval: Option<Duration>) {
+    match interval {
+        Some(interval) => tokio::time::sleep(interval).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_nodes() {
+        let nodes = parse_nodes(&vars(&[
+            ("NODE2", "127.0.0.1:7879"),
+            ("NODE1", "127.0.0.1:7878"),
+            ("NODE1_REGION", "af-south-1"),
+            ("NODE2_WEIGHT", "3"),
+            ("NODE2_POOL", "v2"),
+            ("LB_REGION", "af-south-1"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            nodes,
+            vec![
+                Node::new("127.0.0.1:7878".to_string(), Some("af-south-1".to_string())),
+                Node::new("127.0.0.1:7879".to_string(), None)
+                    .with_weight(3)
+                    .with_pool(Some("v2".to_string())),
+            ]
+        );
+        assert_eq!(nodes[1].points().len(), 3);
+
+        assert!(parse_nodes(&vars(&[("NODE1", "a"), ("NODE1_WEIGHT", "0")])).is_err());
+        assert!(parse_nodes(&vars(&[("NODE1", "a"), ("NODE1_WEIGHT", "x")])).is_err());
+    }
+
+    #[test]
+    fn test_environment_overrides_file() {
+        let path = env::temp_dir().join(format!("lb-reload-{}.env", std::process::id()));
+        std::fs::write(&path, "NODE1=127.0.0.1:7878\nNODE2=127.0.0.1:7879\n").unwrap();
+
+        let source = NodeSource::new(vars(&[("NODE2", "10.0.0.2:8000")]), Some(path.clone()));
+        let addresses: Vec<String> = source
+            .load()
+            .unwrap()
+            .into_iter()
+            .map(|node| node.address)
+            .collect();
+        assert_eq!(addresses, vec!["127.0.0.1:7878", "10.0.0.2:8000"]);
+
+        std::fs::write(&path, "NODE1=127.0.0.1:7878\nNODE1_WEIGHT=-1\n").unwrap();
+        assert!(source.load().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,7 +1,20 @@
 use core::str;
 use std::fmt::Display;
+use tracing::{info, warn};
 use uuid::Uuid;
 
+/// Header carrying the request id to the replica and back to the client.
+pub const REQUEST_ID_HEADER: &str = "X-Request-ID";
+
+/// Headers carrying credentials, their values are never logged.
+const CREDENTIAL_HEADERS: [&str; 5] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-session-token",
+    "x-share-token",
+];
+
 pub struct Request {
     pub request_id: Uuid,
     pub client_ip: String,
@@ -21,7 +34,7 @@ impl Request {
         // add the request ID to the headers
         request
             .headers_mut()
-            .insert("X-Request-ID", request_id.to_string().parse().unwrap());
+            .insert(REQUEST_ID_HEADER, request_id.to_string().parse().unwrap());
 
         Request {
             request_id,
@@ -30,6 +43,31 @@ impl Request {
             request,
         }
     }
+
+    /// Describes the request for the logs: its id, method, path and headers. The query and body
+    /// are left out and credential headers are redacted, as they can carry tokens.
+    pub fn describe(&self) -> String {
+        let headers: Vec<String> = self
+            .request
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                if CREDENTIAL_HEADERS.contains(&name.as_str()) {
+                    format!("{}: [redacted]", name)
+                } else {
+                    format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes()))
+                }
+            })
+            .collect();
+
+        format!(
+            "Request: {{request_id: {}, method: {}, path: {}, headers: [{}]}}",
+            self.request_id,
+            self.request.method(),
+            self.request.uri().path(),
+            headers.join(", ")
+        )
+    }
 }
 
 pub fn buffer_to_request(
@@ -37,19 +75,25 @@ pub fn buffer_to_request(
     client_ip: String,
     request_id: i64,
 ) -> Result<http::Request<Vec<u8>>, String> {
-    let http_request: HttpRequest = match HttpRequest::new(&buffer, client_ip, request_id) {
-        Ok(r) => r,
-        Err(e) => return Err(e),
+    let http_request: HttpRequest = HttpRequest::new(&buffer, client_ip, request_id)?;
+
+    // the body is everything after the blank line following the headers
+    let body: Vec<u8> = match buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(index) => buffer[index + 4..].to_vec(),
+        None => Vec::new(),
     };
 
-    println!("{http_request}");
+    let mut builder = http::Request::builder()
+        .method(http_request.method.to_string().as_str())
+        .uri(http_request.uri.as_str());
+
+    for header in &http_request.headers {
+        if let Some((name, value)) = header.split_once(':') {
+            builder = builder.header(name.trim(), value.trim());
+        }
+    }
 
-    let body: Vec<u8> = Vec::new();
-    return Ok(http::Request::builder()
-        .method("GET")
-        .uri("/")
-        .body(body)
-        .unwrap());
+    builder.body(body).map_err(|e| e.to_string())
 }
 
 #[derive(Debug)]
@@ -57,16 +101,22 @@ pub struct Clock {
     lamport_timestamp: i64,
 }
 
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Clock {
     pub fn new() -> Self {
-        return Clock {
+        Clock {
             lamport_timestamp: 0,
-        };
+        }
     }
     pub fn increment_time(&mut self) -> i64 {
         let temp: i64 = self.lamport_timestamp;
         self.lamport_timestamp += 1;
-        return temp;
+        temp
     }
 }
 
@@ -133,8 +183,8 @@ impl Display for HttpRequest {
 
 impl HttpRequest {
     pub fn print(&self) {
-        println!("{} New Request:", ">>");
-        println!("{}{}", self.method.to_string(), self.uri);
+        info!(">> New Request:");
+        info!("{}{}", self.method, self.uri);
     }
 
     pub fn new(buffer: &[u8], client_ip: String, request_id: i64) -> Result<HttpRequest, String> {
@@ -145,7 +195,7 @@ impl HttpRequest {
         let request: Vec<&str> = request.lines().collect();
 
         if request.len() < 3 {
-            eprintln!("Recieved invalid request");
+            warn!("Recieved invalid request");
             return Err(String::from("Invalid request"));
         }
 
@@ -175,14 +225,14 @@ impl HttpRequest {
             }
         }
 
-        return Ok(HttpRequest {
+        Ok(HttpRequest {
             request_id,
             client_ip,
             headers,
             body,
             method,
             uri,
-        });
+        })
     }
 
     pub fn is_compression_supported(&self) -> bool {
@@ -198,7 +248,7 @@ impl HttpRequest {
                     // multiple compression types
                     let mut encodings: Vec<&str> =
                         header.split(", ").map(|m| m.trim()).collect::<Vec<&str>>();
-                    encodings[0] = &encodings[0].split_whitespace().collect::<Vec<&str>>()[1];
+                    encodings[0] = encodings[0].split_whitespace().collect::<Vec<&str>>()[1];
 
                     for encoding in encodings {
                         if encoding == "gzip" || encoding.contains("gzip") {
@@ -217,11 +267,11 @@ impl HttpRequest {
                 }
             }
         }
-        return false;
+        false
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum HttpCode {
     Ok,
     Created,
@@ -250,50 +300,7 @@ impl Display for HttpCode {
     }
 }
 
-impl PartialEq for HttpCode {
-    fn eq(&self, other: &Self) -> bool {
-        match self {
-            HttpCode::Ok => match other {
-                HttpCode::Ok => true,
-                _ => false,
-            },
-            HttpCode::Created => match other {
-                HttpCode::Created => true,
-                _ => false,
-            },
-            HttpCode::BadRequest => match other {
-                HttpCode::BadRequest => true,
-                _ => false,
-            },
-            HttpCode::Unauthorized => match other {
-                HttpCode::Unauthorized => true,
-                _ => false,
-            },
-            HttpCode::NotFound => match other {
-                HttpCode::NotFound => true,
-                _ => false,
-            },
-            HttpCode::MethodNotAllowed => match other {
-                HttpCode::MethodNotAllowed => true,
-                _ => false,
-            },
-            HttpCode::RequestTimeout => match other {
-                HttpCode::RequestTimeout => true,
-                _ => false,
-            },
-            HttpCode::Teapot => match other {
-                HttpCode::Teapot => true,
-                _ => false,
-            },
-            HttpCode::InternalServerError => match other {
-                HttpCode::InternalServerError => true,
-                _ => false,
-            },
-        }
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum HttpMethod {
     GET,
     POST,
@@ -330,29 +337,28 @@ impl Display for HttpMethod {
     }
 }
 
-impl PartialEq for HttpMethod {
-    fn eq(&self, other: &Self) -> bool {
-        match self {
-            HttpMethod::GET => match other {
-                HttpMethod::GET => true,
-                _ => false,
-            },
-            HttpMethod::POST => match other {
-                HttpMethod::POST => true,
-                _ => false,
-            },
-            HttpMethod::PUT => match other {
-                HttpMethod::PUT => true,
-                _ => false,
-            },
-            HttpMethod::PATCH => match other {
-                HttpMethod::PATCH => true,
-                _ => false,
-            },
-            HttpMethod::DELETE => match other {
-                HttpMethod::DELETE => true,
-                _ => false,
-            },
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_redacts_credentials() {
+        let request = http::Request::get("/document/1?share=secret-share")
+            .header("Authorization", "Bearer secret-bearer")
+            .header("X-Session-Token", "secret-session")
+            .header("X-Share-Token", "secret-share")
+            .header("Cookie", "session=secret-cookie")
+            .header("Accept", "application/json")
+            .body(b"secret-body".to_vec())
+            .unwrap();
+        let request = Request::new("/document/1".to_string(), "127.0.0.1".to_string(), request);
+
+        let description = request.describe();
+        assert!(description.contains(&request.request_id.to_string()));
+        assert!(description.contains("method: GET"));
+        assert!(description.contains("path: /document/1,"));
+        assert!(description.contains("accept: application/json"));
+        assert!(description.contains("authorization: [redacted]"));
+        assert!(!description.contains("secret"), "{}", description);
     }
 }
@@ -0,0 +1,165 @@
+use crate::rate_limiter_proto::RateLimitResponse;
+
+/// Inserts headers into a raw HTTP/1.1 response directly after the status line.
+/// Responses without a status line are returned unchanged.
+pub fn insert_headers(response: Vec<u8>, headers: &[(String, String)]) -> Vec<u8> {
+    let status_end = match response.windows(2).position(|w| w == b"\r\n") {
+        Some(index) => index + 2,
+        None => return response,
+    };
+
+    let mut result: Vec<u8> = Vec::with_capacity(response.len() + headers.len() * 32);
+    result.extend_from_slice(&response[..status_end]);
+    for (name, value) in headers {
+        result.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    result.extend_from_slice(&response[status_end..]);
+    result
+}
+
+/// Sets a header on a raw HTTP/1.1 response, replacing any value the replica already set.
+pub fn set_header(response: Vec<u8>, name: &str, value: &str) -> Vec<u8> {
+    let headers_end = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(index) => index + 2,
+        None => return insert_headers(response, &[(name.to_string(), value.to_string())]),
+    };
+
+    let mut result: Vec<u8> = Vec::with_capacity(response.len() + name.len() + value.len() + 4);
+    let mut lines = response[..headers_end].split_inclusive(|b| *b == b'\n');
+    if let Some(status_line) = lines.next() {
+        result.extend_from_slice(status_line);
+    }
+    result.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    for line in lines {
+        let is_named = line
+            .split(|b| *b == b':')
+            .next()
+            .is_some_and(|header| header.eq_ignore_ascii_case(name.as_bytes()));
+        if !is_named {
+            result.extend_from_slice(line);
+        }
+    }
+    result.extend_from_slice(&response[headers_end..]);
+    result
+}
+
+/// Reads a header from a raw HTTP/1.1 response, None if the replica didn't set it.
+pub fn header(response: &[u8], name: &str) -> Option<String> {
+    let headers_end = response.windows(4).position(|w| w == b"\r\n\r\n")?;
+    String::from_utf8_lossy(&response[..headers_end])
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+/// Reads the status code from the status line of a raw HTTP/1.1 response.
+pub fn status_code(response: &[u8]) -> Option<u16> {
+    response
+        .split(|b| *b == b' ')
+        .nth(1)
+        .and_then(|code| std::str::from_utf8(code).ok())
+        .and_then(|code| code.parse().ok())
+}
+
+/// Builds the standard rate limit headers from the rate limiter verdict.
+/// `Retry-After` is only included when the request was rejected.
+pub fn rate_limit_headers(verdict: &RateLimitResponse) -> Vec<(String, String)> {
+    let mut headers = vec![
+        ("X-RateLimit-Limit".to_string(), verdict.limit.to_string()),
+        (
+            "X-RateLimit-Remaining".to_string(),
+            verdict.remaining.to_string(),
+        ),
+    ];
+
+    if !verdict.allowed {
+        // Retry-After is in whole seconds, round up so clients don't retry too early
+        let seconds = verdict.retry_after_ms.div_ceil(1000).max(1);
+        headers.push(("Retry-After".to_string(), seconds.to_string()));
+    }
+
+    headers
+}
+
+/// Builds the 429 response sent when the rate limiter rejects a request.
+pub fn too_many_requests(verdict: &RateLimitResponse) -> Vec<u8> {
+    insert_headers(
+        "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\n\r\n"
+            .to_string()
+            .into_bytes(),
+        &rate_limit_headers(verdict),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_headers() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi".to_vec();
+        let response = insert_headers(response, &[("X-Test".to_string(), "1".to_string())]);
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            "HTTP/1.1 200 OK\r\nX-Test: 1\r\nContent-Length: 2\r\n\r\nhi"
+        );
+
+        assert_eq!(
+            insert_headers(b"garbage".to_vec(), &[]),
+            b"garbage".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_set_header() {
+        let response =
+            b"HTTP/1.1 200 OK\r\nx-request-id: old\r\nContent-Length: 2\r\n\r\nhi".to_vec();
+        let response = set_header(response, "X-Request-ID", "new");
+        assert_eq!(
+            String::from_utf8(response.clone()).unwrap(),
+            "HTTP/1.1 200 OK\r\nX-Request-ID: new\r\nContent-Length: 2\r\n\r\nhi"
+        );
+        assert_eq!(status_code(&response), Some(200));
+        assert_eq!(header(&response, "x-request-id"), Some("new".to_string()));
+        assert_eq!(header(&response, "Cache-Control"), None);
+
+        let response = set_header(
+            b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            "X-Request-ID",
+            "1",
+        );
+        assert_eq!(
+            String::from_utf8(response.clone()).unwrap(),
+            "HTTP/1.1 500 Internal Server Error\r\nX-Request-ID: 1\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(status_code(&response), Some(500));
+        assert_eq!(status_code(b"garbage"), None);
+    }
+
+    #[test]
+    fn test_rate_limit_headers() {
+        let mut verdict = RateLimitResponse {
+            request_id: "1".to_string(),
+            allowed: true,
+            limit: 100,
+            remaining: 42,
+            retry_after_ms: 0,
+        };
+        let headers = rate_limit_headers(&verdict);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[1].1, "42");
+
+        verdict.allowed = false;
+        verdict.remaining = 0;
+        verdict.retry_after_ms = 1500;
+        let headers = rate_limit_headers(&verdict);
+        assert_eq!(headers[2], ("Retry-After".to_string(), "2".to_string()));
+
+        let response = String::from_utf8(too_many_requests(&verdict)).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 429 Too Many Requests\r\nX-RateLimit-Limit: 100\r\n")
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use crate::response;
+use std::collections::HashMap;
+use std::env;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// Headers identifying who a request is made by or the access it carries.
+const IDENTITY_HEADERS: [&str; 5] = [
+    "authorization",
+    "cookie",
+    "x-session-token",
+    "x-share-token",
+    "x-user-id",
+];
+
+/// The key of a cached response: the path with its query, and the hash of the request's
+/// [`IDENTITY_HEADERS`] so users only get the responses made for them.
+pub type CacheKey = (String, u64);
+
+/// A response from a replica, reused until it expires.
+struct CachedResponse {
+    response: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+}
+
+/// In-memory cache of `200 OK` responses to GET requests, so repeated reads such as everyone
+/// in a screen share fetching the same document don't all reach the replicas.
+/// - Responses are reused for up to `ttl`, shortened by a `max-age` in the replica's
+///   `Cache-Control` header. Responses marked `no-store`, `no-cache` or `max-age=0` are not
+///   stored.
+/// - Requests sent with `Cache-Control: no-cache` or `no-store` skip the cache.
+/// - A request with any other method evicts the responses cached for its resource (the first
+///   two path segments, such as `/document/<id>`).
+/// - When more than `max_entries` responses or `max_bytes` are cached, expired responses are
+///   evicted first, then the oldest.
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    max_bytes: usize,
+    bytes: usize,
+    entries: HashMap<CacheKey, CachedResponse>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl ResponseCache {
+    /// Caches responses for up to `ttl`, a zero `ttl` disables the cache.
+    pub fn new(ttl: Duration, max_entries: usize, max_bytes: usize) -> Self {
+        ResponseCache {
+            ttl,
+            max_entries,
+            max_bytes,
+            bytes: 0,
+            entries: HashMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Builds the cache from the environment (.env file).
+    /// `LB_RESPONSE_CACHE_TTL_MS`: How long responses are reused, 0 (the default) disables
+    /// the cache.
+    /// `LB_RESPONSE_CACHE_MAX_ENTRIES`: Most responses cached, 1000 by default.
+    /// `LB_RESPONSE_CACHE_MAX_BYTES`: Most bytes cached, 64 MiB by default.
+    pub fn from_env() -> Self {
+        let cache = ResponseCache::new(
+            Duration::from_millis(env_usize("LB_RESPONSE_CACHE_TTL_MS", 0) as u64),
+            env_usize("LB_RESPONSE_CACHE_MAX_ENTRIES", DEFAULT_MAX_ENTRIES),
+            env_usize("LB_RESPONSE_CACHE_MAX_BYTES", DEFAULT_MAX_BYTES),
+        );
+        if cache.is_enabled() {
+            info!("Caching GET responses for {:?}", cache.ttl);
+        }
+        cache
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The bytes of the cached responses.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Returns the key of a request whose response may be cached, None if it may not.
+    pub fn key(request: &http::Request<Vec<u8>>) -> Option<CacheKey> {
+        if request.method() != http::Method::GET {
+            return None;
+        }
+        let bypass = request
+            .headers()
+            .get_all(http::header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| directives(value).any(|d| d == "no-cache" || d == "no-store"));
+        if bypass {
+            return None;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        for name in IDENTITY_HEADERS {
+            let values: Vec<&[u8]> = request
+                .headers()
+                .get_all(name)
+                .iter()
+                .map(|value| value.as_bytes())
+                .collect();
+            values.hash(&mut hasher);
+        }
+        let path = request
+            .uri()
+            .path_and_query()
+            .map_or("/", |pq| pq.as_str())
+            .to_string();
+        Some((path, hasher.finish()))
+    }
+
+    /// Returns the cached response for the key, if it hasn't expired.
+    pub fn lookup(&mut self, key: &CacheKey, now: Instant) -> Option<Vec<u8>> {
+        match self.entries.get(key) {
+            Some(entry) if now < entry.expires => {
+                self.hits += 1;
+                Some(entry.response.clone())
+            }
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Stores the replica's response to the request with the key, if it may be cached.
+    pub fn store(&mut self, key: CacheKey, response: &[u8], now: Instant) {
+        if response::status_code(response) != Some(200) || response.len() > self.max_bytes {
+            return;
+        }
+
+        let mut ttl = self.ttl;
+        let cache_control = response::header(response, "Cache-Control").unwrap_or_default();
+        for directive in directives(&cache_control) {
+            match directive.split_once('=') {
+                Some(("max-age", seconds)) => match seconds.parse::<u64>() {
+                    Ok(seconds) => ttl = ttl.min(Duration::from_secs(seconds)),
+                    Err(_) => return,
+                },
+                _ if directive == "no-store" || directive == "no-cache" => return,
+                _ => {}
+            }
+        }
+        if ttl.is_zero() {
+            return;
+        }
+
+        self.remove(&key);
+        self.bytes += response.len();
+        self.entries.insert(
+            key,
+            CachedResponse {
+                response: response.to_vec(),
+                stored: now,
+                expires: now + ttl,
+            },
+        );
+        self.evict(now);
+    }
+
+    /// Evicts the responses cached for the resource of the path.
+    pub fn invalidate(&mut self, path: &str) {
+        let written = resource(path);
+        let keys: Vec<CacheKey> = self
+            .entries
+            .keys()
+            .filter(|(cached, _)| resource(cached) == written)
+            .cloned()
+            .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    /// Evicts every cached response.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.response.len();
+        }
+    }
+
+    /// Evicts expired responses, then the oldest, until the cache is within its bounds.
+    fn evict(&mut self, now: Instant) {
+        if self.entries.len() <= self.max_entries && self.bytes <= self.max_bytes {
+            return;
+        }
+
+        let expired: Vec<CacheKey> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| now >= entry.expires)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+
+        while self.entries.len() > self.max_entries || self.bytes > self.max_bytes {
+            let oldest = match self.entries.iter().min_by_key(|(_, entry)| entry.stored) {
+                Some((key, _)) => key.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+    }
+}
+
+/// The lowercase directives of a `Cache-Control` header.
+fn directives(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split(',')
+        .map(|directive| directive.trim().to_lowercase())
+        .filter(|directive| !directive.is_empty())
+}
+
+/// The first two segments of the path, without the query.
+fn resource(path: &str) -> Vec<&str> {
+    let path = path.split('?').next().unwrap_or(path);
+    path.trim_matches('/').split('/').take(2).collect()
+}
+
+fn env_usize(key: &str, default: usize) -> usize {
+    match env::var(key) {
+        Ok(value) => match value.trim().parse::<usize>() {
+            Ok(v) => v,
+            Err(_) => {
+                warn!("Invalid value for {}: {}, using {}", key, value, default);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, headers: &[(&str, &str)]) -> http::Request<Vec<u8>> {
+        let mut builder = http::Request::builder().method(method).uri(path);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Vec::new()).unwrap()
+    }
+
+    fn ok(body: &str, cache_control: Option<&str>) -> Vec<u8> {
+        let cache_control = cache_control
+            .map(|value| format!("Cache-Control: {}\r\n", value))
+            .unwrap_or_default();
+        format!(
+            "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\n\r\n{}",
+            cache_control,
+            body.len(),
+            body
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_key() {
+        let alice = ResponseCache::key(&request(
+            "GET",
+            "/document/1?v=2",
+            &[("Authorization", "Bearer a")],
+        ));
+        let bob = ResponseCache::key(&request(
+            "GET",
+            "/document/1?v=2",
+            &[("Authorization", "Bearer b")],
+        ));
+        assert_eq!(alice.as_ref().unwrap().0, "/document/1?v=2");
+        assert_ne!(alice, bob);
+
+        // users identified by another header don't share responses either
+        for name in ["X-User-ID", "X-Session-Token", "X-Share-Token", "Cookie"] {
+            let key = |value| ResponseCache::key(&request("GET", "/document/1", &[(name, value)]));
+            assert_ne!(key("a"), key("b"), "{}", name);
+            assert_ne!(
+                key("a"),
+                ResponseCache::key(&request("GET", "/document/1", &[]))
+            );
+        }
+
+        assert_eq!(
+            ResponseCache::key(&request("POST", "/document/1", &[])),
+            None
+        );
+        assert_eq!(
+            ResponseCache::key(&request(
+                "GET",
+                "/document/1",
+                &[("Cache-Control", "no-cache")]
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn test_store_and_lookup() {
+        let now = Instant::now();
+        let mut cache = ResponseCache::new(Duration::from_secs(10), 10, 1024);
+        let key = ResponseCache::key(&request("GET", "/document/1", &[])).unwrap();
+
+        assert_eq!(cache.lookup(&key, now), None);
+        cache.store(key.clone(), &ok("hello", None), now);
+        assert_eq!(cache.lookup(&key, now), Some(ok("hello", None)));
+        assert_eq!(cache.lookup(&key, now + Duration::from_secs(10)), None);
+        assert_eq!((cache.hits, cache.misses), (1, 2));
+
+        // the replica's max-age shortens the ttl
+        cache.store(key.clone(), &ok("hello", Some("private, max-age=1")), now);
+        assert!(cache.lookup(&key, now + Duration::from_secs(2)).is_none());
+
+        // and no-store keeps the response out of the cache
+        cache.clear();
+        cache.store(key.clone(), &ok("hello", Some("no-store")), now);
+        assert!(cache.is_empty());
+        cache.store(
+            key.clone(),
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+            now,
+        );
+        assert!(cache.is_empty());
+
+        // writes evict the responses of their resource
+        cache.store(key.clone(), &ok("hello", None), now);
+        let other = ResponseCache::key(&request("GET", "/document/2", &[])).unwrap();
+        cache.store(other.clone(), &ok("world", None), now);
+        cache.invalidate("/document/1/insert");
+        assert!(cache.lookup(&key, now).is_none());
+        assert!(cache.lookup(&other, now).is_some());
+    }
+
+    #[test]
+    fn test_bounds() {
+        let now = Instant::now();
+        let mut cache = ResponseCache::new(Duration::from_secs(10), 2, 1024);
+        for i in 0..3 {
+            let key =
+                ResponseCache::key(&request("GET", &format!("/document/{}", i), &[])).unwrap();
+            cache.store(key, &ok("hello", None), now + Duration::from_millis(i));
+        }
+        assert_eq!(cache.len(), 2);
+        let first = ResponseCache::key(&request("GET", "/document/0", &[])).unwrap();
+        assert!(cache.lookup(&first, now).is_none());
+
+        let mut cache =
+            ResponseCache::new(Duration::from_secs(10), 10, ok("hello", None).len() * 2);
+        for i in 0..3 {
+            let key =
+                ResponseCache::key(&request("GET", &format!("/document/{}", i), &[])).unwrap();
+            cache.store(key, &ok("hello", None), now + Duration::from_millis(i));
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.bytes(), ok("hello", None).len() * 2);
+    }
+}
@@ -0,0 +1,234 @@
+use crate::ip_filter::Cidr;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::net::IpAddr;
+use tracing::{error, info};
+
+const DEFAULT_ROUTES_FILE: &str = "routes.conf";
+
+/// What the load balancer does with requests to a path.
+/// `Ring`: Proxies the request to the node chosen for the client on the ring.
+/// `Pool`: Proxies the request to a node of the pool (`NODE<n>_POOL`) chosen for the client.
+/// `Deny`: Rejects the request with `403 Forbidden`.
+/// `Allow`: Rejects requests from clients outside the CIDR blocks with `403 Forbidden`, and
+/// proxies the others to the ring.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouteAction {
+    Ring,
+    Pool(String),
+    Deny,
+    Allow(Vec<Cidr>),
+}
+
+impl Display for RouteAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteAction::Ring => write!(f, "ring"),
+            RouteAction::Pool(pool) => write!(f, "pool {}", pool),
+            RouteAction::Deny => write!(f, "deny"),
+            RouteAction::Allow(cidrs) => write!(
+                f,
+                "allow {}",
+                cidrs
+                    .iter()
+                    .map(Cidr::to_string)
+                    .collect::<Vec<String>>()
+                    .join(",")
+            ),
+        }
+    }
+}
+
+/// A rule mapping a path prefix to an action.
+/// `prefix`: The leading path segments the rule applies to, `*` matches a single segment.
+/// `/document` matches `/document` and `/document/1/insert` but not `/documents`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteRule {
+    pub prefix: String,
+    pub action: RouteAction,
+}
+
+impl RouteRule {
+    /// Returns true if the path starts with the rule's prefix.
+    pub fn matches(&self, path: &str) -> bool {
+        // ignore the query string
+        let path = path.split('?').next().unwrap_or(path);
+
+        let mut segments = path.trim_matches('/').split('/');
+        self.segments().all(|prefix| {
+            segments
+                .next()
+                .is_some_and(|s| prefix == "*" || prefix == s)
+        })
+    }
+
+    fn segments(&self) -> impl Iterator<Item = &str> {
+        self.prefix.split('/').filter(|segment| !segment.is_empty())
+    }
+}
+
+/// Routing table mapping path prefixes to actions, the rule with the longest matching prefix
+/// wins. Requests that match no rule are proxied to the ring.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoutingTable {
+    pub rules: Vec<RouteRule>,
+}
+
+impl RoutingTable {
+    pub fn new(rules: Vec<RouteRule>) -> Self {
+        RoutingTable { rules }
+    }
+
+    /// Finds the action for a request path.
+    pub fn resolve(&self, path: &str) -> &RouteAction {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(path))
+            // the first of the longest prefixes
+            .rev()
+            .max_by_key(|rule| rule.segments().count())
+            .map(|rule| &rule.action)
+            .unwrap_or(&RouteAction::Ring)
+    }
+
+    /// Parses a routing table, one rule per line:
+    /// `<path prefix> <action> [<argument>]`
+    ///
+    /// Example
+    /// ```text
+    /// # prefix    action  argument
+    /// /document   pool    replicas
+    /// /metrics    deny
+    /// /sns        allow   54.240.0.0/18,52.95.0.0/16
+    /// /           ring
+    /// ```
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut rules: Vec<RouteRule> = Vec::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let action = match fields.as_slice() {
+                [_, "ring"] => RouteAction::Ring,
+                [_, "deny"] => RouteAction::Deny,
+                [_, "pool", pool] => RouteAction::Pool(pool.to_string()),
+                [_, "allow", cidrs] => RouteAction::Allow(
+                    cidrs
+                        .split(',')
+                        .filter(|cidr| !cidr.is_empty())
+                        .map(|cidr| cidr.parse::<Cidr>())
+                        .collect::<Result<Vec<Cidr>, String>>()
+                        .map_err(|e| format!("Line {}: {}", number + 1, e))?,
+                ),
+                _ => {
+                    return Err(format!(
+                        "Line {}: expected <prefix> ring, deny, pool <pool> or allow <cidrs>",
+                        number + 1
+                    ))
+                }
+            };
+            if !fields[0].starts_with('/') {
+                return Err(format!(
+                    "Line {}: prefix {} must start with /",
+                    number + 1,
+                    fields[0]
+                ));
+            }
+
+            rules.push(RouteRule {
+                prefix: fields[0].to_string(),
+                action,
+            });
+        }
+
+        Ok(RoutingTable::new(rules))
+    }
+}
+
+/// Returns true if the action lets the client through.
+pub fn permits(action: &RouteAction, client: Option<&IpAddr>) -> bool {
+    match action {
+        RouteAction::Deny => false,
+        RouteAction::Allow(cidrs) => {
+            client.is_some_and(|ip| cidrs.iter().any(|cidr| cidr.contains(ip)))
+        }
+        RouteAction::Ring | RouteAction::Pool(_) => true,
+    }
+}
+
+/// Loads the routing table from the file set in `LB_ROUTES_FILE` (defaults to routes.conf).
+/// A missing file results in every request being proxied to the ring.
+pub fn load_routing_table() -> RoutingTable {
+    let path = env::var("LB_ROUTES_FILE").unwrap_or(DEFAULT_ROUTES_FILE.to_string());
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(_) => {
+            info!(
+                "No routes file found at {}, proxying every path to the ring",
+                path
+            );
+            return RoutingTable::default();
+        }
+    };
+
+    match RoutingTable::parse(&contents) {
+        Ok(table) => {
+            info!("Loaded {} routes", table.rules.len());
+            table
+        }
+        Err(e) => {
+            error!("Failed to parse {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let table = RoutingTable::parse(
+            "/document pool replicas\n/document/*/history pool archive\n/metrics deny\n/sns allow 54.240.0.0/18 # AWS\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            table.resolve("/document/1/insert"),
+            &RouteAction::Pool("replicas".to_string())
+        );
+        assert_eq!(
+            table.resolve("/document/1/history?limit=5"),
+            &RouteAction::Pool("archive".to_string())
+        );
+        assert_eq!(table.resolve("/metrics"), &RouteAction::Deny);
+        assert_eq!(table.resolve("/documents"), &RouteAction::Ring);
+        assert_eq!(table.resolve("/"), &RouteAction::Ring);
+
+        let sns = table.resolve("/sns/bounce");
+        assert!(permits(sns, Some(&"54.240.1.1".parse().unwrap())));
+        assert!(!permits(sns, Some(&"203.0.113.1".parse().unwrap())));
+        assert!(!permits(sns, None));
+    }
+
+    #[test]
+    fn test_parse_routing_table() {
+        assert!(RoutingTable::parse("/metrics block").is_err());
+        assert!(RoutingTable::parse("/sns allow 54.240.0.0/99").is_err());
+        assert!(RoutingTable::parse("metrics deny").is_err());
+        assert!(RoutingTable::parse("/document pool").is_err());
+
+        let table = RoutingTable::parse("/ ring\n/sns allow 10.0.0.0/8,::1").unwrap();
+        assert_eq!(
+            table.rules[1].action.to_string(),
+            "allow 10.0.0.0/8,::1/128"
+        );
+    }
+}
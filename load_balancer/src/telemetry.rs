@@ -0,0 +1,77 @@
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/// Name reported for the load balancer's spans.
+pub const SERVICE_NAME: &str = "load-balancer";
+
+/// Installs the global tracing subscriber.
+///
+/// Spans and events are written to stdout, filtered by `RUST_LOG` (defaults to `info`).
+/// When built with the `otlp` feature and `OTEL_EXPORTER_OTLP_ENDPOINT` is set, spans are
+/// also exported over OTLP. Spans carry the `request_id` forwarded to the replicas in
+/// `X-Request-ID`, which links the load balancer and replica traces for a request.
+pub fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer());
+
+    #[cfg(feature = "otlp")]
+    let registry = registry.with(otlp::layer());
+
+    if registry.try_init().is_err() {
+        eprintln!("Tracing subscriber was already initialized");
+    }
+}
+
+/// Flushes any spans still waiting to be exported.
+pub fn shutdown_tracing() {
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::TracerProvider;
+    use opentelemetry_sdk::{runtime, Resource};
+    use tracing_opentelemetry::OpenTelemetryLayer;
+    use tracing_subscriber::registry::LookupSpan;
+
+    /// Builds the OTLP export layer, or `None` if no collector endpoint is configured.
+    pub fn layer<S>() -> Option<OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+
+        let exporter = match SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()
+        {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("Failed to create OTLP exporter: {}", e);
+                return None;
+            }
+        };
+
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                super::SERVICE_NAME,
+            )]))
+            .build();
+
+        let tracer = provider.tracer(super::SERVICE_NAME);
+        opentelemetry::global::set_tracer_provider(provider);
+
+        Some(tracing_opentelemetry::layer().with_tracer(tracer))
+    }
+}
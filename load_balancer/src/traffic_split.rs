@@ -0,0 +1,134 @@
+//! Shifting a share of the clients to another pool of replicas, for blue/green deployments.
+//!
+//! Nodes are put in a pool with `NODE<n>_POOL` (unlabelled nodes are in no pool). While a
+//! split is set, `percent` of the clients are routed over the ring of the nodes in `pool` and
+//! the rest over the ring of every other node. Clients are bucketed by the hash of their
+//! address, so a client stays on the same pool while the percentage does not change and raising
+//! it only moves clients onto the new pool.
+
+use std::env;
+use std::fmt::Display;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::str::FromStr;
+use tracing::{info, warn};
+
+/// The share of clients routed to a pool.
+/// `pool`: The pool the clients are shifted to, such as `v2`.
+/// `percent`: The percentage of clients routed to the pool, from 0 to 100.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrafficSplit {
+    pub pool: String,
+    pub percent: u8,
+}
+
+impl TrafficSplit {
+    pub fn new(pool: String, percent: u8) -> Result<Self, String> {
+        if pool.trim().is_empty() {
+            return Err("pool must not be empty".to_string());
+        }
+        if percent > 100 {
+            return Err(format!("Invalid percent {}, must be at most 100", percent));
+        }
+        Ok(TrafficSplit {
+            pool: pool.trim().to_string(),
+            percent,
+        })
+    }
+
+    /// Reads the split from `LB_SHIFT_POOL` and `LB_SHIFT_PERCENT`, None if no pool is set.
+    pub fn from_env() -> Option<Self> {
+        let pool = env::var("LB_SHIFT_POOL").ok().filter(|p| !p.is_empty())?;
+        let percent = env::var("LB_SHIFT_PERCENT").unwrap_or("0".to_string());
+
+        let split = match percent.trim().parse::<u8>() {
+            Ok(percent) => TrafficSplit::new(pool, percent),
+            Err(_) => Err(format!("Invalid LB_SHIFT_PERCENT {}", percent)),
+        };
+        match split {
+            Ok(split) => {
+                info!(
+                    "Shifting {}% of clients to pool {}",
+                    split.percent, split.pool
+                );
+                Some(split)
+            }
+            Err(e) => {
+                warn!("{}, not shifting traffic", e);
+                None
+            }
+        }
+    }
+
+    /// Returns true if the client is in the share routed to the pool.
+    pub fn shifts<H: Hash>(&self, client: &H) -> bool {
+        // hashed apart from the ring position, so the share is spread across every node
+        let mut hasher = DefaultHasher::new();
+        ("traffic_split", client).hash(&mut hasher);
+        hasher.finish() % 100 < self.percent as u64
+    }
+}
+
+/// Parses `<pool>:<percent>`, such as `v2:10`.
+impl FromStr for TrafficSplit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pool, percent) = match s.trim().rsplit_once(':') {
+            Some(split) => split,
+            None => {
+                return Err(format!(
+                    "Invalid traffic split {}, expected pool:percent",
+                    s
+                ))
+            }
+        };
+        match percent.trim().trim_end_matches('%').parse::<u8>() {
+            Ok(percent) => TrafficSplit::new(pool.to_string(), percent),
+            Err(_) => Err(format!("Invalid percent {}", percent)),
+        }
+    }
+}
+
+impl Display for TrafficSplit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.pool, self.percent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_traffic_split() {
+        let split: TrafficSplit = "v2:25".parse().unwrap();
+        assert_eq!(split, TrafficSplit::new("v2".to_string(), 25).unwrap());
+        assert_eq!(split.to_string(), "v2:25");
+        assert_eq!("v2: 25%".parse::<TrafficSplit>().unwrap().percent, 25);
+
+        assert!("v2".parse::<TrafficSplit>().is_err());
+        assert!("v2:101".parse::<TrafficSplit>().is_err());
+        assert!(":10".parse::<TrafficSplit>().is_err());
+    }
+
+    #[test]
+    fn test_shifts() {
+        let clients: Vec<String> = (0..1000)
+            .map(|i| format!("10.0.{}.{}", i / 256, i))
+            .collect();
+        let shifted = |percent: u8| {
+            let split = TrafficSplit::new("v2".to_string(), percent).unwrap();
+            clients.iter().filter(|c| split.shifts(c)).count()
+        };
+
+        assert_eq!(shifted(0), 0);
+        assert_eq!(shifted(100), clients.len());
+        let quarter = shifted(25);
+        assert!((150..350).contains(&quarter), "{} clients shifted", quarter);
+
+        // raising the percentage keeps the clients already shifted
+        let low = TrafficSplit::new("v2".to_string(), 10).unwrap();
+        let high = TrafficSplit::new("v2".to_string(), 50).unwrap();
+        assert!(clients.iter().all(|c| !low.shifts(c) || high.shifts(c)));
+    }
+}
@@ -0,0 +1,298 @@
+//! Connections from the load balancer to the replicas.
+//!
+//! Requests are sent over plain TCP unless upstream TLS is configured. With it, every
+//! connection to a replica is made over TLS and the load balancer presents its client
+//! certificate, so replicas that require one (`tls.client_ca` in Replica.toml) refuse clients
+//! that bypass the load balancer and its rate limiting.
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use std::env;
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tracing::{error, info};
+
+/// The TLS settings of the connections to the replicas.
+/// `connector`: Verifies replicas against the CA and presents the load balancer's certificate.
+/// `server_name`: The name the replicas' certificates are checked against, None to use the
+/// host of each node's address.
+#[derive(Clone)]
+pub struct UpstreamTls {
+    connector: TlsConnector,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl UpstreamTls {
+    /// Builds the TLS settings from PEM encoded certificates and key.
+    /// `cert`: The load balancer's certificate chain, presented to the replicas.
+    /// `key`: The private key of the certificate.
+    /// `ca`: The certificates the replicas' certificates must be signed by.
+    pub fn new(
+        cert: &[u8],
+        key: &[u8],
+        ca: &[u8],
+        server_name: Option<String>,
+    ) -> Result<Self, String> {
+        let certs: Vec<CertificateDer<'static>> = CertificateDer::pem_slice_iter(cert)
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("Invalid certificate: {}", e))?;
+        if certs.is_empty() {
+            return Err("No certificate found".to_string());
+        }
+        let key = PrivateKeyDer::from_pem_slice(key)
+            .map_err(|e| format!("Invalid private key: {}", e))?;
+
+        let mut roots = RootCertStore::empty();
+        for ca in CertificateDer::pem_slice_iter(ca) {
+            let ca = ca.map_err(|e| format!("Invalid CA certificate: {}", e))?;
+            roots
+                .add(ca)
+                .map_err(|e| format!("Invalid CA certificate: {}", e))?;
+        }
+        if roots.is_empty() {
+            return Err("No CA certificate found".to_string());
+        }
+
+        let server_name = match server_name {
+            Some(name) => Some(
+                ServerName::try_from(name.clone())
+                    .map_err(|_| format!("Invalid server name {}", name))?,
+            ),
+            None => None,
+        };
+
+        // the provider is chosen here as more than one may be compiled into the binary
+        let config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .map_err(|e| e.to_string())?
+                .with_root_certificates(roots)
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| format!("Invalid certificate or key: {}", e))?;
+
+        Ok(UpstreamTls {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    /// Reads the TLS settings from the environment (.env file), None if `LB_UPSTREAM_TLS_CERT`
+    /// is not set. Invalid settings stop the load balancer, so it never falls back to sending
+    /// requests in plain text.
+    /// `LB_UPSTREAM_TLS_CERT`: Path of the load balancer's certificate chain.
+    /// `LB_UPSTREAM_TLS_KEY`: Path of the certificate's private key.
+    /// `LB_UPSTREAM_TLS_CA`: Path of the CA certificates the replicas' certificates are
+    /// verified against.
+    /// `LB_UPSTREAM_TLS_SERVER_NAME`: The name expected in the replicas' certificates, defaults
+    /// to the host of each node's address.
+    pub fn from_env() -> Option<Self> {
+        let cert = env::var("LB_UPSTREAM_TLS_CERT")
+            .ok()
+            .filter(|c| !c.is_empty())?;
+        let read = |key: &str| -> Result<Vec<u8>, String> {
+            let path = env::var(key).map_err(|_| format!("{} must be set", key))?;
+            fs::read(&path).map_err(|e| format!("Failed to read {} {}: {}", key, path, e))
+        };
+        let server_name = env::var("LB_UPSTREAM_TLS_SERVER_NAME")
+            .ok()
+            .filter(|n| !n.is_empty());
+
+        let tls = read("LB_UPSTREAM_TLS_CERT").and_then(|cert| {
+            UpstreamTls::new(
+                &cert,
+                &read("LB_UPSTREAM_TLS_KEY")?,
+                &read("LB_UPSTREAM_TLS_CA")?,
+                server_name,
+            )
+        });
+        match tls {
+            Ok(tls) => {
+                info!(
+                    "Connecting to the replicas over TLS with certificate {}",
+                    cert
+                );
+                Some(tls)
+            }
+            Err(e) => {
+                error!("Failed to configure upstream TLS: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Sends the request to the replica at the address and reads its response, over TLS if
+/// `tls` is set. The replica is expected to close the connection once it has responded.
+pub async fn exchange(
+    address: &str,
+    tls: Option<&UpstreamTls>,
+    request: &[u8],
+) -> Result<Vec<u8>, String> {
+    let (host, port) = host_and_port(address, tls.is_some())?;
+    let mut stream = TcpStream::connect((host.as_str(), port))
+        .await
+        .map_err(|e| format!("Failed to connect to {}: {}", address, e))?;
+
+    let tls = match tls {
+        Some(tls) => tls,
+        None => return send(&mut stream, request).await,
+    };
+    let server_name = match &tls.server_name {
+        Some(name) => name.clone(),
+        None => ServerName::try_from(host.clone())
+            .map_err(|_| format!("Invalid server name {}", host))?,
+    };
+    let mut stream = tls
+        .connector
+        .connect(server_name, stream)
+        .await
+        .map_err(|e| format!("TLS handshake with {} failed: {}", address, e))?;
+    send(&mut stream, request).await
+}
+
+async fn send<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &[u8],
+) -> Result<Vec<u8>, String> {
+    if let Err(e) = stream.write_all(request).await {
+        return Err(format!("Failed to write to server: {}", e));
+    }
+
+    let mut response = Vec::new();
+    match stream.read_to_end(&mut response).await {
+        Ok(_) => Ok(response),
+        // a replica may close the connection without a TLS close_notify once it has responded
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !response.is_empty() => Ok(response),
+        Err(e) => Err(format!("Failed to read from server: {}", e)),
+    }
+}
+
+/// The host and port of a node's address, such as `http://127.0.0.1:7878`. Addresses without
+/// a port use 443 over TLS and 80 otherwise.
+fn host_and_port(address: &str, tls: bool) -> Result<(String, u16), String> {
+    let authority = address
+        .split_once("://")
+        .map_or(address, |(_, rest)| rest)
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    let authority = authority
+        .parse::<http::uri::Authority>()
+        .map_err(|_| format!("Invalid node address {}", address))?;
+
+    let host = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = authority.port_u16().unwrap_or(if tls { 443 } else { 80 });
+    Ok((host, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::ServerConfig;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    // test certificates signed by testdata/ca.pem, the server's for localhost and 127.0.0.1
+    const CA: &[u8] = include_bytes!("../testdata/ca.pem");
+    const CLIENT_CERT: &[u8] = include_bytes!("../testdata/client.pem");
+    const CLIENT_KEY: &[u8] = include_bytes!("../testdata/client.key");
+    const SERVER_CERT: &[u8] = include_bytes!("../testdata/server.pem");
+    const SERVER_KEY: &[u8] = include_bytes!("../testdata/server.key");
+
+    /// A replica requiring client certificates signed by the CA, answering one connection.
+    async fn replica() -> String {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_slice(CA).unwrap())
+            .unwrap();
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .unwrap();
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                CertificateDer::pem_slice_iter(SERVER_CERT)
+                    .collect::<Result<_, _>>()
+                    .unwrap(),
+                PrivateKeyDer::from_pem_slice(SERVER_KEY).unwrap(),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("https://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok(mut stream) = acceptor.accept(stream).await {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .await;
+                let _ = stream.shutdown().await;
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_exchange_over_mutual_tls() {
+        let tls = UpstreamTls::new(CLIENT_CERT, CLIENT_KEY, CA, None).unwrap();
+        let address = replica().await;
+        let response = exchange(&address, Some(&tls), b"GET / HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+
+        // a client presenting a certificate the replica doesn't trust is refused
+        let untrusted = UpstreamTls::new(SERVER_CERT, SERVER_KEY, CA, None).unwrap();
+        let address = replica().await;
+        assert!(
+            exchange(&address, Some(&untrusted), b"GET / HTTP/1.1\r\n\r\n")
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_new() {
+        assert!(
+            UpstreamTls::new(CLIENT_CERT, CLIENT_KEY, CA, Some("localhost".to_string())).is_ok()
+        );
+        assert!(UpstreamTls::new(b"", CLIENT_KEY, CA, None).is_err());
+        assert!(UpstreamTls::new(CLIENT_CERT, b"", CA, None).is_err());
+        assert!(UpstreamTls::new(CLIENT_CERT, CLIENT_KEY, b"", None).is_err());
+        assert!(UpstreamTls::new(CLIENT_CERT, CLIENT_KEY, CA, Some("a b".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_host_and_port() {
+        assert_eq!(
+            host_and_port("http://127.0.0.1:7878", false),
+            Ok(("127.0.0.1".to_string(), 7878))
+        );
+        assert_eq!(
+            host_and_port("https://replica.internal/", true),
+            Ok(("replica.internal".to_string(), 443))
+        );
+        assert_eq!(
+            host_and_port("[::1]:7878", false),
+            Ok(("::1".to_string(), 7878))
+        );
+        assert!(host_and_port("http://", false).is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use crate::rate_limiter_proto::{RateLimitPolicy, RateLimitResponse};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Maximum number of cached verdicts before expired ones are evicted.
+const MAX_ENTRIES: usize = 100_000;
+
+/// A verdict from the rate limiter service, reused until it expires.
+/// `allowance`: Requests that may still be allowed without asking the service.
+/// `hits`: Requests allowed from the cache, reported to the service on the next check.
+struct CachedVerdict {
+    verdict: RateLimitResponse,
+    expires: Instant,
+    allowance: u64,
+    hits: u64,
+}
+
+/// The outcome of a cache lookup.
+/// `Hit`: The verdict for the request, made without asking the rate limiter service.
+/// `Miss`: The service has to be asked, reporting `hits` requests allowed from the cache.
+#[derive(Debug, PartialEq)]
+pub enum CacheLookup {
+    Hit(RateLimitResponse),
+    Miss { hits: u64 },
+}
+
+/// Short-lived cache of rate limiter verdicts, keyed by client IP address and policy name,
+/// so bursts from the same client don't need one call to the service per request.
+/// - Allowed verdicts are reused for up to `ttl` (never longer than the policy window),
+///   spending the remaining quota the service reported. Requests allowed from the cache are
+///   reported with the next check, so the service keeps counting them.
+/// - Rejected verdicts are reused until the client may retry.
+/// - The first request of a client in each window always goes to the service.
+pub struct VerdictCache {
+    ttl: Duration,
+    entries: HashMap<(String, String), CachedVerdict>,
+}
+
+impl VerdictCache {
+    /// Caches verdicts for up to `ttl`, a zero `ttl` disables the cache.
+    pub fn new(ttl: Duration) -> Self {
+        VerdictCache {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// Looks up the verdict for a request of `ip_address` under `policy`.
+    pub fn lookup(
+        &mut self,
+        ip_address: &str,
+        policy: &RateLimitPolicy,
+        request_id: &str,
+        now: Instant,
+    ) -> CacheLookup {
+        let key = (ip_address.to_string(), policy.name.clone());
+        let entry = match self.entries.get_mut(&key) {
+            Some(entry) => entry,
+            None => return CacheLookup::Miss { hits: 0 },
+        };
+
+        let live = now < entry.expires;
+        if live && !entry.verdict.allowed {
+            let retry_after = entry.expires.saturating_duration_since(now);
+            return CacheLookup::Hit(RateLimitResponse {
+                request_id: request_id.to_string(),
+                retry_after_ms: retry_after.as_millis().max(1) as u64,
+                ..entry.verdict.clone()
+            });
+        }
+        if live && entry.allowance > 0 {
+            entry.allowance -= 1;
+            entry.hits += 1;
+            return CacheLookup::Hit(RateLimitResponse {
+                request_id: request_id.to_string(),
+                remaining: entry.allowance,
+                ..entry.verdict.clone()
+            });
+        }
+
+        // expired or out of quota, ask the service again
+        let hits = entry.hits;
+        self.entries.remove(&key);
+        CacheLookup::Miss { hits }
+    }
+
+    /// Caches the verdict returned by the rate limiter service.
+    pub fn insert(
+        &mut self,
+        ip_address: &str,
+        policy: &RateLimitPolicy,
+        verdict: &RateLimitResponse,
+        now: Instant,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let window = Duration::from_millis(policy.window_ms);
+        let lifetime = if verdict.allowed {
+            self.ttl.min(window)
+        } else {
+            Duration::from_millis(verdict.retry_after_ms).min(window)
+        };
+        if lifetime.is_zero() {
+            return;
+        }
+
+        if self.entries.len() >= MAX_ENTRIES {
+            self.evict_expired(now);
+        }
+
+        self.entries.insert(
+            (ip_address.to_string(), policy.name.clone()),
+            CachedVerdict {
+                verdict: verdict.clone(),
+                expires: now + lifetime,
+                allowance: if verdict.allowed {
+                    verdict.remaining
+                } else {
+                    0
+                },
+                hits: 0,
+            },
+        );
+    }
+
+    /// Removes expired verdicts, along with any hits not reported yet.
+    pub fn evict_expired(&mut self, now: Instant) {
+        self.entries.retain(|_, entry| now < entry.expires);
+    }
+
+    /// The number of cached verdicts.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limiter_proto::Algorithm;
+
+    fn policy() -> RateLimitPolicy {
+        RateLimitPolicy {
+            name: "document_insert".to_string(),
+            algorithm: Algorithm::FixedWindow.into(),
+            limit: 3,
+            window_ms: 1_000,
+        }
+    }
+
+    fn verdict(allowed: bool, remaining: u64, retry_after_ms: u64) -> RateLimitResponse {
+        RateLimitResponse {
+            request_id: "1".to_string(),
+            allowed,
+            limit: 3,
+            remaining,
+            retry_after_ms,
+        }
+    }
+
+    #[test]
+    fn test_allowed_verdicts_spend_remaining_quota() {
+        let now = Instant::now();
+        let mut cache = VerdictCache::new(Duration::from_millis(100));
+        let policy = policy();
+
+        // the first request in the window goes to the service
+        assert_eq!(
+            cache.lookup("10.0.0.1", &policy, "1", now),
+            CacheLookup::Miss { hits: 0 }
+        );
+        cache.insert("10.0.0.1", &policy, &verdict(true, 2, 0), now);
+
+        for remaining in [1, 0] {
+            match cache.lookup("10.0.0.1", &policy, "2", now) {
+                CacheLookup::Hit(verdict) => {
+                    assert!(verdict.allowed);
+                    assert_eq!(verdict.remaining, remaining);
+                    assert_eq!(verdict.request_id, "2");
+                }
+                miss => panic!("expected a cached verdict, got {:?}", miss),
+            }
+        }
+
+        // out of quota, the service is asked and told about the cached requests
+        assert_eq!(
+            cache.lookup("10.0.0.1", &policy, "3", now),
+            CacheLookup::Miss { hits: 2 }
+        );
+        assert!(cache.is_empty());
+
+        // other clients are not affected
+        assert_eq!(
+            cache.lookup("10.0.0.2", &policy, "4", now),
+            CacheLookup::Miss { hits: 0 }
+        );
+
+        // verdicts expire after the ttl
+        cache.insert("10.0.0.1", &policy, &verdict(true, 2, 0), now);
+        assert!(matches!(
+            cache.lookup("10.0.0.1", &policy, "5", now),
+            CacheLookup::Hit(_)
+        ));
+        assert_eq!(
+            cache.lookup("10.0.0.1", &policy, "6", now + Duration::from_millis(100)),
+            CacheLookup::Miss { hits: 1 }
+        );
+    }
+
+    #[test]
+    fn test_rejected_verdicts_are_cached_until_retry() {
+        let now = Instant::now();
+        let mut cache = VerdictCache::new(Duration::from_millis(100));
+        let policy = policy();
+
+        cache.insert("10.0.0.1", &policy, &verdict(false, 0, 500), now);
+        match cache.lookup("10.0.0.1", &policy, "2", now + Duration::from_millis(200)) {
+            CacheLookup::Hit(verdict) => {
+                assert!(!verdict.allowed);
+                assert_eq!(verdict.retry_after_ms, 300);
+            }
+            miss => panic!("expected a cached verdict, got {:?}", miss),
+        }
+        assert_eq!(
+            cache.lookup("10.0.0.1", &policy, "3", now + Duration::from_millis(500)),
+            CacheLookup::Miss { hits: 0 }
+        );
+
+        cache.insert("10.0.0.1", &policy, &verdict(false, 0, 500), now);
+        cache.evict_expired(now + Duration::from_millis(500));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_disabled_cache() {
+        let now = Instant::now();
+        let mut cache = VerdictCache::new(Duration::ZERO);
+        assert!(!cache.is_enabled());
+
+        cache.insert("10.0.0.1", &policy(), &verdict(false, 0, 500), now);
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -0,0 +1,12 @@
+use std::error::Error;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let file_path = "proto/rate_limiter.proto";
+
+    tonic_build::configure()
+        .build_client(false)
+        .out_dir("src/proto")
+        .compile_protos(&[file_path], &["proto"])
+        .expect("Failed to compile");
+    Ok(())
+}
@@ -0,0 +1,412 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// The rate limiting algorithms supported by the rate limiter.
+///
+/// - `TokenBucket`: Allows bursts up to `limit` requests, refilling `limit` tokens every `window`.
+/// - `SlidingWindowLog`: Allows at most `limit` requests in any `window` long period.
+/// - `FixedWindow`: Allows at most `limit` requests per aligned `window`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    TokenBucket,
+    SlidingWindowLog,
+    FixedWindow,
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Algorithm::TokenBucket => write!(f, "token_bucket"),
+            Algorithm::SlidingWindowLog => write!(f, "sliding_window_log"),
+            Algorithm::FixedWindow => write!(f, "fixed_window"),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().replace('-', "_").as_str() {
+            "token_bucket" => Ok(Algorithm::TokenBucket),
+            "sliding_window_log" | "sliding_window" => Ok(Algorithm::SlidingWindowLog),
+            "fixed_window" => Ok(Algorithm::FixedWindow),
+            other => Err(format!("Unknown rate limiting algorithm: {}", other)),
+        }
+    }
+}
+
+/// The result of checking a request against a rate limiter.
+/// `allowed`: If the request may proceed.
+/// `limit`: The maximum number of requests allowed by the policy.
+/// `remaining`: The number of requests left before the client is limited.
+/// `retry_after`: How long the client should wait before retrying (only set when rejected).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    pub retry_after: Option<Duration>,
+}
+
+/// Common interface for the rate limiting algorithms.
+/// Each instance holds the state for a single client key.
+pub trait RateLimitAlgorithm: Send + Sync {
+    /// Records a request arriving at `now` and returns whether it is allowed.
+    fn check(&mut self, now: Instant) -> Decision;
+
+    /// Returns whether a request arriving at `now` would be allowed, without recording it.
+    /// `remaining` counts the requests left including that one.
+    fn peek(&self, now: Instant) -> Decision;
+}
+
+/// Configuration for a rate limiting algorithm.
+/// `algorithm`: The algorithm to use.
+/// `limit`: The bucket capacity or the maximum number of requests per window.
+/// `window`: The window length, for the token bucket the time taken to refill `limit` tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AlgorithmConfig {
+    pub algorithm: Algorithm,
+    pub limit: u64,
+    pub window: Duration,
+}
+
+impl AlgorithmConfig {
+    pub fn new(algorithm: Algorithm, limit: u64, window: Duration) -> Self {
+        AlgorithmConfig {
+            algorithm,
+            limit,
+            window,
+        }
+    }
+
+    /// Creates the state for a new client key using this configuration.
+    pub fn build(&self, now: Instant) -> Box<dyn RateLimitAlgorithm> {
+        match self.algorithm {
+            Algorithm::TokenBucket => Box::new(TokenBucket::new(self.limit, self.window, now)),
+            Algorithm::SlidingWindowLog => Box::new(SlidingWindowLog::new(self.limit, self.window)),
+            Algorithm::FixedWindow => Box::new(FixedWindow::new(self.limit, self.window, now)),
+        }
+    }
+}
+
+/// Token bucket: tokens refill continuously at `capacity / window` and each request takes one.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: u64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u64, window: Duration, now: Instant) -> Self {
+        let window = window.as_secs_f64().max(f64::EPSILON);
+        TokenBucket {
+            capacity,
+            tokens: capacity as f64,
+            refill_per_sec: capacity as f64 / window,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        self.tokens = self.tokens_at(now);
+        self.last_refill = now;
+    }
+
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        (self.tokens + elapsed * self.refill_per_sec).min(self.capacity as f64)
+    }
+
+    fn retry_after(&self, tokens: f64) -> Duration {
+        if self.refill_per_sec > 0.0 {
+            Duration::from_secs_f64((1.0 - tokens) / self.refill_per_sec)
+        } else {
+            Duration::MAX
+        }
+    }
+}
+
+impl RateLimitAlgorithm for TokenBucket {
+    fn check(&mut self, now: Instant) -> Decision {
+        self.refill(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Decision {
+                allowed: true,
+                limit: self.capacity,
+                remaining: self.tokens.floor() as u64,
+                retry_after: None,
+            };
+        }
+
+        Decision {
+            allowed: false,
+            limit: self.capacity,
+            remaining: 0,
+            retry_after: Some(self.retry_after(self.tokens)),
+        }
+    }
+
+    fn peek(&self, now: Instant) -> Decision {
+        let tokens = self.tokens_at(now);
+        let allowed = tokens >= 1.0;
+
+        Decision {
+            allowed,
+            limit: self.capacity,
+            remaining: tokens.floor() as u64,
+            retry_after: (!allowed).then(|| self.retry_after(tokens)),
+        }
+    }
+}
+
+/// Sliding window log: keeps the timestamp of every accepted request inside the window.
+#[derive(Debug)]
+pub struct SlidingWindowLog {
+    limit: u64,
+    window: Duration,
+    log: VecDeque<Instant>,
+}
+
+impl SlidingWindowLog {
+    pub fn new(limit: u64, window: Duration) -> Self {
+        SlidingWindowLog {
+            limit,
+            window,
+            log: VecDeque::new(),
+        }
+    }
+}
+
+impl RateLimitAlgorithm for SlidingWindowLog {
+    fn check(&mut self, now: Instant) -> Decision {
+        while let Some(oldest) = self.log.front() {
+            if now.saturating_duration_since(*oldest) >= self.window {
+                self.log.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if (self.log.len() as u64) < self.limit {
+            self.log.push_back(now);
+            return Decision {
+                allowed: true,
+                limit: self.limit,
+                remaining: self.limit - self.log.len() as u64,
+                retry_after: None,
+            };
+        }
+
+        // The oldest entry has to leave the window before another request fits
+        let retry_after = self
+            .log
+            .front()
+            .map(|oldest| self.window.saturating_sub(now.saturating_duration_since(*oldest)))
+            .unwrap_or(self.window);
+
+        Decision {
+            allowed: false,
+            limit: self.limit,
+            remaining: 0,
+            retry_after: Some(retry_after),
+        }
+    }
+
+    fn peek(&self, now: Instant) -> Decision {
+        let mut in_window = self
+            .log
+            .iter()
+            .filter(|at| now.saturating_duration_since(**at) < self.window);
+        let oldest = in_window.next();
+        let count = oldest.map_or(0, |_| 1 + in_window.count() as u64);
+
+        if count < self.limit {
+            return Decision {
+                allowed: true,
+                limit: self.limit,
+                remaining: self.limit - count,
+                retry_after: None,
+            };
+        }
+
+        Decision {
+            allowed: false,
+            limit: self.limit,
+            remaining: 0,
+            retry_after: Some(
+                oldest
+                    .map(|oldest| self.window.saturating_sub(now.saturating_duration_since(*oldest)))
+                    .unwrap_or(self.window),
+            ),
+        }
+    }
+}
+
+/// Fixed window: counts requests in consecutive windows starting at `window_start`.
+#[derive(Debug)]
+pub struct FixedWindow {
+    limit: u64,
+    window: Duration,
+    window_start: Instant,
+    count: u64,
+}
+
+impl FixedWindow {
+    pub fn new(limit: u64, window: Duration, now: Instant) -> Self {
+        FixedWindow {
+            limit,
+            window,
+            window_start: now,
+            count: 0,
+        }
+    }
+}
+
+impl RateLimitAlgorithm for FixedWindow {
+    fn check(&mut self, now: Instant) -> Decision {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= self.window {
+            // Skip ahead to the window containing `now`
+            let offset = elapsed.as_nanos() % self.window.as_nanos().max(1);
+            self.window_start = now - Duration::from_nanos(offset as u64);
+            self.count = 0;
+        }
+
+        if self.count < self.limit {
+            self.count += 1;
+            return Decision {
+                allowed: true,
+                limit: self.limit,
+                remaining: self.limit - self.count,
+                retry_after: None,
+            };
+        }
+
+        let retry_after = self
+            .window
+            .saturating_sub(now.saturating_duration_since(self.window_start));
+
+        Decision {
+            allowed: false,
+            limit: self.limit,
+            remaining: 0,
+            retry_after: Some(retry_after),
+        }
+    }
+
+    fn peek(&self, now: Instant) -> Decision {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        // the window has ended, so a request now would start a new one
+        let count = if elapsed >= self.window { 0 } else { self.count };
+
+        Decision {
+            allowed: count < self.limit,
+            limit: self.limit,
+            remaining: self.limit - count.min(self.limit),
+            retry_after: (count >= self.limit).then(|| self.window.saturating_sub(elapsed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_algorithm_from_str() {
+        assert_eq!(
+            "token_bucket".parse::<Algorithm>(),
+            Ok(Algorithm::TokenBucket)
+        );
+        assert_eq!(
+            "Sliding-Window-Log".parse::<Algorithm>(),
+            Ok(Algorithm::SlidingWindowLog)
+        );
+        assert_eq!(
+            "fixed_window".parse::<Algorithm>(),
+            Ok(Algorithm::FixedWindow)
+        );
+        assert!("leaky_bucket".parse::<Algorithm>().is_err());
+    }
+
+    #[test]
+    fn test_token_bucket_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, Duration::from_secs(2), start);
+
+        assert!(bucket.check(start).allowed);
+        assert!(bucket.check(start).allowed);
+
+        let rejected = bucket.check(start);
+        assert!(!rejected.allowed);
+        assert_eq!(rejected.retry_after, Some(Duration::from_secs(1)));
+
+        assert!(bucket.check(start + Duration::from_secs(1)).allowed);
+    }
+
+    #[test]
+    fn test_sliding_window_log() {
+        let start = Instant::now();
+        let mut log = SlidingWindowLog::new(2, Duration::from_secs(10));
+
+        assert_eq!(log.check(start).remaining, 1);
+        assert!(log.check(start + Duration::from_secs(5)).allowed);
+
+        let rejected = log.check(start + Duration::from_secs(6));
+        assert!(!rejected.allowed);
+        assert_eq!(rejected.retry_after, Some(Duration::from_secs(4)));
+
+        // The first request has left the window
+        assert!(log.check(start + Duration::from_secs(10)).allowed);
+        assert!(!log.check(start + Duration::from_secs(11)).allowed);
+    }
+
+    #[test]
+    fn test_fixed_window_resets() {
+        let start = Instant::now();
+        let mut window = FixedWindow::new(1, Duration::from_secs(60), start);
+
+        assert!(window.check(start).allowed);
+
+        let rejected = window.check(start + Duration::from_secs(20));
+        assert!(!rejected.allowed);
+        assert_eq!(rejected.retry_after, Some(Duration::from_secs(40)));
+
+        assert!(window.check(start + Duration::from_secs(130)).allowed);
+        assert!(!window.check(start + Duration::from_secs(179)).allowed);
+    }
+
+    #[test]
+    fn test_peek_does_not_record() {
+        let start = Instant::now();
+        for algorithm in [Algorithm::TokenBucket, Algorithm::SlidingWindowLog, Algorithm::FixedWindow] {
+            let mut limiter = AlgorithmConfig::new(algorithm, 2, Duration::from_secs(10)).build(start);
+
+            assert_eq!(limiter.peek(start).remaining, 2);
+            assert!(limiter.check(start).allowed);
+            assert_eq!(limiter.peek(start).remaining, 1);
+            assert!(limiter.check(start).allowed);
+
+            let peeked = limiter.peek(start);
+            assert!(!peeked.allowed, "{}", algorithm);
+            assert_eq!(peeked.retry_after, limiter.check(start).retry_after);
+        }
+    }
+
+    #[test]
+    fn test_config_builds_algorithm() {
+        let start = Instant::now();
+        let config = AlgorithmConfig::new(Algorithm::FixedWindow, 1, Duration::from_secs(1));
+        let mut limiter = config.build(start);
+
+        assert!(limiter.check(start).allowed);
+        assert!(!limiter.check(start).allowed);
+    }
+}
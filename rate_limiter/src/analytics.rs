@@ -0,0 +1,473 @@
+//! Aggregates of the rate limiter's decisions, so abuse patterns can be analyzed without
+//! scraping logs.
+//!
+//! Requests are counted per client IP address, endpoint and policy over fixed windows. Each
+//! completed window is summarized into a [`WindowReport`] with the clients and endpoints with
+//! the most rejected requests, which is served on the metrics listener and optionally written
+//! to a Kinesis stream or S3 bucket.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::warn;
+
+/// Most distinct clients or endpoints counted in a window, later ones are counted as `other`.
+const MAX_ENTRIES: usize = 100_000;
+
+/// Requests checked and rejected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Counts {
+    pub requests: u64,
+    pub rejected: u64,
+}
+
+impl Counts {
+    fn record(&mut self, hits: u64, allowed: bool) {
+        self.requests += hits + 1;
+        if !allowed {
+            self.rejected += 1;
+        }
+    }
+
+    /// The share of the requests that were rejected, 0 without requests.
+    pub fn reject_rate(&self) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+        self.rejected as f64 / self.requests as f64
+    }
+}
+
+/// The requests of a client, endpoint or policy in a window.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Ranked {
+    pub name: String,
+    pub requests: u64,
+    pub rejected: u64,
+    pub reject_rate: f64,
+}
+
+/// The summary of a completed window.
+/// `start_ms`, `end_ms`: The window, in milliseconds since the Unix epoch.
+/// `top_ips`, `top_endpoints`: The clients and endpoints with the most rejected requests, then
+/// the most requests.
+/// `policies`: Every policy checked in the window, by name.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WindowReport {
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub requests: u64,
+    pub rejected: u64,
+    pub reject_rate: f64,
+    pub top_ips: Vec<Ranked>,
+    pub top_endpoints: Vec<Ranked>,
+    pub policies: Vec<Ranked>,
+}
+
+/// Counts the decisions of the current window, and keeps the report of the last one.
+/// `top`: The clients and endpoints listed in reports.
+pub struct Analytics {
+    top: usize,
+    start_ms: u64,
+    total: Counts,
+    window: Counts,
+    ips: HashMap<String, Counts>,
+    endpoints: HashMap<String, Counts>,
+    policies: HashMap<String, Counts>,
+    last: Option<WindowReport>,
+}
+
+impl Analytics {
+    pub fn new(top: usize, now_ms: u64) -> Self {
+        Analytics {
+            top,
+            start_ms: now_ms,
+            total: Counts::default(),
+            window: Counts::default(),
+            ips: HashMap::new(),
+            endpoints: HashMap::new(),
+            policies: HashMap::new(),
+            last: None,
+        }
+    }
+
+    /// Counts a decision, after `hits` earlier requests the load balancer allowed from its
+    /// verdict cache. Identifiers in the endpoint are replaced with `*`, so the requests of
+    /// every document are counted together.
+    pub fn record(
+        &mut self,
+        ip_address: &str,
+        endpoint: &str,
+        policy: &str,
+        hits: u64,
+        allowed: bool,
+    ) {
+        self.total.record(hits, allowed);
+        self.window.record(hits, allowed);
+        count(&mut self.ips, ip_address, hits, allowed);
+        count(
+            &mut self.endpoints,
+            &endpoint_pattern(endpoint),
+            hits,
+            allowed,
+        );
+        count(&mut self.policies, policy, hits, allowed);
+    }
+
+    /// Completes the current window, returning its report and starting the next one.
+    pub fn roll(&mut self, now_ms: u64) -> WindowReport {
+        let mut policies = ranked(std::mem::take(&mut self.policies));
+        policies.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let report = WindowReport {
+            start_ms: self.start_ms,
+            end_ms: now_ms,
+            requests: self.window.requests,
+            rejected: self.window.rejected,
+            reject_rate: self.window.reject_rate(),
+            top_ips: top(ranked(std::mem::take(&mut self.ips)), self.top),
+            top_endpoints: top(ranked(std::mem::take(&mut self.endpoints)), self.top),
+            policies,
+        };
+
+        self.start_ms = now_ms;
+        self.window = Counts::default();
+        self.last = Some(report.clone());
+        report
+    }
+
+    /// The report of the last completed window.
+    pub fn last(&self) -> Option<&WindowReport> {
+        self.last.as_ref()
+    }
+
+    /// Formats the totals and the last window's report in the Prometheus text exposition
+    /// format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        for (name, help, value) in [
+            (
+                "rate_limiter_requests_total",
+                "Requests checked since the rate limiter started.",
+                self.total.requests,
+            ),
+            (
+                "rate_limiter_rejected_total",
+                "Requests rejected since the rate limiter started.",
+                self.total.rejected,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        let report = match &self.last {
+            Some(report) => report,
+            None => return out,
+        };
+        let _ = writeln!(
+            out,
+            "# HELP rate_limiter_window_reject_rate Share of the requests rejected in the last window."
+        );
+        let _ = writeln!(out, "# TYPE rate_limiter_window_reject_rate gauge");
+        let _ = writeln!(
+            out,
+            "rate_limiter_window_reject_rate {}",
+            report.reject_rate
+        );
+
+        for (label, rows, what) in [
+            ("policy", &report.policies, "each policy"),
+            (
+                "ip",
+                &report.top_ips,
+                "the clients with the most rejected requests",
+            ),
+            (
+                "endpoint",
+                &report.top_endpoints,
+                "the endpoints with the most rejected requests",
+            ),
+        ] {
+            for (field, verb) in [("requests", "checked"), ("rejected", "rejected")] {
+                let name = format!("rate_limiter_window_{}_{}", label, field);
+                let _ = writeln!(
+                    out,
+                    "# HELP {} Requests {} in the last window, of {}.",
+                    name, verb, what
+                );
+                let _ = writeln!(out, "# TYPE {} gauge", name);
+                for row in rows.iter() {
+                    let value = if field == "requests" {
+                        row.requests
+                    } else {
+                        row.rejected
+                    };
+                    let _ = writeln!(
+                        out,
+                        "{}{{{}=\"{}\"}} {}",
+                        name,
+                        label,
+                        escape_label(&row.name),
+                        value
+                    );
+                }
+            }
+        }
+        out
+    }
+}
+
+fn count(counts: &mut HashMap<String, Counts>, name: &str, hits: u64, allowed: bool) {
+    let name = if counts.len() >= MAX_ENTRIES && !counts.contains_key(name) {
+        "other"
+    } else {
+        name
+    };
+    counts
+        .entry(name.to_string())
+        .or_default()
+        .record(hits, allowed);
+}
+
+fn ranked(counts: HashMap<String, Counts>) -> Vec<Ranked> {
+    counts
+        .into_iter()
+        .map(|(name, counts)| Ranked {
+            name,
+            requests: counts.requests,
+            rejected: counts.rejected,
+            reject_rate: counts.reject_rate(),
+        })
+        .collect()
+}
+
+/// The `limit` entries with the most rejected requests, then the most requests.
+fn top(mut rows: Vec<Ranked>, limit: usize) -> Vec<Ranked> {
+    rows.sort_by(|a, b| {
+        b.rejected
+            .cmp(&a.rejected)
+            .then(b.requests.cmp(&a.requests))
+            .then(a.name.cmp(&b.name))
+    });
+    rows.truncate(limit);
+    rows
+}
+
+/// The endpoint without its query, with identifiers (numbers and UUIDs) replaced with `*`,
+/// such as `/document/*/insert`.
+pub fn endpoint_pattern(endpoint: &str) -> String {
+    let path = endpoint.split('?').next().unwrap_or(endpoint);
+    path.split('/')
+        .map(|segment| {
+            let is_number = !segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit());
+            let is_uuid =
+                segment.len() == 36 && segment.chars().all(|c| c.is_ascii_hexdigit() || c == '-');
+            if is_number || is_uuid {
+                "*"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Where window reports are written.
+/// `Kinesis`: A record per report on the stream, partitioned by window.
+/// `S3`: An object per report under the prefix, named after the window's start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkTarget {
+    Kinesis { stream: String },
+    S3 { bucket: String, prefix: String },
+}
+
+impl SinkTarget {
+    /// Parses a target such as `kinesis://rate-limits` or `s3://analytics/rate-limiter`.
+    pub fn parse(target: &str) -> Result<Self, String> {
+        let target = target.trim();
+        match target.split_once("://") {
+            Some(("kinesis", stream)) if !stream.is_empty() && !stream.contains('/') => {
+                Ok(SinkTarget::Kinesis {
+                    stream: stream.to_string(),
+                })
+            }
+            Some(("s3", rest)) => {
+                let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+                if bucket.is_empty() {
+                    return Err(format!("Invalid analytics sink {}: no bucket", target));
+                }
+                Ok(SinkTarget::S3 {
+                    bucket: bucket.to_string(),
+                    prefix: prefix.trim_matches('/').to_string(),
+                })
+            }
+            _ => Err(format!(
+                "Invalid analytics sink {}: expected kinesis://<stream> or s3://<bucket>/<prefix>",
+                target
+            )),
+        }
+    }
+}
+
+/// Serves the analytics on the listener: `GET /metrics` in the Prometheus text format and
+/// `GET /analytics` with the last window's report as JSON (`null` before the first window
+/// completes).
+pub async fn serve(listener: TcpListener, analytics: Arc<Mutex<Analytics>>) {
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Failed to accept a metrics connection: {}", e);
+                continue;
+            }
+        };
+        let analytics = Arc::clone(&analytics);
+        tokio::spawn(async move {
+            let mut buffer = [0; 1024];
+            let read = match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buffer))
+                .await
+            {
+                Ok(Ok(read)) => read,
+                _ => return,
+            };
+            let request = String::from_utf8_lossy(&buffer[..read]);
+            let response = respond(request.lines().next().unwrap_or_default(), &analytics);
+            let _ = stream.write_all(&response).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+/// The response to a request line of the metrics listener.
+fn respond(request_line: &str, analytics: &Mutex<Analytics>) -> Vec<u8> {
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+    let path = path.map(|path| path.split('?').next().unwrap_or(path));
+
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => {
+            let analytics = analytics.lock().unwrap_or_else(|e| e.into_inner());
+            (
+                "200 OK",
+                "text/plain; version=0.0.4",
+                analytics.to_prometheus(),
+            )
+        }
+        (Some("GET"), Some("/analytics")) => {
+            let analytics = analytics.lock().unwrap_or_else(|e| e.into_inner());
+            let body = serde_json::to_string(&analytics.last()).unwrap_or("null".to_string());
+            ("200 OK", "application/json", body)
+        }
+        _ => ("404 Not Found", "text/plain", String::new()),
+    };
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_reports() {
+        let mut analytics = Analytics::new(2, 1_000);
+        let document = "/document/0b6f7f52-3f0c-4a8e-9d54-0c1b9e3f6a21/insert";
+        for (ip, endpoint, allowed) in [
+            ("10.0.0.1", document, true),
+            ("10.0.0.1", document, false),
+            ("10.0.0.1", "/document/7/insert?x=1", false),
+            ("10.0.0.2", "/create_document", true),
+            ("10.0.0.3", "/create_document", true),
+            ("10.0.0.3", "/create_document", true),
+        ] {
+            analytics.record(ip, endpoint, "write", 0, allowed);
+        }
+
+        let report = analytics.roll(61_000);
+        assert_eq!((report.start_ms, report.end_ms), (1_000, 61_000));
+        assert_eq!((report.requests, report.rejected), (6, 2));
+        assert!((report.reject_rate - 2.0 / 6.0).abs() < 1e-9);
+        let ips: Vec<&str> = report.top_ips.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(ips, vec!["10.0.0.1", "10.0.0.3"]);
+        assert_eq!(report.top_endpoints[0].name, "/document/*/insert");
+        assert_eq!(report.top_endpoints[0].rejected, 2);
+        assert_eq!(report.policies[0].requests, 6);
+
+        // the next window starts empty, the totals keep counting
+        analytics.record("10.0.0.1", "/", "read", 2, true);
+        let report = analytics.roll(121_000);
+        assert_eq!((report.start_ms, report.requests), (61_000, 3));
+        let text = analytics.to_prometheus();
+        assert!(text.contains("rate_limiter_requests_total 9"));
+        assert!(text.contains("rate_limiter_window_policy_requests{policy=\"read\"} 3"));
+        assert!(text.contains("rate_limiter_window_ip_rejected{ip=\"10.0.0.1\"} 0"));
+    }
+
+    #[test]
+    fn test_endpoint_pattern() {
+        assert_eq!(
+            endpoint_pattern("/document/12/runs/3?a=1"),
+            "/document/*/runs/*"
+        );
+        assert_eq!(endpoint_pattern("/create_document"), "/create_document");
+        assert_eq!(endpoint_pattern("/"), "/");
+        assert_eq!(escape_label("a\"b\\"), "a\\\"b\\\\");
+    }
+
+    #[test]
+    fn test_sink_target() {
+        assert_eq!(
+            SinkTarget::parse("kinesis://rate-limits"),
+            Ok(SinkTarget::Kinesis {
+                stream: "rate-limits".to_string()
+            })
+        );
+        assert_eq!(
+            SinkTarget::parse("s3://analytics/rate-limiter/"),
+            Ok(SinkTarget::S3 {
+                bucket: "analytics".to_string(),
+                prefix: "rate-limiter".to_string()
+            })
+        );
+        assert!(SinkTarget::parse("s3://").is_err());
+        assert!(SinkTarget::parse("kinesis://").is_err());
+        assert!(SinkTarget::parse("sqs://queue").is_err());
+    }
+
+    #[test]
+    fn test_respond() {
+        let analytics = Mutex::new(Analytics::new(10, 0));
+        let response = String::from_utf8(respond("GET /analytics HTTP/1.1", &analytics)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK") && response.ends_with("null"));
+
+        analytics
+            .lock()
+            .unwrap()
+            .record("10.0.0.1", "/", "read", 0, false);
+        analytics.lock().unwrap().roll(1_000);
+        let response = String::from_utf8(respond("GET /analytics HTTP/1.1", &analytics)).unwrap();
+        assert!(response.contains("\"top_ips\":[{\"name\":\"10.0.0.1\""));
+        let response = String::from_utf8(respond("GET /metrics HTTP/1.1", &analytics)).unwrap();
+        assert!(response.contains("rate_limiter_rejected_total 1"));
+        assert!(respond("POST /metrics HTTP/1.1", &analytics).starts_with(b"HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,26 @@
+pub mod algorithms;
+pub use algorithms::*;
+
+pub mod analytics;
+pub use analytics::*;
+
+pub mod limiter;
+pub use limiter::*;
+
+pub mod penalty;
+pub use penalty::*;
+
+pub mod store;
+pub use store::*;
+
+pub mod server;
+pub use server::*;
+
+#[cfg(feature = "aws")]
+pub mod sink;
+#[cfg(feature = "aws")]
+pub use sink::*;
+
+pub mod rate_limiter_proto {
+    include!("proto/rate_limiter.rs");
+}
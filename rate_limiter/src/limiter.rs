@@ -0,0 +1,217 @@
+use crate::{AlgorithmConfig, Decision, RateLimitAlgorithm};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Maximum number of client keys held before idle entries are evicted.
+const MAX_KEYS: usize = 100_000;
+
+/// The key a client is limited under for a policy.
+pub fn client_key(ip_address: &str, policy: &str) -> String {
+    format!("{}|{}", ip_address, policy)
+}
+
+/// Returns true if the client key belongs to the client, and to the policy if one is given.
+pub fn key_matches(key: &str, ip_address: &str, policy: Option<&str>) -> bool {
+    match policy {
+        Some(policy) => key == client_key(ip_address, policy),
+        None => key
+            .strip_prefix(ip_address)
+            .is_some_and(|rest| rest.starts_with('|')),
+    }
+}
+
+/// State held for a single client key.
+struct Entry {
+    config: AlgorithmConfig,
+    algorithm: Box<dyn RateLimitAlgorithm>,
+    last_seen: Instant,
+    rejected: u64,
+}
+
+/// The state of a client key, read without recording a request.
+/// `key`: The client key.
+/// `config`: The configuration the key is limited with.
+/// `decision`: Whether a request now would be allowed, and the requests left.
+/// `rejected`: The requests rejected since the key was first seen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyState {
+    pub key: String,
+    pub config: AlgorithmConfig,
+    pub decision: Decision,
+    pub rejected: u64,
+}
+
+/// Keeps a rate limiting algorithm instance for every client key (e.g. IP address + policy).
+/// A key whose policy configuration changes starts again with fresh state.
+#[derive(Default)]
+pub struct Limiter {
+    entries: HashMap<String, Entry>,
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Limiter {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Records a request for `key` and returns whether it is allowed under `config`.
+    pub fn check(&mut self, key: &str, config: &AlgorithmConfig, now: Instant) -> Decision {
+        if self.entries.len() >= MAX_KEYS && !self.entries.contains_key(key) {
+            self.evict_idle(now);
+        }
+
+        let entry = self
+            .entries
+            .entry(key.to_string())
+            .or_insert_with(|| Entry {
+                config: *config,
+                algorithm: config.build(now),
+                last_seen: now,
+                rejected: 0,
+            });
+
+        if entry.config != *config {
+            entry.config = *config;
+            entry.algorithm = config.build(now);
+        }
+
+        entry.last_seen = now;
+        let decision = entry.algorithm.check(now);
+        if !decision.allowed {
+            entry.rejected += 1;
+        }
+        decision
+    }
+
+    /// The state of the keys `matches` selects, sorted by key.
+    pub fn states(&self, matches: impl Fn(&str) -> bool, now: Instant) -> Vec<KeyState> {
+        let mut states: Vec<KeyState> = self
+            .entries
+            .iter()
+            .filter(|(key, _)| matches(key))
+            .map(|(key, entry)| KeyState {
+                key: key.clone(),
+                config: entry.config,
+                decision: entry.algorithm.peek(now),
+                rejected: entry.rejected,
+            })
+            .collect();
+        states.sort_by(|a, b| a.key.cmp(&b.key));
+        states
+    }
+
+    /// Forgets the keys `matches` selects, so their next requests start with a full quota.
+    /// Returns the number of keys reset.
+    pub fn reset(&mut self, matches: impl Fn(&str) -> bool) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|key, _| !matches(key));
+        before - self.entries.len()
+    }
+
+    /// The `limit` keys with the most rejected requests, most rejected first.
+    pub fn offenders(&self, limit: usize, now: Instant) -> Vec<KeyState> {
+        let mut offenders: Vec<KeyState> = self
+            .states(|_| true, now)
+            .into_iter()
+            .filter(|state| state.rejected > 0)
+            .collect();
+        offenders.sort_by(|a, b| b.rejected.cmp(&a.rejected).then(a.key.cmp(&b.key)));
+        offenders.truncate(limit);
+        offenders
+    }
+
+    /// Removes keys that have not been seen for longer than their window.
+    pub fn evict_idle(&mut self, now: Instant) {
+        self.entries.retain(|_, entry| {
+            now.saturating_duration_since(entry.last_seen)
+                < entry.config.window.max(Duration::from_secs(1))
+        });
+    }
+
+    /// The number of client keys currently tracked.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Algorithm;
+
+    #[test]
+    fn test_keys_are_limited_independently() {
+        let now = Instant::now();
+        let config = AlgorithmConfig::new(Algorithm::FixedWindow, 1, Duration::from_secs(60));
+        let mut limiter = Limiter::new();
+
+        assert!(limiter.check("10.0.0.1", &config, now).allowed);
+        assert!(!limiter.check("10.0.0.1", &config, now).allowed);
+        assert!(limiter.check("10.0.0.2", &config, now).allowed);
+        assert_eq!(limiter.len(), 2);
+    }
+
+    #[test]
+    fn test_config_change_resets_state() {
+        let now = Instant::now();
+        let strict = AlgorithmConfig::new(Algorithm::FixedWindow, 1, Duration::from_secs(60));
+        let relaxed = AlgorithmConfig::new(Algorithm::TokenBucket, 10, Duration::from_secs(1));
+        let mut limiter = Limiter::new();
+
+        assert!(limiter.check("key", &strict, now).allowed);
+        assert!(!limiter.check("key", &strict, now).allowed);
+        assert!(limiter.check("key", &relaxed, now).allowed);
+    }
+
+    #[test]
+    fn test_states_reset_and_offenders() {
+        let now = Instant::now();
+        let config = AlgorithmConfig::new(Algorithm::FixedWindow, 1, Duration::from_secs(60));
+        let mut limiter = Limiter::new();
+
+        for key in [
+            "10.0.0.1|login",
+            "10.0.0.1|login",
+            "10.0.0.1|login",
+            "10.0.0.1|read",
+        ] {
+            limiter.check(key, &config, now);
+        }
+        limiter.check("10.0.0.10|login", &config, now);
+        limiter.check("10.0.0.10|login", &config, now);
+
+        let states = limiter.states(|key| key_matches(key, "10.0.0.1", None), now);
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].key, "10.0.0.1|login");
+        assert!(!states[0].decision.allowed);
+        assert_eq!(states[0].rejected, 2);
+
+        let offenders = limiter.offenders(10, now);
+        assert_eq!(offenders.len(), 2);
+        assert_eq!(offenders[0].key, "10.0.0.1|login");
+        assert_eq!(limiter.offenders(1, now).len(), 1);
+
+        assert_eq!(
+            limiter.reset(|key| key_matches(key, "10.0.0.1", Some("login"))),
+            1
+        );
+        assert!(limiter.check("10.0.0.1|login", &config, now).allowed);
+        assert!(!limiter.check("10.0.0.10|login", &config, now).allowed);
+    }
+
+    #[test]
+    fn test_evict_idle() {
+        let now = Instant::now();
+        let config = AlgorithmConfig::new(Algorithm::FixedWindow, 1, Duration::from_secs(1));
+        let mut limiter = Limiter::new();
+
+        limiter.check("key", &config, now);
+        limiter.evict_idle(now + Duration::from_secs(2));
+        assert!(limiter.is_empty());
+    }
+}
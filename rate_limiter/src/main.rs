@@ -1,3 +1,231 @@
-fn main() {
-    println!("Hello, world!");
+use dotenv::dotenv;
+use rate_limiter::rate_limiter_proto::rate_limiter_server::RateLimiterServer;
+use rate_limiter::{
+    now_ms, Analytics, MemoryStore, PenaltyConfig, RateLimiterService, Store, StoreError,
+};
+use std::env;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tonic::transport::Server;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:50051";
+const DEFAULT_PENALTY_FILE: &str = "rate_limiter_penalties.txt";
+const DEFAULT_ANALYTICS_WINDOW_SECS: u64 = 60;
+const DEFAULT_ANALYTICS_TOP: usize = 10;
+
+/// Seconds between evictions of idle client keys.
+const EVICT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    // Load the .env file
+    dotenv().ok();
+
+    let address: SocketAddr = match env::var("RATE_LIMITER_LISTEN_ADDRESS")
+        .unwrap_or(DEFAULT_LISTEN_ADDRESS.to_string())
+        .parse()
+    {
+        Ok(address) => address,
+        Err(_) => {
+            error!("Invalid RATE_LIMITER_LISTEN_ADDRESS");
+            std::process::exit(1);
+        }
+    };
+
+    let penalties = PenaltyConfig::from_env();
+    let store: Arc<dyn Store> = match load_store(penalties.is_some()).await {
+        Ok(store) => store,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let evicted: Arc<dyn Store> = Arc::clone(&store);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EVICT_INTERVAL);
+        loop {
+            interval.tick().await;
+            evicted.evict_idle().await;
+            evicted.persist().await;
+        }
+    });
+
+    info!("Rate limiter listening on {}", address);
+
+    let analytics = match start_analytics().await {
+        Ok(analytics) => analytics,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let service = RateLimiterService::new(Arc::clone(&store))
+        .with_penalties(penalties)
+        .with_analytics(analytics);
+    Server::builder()
+        .add_service(RateLimiterServer::new(service))
+        .serve_with_shutdown(address, async {
+            let _ = tokio::signal::ctrl_c().await;
+            info!("Rate limiter shutting down");
+        })
+        .await?;
+    store.persist().await;
+
+    Ok(())
+}
+
+/// Creates the store selected by the environment (.env file).
+/// `RATE_LIMITER_STORE`: `memory` (default) or `redis`.
+/// `RATE_LIMITER_REDIS_URL`: The Redis server of the redis store.
+/// `RATE_LIMITER_REDIS_PREFIX`: The prefix of every key written to Redis.
+/// `RATE_LIMITER_PENALTY_FILE`: Where the memory store keeps penalty records when `penalties`
+/// are enabled, rate_limiter_penalties.txt by default.
+async fn load_store(penalties: bool) -> Result<Arc<dyn Store>, StoreError> {
+    let kind = env::var("RATE_LIMITER_STORE").unwrap_or("memory".to_string());
+
+    match kind.trim().to_lowercase().as_str() {
+        "memory" if penalties => {
+            let path =
+                env::var("RATE_LIMITER_PENALTY_FILE").unwrap_or(DEFAULT_PENALTY_FILE.to_string());
+            info!("Keeping rate limits in memory and penalties in {}", path);
+            Ok(Arc::new(MemoryStore::with_penalty_file(Path::new(&path))?))
+        }
+        "memory" => {
+            info!("Keeping rate limits in memory");
+            Ok(Arc::new(MemoryStore::new()))
+        }
+        #[cfg(feature = "redis")]
+        "redis" => {
+            let url =
+                env::var("RATE_LIMITER_REDIS_URL").unwrap_or("redis://127.0.0.1/".to_string());
+            let prefix =
+                env::var("RATE_LIMITER_REDIS_PREFIX").unwrap_or("rate_limiter".to_string());
+            let store = rate_limiter::RedisStore::connect(&url, &prefix).await?;
+            info!("Keeping rate limits in Redis at {}", url);
+            Ok(Arc::new(store))
+        }
+        #[cfg(not(feature = "redis"))]
+        "redis" => Err(StoreError::Unavailable(
+            "The rate limiter was built without the redis feature".to_string(),
+        )),
+        other => Err(StoreError::Unavailable(format!(
+            "Unknown RATE_LIMITER_STORE {}",
+            other
+        ))),
+    }
+}
+
+/// Starts collecting analytics when they are served or exported, configured from the
+/// environment (.env file), None when they are neither.
+/// `RATE_LIMITER_METRICS_ADDRESS`: Serves `/metrics` and `/analytics` on this address.
+/// `RATE_LIMITER_ANALYTICS_SINK`: `kinesis://<stream>` or `s3://<bucket>/<prefix>`, written to
+/// with the AWS credentials and region of the environment (needs the aws feature).
+/// `RATE_LIMITER_ANALYTICS_ENDPOINT`: Overrides the sink's AWS endpoint.
+/// `RATE_LIMITER_ANALYTICS_WINDOW_SECS`: The length of a window, 60 by default.
+/// `RATE_LIMITER_ANALYTICS_TOP`: The clients and endpoints listed per window, 10 by default.
+async fn start_analytics() -> Result<Option<Arc<Mutex<Analytics>>>, String> {
+    let address = env::var("RATE_LIMITER_METRICS_ADDRESS")
+        .ok()
+        .filter(|address| !address.trim().is_empty());
+    let sink = env::var("RATE_LIMITER_ANALYTICS_SINK")
+        .ok()
+        .filter(|sink| !sink.trim().is_empty());
+    if address.is_none() && sink.is_none() {
+        return Ok(None);
+    }
+
+    let window = Duration::from_secs(
+        env_parse(
+            "RATE_LIMITER_ANALYTICS_WINDOW_SECS",
+            DEFAULT_ANALYTICS_WINDOW_SECS,
+        )
+        .max(1),
+    );
+    let top = env_parse("RATE_LIMITER_ANALYTICS_TOP", DEFAULT_ANALYTICS_TOP);
+    let analytics = Arc::new(Mutex::new(Analytics::new(top, now_ms())));
+
+    if let Some(address) = address {
+        let listener = tokio::net::TcpListener::bind(address.trim())
+            .await
+            .map_err(|e| {
+                format!(
+                    "Failed to bind RATE_LIMITER_METRICS_ADDRESS {}: {}",
+                    address, e
+                )
+            })?;
+        info!("Serving rate limiting analytics on {}", address);
+        tokio::spawn(rate_limiter::analytics::serve(
+            listener,
+            Arc::clone(&analytics),
+        ));
+    }
+
+    #[cfg(feature = "aws")]
+    let sink = match sink {
+        Some(sink) => Some(load_sink(rate_limiter::SinkTarget::parse(&sink)?).await?),
+        None => None,
+    };
+    #[cfg(not(feature = "aws"))]
+    if sink.is_some() {
+        return Err("The rate limiter was built without the aws feature".to_string());
+    }
+
+    let rolled = Arc::clone(&analytics);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(window);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let report = rolled
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .roll(now_ms());
+            info!(
+                "Rate limited {} of {} requests in the last window",
+                report.rejected, report.requests
+            );
+            #[cfg(feature = "aws")]
+            if let Some(sink) = &sink {
+                if let Err(e) = sink.write(&report).await {
+                    error!("Failed to export rate limiting analytics: {}", e);
+                }
+            }
+        }
+    });
+
+    Ok(Some(analytics))
+}
+
+#[cfg(feature = "aws")]
+async fn load_sink(
+    target: rate_limiter::SinkTarget,
+) -> Result<rate_limiter::AnalyticsSink, String> {
+    let aws_config = aws_config::load_from_env().await;
+    let endpoint = env::var("RATE_LIMITER_ANALYTICS_ENDPOINT")
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty());
+    info!("Exporting rate limiting analytics to {:?}", target);
+    rate_limiter::AnalyticsSink::new(&aws_config, target, endpoint)
+}
+
+fn env_parse<T: std::str::FromStr + std::fmt::Display>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => match value.trim().parse::<T>() {
+            Ok(v) => v,
+            Err(_) => {
+                warn!("Invalid value for {}: {}, using {}", key, value, default);
+                default
+            }
+        },
+        Err(_) => default,
+    }
 }
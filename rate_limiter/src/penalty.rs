@@ -0,0 +1,242 @@
+use std::collections::HashSet;
+use std::env;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+const DEFAULT_BASE_MS: u64 = 60 * 1000;
+const DEFAULT_MAX_MS: u64 = 60 * 60 * 1000;
+const DEFAULT_DECAY_MS: u64 = 10 * 60 * 1000;
+
+/// How repeat offenders are greylisted.
+/// `threshold`: Rejected requests that put a client in a penalty.
+/// `base`: The first penalty, each following one is twice as long as the one before.
+/// `max`: The longest penalty.
+/// `decay`: Time without rejected requests after which one penalty level is forgiven.
+/// `allowlist`: Clients that are never greylisted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PenaltyConfig {
+    pub threshold: u64,
+    pub base: Duration,
+    pub max: Duration,
+    pub decay: Duration,
+    pub allowlist: HashSet<IpAddr>,
+}
+
+impl PenaltyConfig {
+    pub fn new(threshold: u64, base: Duration, max: Duration, decay: Duration) -> Self {
+        PenaltyConfig {
+            threshold,
+            base,
+            max,
+            decay,
+            allowlist: HashSet::new(),
+        }
+    }
+
+    /// Reads the configuration from the environment (.env file), None when greylisting is
+    /// disabled.
+    /// `RATE_LIMITER_PENALTY_THRESHOLD`: Rejected requests that put a client in a penalty, 0
+    /// (the default) disables greylisting.
+    /// `RATE_LIMITER_PENALTY_BASE_MS`: The first penalty, 1 minute by default.
+    /// `RATE_LIMITER_PENALTY_MAX_MS`: The longest penalty, 1 hour by default.
+    /// `RATE_LIMITER_PENALTY_DECAY_MS`: Time without rejected requests after which one penalty
+    /// level is forgiven, 10 minutes by default.
+    /// `RATE_LIMITER_PENALTY_ALLOWLIST`: Comma separated IP addresses never greylisted.
+    pub fn from_env() -> Option<Self> {
+        let threshold = env_u64("RATE_LIMITER_PENALTY_THRESHOLD", 0);
+        if threshold == 0 {
+            return None;
+        }
+
+        let mut config = PenaltyConfig::new(
+            threshold,
+            Duration::from_millis(env_u64("RATE_LIMITER_PENALTY_BASE_MS", DEFAULT_BASE_MS)),
+            Duration::from_millis(env_u64("RATE_LIMITER_PENALTY_MAX_MS", DEFAULT_MAX_MS)),
+            Duration::from_millis(env_u64("RATE_LIMITER_PENALTY_DECAY_MS", DEFAULT_DECAY_MS)),
+        );
+        for address in env::var("RATE_LIMITER_PENALTY_ALLOWLIST")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+        {
+            match address.parse::<IpAddr>() {
+                Ok(ip) => {
+                    config.allowlist.insert(ip.to_canonical());
+                }
+                Err(_) => warn!(
+                    "Invalid address in RATE_LIMITER_PENALTY_ALLOWLIST: {}",
+                    address
+                ),
+            }
+        }
+
+        info!(
+            "Greylisting clients after {} rejected requests for {:?} to {:?}",
+            config.threshold, config.base, config.max
+        );
+        Some(config)
+    }
+
+    /// Returns true if the client may be greylisted.
+    pub fn applies_to(&self, ip_address: &str) -> bool {
+        match ip_address.parse::<IpAddr>() {
+            Ok(ip) => !self.allowlist.contains(&ip.to_canonical()),
+            Err(_) => true,
+        }
+    }
+
+    /// The length of a penalty at the level, doubling from `base` up to `max`.
+    pub fn duration(&self, level: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(level.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.base.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// A client's record of rejected requests, in milliseconds since the Unix epoch so it can be
+/// persisted and shared between instances.
+/// `level`: The number of penalties the client has had, without the ones forgiven.
+/// `violations`: Rejected requests since the last penalty.
+/// `until_ms`: When the current penalty ends.
+/// `last_violation_ms`: When the client last had a request rejected.
+/// `expires_ms`: When the record no longer affects any decision and can be dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Penalty {
+    pub level: u32,
+    pub violations: u64,
+    pub until_ms: u64,
+    pub last_violation_ms: u64,
+    pub expires_ms: u64,
+}
+
+impl Penalty {
+    /// How long the client remains in its penalty, None if it is not in one.
+    pub fn remaining(&self, now_ms: u64) -> Option<Duration> {
+        (now_ms < self.until_ms).then(|| Duration::from_millis(self.until_ms - now_ms))
+    }
+
+    /// The level once the levels forgiven since the last rejected request are taken off.
+    pub fn level_at(&self, now_ms: u64, config: &PenaltyConfig) -> u32 {
+        let decay = config.decay.as_millis().max(1) as u64;
+        let forgiven = now_ms.saturating_sub(self.last_violation_ms) / decay;
+        self.level
+            .saturating_sub(forgiven.min(u32::MAX as u64) as u32)
+    }
+
+    /// Records a rejected request, starting the next penalty once the client reaches the
+    /// threshold. Returns true if a penalty was started.
+    pub fn violate(&mut self, now_ms: u64, config: &PenaltyConfig) -> bool {
+        let decay = config.decay.as_millis() as u64;
+        if now_ms.saturating_sub(self.last_violation_ms) >= decay {
+            self.violations = 0;
+        }
+        self.level = self.level_at(now_ms, config);
+        self.violations += 1;
+        self.last_violation_ms = now_ms;
+
+        let started = self.violations >= config.threshold;
+        if started {
+            self.level = self.level.saturating_add(1);
+            self.violations = 0;
+            self.until_ms = now_ms + config.duration(self.level).as_millis() as u64;
+        }
+        self.expires_ms = self
+            .until_ms
+            .max(now_ms + decay.saturating_mul(self.level.max(1) as u64));
+        started
+    }
+}
+
+/// The current time in milliseconds since the Unix epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn env_u64(key: &str, default: u64) -> u64 {
+    match env::var(key) {
+        Ok(value) => match value.trim().parse::<u64>() {
+            Ok(v) => v,
+            Err(_) => {
+                warn!("Invalid value for {}: {}, using {}", key, value, default);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> PenaltyConfig {
+        PenaltyConfig::new(
+            3,
+            Duration::from_secs(60),
+            Duration::from_secs(300),
+            Duration::from_secs(600),
+        )
+    }
+
+    #[test]
+    fn test_penalties_escalate() {
+        let config = config();
+        let mut penalty = Penalty::default();
+        let now = 1_000_000;
+
+        assert!(!penalty.violate(now, &config));
+        assert!(!penalty.violate(now, &config));
+        assert!(penalty.violate(now, &config));
+        assert_eq!(penalty.remaining(now), Some(Duration::from_secs(60)));
+        assert_eq!(penalty.remaining(now + 60_000), None);
+
+        // the next penalties double, up to the maximum
+        let mut now = now + 60_000;
+        for expected in [120, 240, 300] {
+            for _ in 0..3 {
+                penalty.violate(now, &config);
+            }
+            assert_eq!(penalty.remaining(now), Some(Duration::from_secs(expected)));
+            now += expected * 1000;
+        }
+        assert_eq!(penalty.level, 4);
+    }
+
+    #[test]
+    fn test_penalties_decay() {
+        let config = config();
+        let mut penalty = Penalty::default();
+        for _ in 0..6 {
+            penalty.violate(0, &config);
+        }
+        assert_eq!(penalty.level, 2);
+
+        // one level is forgiven per decay period without rejected requests
+        assert_eq!(penalty.level_at(600_000, &config), 1);
+        assert_eq!(penalty.level_at(1_200_000, &config), 0);
+        assert_eq!(penalty.expires_ms, 1_200_000);
+
+        // and rejected requests from before the decay period don't count
+        penalty.violate(600_000, &config);
+        penalty.violate(600_000, &config);
+        assert!(!penalty.violate(1_200_000, &config));
+        assert_eq!(penalty.violations, 1);
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let mut config = config();
+        config.allowlist.insert("10.0.0.1".parse().unwrap());
+        assert!(!config.applies_to("10.0.0.1"));
+        assert!(!config.applies_to("::ffff:10.0.0.1"));
+        assert!(config.applies_to("10.0.0.2"));
+        assert_eq!(config.duration(0), Duration::from_secs(60));
+        assert_eq!(config.duration(40), Duration::from_secs(300));
+    }
+}
@@ -0,0 +1,498 @@
+use crate::rate_limiter_proto::rate_limiter_server::RateLimiter;
+use crate::rate_limiter_proto::{
+    GetQuotaResponse, ListOffendersRequest, ListOffendersResponse, Quota, QuotaRequest,
+    RateLimitPolicy, RateLimitRequest, RateLimitResponse, ResetQuotaResponse,
+};
+use crate::{
+    client_key, now_ms, Algorithm, AlgorithmConfig, Analytics, Decision, KeyState, PenaltyConfig,
+    Store, StoreError,
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tonic::{Request, Response, Status};
+use tracing::{error, info, info_span, warn, Instrument};
+
+/// Offenders returned by `ListOffenders` when the request sets no limit.
+const DEFAULT_OFFENDERS: usize = 10;
+
+/// Converts the policy hint sent by the load balancer into an algorithm configuration.
+pub fn algorithm_config(policy: &RateLimitPolicy) -> AlgorithmConfig {
+    use crate::rate_limiter_proto::Algorithm as ProtoAlgorithm;
+
+    let algorithm = match policy.algorithm() {
+        ProtoAlgorithm::TokenBucket => Algorithm::TokenBucket,
+        ProtoAlgorithm::SlidingWindowLog => Algorithm::SlidingWindowLog,
+        ProtoAlgorithm::FixedWindow => Algorithm::FixedWindow,
+    };
+
+    AlgorithmConfig::new(
+        algorithm,
+        policy.limit,
+        Duration::from_millis(policy.window_ms),
+    )
+}
+
+/// Converts the state of a client key into the quota reported to operators.
+pub fn quota(state: &KeyState) -> Quota {
+    use crate::rate_limiter_proto::Algorithm as ProtoAlgorithm;
+
+    let (ip_address, policy) = state.key.split_once('|').unwrap_or((&state.key, ""));
+    let algorithm = match state.config.algorithm {
+        Algorithm::TokenBucket => ProtoAlgorithm::TokenBucket,
+        Algorithm::SlidingWindowLog => ProtoAlgorithm::SlidingWindowLog,
+        Algorithm::FixedWindow => ProtoAlgorithm::FixedWindow,
+    };
+
+    Quota {
+        ip_address: ip_address.to_string(),
+        policy: Some(RateLimitPolicy {
+            name: policy.to_string(),
+            algorithm: algorithm.into(),
+            limit: state.config.limit,
+            window_ms: state.config.window.as_millis().min(u64::MAX as u128) as u64,
+        }),
+        allowed: state.decision.allowed,
+        remaining: state.decision.remaining,
+        retry_after_ms: retry_after_ms(&state.decision),
+        rejected: state.rejected,
+    }
+}
+
+fn retry_after_ms(decision: &Decision) -> u64 {
+    decision
+        .retry_after
+        .map(|d| d.as_millis().min(u64::MAX as u128) as u64)
+        .unwrap_or(0)
+}
+
+/// The client and policy named by a quota request, an empty policy names every policy.
+/// None if the request names no client.
+fn quota_target(request: &QuotaRequest) -> Option<(&str, Option<&str>)> {
+    if request.ip_address.trim().is_empty() {
+        return None;
+    }
+    let policy = Some(request.policy_name.as_str()).filter(|p| !p.is_empty());
+    Some((request.ip_address.as_str(), policy))
+}
+
+fn unavailable(e: StoreError) -> Status {
+    error!("Failed to reach the rate limit store: {}", e);
+    Status::unavailable(e.to_string())
+}
+
+/// The rate limiter gRPC service, limiting every client IP address per policy.
+/// Keys have the same shape as the load balancer's embedded mode, so switching modes keeps
+/// the same limits. With penalties, clients whose requests keep being rejected are greylisted:
+/// every request they make is rejected until their penalty ends.
+pub struct RateLimiterService {
+    store: Arc<dyn Store>,
+    penalties: Option<PenaltyConfig>,
+    analytics: Option<Arc<Mutex<Analytics>>>,
+}
+
+impl RateLimiterService {
+    pub fn new(store: Arc<dyn Store>) -> Self {
+        RateLimiterService {
+            store,
+            penalties: None,
+            analytics: None,
+        }
+    }
+
+    /// Greylists repeat offenders as configured.
+    pub fn with_penalties(mut self, penalties: Option<PenaltyConfig>) -> Self {
+        self.penalties = penalties;
+        self
+    }
+
+    /// Counts every decision in the analytics.
+    pub fn with_analytics(mut self, analytics: Option<Arc<Mutex<Analytics>>>) -> Self {
+        self.analytics = analytics;
+        self
+    }
+
+    fn record(&self, request: &RateLimitRequest, policy: &RateLimitPolicy, allowed: bool) {
+        if let Some(analytics) = &self.analytics {
+            let mut analytics = analytics.lock().unwrap_or_else(|e| e.into_inner());
+            analytics.record(
+                &request.ip_address,
+                &request.endpoint,
+                &policy.name,
+                request.hits.min(policy.limit),
+                allowed,
+            );
+        }
+    }
+
+    /// The client's remaining penalty, None if it is not in one.
+    async fn penalty_remaining(
+        &self,
+        ip_address: &str,
+        now_ms: u64,
+    ) -> Result<Option<Duration>, StoreError> {
+        if !self
+            .penalties
+            .as_ref()
+            .is_some_and(|config| config.applies_to(ip_address))
+        {
+            return Ok(None);
+        }
+        let penalty = self.store.penalty(ip_address).await?;
+        Ok(penalty.and_then(|penalty| penalty.remaining(now_ms)))
+    }
+
+    /// Records a rejected request against the client, returning the penalty it started.
+    async fn record_violation(
+        &self,
+        ip_address: &str,
+        now_ms: u64,
+    ) -> Result<Option<Duration>, StoreError> {
+        let config = match &self.penalties {
+            Some(config) if config.applies_to(ip_address) => config,
+            _ => return Ok(None),
+        };
+        let mut penalty = self.store.penalty(ip_address).await?.unwrap_or_default();
+        let started = penalty.violate(now_ms, config);
+        self.store.set_penalty(ip_address, &penalty).await?;
+
+        if !started {
+            return Ok(None);
+        }
+        let remaining = penalty.remaining(now_ms);
+        warn!(
+            "Greylisted {} for {:?} after repeated rejected requests (level {})",
+            ip_address,
+            remaining.unwrap_or_default(),
+            penalty.level
+        );
+        Ok(remaining)
+    }
+}
+
+#[tonic::async_trait]
+impl RateLimiter for RateLimiterService {
+    async fn check_request(
+        &self,
+        request: Request<RateLimitRequest>,
+    ) -> Result<Response<RateLimitResponse>, Status> {
+        let mut request: RateLimitRequest = request.into_inner();
+        let span = info_span!(
+            "rate_limiter.check",
+            request_id = %request.request_id,
+            operation_class = request.operation_class().as_str_name()
+        );
+
+        let policy: RateLimitPolicy = match request.policy.take() {
+            Some(policy) => policy,
+            None => {
+                return Err(Status::invalid_argument(
+                    "The request has no rate limiting policy",
+                ))
+            }
+        };
+
+        let now = now_ms();
+        let penalty = self
+            .penalty_remaining(&request.ip_address, now)
+            .await
+            .map_err(unavailable)?;
+        if let Some(remaining) = penalty {
+            self.record(&request, &policy, false);
+            return Ok(Response::new(RateLimitResponse {
+                request_id: request.request_id,
+                allowed: false,
+                limit: policy.limit,
+                remaining: 0,
+                retry_after_ms: remaining.as_millis().min(u64::MAX as u128) as u64,
+            }));
+        }
+
+        let key = client_key(&request.ip_address, &policy.name);
+        // the load balancer can't have allowed more requests than the limit from its cache
+        let hits: u64 = request.hits.min(policy.limit);
+        let mut decision: Decision = match self
+            .store
+            .check(&key, &algorithm_config(&policy), hits)
+            .instrument(span)
+            .await
+        {
+            Ok(decision) => decision,
+            Err(e) => {
+                error!("Failed to check the rate limit: {}", e);
+                return Err(Status::unavailable(e.to_string()));
+            }
+        };
+
+        if !decision.allowed {
+            let penalty = self
+                .record_violation(&request.ip_address, now)
+                .await
+                .map_err(unavailable)?;
+            decision.retry_after = decision.retry_after.max(penalty);
+        }
+        self.record(&request, &policy, decision.allowed);
+
+        Ok(Response::new(RateLimitResponse {
+            request_id: request.request_id,
+            allowed: decision.allowed,
+            limit: decision.limit,
+            remaining: decision.remaining,
+            retry_after_ms: retry_after_ms(&decision),
+        }))
+    }
+
+    async fn get_quota(
+        &self,
+        request: Request<QuotaRequest>,
+    ) -> Result<Response<GetQuotaResponse>, Status> {
+        let request: QuotaRequest = request.into_inner();
+        let (ip_address, policy) = quota_target(&request)
+            .ok_or_else(|| Status::invalid_argument("The request has no IP address"))?;
+
+        let states = self
+            .store
+            .quotas(ip_address, policy)
+            .await
+            .map_err(unavailable)?;
+        Ok(Response::new(GetQuotaResponse {
+            quotas: states.iter().map(quota).collect(),
+        }))
+    }
+
+    async fn reset_quota(
+        &self,
+        request: Request<QuotaRequest>,
+    ) -> Result<Response<ResetQuotaResponse>, Status> {
+        let request: QuotaRequest = request.into_inner();
+        let (ip_address, policy) = quota_target(&request)
+            .ok_or_else(|| Status::invalid_argument("The request has no IP address"))?;
+
+        let reset = self
+            .store
+            .reset(ip_address, policy)
+            .await
+            .map_err(unavailable)?;
+        info!(
+            "Reset {} quotas of {} ({})",
+            reset,
+            ip_address,
+            policy.unwrap_or("every policy")
+        );
+        Ok(Response::new(ResetQuotaResponse { reset }))
+    }
+
+    async fn list_offenders(
+        &self,
+        request: Request<ListOffendersRequest>,
+    ) -> Result<Response<ListOffendersResponse>, Status> {
+        let limit = match request.into_inner().limit {
+            0 => DEFAULT_OFFENDERS,
+            limit => limit as usize,
+        };
+
+        let offenders = self.store.offenders(limit).await.map_err(unavailable)?;
+        Ok(Response::new(ListOffendersResponse {
+            offenders: offenders.iter().map(quota).collect(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limiter_proto::{Algorithm as ProtoAlgorithm, OperationClass};
+    use crate::{MemoryStore, Penalty};
+
+    fn request(ip_address: &str, request_id: &str) -> Request<RateLimitRequest> {
+        Request::new(RateLimitRequest {
+            ip_address: ip_address.to_string(),
+            endpoint: "/create_document".to_string(),
+            request_id: request_id.to_string(),
+            policy: Some(RateLimitPolicy {
+                name: "create_document".to_string(),
+                algorithm: ProtoAlgorithm::FixedWindow.into(),
+                limit: 1,
+                window_ms: 60_000,
+            }),
+            hits: 0,
+            operation_class: OperationClass::Create.into(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_check_request() {
+        let service = RateLimiterService::new(Arc::new(MemoryStore::new()));
+
+        let verdict = service
+            .check_request(request("10.0.0.1", "1"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(verdict.allowed);
+        assert_eq!(verdict.remaining, 0);
+
+        let verdict = service
+            .check_request(request("10.0.0.1", "2"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!verdict.allowed);
+        assert!(verdict.retry_after_ms > 0);
+        assert_eq!(verdict.request_id, "2");
+
+        assert!(
+            service
+                .check_request(request("10.0.0.2", "3"))
+                .await
+                .unwrap()
+                .into_inner()
+                .allowed
+        );
+
+        let mut missing = request("10.0.0.1", "4");
+        missing.get_mut().policy = None;
+        let status = service.check_request(missing).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_quota_admin() {
+        let service = RateLimiterService::new(Arc::new(MemoryStore::new()));
+        for id in ["1", "2", "3"] {
+            service
+                .check_request(request("10.0.0.1", id))
+                .await
+                .unwrap();
+        }
+        let quota_request = |policy_name: &str| {
+            Request::new(QuotaRequest {
+                ip_address: "10.0.0.1".to_string(),
+                policy_name: policy_name.to_string(),
+            })
+        };
+
+        let quotas = service
+            .get_quota(quota_request(""))
+            .await
+            .unwrap()
+            .into_inner()
+            .quotas;
+        assert_eq!(quotas.len(), 1);
+        assert_eq!(quotas[0].ip_address, "10.0.0.1");
+        assert_eq!(quotas[0].policy.as_ref().unwrap().name, "create_document");
+        assert!(!quotas[0].allowed);
+        assert!(quotas[0].retry_after_ms > 0);
+        assert_eq!(quotas[0].rejected, 2);
+
+        let offenders = service
+            .list_offenders(Request::new(ListOffendersRequest { limit: 0 }))
+            .await
+            .unwrap()
+            .into_inner()
+            .offenders;
+        assert_eq!(offenders.len(), 1);
+        assert_eq!(offenders[0].rejected, 2);
+
+        let reset = service
+            .reset_quota(quota_request("create_document"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(reset.reset, 1);
+        assert!(
+            service
+                .check_request(request("10.0.0.1", "4"))
+                .await
+                .unwrap()
+                .into_inner()
+                .allowed
+        );
+
+        let mut missing = quota_request("");
+        missing.get_mut().ip_address = String::new();
+        let status = service.get_quota(missing).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_analytics() {
+        let analytics = Arc::new(Mutex::new(Analytics::new(10, 0)));
+        let service = RateLimiterService::new(Arc::new(MemoryStore::new()))
+            .with_analytics(Some(Arc::clone(&analytics)));
+        for id in ["1", "2"] {
+            service
+                .check_request(request("10.0.0.1", id))
+                .await
+                .unwrap();
+        }
+
+        let report = analytics.lock().unwrap().roll(1_000);
+        assert_eq!((report.requests, report.rejected), (2, 1));
+        assert_eq!(report.top_ips[0].name, "10.0.0.1");
+        assert_eq!(report.top_endpoints[0].name, "/create_document");
+        assert_eq!(report.policies[0].name, "create_document");
+    }
+
+    #[tokio::test]
+    async fn test_penalties() {
+        let store = Arc::new(MemoryStore::new());
+        let mut config = PenaltyConfig::new(
+            2,
+            Duration::from_secs(120),
+            Duration::from_secs(3600),
+            Duration::from_secs(600),
+        );
+        config.allowlist.insert("10.0.0.9".parse().unwrap());
+        let service = RateLimiterService::new(store.clone()).with_penalties(Some(config));
+
+        // the second rejected request starts a penalty longer than the policy's window
+        for id in ["1", "2"] {
+            service
+                .check_request(request("10.0.0.1", id))
+                .await
+                .unwrap();
+        }
+        let verdict = service
+            .check_request(request("10.0.0.1", "3"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!verdict.allowed);
+        assert!(verdict.retry_after_ms > 60_000);
+        assert_eq!(store.penalty("10.0.0.1").await.unwrap().unwrap().level, 1);
+
+        // the penalty holds after the quota is reset for the policy, but not for every policy
+        let reset = |policy_name: &str| {
+            Request::new(QuotaRequest {
+                ip_address: "10.0.0.1".to_string(),
+                policy_name: policy_name.to_string(),
+            })
+        };
+        service.reset_quota(reset("create_document")).await.unwrap();
+        let verdict = service
+            .check_request(request("10.0.0.1", "4"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!verdict.allowed);
+        service.reset_quota(reset("")).await.unwrap();
+        assert_eq!(store.penalty("10.0.0.1").await.unwrap(), None::<Penalty>);
+        assert!(
+            service
+                .check_request(request("10.0.0.1", "5"))
+                .await
+                .unwrap()
+                .into_inner()
+                .allowed
+        );
+
+        // allowlisted clients are only limited by the policy
+        for id in ["6", "7", "8"] {
+            service
+                .check_request(request("10.0.0.9", id))
+                .await
+                .unwrap();
+        }
+        assert_eq!(store.penalty("10.0.0.9").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,160 @@
+//! Writes window reports to Kinesis or S3, signing requests with the rate limiter's AWS
+//! credentials.
+
+use crate::{SinkTarget, WindowReport};
+use aws_credential_types::provider::{ProvideCredentials, SharedCredentialsProvider};
+use aws_sigv4::http_request::{
+    sign, PayloadChecksumKind, SignableBody, SignableRequest, SigningSettings,
+    UriPathNormalizationMode,
+};
+use aws_sigv4::sign::v4;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::time::SystemTime;
+
+/// Writes every window report to the target.
+/// `endpoint`: Overrides the AWS endpoint of the target's service, such as a local emulator.
+pub struct AnalyticsSink {
+    http: reqwest::Client,
+    credentials: SharedCredentialsProvider,
+    region: String,
+    target: SinkTarget,
+    endpoint: Option<String>,
+}
+
+impl AnalyticsSink {
+    /// Creates the sink with the credentials and region of the AWS configuration.
+    pub fn new(
+        aws_config: &aws_config::SdkConfig,
+        target: SinkTarget,
+        endpoint: Option<String>,
+    ) -> Result<Self, String> {
+        let credentials = aws_config
+            .credentials_provider()
+            .ok_or("No AWS credentials configured")?;
+        let region = aws_config
+            .region()
+            .map(|region| region.to_string())
+            .ok_or("No AWS region configured")?;
+
+        Ok(AnalyticsSink {
+            http: reqwest::Client::new(),
+            credentials,
+            region,
+            target,
+            endpoint,
+        })
+    }
+
+    /// Writes the report as JSON.
+    pub async fn write(&self, report: &WindowReport) -> Result<(), String> {
+        let key = report_key(report.start_ms);
+        let report = serde_json::to_vec(report).map_err(|e| e.to_string())?;
+
+        let (service, method, url, headers, body) = match &self.target {
+            SinkTarget::Kinesis { stream } => {
+                let url = match &self.endpoint {
+                    Some(endpoint) => format!("{}/", endpoint.trim_end_matches('/')),
+                    None => format!("https://kinesis.{}.amazonaws.com/", self.region),
+                };
+                let body = serde_json::json!({
+                    "StreamName": stream,
+                    "Data": BASE64.encode(report),
+                    "PartitionKey": key,
+                });
+                let headers = vec![
+                    ("content-type", "application/x-amz-json-1.1"),
+                    ("x-amz-target", "Kinesis_20131202.PutRecord"),
+                ];
+                let body = body.to_string().into_bytes();
+                ("kinesis", reqwest::Method::POST, url, headers, body)
+            }
+            SinkTarget::S3 { bucket, prefix } => {
+                let base = match &self.endpoint {
+                    Some(endpoint) => format!("{}/{}", endpoint.trim_end_matches('/'), bucket),
+                    None => format!("https://{}.s3.{}.amazonaws.com", bucket, self.region),
+                };
+                let url = match prefix.as_str() {
+                    "" => format!("{}/{}.json", base, key),
+                    prefix => format!("{}/{}/{}.json", base, prefix, key),
+                };
+                let headers = vec![("content-type", "application/json")];
+                ("s3", reqwest::Method::PUT, url, headers, report)
+            }
+        };
+
+        let status = self.send(service, method, &url, &headers, body).await?;
+        if !status.is_success() {
+            return Err(format!(
+                "{} rejected the report with status {}",
+                service, status
+            ));
+        }
+        Ok(())
+    }
+
+    /// Signs the request with SigV4 and sends it, returning the response status.
+    async fn send(
+        &self,
+        service: &str,
+        method: reqwest::Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::StatusCode, String> {
+        let url =
+            reqwest::Url::parse(url).map_err(|_| format!("Invalid {} url {}", service, url))?;
+        let credentials = self
+            .credentials
+            .provide_credentials()
+            .await
+            .map_err(|_| "Failed to load AWS credentials".to_string())?;
+        let host: String = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let mut settings = SigningSettings::default();
+        if service == "s3" {
+            settings.payload_checksum_kind = PayloadChecksumKind::XAmzSha256;
+            settings.uri_path_normalization_mode = UriPathNormalizationMode::Disabled;
+        }
+        let identity = credentials.into();
+        let params = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(&self.region)
+            .name(service)
+            .time(SystemTime::now())
+            .settings(settings)
+            .build()
+            .map_err(|_| format!("Failed to build {} signing parameters", service))?
+            .into();
+        let signable = SignableRequest::new(
+            method.as_str(),
+            url.as_str(),
+            std::iter::once(("host", host.as_str())).chain(headers.iter().copied()),
+            SignableBody::Bytes(&body),
+        );
+        let instructions = signable
+            .and_then(|request| sign(request, &params))
+            .map_err(|_| format!("Failed to sign {} request", service))?
+            .into_parts()
+            .0;
+
+        let mut request = self.http.request(method, url).body(body);
+        for (name, value) in headers.iter().copied().chain(instructions.headers()) {
+            request = request.header(name, value);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("Failed to reach {}: {}", service, e))?;
+        Ok(response.status())
+    }
+}
+
+/// The name of a report: its window's start in milliseconds, padded so names sort in time
+/// order.
+fn report_key(start_ms: u64) -> String {
+    format!("{:020}", start_ms)
+}
@@ -0,0 +1,763 @@
+use crate::{key_matches, now_ms, AlgorithmConfig, Decision, KeyState, Limiter, Penalty};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+use thiserror::Error;
+use tracing::error;
+
+/// Errors returned by a store.
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("Rate limit store unavailable: {0}")]
+    Unavailable(String),
+}
+
+/// Where the state of every client key is kept.
+/// The in-memory store limits clients per rate limiter instance, a shared store (such as
+/// Redis) lets several instances enforce the same limits and keeps them across restarts.
+#[tonic::async_trait]
+pub trait Store: Send + Sync {
+    /// Records a request for `key` and returns whether it is allowed under `config`.
+    /// `hits` earlier requests, already allowed by the caller without asking, are recorded first.
+    async fn check(
+        &self,
+        key: &str,
+        config: &AlgorithmConfig,
+        hits: u64,
+    ) -> Result<Decision, StoreError>;
+
+    /// Drops state that no longer affects any decision.
+    async fn evict_idle(&self) {}
+
+    /// The state of the client's keys, of every policy or only `policy`, without recording a
+    /// request.
+    async fn quotas(
+        &self,
+        ip_address: &str,
+        policy: Option<&str>,
+    ) -> Result<Vec<KeyState>, StoreError>;
+
+    /// Forgets the client's keys, of every policy or only `policy`, so its next requests start
+    /// with a full quota. Resetting every policy also lifts the client's penalty. Returns the
+    /// number of keys reset.
+    async fn reset(&self, ip_address: &str, policy: Option<&str>) -> Result<u64, StoreError>;
+
+    /// The `limit` keys with the most rejected requests, most rejected first.
+    async fn offenders(&self, limit: usize) -> Result<Vec<KeyState>, StoreError>;
+
+    /// The client's penalty record, None if it has none.
+    async fn penalty(&self, ip_address: &str) -> Result<Option<Penalty>, StoreError>;
+
+    /// Stores the client's penalty record until it expires.
+    async fn set_penalty(&self, ip_address: &str, penalty: &Penalty) -> Result<(), StoreError>;
+
+    /// Writes state that must survive a restart.
+    async fn persist(&self) {}
+}
+
+/// Keeps the state of every client key in process. Penalty records are written to the
+/// penalty file, if one is set, so they survive restarts.
+#[derive(Default)]
+pub struct MemoryStore {
+    limiter: Mutex<Limiter>,
+    penalties: Mutex<HashMap<String, Penalty>>,
+    penalty_file: Option<PathBuf>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    /// Keeps penalty records in the file at `path`, loading the ones it holds.
+    pub fn with_penalty_file(path: &Path) -> Result<Self, StoreError> {
+        let penalties = match fs::read_to_string(path) {
+            Ok(contents) => parse_penalties(&contents).map_err(|e| {
+                StoreError::Unavailable(format!("Invalid penalty file {}: {}", path.display(), e))
+            })?,
+            Err(_) => HashMap::new(),
+        };
+
+        Ok(MemoryStore {
+            penalties: Mutex::new(penalties),
+            penalty_file: Some(path.to_path_buf()),
+            ..MemoryStore::default()
+        })
+    }
+
+    /// The number of client keys currently tracked.
+    pub fn len(&self) -> usize {
+        self.limiter.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[tonic::async_trait]
+impl Store for MemoryStore {
+    async fn check(
+        &self,
+        key: &str,
+        config: &AlgorithmConfig,
+        hits: u64,
+    ) -> Result<Decision, StoreError> {
+        let mut limiter = self.limiter.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        for _ in 0..hits {
+            limiter.check(key, config, now);
+        }
+        Ok(limiter.check(key, config, now))
+    }
+
+    async fn evict_idle(&self) {
+        let mut limiter = self.limiter.lock().unwrap_or_else(|e| e.into_inner());
+        limiter.evict_idle(Instant::now());
+
+        let now = now_ms();
+        let mut penalties = self.penalties.lock().unwrap_or_else(|e| e.into_inner());
+        penalties.retain(|_, penalty| now < penalty.expires_ms);
+    }
+
+    async fn quotas(
+        &self,
+        ip_address: &str,
+        policy: Option<&str>,
+    ) -> Result<Vec<KeyState>, StoreError> {
+        let limiter = self.limiter.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(limiter.states(|key| key_matches(key, ip_address, policy), Instant::now()))
+    }
+
+    async fn reset(&self, ip_address: &str, policy: Option<&str>) -> Result<u64, StoreError> {
+        if policy.is_none() {
+            let mut penalties = self.penalties.lock().unwrap_or_else(|e| e.into_inner());
+            penalties.remove(ip_address);
+        }
+        let mut limiter = self.limiter.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(limiter.reset(|key| key_matches(key, ip_address, policy)) as u64)
+    }
+
+    async fn offenders(&self, limit: usize) -> Result<Vec<KeyState>, StoreError> {
+        let limiter = self.limiter.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(limiter.offenders(limit, Instant::now()))
+    }
+
+    async fn penalty(&self, ip_address: &str) -> Result<Option<Penalty>, StoreError> {
+        let penalties = self.penalties.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(penalties.get(ip_address).copied())
+    }
+
+    async fn set_penalty(&self, ip_address: &str, penalty: &Penalty) -> Result<(), StoreError> {
+        let mut penalties = self.penalties.lock().unwrap_or_else(|e| e.into_inner());
+        penalties.insert(ip_address.to_string(), *penalty);
+        Ok(())
+    }
+
+    async fn persist(&self) {
+        let path = match &self.penalty_file {
+            Some(path) => path,
+            None => return,
+        };
+        let contents = {
+            let penalties = self.penalties.lock().unwrap_or_else(|e| e.into_inner());
+            format_penalties(&penalties)
+        };
+        // written next to the file first, so a crash never leaves it half written
+        let temporary = path.with_extension("tmp");
+        if let Err(e) = fs::write(&temporary, contents).and_then(|_| fs::rename(&temporary, path)) {
+            error!("Failed to write {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Formats penalty records one per line:
+/// `<ip address> <level> <violations> <until ms> <last violation ms> <expires ms>`
+fn format_penalties(penalties: &HashMap<String, Penalty>) -> String {
+    let mut lines: Vec<String> = penalties
+        .iter()
+        .map(|(ip_address, p)| {
+            format!(
+                "{} {} {} {} {} {}\n",
+                ip_address, p.level, p.violations, p.until_ms, p.last_violation_ms, p.expires_ms
+            )
+        })
+        .collect();
+    lines.sort();
+    lines.concat()
+}
+
+/// Parses the penalty records written by `format_penalties`, errors as "Line N: ...".
+fn parse_penalties(contents: &str) -> Result<HashMap<String, Penalty>, String> {
+    let mut penalties = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let numbers: Vec<u64> = fields
+            .iter()
+            .skip(1)
+            .map(|field| field.parse::<u64>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("Line {}: invalid number", number + 1))?;
+        match numbers.as_slice() {
+            [level, violations, until_ms, last_violation_ms, expires_ms] => {
+                penalties.insert(
+                    fields[0].to_string(),
+                    Penalty {
+                        level: (*level).min(u32::MAX as u64) as u32,
+                        violations: *violations,
+                        until_ms: *until_ms,
+                        last_violation_ms: *last_violation_ms,
+                        expires_ms: *expires_ms,
+                    },
+                );
+            }
+            _ => return Err(format!(
+                "Line {}: expected <ip> <level> <violations> <until> <last violation> <expires>",
+                number + 1
+            )),
+        }
+    }
+    Ok(penalties)
+}
+
+#[cfg(feature = "redis")]
+pub use self::redis_store::RedisStore;
+
+#[cfg(feature = "redis")]
+mod redis_store {
+    use super::{Store, StoreError};
+    use crate::{key_matches, Algorithm, AlgorithmConfig, Decision, KeyState, Penalty};
+    use redis::aio::ConnectionManager;
+    use redis::Script;
+    use std::time::Duration;
+
+    /// How long a client stays in the offenders after its last rejected request.
+    const OFFENDER_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+
+    /// Each script takes the key, the limit, the window in milliseconds and the earlier hits to
+    /// record, and returns whether the request is allowed, the remaining requests and the
+    /// milliseconds to wait before retrying. Time is read from the Redis server so every
+    /// instance shares a clock.
+    const NOW: &str = "local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local key, limit, window = KEYS[1], tonumber(ARGV[1]), math.max(tonumber(ARGV[2]), 1)
+local hits = tonumber(ARGV[3])
+";
+
+    const TOKEN_BUCKET: &str = "local rate = limit / window
+local state = redis.call('HMGET', key, 'tokens', 'at')
+local tokens = tonumber(state[1]) or limit
+local at = tonumber(state[2]) or now
+tokens = math.min(limit, tokens + math.max(now - at, 0) * rate)
+tokens = math.max(tokens - hits, 0)
+local allowed, retry = 0, 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+elseif rate > 0 then
+    retry = math.ceil((1 - tokens) / rate)
+else
+    retry = window
+end
+redis.call('HSET', key, 'tokens', tostring(tokens), 'at', now)
+redis.call('PEXPIRE', key, window)
+return {allowed, math.floor(tokens), retry}";
+
+    const SLIDING_WINDOW_LOG: &str = "redis.call('ZREMRANGEBYSCORE', key, '-inf', now - window)
+local count = redis.call('ZCARD', key)
+for _ = 1, math.min(hits, limit - count) do
+    redis.call('ZADD', key, now, redis.call('INCR', key .. ':id'))
+    count = count + 1
+end
+if count < limit then
+    local id = redis.call('INCR', key .. ':id')
+    redis.call('ZADD', key, now, id)
+    redis.call('PEXPIRE', key, window)
+    redis.call('PEXPIRE', key .. ':id', window)
+    return {1, limit - count - 1, 0}
+end
+local oldest = redis.call('ZRANGE', key, 0, 0, 'WITHSCORES')
+if oldest[2] == nil then
+    return {0, 0, window}
+end
+return {0, 0, tonumber(oldest[2]) + window - now}";
+
+    const FIXED_WINDOW: &str = "local start = now - now % window
+local window_key = key .. ':' .. start
+local count = tonumber(redis.call('GET', window_key) or '0')
+if hits > 0 then
+    count = redis.call('INCRBY', window_key, hits)
+    redis.call('PEXPIRE', window_key, window)
+end
+if count < limit then
+    count = redis.call('INCR', window_key)
+    redis.call('PEXPIRE', window_key, window)
+    return {1, limit - count, 0}
+end
+return {0, 0, start + window - now}";
+
+    /// The scripts reading a key's state without recording a request, returning the remaining
+    /// requests including the next one.
+    const TOKEN_BUCKET_PEEK: &str = "local rate = limit / window
+local state = redis.call('HMGET', key, 'tokens', 'at')
+local tokens = tonumber(state[1]) or limit
+local at = tonumber(state[2]) or now
+tokens = math.min(limit, tokens + math.max(now - at, 0) * rate)
+if tokens >= 1 then
+    return {1, math.floor(tokens), 0}
+elseif rate > 0 then
+    return {0, 0, math.ceil((1 - tokens) / rate)}
+end
+return {0, 0, window}";
+
+    const SLIDING_WINDOW_LOG_PEEK: &str = "local since = '(' .. (now - window)
+local count = redis.call('ZCOUNT', key, since, '+inf')
+if count < limit then
+    return {1, limit - count, 0}
+end
+local oldest = redis.call('ZRANGEBYSCORE', key, since, '+inf', 'WITHSCORES', 'LIMIT', 0, 1)
+if oldest[2] == nil then
+    return {0, 0, window}
+end
+return {0, 0, tonumber(oldest[2]) + window - now}";
+
+    const FIXED_WINDOW_PEEK: &str = "local start = now - now % window
+local count = tonumber(redis.call('GET', key .. ':' .. start) or '0')
+if count < limit then
+    return {1, limit - count, 0}
+end
+return {0, 0, start + window - now}";
+
+    /// Keeps the state of every client key in Redis, shared by every rate limiter instance.
+    /// Keys include the policy configuration, so a key whose policy changes starts again with
+    /// fresh state, and expire once they no longer affect any decision. Rejected requests are
+    /// counted per client key in the `<prefix>:offenders` sorted set, and penalty records are
+    /// kept in `<prefix>:penalty:<ip address>` hashes until they expire.
+    pub struct RedisStore {
+        connection: ConnectionManager,
+        prefix: String,
+        token_bucket: Script,
+        sliding_window_log: Script,
+        fixed_window: Script,
+        token_bucket_peek: Script,
+        sliding_window_log_peek: Script,
+        fixed_window_peek: Script,
+    }
+
+    impl RedisStore {
+        /// Connects to the Redis server at `url`, prefixing every key with `prefix`.
+        pub async fn connect(url: &str, prefix: &str) -> Result<RedisStore, StoreError> {
+            let client =
+                redis::Client::open(url).map_err(|e| StoreError::Unavailable(e.to_string()))?;
+            let connection = ConnectionManager::new(client)
+                .await
+                .map_err(|e| StoreError::Unavailable(e.to_string()))?;
+
+            Ok(RedisStore {
+                connection,
+                prefix: prefix.to_string(),
+                token_bucket: Script::new(&format!("{}{}", NOW, TOKEN_BUCKET)),
+                sliding_window_log: Script::new(&format!("{}{}", NOW, SLIDING_WINDOW_LOG)),
+                fixed_window: Script::new(&format!("{}{}", NOW, FIXED_WINDOW)),
+                token_bucket_peek: Script::new(&format!("{}{}", NOW, TOKEN_BUCKET_PEEK)),
+                sliding_window_log_peek: Script::new(&format!(
+                    "{}{}",
+                    NOW, SLIDING_WINDOW_LOG_PEEK
+                )),
+                fixed_window_peek: Script::new(&format!("{}{}", NOW, FIXED_WINDOW_PEEK)),
+            })
+        }
+
+        /// The Redis key holding the state of a client key.
+        fn redis_key(&self, key: &str, config: &AlgorithmConfig) -> String {
+            format!(
+                "{}:{}:{}:{}:{}",
+                self.prefix,
+                config.algorithm,
+                config.limit,
+                window_ms(config),
+                key
+            )
+        }
+
+        fn offenders_key(&self) -> String {
+            format!("{}:offenders", self.prefix)
+        }
+
+        fn penalty_key(&self, ip_address: &str) -> String {
+            format!("{}:penalty:{}", self.prefix, ip_address)
+        }
+
+        /// Splits a Redis key into the configuration and the client key it holds the state of,
+        /// None for keys holding anything else.
+        fn parse_redis_key(&self, redis_key: &str) -> Option<(AlgorithmConfig, String)> {
+            let rest = redis_key.strip_prefix(&format!("{}:", self.prefix))?;
+            let mut parts = rest.splitn(4, ':');
+            let algorithm: Algorithm = parts.next()?.parse().ok()?;
+            let limit: u64 = parts.next()?.parse().ok()?;
+            let window_ms: u64 = parts.next()?.parse().ok()?;
+            // drop the suffix of the sliding window's ids and the fixed window's counters
+            let (ip_address, policy) = parts.next()?.split_once('|')?;
+            let policy = policy.split(':').next().unwrap_or(policy);
+
+            Some((
+                AlgorithmConfig::new(algorithm, limit, Duration::from_millis(window_ms)),
+                crate::client_key(ip_address, policy),
+            ))
+        }
+
+        /// The Redis keys holding state of the client's keys, with the configuration and client
+        /// key each belongs to.
+        async fn scan(
+            &self,
+            ip_address: &str,
+            policy: Option<&str>,
+        ) -> Result<Vec<(String, AlgorithmConfig, String)>, StoreError> {
+            let pattern = format!(
+                "{}:*:{}|{}*",
+                self.prefix,
+                escape(ip_address),
+                policy.map(escape).unwrap_or_default()
+            );
+
+            let mut connection = self.connection.clone();
+            let mut found = Vec::new();
+            let mut cursor: u64 = 0;
+            loop {
+                let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(&pattern)
+                    .arg("COUNT")
+                    .arg(1000)
+                    .query_async(&mut connection)
+                    .await
+                    .map_err(|e| StoreError::Unavailable(e.to_string()))?;
+                for redis_key in keys {
+                    if let Some((config, key)) = self.parse_redis_key(&redis_key) {
+                        if key_matches(&key, ip_address, policy) {
+                            found.push((redis_key, config, key));
+                        }
+                    }
+                }
+                if next == 0 {
+                    return Ok(found);
+                }
+                cursor = next;
+            }
+        }
+
+        async fn peek(
+            &self,
+            key: &str,
+            config: &AlgorithmConfig,
+        ) -> Result<(Decision, u64), StoreError> {
+            let script: &Script = match config.algorithm {
+                Algorithm::TokenBucket => &self.token_bucket_peek,
+                Algorithm::SlidingWindowLog => &self.sliding_window_log_peek,
+                Algorithm::FixedWindow => &self.fixed_window_peek,
+            };
+
+            let mut connection = self.connection.clone();
+            let (allowed, remaining, retry_after_ms): (u64, u64, u64) = script
+                .key(self.redis_key(key, config))
+                .arg(config.limit)
+                .arg(window_ms(config))
+                .arg(0)
+                .invoke_async(&mut connection)
+                .await
+                .map_err(|e| StoreError::Unavailable(e.to_string()))?;
+            let rejected: Option<f64> = redis::cmd("ZSCORE")
+                .arg(self.offenders_key())
+                .arg(key)
+                .query_async(&mut connection)
+                .await
+                .map_err(|e| StoreError::Unavailable(e.to_string()))?;
+
+            let decision = Decision {
+                allowed: allowed == 1,
+                limit: config.limit,
+                remaining,
+                retry_after: (allowed == 0).then(|| Duration::from_millis(retry_after_ms)),
+            };
+            Ok((decision, rejected.unwrap_or(0.0) as u64))
+        }
+    }
+
+    fn window_ms(config: &AlgorithmConfig) -> u64 {
+        config.window.as_millis().min(u64::MAX as u128) as u64
+    }
+
+    /// Escapes the characters special to a `SCAN MATCH` pattern.
+    fn escape(value: &str) -> String {
+        value
+            .chars()
+            .flat_map(|c| match c {
+                '*' | '?' | '[' | ']' | '\\' => vec!['\\', c],
+                c => vec![c],
+            })
+            .collect()
+    }
+
+    #[tonic::async_trait]
+    impl Store for RedisStore {
+        async fn check(
+            &self,
+            key: &str,
+            config: &AlgorithmConfig,
+            hits: u64,
+        ) -> Result<Decision, StoreError> {
+            let script: &Script = match config.algorithm {
+                Algorithm::TokenBucket => &self.token_bucket,
+                Algorithm::SlidingWindowLog => &self.sliding_window_log,
+                Algorithm::FixedWindow => &self.fixed_window,
+            };
+            let mut connection = self.connection.clone();
+            let (allowed, remaining, retry_after_ms): (u64, u64, u64) = script
+                .key(self.redis_key(key, config))
+                .arg(config.limit)
+                .arg(window_ms(config))
+                .arg(hits)
+                .invoke_async(&mut connection)
+                .await
+                .map_err(|e| StoreError::Unavailable(e.to_string()))?;
+
+            if allowed == 0 {
+                redis::pipe()
+                    .cmd("ZINCRBY")
+                    .arg(self.offenders_key())
+                    .arg(1)
+                    .arg(key)
+                    .ignore()
+                    .cmd("PEXPIRE")
+                    .arg(self.offenders_key())
+                    .arg(OFFENDER_TTL_MS)
+                    .ignore()
+                    .query_async::<()>(&mut connection)
+                    .await
+                    .map_err(|e| StoreError::Unavailable(e.to_string()))?;
+            }
+
+            Ok(Decision {
+                allowed: allowed == 1,
+                limit: config.limit,
+                remaining,
+                retry_after: (allowed == 0).then(|| Duration::from_millis(retry_after_ms)),
+            })
+        }
+
+        async fn quotas(
+            &self,
+            ip_address: &str,
+            policy: Option<&str>,
+        ) -> Result<Vec<KeyState>, StoreError> {
+            let mut states: Vec<KeyState> = Vec::new();
+            for (_, config, key) in self.scan(ip_address, policy).await? {
+                if states.iter().any(|s| s.key == key && s.config == config) {
+                    continue;
+                }
+                let (decision, rejected) = self.peek(&key, &config).await?;
+                states.push(KeyState {
+                    key,
+                    config,
+                    decision,
+                    rejected,
+                });
+            }
+            states.sort_by(|a, b| a.key.cmp(&b.key));
+            Ok(states)
+        }
+
+        async fn reset(&self, ip_address: &str, policy: Option<&str>) -> Result<u64, StoreError> {
+            let found = self.scan(ip_address, policy).await?;
+            let mut reset: Vec<(&AlgorithmConfig, &String)> = Vec::new();
+            let mut pipe = redis::pipe();
+            if policy.is_none() {
+                pipe.cmd("DEL").arg(self.penalty_key(ip_address)).ignore();
+            }
+            for (redis_key, config, key) in &found {
+                pipe.cmd("DEL").arg(redis_key).ignore();
+                pipe.cmd("ZREM").arg(self.offenders_key()).arg(key).ignore();
+                if !reset.contains(&(config, key)) {
+                    reset.push((config, key));
+                }
+            }
+
+            let mut connection = self.connection.clone();
+            pipe.query_async::<()>(&mut connection)
+                .await
+                .map_err(|e| StoreError::Unavailable(e.to_string()))?;
+            Ok(reset.len() as u64)
+        }
+
+        async fn offenders(&self, limit: usize) -> Result<Vec<KeyState>, StoreError> {
+            let mut connection = self.connection.clone();
+            let keys: Vec<String> = redis::cmd("ZREVRANGE")
+                .arg(self.offenders_key())
+                .arg(0)
+                .arg(limit as i64 - 1)
+                .query_async(&mut connection)
+                .await
+                .map_err(|e| StoreError::Unavailable(e.to_string()))?;
+
+            // clients whose state has expired are left out
+            let mut offenders: Vec<KeyState> = Vec::new();
+            for key in keys {
+                if let Some((ip_address, policy)) = key.split_once('|') {
+                    offenders.extend(self.quotas(ip_address, Some(policy)).await?);
+                }
+            }
+            offenders.sort_by(|a, b| b.rejected.cmp(&a.rejected).then(a.key.cmp(&b.key)));
+            offenders.truncate(limit);
+            Ok(offenders)
+        }
+
+        async fn penalty(&self, ip_address: &str) -> Result<Option<Penalty>, StoreError> {
+            let mut connection = self.connection.clone();
+            let fields: Vec<Option<u64>> = redis::cmd("HMGET")
+                .arg(self.penalty_key(ip_address))
+                .arg(&["level", "violations", "until", "last_violation", "expires"])
+                .query_async(&mut connection)
+                .await
+                .map_err(|e| StoreError::Unavailable(e.to_string()))?;
+
+            match fields.as_slice() {
+                [Some(level), Some(violations), Some(until_ms), Some(last_violation_ms), Some(expires_ms)] => {
+                    Ok(Some(Penalty {
+                        level: (*level).min(u32::MAX as u64) as u32,
+                        violations: *violations,
+                        until_ms: *until_ms,
+                        last_violation_ms: *last_violation_ms,
+                        expires_ms: *expires_ms,
+                    }))
+                }
+                _ => Ok(None),
+            }
+        }
+
+        async fn set_penalty(&self, ip_address: &str, penalty: &Penalty) -> Result<(), StoreError> {
+            let key = self.penalty_key(ip_address);
+            let mut connection = self.connection.clone();
+            redis::pipe()
+                .cmd("HSET")
+                .arg(&key)
+                .arg("level")
+                .arg(penalty.level)
+                .arg("violations")
+                .arg(penalty.violations)
+                .arg("until")
+                .arg(penalty.until_ms)
+                .arg("last_violation")
+                .arg(penalty.last_violation_ms)
+                .arg("expires")
+                .arg(penalty.expires_ms)
+                .ignore()
+                .cmd("PEXPIREAT")
+                .arg(&key)
+                .arg(penalty.expires_ms)
+                .ignore()
+                .query_async::<()>(&mut connection)
+                .await
+                .map_err(|e| StoreError::Unavailable(e.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Algorithm;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        let store = MemoryStore::new();
+        let config = AlgorithmConfig::new(Algorithm::FixedWindow, 1, Duration::from_millis(10));
+
+        assert!(store.check("10.0.0.1", &config, 0).await.unwrap().allowed);
+        assert!(!store.check("10.0.0.1", &config, 0).await.unwrap().allowed);
+        assert_eq!(store.len(), 1);
+
+        // hits count against the limit like checked requests
+        let config = AlgorithmConfig::new(Algorithm::FixedWindow, 3, Duration::from_millis(10));
+        let decision = store.check("10.0.0.2", &config, 2).await.unwrap();
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        store.evict_idle().await;
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_admin() {
+        let store = MemoryStore::new();
+        let config = AlgorithmConfig::new(Algorithm::FixedWindow, 1, Duration::from_secs(60));
+        for _ in 0..3 {
+            store.check("10.0.0.1|login", &config, 0).await.unwrap();
+        }
+
+        let quotas = store.quotas("10.0.0.1", None).await.unwrap();
+        assert_eq!(quotas.len(), 1);
+        assert_eq!(quotas[0].rejected, 2);
+        assert_eq!(store.offenders(10).await.unwrap()[0].key, quotas[0].key);
+        assert!(store
+            .quotas("10.0.0.1", Some("read"))
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(store.reset("10.0.0.1", Some("login")).await.unwrap(), 1);
+        assert!(
+            store
+                .check("10.0.0.1|login", &config, 0)
+                .await
+                .unwrap()
+                .allowed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_penalties_survive_restarts() {
+        let path = std::env::temp_dir().join(format!("penalties-{}.txt", std::process::id()));
+        let penalty = Penalty {
+            level: 2,
+            violations: 1,
+            until_ms: now_ms() + 60_000,
+            last_violation_ms: now_ms(),
+            expires_ms: now_ms() + 600_000,
+        };
+
+        let store = MemoryStore::with_penalty_file(&path).unwrap();
+        store.set_penalty("10.0.0.1", &penalty).await.unwrap();
+        store
+            .set_penalty(
+                "10.0.0.2",
+                &Penalty {
+                    expires_ms: 1,
+                    ..penalty
+                },
+            )
+            .await
+            .unwrap();
+        store.evict_idle().await;
+        store.persist().await;
+
+        let restarted = MemoryStore::with_penalty_file(&path).unwrap();
+        assert_eq!(restarted.penalty("10.0.0.1").await.unwrap(), Some(penalty));
+        assert_eq!(restarted.penalty("10.0.0.2").await.unwrap(), None);
+
+        // resetting every policy lifts the penalty
+        restarted.reset("10.0.0.1", None).await.unwrap();
+        assert_eq!(restarted.penalty("10.0.0.1").await.unwrap(), None);
+
+        fs::write(&path, "10.0.0.1 2 1\n").unwrap();
+        assert!(MemoryStore::with_penalty_file(&path).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,174 @@
+//! Benchmarks for the RGA hot paths, used to compare the arena-backed node store and
+//! dependency-indexed buffer against planned redesigns of the locking.
+//!
+//! Run with `cargo bench --bench rga`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use nimble::json_structures::DocumentSnapshot;
+use nimble::rga::rga::RGA;
+use nimble::S4Vector;
+use uuid::Uuid;
+
+const SIZES: [usize; 3] = [100, 1_000, 10_000];
+
+/// Builds a document of `size` nodes typed left to right and returns it with its last node.
+fn document(size: usize) -> (RGA, S4Vector) {
+    let mut rga = RGA::new(1, 1);
+    let mut last: Option<S4Vector> = None;
+    for i in 0..size {
+        let op = rga
+            .local_insert(format!("line {}\n", i), last, None, Uuid::nil())
+            .unwrap();
+        last = Some(op.s4vector());
+    }
+    (rga, last.unwrap())
+}
+
+fn local_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("local_insert");
+
+    for size in SIZES {
+        let (mut rga, last) = document(size);
+        let head = rga.head.unwrap();
+
+        group.bench_with_input(BenchmarkId::new("append", size), &size, |b, _| {
+            b.iter(|| {
+                rga.local_insert("x".to_string(), Some(last), None, Uuid::nil())
+                    .unwrap()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("after_head", size), &size, |b, _| {
+            b.iter(|| {
+                rga.local_insert("x".to_string(), Some(head), None, Uuid::nil())
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read");
+
+    for size in SIZES {
+        let (rga, _) = document(size);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter(|| rga.read())
+        });
+    }
+    group.finish();
+}
+
+/// Buffers `size` inserts that depend on a node that has not arrived, then delivers it,
+/// which applies them.
+fn buffered_drain(c: &mut Criterion) {
+    let mut group = c.benchmark_group("buffered_drain");
+    let missing = S4Vector {
+        ssn: 1,
+        sum: 1,
+        sid: 2,
+        seq: 1,
+    };
+
+    for size in [10, 100, 1_000u64] {
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter_batched(
+                || {
+                    let mut rga = RGA::new(1, 1);
+                    for i in 0..size {
+                        let s4vector = S4Vector {
+                            ssn: 1,
+                            sum: i + 2,
+                            sid: 2,
+                            seq: i + 2,
+                        };
+                        rga.remote_insert(i.to_string(), s4vector, Some(missing), None);
+                    }
+                    rga
+                },
+                |mut rga| {
+                    rga.remote_insert("dependency".to_string(), missing, None, None);
+                    rga
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn snapshot_load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("snapshot_load");
+
+    for size in SIZES {
+        let (rga, _) = document(size);
+        let nodes = rga.nodes();
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter_batched(
+                || {
+                    nodes
+                        .iter()
+                        .map(|node| DocumentSnapshot {
+                            document_id: Uuid::nil(),
+                            ssn: node.s4vector.ssn as i64,
+                            sum: node.s4vector.sum as i64,
+                            sid: node.s4vector.sid as i64,
+                            seq: node.s4vector.seq as i64,
+                            value: node.value.clone(),
+                            tombstone: node.tombstone,
+                            author: node.author,
+                            authored_at: node.authored_at.clone(),
+                        })
+                        .collect::<Vec<_>>()
+                },
+                |snapshots| RGA::load_snapshot(snapshots, 1, 1),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Edits in the middle of large documents, each of which finds the node's place in document
+/// order.
+fn large_document(c: &mut Criterion) {
+    let mut group = c.benchmark_group("large_document");
+    group.sample_size(20);
+
+    for size in [10_000, 100_000] {
+        let (mut rga, _) = document(size);
+        let head = rga.head.unwrap();
+        let middle = rga.iter().nth(size / 2).unwrap().s4vector;
+
+        group.bench_with_input(BenchmarkId::new("insert", size), &size, |b, _| {
+            b.iter(|| {
+                rga.local_insert("x".to_string(), Some(middle), None, Uuid::nil())
+                    .unwrap()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("update", size), &size, |b, _| {
+            b.iter(|| {
+                rga.local_update(middle, "y".to_string(), Uuid::nil())
+                    .unwrap()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("move", size), &size, |b, _| {
+            b.iter(|| {
+                rga.local_move(middle, middle, Some(head), None, Uuid::nil())
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    local_insert,
+    read,
+    buffered_drain,
+    snapshot_load,
+    large_document
+);
+criterion_main!(benches);
@@ -0,0 +1,306 @@
+//! Authenticated routes giving operators visibility into the state of a replica:
+//! the loaded documents, their memory usage and version vectors, and the backlog of
+//! broadcast operations waiting to be applied, and the members of the cluster.
+//!
+//! Every route requires `Authorization: Bearer <admin.token>`. The routes are disabled
+//! when no token is configured.
+
+use crate::compaction::{self, Trigger};
+use crate::flags::{Feature, Features};
+use crate::gossip::{MemberView, Membership};
+use crate::routes::SharedRGAs;
+use crate::tenancy::Tenant;
+use crate::{snapshot, tenancy, ApiError, DocumentMemory, MemoryConfig, MemoryReport, RequestId};
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use rocket::{delete, get, post, Request};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// `token`: The bearer token required by the admin routes, the routes are disabled if unset.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub token: Option<String>,
+}
+
+/// The bearer token sent with a request (if any).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdminToken(pub Option<String>);
+
+impl AdminToken {
+    /// Returns `Forbidden` if the admin routes are disabled and `Unauthorized` if the request
+    /// did not send the configured token.
+    pub fn require(&self, config: &AdminConfig) -> Result<(), ApiError> {
+        let expected = match &config.token {
+            Some(token) => token,
+            None => {
+                return Err(ApiError::Forbidden(
+                    "The admin API is disabled on this replica".to_string(),
+                ))
+            }
+        };
+
+        match &self.0 {
+            Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+            _ => {
+                error!("Admin request with a missing or invalid token");
+                Err(ApiError::Unauthorized(
+                    "A valid admin bearer token is required".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminToken {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token: Option<String> = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+
+        Outcome::Success(AdminToken(token))
+    }
+}
+
+/// Compares the tokens without returning early on the first mismatch.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The latest operation seen from each site for a document.
+/// `document_id`: The document.
+/// `session_id`: The session of this replica's RGA.
+/// `local_sequence`: This replica's logical clock for the document.
+/// `sites`: The highest `seq` seen from each site id.
+/// `buffered`: Remote operations waiting on missing dependencies.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionVector {
+    pub document_id: Uuid,
+    pub session_id: u64,
+    pub local_sequence: u64,
+    pub sites: BTreeMap<u64, u64>,
+    pub buffered: usize,
+}
+
+/// Remote operations received over SNS that are waiting on missing dependencies.
+/// `total`: The number of buffered operations on the replica.
+/// `documents`: The number of buffered operations per document (documents with none are omitted).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BroadcastBacklog {
+    pub total: usize,
+    pub documents: BTreeMap<Uuid, usize>,
+}
+
+/// Parses the document id from the path.
+pub(crate) fn parse_document_id(id: &str) -> Result<Uuid, ApiError> {
+    match Uuid::parse_str(id) {
+        Ok(id) => Ok(id),
+        Err(_) => {
+            error!("Failed to parse document id");
+            Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ))
+        }
+    }
+}
+
+fn not_loaded(document_id: &Uuid) -> ApiError {
+    error!("Document {} is not loaded", document_id);
+    ApiError::NotFound(format!("Document {} is not loaded", document_id))
+}
+
+/// Lists the documents loaded on the replica with their node and tombstone counts.
+#[get("/admin/documents")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn list_documents(
+    token: AdminToken,
+    admin: &rocket::State<AdminConfig>,
+    rgas: &rocket::State<SharedRGAs>,
+    memory: &rocket::State<MemoryConfig>,
+    request_id: RequestId,
+) -> Result<Json<Vec<DocumentMemory>>, ApiError> {
+    token.require(admin)?;
+    let rgas = rgas.lock().await;
+    Ok(Json(MemoryReport::collect(&rgas, memory).await.documents))
+}
+
+/// Reports the approximate memory held by each loaded document, largest first.
+#[get("/admin/memory")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn fetch_memory_usage(
+    token: AdminToken,
+    admin: &rocket::State<AdminConfig>,
+    rgas: &rocket::State<SharedRGAs>,
+    memory: &rocket::State<MemoryConfig>,
+    request_id: RequestId,
+) -> Result<Json<MemoryReport>, ApiError> {
+    token.require(admin)?;
+    let rgas = rgas.lock().await;
+    Ok(Json(MemoryReport::collect(&rgas, memory).await))
+}
+
+/// Unloads a document, persisting its snapshot first if it has unsaved changes.
+/// The document is reloaded on the next `GET /document/<id>`.
+#[delete("/admin/documents/<id>")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn unload_document(
+    id: String,
+    token: AdminToken,
+    admin: &rocket::State<AdminConfig>,
+    rgas: &rocket::State<SharedRGAs>,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<(), ApiError> {
+    token.require(admin)?;
+    let document_id: Uuid = parse_document_id(&id)?;
+
+    let mut rgas = rgas.lock().await;
+    let rga = match rgas.get(&document_id) {
+        Some(rga) => rga,
+        None => return Err(not_loaded(&document_id)),
+    };
+
+    if rga.dirty {
+        let mut client = tenancy::lock_any(db).await;
+        snapshot::persist_snapshot(&mut client, &document_id, rga).await?;
+    }
+
+    rgas.remove(&document_id);
+    info!("Document unloaded by an administrator");
+    Ok(())
+}
+
+/// Replaces the document's stored rows with a single consolidated snapshot of its current state.
+/// Returns the number of nodes written.
+#[post("/admin/documents/<id>/compact")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn compact_document(
+    id: String,
+    token: AdminToken,
+    admin: &rocket::State<AdminConfig>,
+    rgas: &rocket::State<SharedRGAs>,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    features: &rocket::State<Features>,
+    request_id: RequestId,
+) -> Result<Json<usize>, ApiError> {
+    token.require(admin)?;
+    features.require(Feature::Compaction, &Tenant::Any)?;
+    let document_id: Uuid = parse_document_id(&id)?;
+
+    let mut rgas = rgas.lock().await;
+    let rga = match rgas.get_mut(&document_id) {
+        Some(rga) => rga,
+        None => return Err(not_loaded(&document_id)),
+    };
+
+    let mut client = tenancy::lock_any(db).await;
+    let nodes: usize = compaction::compact(&mut client, &document_id, rga, Trigger::Admin).await?;
+
+    info!(nodes, "Document compacted by an administrator");
+    Ok(Json(nodes))
+}
+
+/// Returns the latest operation seen from each site for a loaded document.
+#[get("/admin/documents/<id>/version")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn fetch_version_vector(
+    id: String,
+    token: AdminToken,
+    admin: &rocket::State<AdminConfig>,
+    rgas: &rocket::State<SharedRGAs>,
+    request_id: RequestId,
+) -> Result<Json<VersionVector>, ApiError> {
+    token.require(admin)?;
+    let document_id: Uuid = parse_document_id(&id)?;
+
+    let rgas = rgas.lock().await;
+    let rga = match rgas.get(&document_id) {
+        Some(rga) => rga,
+        None => return Err(not_loaded(&document_id)),
+    };
+
+    Ok(Json(VersionVector {
+        document_id,
+        session_id: rga.session_id,
+        local_sequence: rga.local_sequence,
+        sites: rga.version_vector(),
+        buffered: rga.buffered(),
+    }))
+}
+
+/// Shows the remote operations buffered on each document until their dependencies arrive.
+#[get("/admin/broadcasts")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn fetch_broadcast_backlog(
+    token: AdminToken,
+    admin: &rocket::State<AdminConfig>,
+    rgas: &rocket::State<SharedRGAs>,
+    request_id: RequestId,
+) -> Result<Json<BroadcastBacklog>, ApiError> {
+    token.require(admin)?;
+    let rgas = rgas.lock().await;
+
+    let documents: BTreeMap<Uuid, usize> = rgas
+        .iter()
+        .filter(|(_, rga)| rga.buffered() > 0)
+        .map(|(document_id, rga)| (*document_id, rga.buffered()))
+        .collect();
+
+    Ok(Json(BroadcastBacklog {
+        total: documents.values().sum(),
+        documents,
+    }))
+}
+
+/// Shows this replica's view of the cluster's membership, gossiped between replicas.
+#[get("/admin/members")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn fetch_members(
+    token: AdminToken,
+    admin: &rocket::State<AdminConfig>,
+    membership: &rocket::State<Membership>,
+    request_id: RequestId,
+) -> Result<Json<Vec<MemberView>>, ApiError> {
+    token.require(admin)?;
+    Ok(Json(membership.view(Instant::now())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_require_token() {
+        let config = AdminConfig {
+            token: Some("s3cret-admin-token".to_string()),
+        };
+
+        assert!(AdminToken(Some("s3cret-admin-token".to_string()))
+            .require(&config)
+            .is_ok());
+        assert!(matches!(
+            AdminToken(Some("wrong".to_string())).require(&config),
+            Err(ApiError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            AdminToken(None).require(&config),
+            Err(ApiError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            AdminToken(Some("s3cret-admin-token".to_string())).require(&AdminConfig::default()),
+            Err(ApiError::Forbidden(_))
+        ));
+    }
+}
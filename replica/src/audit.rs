@@ -0,0 +1,580 @@
+use crate::auth::{self, AuthConfig, SessionError};
+use crate::forwarded;
+use crate::pool::Pool;
+use crate::share::{self, Access, ShareConfig, ShareError, ShareGrant};
+use crate::tenancy::{self, Tenant};
+use crate::{ApiError, AuditEntry, BroadcastOperation};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use tokio_postgres::{Client, Transaction};
+use tracing::{error, instrument};
+use uuid::Uuid;
+
+/// The user and client address behind a request.
+/// `user_id`: The id of the user whose session token the request carries (if any).
+/// `client_ip`: The client address forwarded by the load balancer, or the peer address.
+/// `share`: The share token sent with the request (if any), verified or the reason it was not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Actor {
+    pub user_id: Option<Uuid>,
+    pub client_ip: Option<String>,
+    pub share: Option<Result<ShareGrant, ShareError>>,
+}
+
+impl Actor {
+    /// Returns the user id, or `Unauthorized` if the request did not identify a user.
+    pub fn require_user(&self) -> Result<Uuid, ApiError> {
+        match self.user_id {
+            Some(user_id) => Ok(user_id),
+            None => Err(ApiError::Unauthorized(format!(
+                "{} header is required",
+                auth::SESSION_TOKEN_HEADER
+            ))),
+        }
+    }
+
+    /// Checks the request may use the document with `access`: with the share token sent
+    /// with it, or otherwise as a member of the organization the document belongs to.
+    /// Returns the tenant the request's queries on the document are scoped to. The check runs
+    /// on a pooled connection, so requests aren't serialised behind the shared `Client`.
+    pub async fn authorize(
+        &self,
+        pool: &Pool,
+        document_id: &Uuid,
+        access: Access,
+    ) -> Result<Tenant, ApiError> {
+        if self.share.is_some() {
+            self.authorize_share(document_id, access)?;
+        }
+        let client = pool.get(&Tenant::Any).await?;
+        tenancy::authorize(
+            &client,
+            document_id,
+            self.user_id,
+            access,
+            self.share.is_some(),
+        )
+        .await
+    }
+
+    /// Checks the share token sent with the request allows `access` to the document.
+    /// Requests without a share token are not restricted.
+    pub fn authorize_share(&self, document_id: &Uuid, access: Access) -> Result<(), ApiError> {
+        let grant: &ShareGrant = match &self.share {
+            None => return Ok(()),
+            Some(Ok(grant)) => grant,
+            Some(Err(e)) => {
+                error!("Rejected share token: {}", e);
+                return Err(ApiError::Unauthorized(e.to_string()));
+            }
+        };
+        if grant.document_id != *document_id {
+            error!("Share token used on another document");
+            return Err(ApiError::Forbidden(
+                "The share token does not grant access to this document".to_string(),
+            ));
+        }
+        if grant.access < access {
+            error!("Read-only share token used to change the document");
+            return Err(ApiError::Forbidden(
+                "The share token only grants read access".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Actor {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let session = request
+            .headers()
+            .get_one(auth::SESSION_TOKEN_HEADER)
+            .map(|token| {
+                match request
+                    .rocket()
+                    .state::<AuthConfig>()
+                    .and_then(|config| config.secret.as_deref())
+                {
+                    Some(secret) => auth::verify(token, secret, chrono::Utc::now().timestamp()),
+                    None => Err(SessionError::Disabled),
+                }
+            });
+        // a request with an invalid session is rejected rather than treated as anonymous
+        let user_id: Option<Uuid> = match session {
+            None => None,
+            Some(Ok(session)) => Some(session.user_id),
+            Some(Err(e)) => {
+                error!("Rejected session token: {}", e);
+                return Outcome::Error((Status::Unauthorized, ()));
+            }
+        };
+
+        let client_ip: Option<String> = forwarded::client_ip(request).map(|ip| ip.to_string());
+
+        let token: Option<&str> = request
+            .headers()
+            .get_one(share::SHARE_TOKEN_HEADER)
+            .or_else(|| {
+                request
+                    .query_value::<&str>(share::SHARE_TOKEN_PARAM)
+                    .and_then(|token| token.ok())
+            });
+        let share = token.map(|token| {
+            match request
+                .rocket()
+                .state::<ShareConfig>()
+                .and_then(|config| config.secret.as_deref())
+            {
+                Some(secret) => share::verify(token, secret, chrono::Utc::now().timestamp()),
+                None => Err(ShareError::Disabled),
+            }
+        });
+
+        Outcome::Success(Actor {
+            user_id,
+            client_ip,
+            share,
+        })
+    }
+}
+
+/// Records who performed an operation in the `audit_log` table.
+/// Runs inside the operation's transaction so the audit entry and the operation are
+/// committed (or rolled back) together.
+#[instrument(name = "db.record_audit", skip_all, fields(operation = %operation.operation))]
+pub async fn record(
+    tx: &Transaction<'_>,
+    actor: &Actor,
+    operation: &BroadcastOperation,
+    timestamp: &str,
+) -> Result<(), ApiError> {
+    match tx
+        .execute(
+            "INSERT INTO audit_log (document_id,user_id,client_ip,operation,ssn,sum,sid,seq,timestamp) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9)",
+            &[
+                &operation.document_id,
+                &actor.user_id,
+                &actor.client_ip,
+                &operation.operation,
+                &operation.ssn,
+                &operation.sum,
+                &operation.sid,
+                &operation.seq,
+                &timestamp,
+            ],
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            error!("Failed to insert into audit_log table");
+            Err(ApiError::DatabaseError(
+                "Failed to insert into audit_log table".to_string(),
+            ))
+        }
+    }
+}
+
+/// Returns the owner of a document, or `NotFound` if the document does not exist.
+pub async fn document_owner(client: &Client, document_id: &Uuid) -> Result<Uuid, ApiError> {
+    match client
+        .query_opt(
+            "SELECT owner_id FROM document WHERE document_id=$1",
+            &[document_id],
+        )
+        .await
+    {
+        Ok(Some(row)) => Ok(row.get(0)),
+        Ok(None) => Err(ApiError::NotFound("Document not found".to_string())),
+        Err(_) => {
+            error!("Failed to query the document table");
+            Err(ApiError::DatabaseError(
+                "Failed to query the document table".to_string(),
+            ))
+        }
+    }
+}
+
+/// Returns the audit log of a document, oldest entry first.
+#[instrument(name = "db.fetch_audit", skip(client))]
+pub async fn fetch(client: &Client, document_id: &Uuid) -> Result<Vec<AuditEntry>, ApiError> {
+    let rows = match client
+        .query(
+            "SELECT user_id,client_ip,operation,ssn,sum,sid,seq,timestamp FROM audit_log WHERE document_id=$1 ORDER BY id",
+            &[document_id],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => {
+            error!("Failed to query the audit_log table");
+            return Err(ApiError::DatabaseError(
+                "Failed to query the audit_log table".to_string(),
+            ));
+        }
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| AuditEntry {
+            document_id: *document_id,
+            user_id: row.get(0),
+            client_ip: row.get(1),
+            operation: row.get(2),
+            ssn: row.get(3),
+            sum: row.get(4),
+            sid: row.get(5),
+            seq: row.get(6),
+            timestamp: row.get(7),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_require_user() {
+        let user_id = Uuid::new_v4();
+        let actor = Actor {
+            user_id: Some(user_id),
+            client_ip: None,
+            share: None,
+        };
+        assert_eq!(actor.require_user().unwrap(), user_id);
+
+        let anonymous = Actor {
+            user_id: None,
+            client_ip: Some("127.0.0.1".to_string()),
+            share: None,
+        };
+        assert!(matches!(
+            anonymous.require_user(),
+            Err(ApiError::Unauthorized(_))
+        ));
+    }
+
+    #[rocket::get("/whoami")]
+    fn whoami(actor: Actor) -> Result<String, ApiError> {
+        actor.require_user().map(|user_id| user_id.to_string())
+    }
+
+    #[test]
+    fn test_session_guard() {
+        let secret = "s".repeat(share::MIN_SECRET_LEN);
+        let rocket = rocket::build()
+            .manage(AuthConfig {
+                secret: Some(secret.clone()),
+                ..AuthConfig::default()
+            })
+            .mount("/", rocket::routes![whoami]);
+        let client = rocket::local::blocking::Client::tracked(rocket).unwrap();
+        let whoami = |name: &'static str, value: String| {
+            let response = client
+                .get("/whoami")
+                .header(rocket::http::Header::new(name, value))
+                .dispatch();
+            (
+                response.status(),
+                response.into_string().unwrap_or_default(),
+            )
+        };
+        let user_id = Uuid::new_v4();
+        let session = |user_id, expires_at| auth::UserSession {
+            user_id,
+            expires_at,
+        };
+        let tomorrow: i64 = chrono::Utc::now().timestamp() + 24 * 60 * 60;
+
+        let token: String = auth::sign(&session(user_id, tomorrow), &secret);
+        assert_eq!(
+            whoami(auth::SESSION_TOKEN_HEADER, token),
+            (Status::Ok, user_id.to_string())
+        );
+
+        // the user id header some clients still send identifies no one
+        let (status, _) = whoami("X-User-ID", user_id.to_string());
+        assert_eq!(status, Status::Unauthorized);
+
+        // neither do tokens signed with another secret or that have expired
+        let forged: String = auth::sign(&session(user_id, tomorrow), &"t".repeat(secret.len()));
+        assert_eq!(
+            whoami(auth::SESSION_TOKEN_HEADER, forged).0,
+            Status::Unauthorized
+        );
+        let expired: String = auth::sign(&session(user_id, 1_000), &secret);
+        assert_eq!(
+            whoami(auth::SESSION_TOKEN_HEADER, expired).0,
+            Status::Unauthorized
+        );
+    }
+
+    /// A client of a rocket serving some of the routes under `/document/<id>` from `database`.
+    async fn document_routes(
+        database: &crate::testing::TestDatabase,
+        document_id: Uuid,
+    ) -> rocket::local::asynchronous::Client {
+        use crate::blame::fetch_blame;
+        use crate::changes::{poll_changes, ChangeFeeds};
+        use crate::collaboration::{join, CollaborationSessions};
+        use crate::rga::rga::RGA;
+        use crate::routes::SharedRGAs;
+        use crate::{testing, Session};
+        use rocket::tokio::sync::Mutex;
+        use std::collections::HashMap;
+        use std::sync::Arc;
+
+        let rgas: SharedRGAs = Arc::new(Mutex::new(HashMap::from([(document_id, RGA::new(1, 1))])));
+        let rocket = testing::rocket(database)
+            .await
+            .manage(rgas)
+            .manage(Session {
+                replica_id: 1,
+                session_id: 1,
+            })
+            .manage(ChangeFeeds::default())
+            .manage(CollaborationSessions::default())
+            .mount("/", rocket::routes![join, poll_changes, fetch_blame]);
+        rocket::local::asynchronous::Client::tracked(rocket)
+            .await
+            .unwrap()
+    }
+
+    /// The status of each of the routes of [`document_routes`] for a request with `header`.
+    async fn statuses(
+        client: &rocket::local::asynchronous::Client,
+        document_id: Uuid,
+        header: Option<rocket::http::Header<'static>>,
+    ) -> Vec<Status> {
+        let mut statuses: Vec<Status> = Vec::new();
+        for route in ["join", "changes?timeout=1ms", "blame"] {
+            let uri: String = format!("/document/{}/{}", document_id, route);
+            let mut request = match route {
+                "join" => client.post(uri),
+                _ => client.get(uri),
+            };
+            if let Some(header) = header.clone() {
+                request = request.header(header);
+            }
+            statuses.push(request.dispatch().await.status());
+        }
+        statuses
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_document_routes_need_access() {
+        use crate::testing::{self, TestDatabase, ORGANIZATION_TABLES};
+
+        let database = TestDatabase::create(ORGANIZATION_TABLES).await;
+        let db: Client = database.connect().await;
+        let (member, outsider) = (Uuid::new_v4(), Uuid::new_v4());
+        let org_id: Uuid = db
+            .query_one(
+                "INSERT INTO organizations (name) VALUES ('Acme') RETURNING org_id",
+                &[],
+            )
+            .await
+            .unwrap()
+            .get(0);
+        db.execute(
+            "INSERT INTO org_members (org_id,user_id,role) VALUES ($1,$2,'member')",
+            &[&org_id, &member],
+        )
+        .await
+        .unwrap();
+        let document_id: Uuid = db
+            .query_one(
+                "WITH w AS (INSERT INTO workspaces (org_id,name) VALUES ($1,'Docs') RETURNING workspace_id) \
+                 INSERT INTO document (owner_id,workspace_id) SELECT $2,workspace_id FROM w RETURNING document_id",
+                &[&org_id, &member],
+            )
+            .await
+            .unwrap()
+            .get(0);
+
+        let client = document_routes(&database, document_id).await;
+        for (header, expected) in [
+            (Some(testing::session(member)), Status::Ok),
+            (Some(testing::session(outsider)), Status::Forbidden),
+            (None, Status::Unauthorized),
+        ] {
+            assert_eq!(
+                statuses(&client, document_id, header).await,
+                vec![expected; 3]
+            );
+        }
+        database.drop().await;
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_personal_documents_need_access() {
+        use crate::testing::{self, TestDatabase, ORGANIZATION_TABLES};
+        use rocket::http::Header;
+
+        let database = TestDatabase::create(ORGANIZATION_TABLES).await;
+        let db: Client = database.connect().await;
+        let (owner, member, stranger) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let document_id: Uuid = testing::insert_document(&db, owner).await;
+        testing::insert_member(&db, document_id, member, "read_write").await;
+        let grant = ShareGrant {
+            document_id,
+            access: Access::ReadWrite,
+            expires_at: chrono::Utc::now().timestamp() + 60,
+        };
+        let shared = Header::new(
+            share::SHARE_TOKEN_HEADER,
+            share::sign(&grant, &testing::auth_config().secret.unwrap()),
+        );
+
+        let client = document_routes(&database, document_id).await;
+        for (header, expected) in [
+            (Some(testing::session(owner)), Status::Ok),
+            (Some(testing::session(member)), Status::Ok),
+            (Some(shared), Status::Ok),
+            (Some(testing::session(stranger)), Status::Forbidden),
+            (None, Status::Unauthorized),
+        ] {
+            assert_eq!(
+                statuses(&client, document_id, header).await,
+                vec![expected; 3]
+            );
+        }
+        database.drop().await;
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_concurrent_edits_use_the_pool() {
+        use crate::backpressure::Backlog;
+        use crate::changes::ChangeFeeds;
+        use crate::outbox::Outbox;
+        use crate::quota::Quotas;
+        use crate::rga::rga::RGA;
+        use crate::routes::{insert, DocumentLocks, SharedRGAs};
+        use crate::testing::{TestDatabase, OPERATION_TABLES, ORGANIZATION_TABLES};
+        use crate::throttle::{Throttle, ThrottleConfig};
+        use crate::validation::ValidationConfig;
+        use crate::wal::Wal;
+        use rocket::futures::future::join_all;
+        use rocket::local::asynchronous::Client as LocalClient;
+        use rocket::tokio::sync::Mutex;
+        use std::collections::HashMap;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let database =
+            TestDatabase::create(&format!("{}{}", ORGANIZATION_TABLES, OPERATION_TABLES)).await;
+        let db: Client = database.connect().await;
+        let user_id = Uuid::new_v4();
+        let document_id: Uuid = db
+            .query_one(
+                "INSERT INTO document (owner_id) VALUES ($1) RETURNING document_id",
+                &[&user_id],
+            )
+            .await
+            .unwrap()
+            .get(0);
+
+        let secret = "s".repeat(share::MIN_SECRET_LEN);
+        let rgas: SharedRGAs = Arc::new(Mutex::new(HashMap::from([(document_id, RGA::new(1, 1))])));
+        // the shared connection stays busy, as it would behind a slow query
+        let shared: Arc<Mutex<Client>> = Arc::new(Mutex::new(db));
+        let busy = shared.lock().await;
+        let rocket = rocket::build()
+            .manage(AuthConfig {
+                secret: Some(secret.clone()),
+                ..AuthConfig::default()
+            })
+            .manage(Arc::clone(&shared))
+            .manage(Pool::connect(&database.url, 4).await.unwrap())
+            .manage(rgas)
+            .manage(DocumentLocks::default())
+            .manage(Backlog::new(Default::default(), Default::default()))
+            .manage(Throttle::new(ThrottleConfig::default()))
+            .manage(Outbox::default())
+            .manage(Wal::default())
+            .manage(Quotas::default())
+            .manage(ValidationConfig::default())
+            .manage(ChangeFeeds::default())
+            .mount("/", rocket::routes![insert]);
+        let client = LocalClient::tracked(rocket).await.unwrap();
+
+        let session = auth::UserSession {
+            user_id,
+            expires_at: chrono::Utc::now().timestamp() + 60,
+        };
+        let token: String = auth::sign(&session, &secret);
+        let edits = (0..4).map(|i| {
+            client
+                .post(format!("/document/{}/insert", document_id))
+                .header(rocket::http::ContentType::JSON)
+                .header(rocket::http::Header::new(
+                    auth::SESSION_TOKEN_HEADER,
+                    token.clone(),
+                ))
+                .body(format!(r#"{{"value":"{}","left":null,"right":null}}"#, i))
+                .dispatch()
+        });
+        let responses = rocket::tokio::time::timeout(Duration::from_secs(10), join_all(edits))
+            .await
+            .expect("edits don't wait for the shared connection");
+        for response in responses {
+            assert_eq!(response.status(), Status::Ok);
+        }
+
+        let operations: i64 = busy
+            .query_one("SELECT COUNT(*) FROM operations", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(operations, 4);
+        drop(busy);
+        database.drop().await;
+    }
+
+    #[test]
+    fn test_authorize() {
+        let document_id = Uuid::new_v4();
+        let actor = |share| Actor {
+            user_id: None,
+            client_ip: None,
+            share,
+        };
+        let grant = |access| {
+            Some(Ok(ShareGrant {
+                document_id,
+                access,
+                expires_at: i64::MAX,
+            }))
+        };
+
+        assert!(actor(None)
+            .authorize_share(&document_id, Access::ReadWrite)
+            .is_ok());
+        assert!(actor(grant(Access::ReadWrite))
+            .authorize_share(&document_id, Access::ReadWrite)
+            .is_ok());
+        assert!(actor(grant(Access::Read))
+            .authorize_share(&document_id, Access::Read)
+            .is_ok());
+        assert!(matches!(
+            actor(grant(Access::Read)).authorize_share(&document_id, Access::ReadWrite),
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(matches!(
+            actor(grant(Access::ReadWrite)).authorize_share(&Uuid::new_v4(), Access::Read),
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(matches!(
+            actor(Some(Err(ShareError::Expired))).authorize_share(&document_id, Access::Read),
+            Err(ApiError::Unauthorized(_))
+        ));
+    }
+}
@@ -0,0 +1,224 @@
+//! Login sessions: signed, expiring tokens identifying the user behind a request.
+//!
+//! `POST /users/login` returns a token `<user_id>.<expires_at>.<signature>`, signed with
+//! HMAC-SHA256 under `auth.secret` like share links, so any replica configured with the same
+//! secret accepts it without a lookup. Requests carry the token in `X-Session-Token` and the
+//! `Actor` guard verifies it. The user of a request is only ever taken from a verified token.
+//!
+//! What a document or user route returns depends on who asks, so [`PrivateResponses`] marks
+//! those responses `Cache-Control: private`. Shared caches don't store them, and the load
+//! balancer's cache, which keys them on the request's credentials, only serves them to a
+//! request carrying the same token.
+
+use crate::share;
+use chrono::{DateTime, TimeDelta, Utc};
+use hmac::Mac;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Request, Response};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Header carrying a session token.
+pub const SESSION_TOKEN_HEADER: &str = "X-Session-Token";
+
+/// The longest `session_ttl_secs` accepted, ten years.
+pub const MAX_SESSION_TTL_SECS: u64 = 10 * 365 * 24 * 60 * 60;
+
+/// The first segment of the paths whose responses are private to the requester.
+const PRIVATE_PATHS: [&str; 6] = ["document", "users", "me", "orgs", "workspaces", "projects"];
+
+/// `secret`: The key session tokens are signed with, the same on every replica (None disables
+/// login).
+/// `session_ttl_secs`: How long a session lasts after logging in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub secret: Option<String>,
+    pub session_ttl_secs: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            secret: None,
+            session_ttl_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+impl AuthConfig {
+    /// When a session starting at `now` expires, None if the time can't be represented.
+    pub fn session_expiry(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        i64::try_from(self.session_ttl_secs)
+            .ok()
+            .and_then(TimeDelta::try_seconds)
+            .and_then(|ttl| now.checked_add_signed(ttl))
+    }
+}
+
+/// The user identified by a verified session token.
+/// `user_id`: The user that logged in.
+/// `expires_at`: Unix time (seconds) after which the token is rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserSession {
+    pub user_id: Uuid,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SessionError {
+    #[error("Login is disabled on this replica")]
+    Disabled,
+    #[error("The session token is malformed")]
+    Malformed,
+    #[error("The session token signature is invalid")]
+    InvalidSignature,
+    #[error("The session has expired")]
+    Expired,
+}
+
+fn payload(session: &UserSession) -> String {
+    format!("{}.{}", session.user_id, session.expires_at)
+}
+
+/// Signs a session, returning its token.
+pub fn sign(session: &UserSession, secret: &str) -> String {
+    let payload: String = payload(session);
+    let mut mac = share::mac(secret);
+    mac.update(payload.as_bytes());
+    format!("{}.{}", payload, hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Verifies a token's signature and expiry, returning the session it identifies.
+pub fn verify(token: &str, secret: &str, now: i64) -> Result<UserSession, SessionError> {
+    let (payload, signature) = token.rsplit_once('.').ok_or(SessionError::Malformed)?;
+    let signature: Vec<u8> = hex::decode(signature).map_err(|_| SessionError::Malformed)?;
+
+    let mut mac = share::mac(secret);
+    mac.update(payload.as_bytes());
+    // compares in constant time
+    mac.verify_slice(&signature)
+        .map_err(|_| SessionError::InvalidSignature)?;
+
+    // share tokens have three fields, so one signed with the same secret is not a session
+    let session = match payload.split_once('.') {
+        Some((user_id, expires_at)) => UserSession {
+            user_id: Uuid::parse_str(user_id).map_err(|_| SessionError::Malformed)?,
+            expires_at: expires_at.parse().map_err(|_| SessionError::Malformed)?,
+        },
+        None => return Err(SessionError::Malformed),
+    };
+    if session.expires_at <= now {
+        return Err(SessionError::Expired);
+    }
+    Ok(session)
+}
+
+/// Fairing marking the responses of document and user routes `Cache-Control: private`, unless
+/// the route set its own.
+pub struct PrivateResponses;
+
+#[rocket::async_trait]
+impl Fairing for PrivateResponses {
+    fn info(&self) -> Info {
+        Info {
+            name: "Private responses",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let private: bool = request
+            .uri()
+            .path()
+            .segments()
+            .next()
+            .is_some_and(|segment| PRIVATE_PATHS.contains(&segment));
+        if private && !response.headers().contains("Cache-Control") {
+            response.set_header(Header::new("Cache-Control", "private"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::share::{Access, ShareGrant, MIN_SECRET_LEN};
+
+    #[rocket::get("/document/<_id>")]
+    fn document(_id: &str) {}
+
+    #[rocket::get("/errors")]
+    fn errors() {}
+
+    #[test]
+    fn test_private_responses() {
+        let rocket = rocket::build()
+            .attach(PrivateResponses)
+            .mount("/", rocket::routes![document, errors]);
+        let client = rocket::local::blocking::Client::tracked(rocket).unwrap();
+        let cache_control = |uri: &'static str| {
+            client
+                .get(uri)
+                .dispatch()
+                .headers()
+                .get_one("Cache-Control")
+                .map(str::to_string)
+        };
+        assert_eq!(cache_control("/document/1"), Some("private".to_string()));
+        assert_eq!(cache_control("/errors"), None);
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let now: DateTime<Utc> = Utc::now();
+        let config = |session_ttl_secs| AuthConfig {
+            secret: None,
+            session_ttl_secs,
+        };
+        assert_eq!(
+            config(60).session_expiry(now),
+            Some(now + TimeDelta::seconds(60))
+        );
+        assert!(config(MAX_SESSION_TTL_SECS).session_expiry(now).is_some());
+        // too large to be a duration, or wrapping to a negative one
+        assert_eq!(config(u64::MAX).session_expiry(now), None);
+        assert_eq!(config(i64::MAX as u64 / 1000).session_expiry(now), None);
+    }
+
+    #[test]
+    fn test_session_token() {
+        let secret = "s".repeat(MIN_SECRET_LEN);
+        let session = UserSession {
+            user_id: Uuid::new_v4(),
+            expires_at: 1_000,
+        };
+        let token = sign(&session, &secret);
+        assert_eq!(verify(&token, &secret, 999), Ok(session));
+        assert_eq!(verify(&token, &secret, 1_000), Err(SessionError::Expired));
+        assert_eq!(
+            verify(&token, &"t".repeat(MIN_SECRET_LEN), 999),
+            Err(SessionError::InvalidSignature)
+        );
+
+        // the user can't be changed without the secret
+        let forged = token.replacen(&session.user_id.to_string(), &Uuid::new_v4().to_string(), 1);
+        assert_eq!(
+            verify(&forged, &secret, 999),
+            Err(SessionError::InvalidSignature)
+        );
+        assert_eq!(verify("token", &secret, 999), Err(SessionError::Malformed));
+
+        // nor is a share token a session
+        let grant = ShareGrant {
+            document_id: Uuid::new_v4(),
+            access: Access::ReadWrite,
+            expires_at: 1_000,
+        };
+        assert_eq!(
+            verify(&share::sign(&grant, &secret), &secret, 999),
+            Err(SessionError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,686 @@
+//! Conversion between a document's operations log and Automerge changes.
+//!
+//! Changes use the JSON form returned by `Automerge.decodeChange`, which clients turn into the
+//! binary format with `Automerge.encodeChange`. The document is a `Text` object under the root
+//! key `content` holding one element per character.
+//!
+//! Export replays the operations log in timestamp order, one change per logged operation, by
+//! an actor for each replica. The log doesn't record where nodes were inserted, so each node is
+//! placed by its position in the loaded document. Changes carry no `deps`, so they must be
+//! applied in the order they are returned.
+
+use crate::changes::ChangeFeeds;
+use crate::flags::{Feature, Features};
+use crate::history::{fetch_operations, LoggedOperation};
+use crate::limits::JsonBody;
+use crate::outbox::Outbox;
+use crate::pool::Pool;
+use crate::rga::rga::{Chunks, Node, READ_CHUNK_BYTES, RGA};
+use crate::routes::{DocumentLocks, SharedRGAs};
+use crate::share::Access;
+use crate::tenancy::{self, Tenant};
+use crate::wal::Wal;
+use crate::{
+    db, quota, Actor, ApiError, BroadcastOperation, ContentNode, DocumentContent, FieldError,
+    Quotas, RequestId, ValidationConfig,
+};
+use rocket::futures::stream;
+use rocket::response::stream::TextStream;
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use rocket::tokio::sync::{Mutex, OwnedMutexGuard};
+use rocket::{get, post, Request};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// The root key of the document's text object.
+pub const TEXT_KEY: &str = "content";
+
+const ROOT: &str = "_root";
+const HEAD: &str = "_head";
+
+/// An Automerge operation.
+/// `action`: `makeText`, `set` or `del`.
+/// `obj`: The object the operation applies to.
+/// `key`: The key in a map object (`makeText` on the root).
+/// `elem_id`: The element the operation applies to, or inserts after (`_head` at the start).
+/// `insert`: Whether a `set` inserts a new element.
+/// `value`: The character set or inserted.
+/// `values`: Several characters inserted one after the other.
+/// `multi_op`: The number of consecutive elements deleted.
+/// `pred`: The operations this one overwrites.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AutomergeOp {
+    pub action: String,
+    pub obj: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elem_id: Option<String>,
+    #[serde(default)]
+    pub insert: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<serde_json::Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multi_op: Option<u64>,
+    #[serde(default)]
+    pub pred: Vec<String>,
+}
+
+/// An Automerge change, in the form returned by `Automerge.decodeChange`.
+/// `actor`: The hex id of the actor that made the change.
+/// `seq`: The change's position among the actor's changes, starting at 1.
+/// `start_op`: The counter of the change's first operation.
+/// `time`: When the change was made, in seconds since the Unix epoch.
+/// `message`: A description of the change.
+/// `deps`: The hashes of the changes this one depends on.
+/// `ops`: The change's operations.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AutomergeChange {
+    pub actor: String,
+    pub seq: u64,
+    pub start_op: u64,
+    #[serde(default)]
+    pub time: i64,
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(default)]
+    pub deps: Vec<String>,
+    pub ops: Vec<AutomergeOp>,
+}
+
+/// The Automerge actor of a replica.
+fn actor_id(site_id: i64) -> String {
+    format!("{:016x}", site_id)
+}
+
+fn op_id(counter: u64, actor: &str) -> String {
+    format!("{}@{}", counter, actor)
+}
+
+/// Parses `counter@actor`, which orders by counter then actor.
+fn parse_op_id(id: &str) -> Option<(u64, String)> {
+    let (counter, actor) = id.split_once('@')?;
+    Some((counter.parse().ok()?, actor.to_string()))
+}
+
+/// Converts an operations log into Automerge changes.
+/// `order`: The s4vectors of the document's nodes in document order.
+pub fn export_changes(operations: &[LoggedOperation], order: &[[i64; 4]]) -> Vec<AutomergeChange> {
+    let position: HashMap<[i64; 4], usize> = order
+        .iter()
+        .enumerate()
+        .map(|(index, s4vector)| (*s4vector, index))
+        .collect();
+
+    let mut logged: Vec<&LoggedOperation> = operations.iter().collect();
+    logged.sort_by_key(|operation| operation.timestamp);
+
+    let mut changes: Vec<AutomergeChange> = Vec::new();
+    let mut seqs: HashMap<String, u64> = HashMap::new();
+    let mut next_op: u64 = 1;
+    let mut text: Option<String> = None;
+    // the live characters of each node, and the last character each node ever had
+    let mut live: HashMap<[i64; 4], Vec<(String, char)>> = HashMap::new();
+    let mut tails: HashMap<[i64; 4], String> = HashMap::new();
+    let mut placed: BTreeSet<(usize, [i64; 4])> = BTreeSet::new();
+
+    for operation in logged {
+        let node: [i64; 4] = operation.s4vector;
+        let value: &str = match (operation.tombstone, operation.value.as_deref()) {
+            (true, _) => "",
+            (false, Some(value)) => value,
+            (false, None) => continue,
+        };
+        let current: String = live
+            .get(&node)
+            .map(|chars| chars.iter().map(|(_, c)| c).collect())
+            .unwrap_or_default();
+        let key: (usize, [i64; 4]) = (position.get(&node).copied().unwrap_or(usize::MAX), node);
+        let inserted: bool = placed.insert(key);
+        if !inserted && current == value {
+            continue;
+        }
+
+        let actor: String = actor_id(node[2]);
+        let start_op: u64 = next_op;
+        let mut ops: Vec<AutomergeOp> = Vec::new();
+
+        let obj: String = match &text {
+            Some(obj) => obj.clone(),
+            None => {
+                let obj: String = op_id(next_op, &actor);
+                ops.push(AutomergeOp {
+                    action: "makeText".to_string(),
+                    obj: ROOT.to_string(),
+                    key: Some(TEXT_KEY.to_string()),
+                    elem_id: None,
+                    insert: false,
+                    value: None,
+                    values: None,
+                    multi_op: None,
+                    pred: Vec::new(),
+                });
+                next_op += 1;
+                text = Some(obj.clone());
+                obj
+            }
+        };
+
+        for (id, _) in live.remove(&node).unwrap_or_default() {
+            ops.push(AutomergeOp {
+                action: "del".to_string(),
+                obj: obj.clone(),
+                key: None,
+                elem_id: Some(id.clone()),
+                insert: false,
+                value: None,
+                values: None,
+                multi_op: None,
+                pred: vec![id],
+            });
+            next_op += 1;
+        }
+
+        // new characters follow the node's old ones, or the nearest node before it
+        let mut previous: String = match tails.get(&node) {
+            Some(tail) => tail.clone(),
+            None => placed
+                .range(..key)
+                .rev()
+                .find_map(|(_, before)| tails.get(before).cloned())
+                .unwrap_or_else(|| HEAD.to_string()),
+        };
+        let mut chars: Vec<(String, char)> = Vec::new();
+        for c in value.chars() {
+            let id: String = op_id(next_op, &actor);
+            ops.push(AutomergeOp {
+                action: "set".to_string(),
+                obj: obj.clone(),
+                key: None,
+                elem_id: Some(previous),
+                insert: true,
+                value: Some(serde_json::Value::String(c.to_string())),
+                values: None,
+                multi_op: None,
+                pred: Vec::new(),
+            });
+            next_op += 1;
+            chars.push((id.clone(), c));
+            previous = id;
+        }
+        if !chars.is_empty() {
+            tails.insert(node, previous);
+            live.insert(node, chars);
+        }
+
+        if ops.is_empty() {
+            continue;
+        }
+        let message: &str = if inserted {
+            "Insert"
+        } else if value.is_empty() {
+            "Delete"
+        } else {
+            "Update"
+        };
+        let seq: &mut u64 = seqs.entry(actor.clone()).or_insert(0);
+        *seq += 1;
+        changes.push(AutomergeChange {
+            actor,
+            seq: *seq,
+            start_op,
+            time: operation.timestamp.timestamp(),
+            message: Some(message.to_string()),
+            deps: Vec::new(),
+            ops,
+        });
+    }
+    changes
+}
+
+/// A text object rebuilt from Automerge operations.
+#[derive(Debug, Default)]
+struct TextObject {
+    key: Option<String>,
+    values: HashMap<String, String>,
+    deleted: HashMap<String, bool>,
+    children: HashMap<String, Vec<(u64, String)>>,
+}
+
+impl TextObject {
+    /// The visible characters in order: elements follow the element they were inserted
+    /// after, with later insertions (higher op ids) first.
+    fn text(&self) -> String {
+        let mut text = String::new();
+        let mut stack: Vec<String> = vec![HEAD.to_string()];
+        while let Some(element) = stack.pop() {
+            if !self.deleted.get(&element).copied().unwrap_or(false) {
+                if let Some(value) = self.values.get(&element) {
+                    text.push_str(value);
+                }
+            }
+            if let Some(children) = self.children.get(&element) {
+                let mut children: Vec<&(u64, String)> = children.iter().collect();
+                // pushed lowest first so the highest op id is visited first
+                children.sort();
+                stack.extend(
+                    children
+                        .into_iter()
+                        .map(|(counter, actor)| op_id(*counter, actor)),
+                );
+            }
+        }
+        text
+    }
+}
+
+fn invalid_change(message: &str) -> ApiError {
+    error!("Rejected Automerge change: {}", message);
+    ApiError::ValidationFailed(vec![FieldError::new("changes", message)])
+}
+
+fn text_value(value: &serde_json::Value) -> Result<String, ApiError> {
+    match value.as_str() {
+        Some(value) => Ok(value.to_string()),
+        None => Err(invalid_change("text elements must be strings")),
+    }
+}
+
+/// Rebuilds the text of the `content` text object (or the first text object made on the root)
+/// from Automerge changes, applied in order. Other objects are ignored.
+pub fn import_text(changes: &[AutomergeChange]) -> Result<String, ApiError> {
+    let mut objects: HashMap<String, TextObject> = HashMap::new();
+    let mut made: Vec<String> = Vec::new();
+
+    for change in changes {
+        let mut counter: u64 = change.start_op;
+        for op in &change.ops {
+            let id: String = op_id(counter, &change.actor);
+            match op.action.as_str() {
+                "makeText" if op.obj == ROOT => {
+                    objects.entry(id.clone()).or_default().key = op.key.clone();
+                    made.push(id);
+                    counter += 1;
+                }
+                "set" => {
+                    let object: &mut TextObject = objects.entry(op.obj.clone()).or_default();
+                    let elem_id: String = op.elem_id.clone().unwrap_or_else(|| HEAD.to_string());
+                    let values: Vec<String> = match (&op.values, &op.value) {
+                        (Some(values), _) => {
+                            values.iter().map(text_value).collect::<Result<_, _>>()?
+                        }
+                        (None, Some(value)) => vec![text_value(value)?],
+                        (None, None) => return Err(invalid_change("set operations need a value")),
+                    };
+
+                    if !op.insert {
+                        if let Some(value) = values.into_iter().next() {
+                            object.values.insert(elem_id, value);
+                        }
+                        counter += 1;
+                        continue;
+                    }
+
+                    let mut previous: String = elem_id;
+                    for value in values {
+                        let id: String = op_id(counter, &change.actor);
+                        object
+                            .children
+                            .entry(previous)
+                            .or_default()
+                            .push((counter, change.actor.clone()));
+                        object.values.insert(id.clone(), value);
+                        previous = id;
+                        counter += 1;
+                    }
+                }
+                "del" => {
+                    let object: &mut TextObject = objects.entry(op.obj.clone()).or_default();
+                    let (first, actor) = match op.elem_id.as_deref().and_then(parse_op_id) {
+                        Some(elem) => elem,
+                        None => return Err(invalid_change("del operations need an elemId")),
+                    };
+                    let count: u64 = op.multi_op.unwrap_or(1);
+                    if count > 1_000_000 {
+                        return Err(invalid_change("multiOp is too large"));
+                    }
+                    for offset in 0..count {
+                        object.deleted.insert(op_id(first + offset, &actor), true);
+                    }
+                    counter += count;
+                }
+                _ => counter += 1,
+            }
+        }
+    }
+
+    let text: Option<&String> = made
+        .iter()
+        .find(|id| objects[*id].key.as_deref() == Some(TEXT_KEY))
+        .or(made.first());
+    match text {
+        Some(id) => Ok(objects[id].text()),
+        None => Err(invalid_change("no text object is made on the root")),
+    }
+}
+
+/// Splits text into lines, and lines longer than `max_bytes` into chunks, one per node.
+pub fn split_nodes(text: &str, max_bytes: usize) -> Vec<String> {
+    let mut nodes: Vec<String> = Vec::new();
+    for line in text.split_inclusive('\n') {
+        let mut chunk = String::new();
+        for c in line.chars() {
+            if chunk.len() + c.len_utf8() > max_bytes && !chunk.is_empty() {
+                nodes.push(std::mem::take(&mut chunk));
+            }
+            chunk.push(c);
+        }
+        if !chunk.is_empty() {
+            nodes.push(chunk);
+        }
+    }
+    nodes
+}
+
+fn parse_request(id: &str, format: &str, formats: &[&str]) -> Result<Uuid, ApiError> {
+    if !formats.contains(&format) {
+        error!("Unsupported format {:?}", format);
+        return Err(ApiError::InvalidOperation(format!(
+            "format must be {}",
+            formats.join(" or ")
+        )));
+    }
+    match Uuid::parse_str(id) {
+        Ok(id) => Ok(id),
+        Err(_) => {
+            error!("Failed to parse document id");
+            Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ))
+        }
+    }
+}
+
+/// An exported document.
+pub enum Export {
+    Automerge(Json<Vec<AutomergeChange>>),
+    Text(TextStream<stream::Iter<Chunks>>),
+}
+
+impl<'r> Responder<'r, 'r> for Export {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'r> {
+        match self {
+            Export::Automerge(changes) => changes.respond_to(request),
+            Export::Text(text) => text.respond_to(request),
+        }
+    }
+}
+
+/// Exports a loaded document's history as Automerge changes, or its content as plain text.
+/// `format`: The export format, `automerge` or `text`. Text is streamed in chunks.
+#[get("/document/<id>/export?<format>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn export_document(
+    id: String,
+    format: String,
+    rgas: &rocket::State<SharedRGAs>,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    features: &rocket::State<Features>,
+    actor: Actor,
+    request_id: RequestId,
+) -> Result<Export, ApiError> {
+    let document_id: Uuid = parse_request(&id, &format, &["automerge", "text"])?;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
+    if format == "automerge" {
+        features.require(Feature::Automerge, &tenant)?;
+    }
+
+    let rgas = rgas.lock().await;
+    let rga: &RGA = match rgas.get(&document_id) {
+        Some(r) => r,
+        None => {
+            error!("Document not found");
+            return Err(ApiError::NotFound(String::from("Document not found")));
+        }
+    };
+    if format == "text" {
+        info!(bytes = rga.content_size(), "Exporting document as text");
+        return Ok(Export::Text(TextStream(rga.read_chunks(READ_CHUNK_BYTES))));
+    }
+    let order: Vec<[i64; 4]> = rga
+        .ordered_nodes()
+        .iter()
+        .map(|node| node.s4vector.to_i64())
+        .collect::<Result<_, _>>()?;
+
+    let operations: Vec<LoggedOperation> =
+        fetch_operations(&*tenancy::lock(db, &tenant).await?, &document_id, None).await?;
+    let changes: Vec<AutomergeChange> = export_changes(&operations, &order);
+
+    info!(
+        "Exported {} operations as {} Automerge changes",
+        operations.len(),
+        changes.len()
+    );
+    Ok(Export::Automerge(Json(changes)))
+}
+
+/// Imports the text of Automerge changes into a loaded document without content, one node
+/// per line. The changes' history is not kept, the nodes are written by this replica.
+/// `format`: The import format, `automerge`.
+/// Returns the document's content.
+#[post("/document/<id>/import?<format>", data = "<changes>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn import_document(
+    id: String,
+    format: String,
+    changes: JsonBody<Vec<AutomergeChange>>,
+    actor: Actor,
+    rgas: &rocket::State<SharedRGAs>,
+    locks: &rocket::State<DocumentLocks>,
+    pool: &rocket::State<Pool>,
+    features: &rocket::State<Features>,
+    outbox: &rocket::State<Outbox>,
+    wal: &rocket::State<Wal>,
+    feeds: &rocket::State<ChangeFeeds>,
+    quotas: &rocket::State<Quotas>,
+    validation: &rocket::State<ValidationConfig>,
+    request_id: RequestId,
+) -> Result<Json<DocumentContent>, ApiError> {
+    let document_id: Uuid = parse_request(&id, &format, &["automerge"])?;
+    let tenant: Tenant = actor
+        .authorize(pool, &document_id, Access::ReadWrite)
+        .await?;
+    features.require(Feature::Automerge, &tenant)?;
+    let text: String = import_text(&changes)?;
+    quota::record_operation(&*pool.get(&tenant).await?, &document_id, quotas).await?;
+
+    // Held until the import is applied, so no other edit of the document comes in between
+    let edit: OwnedMutexGuard<()> = locks.lock(document_id).await;
+    // imported into a copy, the document only changes once the nodes are logged
+    let mut prepared: RGA = match rgas.lock().await.get(&document_id) {
+        Some(rga) => rga.clone(),
+        None => {
+            error!("Document not found");
+            return Err(ApiError::NotFound(String::from("Document not found")));
+        }
+    };
+
+    let nodes: Vec<Node> = prepared.ordered_nodes();
+    if nodes
+        .iter()
+        .any(|node| !node.tombstone && !node.value.is_empty())
+    {
+        error!("Rejected import into a document with content");
+        return Err(ApiError::Conflict(
+            "Automerge changes can only be imported into a document without content".to_string(),
+        ));
+    }
+    quotas.check_document_size(0, text.len(), 0)?;
+
+    // appended after every existing node, which are all empty
+    let mut left = nodes.last().map(|node| node.s4vector);
+    let mut operations: Vec<BroadcastOperation> = Vec::new();
+    for value in split_nodes(&text, validation.max_node_bytes) {
+        let operation: BroadcastOperation =
+            match prepared.local_insert(value, left, None, document_id) {
+                Ok(operation) => operation,
+                Err(_) => {
+                    error!("Failed to insert imported text");
+                    return Err(ApiError::InternalServerError(
+                        "Failed to insert imported text".to_string(),
+                    ));
+                }
+            };
+        left = Some(operation.s4vector());
+        operations.push(operation);
+    }
+
+    let imported: usize = operations.len();
+    db::commit_operations(
+        pool,
+        rgas,
+        wal,
+        edit,
+        &prepared,
+        &actor,
+        &request_id,
+        document_id,
+        &tenant,
+        operations,
+        outbox,
+        feeds,
+    )
+    .await?;
+
+    info!(
+        "Imported {} bytes of Automerge text as {} nodes",
+        text.len(),
+        imported
+    );
+    Ok(Json(DocumentContent {
+        document_id,
+        nodes: prepared
+            .read_nodes()
+            .into_iter()
+            .map(|(s4vector, value)| ContentNode { s4vector, value })
+            .collect(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    fn logged(s4vector: [i64; 4], value: &str, tombstone: bool, at: &str) -> LoggedOperation {
+        LoggedOperation {
+            s4vector,
+            operation: None,
+            value: Some(value.to_string()),
+            tombstone,
+            left: None,
+            right: None,
+            nodes: Vec::new(),
+            timestamp: DateTime::parse_from_rfc3339(at).unwrap().to_utc(),
+        }
+    }
+
+    #[test]
+    fn test_export_and_import_changes() {
+        let (a, b, c) = ([1, 1, 1, 1], [1, 2, 2, 1], [1, 3, 1, 2]);
+        let operations = vec![
+            logged(a, "ab", false, "2025-01-04T10:00:01Z"),
+            // inserted after b but placed between a and b in the document
+            logged(b, "cd", false, "2025-01-04T10:00:02Z"),
+            logged(c, "x", false, "2025-01-04T10:00:03Z"),
+            logged(a, "AB", false, "2025-01-04T10:00:04Z"),
+            logged(b, "", true, "2025-01-04T10:00:05Z"),
+        ];
+        let changes = export_changes(&operations, &[a, c, b]);
+
+        assert_eq!(changes.len(), 5);
+        assert_eq!(changes[0].ops[0].action, "makeText");
+        assert_eq!(changes[1].actor, actor_id(2));
+        assert_eq!((changes[2].seq, changes[2].start_op), (2, 6));
+        assert_eq!(changes[4].message.as_deref(), Some("Delete"));
+
+        let history: Vec<String> = (1..=changes.len())
+            .map(|applied| import_text(&changes[..applied]).unwrap())
+            .collect();
+        assert_eq!(history, vec!["ab", "abcd", "abxcd", "ABxcd", "ABx"]);
+
+        // round trips through JSON in the decodeChange format
+        let json = serde_json::to_value(&changes[0]).unwrap();
+        assert_eq!(json["startOp"], 1);
+        assert_eq!(json["ops"][1]["elemId"], "_head");
+        let parsed: Vec<AutomergeChange> =
+            serde_json::from_value(serde_json::to_value(&changes).unwrap()).unwrap();
+        assert_eq!(parsed, changes);
+    }
+
+    #[test]
+    fn test_import_concurrent_inserts() {
+        let op = |action: &str, elem_id: &str, value: Option<&str>| AutomergeOp {
+            action: action.to_string(),
+            obj: "1@aa".to_string(),
+            key: None,
+            elem_id: Some(elem_id.to_string()),
+            insert: action == "set",
+            value: value.map(|v| serde_json::Value::String(v.to_string())),
+            values: None,
+            multi_op: None,
+            pred: Vec::new(),
+        };
+        let mut make = op("makeText", "", None);
+        make.obj = ROOT.to_string();
+        make.key = Some(TEXT_KEY.to_string());
+
+        let mut multi = op("set", "_head", None);
+        multi.values = Some(vec!["h".into(), "i".into()]);
+        let changes = vec![
+            AutomergeChange {
+                actor: "aa".to_string(),
+                seq: 1,
+                start_op: 1,
+                time: 0,
+                message: None,
+                deps: Vec::new(),
+                ops: vec![make, multi],
+            },
+            // both insert after "h", the higher op id comes first
+            AutomergeChange {
+                actor: "bb".to_string(),
+                seq: 1,
+                start_op: 4,
+                time: 0,
+                message: None,
+                deps: Vec::new(),
+                ops: vec![op("set", "2@aa", Some("1")), op("del", "3@aa", None)],
+            },
+            AutomergeChange {
+                actor: "aa".to_string(),
+                seq: 2,
+                start_op: 4,
+                time: 0,
+                message: None,
+                deps: Vec::new(),
+                ops: vec![op("set", "2@aa", Some("2"))],
+            },
+        ];
+        assert_eq!(import_text(&changes).unwrap(), "h12");
+        assert_eq!(split_nodes("ab\ncdef", 2), vec!["ab", "\n", "cd", "ef"]);
+    }
+}
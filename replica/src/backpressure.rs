@@ -0,0 +1,522 @@
+//! Backpressure on operation ingestion.
+//!
+//! The operation routes are rejected while one of the replica's backlogs is over its limit,
+//! rather than accepting work the replica can't keep up with:
+//! - the dependency buffer, remote operations waiting on operations that haven't arrived yet,
+//!   returns 503 as the replica has fallen behind the rest of the cluster.
+//! - the write-behind backlog, documents with changes that haven't been checkpointed yet,
+//!   returns 429 as edits are arriving faster than they are persisted.
+//! - the broadcast backlog, broadcasts received but not applied yet (including messages waiting
+//!   in the replica's SQS queue), returns 503 as the replica has fallen behind the rest of the
+//!   cluster.
+//!
+//! Rejections carry `Retry-After`, and the backlog sizes are exported as metrics.
+//!
+//! Each document's dependency buffer is also bounded on its own, so a peer sending operations
+//! that depend on nodes that never arrive can't exhaust the replica's memory. Operations
+//! buffered for longer than `buffer.max_age_secs` are dropped, and so are the oldest operations
+//! of a full buffer unless `buffer.overflow` rejects new ones instead. A document that lost
+//! operations is reloaded from the database, which every replica writes its operations to.
+
+use crate::divergence;
+use crate::rga::rga::{Shed, RGA};
+use crate::routes::SharedRGAs;
+use crate::{ApiError, BroadcastOperation, Session};
+use rocket::fairing::AdHoc;
+use rocket::tokio::{self, sync::Mutex};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_postgres::Client;
+use tracing::{error, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+/// Limits on the replica's backlogs, 0 disables a limit.
+/// `max_buffered_operations`: Remote operations waiting on missing dependencies, across every
+/// loaded document.
+/// `max_dirty_documents`: Loaded documents with changes that are not in their snapshot yet.
+/// `max_pending_broadcasts`: Broadcasts received but not applied yet.
+/// `retry_after_secs`: Seconds clients are asked to wait before retrying a rejected operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackpressureConfig {
+    pub max_buffered_operations: usize,
+    pub max_dirty_documents: usize,
+    pub max_pending_broadcasts: usize,
+    pub retry_after_secs: u64,
+}
+
+impl Default for BackpressureConfig {
+    fn default() -> Self {
+        BackpressureConfig {
+            max_buffered_operations: 10_000,
+            max_dirty_documents: 1_000,
+            max_pending_broadcasts: 1_000,
+            retry_after_secs: 1,
+        }
+    }
+}
+
+/// What happens to a remote operation that has to be buffered when its document's buffer is full.
+/// `DropOldest`: The operation is buffered and the oldest buffered operations are dropped, the
+/// document is then reloaded from the database.
+/// `Reject`: The operation is rejected with `503 Service Unavailable`, to be delivered again later.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    #[default]
+    DropOldest,
+    Reject,
+}
+
+/// Limits on the dependency buffer of each loaded document.
+/// `max_operations`: Operations a document may buffer, 0 disables the limit.
+/// `max_age_secs`: Seconds an operation may stay buffered before it is dropped, 0 disables the limit.
+/// `overflow`: What happens to an operation arriving when the buffer is full.
+/// `check_interval_secs`: Seconds between sweeps of the buffers for expired operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BufferConfig {
+    pub max_operations: usize,
+    pub max_age_secs: u64,
+    pub overflow: OverflowPolicy,
+    pub check_interval_secs: u64,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        BufferConfig {
+            max_operations: 1_000,
+            max_age_secs: 300,
+            overflow: OverflowPolicy::DropOldest,
+            check_interval_secs: 30,
+        }
+    }
+}
+
+impl BufferConfig {
+    fn max_age(&self) -> Option<Duration> {
+        (self.max_age_secs > 0).then(|| Duration::from_secs(self.max_age_secs))
+    }
+}
+
+/// The size of each backlog.
+/// `buffered_operations`: Remote operations waiting on missing dependencies.
+/// `dirty_documents`: Documents with changes that are not in their snapshot yet.
+/// `pending_broadcasts`: Broadcasts received but not applied yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BacklogSizes {
+    pub buffered_operations: usize,
+    pub dirty_documents: usize,
+    pub pending_broadcasts: usize,
+}
+
+/// The names of the backlogs, in the order of `BacklogSizes::values`.
+const BACKLOGS: [&str; 3] = [
+    "buffered_operations",
+    "dirty_documents",
+    "pending_broadcasts",
+];
+
+impl BacklogSizes {
+    fn values(&self) -> [usize; 3] {
+        [
+            self.buffered_operations,
+            self.dirty_documents,
+            self.pending_broadcasts,
+        ]
+    }
+}
+
+impl BackpressureConfig {
+    fn limits(&self) -> [usize; 3] {
+        [
+            self.max_buffered_operations,
+            self.max_dirty_documents,
+            self.max_pending_broadcasts,
+        ]
+    }
+}
+
+/// The replica's backlogs, managed in Rocket's state.
+/// `resyncs`: Documents that lost buffered operations, waiting to be reloaded.
+/// `shed`: Buffered operations dropped since the replica started, expired then overflowed.
+/// `buffer_rejections`: Remote operations rejected because their document's buffer was full.
+#[derive(Clone, Default)]
+pub struct Backlog {
+    config: BackpressureConfig,
+    buffer: BufferConfig,
+    applying: Arc<AtomicUsize>,
+    queued: Arc<AtomicUsize>,
+    rejections: Arc<[AtomicU64; 3]>,
+    resyncs: Arc<std::sync::Mutex<HashSet<Uuid>>>,
+    shed: Arc<[AtomicU64; 2]>,
+    buffer_rejections: Arc<AtomicU64>,
+}
+
+/// Counts a broadcast as pending until it is dropped.
+pub struct PendingBroadcast(Arc<AtomicUsize>);
+
+impl Drop for PendingBroadcast {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Backlog {
+    pub fn new(config: BackpressureConfig, buffer: BufferConfig) -> Self {
+        Backlog {
+            config,
+            buffer,
+            ..Backlog::default()
+        }
+    }
+
+    /// Rejects a remote operation that would be buffered while its document's buffer is full,
+    /// if the overflow policy is `reject`.
+    pub fn admit_buffered(
+        &self,
+        rga: &RGA,
+        operation: &BroadcastOperation,
+    ) -> Result<(), ApiError> {
+        if self.buffer.overflow != OverflowPolicy::Reject
+            || self.buffer.max_operations == 0
+            || rga.buffered() < self.buffer.max_operations
+            || !rga.would_buffer(operation)
+        {
+            return Ok(());
+        }
+
+        self.buffer_rejections.fetch_add(1, Ordering::Relaxed);
+        let message: String = "The document's dependency buffer is full".to_string();
+        warn!(document_id = %operation.document_id, "Rejected a broadcast: {}", message);
+        Err(ApiError::ServiceUnavailable(
+            message,
+            self.config.retry_after_secs,
+        ))
+    }
+
+    /// Drops the expired operations of a document's buffer and, if it is over its size, the
+    /// oldest ones. A document that lost operations is queued to be reloaded.
+    pub fn shed(&self, document_id: Uuid, rga: &mut RGA) -> Shed {
+        let shed: Shed = rga.shed_buffer(self.buffer.max_operations, self.buffer.max_age());
+        if shed.total() == 0 {
+            return shed;
+        }
+
+        self.shed[0].fetch_add(shed.expired as u64, Ordering::Relaxed);
+        self.shed[1].fetch_add(shed.overflowed as u64, Ordering::Relaxed);
+        warn!(
+            document_id = %document_id,
+            expired = shed.expired,
+            overflowed = shed.overflowed,
+            "Dropped buffered operations, the document will be reloaded"
+        );
+        self.request_resync(document_id);
+        shed
+    }
+
+    fn request_resync(&self, document_id: Uuid) {
+        if let Ok(mut resyncs) = self.resyncs.lock() {
+            resyncs.insert(document_id);
+        }
+    }
+
+    /// Takes the documents waiting to be reloaded.
+    fn take_resyncs(&self) -> Vec<Uuid> {
+        match self.resyncs.lock() {
+            Ok(mut resyncs) => resyncs.drain().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Counts a received broadcast as pending until the returned guard is dropped.
+    pub fn receive(&self) -> PendingBroadcast {
+        self.applying.fetch_add(1, Ordering::Relaxed);
+        PendingBroadcast(Arc::clone(&self.applying))
+    }
+
+    /// Records the approximate number of broadcasts waiting in the replica's SQS queue.
+    pub fn set_queued(&self, queued: usize) {
+        self.queued.store(queued, Ordering::Relaxed);
+    }
+
+    /// Measures the backlogs of the loaded documents.
+    pub fn sizes(&self, rgas: &HashMap<Uuid, RGA>) -> BacklogSizes {
+        BacklogSizes {
+            buffered_operations: rgas.values().map(|rga| rga.buffered()).sum(),
+            dirty_documents: rgas.values().filter(|rga| rga.dirty).count(),
+            pending_broadcasts: self.applying.load(Ordering::Relaxed)
+                + self.queued.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Rejects an operation while a backlog is over its limit.
+    pub fn admit(&self, rgas: &HashMap<Uuid, RGA>) -> Result<(), ApiError> {
+        self.check(&self.sizes(rgas))
+    }
+
+    fn check(&self, sizes: &BacklogSizes) -> Result<(), ApiError> {
+        let over = sizes
+            .values()
+            .into_iter()
+            .zip(self.config.limits())
+            .position(|(size, limit)| limit > 0 && size > limit);
+        let Some(backlog) = over else {
+            return Ok(());
+        };
+
+        self.rejections[backlog].fetch_add(1, Ordering::Relaxed);
+        let message: String = format!(
+            "The replica's {} backlog is full",
+            BACKLOGS[backlog].replace('_', " ")
+        );
+        warn!(
+            backlog = BACKLOGS[backlog],
+            "Rejected an operation: {}", message
+        );
+        let retry_after: u64 = self.config.retry_after_secs;
+        Err(match backlog {
+            1 => ApiError::SlowDown(message, retry_after),
+            _ => ApiError::ServiceUnavailable(message, retry_after),
+        })
+    }
+
+    /// Formats the backlog sizes, limits and rejections in the Prometheus text exposition format.
+    pub fn to_prometheus(&self, sizes: &BacklogSizes) -> String {
+        let mut out = String::new();
+        let series: [(&str, &str, &str, [u64; 3]); 3] = [
+            (
+                "nimble_backlog",
+                "Size of each backlog checked before accepting operations.",
+                "gauge",
+                sizes.values().map(|size| size as u64),
+            ),
+            (
+                "nimble_backlog_limit",
+                "Configured limit of each backlog, 0 if disabled.",
+                "gauge",
+                self.config.limits().map(|limit| limit as u64),
+            ),
+            (
+                "nimble_backpressure_rejections_total",
+                "Operations rejected because a backlog was over its limit.",
+                "counter",
+                [0, 1, 2].map(|i| self.rejections[i].load(Ordering::Relaxed)),
+            ),
+        ];
+        for (name, help, kind, values) in series {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (backlog, value) in BACKLOGS.iter().zip(values) {
+                let _ = writeln!(out, "{}{{backlog=\"{}\"}} {}", name, backlog, value);
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP nimble_buffer_dropped_total Buffered operations dropped, by reason."
+        );
+        let _ = writeln!(out, "# TYPE nimble_buffer_dropped_total counter");
+        for (reason, dropped) in ["expired", "overflow"].iter().zip(self.shed.iter()) {
+            let _ = writeln!(
+                out,
+                "nimble_buffer_dropped_total{{reason=\"{}\"}} {}",
+                reason,
+                dropped.load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP nimble_buffer_rejections_total Remote operations rejected because their document's buffer was full."
+        );
+        let _ = writeln!(out, "# TYPE nimble_buffer_rejections_total counter");
+        let _ = writeln!(
+            out,
+            "nimble_buffer_rejections_total {}",
+            self.buffer_rejections.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+/// Drops the expired operations of every loaded document's buffer, then reloads the documents
+/// that lost operations since the last sweep.
+async fn sweep(backlog: &Backlog, rgas: &SharedRGAs, db: &Arc<Mutex<Client>>, session: &Session) {
+    {
+        let mut rgas = rgas.lock().await;
+        for (document_id, rga) in rgas.iter_mut() {
+            backlog.shed(*document_id, rga);
+        }
+    }
+
+    for document_id in backlog.take_resyncs() {
+        match divergence::resync(rgas, db, session, &document_id).await {
+            Ok(()) => {
+                info!(document_id = %document_id, "Reloaded a document that dropped buffered operations")
+            }
+            Err(e) => {
+                error!(document_id = %document_id, "Failed to reload the document: {}", e);
+                backlog.request_resync(document_id);
+            }
+        }
+    }
+}
+
+/// Fairing that starts a background task sweeping the dependency buffers every
+/// `check_interval_secs`. The task stops when Rocket begins shutting down.
+pub fn attach_buffer_sweep(config: BufferConfig) -> AdHoc {
+    AdHoc::on_liftoff("Dependency buffer sweep", move |rocket| {
+        Box::pin(async move {
+            if config.check_interval_secs == 0 {
+                info!("Dependency buffer sweep is disabled");
+                return;
+            }
+
+            let (backlog, rgas, db, session) = match (
+                rocket.state::<Backlog>(),
+                rocket.state::<SharedRGAs>(),
+                rocket.state::<Arc<Mutex<Client>>>(),
+                rocket.state::<Session>(),
+            ) {
+                (Some(backlog), Some(rgas), Some(db), Some(session)) => {
+                    (backlog.clone(), Arc::clone(rgas), Arc::clone(db), *session)
+                }
+                _ => {
+                    warn!("Replica state is unavailable, the dependency buffer sweep is disabled");
+                    return;
+                }
+            };
+            let mut shutdown = rocket.shutdown();
+
+            tokio::spawn(async move {
+                let mut interval =
+                    tokio::time::interval(Duration::from_secs(config.check_interval_secs));
+                interval.tick().await;
+
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = &mut shutdown => break,
+                    }
+
+                    sweep(&backlog, &rgas, &db, &session)
+                        .instrument(info_span!("backpressure.sweep"))
+                        .await;
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backpressure() {
+        let backlog = Backlog::new(
+            BackpressureConfig {
+                max_buffered_operations: 10,
+                max_dirty_documents: 2,
+                max_pending_broadcasts: 0,
+                retry_after_secs: 3,
+            },
+            BufferConfig::default(),
+        );
+        let mut sizes = BacklogSizes {
+            buffered_operations: 10,
+            dirty_documents: 2,
+            pending_broadcasts: 1_000_000,
+        };
+        assert!(backlog.check(&sizes).is_ok());
+
+        sizes.dirty_documents = 3;
+        let error = backlog.check(&sizes).unwrap_err();
+        assert!(matches!(error, ApiError::SlowDown(_, 3)));
+
+        sizes.buffered_operations = 11;
+        let error = backlog.check(&sizes).unwrap_err();
+        assert!(matches!(error, ApiError::ServiceUnavailable(_, 3)));
+
+        let pending = backlog.receive();
+        backlog.set_queued(4);
+        assert_eq!(backlog.sizes(&HashMap::new()).pending_broadcasts, 5);
+        drop(pending);
+        assert_eq!(backlog.sizes(&HashMap::new()).pending_broadcasts, 4);
+
+        let text = backlog.to_prometheus(&sizes);
+        assert!(text.contains("nimble_backlog{backlog=\"buffered_operations\"} 11"));
+        assert!(text.contains("nimble_backlog_limit{backlog=\"pending_broadcasts\"} 0"));
+        assert!(
+            text.contains("nimble_backpressure_rejections_total{backlog=\"dirty_documents\"} 1")
+        );
+        assert!(text
+            .contains("nimble_backpressure_rejections_total{backlog=\"buffered_operations\"} 1"));
+    }
+
+    fn delete(seq: i64) -> BroadcastOperation {
+        BroadcastOperation {
+            operation: "Delete".to_string(),
+            document_id: Uuid::nil(),
+            ssn: 1,
+            sum: 1,
+            sid: 2,
+            seq,
+            value: None,
+            left: None,
+            right: None,
+            nodes: Vec::new(),
+            request_id: None,
+            author: None,
+            origin_region: None,
+            sent_at: None,
+        }
+    }
+
+    #[test]
+    fn test_buffer_limits() {
+        let config = BufferConfig {
+            max_operations: 1,
+            max_age_secs: 0,
+            overflow: OverflowPolicy::Reject,
+            check_interval_secs: 30,
+        };
+        let rejecting = Backlog::new(BackpressureConfig::default(), config);
+        let mut rga = RGA::new(1, 1);
+        rga.apply_remote(delete(1)).unwrap();
+        assert_eq!(rga.buffered(), 1);
+
+        // only operations that would be buffered are rejected
+        let error = rejecting.admit_buffered(&rga, &delete(2)).unwrap_err();
+        assert!(matches!(error, ApiError::ServiceUnavailable(_, 1)));
+        let mut insert = delete(3);
+        insert.operation = "Insert".to_string();
+        insert.value = Some("a".to_string());
+        assert!(rejecting.admit_buffered(&rga, &insert).is_ok());
+
+        let dropping = Backlog::new(
+            BackpressureConfig::default(),
+            BufferConfig {
+                overflow: OverflowPolicy::DropOldest,
+                ..config
+            },
+        );
+        assert!(dropping.admit_buffered(&rga, &delete(2)).is_ok());
+        rga.apply_remote(delete(2)).unwrap();
+        let document_id = Uuid::new_v4();
+        assert_eq!(dropping.shed(document_id, &mut rga).overflowed, 1);
+        assert_eq!(rga.buffered(), 1);
+        assert_eq!(dropping.take_resyncs(), vec![document_id]);
+        assert!(dropping.take_resyncs().is_empty());
+
+        let text = dropping.to_prometheus(&BacklogSizes::default());
+        assert!(text.contains("nimble_buffer_dropped_total{reason=\"overflow\"} 1"));
+        assert!(text.contains("nimble_buffer_dropped_total{reason=\"expired\"} 0"));
+        let text = rejecting.to_prometheus(&BacklogSizes::default());
+        assert!(text.contains("nimble_buffer_rejections_total 1"));
+    }
+}
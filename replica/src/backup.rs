@@ -0,0 +1,596 @@
+//! Backups of documents to a versioned S3 bucket.
+//!
+//! Every `backup.interval_secs` each document's metadata and compacted snapshot is written to
+//! `<prefix>/documents/<document_id>.json`, and `<prefix>/index.json` lists the object version
+//! of every backup taken in the run. Bucket versioning keeps earlier backups, which can be
+//! restored by version id.
+
+use crate::admin::{parse_document_id, AdminConfig, AdminToken};
+use crate::flags::{Feature, Features};
+use crate::leader::{Leader, BACKUP_JOB};
+use crate::notifications::{self, NotificationKind};
+use crate::rga::rga::{Node, RGA};
+use crate::routes::SharedRGAs;
+use crate::signing::AwsSigner;
+use crate::tenancy::Tenant;
+use crate::{
+    db, encryption, snapshot, tenancy, ApiError, DocumentBackup, DocumentSnapshot, RequestId,
+};
+use aws_config::SdkConfig;
+use aws_credential_types::provider::SharedCredentialsProvider;
+use chrono::Utc;
+use rocket::fairing::AdHoc;
+use rocket::serde::json::Json;
+use rocket::tokio::{self, sync::Mutex};
+use rocket::{post, State};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_postgres::Client;
+use tracing::{error, info, info_span, instrument, warn, Instrument};
+use uuid::Uuid;
+
+/// Backups of documents to S3.
+/// `bucket`: The versioned bucket backups are written to, backups are disabled if unset.
+/// `region`: The region of the bucket.
+/// `prefix`: The key prefix of every backup object.
+/// `endpoint`: An S3 compatible endpoint to use instead of AWS (path-style addressing).
+/// `interval_secs`: Seconds between scheduled backups, 0 disables the schedule.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupConfig {
+    pub bucket: Option<String>,
+    pub region: String,
+    pub prefix: String,
+    pub endpoint: Option<String>,
+    pub interval_secs: u64,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            bucket: None,
+            region: "af-south-1".to_string(),
+            prefix: "backups".to_string(),
+            endpoint: None,
+            interval_secs: 60 * 60,
+        }
+    }
+}
+
+/// A backup listed in the index.
+/// `document_id`: The document.
+/// `version_id`: The S3 version of its backup object (None if the bucket is not versioned).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupEntry {
+    pub document_id: Uuid,
+    pub version_id: Option<String>,
+}
+
+/// The backups taken in the latest run.
+/// `backed_up_at`: When the run started.
+/// `documents`: The backup of every document that was backed up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupIndex {
+    pub backed_up_at: String,
+    pub documents: Vec<BackupEntry>,
+}
+
+/// A minimal S3 client signing requests with the replica's AWS credentials.
+pub struct S3Store {
+    signer: AwsSigner,
+    base_url: String,
+    prefix: String,
+}
+
+impl S3Store {
+    /// Creates the store, or returns None if no bucket or AWS credentials are configured.
+    pub fn new(aws_config: &SdkConfig, config: &BackupConfig) -> Option<S3Store> {
+        let bucket: &String = config.bucket.as_ref()?;
+        let credentials: SharedCredentialsProvider = aws_config.credentials_provider()?;
+
+        let base_url: String = match &config.endpoint {
+            Some(endpoint) => format!("{}/{}", endpoint.trim_end_matches('/'), bucket),
+            None => format!("https://{}.s3.{}.amazonaws.com", bucket, config.region),
+        };
+
+        Some(S3Store {
+            signer: AwsSigner::new(credentials, &config.region, "s3"),
+            base_url,
+            prefix: config.prefix.trim_matches('/').to_string(),
+        })
+    }
+
+    /// The key of a document's backup object.
+    pub fn document_key(&self, document_id: &Uuid) -> String {
+        format!("{}/documents/{}.json", self.prefix, document_id)
+    }
+
+    /// The key of the backup index.
+    pub fn index_key(&self) -> String {
+        format!("{}/index.json", self.prefix)
+    }
+
+    /// Signs and sends a request for `key`.
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        query: Option<(&str, &str)>,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, ApiError> {
+        let mut url = match reqwest::Url::parse(&format!("{}/{}", self.base_url, key)) {
+            Ok(url) => url,
+            Err(_) => {
+                error!("Invalid S3 object url");
+                return Err(ApiError::InternalServerError(
+                    "Invalid S3 object url".to_string(),
+                ));
+            }
+        };
+        if let Some((name, value)) = query {
+            url.query_pairs_mut().append_pair(name, value);
+        }
+
+        self.signer.send(method, url, &[], body).await
+    }
+
+    /// Writes an object and returns its version id.
+    #[instrument(name = "s3.put_object", skip(self, body))]
+    pub async fn put_object(&self, key: &str, body: Vec<u8>) -> Result<Option<String>, ApiError> {
+        let response = self.send(reqwest::Method::PUT, key, None, body).await?;
+        if !response.status().is_success() {
+            error!("S3 rejected the upload with status {}", response.status());
+            return Err(ApiError::RequestFailed(format!(
+                "S3 rejected the upload with status {}",
+                response.status()
+            )));
+        }
+
+        Ok(response
+            .headers()
+            .get("x-amz-version-id")
+            .and_then(|version| version.to_str().ok())
+            .map(|version| version.to_string()))
+    }
+
+    /// Reads an object, or a specific version of it.
+    #[instrument(name = "s3.get_object", skip(self))]
+    pub async fn get_object(
+        &self,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<Vec<u8>, ApiError> {
+        let query = version_id.map(|version| ("versionId", version));
+        let response = self
+            .send(reqwest::Method::GET, key, query, Vec::new())
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => match response.bytes().await {
+                Ok(body) => Ok(body.to_vec()),
+                Err(_) => {
+                    error!("Failed to read the S3 object");
+                    Err(ApiError::RequestFailed(
+                        "Failed to read the S3 object".to_string(),
+                    ))
+                }
+            },
+            reqwest::StatusCode::NOT_FOUND => {
+                Err(ApiError::NotFound(format!("No backup found at {}", key)))
+            }
+            status => {
+                error!("S3 rejected the download with status {}", status);
+                Err(ApiError::RequestFailed(format!(
+                    "S3 rejected the download with status {}",
+                    status
+                )))
+            }
+        }
+    }
+
+    /// Reads a document's backup.
+    pub async fn get_backup(
+        &self,
+        document_id: &Uuid,
+        version_id: Option<&str>,
+    ) -> Result<DocumentBackup, ApiError> {
+        let body: Vec<u8> = self
+            .get_object(&self.document_key(document_id), version_id)
+            .await?;
+        let body: Vec<u8> = encryption::open_backup(body).await?;
+        match serde_json::from_slice(&body) {
+            Ok(backup) => Ok(backup),
+            Err(_) => {
+                error!("Failed to parse the backup of {}", document_id);
+                Err(ApiError::InternalServerError(
+                    "Failed to parse the backup".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+/// Converts RGA nodes to snapshot rows.
+fn snapshot_rows(document_id: Uuid, nodes: Vec<Node>) -> Result<Vec<DocumentSnapshot>, ApiError> {
+    let mut rows: Vec<DocumentSnapshot> = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        let [ssn, sum, sid, seq] = node.s4vector.to_i64()?;
+        rows.push(DocumentSnapshot {
+            document_id,
+            ssn,
+            sum,
+            sid,
+            seq,
+            value: node.value,
+            tombstone: node.tombstone,
+            author: node.author,
+            authored_at: node.authored_at,
+        });
+    }
+    Ok(rows)
+}
+
+/// Builds the backup of a document: the loaded RGA if there is one (persisting unsaved changes
+/// first), otherwise the stored snapshot, compacted to one row per node.
+async fn build_backup(
+    client: &mut Client,
+    rgas: &mut HashMap<Uuid, RGA>,
+    document_id: Uuid,
+) -> Result<DocumentBackup, ApiError> {
+    let row = match client
+        .query_one(
+            "SELECT owner_id,title,creation_date,expires_at,language,workspace_id FROM document WHERE document_id=$1",
+            &[&document_id],
+        )
+        .await
+    {
+        Ok(row) => row,
+        Err(_) => {
+            error!("Failed to read the document's metadata");
+            return Err(ApiError::DatabaseError(
+                "Failed to read the document's metadata".to_string(),
+            ));
+        }
+    };
+
+    let nodes: Vec<Node> = match rgas.get_mut(&document_id) {
+        Some(rga) => {
+            if rga.dirty {
+                snapshot::persist_snapshot(client, &document_id, rga).await?;
+                rga.dirty = false;
+            }
+            rga.nodes()
+        }
+        None => {
+            let snapshots: Vec<DocumentSnapshot> =
+                snapshot::load_snapshots(client, &document_id).await?;
+            RGA::load_snapshot(snapshots, 0, 0).nodes()
+        }
+    };
+
+    Ok(DocumentBackup {
+        document_id,
+        owner_id: row.get(0),
+        title: row.get(1),
+        creation_date: row.get(2),
+        expires_at: row.get(3),
+        language: row.get(4),
+        workspace_id: row.get(5),
+        backed_up_at: Utc::now().to_rfc3339(),
+        nodes: snapshot_rows(document_id, nodes)?,
+    })
+}
+
+/// Backs up every document that is not archived and writes the index.
+/// The locks are held while a single document's backup is built, not while it is uploaded.
+/// Returns the index of the run.
+#[instrument(name = "backup.run", skip_all)]
+pub async fn backup_all(
+    store: &S3Store,
+    rgas: &SharedRGAs,
+    db: &Arc<Mutex<Client>>,
+) -> Result<BackupIndex, ApiError> {
+    let backed_up_at: String = Utc::now().to_rfc3339();
+
+    let document_ids: Vec<Uuid> = match tenancy::lock_any(db)
+        .await
+        .query(
+            "SELECT document_id FROM document WHERE archived_at IS NULL",
+            &[],
+        )
+        .await
+    {
+        Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+        Err(_) => {
+            error!("Failed to list documents to back up");
+            return Err(ApiError::DatabaseError(
+                "Failed to list documents to back up".to_string(),
+            ));
+        }
+    };
+
+    let mut documents: Vec<BackupEntry> = Vec::with_capacity(document_ids.len());
+    for document_id in document_ids {
+        let backup: DocumentBackup = {
+            let mut rgas = rgas.lock().await;
+            let mut client = tenancy::lock_any(db).await;
+            match build_backup(&mut client, &mut rgas, document_id).await {
+                Ok(backup) => backup,
+                Err(e) => {
+                    error!(document_id = %document_id, "Failed to back up document: {}", e);
+                    continue;
+                }
+            }
+        };
+
+        let body: Vec<u8> = match serde_json::to_vec(&backup) {
+            Ok(body) => body,
+            Err(_) => continue,
+        };
+        match store
+            .put_object(
+                &store.document_key(&document_id),
+                encryption::seal_backup(body),
+            )
+            .await
+        {
+            Ok(version_id) => documents.push(BackupEntry {
+                document_id,
+                version_id,
+            }),
+            Err(e) => error!(document_id = %document_id, "Failed to upload backup: {}", e),
+        }
+    }
+
+    let index = BackupIndex {
+        backed_up_at,
+        documents,
+    };
+    let body: Vec<u8> = match serde_json::to_vec(&index) {
+        Ok(body) => body,
+        Err(_) => {
+            return Err(ApiError::InternalServerError(
+                "Failed to serialize the backup index".to_string(),
+            ))
+        }
+    };
+    store.put_object(&store.index_key(), body).await?;
+
+    info!("Backed up {} documents", index.documents.len());
+    Ok(index)
+}
+
+/// Fairing that backs up every document every `interval_secs`, if a bucket is configured.
+/// Only the replica holding the lease on scheduled backups runs them.
+pub fn attach_backups(interval_secs: u64) -> AdHoc {
+    AdHoc::on_liftoff("Scheduled backups", move |rocket| {
+        Box::pin(async move {
+            let (store, rgas, db, leader, features) = match (
+                rocket
+                    .state::<Option<Arc<S3Store>>>()
+                    .and_then(Option::as_ref),
+                rocket.state::<SharedRGAs>(),
+                rocket.state::<Arc<Mutex<Client>>>(),
+                rocket.state::<Leader>(),
+                rocket.state::<Features>(),
+            ) {
+                (Some(store), Some(rgas), Some(db), Some(leader), Some(features)) => (
+                    Arc::clone(store),
+                    Arc::clone(rgas),
+                    Arc::clone(db),
+                    leader.clone(),
+                    features.clone(),
+                ),
+                (None, _, _, _, _) => {
+                    info!("Backups are disabled, no bucket is configured");
+                    return;
+                }
+                _ => {
+                    warn!("Replica state is unavailable, scheduled backups are disabled");
+                    return;
+                }
+            };
+            if interval_secs == 0 {
+                info!("Scheduled backups are disabled");
+                return;
+            }
+            let mut shutdown = rocket.shutdown();
+
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+                interval.tick().await;
+
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = &mut shutdown => break,
+                    }
+
+                    if !features.enabled(Feature::Backups, &Tenant::Any) {
+                        continue;
+                    }
+                    if !leader
+                        .acquire(&*db.lock().await, BACKUP_JOB, interval_secs)
+                        .await
+                    {
+                        continue;
+                    }
+                    // errors are logged, the next run retries
+                    let _ = backup_all(&store, &rgas, &db)
+                        .instrument(info_span!("backup.scheduled"))
+                        .await;
+                }
+                leader.release(&*db.lock().await, BACKUP_JOB).await;
+            });
+        })
+    })
+}
+
+/// Returns the store, or `Forbidden` if backups are not configured. The replica manages an
+/// `Option<Arc<S3Store>>`, None when no bucket is configured.
+fn require_store(store: &Option<Arc<S3Store>>) -> Result<&S3Store, ApiError> {
+    match store {
+        Some(store) => Ok(store),
+        None => Err(ApiError::Forbidden(
+            "Backups are not configured on this replica".to_string(),
+        )),
+    }
+}
+
+/// Restores a document from its latest backup, or from the backup with S3 version `version`.
+/// The document is unloaded so the next `GET /document/<id>` loads the restored state.
+/// Returns the number of nodes restored.
+#[post("/document/<id>/restore_backup?<version>")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+#[allow(clippy::too_many_arguments)]
+pub async fn restore_backup(
+    id: String,
+    version: Option<String>,
+    token: AdminToken,
+    admin: &State<AdminConfig>,
+    store: &State<Option<Arc<S3Store>>>,
+    rgas: &State<SharedRGAs>,
+    db: &State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<usize>, ApiError> {
+    token.require(admin)?;
+    let document_id: Uuid = parse_document_id(&id)?;
+    let store: &S3Store = require_store(store)?;
+
+    let backup: DocumentBackup = store.get_backup(&document_id, version.as_deref()).await?;
+
+    let mut rgas = rgas.lock().await;
+    let mut client = tenancy::lock_any(db).await;
+    let restored: usize = db::restore_document(&mut client, &backup).await?;
+    rgas.remove(&document_id);
+    notifications::notify_document(
+        &client,
+        &document_id,
+        NotificationKind::DocumentRestored,
+        None,
+        "The document was restored from a backup",
+    )
+    .await;
+
+    info!("Document restored from backup by an administrator");
+    Ok(Json(restored))
+}
+
+/// Disaster recovery: restores every document listed in the latest backup index.
+/// Restored documents are unloaded. Returns the restored documents.
+#[post("/admin/backups/restore")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn restore_all_backups(
+    token: AdminToken,
+    admin: &State<AdminConfig>,
+    store: &State<Option<Arc<S3Store>>>,
+    rgas: &State<SharedRGAs>,
+    db: &State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<Vec<Uuid>>, ApiError> {
+    token.require(admin)?;
+    let store: &S3Store = require_store(store)?;
+
+    let index: BackupIndex =
+        match serde_json::from_slice(&store.get_object(&store.index_key(), None).await?) {
+            Ok(index) => index,
+            Err(_) => {
+                error!("Failed to parse the backup index");
+                return Err(ApiError::InternalServerError(
+                    "Failed to parse the backup index".to_string(),
+                ));
+            }
+        };
+
+    let mut backups: Vec<DocumentBackup> = Vec::with_capacity(index.documents.len());
+    for entry in &index.documents {
+        match store
+            .get_backup(&entry.document_id, entry.version_id.as_deref())
+            .await
+        {
+            Ok(backup) => backups.push(backup),
+            Err(e) => error!(document_id = %entry.document_id, "Failed to download backup: {}", e),
+        }
+    }
+
+    let mut rgas = rgas.lock().await;
+    let mut client = tenancy::lock_any(db).await;
+    let restored: Vec<Uuid> = db::restore_documents(&mut client, &backups).await;
+    for document_id in &restored {
+        rgas.remove(document_id);
+    }
+
+    info!(
+        "Restored {} documents from the backups taken at {}",
+        restored.len(),
+        index.backed_up_at
+    );
+    Ok(Json(restored))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestDatabase, ORGANIZATION_TABLES};
+    use aws_credential_types::Credentials;
+    use rocket::http::{Header, Status};
+    use rocket::local::asynchronous::Client as LocalClient;
+
+    #[test]
+    fn test_store_keys() {
+        let aws_config = SdkConfig::builder()
+            .credentials_provider(SharedCredentialsProvider::new(Credentials::for_tests()))
+            .build();
+
+        assert!(S3Store::new(&aws_config, &BackupConfig::default()).is_none());
+
+        let config = BackupConfig {
+            bucket: Some("nimble-backups".to_string()),
+            prefix: "/replica-1/".to_string(),
+            ..BackupConfig::default()
+        };
+        let store = S3Store::new(&aws_config, &config).unwrap();
+        assert_eq!(
+            store.base_url,
+            "https://nimble-backups.s3.af-south-1.amazonaws.com"
+        );
+        assert_eq!(
+            store.document_key(&Uuid::nil()),
+            "replica-1/documents/00000000-0000-0000-0000-000000000000.json"
+        );
+        assert_eq!(store.index_key(), "replica-1/index.json");
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_restore_without_a_bucket() {
+        let database = TestDatabase::create(ORGANIZATION_TABLES).await;
+        let rocket = testing::rocket(&database)
+            .await
+            .manage(AdminConfig {
+                token: Some("admin-token".to_string()),
+            })
+            .manage(None::<Arc<S3Store>>)
+            .manage(SharedRGAs::default())
+            .mount("/", rocket::routes![restore_backup, restore_all_backups]);
+        // the replica launches without a bucket, and the restores report they are disabled
+        let client = LocalClient::tracked(rocket).await.unwrap();
+
+        let response = client
+            .post("/admin/backups/restore")
+            .header(Header::new("Authorization", "Bearer admin-token"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .post(format!("/document/{}/restore_backup", Uuid::nil()))
+            .header(Header::new("Authorization", "Bearer admin-token"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        database.drop().await;
+    }
+}
@@ -0,0 +1,447 @@
+//! Chaos testing for replication between replicas.
+//!
+//! Runs several in-process replicas of a document, connected by an in-memory broadcaster
+//! standing in for SNS, and applies random local edits on each of them. The broadcaster
+//! injects the faults SNS can produce: delayed, duplicated and reordered deliveries, and
+//! partitions that hold messages between groups of replicas until they heal.
+//!
+//! Once every message has been delivered the run checks that:
+//! - the replicas have converged on the same nodes, in the same order, with the same values
+//! - no operation is still buffered waiting on a dependency (liveness)
+//! - no edit, delivery or read panicked or hung
+//!
+//! Runs are deterministic for a seed, so a failing seed can be replayed.
+//!
+//! Usage:
+//! `cargo run --bin chaos -- [--replicas 3] [--operations 200] [--runs 20] [--seed 1]
+//!  [--max-delay 5] [--duplicate 0.1] [--partition 0.02]`
+
+use nimble::json_structures::BroadcastOperation;
+use nimble::rga::rga::RGA;
+use nimble::S4Vector;
+use rocket::tokio::task::{JoinError, JoinHandle};
+use rocket::tokio::{self, sync::Mutex};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Settings for a chaos run.
+/// `replicas`: The number of replicas editing the document.
+/// `operations`: The number of local edits made across all replicas per run.
+/// `runs`: The number of runs, each with the next seed.
+/// `seed`: The seed of the first run.
+/// `max_delay`: The maximum number of ticks a delivery is delayed by.
+/// `duplicate`: The probability a message is delivered twice.
+/// `partition`: The probability a partition starts on each tick.
+#[derive(Debug, Clone)]
+struct Config {
+    replicas: usize,
+    operations: usize,
+    runs: u64,
+    seed: u64,
+    max_delay: u64,
+    duplicate: f64,
+    partition: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            replicas: 3,
+            operations: 200,
+            runs: 20,
+            seed: 1,
+            max_delay: 5,
+            duplicate: 0.1,
+            partition: 0.02,
+        }
+    }
+}
+
+impl Config {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut args = args.peekable();
+
+        while let Some(flag) = args.next() {
+            let value = match args.next() {
+                Some(v) => v,
+                None => return Err(format!("missing value for {}", flag)),
+            };
+            let invalid = || format!("invalid value '{}' for {}", value, flag);
+
+            match flag.as_str() {
+                "--replicas" => config.replicas = value.parse().map_err(|_| invalid())?,
+                "--operations" => config.operations = value.parse().map_err(|_| invalid())?,
+                "--runs" => config.runs = value.parse().map_err(|_| invalid())?,
+                "--seed" => config.seed = value.parse().map_err(|_| invalid())?,
+                "--max-delay" => config.max_delay = value.parse().map_err(|_| invalid())?,
+                "--duplicate" => config.duplicate = value.parse().map_err(|_| invalid())?,
+                "--partition" => config.partition = value.parse().map_err(|_| invalid())?,
+                _ => return Err(format!("unknown flag {}", flag)),
+            }
+        }
+
+        if config.replicas < 2 {
+            return Err("--replicas must be at least 2".to_string());
+        }
+        Ok(config)
+    }
+}
+
+/// SplitMix64, so runs are reproducible from their seed without extra dependencies.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n.max(1)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        (self.next() as f64 / u64::MAX as f64) < probability
+    }
+}
+
+/// A broadcast on its way to a replica.
+struct Message {
+    from: usize,
+    to: usize,
+    deliver_at: u64,
+    operation: BroadcastOperation,
+}
+
+/// The in-memory broadcaster.
+/// `pending`: Messages that have not been delivered yet.
+/// `partition`: The side of the partition each replica is on and the tick it heals at.
+#[derive(Default)]
+struct Network {
+    pending: Vec<Message>,
+    partition: Option<(Vec<bool>, u64)>,
+    delivered: usize,
+    duplicated: usize,
+    partitions: usize,
+}
+
+impl Network {
+    fn broadcast(
+        &mut self,
+        rng: &mut Rng,
+        config: &Config,
+        from: usize,
+        tick: u64,
+        op: BroadcastOperation,
+    ) {
+        for to in (0..config.replicas).filter(|to| *to != from) {
+            let copies = if rng.chance(config.duplicate) { 2 } else { 1 };
+            self.duplicated += copies - 1;
+
+            for _ in 0..copies {
+                self.pending.push(Message {
+                    from,
+                    to,
+                    deliver_at: tick + rng.below(config.max_delay + 1),
+                    operation: op.clone(),
+                });
+            }
+        }
+    }
+
+    fn update_partition(&mut self, rng: &mut Rng, config: &Config, tick: u64) {
+        match &self.partition {
+            Some((_, heals_at)) if tick >= *heals_at => self.partition = None,
+            None if rng.chance(config.partition) => {
+                let mut sides: Vec<bool> = (0..config.replicas).map(|_| rng.chance(0.5)).collect();
+                // both sides need at least one replica
+                sides[0] = true;
+                sides[config.replicas - 1] = false;
+                self.partition = Some((sides, tick + 1 + rng.below(20)));
+                self.partitions += 1;
+            }
+            _ => (),
+        }
+    }
+
+    fn is_blocked(&self, message: &Message) -> bool {
+        match &self.partition {
+            Some((sides, _)) => sides[message.from] != sides[message.to],
+            None => false,
+        }
+    }
+
+    /// Removes the messages that can be delivered at `tick`, in a random order.
+    fn take_deliverable(&mut self, rng: &mut Rng, tick: u64) -> Vec<Message> {
+        let (mut ready, pending): (Vec<Message>, Vec<Message>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|m| m.deliver_at <= tick && !self.is_blocked(m));
+        self.pending = pending;
+
+        for i in (1..ready.len()).rev() {
+            ready.swap(i, rng.below(i as u64 + 1) as usize);
+        }
+        self.delivered += ready.len();
+        ready
+    }
+}
+
+/// Makes a random local edit on a replica and returns the operation to broadcast.
+fn local_edit(
+    rng: &mut Rng,
+    rga: &mut RGA,
+    replica: usize,
+    n: usize,
+    document_id: Uuid,
+) -> Option<BroadcastOperation> {
+    let nodes: Vec<(S4Vector, String)> = rga.read_nodes();
+    let value = format!("r{}-{}", replica, n);
+
+    // an empty document can only be inserted into
+    let roll = if nodes.is_empty() { 0 } else { rng.below(10) };
+    let result = match roll {
+        0..=5 => {
+            let position = rng.below(nodes.len() as u64 + 1) as usize;
+            let left = position.checked_sub(1).map(|i| nodes[i].0);
+            let right = nodes.get(position).map(|node| node.0);
+            rga.local_insert(value, left, right, document_id)
+        }
+        6..=7 => {
+            let target = nodes[rng.below(nodes.len() as u64) as usize].0;
+            rga.local_update(target, value, document_id)
+        }
+        _ => {
+            let target = nodes[rng.below(nodes.len() as u64) as usize].0;
+            rga.local_delete(target, document_id)
+        }
+    };
+
+    result.ok()
+}
+
+/// Runs one seeded scenario and returns the violated invariants.
+async fn run(config: &Config, seed: u64) -> (Vec<String>, Network) {
+    let mut rng = Rng(seed);
+    let document_id = Uuid::from_u64_pair(0, seed);
+    let replicas: Vec<Arc<Mutex<RGA>>> = (0..config.replicas)
+        .map(|i| Arc::new(Mutex::new(RGA::new(1, i as u64 + 1))))
+        .collect();
+    let mut network = Network::default();
+    let mut violations: Vec<String> = Vec::new();
+
+    let mut tick: u64 = 0;
+    let mut edits: usize = 0;
+    while edits < config.operations || !network.pending.is_empty() {
+        if edits < config.operations {
+            network.update_partition(&mut rng, config, tick);
+
+            let replica = rng.below(config.replicas as u64) as usize;
+            let rga = Arc::clone(&replicas[replica]);
+            let mut edit_rng = Rng(rng.next());
+            let edit = tokio::spawn(async move {
+                let mut rga = rga.lock().await;
+                local_edit(&mut edit_rng, &mut rga, replica, edits, document_id)
+            });
+            match supervise(edit).await {
+                Ok(Some(mut op)) => {
+                    op.document_id = document_id;
+                    network.broadcast(&mut rng, config, replica, tick, op);
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    violations.push(format!("local edit on replica {} {}", replica, e));
+                    return (violations, network);
+                }
+            }
+            edits += 1;
+        } else {
+            // no more edits, let the network heal and drain
+            network.partition = None;
+        }
+
+        for message in network.take_deliverable(&mut rng, tick) {
+            let rga = Arc::clone(&replicas[message.to]);
+            let description = format!(
+                "{} {:?} from replica {} to replica {}",
+                message.operation.operation,
+                message.operation.s4vector(),
+                message.from,
+                message.to
+            );
+
+            let delivery =
+                tokio::spawn(async move { rga.lock().await.apply_remote(message.operation) });
+            match supervise(delivery).await {
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) => violations.push(format!("{} was rejected: {}", description, e)),
+                Err(e) if e == HUNG => {
+                    violations.push(format!("{} {}", description, e));
+                    return (violations, network);
+                }
+                Err(e) => violations.push(format!("{} {}", description, e)),
+            }
+        }
+        tick += 1;
+    }
+
+    let mut views: Vec<Vec<(S4Vector, String)>> = Vec::with_capacity(config.replicas);
+    for (i, rga) in replicas.iter().enumerate() {
+        let rga = Arc::clone(rga);
+        let read = tokio::spawn(async move {
+            let rga = rga.lock().await;
+            (rga.buffered(), rga.read_nodes())
+        });
+        match supervise(read).await {
+            Ok((buffered, view)) => {
+                if buffered > 0 {
+                    violations.push(format!(
+                        "replica {} still has {} buffered operations",
+                        i, buffered
+                    ));
+                }
+                views.push(view);
+            }
+            Err(e) => {
+                violations.push(format!("reading replica {} {}", i, e));
+                return (violations, network);
+            }
+        }
+    }
+    for (i, view) in views.iter().enumerate().skip(1) {
+        if *view != views[0] {
+            violations.push(format!(
+                "replica {} diverged from replica 0 ({} nodes vs {} nodes)",
+                i,
+                view.len(),
+                views[0].len()
+            ));
+        }
+    }
+
+    (violations, network)
+}
+
+/// Reported when a task does not finish within `TASK_TIMEOUT`, e.g. looping over a cycle in the list.
+const HUNG: &str = "hung";
+
+/// How long a single edit, delivery or read may take before the replica is considered hung.
+const TASK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits for a task touching a replica, turning panics and hangs into errors
+/// so they are reported as violations instead of ending (or stalling) the run.
+async fn supervise<T>(task: JoinHandle<T>) -> Result<T, String> {
+    let abort = task.abort_handle();
+    match tokio::time::timeout(TASK_TIMEOUT, task).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) if e.is_panic() => Err(format!("panicked: {}", panic_message(e))),
+        Ok(Err(e)) => Err(format!("failed: {}", e)),
+        Err(_) => {
+            abort.abort();
+            Err(HUNG.to_string())
+        }
+    }
+}
+
+fn panic_message(error: JoinError) -> String {
+    let payload = error.into_panic();
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[rocket::main]
+async fn main() -> ExitCode {
+    let config = match Config::parse(std::env::args().skip(1)) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // panics are reported as violations
+    std::panic::set_hook(Box::new(|_| {}));
+
+    let mut failed: u64 = 0;
+    for seed in config.seed..config.seed + config.runs {
+        let (violations, network) = run(&config, seed).await;
+        let summary = format!(
+            "{} deliveries, {} duplicates, {} partitions",
+            network.delivered, network.duplicated, network.partitions
+        );
+
+        if violations.is_empty() {
+            println!("seed {}: ok ({})", seed, summary);
+            continue;
+        }
+
+        failed += 1;
+        println!(
+            "seed {}: {} violations ({})",
+            seed,
+            violations.len(),
+            summary
+        );
+        for violation in violations.iter().take(5) {
+            println!("  - {}", violation);
+        }
+        if violations.len() > 5 {
+            println!("  - ... and {} more", violations.len() - 5);
+        }
+    }
+
+    println!("{} of {} runs passed", config.runs - failed, config.runs);
+    if failed > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(args(&["--replicas", "5", "--duplicate", "0.5"])).unwrap();
+        assert_eq!(config.replicas, 5);
+        assert_eq!(config.duplicate, 0.5);
+        assert_eq!(config.operations, Config::default().operations);
+
+        assert!(Config::parse(args(&["--replicas", "1"])).is_err());
+        assert!(Config::parse(args(&["--replicas"])).is_err());
+        assert!(Config::parse(args(&["--unknown", "1"])).is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_fault_free_run_converges() {
+        let config = Config {
+            operations: 50,
+            max_delay: 0,
+            duplicate: 0.0,
+            partition: 0.0,
+            ..Config::default()
+        };
+        let (violations, _) = run(&config, 7).await;
+        assert!(violations.is_empty(), "{:?}", violations);
+    }
+}
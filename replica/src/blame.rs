@@ -0,0 +1,147 @@
+use crate::pool::Pool;
+use crate::rga::rga::{Node, RGA};
+use crate::routes::SharedRGAs;
+use crate::share::Access;
+use crate::{Actor, ApiError, RequestId, S4Vector};
+use rocket::get;
+use rocket::serde::json::Json;
+use serde::{Deserialize, Serialize};
+use tracing::{error, instrument};
+use uuid::Uuid;
+
+/// A run of consecutive text written by the same author on the same replica.
+/// `author`: The user that wrote the text (None if unknown, e.g. anonymous requests).
+/// `site_id`: The replica the text was written on.
+/// `authored_at`: When the most recent node of the run was written (None if unknown).
+/// `start`: The s4vector of the first node of the run.
+/// `nodes`: The number of nodes in the run.
+/// `text`: The text of the run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlameRun {
+    pub author: Option<Uuid>,
+    pub site_id: u64,
+    pub authored_at: Option<String>,
+    pub start: S4Vector,
+    pub nodes: usize,
+    pub text: String,
+}
+
+/// Response body with the authorship of a loaded document.
+/// `document_id`: The document.
+/// `runs`: The document's visible text in order, split into runs by author.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DocumentBlame {
+    pub document_id: Uuid,
+    pub runs: Vec<BlameRun>,
+}
+
+/// Groups visible nodes (in document order) into runs of the same author and site.
+pub fn blame_runs(nodes: Vec<Node>) -> Vec<BlameRun> {
+    let mut runs: Vec<BlameRun> = Vec::new();
+
+    for node in nodes {
+        match runs.last_mut() {
+            Some(run) if run.author == node.author && run.site_id == node.s4vector.sid => {
+                run.nodes += 1;
+                run.text.push_str(&node.value);
+                // timestamps are RFC 3339 in UTC, so the latest sorts last
+                if node.authored_at > run.authored_at {
+                    run.authored_at = node.authored_at;
+                }
+            }
+            _ => runs.push(BlameRun {
+                author: node.author,
+                site_id: node.s4vector.sid,
+                authored_at: node.authored_at,
+                start: node.s4vector,
+                nodes: 1,
+                text: node.value,
+            }),
+        }
+    }
+    runs
+}
+
+/// Returns the text of a loaded document annotated with who wrote it and when.
+/// Updating a node attributes its value to the user that made the update.
+#[get("/document/<id>/blame")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn fetch_blame(
+    id: String,
+    actor: Actor,
+    pool: &rocket::State<Pool>,
+    rgas: &rocket::State<SharedRGAs>,
+    request_id: RequestId,
+) -> Result<Json<DocumentBlame>, ApiError> {
+    let document_id: Uuid = match Uuid::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => {
+            error!("Failed to parse document id");
+            return Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ));
+        }
+    };
+    actor.authorize(pool, &document_id, Access::Read).await?;
+
+    let mut rgas = rgas.lock().await;
+    let rga: &mut RGA = match rgas.get_mut(&document_id) {
+        Some(r) => r,
+        None => {
+            error!("Document not found");
+            return Err(ApiError::NotFound(String::from("Document not found")));
+        }
+    };
+    rga.touch();
+
+    Ok(Json(DocumentBlame {
+        document_id,
+        runs: blame_runs(rga.visible_nodes()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DocumentSnapshot;
+
+    #[test]
+    fn test_blame_runs() {
+        let alice = Uuid::from_u128(1);
+        let bob = Uuid::from_u128(2);
+        let row = |seq: i64, sid: i64, author: Option<Uuid>, at: &str| DocumentSnapshot {
+            document_id: Uuid::nil(),
+            ssn: 1,
+            sum: seq,
+            sid,
+            seq,
+            value: format!("{} ", seq),
+            tombstone: false,
+            author,
+            authored_at: Some(at.to_string()),
+        };
+
+        // authorship is restored from the snapshot
+        let snapshots = vec![
+            row(1, 1, Some(alice), "2025-01-04T10:00:02Z"),
+            row(2, 1, Some(alice), "2025-01-04T10:00:01Z"),
+            row(3, 1, Some(bob), "2025-01-04T10:00:03Z"),
+            row(4, 2, Some(bob), "2025-01-04T10:00:04Z"),
+        ];
+        let rga = RGA::load_snapshot(snapshots, 1, 1);
+        let runs = blame_runs(rga.nodes());
+        let summary: Vec<(Option<Uuid>, u64, &str, usize)> = runs
+            .iter()
+            .map(|run| (run.author, run.site_id, run.text.as_str(), run.nodes))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Some(alice), 1, "1 2 ", 2),
+                (Some(bob), 1, "3 ", 1),
+                (Some(bob), 2, "4 ", 1),
+            ]
+        );
+        assert_eq!(runs[0].authored_at.as_deref(), Some("2025-01-04T10:00:02Z"));
+    }
+}
@@ -0,0 +1,310 @@
+//! Per-document feeds of applied operations.
+//!
+//! Every operation applied on the replica, whether it came from a client or another replica, is
+//! published to its document's feed with a version number. Clients that cannot hold a
+//! streaming connection open poll `GET /document/<id>/changes`, which waits on the feed until
+//! an operation newer than the client's version arrives.
+
+use crate::pool::Pool;
+use crate::routes::SharedRGAs;
+use crate::share::Access;
+use crate::{Actor, ApiError, AppliedOperation, RequestId};
+use rocket::serde::json::Json;
+use rocket::tokio::sync::broadcast;
+use rocket::tokio::{self, time};
+use rocket::{get, Shutdown};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, instrument};
+use uuid::Uuid;
+
+/// Operations kept per document for clients catching up.
+pub const HISTORY_LEN: usize = 1024;
+
+/// How long a poll waits when no `timeout` is given.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest a poll may wait.
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An operation published to a document's feed.
+/// `version`: The document's version after the operation, increasing by one per operation.
+/// `operation`: The applied operation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Change {
+    pub version: u64,
+    pub operation: AppliedOperation,
+}
+
+/// Response body of a changes poll.
+/// `document_id`: The document.
+/// `version`: The version to pass as `since` in the next poll.
+/// `changes`: The operations applied after `since`, oldest first (empty if the poll timed out).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangesResponse {
+    pub document_id: Uuid,
+    pub version: u64,
+    pub changes: Vec<Change>,
+}
+
+/// The feed of a single document.
+/// `version`: The version of the latest operation.
+/// `history`: The most recent operations, at most `HISTORY_LEN`.
+/// `sender`: Wakes the polls waiting on the document.
+struct DocumentFeed {
+    version: u64,
+    history: VecDeque<Change>,
+    sender: broadcast::Sender<u64>,
+}
+
+impl DocumentFeed {
+    fn new() -> Self {
+        DocumentFeed {
+            version: 0,
+            history: VecDeque::new(),
+            sender: broadcast::channel(16).0,
+        }
+    }
+
+    /// The changes after `since`, or `Gone` if some of them are no longer kept.
+    fn since(&self, since: u64) -> Result<Vec<Change>, ApiError> {
+        if since > self.version {
+            return Err(ApiError::Gone(format!(
+                "Version {} is ahead of the replica's version {}, reload the document",
+                since, self.version
+            )));
+        }
+
+        let oldest: u64 = self.version - self.history.len() as u64;
+        if since < oldest {
+            return Err(ApiError::Gone(format!(
+                "Changes before version {} are no longer kept, reload the document",
+                oldest
+            )));
+        }
+
+        let skip: usize = (since - oldest) as usize;
+        Ok(self.history.iter().skip(skip).cloned().collect())
+    }
+}
+
+/// The change feeds of every document, managed in Rocket's state.
+#[derive(Clone, Default)]
+pub struct ChangeFeeds {
+    feeds: Arc<Mutex<HashMap<Uuid, DocumentFeed>>>,
+}
+
+impl ChangeFeeds {
+    /// Publishes an applied operation and wakes the polls waiting on its document.
+    /// Returns the document's new version.
+    pub fn publish(&self, document_id: Uuid, operation: AppliedOperation) -> u64 {
+        let mut feeds = self.feeds.lock().unwrap_or_else(|e| e.into_inner());
+        let feed: &mut DocumentFeed = feeds.entry(document_id).or_insert_with(DocumentFeed::new);
+
+        feed.version += 1;
+        feed.history.push_back(Change {
+            version: feed.version,
+            operation,
+        });
+        if feed.history.len() > HISTORY_LEN {
+            feed.history.pop_front();
+        }
+
+        // no receivers just means no one is polling
+        let _ = feed.sender.send(feed.version);
+        feed.version
+    }
+
+    /// The current version and the changes after `since` (or after the current version if
+    /// `since` is None). If there are none, also returns a receiver woken by the next change,
+    /// subscribed before the lock is released so no change can be missed.
+    fn poll(
+        &self,
+        document_id: Uuid,
+        since: Option<u64>,
+    ) -> Result<(u64, u64, Vec<Change>, broadcast::Receiver<u64>), ApiError> {
+        let mut feeds = self.feeds.lock().unwrap_or_else(|e| e.into_inner());
+        let feed: &mut DocumentFeed = feeds.entry(document_id).or_insert_with(DocumentFeed::new);
+
+        let since: u64 = since.unwrap_or(feed.version);
+        let changes: Vec<Change> = feed.since(since)?;
+        Ok((since, feed.version, changes, feed.sender.subscribe()))
+    }
+
+    /// The document's current version.
+    pub fn version(&self, document_id: Uuid) -> u64 {
+        let feeds = self.feeds.lock().unwrap_or_else(|e| e.into_inner());
+        feeds.get(&document_id).map_or(0, |feed| feed.version)
+    }
+
+    /// Returns the changes after `since`.
+    pub fn changes(&self, document_id: Uuid, since: u64) -> Result<(u64, Vec<Change>), ApiError> {
+        let feeds = self.feeds.lock().unwrap_or_else(|e| e.into_inner());
+        match feeds.get(&document_id) {
+            Some(feed) => Ok((feed.version, feed.since(since)?)),
+            None => Ok((0, DocumentFeed::new().since(since)?)),
+        }
+    }
+}
+
+/// Parses a poll timeout such as `30s`, `500ms` or `30` (seconds), capped at `MAX_TIMEOUT`.
+pub fn parse_timeout(timeout: &str) -> Option<Duration> {
+    let timeout: &str = timeout.trim();
+    let duration: Duration = if let Some(ms) = timeout.strip_suffix("ms") {
+        Duration::from_millis(ms.trim().parse().ok()?)
+    } else {
+        let secs: &str = timeout.strip_suffix('s').unwrap_or(timeout);
+        Duration::from_secs(secs.trim().parse().ok()?)
+    };
+    Some(duration.min(MAX_TIMEOUT))
+}
+
+/// Waits until operations newer than `since` are applied to a loaded document, or until the
+/// timeout expires, for clients that can't keep a streaming connection open.
+/// `since`: The version from the previous poll, omit it to wait for the next operation.
+/// `timeout`: How long to wait, e.g. `30s` (defaults to 30 seconds, at most 60).
+///
+/// Returns `410 Gone` if `since` is older than the operations the replica keeps (or from
+/// before a restart), in which case the client should reload the document.
+#[get("/document/<id>/changes?<since>&<timeout>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn poll_changes(
+    id: String,
+    since: Option<u64>,
+    timeout: Option<String>,
+    actor: Actor,
+    pool: &rocket::State<Pool>,
+    rgas: &rocket::State<SharedRGAs>,
+    feeds: &rocket::State<ChangeFeeds>,
+    mut shutdown: Shutdown,
+    request_id: RequestId,
+) -> Result<Json<ChangesResponse>, ApiError> {
+    let document_id: Uuid = match Uuid::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => {
+            error!("Failed to parse document id");
+            return Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ));
+        }
+    };
+
+    let timeout: Duration = match timeout {
+        Some(timeout) => match parse_timeout(&timeout) {
+            Some(timeout) => timeout,
+            None => {
+                error!("Failed to parse the poll timeout");
+                return Err(ApiError::InvalidOperation(
+                    "timeout must be a duration such as 30s or 500ms".to_string(),
+                ));
+            }
+        },
+        None => DEFAULT_TIMEOUT,
+    };
+    actor.authorize(pool, &document_id, Access::Read).await?;
+
+    if !rgas.lock().await.contains_key(&document_id) {
+        error!("Document not found");
+        return Err(ApiError::NotFound(String::from("Document not found")));
+    }
+
+    let (since, version, changes, mut receiver) = feeds.poll(document_id, since)?;
+    if !changes.is_empty() {
+        return Ok(Json(ChangesResponse {
+            document_id,
+            version,
+            changes,
+        }));
+    }
+
+    // any wake up (including a lagged receiver) means there is something newer than `since`
+    tokio::select! {
+        _ = receiver.recv() => {}
+        _ = time::sleep(timeout) => {}
+        _ = &mut shutdown => {}
+    }
+
+    let (version, changes) = feeds.changes(document_id, since)?;
+    Ok(Json(ChangesResponse {
+        document_id,
+        version,
+        changes,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BroadcastOperation;
+
+    fn operation(seq: i64) -> AppliedOperation {
+        AppliedOperation::new(
+            BroadcastOperation {
+                operation: "Insert".to_string(),
+                document_id: Uuid::nil(),
+                ssn: 1,
+                sum: seq,
+                sid: 1,
+                seq,
+                value: Some("a".to_string()),
+                left: None,
+                right: None,
+                nodes: Vec::new(),
+                request_id: None,
+                author: None,
+                origin_region: None,
+                sent_at: None,
+            },
+            String::new(),
+        )
+    }
+
+    #[test]
+    fn test_changes_since() {
+        let feeds = ChangeFeeds::default();
+        let document_id = Uuid::nil();
+
+        assert_eq!(feeds.changes(document_id, 0).unwrap().0, 0);
+        for seq in 1..=HISTORY_LEN as i64 + 2 {
+            feeds.publish(document_id, operation(seq));
+        }
+
+        let (version, changes) = feeds.changes(document_id, HISTORY_LEN as u64).unwrap();
+        assert_eq!(version, HISTORY_LEN as u64 + 2);
+        let versions: Vec<u64> = changes.iter().map(|c| c.version).collect();
+        assert_eq!(
+            versions,
+            vec![HISTORY_LEN as u64 + 1, HISTORY_LEN as u64 + 2]
+        );
+
+        // the first two operations have been dropped from the history
+        assert!(feeds.changes(document_id, 1).is_err());
+        assert!(feeds.changes(document_id, 2).is_ok());
+        assert!(feeds.changes(document_id, version + 1).is_err());
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_timeout("15"), Some(Duration::from_secs(15)));
+        assert_eq!(parse_timeout("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_timeout("1h"), None);
+        assert_eq!(parse_timeout("600s"), Some(MAX_TIMEOUT));
+    }
+
+    #[rocket::async_test]
+    async fn test_poll_wakes_on_publish() {
+        let feeds = ChangeFeeds::default();
+        let document_id = Uuid::nil();
+
+        let (since, _, changes, mut receiver) = feeds.poll(document_id, None).unwrap();
+        assert!(changes.is_empty());
+
+        feeds.publish(document_id, operation(1));
+        assert_eq!(receiver.recv().await.unwrap(), 1);
+        assert_eq!(feeds.changes(document_id, since).unwrap().1.len(), 1);
+    }
+}
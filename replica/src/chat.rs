@@ -0,0 +1,462 @@
+//! Per-document chat between collaborators.
+//!
+//! Messages are kept in memory per document and delivered by long polling
+//! `GET /document/<id>/chat`, the same way operations are delivered by the change feeds.
+//! Each message is mirrored to the other replicas through SNS with `persist: false`, so only
+//! the replica that received it stores it, and only for documents created with
+//! `persist_chat` enabled.
+
+use crate::pool::Pool;
+use crate::changes::{parse_timeout, DEFAULT_TIMEOUT};
+use crate::flags::{Feature, Features};
+use crate::limits::JsonBody;
+use crate::routes::SharedRGAs;
+use crate::share::Access;
+use crate::tenancy::{self, Tenant};
+use crate::{expiry, sqs, Actor, ApiError, FieldError, RequestId};
+use aws_sdk_sns::Client as SnsClient;
+use rocket::serde::json::Json;
+use rocket::tokio::sync::{broadcast, Mutex};
+use rocket::tokio::{self, time};
+use rocket::{get, post, Shutdown};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// Messages kept in memory per document.
+pub const CHAT_HISTORY_LEN: usize = 256;
+
+/// The longest chat message accepted, in UTF-8 bytes.
+pub const MAX_MESSAGE_BYTES: usize = 2000;
+
+/// A chat message.
+/// `message_id`: Unique id of the message, used to ignore duplicate deliveries.
+/// `document_id`: The document the message was sent on.
+/// `author`: The user that sent the message (None if the request was anonymous).
+/// `text`: The message.
+/// `sent_at`: When the message was sent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub message_id: Uuid,
+    pub document_id: Uuid,
+    pub author: Option<Uuid>,
+    pub text: String,
+    pub sent_at: String,
+}
+
+/// A chat message mirrored to other replicas through SNS.
+/// `chat`: The message.
+/// `persist`: Whether the receiving replica should store the message, always false since
+/// the sending replica already has.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatBroadcast {
+    pub chat: ChatMessage,
+    #[serde(default)]
+    pub persist: bool,
+}
+
+/// Request body for sending a message.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatRequest {
+    pub text: String,
+}
+
+/// A message in a document's chat.
+/// `seq`: The position of the message in the document's chat on this replica.
+/// `message`: The message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatEntry {
+    pub seq: u64,
+    pub message: ChatMessage,
+}
+
+/// Response body of a chat poll.
+/// `document_id`: The document.
+/// `seq`: The value to pass as `since` in the next poll.
+/// `messages`: The messages after `since`, oldest first (empty if the poll timed out).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatResponse {
+    pub document_id: Uuid,
+    pub seq: u64,
+    pub messages: Vec<ChatEntry>,
+}
+
+struct Room {
+    seq: u64,
+    history: VecDeque<ChatEntry>,
+    sender: broadcast::Sender<u64>,
+}
+
+impl Room {
+    fn new() -> Self {
+        Room {
+            seq: 0,
+            history: VecDeque::new(),
+            sender: broadcast::channel(16).0,
+        }
+    }
+
+    fn since(&self, since: u64) -> Vec<ChatEntry> {
+        self.history
+            .iter()
+            .filter(|entry| entry.seq > since)
+            .cloned()
+            .collect()
+    }
+}
+
+/// The chat rooms of every document, managed in Rocket's state.
+#[derive(Clone, Default)]
+pub struct ChatRooms {
+    rooms: Arc<std::sync::Mutex<HashMap<Uuid, Room>>>,
+}
+
+impl ChatRooms {
+    /// Adds a message to its document's chat and wakes the polls waiting on it.
+    /// Returns the message's seq, or None if the message was already delivered.
+    pub fn publish(&self, message: ChatMessage) -> Option<u64> {
+        let mut rooms = self.rooms.lock().unwrap_or_else(|e| e.into_inner());
+        let room: &mut Room = rooms.entry(message.document_id).or_insert_with(Room::new);
+
+        if room
+            .history
+            .iter()
+            .any(|entry| entry.message.message_id == message.message_id)
+        {
+            return None;
+        }
+
+        room.seq += 1;
+        room.history.push_back(ChatEntry {
+            seq: room.seq,
+            message,
+        });
+        if room.history.len() > CHAT_HISTORY_LEN {
+            room.history.pop_front();
+        }
+
+        // no receivers just means no one is polling
+        let _ = room.sender.send(room.seq);
+        Some(room.seq)
+    }
+
+    /// The current seq and the messages after `since` (or after the current seq if None),
+    /// with a receiver woken by the next message.
+    fn poll(
+        &self,
+        document_id: Uuid,
+        since: Option<u64>,
+    ) -> (u64, u64, Vec<ChatEntry>, broadcast::Receiver<u64>) {
+        let mut rooms = self.rooms.lock().unwrap_or_else(|e| e.into_inner());
+        let room: &mut Room = rooms.entry(document_id).or_insert_with(Room::new);
+
+        let since: u64 = since.unwrap_or(room.seq);
+        (since, room.seq, room.since(since), room.sender.subscribe())
+    }
+
+    /// The current seq and the messages after `since`. Messages no longer kept are skipped.
+    pub fn messages(&self, document_id: Uuid, since: u64) -> (u64, Vec<ChatEntry>) {
+        let rooms = self.rooms.lock().unwrap_or_else(|e| e.into_inner());
+        match rooms.get(&document_id) {
+            Some(room) => (room.seq, room.since(since)),
+            None => (0, Vec::new()),
+        }
+    }
+}
+
+/// Stores a message in the `chat_messages` table if the document was created with
+/// `persist_chat`. Returns whether it was stored.
+pub async fn persist_message(client: &Client, message: &ChatMessage) -> Result<bool, ApiError> {
+    match client
+        .execute(
+            "INSERT INTO chat_messages (message_id,document_id,author,text,sent_at) \
+             SELECT $1,$2,$3,$4,$5 FROM document WHERE document_id=$2 AND persist_chat",
+            &[
+                &message.message_id,
+                &message.document_id,
+                &message.author,
+                &message.text,
+                &message.sent_at,
+            ],
+        )
+        .await
+    {
+        Ok(rows) => Ok(rows > 0),
+        Err(_) => {
+            error!("Failed to insert into the chat_messages table");
+            Err(ApiError::DatabaseError(
+                "Failed to store the chat message".to_string(),
+            ))
+        }
+    }
+}
+
+/// Mirrors a chat message to other replicas.
+#[instrument(name = "sns.publish_chat", skip_all, fields(document_id = %message.document_id))]
+pub async fn send_chat(
+    sns_client: Arc<Mutex<SnsClient>>,
+    topic_arn: &str,
+    message: &ChatMessage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let broadcast = ChatBroadcast {
+        chat: message.clone(),
+        persist: false,
+    };
+    let message = match serde_json::to_string(&broadcast) {
+        Ok(m) => m,
+        Err(_) => return Err(Box::new(Error::other("Failed to serialize chat message"))),
+    };
+
+    let publish = sns_client
+        .lock()
+        .await
+        .publish()
+        .topic_arn(topic_arn)
+        .message(message);
+    sqs::tag_origin(publish).send().await?;
+
+    info!("Chat message sent to other replicas");
+    Ok(())
+}
+
+fn parse_document_id(id: &str) -> Result<Uuid, ApiError> {
+    match Uuid::parse_str(id) {
+        Ok(id) => Ok(id),
+        Err(_) => {
+            error!("Failed to parse document id");
+            Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ))
+        }
+    }
+}
+
+/// Sends a chat message to the collaborators of a loaded document.
+/// Returns the message with its id and time.
+#[post("/document/<id>/chat", data = "<request>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn send_message(
+    id: String,
+    request: JsonBody<ChatRequest>,
+    actor: Actor,
+    rgas: &rocket::State<SharedRGAs>,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    features: &rocket::State<Features>,
+    sns_client: &rocket::State<Arc<Mutex<SnsClient>>>,
+    topic: &rocket::State<Arc<Mutex<String>>>,
+    chats: &rocket::State<ChatRooms>,
+    request_id: RequestId,
+) -> Result<Json<ChatMessage>, ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+
+    let text: &str = request.text.trim();
+    let invalid: Option<&str> = if text.is_empty() {
+        Some("must not be empty")
+    } else if text.len() > MAX_MESSAGE_BYTES {
+        Some("is too long")
+    } else {
+        None
+    };
+    if let Some(message) = invalid {
+        error!("Rejected chat message");
+        return Err(ApiError::ValidationFailed(vec![FieldError::new(
+            "text", message,
+        )]));
+    }
+
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
+    features.require(Feature::Chat, &tenant)?;
+    if !rgas.lock().await.contains_key(&document_id) {
+        error!("Document not found");
+        return Err(ApiError::NotFound(String::from("Document not found")));
+    }
+
+    let message = ChatMessage {
+        message_id: Uuid::new_v4(),
+        document_id,
+        author: actor.user_id,
+        text: text.to_string(),
+        sent_at: expiry::timestamp(chrono::Utc::now()),
+    };
+
+    persist_message(&*tenancy::lock(db, &tenant).await?, &message).await?;
+    chats.publish(message.clone());
+
+    // chat is best effort, the message is still delivered on this replica
+    if send_chat(Arc::clone(sns_client), &topic.lock().await, &message)
+        .await
+        .is_err()
+    {
+        error!("Failed to mirror the chat message to other replicas");
+    }
+
+    Ok(Json(message))
+}
+
+/// Waits for chat messages after `since` on a loaded document, or until the timeout expires.
+/// `since`: The seq from the previous poll, omit it to wait for the next message.
+/// `timeout`: How long to wait, e.g. `30s` (defaults to 30 seconds, at most 60).
+#[get("/document/<id>/chat?<since>&<timeout>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn poll_messages(
+    id: String,
+    since: Option<u64>,
+    timeout: Option<String>,
+    actor: Actor,
+    pool: &rocket::State<Pool>,
+    rgas: &rocket::State<SharedRGAs>,
+    chats: &rocket::State<ChatRooms>,
+    mut shutdown: Shutdown,
+    request_id: RequestId,
+) -> Result<Json<ChatResponse>, ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+    actor.authorize(pool, &document_id, Access::Read).await?;
+
+    let timeout: Duration = match timeout {
+        Some(timeout) => match parse_timeout(&timeout) {
+            Some(timeout) => timeout,
+            None => {
+                error!("Failed to parse the poll timeout");
+                return Err(ApiError::InvalidOperation(
+                    "timeout must be a duration such as 30s or 500ms".to_string(),
+                ));
+            }
+        },
+        None => DEFAULT_TIMEOUT,
+    };
+
+    if !rgas.lock().await.contains_key(&document_id) {
+        error!("Document not found");
+        return Err(ApiError::NotFound(String::from("Document not found")));
+    }
+
+    let (since, seq, messages, mut receiver) = chats.poll(document_id, since);
+    if !messages.is_empty() {
+        return Ok(Json(ChatResponse {
+            document_id,
+            seq,
+            messages,
+        }));
+    }
+
+    tokio::select! {
+        _ = receiver.recv() => {}
+        _ = time::sleep(timeout) => {}
+        _ = &mut shutdown => {}
+    }
+
+    let (seq, messages) = chats.messages(document_id, since);
+    Ok(Json(ChatResponse {
+        document_id,
+        seq,
+        messages,
+    }))
+}
+
+/// Returns the stored messages of a document created with `persist_chat`, oldest first.
+/// `limit`: The number of most recent messages to return (defaults to 100).
+#[get("/document/<id>/chat/history?<limit>")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn fetch_chat_history(
+    id: String,
+    limit: Option<i64>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    features: &rocket::State<Features>,
+    request_id: RequestId,
+) -> Result<Json<Vec<ChatMessage>>, ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+    let limit: i64 = limit.unwrap_or(100).clamp(1, 1000);
+
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
+    features.require(Feature::Chat, &tenant)?;
+    let client = tenancy::lock(db, &tenant).await?;
+    let rows = match client
+        .query(
+            "SELECT * FROM (SELECT message_id,document_id,author,text,sent_at FROM chat_messages \
+             WHERE document_id=$1 ORDER BY sent_at DESC LIMIT $2) AS recent ORDER BY sent_at",
+            &[&document_id, &limit],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => {
+            error!("Failed to read the chat_messages table");
+            return Err(ApiError::DatabaseError(
+                "Failed to read the chat history".to_string(),
+            ));
+        }
+    };
+
+    Ok(Json(
+        rows.iter()
+            .map(|row| ChatMessage {
+                message_id: row.get(0),
+                document_id: row.get(1),
+                author: row.get(2),
+                text: row.get(3),
+                sent_at: row.get(4),
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> ChatMessage {
+        ChatMessage {
+            message_id: Uuid::new_v4(),
+            document_id: Uuid::nil(),
+            author: None,
+            text: text.to_string(),
+            sent_at: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_publish_messages() {
+        let chats = ChatRooms::default();
+        let hello = message("hello");
+
+        assert_eq!(chats.publish(hello.clone()), Some(1));
+        // the same message mirrored back through SNS is ignored
+        assert_eq!(chats.publish(hello), None);
+        assert_eq!(chats.publish(message("world")), Some(2));
+
+        let (seq, messages) = chats.messages(Uuid::nil(), 1);
+        assert_eq!(seq, 2);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].message.text, "world");
+
+        for _ in 0..CHAT_HISTORY_LEN {
+            chats.publish(message("spam"));
+        }
+        assert_eq!(chats.messages(Uuid::nil(), 0).1.len(), CHAT_HISTORY_LEN);
+    }
+
+    #[test]
+    fn test_broadcast_is_not_an_operation() {
+        let broadcast = serde_json::to_string(&ChatBroadcast {
+            chat: message("hello"),
+            persist: false,
+        })
+        .unwrap();
+        assert!(serde_json::from_str::<crate::BroadcastOperation>(&broadcast).is_err());
+        assert!(
+            !serde_json::from_str::<ChatBroadcast>(&broadcast)
+                .unwrap()
+                .persist
+        );
+    }
+}
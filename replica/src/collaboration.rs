@@ -0,0 +1,457 @@
+//! Explicit collaboration sessions on a document.
+//!
+//! A client joins a loaded document with `POST /document/<id>/join` and receives a session
+//! token, the replica's site id and a sub id unique among the document's collaborators on the
+//! replica, along with the document's content, version vector and change feed version so it
+//! can start polling for changes. `POST /document/<id>/leave` ends the session and frees the
+//! sub id for reuse.
+//!
+//! Collaborators share their cursor and selection with `POST /document/<id>/selection`.
+//! Positions are anchored to the s4vector of a node rather than an index, so they stay
+//! attached to the same characters while others edit the document.
+//! `GET /document/<id>/selections` returns every collaborator's selection, optionally
+//! translated to current character indices.
+
+use crate::pool::Pool;
+use crate::changes::ChangeFeeds;
+use crate::limits::JsonBody;
+use crate::routes::SharedRGAs;
+use crate::share::Access;
+use crate::{expiry, Actor, ApiError, ContentNode, FieldError, RequestId, S4Vector, Session};
+use rocket::serde::json::Json;
+use rocket::{get, post};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// A position in a document anchored to a node.
+/// `node`: The node the position is in (None for the start of the document).
+/// `offset`: The number of characters into the node's value, clamped to its length.
+///
+/// A position in a deleted node collapses to where the node was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Anchor {
+    pub node: Option<S4Vector>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+impl Anchor {
+    /// The character index of the position, or None if its node has not been applied here.
+    /// `offsets`: The start index and visible length of each node, from `RGA::char_offsets`.
+    pub fn index(&self, offsets: &HashMap<S4Vector, (usize, usize)>) -> Option<usize> {
+        match self.node {
+            None => Some(0),
+            Some(node) => offsets
+                .get(&node)
+                .map(|(start, len)| start + self.offset.min(*len)),
+        }
+    }
+}
+
+/// A cursor (`anchor` and `head` equal) or a selection from `anchor` to `head`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Selection {
+    pub anchor: Anchor,
+    pub head: Anchor,
+}
+
+/// A client collaborating on a document.
+/// `user_id`: The user that joined (None if the request was anonymous).
+/// `sub_id`: The collaborator's id among the document's collaborators on this replica.
+/// `joined_at`: When the collaborator joined.
+/// `selection`: The collaborator's cursor or selection, once shared.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Collaborator {
+    pub user_id: Option<Uuid>,
+    pub sub_id: u32,
+    pub joined_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selection: Option<Selection>,
+}
+
+/// Request body for sharing a selection.
+/// `token`: The token returned when joining.
+/// `selection`: The collaborator's cursor or selection.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SelectionRequest {
+    pub token: Uuid,
+    pub selection: Selection,
+}
+
+/// A collaborator's selection.
+/// `sub_id`, `user_id`: The collaborator.
+/// `selection`: The selection, anchored to nodes.
+/// `anchor_index`, `head_index`: The current character indices of the selection's ends, when
+/// requested (None if a node has not been applied on this replica yet).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CollaboratorSelection {
+    pub sub_id: u32,
+    pub user_id: Option<Uuid>,
+    pub selection: Selection,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anchor_index: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head_index: Option<usize>,
+}
+
+/// Response body for joining a document.
+/// `document_id`: The document.
+/// `token`: The session token, sent to `leave` when the client is done.
+/// `site_id`: The site id (`sid`) of the replica, used in the s4vectors of the client's edits.
+/// `sub_id`: The client's id among the document's collaborators on this replica.
+/// `version`: The change feed version, passed as `since` to `GET /document/<id>/changes`.
+/// `version_vector`: The highest sequence number seen from each site.
+/// `nodes`: The visible nodes in document order.
+/// `collaborators`: Everyone collaborating on the document through this replica, including the client.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JoinResponse {
+    pub document_id: Uuid,
+    pub token: Uuid,
+    pub site_id: u64,
+    pub sub_id: u32,
+    pub version: u64,
+    pub version_vector: BTreeMap<u64, u64>,
+    pub nodes: Vec<ContentNode>,
+    pub collaborators: Vec<Collaborator>,
+}
+
+/// Request body for leaving a document.
+/// `token`: The token returned when joining.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeaveRequest {
+    pub token: Uuid,
+}
+
+/// The collaboration sessions of every document, managed in Rocket's state.
+#[derive(Clone, Default)]
+pub struct CollaborationSessions {
+    documents: Arc<Mutex<HashMap<Uuid, HashMap<Uuid, Collaborator>>>>,
+}
+
+impl CollaborationSessions {
+    /// Starts a session, allocating the lowest sub id not in use on the document.
+    /// Returns the session token and the collaborator.
+    pub fn join(&self, document_id: Uuid, user_id: Option<Uuid>) -> (Uuid, Collaborator) {
+        let mut documents = self.documents.lock().unwrap_or_else(|e| e.into_inner());
+        let sessions: &mut HashMap<Uuid, Collaborator> = documents.entry(document_id).or_default();
+
+        let sub_id: u32 = (0..)
+            .find(|sub_id| sessions.values().all(|c| c.sub_id != *sub_id))
+            .unwrap_or_default();
+        let collaborator = Collaborator {
+            user_id,
+            sub_id,
+            joined_at: expiry::timestamp(chrono::Utc::now()),
+            selection: None,
+        };
+
+        let token: Uuid = Uuid::new_v4();
+        sessions.insert(token, collaborator.clone());
+        (token, collaborator)
+    }
+
+    /// Ends a session, returning the collaborator it belonged to.
+    pub fn leave(&self, document_id: Uuid, token: &Uuid) -> Option<Collaborator> {
+        let mut documents = self.documents.lock().unwrap_or_else(|e| e.into_inner());
+        let sessions: &mut HashMap<Uuid, Collaborator> = documents.get_mut(&document_id)?;
+
+        let collaborator: Option<Collaborator> = sessions.remove(token);
+        if sessions.is_empty() {
+            documents.remove(&document_id);
+        }
+        collaborator
+    }
+
+    /// Records a collaborator's selection, returning the collaborator (None if the token is
+    /// unknown).
+    pub fn select(
+        &self,
+        document_id: Uuid,
+        token: &Uuid,
+        selection: Selection,
+    ) -> Option<Collaborator> {
+        let mut documents = self.documents.lock().unwrap_or_else(|e| e.into_inner());
+        let collaborator: &mut Collaborator = documents.get_mut(&document_id)?.get_mut(token)?;
+        collaborator.selection = Some(selection);
+        Some(collaborator.clone())
+    }
+
+    /// The document's collaborators ordered by sub id.
+    pub fn collaborators(&self, document_id: Uuid) -> Vec<Collaborator> {
+        let documents = self.documents.lock().unwrap_or_else(|e| e.into_inner());
+        let mut collaborators: Vec<Collaborator> = documents
+            .get(&document_id)
+            .map(|sessions| sessions.values().cloned().collect())
+            .unwrap_or_default();
+        collaborators.sort_by_key(|c| c.sub_id);
+        collaborators
+    }
+}
+
+fn parse_document_id(id: &str) -> Result<Uuid, ApiError> {
+    match Uuid::parse_str(id) {
+        Ok(id) => Ok(id),
+        Err(_) => {
+            error!("Failed to parse document id");
+            Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ))
+        }
+    }
+}
+
+/// Joins a loaded document, returning a session token, the client's ids and the document's
+/// current state.
+#[post("/document/<id>/join")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn join(
+    id: String,
+    actor: Actor,
+    pool: &rocket::State<Pool>,
+    rgas: &rocket::State<SharedRGAs>,
+    session: &rocket::State<Session>,
+    feeds: &rocket::State<ChangeFeeds>,
+    sessions: &rocket::State<CollaborationSessions>,
+    request_id: RequestId,
+) -> Result<Json<JoinResponse>, ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+    actor.authorize(pool, &document_id, Access::Read).await?;
+
+    let mut rgas = rgas.lock().await;
+    let rga = match rgas.get_mut(&document_id) {
+        Some(rga) => rga,
+        None => {
+            error!("Document not found");
+            return Err(ApiError::NotFound(String::from("Document not found")));
+        }
+    };
+    rga.touch();
+
+    // read under the RGA lock so the state and version match
+    let nodes: Vec<ContentNode> = rga
+        .read_nodes()
+        .into_iter()
+        .map(|(s4vector, value)| ContentNode { s4vector, value })
+        .collect();
+    let version_vector: BTreeMap<u64, u64> = rga.version_vector();
+    let version: u64 = feeds.version(document_id);
+
+    let (token, collaborator) = sessions.join(document_id, actor.user_id);
+    info!(sub_id = collaborator.sub_id, "Collaborator joined");
+
+    Ok(Json(JoinResponse {
+        document_id,
+        token,
+        site_id: session.replica_id,
+        sub_id: collaborator.sub_id,
+        version,
+        version_vector,
+        nodes,
+        collaborators: sessions.collaborators(document_id),
+    }))
+}
+
+/// Leaves a document, ending the session started by `join`.
+#[post("/document/<id>/leave", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn leave(
+    id: String,
+    request: JsonBody<LeaveRequest>,
+    actor: Actor,
+    pool: &rocket::State<Pool>,
+    sessions: &rocket::State<CollaborationSessions>,
+    request_id: RequestId,
+) -> Result<(), ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+    actor.authorize(pool, &document_id, Access::Read).await?;
+
+    match sessions.leave(document_id, &request.token) {
+        Some(collaborator) => {
+            info!(sub_id = collaborator.sub_id, "Collaborator left");
+            Ok(())
+        }
+        None => {
+            error!("Leave with an unknown session token");
+            Err(ApiError::NotFound(
+                "No session with this token on the document".to_string(),
+            ))
+        }
+    }
+}
+
+/// Shares the collaborator's cursor or selection with the document's other collaborators.
+/// Returns `422` if a position references a node that has not been applied on this replica.
+#[post("/document/<id>/selection", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn update_selection(
+    id: String,
+    request: JsonBody<SelectionRequest>,
+    actor: Actor,
+    pool: &rocket::State<Pool>,
+    rgas: &rocket::State<SharedRGAs>,
+    sessions: &rocket::State<CollaborationSessions>,
+    request_id: RequestId,
+) -> Result<(), ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+    actor.authorize(pool, &document_id, Access::Read).await?;
+    let selection: Selection = request.selection;
+
+    let offsets = {
+        let rgas = rgas.lock().await;
+        match rgas.get(&document_id) {
+            Some(rga) => rga.char_offsets(),
+            None => {
+                error!("Document not found");
+                return Err(ApiError::NotFound(String::from("Document not found")));
+            }
+        }
+    };
+
+    let mut errors: Vec<FieldError> = Vec::new();
+    for (field, anchor) in [
+        ("selection.anchor.node", selection.anchor),
+        ("selection.head.node", selection.head),
+    ] {
+        if anchor.index(&offsets).is_none() {
+            errors.push(FieldError::new(field, "is not a node of the document"));
+        }
+    }
+    if !errors.is_empty() {
+        error!("Rejected selection with {} invalid fields", errors.len());
+        return Err(ApiError::ValidationFailed(errors));
+    }
+
+    match sessions.select(document_id, &request.token, selection) {
+        Some(_) => Ok(()),
+        None => {
+            error!("Selection with an unknown session token");
+            Err(ApiError::NotFound(
+                "No session with this token on the document".to_string(),
+            ))
+        }
+    }
+}
+
+/// Returns the selections of the document's collaborators on this replica.
+/// `indices`: Also translate each selection to current character indices.
+#[get("/document/<id>/selections?<indices>")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn fetch_selections(
+    id: String,
+    indices: Option<bool>,
+    actor: Actor,
+    pool: &rocket::State<Pool>,
+    rgas: &rocket::State<SharedRGAs>,
+    sessions: &rocket::State<CollaborationSessions>,
+    request_id: RequestId,
+) -> Result<Json<Vec<CollaboratorSelection>>, ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+    actor.authorize(pool, &document_id, Access::Read).await?;
+
+    let offsets: Option<HashMap<S4Vector, (usize, usize)>> = {
+        let rgas = rgas.lock().await;
+        let rga = match rgas.get(&document_id) {
+            Some(rga) => rga,
+            None => {
+                error!("Document not found");
+                return Err(ApiError::NotFound(String::from("Document not found")));
+            }
+        };
+        match indices {
+            Some(true) => Some(rga.char_offsets()),
+            _ => None,
+        }
+    };
+
+    let selections: Vec<CollaboratorSelection> = sessions
+        .collaborators(document_id)
+        .into_iter()
+        .filter_map(|collaborator| {
+            let selection: Selection = collaborator.selection?;
+            let index = |anchor: Anchor| offsets.as_ref().and_then(|o| anchor.index(o));
+            Some(CollaboratorSelection {
+                sub_id: collaborator.sub_id,
+                user_id: collaborator.user_id,
+                anchor_index: index(selection.anchor),
+                head_index: index(selection.head),
+                selection,
+            })
+        })
+        .collect();
+
+    Ok(Json(selections))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_and_leave() {
+        let sessions = CollaborationSessions::default();
+        let document_id = Uuid::nil();
+
+        let (first, a) = sessions.join(document_id, None);
+        let (_, b) = sessions.join(document_id, None);
+        assert_eq!((a.sub_id, b.sub_id), (0, 1));
+        assert_eq!(sessions.join(Uuid::max(), None).1.sub_id, 0);
+
+        // the sub id is reused once its collaborator leaves
+        assert_eq!(sessions.leave(document_id, &first), Some(a));
+        assert_eq!(sessions.leave(document_id, &first), None);
+        assert_eq!(sessions.join(document_id, None).1.sub_id, 0);
+
+        let sub_ids: Vec<u32> = sessions
+            .collaborators(document_id)
+            .iter()
+            .map(|c| c.sub_id)
+            .collect();
+        assert_eq!(sub_ids, vec![0, 1]);
+    }
+
+    #[rocket::async_test]
+    async fn test_anchor_index() {
+        use crate::rga::rga::RGA;
+
+        let document_id = Uuid::nil();
+        let mut rga = RGA::new(1, 1);
+        let hello = rga
+            .local_insert("hello ".to_string(), None, None, document_id)
+            .unwrap()
+            .s4vector();
+        let world = rga
+            .local_insert("world".to_string(), Some(hello), None, document_id)
+            .unwrap()
+            .s4vector();
+
+        let at = |node: Option<S4Vector>, offset: usize| Anchor { node, offset };
+        let offsets = rga.char_offsets();
+        assert_eq!(at(None, 3).index(&offsets), Some(0));
+        assert_eq!(at(Some(world), 2).index(&offsets), Some(8));
+        assert_eq!(at(Some(world), 99).index(&offsets), Some(11));
+
+        // the cursor stays on "world" when text is inserted before it
+        rga.local_insert("big ".to_string(), Some(hello), None, document_id)
+            .unwrap();
+        let offsets = rga.char_offsets();
+        assert_eq!(at(Some(world), 2).index(&offsets), Some(12));
+
+        // and collapses to where "hello " was once it is deleted
+        rga.local_delete(hello, document_id).unwrap();
+        let offsets = rga.char_offsets();
+        assert_eq!(at(Some(hello), 4).index(&offsets), Some(0));
+        assert_eq!(at(Some(world), 0).index(&offsets), Some(4));
+
+        let unknown = S4Vector {
+            ssn: 9,
+            sum: 9,
+            sid: 9,
+            seq: 9,
+        };
+        assert_eq!(at(Some(unknown), 0).index(&offsets), None);
+    }
+}
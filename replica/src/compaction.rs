@@ -0,0 +1,302 @@
+//! Compaction of documents whose deletes have piled up. Deleted nodes stay in the RGA as
+//! tombstones so concurrent inserts can still be ordered against them, and every delete is
+//! logged after the document's checkpoint until the next snapshot. Instead of waiting for the
+//! autosave timer, documents are compacted as soon as their tombstone ratio or tombstone count
+//! crosses a threshold.
+
+use crate::flags::{Feature, Features};
+use crate::rga::rga::RGA;
+use crate::routes::SharedRGAs;
+use crate::tenancy::Tenant;
+use crate::{snapshot, tenancy, ApiError};
+use rocket::fairing::AdHoc;
+use rocket::tokio::{self, sync::Mutex};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_postgres::Client;
+use tracing::{error, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+/// Number of compactions requested by an administrator since the replica started.
+static ADMIN_COMPACTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of compactions triggered by tombstones since the replica started.
+static TOMBSTONE_COMPACTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Thresholds that trigger the compaction of a loaded document.
+/// `tombstone_ratio`: Share of a document's nodes that may be tombstones before it is compacted, 0 disables the trigger.
+/// `min_nodes`: Documents with fewer nodes are never compacted for their tombstone ratio.
+/// `max_tombstones`: Tombstones a document may hold before it is compacted whatever its ratio, 0 disables the trigger.
+/// `check_interval_secs`: Seconds between checks of the thresholds, 0 disables automatic compaction.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompactionConfig {
+    pub tombstone_ratio: f64,
+    pub min_nodes: usize,
+    pub max_tombstones: usize,
+    pub check_interval_secs: u64,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        CompactionConfig {
+            tombstone_ratio: 0.5,
+            min_nodes: 1000,
+            max_tombstones: 50_000,
+            check_interval_secs: 10,
+        }
+    }
+}
+
+impl CompactionConfig {
+    /// Whether a document with `nodes` nodes, `tombstones` of them deleted, crosses a threshold.
+    pub fn exceeded(&self, nodes: usize, tombstones: usize) -> bool {
+        if tombstones == 0 {
+            return false;
+        }
+        let by_ratio: bool = self.tombstone_ratio > 0.0
+            && nodes >= self.min_nodes
+            && tombstones as f64 / nodes as f64 >= self.tombstone_ratio;
+        let by_count: bool = self.max_tombstones > 0 && tombstones >= self.max_tombstones;
+        by_ratio || by_count
+    }
+
+    /// Whether a loaded document is due for compaction: it has changes that are not in its
+    /// snapshot, crosses a threshold and has gained tombstones since it was last compacted.
+    pub fn due(&self, rga: &RGA) -> bool {
+        rga.dirty
+            && rga.tombstones() > rga.compacted_tombstones
+            && self.exceeded(rga.len(), rga.tombstones())
+    }
+}
+
+/// What started a compaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Admin,
+    Tombstones,
+}
+
+/// Replaces a document's stored rows with a single snapshot and marks it clean.
+/// Returns the number of nodes written.
+pub async fn compact(
+    client: &mut Client,
+    document_id: &Uuid,
+    rga: &mut RGA,
+    trigger: Trigger,
+) -> Result<usize, ApiError> {
+    let nodes: usize = snapshot::persist_snapshot(client, document_id, rga).await?;
+    rga.dirty = false;
+    rga.compacted_tombstones = rga.tombstones();
+
+    let counter: &AtomicU64 = match trigger {
+        Trigger::Admin => &ADMIN_COMPACTIONS,
+        Trigger::Tombstones => &TOMBSTONE_COMPACTIONS,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    Ok(nodes)
+}
+
+/// Compacts every loaded document that is due. A failure for one document is logged and the
+/// remaining documents are still compacted.
+/// Returns the number of documents compacted.
+pub async fn compact_due(
+    client: &mut Client,
+    rgas: &mut HashMap<Uuid, RGA>,
+    config: &CompactionConfig,
+) -> usize {
+    let mut compacted: usize = 0;
+
+    for (document_id, rga) in rgas.iter_mut().filter(|(_, rga)| config.due(rga)) {
+        let tombstones: usize = rga.tombstones();
+        match compact(client, document_id, rga, Trigger::Tombstones).await {
+            Ok(nodes) => {
+                compacted += 1;
+                info!(document_id = %document_id, nodes, tombstones, "Compacted document");
+            }
+            Err(e) => {
+                error!(document_id = %document_id, "Failed to compact document: {}", e);
+            }
+        }
+    }
+
+    compacted
+}
+
+/// Renders the tombstone ratio of each loaded document and the compactions run since the
+/// replica started in the Prometheus text exposition format.
+pub fn to_prometheus(rgas: &HashMap<Uuid, RGA>) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "# HELP nimble_document_tombstone_ratio Share of a document's nodes that are tombstones."
+    );
+    let _ = writeln!(out, "# TYPE nimble_document_tombstone_ratio gauge");
+    for (document_id, rga) in rgas {
+        let _ = writeln!(
+            out,
+            "nimble_document_tombstone_ratio{{document_id=\"{}\"}} {}",
+            document_id,
+            rga.tombstone_ratio()
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP nimble_compactions_total Documents compacted, by what triggered the compaction."
+    );
+    let _ = writeln!(out, "# TYPE nimble_compactions_total counter");
+    let _ = writeln!(
+        out,
+        "nimble_compactions_total{{trigger=\"admin\"}} {}",
+        ADMIN_COMPACTIONS.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "nimble_compactions_total{{trigger=\"tombstones\"}} {}",
+        TOMBSTONE_COMPACTIONS.load(Ordering::Relaxed)
+    );
+
+    out
+}
+
+/// Fairing that starts a background task compacting the documents that are due every
+/// `check_interval_secs`. Like the admin compaction route, it only follows the deployment's
+/// `compaction` flag.
+pub fn attach_compaction(config: CompactionConfig) -> AdHoc {
+    AdHoc::on_liftoff("Tombstone compaction", move |rocket| {
+        Box::pin(async move {
+            if config.check_interval_secs == 0 {
+                info!("Automatic compaction is disabled");
+                return;
+            }
+
+            let (rgas, db, features) = match (
+                rocket.state::<SharedRGAs>(),
+                rocket.state::<Arc<Mutex<Client>>>(),
+                rocket.state::<Features>(),
+            ) {
+                (Some(rgas), Some(db), Some(features)) => {
+                    (Arc::clone(rgas), Arc::clone(db), features.clone())
+                }
+                _ => {
+                    warn!("Replica state is unavailable, automatic compaction is disabled");
+                    return;
+                }
+            };
+            let mut shutdown = rocket.shutdown();
+
+            tokio::spawn(async move {
+                let mut interval =
+                    tokio::time::interval(Duration::from_secs(config.check_interval_secs));
+                interval.tick().await;
+
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = &mut shutdown => break,
+                    }
+                    if !features.enabled(Feature::Compaction, &Tenant::Any) {
+                        continue;
+                    }
+
+                    async {
+                        // same lock order as the routes: documents, then the database
+                        let mut rgas = rgas.lock().await;
+                        if !rgas.values().any(|rga| config.due(rga)) {
+                            return;
+                        }
+                        let mut client = tenancy::lock_any(&db).await;
+                        compact_due(&mut client, &mut rgas, &config).await;
+                    }
+                    .instrument(info_span!("compaction.check"))
+                    .await;
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::s4vector::S4Vector;
+
+    #[test]
+    fn test_exceeded() {
+        let config = CompactionConfig {
+            tombstone_ratio: 0.5,
+            min_nodes: 10,
+            max_tombstones: 100,
+            check_interval_secs: 10,
+        };
+
+        assert!(!config.exceeded(0, 0));
+        assert!(!config.exceeded(20, 9));
+        assert!(config.exceeded(20, 10));
+        // too small for the ratio, but not for the count
+        assert!(!config.exceeded(8, 8));
+        assert!(config.exceeded(1000, 100));
+
+        let ratio_only = CompactionConfig {
+            max_tombstones: 0,
+            ..config
+        };
+        assert!(!ratio_only.exceeded(1000, 100));
+        let disabled = CompactionConfig {
+            tombstone_ratio: 0.0,
+            ..ratio_only
+        };
+        assert!(!disabled.exceeded(20, 20));
+    }
+
+    #[test]
+    fn test_due() {
+        let config = CompactionConfig {
+            tombstone_ratio: 0.5,
+            min_nodes: 2,
+            max_tombstones: 0,
+            check_interval_secs: 10,
+        };
+        let document_id = Uuid::new_v4();
+        let mut rga = RGA::new(1, 1);
+        let mut inserted: Vec<S4Vector> = Vec::new();
+        for value in ["a", "b", "c", "d"] {
+            let operation = rga
+                .local_insert(
+                    value.to_string(),
+                    inserted.last().copied(),
+                    None,
+                    document_id,
+                )
+                .unwrap();
+            inserted.push(
+                S4Vector::from_i64(operation.ssn, operation.sum, operation.sid, operation.seq)
+                    .unwrap(),
+            );
+        }
+
+        rga.local_delete(inserted[0], document_id).unwrap();
+        assert_eq!((rga.live(), rga.tombstones()), (3, 1));
+        assert!(!config.due(&rga));
+
+        rga.local_delete(inserted[1], document_id).unwrap();
+        // deleting a node twice does not count it again
+        rga.local_delete(inserted[1], document_id).unwrap();
+        assert_eq!((rga.live(), rga.tombstones()), (2, 2));
+        assert_eq!(rga.tombstone_ratio(), 0.5);
+        assert!(config.due(&rga));
+
+        // compacted documents are due again once they gain tombstones
+        rga.dirty = false;
+        rga.compacted_tombstones = rga.tombstones();
+        assert!(!config.due(&rga));
+        rga.local_delete(inserted[2], document_id).unwrap();
+        assert!(config.due(&rga));
+    }
+}
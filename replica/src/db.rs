@@ -1,20 +1,30 @@
-use crate::{ApiError, BroadcastOperation};
+use crate::changes::ChangeFeeds;
+use crate::outbox::{self, Outbox};
+use crate::pool::Pool;
+use crate::query_metrics::QueryTimer;
+use crate::rga::rga::RGA;
+use crate::routes::{self, SharedRGAs};
+use crate::tenancy::Tenant;
+use crate::usage::{self, Metric};
+use crate::wal::{Wal, WalRecord};
+use crate::{audit, encryption, history, region, sqs, starred, Actor, ApiError, AppliedOperation, BroadcastOperation, DocumentBackup, RequestId};
 use aws_sdk_sns::Client as SnsClient;
-use log::{error, info};
 use rocket::fairing::AdHoc;
 use rocket::tokio;
-use rocket::tokio::sync::Mutex;
-use std::io::{Error, ErrorKind};
+use rocket::tokio::sync::{Mutex, OwnedMutexGuard};
+use std::io::Error;
 use std::sync::Arc;
 use tokio_postgres::{Client, NoTls};
+use uuid::Uuid;
+use tracing::{error, info, instrument};
 
 /// Fairing for managing the PostgreSQL client in rocket's state
-pub fn attatch_db() -> AdHoc {
-    AdHoc::on_ignite("Attatch DB", |rocket| async {
-        match connect_to_db().await {
+pub fn attatch_db(database_url: String) -> AdHoc {
+    AdHoc::on_ignite("Attatch DB", |rocket| async move {
+        match connect_to_db(&database_url).await {
             Ok(client) => rocket.manage(Arc::new(Mutex::new(client))),
             Err(e) => {
-                error!(target: "error_logger","Unable to start server, failed to initialize database: {}",e);
+                error!("Unable to start server, failed to initialize database: {}",e);
                 eprintln!("Failed to initialize DB: {:?}", e);
                 std::process::exit(1);
             }
@@ -22,34 +32,26 @@ pub fn attatch_db() -> AdHoc {
     })
 }
 
-/// Connects to the AWS RDS instance using the database connection url set in the .env file under
-/// DB_URL
-pub async fn connect_to_db() -> Result<Client, ApiError> {
-    let database_url = match std::env::var("DB_URL") {
-        Ok(url) => url,
-        Err(_) => {
-            error!(target: "error_logger","DB_URL not set in the .env file");
-            std::process::exit(1);
-        }
-    };
-
-    let (client, connection) = tokio_postgres::connect(&database_url, NoTls)
+/// Connects to the AWS RDS instance using the configured database connection url
+pub async fn connect_to_db(database_url: &str) -> Result<Client, ApiError> {
+    let (client, connection) = tokio_postgres::connect(database_url, NoTls)
         .await
         .map_err(|e| {
-            error!(target:"error_logger","Failed to establish database connection.");
+            error!("Failed to establish database connection.");
             ApiError::DatabaseError(e.to_string())
         })?;
 
     tokio::spawn(async move {
         if connection.await.is_err() {
-            error!(target:"error_logger","Failed to keep PostgreSQL connection")
+            error!("Failed to keep PostgreSQL connection")
         }
     });
-    info!(target:"request_logger","Successfully established a connection to the database");
+    info!("Successfully established a connection to the database");
     Ok(client)
 }
 
 /// Sends a SNS message
+#[instrument(name = "sns.publish", skip(message))]
 pub async fn send_sns_notification(
     message: &str,
     sns_topic: &str,
@@ -65,41 +67,295 @@ pub async fn send_sns_notification(
         .await
     {
         Ok(_) => {
-            info!(target: "request_logger","SNS notification sent to other replicas");
+            info!("SNS notification sent to other replicas");
             Ok(())
         }
         Err(e) => {
-            error!(target:"error_logger","Failed to send SNS notification to other replicas");
+            error!("Failed to send SNS notification to other replicas");
             Err(Box::new(e))
         }
     }
 }
 
 /// Send operation SNS notification to other replicas
+#[instrument(name = "sns.publish", skip_all, fields(operation = %operation.operation, document_id = %operation.document_id))]
 pub async fn send_operation(
     sns_client: Arc<Mutex<SnsClient>>,
     topic_arn: &str,
     operation: &BroadcastOperation,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let message = match serde_json::to_string(operation) {
+    let mut operation = operation.clone();
+    operation.origin_region = region::local_region();
+    operation.sent_at = Some(chrono::Utc::now().to_rfc3339());
+    let message = match serde_json::to_string(&operation) {
         Ok(m) => m,
         Err(_) => {
-            return Err(Box::new(Error::new(
-                ErrorKind::Other,
-                "Failed to serialize operation",
-            )))
+            return Err(Box::new(Error::other("Failed to serialize operation")))
+        }
+    };
+
+    let publish = sns_client.lock().await.publish().topic_arn(topic_arn).message(message);
+    sqs::tag_origin(publish).send().await?;
+
+    info!("SNS {} operation sent to other replicas",operation.operation);
+    Ok(())
+}
+
+/// Replaces a document's metadata and snapshot with a backup, recreating the document if it
+/// no longer exists and clearing its archived and trashed state and its checkpoint. The
+/// operations log is left unchanged.
+/// The document's RGA must be reloaded afterwards.
+/// Returns the number of nodes restored.
+#[instrument(name = "db.restore_document", skip_all, fields(document_id = %backup.document_id))]
+pub async fn restore_document(client: &mut Client, backup: &DocumentBackup) -> Result<usize, ApiError> {
+    let _timer = QueryTimer::start("restore_document").param("document_id", backup.document_id);
+    let tx = match client.transaction().await {
+        Ok(tx) => tx,
+        Err(_) => {
+            error!("Failed to start database transaction");
+            return Err(ApiError::DatabaseError("Failed to start database transaction".to_string()));
         }
     };
 
-    sns_client
-        .lock()
+    if tx
+        .execute(
+            "INSERT INTO document (document_id,owner_id,creation_date,title,expires_at,language,workspace_id) VALUES ($1,$2,$3,$4,$5,$6,$7) \
+             ON CONFLICT (document_id) DO UPDATE SET owner_id=EXCLUDED.owner_id, creation_date=EXCLUDED.creation_date, \
+             title=EXCLUDED.title, expires_at=EXCLUDED.expires_at, language=EXCLUDED.language, \
+             workspace_id=EXCLUDED.workspace_id, archived_at=NULL, trashed_at=NULL",
+            &[&backup.document_id, &backup.owner_id, &backup.creation_date, &backup.title, &backup.expires_at, &backup.language, &backup.workspace_id],
+        )
         .await
-        .publish()
-        .topic_arn(topic_arn)
-        .message(message)
-        .send()
-        .await?;
+        .is_err()
+    {
+        error!("Failed to restore the document table row");
+        return Err(ApiError::DatabaseError("Failed to restore the document table row".to_string()));
+    }
+
+    // the checkpoint is newer than the backup, the document is loaded from the restored rows
+    if tx
+        .execute("DELETE FROM document_checkpoints WHERE document_id=$1", &[&backup.document_id])
+        .await
+        .is_err()
+    {
+        error!("Failed to clear the document_checkpoints table");
+        return Err(ApiError::DatabaseError("Failed to clear the document_checkpoints table".to_string()));
+    }
+
+    if tx
+        .execute("DELETE FROM document_snapshots WHERE document_id=$1", &[&backup.document_id])
+        .await
+        .is_err()
+    {
+        error!("Failed to clear the document_snapshots table");
+        return Err(ApiError::DatabaseError("Failed to clear the document_snapshots table".to_string()));
+    }
+
+    let insert = match tx.prepare("INSERT INTO document_snapshots (document_id,ssn,sum,sid,seq,value,tombstone,author,authored_at) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9)").await {
+        Ok(q) => q,
+        Err(_) => {
+            error!("Failed to create insert query for document_snapshot table");
+            return Err(ApiError::DatabaseError("Failed to create insert query for document_snapshot table".to_string()));
+        }
+    };
+
+    for node in &backup.nodes {
+        if tx
+            .execute(
+                &insert,
+                &[&backup.document_id, &node.ssn, &node.sum, &node.sid, &node.seq, &encryption::seal(&node.value), &node.tombstone, &node.author, &node.authored_at],
+            )
+            .await
+            .is_err()
+        {
+            error!("Failed to insert into document_snapshot table");
+            return Err(ApiError::DatabaseError("Failed to insert into document_snapshot table".to_string()));
+        }
+    }
+
+    if tx.commit().await.is_err() {
+        error!("Failed to commit database transaction");
+        return Err(ApiError::DatabaseError("Failed to commit database transaction".to_string()));
+    }
+
+    info!("Restored {} nodes from the backup taken at {}", backup.nodes.len(), backup.backed_up_at);
+    Ok(backup.nodes.len())
+}
+
+/// Disaster recovery: restores every backup, each in its own transaction so one failure does
+/// not prevent the others from being restored.
+/// Returns the restored documents.
+#[instrument(name = "db.restore_documents", skip_all, fields(backups = backups.len()))]
+pub async fn restore_documents(client: &mut Client, backups: &[DocumentBackup]) -> Vec<Uuid> {
+    let mut restored: Vec<Uuid> = Vec::with_capacity(backups.len());
+    for backup in backups {
+        match restore_document(client, backup).await {
+            Ok(_) => restored.push(backup.document_id),
+            Err(e) => error!(document_id = %backup.document_id, "Failed to restore document: {}", e),
+        }
+    }
+    info!("Restored {} of {} documents", restored.len(), backups.len());
+    restored
+}
+
+/// Logs operations applied to a document's RGA by the replica and writes the nodes they
+/// changed to the snapshot, with an audit entry and an outbox entry for each, in one transaction.
+/// The nodes are read from the RGA, so the operations must already have been applied.
+#[instrument(name = "db.record_operations", skip_all, fields(operations = operations.len()))]
+pub async fn record_operations(client: &mut Client, rga: &RGA, actor: &Actor, operations: &[BroadcastOperation], timestamp: &str) -> Result<(), ApiError> {
+    let mut timer = QueryTimer::start("record_operations").param("operations", operations.len());
+    if let Some(operation) = operations.first() {
+        timer.set("document_id", operation.document_id);
+    }
+    let tx = match client.transaction().await {
+        Ok(tx) => tx,
+        Err(_) => {
+            error!("Failed to create database transaction");
+            return Err(ApiError::DatabaseError("Failed to create database transaction".to_string()));
+        }
+    };
+
+    for operation in operations {
+        let node = match rga.node(&operation.s4vector()) {
+            Some(node) => node.clone(),
+            None => {
+                error!("Recorded operation's node is not in the RGA");
+                return Err(ApiError::InternalServerError("Failed to find the operation's node".to_string()));
+            }
+        };
+        let [ssn, sum, sid, seq] = node.s4vector.to_i64()?;
+        let value: String = encryption::seal(&node.value);
+
+        if tx
+            .execute(
+                "INSERT INTO operations (document_id,ssn,sum,sid,seq,value,tombstone,timestamp,operation,left_origin,right_origin) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11)",
+                &[&operation.document_id, &ssn, &sum, &sid, &seq, &value, &node.tombstone, &timestamp, &operation.operation, &history::origin_column(operation.left)?, &history::origin_column(operation.right)?],
+            )
+            .await
+            .is_err()
+        {
+            error!("Failed to insert into operations table");
+            return Err(ApiError::DatabaseError("Failed to insert into operations table".to_string()));
+        }
 
-    info!(target: "request_logger","SNS {} operation sent to other replicas",operation.operation);
+        if tx
+            .execute(
+                "INSERT INTO document_snapshots (document_id,ssn,sum,sid,seq,value,tombstone,author,authored_at) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9) \
+                 ON CONFLICT (document_id,ssn,sum,sid,seq) DO UPDATE SET value = EXCLUDED.value, tombstone = EXCLUDED.tombstone, author = EXCLUDED.author, authored_at = EXCLUDED.authored_at",
+                &[&operation.document_id, &ssn, &sum, &sid, &seq, &value, &node.tombstone, &operation.author, &timestamp],
+            )
+            .await
+            .is_err()
+        {
+            error!("Failed to insert into document_snapshot table");
+            return Err(ApiError::DatabaseError("Failed to insert into document_snapshot table".to_string()));
+        }
+
+        audit::record(&tx, actor, operation, timestamp).await?;
+        outbox::enqueue(&tx, operation).await?;
+    }
+    if let (Some(user_id), Some(operation)) = (actor.user_id, operations.first()) {
+        starred::touch(&tx, &user_id, &operation.document_id).await?;
+    }
+
+    if tx.commit().await.is_err() {
+        error!("Failed to commit database transaction");
+        return Err(ApiError::DatabaseError("Failed to commit database transaction".to_string()));
+    }
     Ok(())
 }
+
+/// Logs a move the replica applied to a document's RGA, with the nodes it moved, and adds it
+/// to the audit log and the outbox in one transaction. The nodes' rows in the snapshot don't
+/// change, the next checkpoint of the document keeps their new order.
+#[instrument(name = "db.record_move", skip_all)]
+pub async fn record_move(client: &mut Client, actor: &Actor, operation: &BroadcastOperation, timestamp: &str) -> Result<(), ApiError> {
+    let tx = match client.transaction().await {
+        Ok(tx) => tx,
+        Err(_) => {
+            error!("Failed to create database transaction");
+            return Err(ApiError::DatabaseError("Failed to create database transaction".to_string()));
+        }
+    };
+    if tx
+        .execute(
+            "INSERT INTO operations (document_id,ssn,sum,sid,seq,value,tombstone,timestamp,operation,left_origin,right_origin,nodes) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12)",
+            &[&operation.document_id, &operation.ssn, &operation.sum, &operation.sid, &operation.seq, &encryption::seal(""), &false, &timestamp, &operation.operation, &history::origin_column(operation.left)?, &history::origin_column(operation.right)?, &history::nodes_column(&operation.nodes)?],
+        )
+        .await
+        .is_err()
+    {
+        error!("Failed to insert into operations table");
+        return Err(ApiError::DatabaseError("Failed to insert into operations table".to_string()));
+    }
+    audit::record(&tx, actor, operation, timestamp).await?;
+    outbox::enqueue(&tx, operation).await?;
+    if let Some(user_id) = actor.user_id {
+        starred::touch(&tx, &user_id, &operation.document_id).await?;
+    }
+    if tx.commit().await.is_err() {
+        error!("Failed to commit database transaction");
+        return Err(ApiError::DatabaseError("Failed to commit database transaction".to_string()));
+    }
+    Ok(())
+}
+
+/// Commits operations a request prepared on a copy of a document's RGA, holding the
+/// document's `edit` lock. They are appended to the write-ahead log together, then applied to
+/// the loaded document and the lock released, so a request that fails part way through leaves
+/// the document unchanged. They are then persisted in one transaction on a pooled connection
+/// and published to the document's change feed; the outbox broadcasts them to other replicas.
+/// Returns the operations as they were published.
+#[allow(clippy::too_many_arguments)]
+pub async fn commit_operations(pool: &Pool, rgas: &SharedRGAs, wal: &Wal, edit: OwnedMutexGuard<()>, prepared: &RGA, actor: &Actor, request_id: &RequestId, document_id: Uuid, tenant: &Tenant, mut operations: Vec<BroadcastOperation>, outbox: &Outbox, feeds: &ChangeFeeds) -> Result<Vec<AppliedOperation>, ApiError> {
+    let timestamp: String = chrono::Utc::now().to_rfc3339();
+    let mut records: Vec<WalRecord> = Vec::with_capacity(operations.len());
+    for operation in operations.iter_mut() {
+        operation.document_id = document_id;
+        operation.request_id = Some(request_id.0.clone());
+        operation.author = actor.user_id;
+        let deleted: bool = operation.operation == "Delete";
+        records.push(WalRecord {
+            operation: operation.clone(),
+            value: encryption::seal(operation.value.as_deref().unwrap_or_default()),
+            tombstone: deleted,
+            authored_at: (!deleted).then(|| timestamp.clone()),
+            client_ip: actor.client_ip.clone(),
+            timestamp: timestamp.clone(),
+        });
+    }
+    // Logged before the database, so the operations are kept if the database fails
+    let lsns: Vec<Option<u64>> = wal.append_all(&records).await?;
+
+    if let Some(rga) = rgas.lock().await.get_mut(&document_id) {
+        // the prepared inserts used the copy's sequence, the document's next insert follows it
+        rga.local_sequence = rga.local_sequence.max(prepared.local_sequence);
+    }
+    routes::apply_logged(rgas, &document_id, &operations).await;
+    drop(edit);
+
+    let persisted: Result<(), ApiError> = async {
+        let mut client = pool.get(tenant).await?;
+        record_operations(&mut client, prepared, actor, &operations, &timestamp).await
+    }
+    .await;
+    for lsn in lsns {
+        wal.settle(lsn, persisted.clone()).await?;
+    }
+    outbox.wake();
+    usage::record(tenant, document_id, Metric::Operations, operations.len() as f64);
+    usage::record(tenant, document_id, Metric::BroadcastMessages, operations.len() as f64);
+
+    if let Some(rga) = rgas.lock().await.get_mut(&document_id) {
+        for operation in operations.iter().filter(|operation| operation.operation != "Delete") {
+            rga.set_author(operation.s4vector(), operation.author, Some(timestamp.clone()));
+        }
+    }
+    let mut applied: Vec<AppliedOperation> = Vec::with_capacity(operations.len());
+    for operation in operations {
+        let operation = AppliedOperation::new(operation, timestamp.clone());
+        feeds.publish(document_id, operation.clone());
+        applied.push(operation);
+    }
+    Ok(applied)
+}
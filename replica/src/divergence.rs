@@ -0,0 +1,469 @@
+//! Detection of documents whose state differs between replicas.
+//!
+//! Every replica serves a digest of each loaded document on
+//! `GET /internal/document/<id>/digest`, a hash over its visible nodes in document order along
+//! with its version vector. Every `interval_secs` the replica fetches the digests of its loaded
+//! documents from the members gossip reports alive. Two replicas that have seen the same
+//! operations (equal version vectors, nothing buffered) must have the same digest, so a
+//! mismatch means the documents have diverged. It has to be seen for `confirmations`
+//! consecutive rounds before it is acted on, then the divergence is logged, counted, and the
+//! local copy is reloaded from the database, which every replica writes its operations to.
+
+use crate::admin::parse_document_id;
+use crate::gossip::{require_token, GossipConfig, MemberState, Membership};
+use crate::reporting::{self, ErrorEvent, Level, Source};
+use crate::rga::rga::RGA;
+use crate::routes::SharedRGAs;
+use crate::{snapshot, tenancy};
+use crate::{AdminToken, ApiError, RequestId, Session};
+use rocket::fairing::AdHoc;
+use rocket::get;
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use rocket::tokio::{self, time};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_postgres::Client;
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument};
+use uuid::Uuid;
+
+/// `interval_secs`: Seconds between comparisons with the other replicas, 0 disables them.
+/// `confirmations`: Consecutive rounds a mismatch must be seen in before the document is resynced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DivergenceConfig {
+    pub interval_secs: u64,
+    pub confirmations: u32,
+}
+
+impl Default for DivergenceConfig {
+    fn default() -> Self {
+        DivergenceConfig {
+            interval_secs: 60,
+            confirmations: 2,
+        }
+    }
+}
+
+/// The state of a document on one replica.
+/// `document_id`: The document.
+/// `digest`: The hex encoded SHA-256 digest of the visible nodes, see `RGA::digest`.
+/// `sites`: The highest sequence number applied from each site id.
+/// `buffered`: Operations waiting on missing dependencies.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentDigest {
+    pub document_id: Uuid,
+    pub digest: String,
+    pub sites: BTreeMap<u64, u64>,
+    pub buffered: usize,
+}
+
+impl DocumentDigest {
+    pub async fn of(document_id: Uuid, rga: &RGA) -> Self {
+        DocumentDigest {
+            document_id,
+            digest: rga.digest(),
+            sites: rga.version_vector(),
+            buffered: rga.buffered(),
+        }
+    }
+
+    /// Whether both replicas have applied the same operations, so their digests must match.
+    fn comparable(&self, other: &DocumentDigest) -> bool {
+        self.buffered == 0 && other.buffered == 0 && self.sites == other.sites
+    }
+}
+
+/// The result of comparing a document with another replica's copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    /// Same operations, same state.
+    Converged,
+    /// Operations are still being delivered, the states can't be compared yet.
+    InFlight,
+    /// The states differ, but not yet for enough rounds.
+    Mismatch,
+    /// The states have differed for `confirmations` rounds.
+    Diverged,
+}
+
+/// Tracks the mismatches seen between this replica's documents and other replicas.
+/// `mismatches`: Consecutive rounds a mismatch was seen in, by document and replica id.
+/// `detected`: Divergences confirmed.
+/// `resynced`: Documents reloaded after a divergence.
+#[derive(Debug, Clone, Default)]
+pub struct Divergence {
+    mismatches: Arc<std::sync::Mutex<HashMap<(Uuid, i64), u32>>>,
+    detected: Arc<AtomicU64>,
+    resynced: Arc<AtomicU64>,
+}
+
+impl Divergence {
+    /// Compares a local digest with a replica's digest of the same document.
+    pub fn compare(
+        &self,
+        replica_id: i64,
+        local: &DocumentDigest,
+        remote: &DocumentDigest,
+        confirmations: u32,
+    ) -> Comparison {
+        let key = (local.document_id, replica_id);
+        let mut mismatches = self.mismatches.lock().unwrap_or_else(|e| e.into_inner());
+
+        if !local.comparable(remote) {
+            // a mismatch has to be seen in consecutive comparable rounds
+            mismatches.remove(&key);
+            return Comparison::InFlight;
+        }
+        if local.digest == remote.digest {
+            mismatches.remove(&key);
+            return Comparison::Converged;
+        }
+
+        let rounds = mismatches.entry(key).or_insert(0);
+        *rounds += 1;
+        if *rounds < confirmations.max(1) {
+            return Comparison::Mismatch;
+        }
+        mismatches.remove(&key);
+        self.detected.fetch_add(1, Ordering::Relaxed);
+        Comparison::Diverged
+    }
+
+    /// Forgets the mismatches of documents that are no longer loaded.
+    pub fn retain(&self, documents: &HashSet<Uuid>) {
+        self.mismatches
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|(document_id, _), _| documents.contains(document_id));
+    }
+
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP nimble_divergences_total Documents found to differ from another replica's copy."
+        );
+        let _ = writeln!(out, "# TYPE nimble_divergences_total counter");
+        let _ = writeln!(
+            out,
+            "nimble_divergences_total {}",
+            self.detected.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP nimble_divergence_resyncs_total Documents reloaded from the database after diverging."
+        );
+        let _ = writeln!(out, "# TYPE nimble_divergence_resyncs_total counter");
+        let _ = writeln!(
+            out,
+            "nimble_divergence_resyncs_total {}",
+            self.resynced.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+/// Returns the digest of a loaded document, for other replicas to compare with theirs.
+///
+/// Example Response
+/// {
+///     "document_id" : "3f1b8a52-6f0e-4c7a-9a59-2d8c1f0b7e41",
+///     "digest" : "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+///     "sites" : { "1" : 42, "2" : 17 },
+///     "buffered" : 0
+/// }
+#[get("/internal/document/<id>/digest")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn fetch_digest(
+    id: String,
+    token: AdminToken,
+    config: &rocket::State<GossipConfig>,
+    rgas: &rocket::State<SharedRGAs>,
+    request_id: RequestId,
+) -> Result<Json<DocumentDigest>, ApiError> {
+    require_token(&token, config)?;
+
+    let document_id: Uuid = parse_document_id(&id)?;
+
+    let rgas = rgas.lock().await;
+    match rgas.get(&document_id) {
+        Some(rga) => Ok(Json(DocumentDigest::of(document_id, rga).await)),
+        // expected while other replicas compare documents this replica has not loaded
+        None => Err(ApiError::NotFound(format!(
+            "Document {} is not loaded",
+            document_id
+        ))),
+    }
+}
+
+async fn fetch_remote_digest(
+    http: &reqwest::Client,
+    address: &str,
+    document_id: &Uuid,
+    config: &GossipConfig,
+) -> Option<DocumentDigest> {
+    let mut request = http.get(format!(
+        "{}/internal/document/{}/digest",
+        address.trim_end_matches('/'),
+        document_id
+    ));
+    if let Some(token) = &config.token {
+        request = request.bearer_auth(token);
+    }
+
+    let response = match request.send().await {
+        Ok(response) if response.status().is_success() => response,
+        // the document is not loaded there, or the replica is unreachable
+        Ok(_) => return None,
+        Err(e) => {
+            debug!(address, "Failed to fetch a document digest: {}", e);
+            return None;
+        }
+    };
+    let bytes = response.bytes().await.ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// Replaces the loaded copy of a document with the state persisted in the database.
+pub async fn resync(
+    rgas: &SharedRGAs,
+    db: &Arc<Mutex<Client>>,
+    session: &Session,
+    document_id: &Uuid,
+) -> Result<(), ApiError> {
+    let mut rgas = rgas.lock().await;
+    if !rgas.contains_key(document_id) {
+        return Ok(());
+    }
+    let client = tenancy::lock_any(db).await;
+    let rga: RGA = snapshot::load_rga(&client, document_id, session).await?;
+    rgas.insert(*document_id, rga);
+    Ok(())
+}
+
+struct Comparer {
+    config: DivergenceConfig,
+    gossip: GossipConfig,
+    http: reqwest::Client,
+    rgas: SharedRGAs,
+    db: Arc<Mutex<Client>>,
+    session: Session,
+    membership: Membership,
+    divergence: Divergence,
+}
+
+impl Comparer {
+    async fn round(&self) {
+        let peers: Vec<(i64, String)> = self
+            .membership
+            .view(Instant::now())
+            .into_iter()
+            .skip(1)
+            .filter(|member| member.state == MemberState::Alive)
+            .map(|member| (member.member.replica_id, member.member.address))
+            .collect();
+
+        let mut local: Vec<DocumentDigest> = Vec::new();
+        {
+            let rgas = self.rgas.lock().await;
+            self.divergence.retain(&rgas.keys().copied().collect());
+            for (document_id, rga) in rgas.iter() {
+                if rga.buffered() == 0 {
+                    local.push(DocumentDigest::of(*document_id, rga).await);
+                }
+            }
+        }
+        if peers.is_empty() {
+            return;
+        }
+
+        for digest in local {
+            let remote = peers.iter().map(|(replica_id, address)| async move {
+                (
+                    *replica_id,
+                    fetch_remote_digest(&self.http, address, &digest.document_id, &self.gossip)
+                        .await,
+                )
+            });
+            let mut diverged: Vec<i64> = Vec::new();
+            for (replica_id, remote) in rocket::futures::future::join_all(remote).await {
+                let Some(remote) = remote else { continue };
+                let comparison = self.divergence.compare(
+                    replica_id,
+                    &digest,
+                    &remote,
+                    self.config.confirmations,
+                );
+                if comparison == Comparison::Diverged {
+                    diverged.push(replica_id);
+                }
+            }
+            if diverged.is_empty() {
+                continue;
+            }
+
+            warn!(
+                document_id = %digest.document_id,
+                replicas = ?diverged,
+                "Document diverged from other replicas, reloading it from the database"
+            );
+            let alarm = ErrorEvent::new(
+                Level::Warning,
+                Source::Divergence,
+                "divergence::diverged",
+                "Document diverged from other replicas".to_string(),
+            )
+            .with_document(Some(digest.document_id))
+            .with_extra("replicas", format!("{:?}", diverged));
+            match resync(&self.rgas, &self.db, &self.session, &digest.document_id).await {
+                Ok(()) => {
+                    self.divergence.resynced.fetch_add(1, Ordering::Relaxed);
+                    reporting::report(alarm);
+                }
+                Err(e) => {
+                    error!(document_id = %digest.document_id, "Failed to resync the document: {}", e);
+                    let mut alarm = alarm.with_extra("resync_error", e);
+                    alarm.level = Level::Error;
+                    reporting::report(alarm);
+                }
+            }
+        }
+    }
+}
+
+/// Fairing that starts comparing documents with the other replicas every `interval_secs`.
+/// Requires gossip, since the replicas compared with are its alive members.
+/// The task stops when Rocket begins shutting down.
+pub fn attach_divergence(config: DivergenceConfig) -> AdHoc {
+    AdHoc::on_liftoff("Divergence detection", move |rocket| {
+        Box::pin(async move {
+            let gossip: GossipConfig = match rocket.state::<GossipConfig>() {
+                Some(gossip) if gossip.address.is_some() && config.interval_secs > 0 => {
+                    gossip.clone()
+                }
+                _ => {
+                    info!("Divergence detection is disabled");
+                    return;
+                }
+            };
+            let (rgas, db, session, membership, divergence) = match (
+                rocket.state::<SharedRGAs>(),
+                rocket.state::<Arc<Mutex<Client>>>(),
+                rocket.state::<Session>(),
+                rocket.state::<Membership>(),
+                rocket.state::<Divergence>(),
+            ) {
+                (Some(rgas), Some(db), Some(session), Some(membership), Some(divergence)) => (
+                    Arc::clone(rgas),
+                    Arc::clone(db),
+                    *session,
+                    membership.clone(),
+                    divergence.clone(),
+                ),
+                _ => {
+                    warn!("Replica state is unavailable, divergence detection is disabled");
+                    return;
+                }
+            };
+            let mut shutdown = rocket.shutdown();
+            let http = reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap_or_default();
+            let comparer = Comparer {
+                config,
+                gossip,
+                http,
+                rgas,
+                db,
+                session,
+                membership,
+                divergence,
+            };
+
+            tokio::spawn(async move {
+                let mut interval = time::interval(Duration::from_secs(config.interval_secs));
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = &mut shutdown => break,
+                    }
+                    comparer
+                        .round()
+                        .instrument(info_span!("divergence.round"))
+                        .await;
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(digest: &str, sites: &[(u64, u64)], buffered: usize) -> DocumentDigest {
+        DocumentDigest {
+            document_id: Uuid::nil(),
+            digest: digest.to_string(),
+            sites: sites.iter().copied().collect(),
+            buffered,
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let divergence = Divergence::default();
+        let local = digest("a", &[(1, 4), (2, 3)], 0);
+
+        assert_eq!(
+            divergence.compare(2, &local, &digest("a", &[(1, 4), (2, 3)], 0), 2),
+            Comparison::Converged
+        );
+        // the other replica has not seen every operation yet
+        assert_eq!(
+            divergence.compare(2, &local, &digest("b", &[(1, 4), (2, 4)], 0), 2),
+            Comparison::InFlight
+        );
+        assert_eq!(
+            divergence.compare(2, &local, &digest("b", &[(1, 4), (2, 3)], 1), 2),
+            Comparison::InFlight
+        );
+
+        let diverged = digest("b", &[(1, 4), (2, 3)], 0);
+        assert_eq!(
+            divergence.compare(2, &local, &diverged, 2),
+            Comparison::Mismatch
+        );
+        // mismatches are counted per replica
+        assert_eq!(
+            divergence.compare(3, &local, &diverged, 2),
+            Comparison::Mismatch
+        );
+        assert_eq!(
+            divergence.compare(2, &local, &diverged, 2),
+            Comparison::Diverged
+        );
+
+        // a round in flight resets the count
+        divergence.compare(3, &local, &digest("b", &[(1, 5), (2, 3)], 0), 2);
+        assert_eq!(
+            divergence.compare(3, &local, &diverged, 2),
+            Comparison::Mismatch
+        );
+        divergence.retain(&HashSet::new());
+        assert_eq!(
+            divergence.compare(3, &local, &diverged, 2),
+            Comparison::Mismatch
+        );
+
+        assert!(divergence
+            .to_prometheus()
+            .contains("nimble_divergences_total 1\n"));
+    }
+}
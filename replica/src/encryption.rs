@@ -0,0 +1,623 @@
+//! Encryption at rest of document content, with envelope encryption under a KMS key.
+//!
+//! When `encryption.kms_key_id` is set, each replica asks KMS for a data key when it starts
+//! (and again every `rotate_after_secs`). The data key encrypts node values with AES-256-GCM
+//! before they are written to `document_snapshots` and `operations`, and the S3 backups of
+//! documents. Only the data key as encrypted by KMS is stored, in the `data_keys` table, so
+//! reading the content back requires access to the KMS key.
+//!
+//! Encrypted values are stored as `enc:v1:<key_id>:<base64 nonce and ciphertext>`, where
+//! `key_id` is the row of `data_keys` holding the data key. Backups are self-contained: the
+//! object starts with `BACKUP_MAGIC`, followed by the key id, the encrypted data key, the nonce
+//! and the ciphertext, so a backup can be restored into an empty database.
+//! Values and backups written before encryption was enabled are read as they are.
+
+use crate::signing::AwsSigner;
+use crate::ApiError;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aws_config::SdkConfig;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rocket::fairing::AdHoc;
+use rocket::tokio::{self, sync::Mutex};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tokio_postgres::Client;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+/// Prefix of encrypted values in the database.
+pub const VALUE_PREFIX: &str = "enc:v1:";
+
+/// First bytes of an encrypted backup object.
+pub const BACKUP_MAGIC: &[u8; 8] = b"NMBLENC1";
+
+const NONCE_LEN: usize = 12;
+
+/// Encryption at rest.
+/// `kms_key_id`: The KMS key (id, ARN or alias) data keys are generated under, encryption is
+/// disabled if unset.
+/// `region`: The region of the KMS key.
+/// `endpoint`: A KMS compatible endpoint to use instead of AWS.
+/// `rotate_after_secs`: Seconds a data key encrypts new content before another is generated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub kms_key_id: Option<String>,
+    pub region: String,
+    pub endpoint: Option<String>,
+    pub rotate_after_secs: u64,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        EncryptionConfig {
+            kms_key_id: None,
+            region: "af-south-1".to_string(),
+            endpoint: None,
+            rotate_after_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+/// A minimal KMS client signing requests with the replica's AWS credentials.
+pub struct Kms {
+    signer: AwsSigner,
+    url: String,
+    key_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct KmsResponse {
+    #[serde(default)]
+    key_id: Option<String>,
+    #[serde(default)]
+    plaintext: Option<String>,
+    #[serde(default)]
+    ciphertext_blob: Option<String>,
+}
+
+impl Kms {
+    /// Creates the client, or returns None if no KMS key or AWS credentials are configured.
+    pub fn new(aws_config: &SdkConfig, config: &EncryptionConfig) -> Option<Kms> {
+        let key_id: &String = config.kms_key_id.as_ref()?;
+        let credentials = aws_config.credentials_provider()?;
+
+        let url: String = match &config.endpoint {
+            Some(endpoint) => format!("{}/", endpoint.trim_end_matches('/')),
+            None => format!("https://kms.{}.amazonaws.com/", config.region),
+        };
+
+        Some(Kms {
+            signer: AwsSigner::new(credentials, &config.region, "kms"),
+            url,
+            key_id: key_id.clone(),
+        })
+    }
+
+    /// Calls a KMS action with the JSON protocol.
+    async fn call(&self, action: &str, body: serde_json::Value) -> Result<KmsResponse, ApiError> {
+        let url = match reqwest::Url::parse(&self.url) {
+            Ok(url) => url,
+            Err(_) => {
+                error!("Invalid KMS url");
+                return Err(ApiError::InternalServerError("Invalid KMS url".to_string()));
+            }
+        };
+        let target: String = format!("TrentService.{}", action);
+        let headers = [
+            ("content-type", "application/x-amz-json-1.1"),
+            ("x-amz-target", target.as_str()),
+        ];
+
+        let response = self
+            .signer
+            .send(
+                reqwest::Method::POST,
+                url,
+                &headers,
+                body.to_string().into_bytes(),
+            )
+            .await?;
+        if !response.status().is_success() {
+            error!("KMS rejected {} with status {}", action, response.status());
+            return Err(ApiError::RequestFailed(format!(
+                "KMS rejected {} with status {}",
+                action,
+                response.status()
+            )));
+        }
+
+        let body = match response.bytes().await {
+            Ok(body) => body,
+            Err(_) => {
+                error!("Failed to read the KMS {} response", action);
+                return Err(ApiError::RequestFailed(format!(
+                    "Failed to read the KMS {} response",
+                    action
+                )));
+            }
+        };
+        match serde_json::from_slice::<KmsResponse>(&body) {
+            Ok(response) => Ok(response),
+            Err(_) => {
+                error!("Failed to parse the KMS {} response", action);
+                Err(ApiError::RequestFailed(format!(
+                    "Failed to parse the KMS {} response",
+                    action
+                )))
+            }
+        }
+    }
+
+    /// Generates a data key, returning the KMS key it is encrypted under, the key and the key
+    /// encrypted by KMS.
+    #[instrument(name = "kms.generate_data_key", skip(self))]
+    pub async fn generate_data_key(&self) -> Result<(String, Vec<u8>, Vec<u8>), ApiError> {
+        let response = self
+            .call(
+                "GenerateDataKey",
+                serde_json::json!({ "KeyId": self.key_id, "KeySpec": "AES_256" }),
+            )
+            .await?;
+
+        match (
+            response.plaintext.as_deref().map(decode),
+            response.ciphertext_blob.as_deref().map(decode),
+        ) {
+            (Some(Ok(plaintext)), Some(Ok(ciphertext))) => Ok((
+                response.key_id.unwrap_or_else(|| self.key_id.clone()),
+                plaintext,
+                ciphertext,
+            )),
+            _ => {
+                error!("KMS returned an invalid data key");
+                Err(ApiError::RequestFailed(
+                    "KMS returned an invalid data key".to_string(),
+                ))
+            }
+        }
+    }
+
+    /// Decrypts a data key encrypted by KMS.
+    #[instrument(name = "kms.decrypt", skip_all)]
+    pub async fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, ApiError> {
+        let response = self
+            .call(
+                "Decrypt",
+                serde_json::json!({ "CiphertextBlob": BASE64.encode(ciphertext) }),
+            )
+            .await?;
+
+        match response.plaintext.as_deref().map(decode) {
+            Some(Ok(plaintext)) => Ok(plaintext),
+            _ => {
+                error!("KMS returned an invalid data key");
+                Err(ApiError::RequestFailed(
+                    "KMS returned an invalid data key".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    BASE64.decode(value)
+}
+
+fn cipher(key: &[u8]) -> Result<Aes256Gcm, ApiError> {
+    if key.len() != 32 {
+        error!("Data key is {} bytes instead of 32", key.len());
+        return Err(ApiError::InternalServerError(
+            "Invalid data key".to_string(),
+        ));
+    }
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)))
+}
+
+/// The data key new content is encrypted with.
+/// `id`: The key's row in `data_keys`.
+/// `encrypted`: The key as encrypted by KMS, embedded in backups.
+#[derive(Clone)]
+pub struct DataKey {
+    pub id: Uuid,
+    cipher: Aes256Gcm,
+    encrypted: Vec<u8>,
+    created: Instant,
+}
+
+impl DataKey {
+    pub fn new(id: Uuid, key: &[u8], encrypted: Vec<u8>) -> Result<Self, ApiError> {
+        Ok(DataKey {
+            id,
+            cipher: cipher(key)?,
+            encrypted,
+            created: Instant::now(),
+        })
+    }
+
+    /// Encrypts a value, returning the value to store.
+    pub fn seal_value(&self, value: &str) -> String {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut sealed: Vec<u8> = nonce.to_vec();
+        // AES-GCM only fails on plaintexts over 64 GiB
+        sealed.extend(
+            self.cipher
+                .encrypt(&nonce, value.as_bytes())
+                .unwrap_or_default(),
+        );
+        format!("{}{}:{}", VALUE_PREFIX, self.id, BASE64.encode(sealed))
+    }
+
+    /// Encrypts a backup object.
+    pub fn seal_backup(&self, body: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut sealed: Vec<u8> = BACKUP_MAGIC.to_vec();
+        sealed.extend(self.id.as_bytes());
+        sealed.extend((self.encrypted.len() as u32).to_be_bytes());
+        sealed.extend(&self.encrypted);
+        sealed.extend(nonce.as_slice());
+        sealed.extend(self.cipher.encrypt(&nonce, body).unwrap_or_default());
+        sealed
+    }
+}
+
+/// Splits a stored value into its key id and sealed bytes, None if it is not encrypted.
+fn parse_value(value: &str) -> Option<(Uuid, Vec<u8>)> {
+    let (key_id, sealed) = value.strip_prefix(VALUE_PREFIX)?.split_once(':')?;
+    Some((Uuid::parse_str(key_id).ok()?, BASE64.decode(sealed).ok()?))
+}
+
+/// An encrypted backup object's key id, encrypted data key and sealed body.
+struct SealedBackup<'a> {
+    key_id: Uuid,
+    encrypted_key: &'a [u8],
+    sealed: &'a [u8],
+}
+
+/// Splits an encrypted backup object, None if it is not encrypted.
+fn parse_backup(body: &[u8]) -> Option<SealedBackup<'_>> {
+    let rest: &[u8] = body.strip_prefix(BACKUP_MAGIC)?;
+    let key_id = Uuid::from_slice(rest.get(..16)?).ok()?;
+    let len = u32::from_be_bytes(rest.get(16..20)?.try_into().ok()?) as usize;
+    Some(SealedBackup {
+        key_id,
+        encrypted_key: rest.get(20..20 + len)?,
+        sealed: rest.get(20 + len..)?,
+    })
+}
+
+fn open_sealed(cipher: &Aes256Gcm, sealed: &[u8]) -> Result<Vec<u8>, ApiError> {
+    if sealed.len() < NONCE_LEN {
+        error!("Encrypted content is truncated");
+        return Err(ApiError::InternalServerError(
+            "Failed to decrypt stored content".to_string(),
+        ));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    match cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+        Ok(plaintext) => Ok(plaintext),
+        Err(_) => {
+            error!("Failed to decrypt stored content");
+            Err(ApiError::InternalServerError(
+                "Failed to decrypt stored content".to_string(),
+            ))
+        }
+    }
+}
+
+/// The data keys of the replica.
+/// `current`: The key new content is encrypted with.
+/// `keys`: Every data key decrypted so far, by id.
+/// `rotating`: Whether a new data key is being generated.
+struct Keyring {
+    kms: Kms,
+    db: Arc<Mutex<Client>>,
+    rotate_after: Duration,
+    current: RwLock<DataKey>,
+    keys: RwLock<HashMap<Uuid, Aes256Gcm>>,
+    rotating: AtomicBool,
+}
+
+static KEYRING: OnceLock<Arc<Keyring>> = OnceLock::new();
+
+/// Whether content is encrypted at rest.
+pub fn is_enabled() -> bool {
+    KEYRING.get().is_some()
+}
+
+/// Generates a data key with KMS and records it in `data_keys`.
+async fn create_data_key(kms: &Kms, client: &Client) -> Result<DataKey, ApiError> {
+    let (kms_key_id, key, encrypted) = kms.generate_data_key().await?;
+    let key = DataKey::new(Uuid::new_v4(), &key, encrypted)?;
+
+    match client
+        .execute(
+            "INSERT INTO data_keys (key_id,kms_key_id,encrypted_key,created_at) VALUES ($1,$2,$3,$4)",
+            &[
+                &key.id,
+                &kms_key_id,
+                &key.encrypted,
+                &chrono::Utc::now().to_rfc3339(),
+            ],
+        )
+        .await
+    {
+        Ok(_) => {
+            info!(key_id = %key.id, "Generated a data key");
+            Ok(key)
+        }
+        Err(_) => {
+            error!("Failed to insert into the data_keys table");
+            Err(ApiError::DatabaseError(
+                "Failed to record the data key".to_string(),
+            ))
+        }
+    }
+}
+
+impl Keyring {
+    fn current(&self) -> DataKey {
+        let current: DataKey = self
+            .current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        if current.created.elapsed() >= self.rotate_after {
+            self.rotate();
+        }
+        current
+    }
+
+    /// Generates the next data key in the background, content keeps being encrypted with the
+    /// current one until it is ready.
+    fn rotate(&self) {
+        let Some(keyring) = KEYRING.get().cloned() else {
+            return;
+        };
+        if tokio::runtime::Handle::try_current().is_err()
+            || self.rotating.swap(true, Ordering::AcqRel)
+        {
+            return;
+        }
+
+        tokio::spawn(async move {
+            let result = {
+                let client = keyring.db.lock().await;
+                create_data_key(&keyring.kms, &client).await
+            };
+            match result {
+                Ok(key) => {
+                    keyring
+                        .keys
+                        .write()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .insert(key.id, key.cipher.clone());
+                    *keyring.current.write().unwrap_or_else(|e| e.into_inner()) = key;
+                }
+                Err(e) => warn!("Failed to rotate the data key: {}", e),
+            }
+            keyring.rotating.store(false, Ordering::Release);
+        });
+    }
+
+    fn cached(&self, key_id: &Uuid) -> Option<Aes256Gcm> {
+        self.keys
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(key_id)
+            .cloned()
+    }
+
+    fn cache(&self, key_id: Uuid, key: &[u8]) -> Result<Aes256Gcm, ApiError> {
+        let cipher: Aes256Gcm = cipher(key)?;
+        self.keys
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key_id, cipher.clone());
+        Ok(cipher)
+    }
+
+    /// Returns a data key, decrypting it with KMS the first time it is used.
+    async fn key(&self, client: &Client, key_id: &Uuid) -> Result<Aes256Gcm, ApiError> {
+        if let Some(cipher) = self.cached(key_id) {
+            return Ok(cipher);
+        }
+
+        let encrypted: Vec<u8> = match client
+            .query_opt(
+                "SELECT encrypted_key FROM data_keys WHERE key_id=$1",
+                &[key_id],
+            )
+            .await
+        {
+            Ok(Some(row)) => row.get(0),
+            Ok(None) => {
+                error!(key_id = %key_id, "Data key not found");
+                return Err(ApiError::InternalServerError(
+                    "The key of stored content was not found".to_string(),
+                ));
+            }
+            Err(_) => {
+                error!("Failed to read the data_keys table");
+                return Err(ApiError::DatabaseError(
+                    "Failed to read the data_keys table".to_string(),
+                ));
+            }
+        };
+        let key: Vec<u8> = self.kms.decrypt(&encrypted).await?;
+        self.cache(*key_id, &key)
+    }
+}
+
+/// Encrypts a value before it is written, unless encryption is disabled.
+pub fn seal(value: &str) -> String {
+    match KEYRING.get() {
+        Some(keyring) => keyring.current().seal_value(value),
+        None => value.to_string(),
+    }
+}
+
+/// Encrypts an optional value before it is written, unless encryption is disabled.
+pub fn seal_opt(value: &Option<String>) -> Option<String> {
+    value.as_deref().map(seal)
+}
+
+/// Decrypts a value read from the database. Values that are not encrypted are returned as
+/// they are.
+pub async fn open(client: &Client, value: String) -> Result<String, ApiError> {
+    let Some(keyring) = KEYRING.get() else {
+        return Ok(value);
+    };
+    let Some((key_id, sealed)) = parse_value(&value) else {
+        return Ok(value);
+    };
+
+    let cipher: Aes256Gcm = keyring.key(client, &key_id).await?;
+    into_string(open_sealed(&cipher, &sealed)?)
+}
+
+/// Decrypts an optional value read from the database.
+pub async fn open_opt(client: &Client, value: Option<String>) -> Result<Option<String>, ApiError> {
+    match value {
+        Some(value) => Ok(Some(open(client, value).await?)),
+        None => Ok(None),
+    }
+}
+
+fn into_string(plaintext: Vec<u8>) -> Result<String, ApiError> {
+    match String::from_utf8(plaintext) {
+        Ok(value) => Ok(value),
+        Err(_) => {
+            error!("Decrypted content is not UTF-8");
+            Err(ApiError::InternalServerError(
+                "Failed to decrypt stored content".to_string(),
+            ))
+        }
+    }
+}
+
+/// Encrypts a backup object before it is uploaded, unless encryption is disabled.
+pub fn seal_backup(body: Vec<u8>) -> Vec<u8> {
+    match KEYRING.get() {
+        Some(keyring) => keyring.current().seal_backup(&body),
+        None => body,
+    }
+}
+
+/// Decrypts a downloaded backup object. Objects that are not encrypted are returned as they
+/// are, encrypted objects can only be read while encryption is enabled.
+pub async fn open_backup(body: Vec<u8>) -> Result<Vec<u8>, ApiError> {
+    let Some(backup) = parse_backup(&body) else {
+        return Ok(body);
+    };
+    let Some(keyring) = KEYRING.get() else {
+        error!("Encrypted backup read while encryption is disabled");
+        return Err(ApiError::InternalServerError(
+            "The backup is encrypted but encryption is not configured".to_string(),
+        ));
+    };
+
+    let cipher: Aes256Gcm = match keyring.cached(&backup.key_id) {
+        Some(cipher) => cipher,
+        None => {
+            let key: Vec<u8> = keyring.kms.decrypt(backup.encrypted_key).await?;
+            keyring.cache(backup.key_id, &key)?
+        }
+    };
+    open_sealed(&cipher, backup.sealed)
+}
+
+/// Fairing that generates the replica's first data key before Rocket starts serving, if
+/// `encryption.kms_key_id` is set. Must be attached after the database.
+pub fn attach_encryption(kms: Option<Kms>, config: EncryptionConfig) -> AdHoc {
+    AdHoc::on_ignite("Encryption at rest", move |rocket| async move {
+        let Some(kms) = kms else {
+            info!("Encryption at rest is disabled");
+            return rocket;
+        };
+        let Some(db) = rocket.state::<Arc<Mutex<Client>>>().cloned() else {
+            error!("Unable to start server, encryption requires the database");
+            std::process::exit(1);
+        };
+
+        let result = {
+            let client = db.lock().await;
+            create_data_key(&kms, &client).await
+        };
+        let key: DataKey = match result {
+            Ok(key) => key,
+            Err(e) => {
+                error!(
+                    "Unable to start server, failed to generate a data key: {}",
+                    e
+                );
+                eprintln!("Failed to generate a data key: {:?}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let keys: HashMap<Uuid, Aes256Gcm> = HashMap::from([(key.id, key.cipher.clone())]);
+        let _ = KEYRING.set(Arc::new(Keyring {
+            kms,
+            db,
+            rotate_after: Duration::from_secs(config.rotate_after_secs),
+            current: RwLock::new(key),
+            keys: RwLock::new(keys),
+            rotating: AtomicBool::new(false),
+        }));
+        rocket
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_value() {
+        let key = DataKey::new(Uuid::new_v4(), &[7; 32], vec![1, 2, 3]).unwrap();
+        let sealed: String = key.seal_value("fn main() {}");
+        assert!(sealed.starts_with(&format!("{}{}:", VALUE_PREFIX, key.id)));
+        // a random nonce per value
+        assert_ne!(sealed, key.seal_value("fn main() {}"));
+
+        let (key_id, bytes) = parse_value(&sealed).unwrap();
+        assert_eq!(key_id, key.id);
+        assert_eq!(
+            open_sealed(&key.cipher, &bytes).unwrap(),
+            b"fn main() {}".to_vec()
+        );
+        let other = DataKey::new(key.id, &[8; 32], Vec::new()).unwrap();
+        assert!(open_sealed(&other.cipher, &bytes).is_err());
+
+        assert!(parse_value("fn main() {}").is_none());
+        assert!(DataKey::new(key.id, &[7; 16], Vec::new()).is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_seal_backup() {
+        let key = DataKey::new(Uuid::new_v4(), &[7; 32], vec![1, 2, 3]).unwrap();
+        let sealed: Vec<u8> = key.seal_backup(b"{\"nodes\":[]}");
+
+        let backup = parse_backup(&sealed).unwrap();
+        assert_eq!(backup.key_id, key.id);
+        assert_eq!(backup.encrypted_key, &[1, 2, 3]);
+        assert_eq!(
+            open_sealed(&key.cipher, backup.sealed).unwrap(),
+            b"{\"nodes\":[]}".to_vec()
+        );
+
+        // backups taken before encryption was enabled are read as they are
+        assert_eq!(
+            open_backup(b"{\"nodes\":[]}".to_vec()).await.unwrap(),
+            b"{\"nodes\":[]}".to_vec()
+        );
+        assert!(parse_backup(&sealed[..20]).is_none());
+    }
+}
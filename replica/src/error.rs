@@ -1,49 +1,522 @@
+use crate::yjs::YjsError;
+use crate::{ErrorCatalogEntry, ErrorResponse, FieldError, RequestId, S4VectorError};
 use miette::Diagnostic;
 use rocket::http::{ContentType, Status};
 use rocket::response::Responder;
-use rocket::{Request, Response};
+use rocket::serde::json::Json;
+use rocket::{catch, catchers, get, Catcher, Request, Response};
 use std::io::Cursor;
 use thiserror::Error;
+use tracing::error;
+
+/// The path the error catalog is served under, each error is documented at
+/// `/errors/<code>`.
+pub const ERRORS_PATH: &str = "/errors";
 
 // Error struct for API
-#[derive(Debug, Error, Diagnostic)]
+#[derive(Debug, Clone, Error, Diagnostic)]
 pub enum ApiError {
     #[error("Dependency missing for the operation")]
-    #[diagnostic(code(api::dependency_missing))]
+    #[diagnostic(
+        code(api::dependency_missing),
+        help("Retry once the operations the request depends on have been applied")
+    )]
     DependencyMissing,
 
     #[error("Invalid operation: {0}")]
-    #[diagnostic(code(api::invalid_operation))]
+    #[diagnostic(code(api::invalid_operation), help("Check the operation against the current document, such as the s4vector of the node it targets"))]
     InvalidOperation(String),
 
     #[error("Failed to process request: {0}")]
-    #[diagnostic(code(api::request_failed))]
+    #[diagnostic(
+        code(api::request_failed),
+        help("Retry the request, and report the `request_id` if it keeps failing")
+    )]
     RequestFailed(String),
 
     #[error("Database Error {0}")]
-    #[diagnostic(code(api::database_error))]
+    #[diagnostic(
+        code(api::database_error),
+        help("Retry the request, and report the `request_id` if it keeps failing")
+    )]
     DatabaseError(String),
 
+    #[error("Invalid fields: {}", .0.iter().map(|e| e.field.as_str()).collect::<Vec<_>>().join(", "))]
+    #[diagnostic(
+        code(api::validation_failed),
+        help("Correct the fields listed in `errors` and retry")
+    )]
+    ValidationFailed(Vec<FieldError>),
+
     #[error("Server Error {0}")]
-    #[diagnostic(code(api::database_error))]
+    #[diagnostic(
+        code(api::internal_server_error),
+        help("Report the `request_id` of the failed request")
+    )]
     InternalServerError(String),
+
+    #[error("Not found: {0}")]
+    #[diagnostic(
+        code(api::not_found),
+        help("Documents must be loaded with GET /document/<id> before they can be edited")
+    )]
+    NotFound(String),
+
+    #[error("Unauthorized: {0}")]
+    #[diagnostic(
+        code(api::unauthorized),
+        help(
+            "Log in and send the session token in `X-Session-Token`, or send a valid share token"
+        )
+    )]
+    Unauthorized(String),
+
+    #[error("Forbidden: {0}")]
+    #[diagnostic(code(api::forbidden), help("Ask the owner of the resource for access"))]
+    Forbidden(String),
+
+    #[error("Gone: {0}")]
+    #[diagnostic(
+        code(api::gone),
+        help("Reload the resource, it has expired or been removed")
+    )]
+    Gone(String),
+
+    #[error("Conflict: {0}")]
+    #[diagnostic(
+        code(api::conflict),
+        help("Fetch the latest document state and retry the operation")
+    )]
+    Conflict(String),
+
+    #[error("Too many requests: {0}")]
+    #[diagnostic(
+        code(api::too_many_requests),
+        help("Retry once the current one minute window has passed")
+    )]
+    TooManyRequests(String),
+
+    #[error("Too many requests: {0}")]
+    #[diagnostic(
+        code(api::slow_down),
+        help("Retry after the number of seconds in the Retry-After header")
+    )]
+    SlowDown(String, u64),
+
+    #[error("Service unavailable: {0}")]
+    #[diagnostic(
+        code(api::service_unavailable),
+        help("Retry after the number of seconds in the Retry-After header")
+    )]
+    ServiceUnavailable(String, u64),
+
+    #[error("Quota exceeded: {0}")]
+    #[diagnostic(
+        code(api::quota_exceeded),
+        help("Delete unused documents or ask an administrator to raise the quota")
+    )]
+    QuotaExceeded(String),
+
+    #[error("Unsupported media type: {0}")]
+    #[diagnostic(
+        code(api::unsupported_media_type),
+        help("Send the request body as JSON with `Content-Type: application/json`")
+    )]
+    UnsupportedMediaType(String),
+
+    #[error("Payload too large: {0}")]
+    #[diagnostic(
+        code(api::payload_too_large),
+        help("Split the request into smaller ones")
+    )]
+    PayloadTooLarge(String),
 }
 
-impl<'r> Responder<'r, 'static> for ApiError {
-    fn respond_to(self, _: &'r Request<'_>) -> Result<Response<'static>, Status> {
-        let message = format!("{:?}", self);
-        let status = match self {
+impl ApiError {
+    /// The HTTP status returned to the client for this error.
+    pub fn status(&self) -> Status {
+        match self {
             ApiError::DependencyMissing => Status::Ok,
             ApiError::InvalidOperation(_) => Status::BadRequest,
+            ApiError::ValidationFailed(_) => Status::UnprocessableEntity,
             ApiError::RequestFailed(_) => Status::InternalServerError,
             ApiError::DatabaseError(_) => Status::InternalServerError,
             ApiError::InternalServerError(_) => Status::InternalServerError,
+            ApiError::NotFound(_) => Status::NotFound,
+            ApiError::Unauthorized(_) => Status::Unauthorized,
+            ApiError::Forbidden(_) => Status::Forbidden,
+            ApiError::Gone(_) => Status::Gone,
+            ApiError::Conflict(_) => Status::Conflict,
+            ApiError::TooManyRequests(_) => Status::TooManyRequests,
+            ApiError::SlowDown(_, _) => Status::TooManyRequests,
+            ApiError::ServiceUnavailable(_, _) => Status::ServiceUnavailable,
+            ApiError::QuotaExceeded(_) => Status::TooManyRequests,
+            ApiError::PayloadTooLarge(_) => Status::PayloadTooLarge,
+            ApiError::UnsupportedMediaType(_) => Status::UnsupportedMediaType,
+        }
+    }
+
+    /// Seconds the client should wait before retrying, sent as `Retry-After`.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            ApiError::SlowDown(_, secs) | ApiError::ServiceUnavailable(_, secs) => Some(*secs),
+            _ => None,
+        }
+    }
+
+    /// One error of every kind, in the order of the error catalog.
+    pub fn kinds() -> Vec<ApiError> {
+        vec![
+            ApiError::InvalidOperation(String::new()),
+            ApiError::ValidationFailed(Vec::new()),
+            ApiError::NotFound(String::new()),
+            ApiError::Unauthorized(String::new()),
+            ApiError::Forbidden(String::new()),
+            ApiError::Gone(String::new()),
+            ApiError::Conflict(String::new()),
+            ApiError::PayloadTooLarge(String::new()),
+            ApiError::UnsupportedMediaType(String::new()),
+            ApiError::TooManyRequests(String::new()),
+            ApiError::SlowDown(String::new(), 0),
+            ApiError::QuotaExceeded(String::new()),
+            ApiError::DependencyMissing,
+            ApiError::RequestFailed(String::new()),
+            ApiError::DatabaseError(String::new()),
+            ApiError::InternalServerError(String::new()),
+            ApiError::ServiceUnavailable(String::new(), 0),
+        ]
+    }
+
+    /// When the error is returned, for the error catalog.
+    pub fn description(&self) -> &'static str {
+        match self {
+            ApiError::DependencyMissing => {
+                "An operation refers to operations the replica has not applied yet."
+            }
+            ApiError::InvalidOperation(_) => {
+                "The request is malformed or can't be applied to the document in its current state."
+            }
+            ApiError::RequestFailed(_) => "The replica failed to process a valid request.",
+            ApiError::DatabaseError(_) => "The replica's database failed to run a query.",
+            ApiError::ValidationFailed(_) => {
+                "Fields of the request body are invalid. Each one is listed in `errors` with the reason it was rejected."
+            }
+            ApiError::InternalServerError(_) => "An unexpected error occurred on the replica.",
+            ApiError::NotFound(_) => {
+                "The document, user or other resource does not exist, or the document is not loaded on the replica."
+            }
+            ApiError::Unauthorized(_) => {
+                "The request does not identify a user, or its share token is expired or invalid."
+            }
+            ApiError::Forbidden(_) => "The user or share token does not have access to the resource.",
+            ApiError::Gone(_) => {
+                "The resource existed but is no longer available, such as an expired document or a change feed version older than the replica keeps."
+            }
+            ApiError::Conflict(_) => "The request conflicts with the current state of the resource.",
+            ApiError::TooManyRequests(_) => "The client sent more requests than its rate limit allows.",
+            ApiError::SlowDown(_, _) => {
+                "The replica's broadcast backlog for the document is full, so edits are briefly refused."
+            }
+            ApiError::ServiceUnavailable(_, _) => {
+                "The replica is overloaded or shutting down and can't serve the request for now."
+            }
+            ApiError::QuotaExceeded(_) => {
+                "The owner or workspace has reached its quota, such as the number of documents it may create."
+            }
+            ApiError::UnsupportedMediaType(_) => "The request body is not sent as JSON.",
+            ApiError::PayloadTooLarge(_) => {
+                "The request body or a value in it exceeds the replica's size or nesting limits."
+            }
+        }
+    }
+
+    /// The diagnostic code of the error, such as `api::not_found`.
+    pub fn code_name(&self) -> String {
+        match self.code() {
+            Some(code) => code.to_string(),
+            None => "api::unknown".to_string(),
+        }
+    }
+
+    /// The error's entry in the error catalog.
+    pub fn catalog_entry(&self) -> ErrorCatalogEntry {
+        let code: String = self.code_name();
+        ErrorCatalogEntry {
+            docs_url: docs_url(&code),
+            code,
+            status: self.status().code,
+            description: self.description().to_string(),
+            help: self.help().map(|help| help.to_string()),
+        }
+    }
+
+    /// Builds the JSON body for the error using its diagnostic code and help text.
+    pub fn to_response(&self, request_id: Option<String>) -> ErrorResponse {
+        let code: String = self.code_name();
+        ErrorResponse {
+            docs_url: Some(docs_url(&code)),
+            code,
+            message: self.to_string(),
+            details: self.help().map(|help| help.to_string()),
+            request_id,
+            errors: match self {
+                ApiError::ValidationFailed(errors) => errors.clone(),
+                _ => Vec::new(),
+            },
+        }
+    }
+}
+
+/// Where the error with the diagnostic code is documented.
+pub fn docs_url(code: &str) -> String {
+    format!("{}/{}", ERRORS_PATH, code)
+}
+
+/// Every error the API returns, with its status and how to resolve it.
+pub fn error_catalog() -> Vec<ErrorCatalogEntry> {
+    ApiError::kinds()
+        .iter()
+        .map(ApiError::catalog_entry)
+        .collect()
+}
+
+/// The catalog entry of a diagnostic code, with or without its `api::` prefix.
+pub fn find_error(code: &str) -> Option<ErrorCatalogEntry> {
+    let code: String = if code.contains("::") {
+        code.to_string()
+    } else {
+        format!("api::{}", code)
+    };
+    error_catalog().into_iter().find(|entry| entry.code == code)
+}
+
+/// Lists the error catalog.
+///
+/// # Response
+/// [
+///     { "code" : "api::invalid_operation", "status" : 400, "description" : "...", "help" : "...", "docs_url" : "/errors/api::invalid_operation" },
+///     ...
+/// ]
+#[get("/errors")]
+pub fn list_errors() -> Json<Vec<ErrorCatalogEntry>> {
+    Json(error_catalog())
+}
+
+/// Documents the error with the diagnostic code, such as `GET /errors/api::not_found`.
+#[get("/errors/<code>")]
+pub fn fetch_error(code: &str) -> Result<Json<ErrorCatalogEntry>, ApiError> {
+    match find_error(code) {
+        Some(entry) => Ok(Json(entry)),
+        None => Err(ApiError::NotFound(format!(
+            "No error with the code {}",
+            code
+        ))),
+    }
+}
+
+#[catch(404)]
+fn not_found(req: &Request<'_>) -> ApiError {
+    ApiError::NotFound(format!(
+        "No route for {} {}",
+        req.method(),
+        req.uri().path()
+    ))
+}
+
+#[catch(500)]
+fn internal_server_error(req: &Request<'_>) -> ApiError {
+    error!(request_id = %RequestId::of(req), "Failed to respond to {} {}", req.method(), req.uri());
+    ApiError::InternalServerError("The request could not be completed".to_string())
+}
+
+/// Answers every other status with the error of the same status, or a generic client or
+/// server error keeping the status.
+#[catch(default)]
+fn default_catcher(status: Status, _: &Request<'_>) -> (Status, ApiError) {
+    let reason: String = status.reason().unwrap_or("Request failed").to_string();
+    let error: ApiError = match status.code {
+        401 => ApiError::Unauthorized(reason),
+        403 => ApiError::Forbidden(reason),
+        409 => ApiError::Conflict(reason),
+        410 => ApiError::Gone(reason),
+        429 => ApiError::TooManyRequests(reason),
+        400..=499 => ApiError::InvalidOperation(reason),
+        _ => ApiError::InternalServerError(reason),
+    };
+    (status, error)
+}
+
+/// Catchers returning the structured error format for unmatched routes, failed requests and
+/// every other status Rocket answers itself, instead of its HTML error pages. Rejected request
+/// bodies are answered by the catchers of [`crate::limits::catchers`].
+pub fn catchers() -> Vec<Catcher> {
+    catchers![not_found, internal_server_error, default_catcher]
+}
+
+impl From<S4VectorError> for ApiError {
+    fn from(e: S4VectorError) -> Self {
+        ApiError::InvalidOperation(e.to_string())
+    }
+}
+
+impl From<YjsError> for ApiError {
+    fn from(e: YjsError) -> Self {
+        ApiError::InvalidOperation(e.to_string())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> Result<Response<'static>, Status> {
+        let request_id: RequestId = RequestId::of(request);
+        crate::reporting::report_request(&self, request);
+
+        let body: String = match serde_json::to_string(&self.to_response(Some(request_id.0))) {
+            Ok(body) => body,
+            Err(_) => return Err(Status::InternalServerError),
+        };
+
+        let mut response = Response::build();
+        response
+            .status(self.status())
+            .header(ContentType::JSON)
+            .sized_body(body.len(), Cursor::new(body));
+        if let Some(secs) = self.retry_after() {
+            response.raw_header("Retry-After", secs.to_string());
+        }
+        response.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_mapping() {
+        assert_eq!(ApiError::NotFound(String::new()).status(), Status::NotFound);
+        assert_eq!(
+            ApiError::Unauthorized(String::new()).status(),
+            Status::Unauthorized
+        );
+        assert_eq!(
+            ApiError::Forbidden(String::new()).status(),
+            Status::Forbidden
+        );
+        assert_eq!(ApiError::Gone(String::new()).status(), Status::Gone);
+        assert_eq!(ApiError::Conflict(String::new()).status(), Status::Conflict);
+        assert_eq!(
+            ApiError::TooManyRequests(String::new()).status(),
+            Status::TooManyRequests
+        );
+        assert_eq!(
+            ApiError::InvalidOperation(String::new()).status(),
+            Status::BadRequest
+        );
+        assert_eq!(
+            ApiError::ServiceUnavailable(String::new(), 2).status(),
+            Status::ServiceUnavailable
+        );
+        assert_eq!(ApiError::SlowDown(String::new(), 2).retry_after(), Some(2));
+        assert_eq!(ApiError::Conflict(String::new()).retry_after(), None);
+    }
+
+    #[test]
+    fn test_error_response_body() {
+        let error = ApiError::NotFound("Document not found".to_string());
+        let body = error.to_response(Some("abc-123".to_string()));
+
+        assert_eq!(body.code, "api::not_found");
+        assert_eq!(body.message, "Not found: Document not found");
+        assert!(body.details.is_some());
+        assert_eq!(body.docs_url, Some("/errors/api::not_found".to_string()));
+        assert_eq!(body.request_id, Some("abc-123".to_string()));
+
+        let body = ApiError::DatabaseError("Failed".to_string()).to_response(None);
+        assert_eq!(body.code, "api::database_error");
+        assert_eq!(body.request_id, None);
+    }
+
+    #[test]
+    fn test_error_catalog() {
+        let catalog = error_catalog();
+        assert_eq!(catalog.len(), ApiError::kinds().len());
+
+        // every code is documented once, with how to resolve it
+        let mut codes: Vec<&str> = catalog.iter().map(|e| e.code.as_str()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), catalog.len());
+        assert!(catalog
+            .iter()
+            .all(|e| e.help.is_some() && e.code.starts_with("api::")));
+
+        let entry = find_error("conflict").unwrap();
+        assert_eq!(entry, find_error("api::conflict").unwrap());
+        assert_eq!(entry.status, 409);
+        assert_eq!(entry.docs_url, "/errors/api::conflict");
+        assert_eq!(find_error("api::unknown"), None);
+    }
+
+    #[test]
+    fn test_error_routes() {
+        let rocket = rocket::build().mount("/", rocket::routes![list_errors, fetch_error]);
+        let client = rocket::local::blocking::Client::tracked(rocket).unwrap();
+
+        let response = client.get("/errors/api::gone").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let entry: ErrorCatalogEntry = response.into_json().unwrap();
+        assert_eq!((entry.code.as_str(), entry.status), ("api::gone", 410));
+
+        let response = client.get("/errors/api::missing").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let body: ErrorResponse = response.into_json().unwrap();
+        assert_eq!(body.code, "api::not_found");
+
+        let entries: Vec<ErrorCatalogEntry> = client.get("/errors").dispatch().into_json().unwrap();
+        assert_eq!(entries.len(), ApiError::kinds().len());
+    }
+
+    #[rocket::get("/status/<code>")]
+    fn status(code: u16) -> Status {
+        Status::new(code)
+    }
+
+    #[rocket::get("/panic")]
+    fn panic() -> &'static str {
+        panic!("handler failed")
+    }
+
+    #[test]
+    fn test_catchers() {
+        let rocket = rocket::build()
+            .register("/", catchers())
+            .mount("/", rocket::routes![status, panic]);
+        let client = rocket::local::blocking::Client::tracked(rocket).unwrap();
+        let get = |uri: &str| {
+            let response = client
+                .get(uri.to_string())
+                .header(rocket::http::Header::new("X-Request-ID", "abc-123"))
+                .dispatch();
+            assert_eq!(response.content_type(), Some(ContentType::JSON));
+            let status: Status = response.status();
+            let body: ErrorResponse = response.into_json().unwrap();
+            assert_eq!(body.request_id, Some("abc-123".to_string()));
+            (status.code, body.code)
         };
 
-        Response::build()
-            .status(status)
-            .header(ContentType::Plain)
-            .sized_body(message.len(), Cursor::new(message))
-            .ok()
+        assert_eq!(get("/missing"), (404, "api::not_found".to_string()));
+        assert_eq!(
+            get("/panic"),
+            (500, "api::internal_server_error".to_string())
+        );
+        assert_eq!(get("/status/401"), (401, "api::unauthorized".to_string()));
+        assert_eq!(
+            get("/status/405"),
+            (405, "api::invalid_operation".to_string())
+        );
+        assert_eq!(
+            get("/status/502"),
+            (502, "api::internal_server_error".to_string())
+        );
     }
 }
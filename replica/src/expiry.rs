@@ -0,0 +1,353 @@
+use crate::flags::{Feature, Features};
+use crate::leader::{Leader, REAPER_JOB};
+use crate::rga::rga::RGA;
+use crate::routes::SharedRGAs;
+use crate::tenancy::Tenant;
+use crate::{tenancy, ApiError};
+use chrono::{DateTime, Duration as TimeDelta, SecondsFormat, Utc};
+use rocket::fairing::AdHoc;
+use rocket::tokio::{self, sync::Mutex};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_postgres::Client;
+use tracing::{error, info, info_span, instrument, warn, Instrument};
+use uuid::Uuid;
+
+/// Expiry of documents created with a time-to-live, and of documents in the trash.
+/// `check_interval_secs`: Seconds between runs of the reaper, 0 disables it.
+/// `purge_grace_secs`: Seconds an archived document is kept before its content is purged.
+/// `trash_retention_secs`: Seconds a deleted document can be restored before it is purged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExpiryConfig {
+    pub check_interval_secs: u64,
+    pub purge_grace_secs: u64,
+    pub trash_retention_secs: u64,
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        ExpiryConfig {
+            check_interval_secs: 60,
+            purge_grace_secs: 24 * 60 * 60,
+            trash_retention_secs: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// Formats a time the way `expires_at` and `archived_at` are stored, so the columns can be
+/// compared as text.
+pub fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// The stored time `secs` seconds before `now`, None if it is before the earliest time.
+pub fn cutoff(now: DateTime<Utc>, secs: u64) -> Option<String> {
+    let secs: TimeDelta = i64::try_from(secs).ok().and_then(TimeDelta::try_seconds)?;
+    now.checked_sub_signed(secs).map(timestamp)
+}
+
+/// The `expires_at` value of a document created at `now` with a time-to-live of `ttl_secs`.
+/// Returns `None` if the document does not expire, and `InvalidOperation` for a zero or
+/// unrepresentable ttl.
+pub fn expires_at(now: DateTime<Utc>, ttl_secs: Option<u64>) -> Result<Option<String>, ApiError> {
+    let ttl_secs: u64 = match ttl_secs {
+        Some(ttl_secs) => ttl_secs,
+        None => return Ok(None),
+    };
+
+    let expiry = i64::try_from(ttl_secs)
+        .ok()
+        .filter(|ttl| *ttl > 0)
+        .and_then(TimeDelta::try_seconds)
+        .and_then(|ttl| now.checked_add_signed(ttl));
+
+    match expiry {
+        Some(expiry) => Ok(Some(timestamp(expiry))),
+        None => Err(ApiError::InvalidOperation(
+            "ttl_secs must be a positive number of seconds".to_string(),
+        )),
+    }
+}
+
+/// Returns `Gone` if the document has been archived or moved to the trash, so it is not
+/// loaded again.
+pub async fn check_not_archived(client: &Client, document_id: &Uuid) -> Result<(), ApiError> {
+    match client
+        .query_opt(
+            "SELECT archived_at,trashed_at FROM document WHERE document_id=$1",
+            &[document_id],
+        )
+        .await
+    {
+        Ok(Some(row)) => match (
+            row.get::<_, Option<String>>(0),
+            row.get::<_, Option<String>>(1),
+        ) {
+            (Some(_), Some(trashed_at)) => Err(ApiError::Gone(format!(
+                "Document was moved to the trash at {}",
+                trashed_at
+            ))),
+            (Some(archived_at), None) => Err(ApiError::Gone(format!(
+                "Document expired and was archived at {}",
+                archived_at
+            ))),
+            (None, _) => Ok(()),
+        },
+        Ok(None) => Ok(()),
+        Err(_) => {
+            error!("Failed to check if the document is archived");
+            Err(ApiError::DatabaseError(
+                "Failed to check if the document is archived".to_string(),
+            ))
+        }
+    }
+}
+
+/// Archives every document whose time-to-live has passed and evicts their RGAs.
+/// Archived documents keep their content until they are purged, but can no longer be loaded.
+/// Returns the archived documents.
+#[instrument(name = "expiry.archive", skip_all)]
+pub async fn archive_expired(
+    client: &Client,
+    rgas: &mut HashMap<Uuid, RGA>,
+    now: DateTime<Utc>,
+) -> Result<Vec<Uuid>, ApiError> {
+    let rows = match client
+        .query(
+            "UPDATE document SET archived_at=$1 WHERE archived_at IS NULL AND expires_at IS NOT NULL AND expires_at <= $1 RETURNING document_id",
+            &[&timestamp(now)],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => {
+            error!("Failed to archive expired documents");
+            return Err(ApiError::DatabaseError(
+                "Failed to archive expired documents".to_string(),
+            ));
+        }
+    };
+
+    let archived: Vec<Uuid> = rows.iter().map(|row| row.get(0)).collect();
+    for document_id in &archived {
+        rgas.remove(document_id);
+        info!(document_id = %document_id, "Archived expired document");
+    }
+    Ok(archived)
+}
+
+/// Deletes the operations and snapshots of documents archived more than `purge_grace_secs` ago,
+/// or moved to the trash more than `trash_retention_secs` ago, along with the document itself.
+/// The audit log is kept.
+/// Returns the number of documents purged.
+#[instrument(name = "expiry.purge", skip_all)]
+pub async fn purge_archived(
+    client: &mut Client,
+    now: DateTime<Utc>,
+    config: &ExpiryConfig,
+) -> Result<usize, ApiError> {
+    // an unrepresentable cutoff is before every stored time, so nothing is old enough
+    let archived_cutoff: String = cutoff(now, config.purge_grace_secs).unwrap_or_default();
+    let trash_cutoff: String = cutoff(now, config.trash_retention_secs).unwrap_or_default();
+
+    let tx = match client.transaction().await {
+        Ok(tx) => tx,
+        Err(_) => {
+            error!("Failed to start database transaction");
+            return Err(ApiError::DatabaseError(
+                "Failed to start database transaction".to_string(),
+            ));
+        }
+    };
+
+    let rows = match tx
+        .query(
+            "DELETE FROM document WHERE archived_at IS NOT NULL \
+             AND ((trashed_at IS NULL AND archived_at <= $1) OR trashed_at <= $2) RETURNING document_id",
+            &[&archived_cutoff, &trash_cutoff],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => {
+            error!("Failed to delete archived documents");
+            return Err(ApiError::DatabaseError(
+                "Failed to delete archived documents".to_string(),
+            ));
+        }
+    };
+    let purged: Vec<Uuid> = rows.iter().map(|row| row.get(0)).collect();
+    if purged.is_empty() {
+        return Ok(0);
+    }
+
+    for table in [
+        "operations",
+        "document_snapshots",
+        "document_quota",
+        "document_tags",
+        "document_activity",
+        "document_members",
+        "document_invitations",
+    ] {
+        let query = format!("DELETE FROM {} WHERE document_id = ANY($1)", table);
+        if tx.execute(&query, &[&purged]).await.is_err() {
+            error!("Failed to purge the {} table", table);
+            return Err(ApiError::DatabaseError(format!(
+                "Failed to purge the {} table",
+                table
+            )));
+        }
+    }
+
+    if tx.commit().await.is_err() {
+        error!("Failed to commit database transaction");
+        return Err(ApiError::DatabaseError(
+            "Failed to commit database transaction".to_string(),
+        ));
+    }
+
+    for document_id in &purged {
+        info!(document_id = %document_id, "Purged archived document");
+    }
+    Ok(purged.len())
+}
+
+/// Evicts the loaded documents that have been archived or purged, e.g. by another replica's reaper.
+/// Returns the evicted documents.
+#[instrument(name = "expiry.evict", skip_all)]
+pub async fn evict_archived(
+    client: &Client,
+    rgas: &mut HashMap<Uuid, RGA>,
+) -> Result<Vec<Uuid>, ApiError> {
+    let loaded: Vec<Uuid> = rgas.keys().copied().collect();
+    if loaded.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let rows = match client
+        .query(
+            "SELECT id FROM unnest($1::uuid[]) AS id WHERE NOT EXISTS \
+             (SELECT 1 FROM document WHERE document_id=id AND archived_at IS NULL)",
+            &[&loaded],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => {
+            error!("Failed to find archived documents");
+            return Err(ApiError::DatabaseError(
+                "Failed to find archived documents".to_string(),
+            ));
+        }
+    };
+
+    let evicted: Vec<Uuid> = rows.iter().map(|row| row.get(0)).collect();
+    for document_id in &evicted {
+        rgas.remove(document_id);
+        info!(document_id = %document_id, "Evicted archived document");
+    }
+    Ok(evicted)
+}
+
+/// Fairing that starts the reaper, a background task archiving expired documents and purging
+/// archived ones every `check_interval_secs`. Only the replica holding the reaper's lease archives
+/// and purges, every replica evicts the archived documents it has loaded.
+pub fn attach_reaper(config: ExpiryConfig) -> AdHoc {
+    AdHoc::on_liftoff("Document reaper", move |rocket| {
+        Box::pin(async move {
+            if config.check_interval_secs == 0 {
+                info!("Document reaper is disabled");
+                return;
+            }
+
+            let (rgas, db, leader, features) = match (
+                rocket.state::<SharedRGAs>(),
+                rocket.state::<Arc<Mutex<Client>>>(),
+                rocket.state::<Leader>(),
+                rocket.state::<Features>(),
+            ) {
+                (Some(rgas), Some(db), Some(leader), Some(features)) => (
+                    Arc::clone(rgas),
+                    Arc::clone(db),
+                    leader.clone(),
+                    features.clone(),
+                ),
+                _ => {
+                    warn!("Replica state is unavailable, the document reaper is disabled");
+                    return;
+                }
+            };
+            let mut shutdown = rocket.shutdown();
+
+            tokio::spawn(async move {
+                let mut interval =
+                    tokio::time::interval(Duration::from_secs(config.check_interval_secs));
+
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = &mut shutdown => break,
+                    }
+
+                    async {
+                        let now: DateTime<Utc> = Utc::now();
+                        let mut rgas = rgas.lock().await;
+                        let mut client = tenancy::lock_any(&db).await;
+
+                        // errors are logged, the next run retries
+                        if features.enabled(Feature::Expiry, &Tenant::Any)
+                            && leader
+                                .acquire(&client, REAPER_JOB, config.check_interval_secs)
+                                .await
+                        {
+                            let _ = archive_expired(&client, &mut rgas, now).await;
+                            let _ = purge_archived(&mut client, now, &config).await;
+                        }
+                        let _ = evict_archived(&client, &mut rgas).await;
+                    }
+                    .instrument(info_span!("expiry.reap"))
+                    .await;
+                }
+                leader.release(&*db.lock().await, REAPER_JOB).await;
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expires_at() {
+        let now: DateTime<Utc> = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .to_utc();
+
+        assert_eq!(expires_at(now, None).unwrap(), None);
+        assert_eq!(
+            expires_at(now, Some(90)).unwrap(),
+            Some("2024-01-01T00:01:30Z".to_string())
+        );
+        assert!(expires_at(now, Some(0)).is_err());
+        assert!(expires_at(now, Some(u64::MAX)).is_err());
+
+        // stored timestamps order the same way as the times they represent
+        assert!(timestamp(now) < expires_at(now, Some(1)).unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_cutoff() {
+        let now: DateTime<Utc> = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .to_utc();
+
+        assert_eq!(cutoff(now, 90), Some("2023-12-31T23:58:30Z".to_string()));
+        assert_eq!(cutoff(now, 0), Some(timestamp(now)));
+        assert_eq!(cutoff(now, u64::MAX), None);
+    }
+}
@@ -0,0 +1,517 @@
+//! Feature flags: capabilities that can be turned off per deployment or per organization
+//! without a separate build.
+//!
+//! Each feature is on or off in `[features]`. Rows of the `feature_flags` table override it for
+//! the whole deployment (`org_id` NULL) or for one organization, which wins over both. Every
+//! replica reloads the table every `features.refresh_interval_secs`, and the replica changing a
+//! flag through `POST /admin/features/<feature>` at once. Routes check the flag of the tenant
+//! they serve with [`Features::require`], background jobs and admin routes the deployment's.
+
+use crate::admin::{AdminConfig, AdminToken};
+use crate::limits::JsonBody;
+use crate::tenancy::{self, Tenant};
+use crate::{ApiError, RequestId};
+use rocket::fairing::AdHoc;
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use rocket::tokio::{self, time};
+use rocket::{delete, get, post};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+/// A capability behind a flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// `POST /admin/documents/<id>/compact`.
+    Compaction,
+    /// Running documents' code in the sandbox.
+    Execution,
+    /// The Yjs sync adapter.
+    Yjs,
+    /// Automerge export and import.
+    Automerge,
+    /// Document chat.
+    Chat,
+    /// Creating share links.
+    ShareLinks,
+    /// Scheduled backups.
+    Backups,
+    /// Archiving expired documents and purging archived ones.
+    Expiry,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 8] = [
+        Feature::Compaction,
+        Feature::Execution,
+        Feature::Yjs,
+        Feature::Automerge,
+        Feature::Chat,
+        Feature::ShareLinks,
+        Feature::Backups,
+        Feature::Expiry,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Feature::Compaction => "compaction",
+            Feature::Execution => "execution",
+            Feature::Yjs => "yjs",
+            Feature::Automerge => "automerge",
+            Feature::Chat => "chat",
+            Feature::ShareLinks => "share_links",
+            Feature::Backups => "backups",
+            Feature::Expiry => "expiry",
+        }
+    }
+
+    pub fn parse(feature: &str) -> Option<Feature> {
+        Feature::ALL
+            .into_iter()
+            .find(|candidate| candidate.as_str() == feature)
+    }
+}
+
+/// Whether each feature is on when no override says otherwise.
+/// `refresh_interval_secs`: Seconds between reloads of the `feature_flags` table, 0 only reads
+/// it on startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeaturesConfig {
+    pub compaction: bool,
+    pub execution: bool,
+    pub yjs: bool,
+    pub automerge: bool,
+    pub chat: bool,
+    pub share_links: bool,
+    pub backups: bool,
+    pub expiry: bool,
+    pub refresh_interval_secs: u64,
+}
+
+impl Default for FeaturesConfig {
+    fn default() -> Self {
+        FeaturesConfig {
+            compaction: true,
+            execution: true,
+            yjs: true,
+            automerge: true,
+            chat: true,
+            share_links: true,
+            backups: true,
+            expiry: true,
+            refresh_interval_secs: 30,
+        }
+    }
+}
+
+impl FeaturesConfig {
+    pub fn enabled(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::Compaction => self.compaction,
+            Feature::Execution => self.execution,
+            Feature::Yjs => self.yjs,
+            Feature::Automerge => self.automerge,
+            Feature::Chat => self.chat,
+            Feature::ShareLinks => self.share_links,
+            Feature::Backups => self.backups,
+            Feature::Expiry => self.expiry,
+        }
+    }
+}
+
+/// The overrides read from the `feature_flags` table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Overrides {
+    pub deployment: HashMap<Feature, bool>,
+    pub orgs: HashMap<(Uuid, Feature), bool>,
+}
+
+/// Where a flag's value comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlagSource {
+    Config,
+    Deployment,
+    Organization,
+}
+
+/// The feature flags, managed in Rocket's state.
+#[derive(Debug, Clone)]
+pub struct Features {
+    config: FeaturesConfig,
+    overrides: Arc<RwLock<Overrides>>,
+}
+
+impl Features {
+    pub fn new(config: FeaturesConfig) -> Self {
+        Features {
+            config,
+            overrides: Arc::new(RwLock::new(Overrides::default())),
+        }
+    }
+
+    /// Whether a feature is on for a tenant and where that comes from. `Tenant::Any` and
+    /// `Tenant::Personal` only see the deployment's flags.
+    pub fn resolve(&self, feature: Feature, tenant: &Tenant) -> (bool, FlagSource) {
+        let overrides = self.overrides.read().unwrap_or_else(|e| e.into_inner());
+        if let Tenant::Org(org_id) = tenant {
+            if let Some(enabled) = overrides.orgs.get(&(*org_id, feature)) {
+                return (*enabled, FlagSource::Organization);
+            }
+        }
+        match overrides.deployment.get(&feature) {
+            Some(enabled) => (*enabled, FlagSource::Deployment),
+            None => (self.config.enabled(feature), FlagSource::Config),
+        }
+    }
+
+    /// Whether a feature is on for a tenant.
+    pub fn enabled(&self, feature: Feature, tenant: &Tenant) -> bool {
+        self.resolve(feature, tenant).0
+    }
+
+    /// Returns `Forbidden` if a feature is off for a tenant.
+    pub fn require(&self, feature: Feature, tenant: &Tenant) -> Result<(), ApiError> {
+        if self.enabled(feature, tenant) {
+            return Ok(());
+        }
+        error!(feature = feature.as_str(), "Request for a disabled feature");
+        Err(ApiError::Forbidden(format!(
+            "The {} feature is disabled",
+            feature.as_str()
+        )))
+    }
+
+    /// Replaces the overrides with the `feature_flags` table. Rows naming unknown features are
+    /// skipped, so replicas running an older build ignore flags they don't have.
+    #[instrument(name = "flags.reload", skip_all)]
+    pub async fn reload(&self, client: &Client) -> Result<(), ApiError> {
+        let rows = match client
+            .query("SELECT feature,org_id,enabled FROM feature_flags", &[])
+            .await
+        {
+            Ok(rows) => rows,
+            Err(_) => {
+                error!("Failed to read the feature_flags table");
+                return Err(ApiError::DatabaseError(
+                    "Failed to read the feature_flags table".to_string(),
+                ));
+            }
+        };
+
+        let mut overrides = Overrides::default();
+        for row in rows {
+            let Some(feature) = Feature::parse(row.get(0)) else {
+                continue;
+            };
+            match row.get::<_, Option<Uuid>>(1) {
+                Some(org_id) => overrides.orgs.insert((org_id, feature), row.get(2)),
+                None => overrides.deployment.insert(feature, row.get(2)),
+            };
+        }
+        *self.overrides.write().unwrap_or_else(|e| e.into_inner()) = overrides;
+        Ok(())
+    }
+}
+
+/// Fairing that reads the `feature_flags` table on startup and reloads it every
+/// `refresh_interval_secs`. The replica starts with the configured flags if it can't be read.
+pub fn attach_features(config: FeaturesConfig) -> AdHoc {
+    AdHoc::on_liftoff("Feature flags", move |rocket| {
+        Box::pin(async move {
+            let (features, db) = match (
+                rocket.state::<Features>(),
+                rocket.state::<Arc<Mutex<Client>>>(),
+            ) {
+                (Some(features), Some(db)) => (features.clone(), Arc::clone(db)),
+                _ => {
+                    warn!("Replica state is unavailable, feature flag overrides are disabled");
+                    return;
+                }
+            };
+            if let Err(e) = features.reload(&*db.lock().await).await {
+                warn!("Starting with the configured feature flags: {}", e);
+            }
+            if config.refresh_interval_secs == 0 {
+                return;
+            }
+            let mut shutdown = rocket.shutdown();
+
+            tokio::spawn(async move {
+                let mut interval =
+                    time::interval(Duration::from_secs(config.refresh_interval_secs));
+                interval.tick().await;
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = &mut shutdown => break,
+                    }
+                    if let Err(e) = features.reload(&*db.lock().await).await {
+                        warn!("Keeping the previous feature flags: {}", e);
+                    }
+                }
+            });
+        })
+    })
+}
+
+/// A feature's value.
+/// `org_id`: The organization it was resolved for, None for the deployment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeatureFlag {
+    pub feature: Feature,
+    pub org_id: Option<Uuid>,
+    pub enabled: bool,
+    pub source: FlagSource,
+}
+
+/// Request body for overriding a flag.
+/// `org_id`: The organization to override it for, the whole deployment if unset.
+#[derive(Debug, Deserialize)]
+pub struct FeatureFlagRequest {
+    #[serde(default)]
+    pub org_id: Option<Uuid>,
+    pub enabled: bool,
+}
+
+fn parse_feature(feature: &str) -> Result<Feature, ApiError> {
+    match Feature::parse(feature) {
+        Some(feature) => Ok(feature),
+        None => {
+            error!("Unknown feature {}", feature);
+            Err(ApiError::NotFound(format!("Unknown feature {}", feature)))
+        }
+    }
+}
+
+fn parse_org_id(org_id: Option<String>) -> Result<Option<Uuid>, ApiError> {
+    match org_id.map(|id| Uuid::parse_str(&id)) {
+        None => Ok(None),
+        Some(Ok(org_id)) => Ok(Some(org_id)),
+        Some(Err(_)) => {
+            error!("Failed to parse organization id");
+            Err(ApiError::InvalidOperation(
+                "Failed to parse organization id".to_string(),
+            ))
+        }
+    }
+}
+
+fn tenant_of(org_id: Option<Uuid>) -> Tenant {
+    match org_id {
+        Some(org_id) => Tenant::Org(org_id),
+        None => Tenant::Any,
+    }
+}
+
+/// Lists every feature's value for the deployment, or for an organization.
+///
+/// Example Response:
+/// [
+///     { "feature" : "compaction", "org_id" : null, "enabled" : true, "source" : "config" },
+///     { "feature" : "execution", "org_id" : null, "enabled" : false, "source" : "deployment" }
+/// ]
+#[get("/admin/features?<org_id>")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn list_features(
+    org_id: Option<String>,
+    token: AdminToken,
+    admin: &rocket::State<AdminConfig>,
+    features: &rocket::State<Features>,
+    request_id: RequestId,
+) -> Result<Json<Vec<FeatureFlag>>, ApiError> {
+    token.require(admin)?;
+    let org_id: Option<Uuid> = parse_org_id(org_id)?;
+
+    Ok(Json(
+        Feature::ALL
+            .into_iter()
+            .map(|feature| {
+                let (enabled, source) = features.resolve(feature, &tenant_of(org_id));
+                FeatureFlag {
+                    feature,
+                    org_id,
+                    enabled,
+                    source,
+                }
+            })
+            .collect(),
+    ))
+}
+
+/// Writes an override in a transaction, replacing the previous one. `enabled` None only
+/// removes it.
+async fn write_override(
+    client: &mut Client,
+    feature: Feature,
+    org_id: Option<Uuid>,
+    enabled: Option<bool>,
+) -> Result<(), ApiError> {
+    let tx = match client.transaction().await {
+        Ok(tx) => tx,
+        Err(_) => {
+            error!("Failed to create database transaction");
+            return Err(ApiError::DatabaseError(
+                "Failed to create database transaction".to_string(),
+            ));
+        }
+    };
+    if tx
+        .execute(
+            "DELETE FROM feature_flags WHERE feature=$1 AND org_id IS NOT DISTINCT FROM $2",
+            &[&feature.as_str(), &org_id],
+        )
+        .await
+        .is_err()
+    {
+        error!("Failed to delete from the feature_flags table");
+        return Err(ApiError::DatabaseError(
+            "Failed to delete from the feature_flags table".to_string(),
+        ));
+    }
+    if let Some(enabled) = enabled {
+        if tx
+            .execute(
+                "INSERT INTO feature_flags (feature,org_id,enabled,updated_at) VALUES ($1,$2,$3,$4)",
+                &[
+                    &feature.as_str(),
+                    &org_id,
+                    &enabled,
+                    &chrono::Utc::now().to_rfc3339(),
+                ],
+            )
+            .await
+            .is_err()
+        {
+            error!("Failed to insert into the feature_flags table");
+            return Err(ApiError::DatabaseError(
+                "Failed to insert into the feature_flags table".to_string(),
+            ));
+        }
+    }
+    if tx.commit().await.is_err() {
+        error!("Failed to commit database transaction");
+        return Err(ApiError::DatabaseError(
+            "Failed to commit database transaction".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Turns a feature on or off for the deployment or an organization. Other replicas pick the
+/// change up within `features.refresh_interval_secs`.
+///
+/// Example Request:
+/// {
+///     "org_id": "6a0f1c3e-2b4d-4e8f-9a1b-3c5d7e9f1a2b",
+///     "enabled": false
+/// }
+#[post("/admin/features/<feature>", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id, feature = %feature))]
+pub async fn set_feature(
+    feature: String,
+    request: JsonBody<FeatureFlagRequest>,
+    token: AdminToken,
+    admin: &rocket::State<AdminConfig>,
+    features: &rocket::State<Features>,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<FeatureFlag>, ApiError> {
+    token.require(admin)?;
+    let feature: Feature = parse_feature(&feature)?;
+
+    let mut client = tenancy::lock_any(db).await;
+    write_override(&mut client, feature, request.org_id, Some(request.enabled)).await?;
+    features.reload(&client).await?;
+
+    info!(org_id = ?request.org_id, enabled = request.enabled, "Feature flag overridden");
+    let (enabled, source) = features.resolve(feature, &tenant_of(request.org_id));
+    Ok(Json(FeatureFlag {
+        feature,
+        org_id: request.org_id,
+        enabled,
+        source,
+    }))
+}
+
+/// Removes the override of a feature for the deployment or an organization, returning the
+/// value it falls back to.
+#[delete("/admin/features/<feature>?<org_id>")]
+#[instrument(skip_all, fields(request_id = %request_id, feature = %feature))]
+pub async fn clear_feature(
+    feature: String,
+    org_id: Option<String>,
+    token: AdminToken,
+    admin: &rocket::State<AdminConfig>,
+    features: &rocket::State<Features>,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<FeatureFlag>, ApiError> {
+    token.require(admin)?;
+    let feature: Feature = parse_feature(&feature)?;
+    let org_id: Option<Uuid> = parse_org_id(org_id)?;
+
+    let mut client = tenancy::lock_any(db).await;
+    write_override(&mut client, feature, org_id, None).await?;
+    features.reload(&client).await?;
+
+    info!(org_id = ?org_id, "Feature flag override removed");
+    let (enabled, source) = features.resolve(feature, &tenant_of(org_id));
+    Ok(Json(FeatureFlag {
+        feature,
+        org_id,
+        enabled,
+        source,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        for feature in Feature::ALL {
+            assert_eq!(Feature::parse(feature.as_str()), Some(feature));
+        }
+        assert_eq!(Feature::parse("time_travel"), None);
+
+        let features = Features::new(FeaturesConfig {
+            execution: false,
+            ..FeaturesConfig::default()
+        });
+        let (org_id, other) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(
+            features.resolve(Feature::Execution, &Tenant::Org(org_id)),
+            (false, FlagSource::Config)
+        );
+        assert!(features.require(Feature::Yjs, &Tenant::Personal).is_ok());
+
+        *features.overrides.write().unwrap() = Overrides {
+            deployment: HashMap::from([(Feature::Yjs, false)]),
+            orgs: HashMap::from([
+                ((org_id, Feature::Execution), true),
+                ((org_id, Feature::Yjs), true),
+            ]),
+        };
+        // an organization's override wins over the deployment's and the config
+        assert_eq!(
+            features.resolve(Feature::Yjs, &Tenant::Org(org_id)),
+            (true, FlagSource::Organization)
+        );
+        assert!(features.enabled(Feature::Execution, &Tenant::Org(org_id)));
+        assert_eq!(
+            features.resolve(Feature::Yjs, &Tenant::Org(other)),
+            (false, FlagSource::Deployment)
+        );
+        assert!(features.require(Feature::Yjs, &Tenant::Personal).is_err());
+        assert!(!features.enabled(Feature::Execution, &Tenant::Any));
+    }
+}
@@ -0,0 +1,84 @@
+//! The address of the client behind the load balancer.
+//!
+//! The load balancer appends the address it received each request from to the standard
+//! `X-Forwarded-For` and `Forwarded` headers. Entries to the left of it were sent by the
+//! client (or proxies in front of it) and can't be trusted, so the original client is the
+//! last entry.
+
+use rocket::Request;
+use std::net::{IpAddr, SocketAddr};
+
+/// Header listing the addresses a request was forwarded for, oldest first.
+pub const X_FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+/// Standard header (RFC 7239) carrying the same addresses as `for=` parameters.
+pub const FORWARDED_HEADER: &str = "Forwarded";
+
+/// Returns the address of the client that sent the request to the load balancer.
+/// Falls back to the peer address when the request did not come through the load balancer.
+pub fn client_ip(request: &Request<'_>) -> Option<IpAddr> {
+    let x_forwarded_for: Vec<&str> = request.headers().get(X_FORWARDED_FOR_HEADER).collect();
+    let forwarded: Vec<&str> = request.headers().get(FORWARDED_HEADER).collect();
+
+    forwarded_client_ip(&x_forwarded_for.join(","), &forwarded.join(","))
+        .or_else(|| request.client_ip())
+}
+
+/// Finds the last address in `X-Forwarded-For`, or in the `for=` parameters of `Forwarded`
+/// if there is none. Obfuscated identifiers such as `unknown` are skipped.
+pub fn forwarded_client_ip(x_forwarded_for: &str, forwarded: &str) -> Option<IpAddr> {
+    let from_x_forwarded_for = x_forwarded_for.split(',').rev().find_map(parse_node);
+    from_x_forwarded_for.or_else(|| {
+        forwarded
+            .split(',')
+            .rev()
+            .flat_map(|element| element.split(';'))
+            .filter_map(|pair| pair.split_once('='))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+            .find_map(|(_, node)| parse_node(node))
+    })
+}
+
+/// Parses a node: an IP address, optionally quoted, bracketed (IPv6) or with a port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(socket) = node.parse::<SocketAddr>() {
+        return Some(socket.ip());
+    }
+    node.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forwarded_client_ip() {
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+
+        assert_eq!(forwarded_client_ip("10.0.0.12", ""), ip("10.0.0.12"));
+        // entries before the load balancer's are client supplied
+        assert_eq!(
+            forwarded_client_ip("1.2.3.4, 10.0.0.12", ""),
+            ip("10.0.0.12")
+        );
+        assert_eq!(
+            forwarded_client_ip("10.0.0.12:53122, unknown", ""),
+            ip("10.0.0.12")
+        );
+
+        assert_eq!(
+            forwarded_client_ip("", "for=1.2.3.4, for=\"[2001:db8::1]:4711\";proto=http"),
+            ip("2001:db8::1")
+        );
+        assert_eq!(
+            forwarded_client_ip("", "For=10.0.0.12;host=example.com, for=_hidden"),
+            ip("10.0.0.12")
+        );
+        assert_eq!(forwarded_client_ip("", "proto=http"), None);
+        assert_eq!(forwarded_client_ip("", ""), None);
+    }
+}
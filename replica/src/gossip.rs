@@ -0,0 +1,479 @@
+//! Membership of the replicas in the cluster, maintained by gossiping heartbeats.
+//!
+//! Every `interval_secs` a replica bumps its own heartbeat and sends its view of the cluster to
+//! `fanout` members over `POST /internal/gossip`. The receiver merges the view into its own and
+//! answers with the result, so both sides learn of each other's members. A member whose
+//! heartbeat stops increasing is suspected after `suspect_after_secs`, considered failed after
+//! `fail_after_secs` and forgotten after `forget_after_secs`. A replica that restarts gossips a
+//! new generation, so its heartbeat starting from 0 again is not mistaken for an old one.
+
+use crate::admin::constant_time_eq;
+use crate::limits::JsonBody;
+use crate::{AdminToken, ApiError, RequestId};
+use rocket::fairing::AdHoc;
+use rocket::post;
+use rocket::serde::json::Json;
+use rocket::tokio::{self, time};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, info_span, instrument, warn, Instrument};
+
+/// Gossip between replicas.
+/// `address`: The URL other replicas reach this replica at, gossip is disabled if unset.
+/// `seeds`: Addresses of replicas to gossip with until their members are known.
+/// `token`: The bearer token sent with gossip and required of incoming gossip.
+/// `allow_unauthenticated`: Whether gossip may run without a `token`, accepting any peer.
+/// `interval_secs`: Seconds between gossip rounds.
+/// `fanout`: Members gossiped with each round.
+/// `suspect_after_secs`: Seconds without a newer heartbeat before a member is suspected.
+/// `fail_after_secs`: Seconds without a newer heartbeat before a member is considered failed.
+/// `forget_after_secs`: Seconds without a newer heartbeat before a member is removed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GossipConfig {
+    pub address: Option<String>,
+    pub seeds: Vec<String>,
+    pub token: Option<String>,
+    pub allow_unauthenticated: bool,
+    pub interval_secs: u64,
+    pub fanout: usize,
+    pub suspect_after_secs: u64,
+    pub fail_after_secs: u64,
+    pub forget_after_secs: u64,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        GossipConfig {
+            address: None,
+            seeds: Vec::new(),
+            token: None,
+            allow_unauthenticated: false,
+            interval_secs: 1,
+            fanout: 3,
+            suspect_after_secs: 5,
+            fail_after_secs: 15,
+            forget_after_secs: 10 * 60,
+        }
+    }
+}
+
+/// How a member is doing, judged by how long ago its heartbeat last increased.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Failed,
+}
+
+/// A member as it is gossiped.
+/// `replica_id`: The member's replica id.
+/// `address`: The URL the member is reached at.
+/// `generation`: When the member started (milliseconds since the epoch).
+/// `heartbeat`: The member's heartbeat within its generation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemberDigest {
+    pub replica_id: i64,
+    pub address: String,
+    pub generation: i64,
+    pub heartbeat: u64,
+}
+
+/// Request and response body of `POST /internal/gossip`.
+/// `members`: The sender's view of the cluster, including itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GossipMessage {
+    pub members: Vec<MemberDigest>,
+}
+
+/// A member as this replica sees it.
+/// `state`: Whether the member is alive, suspected or failed.
+/// `last_heard_ms`: Milliseconds since the member's heartbeat last increased.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemberView {
+    #[serde(flatten)]
+    pub member: MemberDigest,
+    pub state: MemberState,
+    pub last_heard_ms: u64,
+}
+
+struct Member {
+    digest: MemberDigest,
+    last_heard: Instant,
+    state: MemberState,
+}
+
+struct MembershipState {
+    local: MemberDigest,
+    members: BTreeMap<i64, Member>,
+    round: usize,
+}
+
+/// This replica's view of the cluster, managed in Rocket's state.
+#[derive(Clone)]
+pub struct Membership {
+    state: Arc<std::sync::Mutex<MembershipState>>,
+}
+
+impl Membership {
+    pub fn new(replica_id: i64, address: Option<String>) -> Self {
+        let local = MemberDigest {
+            replica_id,
+            address: address.unwrap_or_default(),
+            generation: chrono::Utc::now().timestamp_millis(),
+            heartbeat: 0,
+        };
+        Membership {
+            state: Arc::new(std::sync::Mutex::new(MembershipState {
+                local,
+                members: BTreeMap::new(),
+                round: 0,
+            })),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MembershipState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The view gossiped to other members: this replica and every member that hasn't failed.
+    pub fn digest(&self) -> GossipMessage {
+        let state = self.lock();
+        let mut members: Vec<MemberDigest> = vec![state.local.clone()];
+        members.extend(
+            state
+                .members
+                .values()
+                .filter(|member| member.state != MemberState::Failed)
+                .map(|member| member.digest.clone()),
+        );
+        GossipMessage { members }
+    }
+
+    /// Merges a view received from another member. Members with a newer heartbeat are alive.
+    pub fn merge(&self, message: &GossipMessage, now: Instant) {
+        let mut state = self.lock();
+        let local_id: i64 = state.local.replica_id;
+        for digest in &message.members {
+            if digest.replica_id == local_id {
+                continue;
+            }
+            match state.members.get_mut(&digest.replica_id) {
+                Some(member) => {
+                    if (digest.generation, digest.heartbeat)
+                        > (member.digest.generation, member.digest.heartbeat)
+                    {
+                        if member.state != MemberState::Alive {
+                            info!(replica_id = digest.replica_id, "Member is alive again");
+                        }
+                        member.digest = digest.clone();
+                        member.last_heard = now;
+                        member.state = MemberState::Alive;
+                    }
+                }
+                None => {
+                    info!(replica_id = digest.replica_id, address = %digest.address, "Member joined");
+                    state.members.insert(
+                        digest.replica_id,
+                        Member {
+                            digest: digest.clone(),
+                            last_heard: now,
+                            state: MemberState::Alive,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Starts a gossip round: bumps this replica's heartbeat, updates the state of the other
+    /// members and returns the addresses to gossip with.
+    pub fn tick(&self, config: &GossipConfig, now: Instant) -> Vec<String> {
+        let mut state = self.lock();
+        state.local.heartbeat += 1;
+
+        let (suspect, fail, forget) = (
+            Duration::from_secs(config.suspect_after_secs),
+            Duration::from_secs(config.fail_after_secs),
+            Duration::from_secs(config.forget_after_secs),
+        );
+        state.members.retain(|replica_id, member| {
+            let silent: Duration = now.saturating_duration_since(member.last_heard);
+            let next: MemberState = if silent >= fail {
+                MemberState::Failed
+            } else if silent >= suspect {
+                MemberState::Suspect
+            } else {
+                MemberState::Alive
+            };
+            if next != member.state {
+                warn!(replica_id, state = ?next, "Member state changed");
+                member.state = next;
+            }
+            if silent >= forget {
+                info!(replica_id, "Forgot failed member");
+            }
+            silent < forget
+        });
+
+        // seeds are gossiped with until members are known, then members are taken in turn
+        let addresses: Vec<String> = state
+            .members
+            .values()
+            .filter(|member| member.state != MemberState::Failed)
+            .map(|member| member.digest.address.clone())
+            .collect();
+        if addresses.is_empty() {
+            return config.seeds.clone();
+        }
+        let round: usize = state.round;
+        state.round = round.wrapping_add(config.fanout);
+        (0..config.fanout.min(addresses.len()))
+            .map(|i| addresses[(round + i) % addresses.len()].clone())
+            .collect()
+    }
+
+    /// Every known member, starting with this replica.
+    pub fn view(&self, now: Instant) -> Vec<MemberView> {
+        let state = self.lock();
+        let mut view: Vec<MemberView> = vec![MemberView {
+            member: state.local.clone(),
+            state: MemberState::Alive,
+            last_heard_ms: 0,
+        }];
+        view.extend(state.members.values().map(|member| MemberView {
+            member: member.digest.clone(),
+            state: member.state,
+            last_heard_ms: now.saturating_duration_since(member.last_heard).as_millis() as u64,
+        }));
+        view
+    }
+}
+
+/// Returns `Unauthorized` unless the request sent the gossip token, or no token is configured
+/// and `allow_unauthenticated` is set.
+pub(crate) fn require_token(token: &AdminToken, config: &GossipConfig) -> Result<(), ApiError> {
+    match (&config.token, &token.0) {
+        (None, _) if config.allow_unauthenticated => Ok(()),
+        (Some(expected), Some(token))
+            if constant_time_eq(token.as_bytes(), expected.as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => {
+            warn!("Gossip with a missing or invalid token");
+            Err(ApiError::Unauthorized(
+                "A valid gossip bearer token is required".to_string(),
+            ))
+        }
+    }
+}
+
+/// Receives another member's view of the cluster and answers with this replica's.
+#[post("/internal/gossip", data = "<message>")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn receive_gossip(
+    message: JsonBody<GossipMessage>,
+    token: AdminToken,
+    config: &rocket::State<GossipConfig>,
+    membership: &rocket::State<Membership>,
+    request_id: RequestId,
+) -> Result<Json<GossipMessage>, ApiError> {
+    if config.address.is_none() {
+        return Err(ApiError::Forbidden(
+            "Gossip is disabled on this replica".to_string(),
+        ));
+    }
+    require_token(&token, config)?;
+
+    membership.merge(&message, Instant::now());
+    Ok(Json(membership.digest()))
+}
+
+async fn gossip_with(
+    http: &reqwest::Client,
+    address: &str,
+    config: &GossipConfig,
+    message: &GossipMessage,
+) -> Option<GossipMessage> {
+    let body: Vec<u8> = serde_json::to_vec(message).ok()?;
+    let mut request = http
+        .post(format!("{}/internal/gossip", address.trim_end_matches('/')))
+        .header("Content-Type", "application/json")
+        .body(body);
+    if let Some(token) = &config.token {
+        request = request.bearer_auth(token);
+    }
+
+    let response = match request.send().await {
+        Ok(response) if response.status().is_success() => response,
+        Ok(response) => {
+            debug!(address, status = %response.status(), "Gossip was rejected");
+            return None;
+        }
+        Err(e) => {
+            debug!(address, "Failed to gossip: {}", e);
+            return None;
+        }
+    };
+    let bytes = response.bytes().await.ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// Fairing that starts the gossip rounds, if `gossip.address` is set.
+/// The task stops when Rocket begins shutting down.
+pub fn attach_gossip(config: GossipConfig) -> AdHoc {
+    AdHoc::on_liftoff("Gossip membership", move |rocket| {
+        Box::pin(async move {
+            if config.address.is_none() || config.interval_secs == 0 {
+                info!("Gossip is disabled");
+                return;
+            }
+            let membership: Membership = match rocket.state::<Membership>() {
+                Some(membership) => membership.clone(),
+                None => {
+                    warn!("Replica state is unavailable, gossip is disabled");
+                    return;
+                }
+            };
+            let mut shutdown = rocket.shutdown();
+            let http = reqwest::Client::builder()
+                .timeout(Duration::from_secs(config.interval_secs.max(1)))
+                .build()
+                .unwrap_or_default();
+
+            tokio::spawn(async move {
+                let mut interval = time::interval(Duration::from_secs(config.interval_secs));
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = &mut shutdown => break,
+                    }
+
+                    async {
+                        let targets: Vec<String> = membership.tick(&config, Instant::now());
+                        let message: GossipMessage = membership.digest();
+                        let replies = targets
+                            .iter()
+                            .map(|address| gossip_with(&http, address, &config, &message));
+                        for reply in rocket::futures::future::join_all(replies)
+                            .await
+                            .into_iter()
+                            .flatten()
+                        {
+                            membership.merge(&reply, Instant::now());
+                        }
+                    }
+                    .instrument(info_span!("gossip.round"))
+                    .await;
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(replica_id: i64, generation: i64, heartbeat: u64) -> MemberDigest {
+        MemberDigest {
+            replica_id,
+            address: format!("http://replica-{}", replica_id),
+            generation,
+            heartbeat,
+        }
+    }
+
+    #[test]
+    fn test_membership() {
+        let config = GossipConfig {
+            address: Some("http://replica-1".to_string()),
+            seeds: vec!["http://seed".to_string()],
+            fanout: 1,
+            ..GossipConfig::default()
+        };
+        let membership = Membership::new(1, config.address.clone());
+        let start = Instant::now();
+        assert_eq!(membership.tick(&config, start), vec!["http://seed"]);
+
+        membership.merge(
+            &GossipMessage {
+                members: vec![digest(1, 0, 99), digest(2, 10, 4), digest(3, 10, 7)],
+            },
+            start,
+        );
+        assert_eq!(membership.digest().members.len(), 3);
+        assert_eq!(membership.digest().members[0].heartbeat, 1);
+        // members are taken in turn
+        assert_eq!(membership.tick(&config, start), vec!["http://replica-2"]);
+        assert_eq!(membership.tick(&config, start), vec!["http://replica-3"]);
+
+        // replica 2 keeps its heartbeat going, replica 3 goes quiet
+        let later = start + Duration::from_secs(6);
+        membership.merge(
+            &GossipMessage {
+                members: vec![digest(2, 10, 5)],
+            },
+            later,
+        );
+        membership.tick(&config, later);
+        let states: Vec<MemberState> = membership.view(later).iter().map(|m| m.state).collect();
+        assert_eq!(
+            states,
+            vec![MemberState::Alive, MemberState::Alive, MemberState::Suspect]
+        );
+
+        // an old heartbeat doesn't revive a member, a new generation does
+        let failed = start + Duration::from_secs(16);
+        membership.merge(
+            &GossipMessage {
+                members: vec![digest(3, 10, 6)],
+            },
+            failed,
+        );
+        membership.tick(&config, failed);
+        assert_eq!(membership.view(failed)[2].state, MemberState::Failed);
+        assert!(!membership
+            .digest()
+            .members
+            .iter()
+            .any(|m| m.replica_id == 3));
+        membership.merge(
+            &GossipMessage {
+                members: vec![digest(3, 20, 0)],
+            },
+            failed,
+        );
+        assert_eq!(membership.view(failed)[2].state, MemberState::Alive);
+
+        membership.tick(&config, start + Duration::from_secs(10 * 60 + 16));
+        assert_eq!(membership.view(start).len(), 1);
+    }
+
+    #[test]
+    fn test_require_token() {
+        // without a token gossip is refused, unless it is explicitly allowed
+        let mut config = GossipConfig::default();
+        assert!(matches!(
+            require_token(&AdminToken(Some("anything".to_string())), &config),
+            Err(ApiError::Unauthorized(_))
+        ));
+        config.allow_unauthenticated = true;
+        assert!(require_token(&AdminToken(None), &config).is_ok());
+
+        config.token = Some("gossip-token".to_string());
+        assert!(require_token(&AdminToken(Some("gossip-token".to_string())), &config).is_ok());
+        assert!(matches!(
+            require_token(&AdminToken(None), &config),
+            Err(ApiError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            require_token(&AdminToken(Some("gossip-tokem".to_string())), &config),
+            Err(ApiError::Unauthorized(_))
+        ));
+    }
+}
@@ -0,0 +1,272 @@
+//! Readiness probe for orchestration systems.
+//!
+//! `GET /ready` checks the dependencies a replica needs to serve edits, rather than only
+//! whether the process is up:
+//! - `database`: a `SELECT 1` round trip on the replica's connection.
+//! - `broadcaster`: the SNS topic's attributes can be read with the replica's credentials.
+//! - `documents`: the loaded RGAs are within the memory cap, so new documents can be loaded.
+//!
+//! Responds `200` when every component is ready and `503` otherwise, with the status of each
+//! component. Details are kept generic since the route is public, the errors are logged.
+
+use crate::routes::SharedRGAs;
+use crate::MemoryConfig;
+use aws_sdk_sns::Client as SnsClient;
+use rocket::get;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use rocket::tokio::time::timeout;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_postgres::Client;
+use tracing::{error, instrument, warn};
+
+/// `timeout_ms`: How long each component check may take before it is reported as failed.
+/// `check_broadcaster`: Whether the SNS topic is checked (disable for local development).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReadinessConfig {
+    pub timeout_ms: u64,
+    pub check_broadcaster: bool,
+}
+
+impl Default for ReadinessConfig {
+    fn default() -> Self {
+        ReadinessConfig {
+            timeout_ms: 2_000,
+            check_broadcaster: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ComponentState {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+/// The status of one component.
+/// `name`: The component checked.
+/// `status`: Whether it is ready (`skipped` components don't affect readiness).
+/// `detail`: A short description of the result.
+/// `latency_ms`: How long the check took.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ComponentStatus {
+    pub name: String,
+    pub status: ComponentState,
+    pub detail: String,
+    pub latency_ms: u64,
+}
+
+/// `ready`: True if no component failed.
+/// `components`: The status of each component.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub components: Vec<ComponentStatus>,
+}
+
+impl Readiness {
+    pub fn new(components: Vec<ComponentStatus>) -> Self {
+        Readiness {
+            ready: components
+                .iter()
+                .all(|component| component.status != ComponentState::Failed),
+            components,
+        }
+    }
+}
+
+/// Runs a component check, failing it if it takes longer than `limit`.
+async fn check<F>(name: &str, limit: Duration, check: F) -> ComponentStatus
+where
+    F: Future<Output = Result<String, String>>,
+{
+    let start = Instant::now();
+    let (status, detail) = match timeout(limit, check).await {
+        Ok(Ok(detail)) => (ComponentState::Ok, detail),
+        Ok(Err(detail)) => (ComponentState::Failed, detail),
+        Err(_) => (
+            ComponentState::Failed,
+            format!("timed out after {}ms", limit.as_millis()),
+        ),
+    };
+    if status == ComponentState::Failed {
+        warn!(component = name, "Readiness check failed: {}", detail);
+    }
+
+    ComponentStatus {
+        name: name.to_string(),
+        status,
+        detail,
+        latency_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+async fn check_database(db: &Arc<Mutex<Client>>) -> Result<String, String> {
+    let client = db.lock().await;
+    if client.is_closed() {
+        error!("The database connection is closed");
+        return Err("connection closed".to_string());
+    }
+
+    match client.simple_query("SELECT 1").await {
+        Ok(_) => Ok("reachable".to_string()),
+        Err(e) => {
+            error!("Readiness query failed: {}", e);
+            Err("query failed".to_string())
+        }
+    }
+}
+
+async fn check_broadcaster(
+    sns_client: &Arc<Mutex<SnsClient>>,
+    topic_arn: &Arc<Mutex<String>>,
+) -> Result<String, String> {
+    let topic_arn: String = topic_arn.lock().await.clone();
+    let sns_client = sns_client.lock().await;
+
+    match sns_client
+        .get_topic_attributes()
+        .topic_arn(topic_arn)
+        .send()
+        .await
+    {
+        Ok(_) => Ok("topic reachable".to_string()),
+        Err(e) => {
+            error!("Failed to read the SNS topic attributes: {}", e);
+            Err("topic unreachable or access denied".to_string())
+        }
+    }
+}
+
+/// Fails once the loaded documents use more memory than the cap allows.
+fn check_memory(documents: usize, total_bytes: usize, max_bytes: usize) -> Result<String, String> {
+    let detail: String = if max_bytes == 0 {
+        format!("{} documents loaded, {} bytes", documents, total_bytes)
+    } else {
+        format!(
+            "{} documents loaded, {} of {} bytes",
+            documents, total_bytes, max_bytes
+        )
+    };
+
+    if max_bytes > 0 && total_bytes > max_bytes {
+        Err(detail)
+    } else {
+        Ok(detail)
+    }
+}
+
+async fn check_documents(rgas: &SharedRGAs, config: &MemoryConfig) -> Result<String, String> {
+    let rgas = rgas.lock().await;
+    let mut total_bytes: usize = 0;
+    for rga in rgas.values() {
+        total_bytes += rga.memory_usage().approx_bytes;
+    }
+    check_memory(rgas.len(), total_bytes, config.max_bytes)
+}
+
+/// Reports whether the replica is ready to serve edits, for load balancers and orchestrators.
+///
+/// Example Response
+/// {
+///     "ready" : false,
+///     "components" : [
+///         { "name" : "database", "status" : "ok", "detail" : "reachable", "latency_ms" : 1 },
+///         { "name" : "broadcaster", "status" : "failed", "detail" : "timed out after 2000ms", "latency_ms" : 2000 },
+///         { "name" : "documents", "status" : "ok", "detail" : "12 documents loaded, 1048576 of 268435456 bytes", "latency_ms" : 0 }
+///     ]
+/// }
+#[get("/ready")]
+#[instrument(skip_all)]
+pub async fn ready(
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    sns_client: &rocket::State<Arc<Mutex<SnsClient>>>,
+    topic_arn: &rocket::State<Arc<Mutex<String>>>,
+    rgas: &rocket::State<SharedRGAs>,
+    memory: &rocket::State<MemoryConfig>,
+    config: &rocket::State<ReadinessConfig>,
+) -> (Status, Json<Readiness>) {
+    let limit = Duration::from_millis(config.timeout_ms);
+
+    let broadcaster = async {
+        if !config.check_broadcaster {
+            return ComponentStatus {
+                name: "broadcaster".to_string(),
+                status: ComponentState::Skipped,
+                detail: "disabled".to_string(),
+                latency_ms: 0,
+            };
+        }
+        check(
+            "broadcaster",
+            limit,
+            check_broadcaster(sns_client, topic_arn),
+        )
+        .await
+    };
+
+    let (database, broadcaster, documents) = rocket::tokio::join!(
+        check("database", limit, check_database(db)),
+        broadcaster,
+        check("documents", limit, check_documents(rgas, memory)),
+    );
+
+    let readiness = Readiness::new(vec![database, broadcaster, documents]);
+    let status = if readiness.ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (status, Json(readiness))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn component(name: &str, status: ComponentState) -> ComponentStatus {
+        ComponentStatus {
+            name: name.to_string(),
+            status,
+            detail: String::new(),
+            latency_ms: 0,
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_readiness() {
+        let readiness = Readiness::new(vec![
+            component("database", ComponentState::Ok),
+            component("broadcaster", ComponentState::Skipped),
+        ]);
+        assert!(readiness.ready);
+
+        let readiness = Readiness::new(vec![
+            component("database", ComponentState::Failed),
+            component("documents", ComponentState::Ok),
+        ]);
+        assert!(!readiness.ready);
+
+        let slow = check("database", Duration::from_millis(10), async {
+            rocket::tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok("reachable".to_string())
+        })
+        .await;
+        assert_eq!(slow.status, ComponentState::Failed);
+        assert_eq!(slow.detail, "timed out after 10ms");
+
+        assert!(check_memory(2, 100, 0).is_ok());
+        assert!(check_memory(2, 100, 100).is_ok());
+        assert_eq!(
+            check_memory(2, 101, 100),
+            Err("2 documents loaded, 101 of 100 bytes".to_string())
+        );
+    }
+}
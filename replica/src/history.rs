@@ -0,0 +1,834 @@
+//! Reads of a document as it was at a past moment, and of who changed it when.
+//!
+//! The state is rebuilt by replaying the document's operations log up to the requested time.
+//! Operations are ordered by the wall-clock `timestamp` recorded by the replica that logged
+//! them, so "as of" is only as precise as the clocks of the replicas are in sync.
+//!
+//! The history of a document is read from its audit log, which the replicas share. Entries are
+//! paged in the order they were committed to the database, which is stable as new entries are
+//! always appended after the ones already read.
+
+use crate::pool::Pool;
+use crate::query_metrics::QueryTimer;
+use crate::rga::rga::RGA;
+use crate::share::Access;
+use crate::tenancy::{self, Tenant};
+use crate::{
+    audit, encryption, expiry, Actor, ApiError, BroadcastOperation, ContentNode, RequestId,
+    S4Vector,
+};
+use chrono::{DateTime, Duration, Utc};
+use rocket::get;
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// A row of the operations log.
+/// `s4vector`: The node the operation applied to, as stored (`ssn`, `sum`, `sid`, `seq`).
+/// `operation`: The operation's type (None in rows logged before it was kept).
+/// `value`: The node's value after the operation.
+/// `tombstone`: Whether the operation deleted the node.
+/// `left`, `right`: The operation's left and right origins.
+/// `nodes`: The nodes a move moved, in order (empty for other operations).
+/// `timestamp`: When the operation was logged.
+#[derive(Debug, Clone)]
+pub struct LoggedOperation {
+    pub s4vector: [i64; 4],
+    pub operation: Option<String>,
+    pub value: Option<String>,
+    pub tombstone: bool,
+    pub left: Option<S4Vector>,
+    pub right: Option<S4Vector>,
+    pub nodes: Vec<S4Vector>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Where a read of the operations log starts.
+/// `position`: The operations inserted after this position of the log are read.
+/// `since`: The operations logged after this time are read as well, whatever their position.
+#[derive(Debug, Clone, Copy)]
+pub struct LogTail {
+    pub position: i64,
+    pub since: DateTime<Utc>,
+}
+
+/// Response body with the content of a document at a past moment.
+/// `document_id`: The document.
+/// `timestamp`: The moment the document was rebuilt at.
+/// `operations`: The number of logged operations replayed.
+/// `content`: The document's text.
+/// `nodes`: The visible nodes in document order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DocumentAt {
+    pub document_id: Uuid,
+    pub timestamp: String,
+    pub operations: usize,
+    pub content: String,
+    pub nodes: Vec<ContentNode>,
+}
+
+/// Entries returned per page of history when the request does not set a limit.
+pub const DEFAULT_HISTORY_LIMIT: i64 = 100;
+
+/// Most entries a page of history may hold.
+pub const MAX_HISTORY_LIMIT: i64 = 1000;
+
+/// Longest pause between two operations of a user that the summary still counts as one change.
+pub const SUMMARY_GAP_SECS: i64 = 300;
+
+/// The entries of a document's history a request asks for.
+/// `user_id`: Only operations by this user.
+/// `operation`: Only operations of this type, as logged (Insert, Update, Delete or Move).
+/// `from`, `to`: Only operations logged at or after `from` and before `to`, as RFC 3339 in UTC.
+/// `after`: Only entries after this cursor.
+/// `limit`: The most entries to return.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryFilter {
+    pub user_id: Option<Uuid>,
+    pub operation: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub after: i64,
+    pub limit: i64,
+}
+
+impl HistoryFilter {
+    /// Parses the query parameters of `GET /document/<id>/history`.
+    pub fn parse(
+        actor: Option<&str>,
+        operation: Option<&str>,
+        from: Option<&str>,
+        to: Option<&str>,
+        cursor: Option<i64>,
+        limit: Option<i64>,
+    ) -> Result<Self, ApiError> {
+        let user_id: Option<Uuid> = match actor.map(Uuid::parse_str) {
+            None => None,
+            Some(Ok(user_id)) => Some(user_id),
+            Some(Err(_)) => return Err(invalid("actor must be a user id")),
+        };
+        let operation: Option<String> = match operation.map(str::to_lowercase).as_deref() {
+            None => None,
+            Some("insert") => Some("Insert".to_string()),
+            Some("update") => Some("Update".to_string()),
+            Some("delete") => Some("Delete".to_string()),
+            Some("move") => Some("Move".to_string()),
+            Some(_) => return Err(invalid("operation must be insert, update, delete or move")),
+        };
+        let time = |name: &str, value: Option<&str>| match value.map(DateTime::parse_from_rfc3339) {
+            None => Ok(None),
+            // stored timestamps are RFC 3339 in UTC, so they compare as text
+            Some(Ok(time)) => Ok(Some(time.to_utc().to_rfc3339())),
+            Some(Err(_)) => Err(invalid(&format!(
+                "{} must be an RFC 3339 time such as 2025-01-04T10:15:02Z",
+                name
+            ))),
+        };
+        let limit: i64 = limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+        if !(1..=MAX_HISTORY_LIMIT).contains(&limit) {
+            return Err(invalid(&format!(
+                "limit must be between 1 and {}",
+                MAX_HISTORY_LIMIT
+            )));
+        }
+
+        Ok(HistoryFilter {
+            user_id,
+            operation,
+            from: time("from", from)?,
+            to: time("to", to)?,
+            after: cursor.unwrap_or(0),
+            limit,
+        })
+    }
+}
+
+fn invalid(message: &str) -> ApiError {
+    error!("Invalid history request: {}", message);
+    ApiError::InvalidOperation(message.to_string())
+}
+
+/// An operation in a document's history.
+/// `cursor`: Position of the entry in the history, pass it back as `cursor` to read the entries after it.
+/// `user_id`: The user that performed the operation (None if the request was anonymous).
+/// `operation`: The operation type (Insert, Update, Delete or Move).
+/// `ssn`, `sum`, `sid`, `seq`: The s4vector of the affected node.
+/// `timestamp`: When the operation was applied.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub cursor: i64,
+    pub user_id: Option<Uuid>,
+    pub operation: String,
+    pub ssn: i64,
+    pub sum: i64,
+    pub sid: i64,
+    pub seq: i64,
+    pub timestamp: String,
+}
+
+/// Consecutive operations of one user, without a pause longer than `SUMMARY_GAP_SECS`.
+/// `user_id`: The user that performed them (None if the requests were anonymous).
+/// `from`, `to`: When the first and the last of them were applied.
+/// `operations`: How many of each type there were.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryChange {
+    pub user_id: Option<Uuid>,
+    pub from: String,
+    pub to: String,
+    pub operations: BTreeMap<String, usize>,
+}
+
+/// Response body with a page of a document's history.
+/// `entries`: The operations, oldest first. Left out when a summary is requested.
+/// `changes`: The operations summarized as changes, when a summary is requested.
+/// `next_cursor`: The cursor of the next page, None on the last page.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryPage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entries: Option<Vec<HistoryEntry>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changes: Option<Vec<HistoryChange>>,
+    pub next_cursor: Option<i64>,
+}
+
+/// Groups consecutive entries by the same user into changes. An entry starts a new change
+/// when its user differs from the previous entry's, or when it follows it by more than
+/// `SUMMARY_GAP_SECS`.
+pub fn summarize(entries: &[HistoryEntry]) -> Vec<HistoryChange> {
+    let gap: Duration = Duration::seconds(SUMMARY_GAP_SECS);
+    let time = |entry: &HistoryEntry| DateTime::parse_from_rfc3339(&entry.timestamp).ok();
+
+    let mut changes: Vec<HistoryChange> = Vec::new();
+    let mut previous: Option<&HistoryEntry> = None;
+    for entry in entries {
+        let continues: bool = previous.is_some_and(|previous| {
+            previous.user_id == entry.user_id
+                && match (time(previous), time(entry)) {
+                    (Some(previous), Some(at)) => at - previous <= gap,
+                    _ => false,
+                }
+        });
+        if !continues {
+            changes.push(HistoryChange {
+                user_id: entry.user_id,
+                from: entry.timestamp.clone(),
+                to: entry.timestamp.clone(),
+                operations: BTreeMap::new(),
+            });
+        }
+        if let Some(change) = changes.last_mut() {
+            change.to = entry.timestamp.clone();
+            *change
+                .operations
+                .entry(entry.operation.clone())
+                .or_insert(0) += 1;
+        }
+        previous = Some(entry);
+    }
+    changes
+}
+
+/// Reads a page of the document's history: the audit log entries matching the filter, oldest
+/// first. Returns the entries and whether more follow them.
+#[instrument(name = "db.fetch_history", skip(client))]
+pub async fn fetch_history(
+    client: &Client,
+    document_id: &Uuid,
+    filter: &HistoryFilter,
+) -> Result<(Vec<HistoryEntry>, bool), ApiError> {
+    let rows = match client
+        .query(
+            "SELECT id,user_id,operation,ssn,sum,sid,seq,timestamp FROM audit_log WHERE document_id=$1 \
+             AND ($2::uuid IS NULL OR user_id=$2) AND ($3::text IS NULL OR operation=$3) \
+             AND ($4::text IS NULL OR timestamp >= $4) AND ($5::text IS NULL OR timestamp < $5) \
+             AND id > $6 ORDER BY id LIMIT $7",
+            &[
+                document_id,
+                &filter.user_id,
+                &filter.operation,
+                &filter.from,
+                &filter.to,
+                &filter.after,
+                // one more than the page, to know whether another follows
+                &(filter.limit + 1),
+            ],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => {
+            error!("Failed to query the audit_log table");
+            return Err(ApiError::DatabaseError(
+                "Failed to query the audit_log table".to_string(),
+            ));
+        }
+    };
+
+    let more: bool = rows.len() as i64 > filter.limit;
+    let entries: Vec<HistoryEntry> = rows
+        .iter()
+        .take(filter.limit as usize)
+        .map(|row| HistoryEntry {
+            cursor: row.get(0),
+            user_id: row.get(1),
+            operation: row.get(2),
+            ssn: row.get(3),
+            sum: row.get(4),
+            sid: row.get(5),
+            seq: row.get(6),
+            timestamp: row.get(7),
+        })
+        .collect();
+    Ok((entries, more))
+}
+
+/// Replays the operations logged at or before `at` into an empty RGA.
+pub fn replay(document_id: Uuid, operations: &[LoggedOperation], at: DateTime<Utc>) -> RGA {
+    let mut rga: RGA = RGA::new(0, 0);
+    let applied: Vec<LoggedOperation> = operations
+        .iter()
+        .filter(|operation| operation.timestamp <= at)
+        .cloned()
+        .collect();
+    apply_operations(&mut rga, document_id, applied);
+    rga
+}
+
+/// Applies logged operations to an RGA in the order they were logged. Operations the RGA
+/// already has change nothing.
+///
+/// Operations are applied as if another replica had sent them, so an insert goes between its
+/// origins whatever was inserted there concurrently, and an operation logged before the node
+/// it depends on waits for it. Rows logged before their type was kept have no origins, so
+/// their node goes right after the closest one by s4vector like in a document loaded from
+/// its rows.
+pub fn apply_operations(rga: &mut RGA, document_id: Uuid, mut operations: Vec<LoggedOperation>) {
+    operations.sort_by_key(|operation| operation.timestamp);
+
+    for operation in operations {
+        let [ssn, sum, sid, seq] = operation.s4vector;
+        let s4: S4Vector = match S4Vector::from_i64(ssn, sum, sid, seq) {
+            Ok(s4) => s4,
+            Err(e) => {
+                error!("Skipping logged operation: {}", e);
+                continue;
+            }
+        };
+
+        let Some(kind) = operation.operation else {
+            apply_unlabelled(rga, s4, operation.value, operation.tombstone);
+            continue;
+        };
+        let applied: bool = match (kind.as_str(), rga.node(&s4)) {
+            ("Insert", node) => node.is_some(),
+            ("Update", Some(node)) => operation.value.as_ref() == Some(&node.value),
+            ("Delete", Some(node)) => node.tombstone,
+            // a node already moved by this move, or a later one, stays where it is
+            ("Move", _) => operation.nodes.iter().all(|s4vector| {
+                rga.node(s4vector)
+                    .is_some_and(|node| node.moved.is_some_and(|moved| moved >= s4))
+            }),
+            _ => false,
+        };
+        if applied {
+            continue;
+        }
+        let operation = BroadcastOperation {
+            operation: kind,
+            document_id,
+            ssn,
+            sum,
+            sid,
+            seq,
+            value: operation.value,
+            left: operation.left,
+            right: operation.right,
+            nodes: operation.nodes,
+            request_id: None,
+            author: None,
+            origin_region: None,
+            sent_at: None,
+        };
+        if let Err(e) = rga.apply_remote(operation) {
+            error!("Skipping logged operation: {}", e);
+        }
+    }
+}
+
+/// Applies an operation logged without its type or origins.
+fn apply_unlabelled(rga: &mut RGA, s4: S4Vector, value: Option<String>, tombstone: bool) {
+    match (rga.node(&s4), value) {
+        (None, value) => {
+            let order: Vec<S4Vector> = rga.iter().map(|node| node.s4vector).collect();
+            let left: Option<usize> = order
+                .iter()
+                .enumerate()
+                .filter(|(_, other)| **other < s4)
+                .max_by_key(|(_, other)| **other)
+                .map(|(i, _)| i);
+            let right: Option<S4Vector> = order.get(left.map_or(0, |left| left + 1)).copied();
+            rga.remote_insert(
+                value.unwrap_or_default(),
+                s4,
+                left.map(|left| order[left]),
+                right,
+            );
+        }
+        (Some(node), Some(value)) if node.value != value => rga.remote_update(s4, value),
+        _ => {}
+    }
+    if tombstone && rga.node(&s4).is_some_and(|node| !node.tombstone) {
+        rga.remote_delete(s4);
+    }
+}
+
+/// An operation's origin as stored in the operations log.
+pub fn origin_column(origin: Option<S4Vector>) -> Result<Option<Vec<i64>>, ApiError> {
+    Ok(match origin {
+        Some(origin) => Some(origin.to_i64()?.to_vec()),
+        None => None,
+    })
+}
+
+/// Reads an origin stored by [`origin_column`].
+fn parse_origin(column: Option<Vec<i64>>) -> Option<S4Vector> {
+    let [ssn, sum, sid, seq] = <[i64; 4]>::try_from(column?).ok()?;
+    S4Vector::from_i64(ssn, sum, sid, seq).ok()
+}
+
+/// The nodes a move moved as stored in the operations log, four values per node.
+pub fn nodes_column(nodes: &[S4Vector]) -> Result<Option<Vec<i64>>, ApiError> {
+    if nodes.is_empty() {
+        return Ok(None);
+    }
+    let mut column: Vec<i64> = Vec::with_capacity(nodes.len() * 4);
+    for node in nodes {
+        column.extend(node.to_i64()?);
+    }
+    Ok(Some(column))
+}
+
+/// Reads the nodes stored by [`nodes_column`].
+fn parse_nodes(column: Option<Vec<i64>>) -> Vec<S4Vector> {
+    column
+        .unwrap_or_default()
+        .chunks_exact(4)
+        .filter_map(|node| S4Vector::from_i64(node[0], node[1], node[2], node[3]).ok())
+        .collect()
+}
+
+/// Reads the document's operations log, or only its `tail`, skipping rows with an unreadable
+/// timestamp.
+pub async fn fetch_operations(
+    client: &Client,
+    document_id: &Uuid,
+    tail: Option<LogTail>,
+) -> Result<Vec<LoggedOperation>, ApiError> {
+    let mut timer = QueryTimer::start("load_operations").param("document_id", document_id);
+    if let Some(tail) = tail {
+        timer.set("position", tail.position);
+        timer.set("since", tail.since.to_rfc3339());
+    }
+    let rows = match tail {
+        // timestamps are RFC 3339 in UTC, so they sort as text
+        Some(tail) => {
+            client
+                .query(
+                    "SELECT ssn,sum,sid,seq,value,tombstone,timestamp,operation,left_origin,right_origin,nodes FROM operations WHERE document_id=$1 AND (log_position > $2 OR timestamp > $3)",
+                    &[document_id, &tail.position, &tail.since.to_rfc3339()],
+                )
+                .await
+        }
+        None => {
+            client
+                .query(
+                    "SELECT ssn,sum,sid,seq,value,tombstone,timestamp,operation,left_origin,right_origin,nodes FROM operations WHERE document_id=$1",
+                    &[document_id],
+                )
+                .await
+        }
+    };
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(_) => {
+            error!("Failed to read the operations table");
+            return Err(ApiError::DatabaseError(
+                "Failed to read the document's operations".to_string(),
+            ));
+        }
+    };
+
+    timer.set("rows", rows.len());
+    drop(timer);
+
+    let mut operations: Vec<LoggedOperation> = Vec::with_capacity(rows.len());
+    for row in rows {
+        let timestamp: String = row.get(6);
+        let timestamp: DateTime<Utc> = match DateTime::parse_from_rfc3339(&timestamp) {
+            Ok(timestamp) => timestamp.to_utc(),
+            Err(_) => {
+                error!("Skipping logged operation with timestamp {:?}", timestamp);
+                continue;
+            }
+        };
+
+        operations.push(LoggedOperation {
+            s4vector: [row.get(0), row.get(1), row.get(2), row.get(3)],
+            operation: row.get(7),
+            value: encryption::open_opt(client, row.get(4)).await?,
+            tombstone: row.get::<_, Option<bool>>(5).unwrap_or(false),
+            left: parse_origin(row.get(8)),
+            right: parse_origin(row.get(9)),
+            nodes: parse_nodes(row.get(10)),
+            timestamp,
+        });
+    }
+    Ok(operations)
+}
+
+/// Rebuilds a document as it was at `timestamp` (RFC 3339) by replaying its operations log,
+/// returning its text and visible nodes. Works whether or not the document is loaded.
+/// Returns `410 Gone` for archived documents, whose log may already have been purged.
+#[get("/document/<id>/at?<timestamp>")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn fetch_document_at(
+    id: String,
+    timestamp: String,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    request_id: RequestId,
+) -> Result<Json<DocumentAt>, ApiError> {
+    let document_id: Uuid = match Uuid::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => {
+            error!("Failed to parse document id");
+            return Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ));
+        }
+    };
+
+    let at: DateTime<Utc> = match DateTime::parse_from_rfc3339(&timestamp) {
+        Ok(at) => at.to_utc(),
+        Err(_) => {
+            error!("Failed to parse the timestamp");
+            return Err(ApiError::InvalidOperation(
+                "timestamp must be an RFC 3339 time such as 2025-01-04T10:15:02Z".to_string(),
+            ));
+        }
+    };
+
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
+    let operations: Vec<LoggedOperation> = {
+        let client = tenancy::lock(db, &tenant).await?;
+        match client
+            .query_opt(
+                "SELECT document_id FROM document WHERE document_id=$1",
+                &[&document_id],
+            )
+            .await
+        {
+            Ok(Some(_)) => (),
+            Ok(None) => {
+                error!("Document not found");
+                return Err(ApiError::NotFound(String::from("Document not found")));
+            }
+            Err(_) => {
+                error!("Failed to read the document table");
+                return Err(ApiError::DatabaseError(
+                    "Failed to find document in database".to_string(),
+                ));
+            }
+        }
+        expiry::check_not_archived(&client, &document_id).await?;
+
+        fetch_operations(&client, &document_id, None).await?
+    };
+
+    let rga: RGA = replay(document_id, &operations, at);
+    let replayed: usize = operations
+        .iter()
+        .filter(|operation| operation.timestamp <= at)
+        .count();
+
+    let nodes: Vec<ContentNode> = rga
+        .read_nodes()
+        .into_iter()
+        .map(|(s4vector, value)| ContentNode { s4vector, value })
+        .collect();
+    let content: String = rga.text().to_string();
+
+    info!(replayed, "Rebuilt document at {}", expiry::timestamp(at));
+    Ok(Json(DocumentAt {
+        document_id,
+        timestamp: expiry::timestamp(at),
+        operations: replayed,
+        content,
+        nodes,
+    }))
+}
+
+/// Returns a page of a document's history: who performed each operation and when, oldest
+/// first, without the client addresses kept in the audit log. Anyone who can read the
+/// document can read its history. `id` is the document UUID.
+/// `actor`: Only operations by this user id.
+/// `operation`: Only `insert`, `update`, `delete` or `move` operations.
+/// `from`, `to`: Only operations at or after `from` and before `to` (RFC 3339).
+/// `cursor`: The `next_cursor` of the previous page.
+/// `limit`: Entries per page, 100 by default and at most 1000.
+/// `summary`: Return the page's operations grouped into changes by user instead.
+///
+/// Example Response
+/// {
+///     "changes" : [
+///         {
+///             "user_id" : "550e8400-e29b-41d4-a716-446655440000",
+///             "from" : "2025-01-04T10:15:02.114+00:00",
+///             "to" : "2025-01-04T10:17:40.802+00:00",
+///             "operations" : { "Delete" : 2, "Insert" : 41 }
+///         }
+///     ],
+///     "next_cursor" : 1843
+/// }
+#[get("/document/<id>/history?<actor>&<operation>&<from>&<to>&<cursor>&<limit>&<summary>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn fetch_history_page(
+    id: String,
+    actor: Option<String>,
+    operation: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    cursor: Option<i64>,
+    limit: Option<i64>,
+    summary: Option<bool>,
+    requester: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    request_id: RequestId,
+) -> Result<Json<HistoryPage>, ApiError> {
+    let document_id: Uuid = match Uuid::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => {
+            error!("Failed to parse document id");
+            return Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ));
+        }
+    };
+    let filter: HistoryFilter = HistoryFilter::parse(
+        actor.as_deref(),
+        operation.as_deref(),
+        from.as_deref(),
+        to.as_deref(),
+        cursor,
+        limit,
+    )?;
+
+    let tenant: Tenant = requester
+        .authorize(pool, &document_id, Access::Read)
+        .await?;
+    let client = tenancy::lock(db, &tenant).await?;
+    audit::document_owner(&client, &document_id).await?;
+    let (entries, more) = fetch_history(&client, &document_id, &filter).await?;
+
+    let next_cursor: Option<i64> = if more {
+        entries.last().map(|entry| entry.cursor)
+    } else {
+        None
+    };
+    if summary.unwrap_or(false) {
+        return Ok(Json(HistoryPage {
+            entries: None,
+            changes: Some(summarize(&entries)),
+            next_cursor,
+        }));
+    }
+    Ok(Json(HistoryPage {
+        entries: Some(entries),
+        changes: None,
+        next_cursor,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged(s4vector: [i64; 4], value: &str, tombstone: bool, at: &str) -> LoggedOperation {
+        LoggedOperation {
+            s4vector,
+            operation: None,
+            value: Some(value.to_string()),
+            tombstone,
+            left: None,
+            right: None,
+            nodes: Vec::new(),
+            timestamp: DateTime::parse_from_rfc3339(at).unwrap().to_utc(),
+        }
+    }
+
+    /// An operation as its route logs it.
+    fn log(operation: &BroadcastOperation, at: &str) -> LoggedOperation {
+        LoggedOperation {
+            s4vector: [operation.ssn, operation.sum, operation.sid, operation.seq],
+            operation: Some(operation.operation.clone()),
+            value: operation.value.clone(),
+            tombstone: operation.operation == "Delete",
+            left: operation.left,
+            right: operation.right,
+            nodes: operation.nodes.clone(),
+            timestamp: DateTime::parse_from_rfc3339(at).unwrap().to_utc(),
+        }
+    }
+
+    fn time(at: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(at).unwrap().to_utc()
+    }
+
+    #[test]
+    fn test_replay_to_timestamp() {
+        let document_id = Uuid::nil();
+        // logged out of order, as rows come back from the database
+        let operations = vec![
+            logged([1, 2, 1, 2], "b", false, "2025-01-04T10:00:02Z"),
+            logged([1, 1, 1, 1], "a", false, "2025-01-04T10:00:01Z"),
+            logged([1, 1, 1, 1], "A", false, "2025-01-04T10:00:03+02:00"),
+            logged([1, 1, 1, 1], "A", false, "2025-01-04T10:00:03.5Z"),
+            logged([1, 2, 1, 2], "", true, "2025-01-04T10:00:04Z"),
+        ];
+
+        assert!(replay(document_id, &operations, time("2025-01-04T08:00:00Z")).is_empty());
+
+        let state = replay(document_id, &operations, time("2025-01-04T10:00:02Z")).nodes();
+        let values: Vec<(&str, bool)> = state
+            .iter()
+            .map(|node| (node.value.as_str(), node.tombstone))
+            .collect();
+        // the +02:00 update happened before the insert it appears to follow
+        assert_eq!(values, vec![("a", false), ("b", false)]);
+
+        let state = replay(document_id, &operations, time("2025-01-04T10:00:04Z")).nodes();
+        assert_eq!(state.len(), 2);
+        assert_eq!(state[0].value, "A");
+        assert!(state[1].tombstone);
+    }
+
+    #[test]
+    fn test_replay_concurrent_inserts() {
+        let document_id = Uuid::nil();
+        let mut first = RGA::new(1, 1);
+        let a = first
+            .local_insert("a".to_string(), None, None, document_id)
+            .unwrap();
+        let b = first
+            .local_insert("b".to_string(), Some(a.s4vector()), None, document_id)
+            .unwrap();
+        // a replica in a later session, whose nodes sort after "b" wherever they are
+        let mut second = RGA::new(2, 2);
+        second.apply_remote(a.clone()).unwrap();
+        second.apply_remote(b.clone()).unwrap();
+
+        // both replicas insert at the start before hearing of each other's insert
+        let x = first
+            .local_insert("x".to_string(), None, Some(a.s4vector()), document_id)
+            .unwrap();
+        let y = second
+            .local_insert("y".to_string(), None, Some(a.s4vector()), document_id)
+            .unwrap();
+        let z = second
+            .local_insert("z".to_string(), Some(y.s4vector()), None, document_id)
+            .unwrap();
+        first.apply_remote(y.clone()).unwrap();
+        first.apply_remote(z.clone()).unwrap();
+        second.apply_remote(x.clone()).unwrap();
+        assert_eq!(first.text(), "xyzab");
+        assert_eq!(second.text(), "xyzab");
+
+        let delete = first.local_delete(x.s4vector(), document_id).unwrap();
+        let operations = vec![
+            log(&a, "2025-01-04T10:00:01Z"),
+            log(&b, "2025-01-04T10:00:01Z"),
+            log(&x, "2025-01-04T10:00:02Z"),
+            log(&y, "2025-01-04T10:00:02Z"),
+            // logged by a replica whose clock runs behind, before the insert it follows
+            log(&z, "2025-01-04T10:00:01.5Z"),
+            log(&delete, "2025-01-04T10:00:04Z"),
+        ];
+
+        let state = replay(document_id, &operations, time("2025-01-04T10:00:03Z"));
+        assert_eq!(state.text(), "xyzab");
+        let state = replay(document_id, &operations, time("2025-01-04T10:00:04Z"));
+        assert_eq!(state.text(), "yzab");
+    }
+
+    #[test]
+    fn test_parse_history_filter() {
+        let user_id = Uuid::new_v4();
+        let filter = HistoryFilter::parse(
+            Some(&user_id.to_string()),
+            Some("DELETE"),
+            Some("2025-01-04T12:00:00+02:00"),
+            None,
+            Some(42),
+            None,
+        )
+        .unwrap();
+        assert_eq!(filter.user_id, Some(user_id));
+        assert_eq!(filter.operation.as_deref(), Some("Delete"));
+        assert_eq!(filter.from.as_deref(), Some("2025-01-04T10:00:00+00:00"));
+        assert_eq!((filter.after, filter.limit), (42, DEFAULT_HISTORY_LIMIT));
+
+        let parse =
+            |operation, from, limit| HistoryFilter::parse(None, operation, from, None, None, limit);
+        assert!(parse(Some("rename"), None, None).is_err());
+        assert!(parse(None, Some("yesterday"), None).is_err());
+        assert!(parse(None, None, Some(0)).is_err());
+        assert!(parse(None, None, Some(MAX_HISTORY_LIMIT + 1)).is_err());
+    }
+
+    #[test]
+    fn test_summarize() {
+        let alice = Some(Uuid::new_v4());
+        let bob = Some(Uuid::new_v4());
+        let entry = |cursor, user_id, operation: &str, timestamp: &str| HistoryEntry {
+            cursor,
+            user_id,
+            operation: operation.to_string(),
+            ssn: 1,
+            sum: cursor,
+            sid: 1,
+            seq: cursor,
+            timestamp: timestamp.to_string(),
+        };
+        let entries = vec![
+            entry(1, alice, "Insert", "2025-01-04T10:00:00+00:00"),
+            entry(2, alice, "Insert", "2025-01-04T10:04:00+00:00"),
+            entry(3, alice, "Delete", "2025-01-04T10:08:00+00:00"),
+            entry(4, bob, "Update", "2025-01-04T10:08:30+00:00"),
+            // after a longer pause the same user starts a new change
+            entry(5, bob, "Insert", "2025-01-04T10:20:00+00:00"),
+        ];
+
+        let changes = summarize(&entries);
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0].user_id, alice);
+        assert_eq!(
+            (changes[0].from.as_str(), changes[0].to.as_str()),
+            ("2025-01-04T10:00:00+00:00", "2025-01-04T10:08:00+00:00")
+        );
+        assert_eq!(
+            changes[0].operations,
+            BTreeMap::from([("Delete".to_string(), 1), ("Insert".to_string(), 2)])
+        );
+        assert_eq!(changes[1].operations.get("Update"), Some(&1));
+        assert_eq!(changes[2].from, "2025-01-04T10:20:00+00:00");
+        assert!(summarize(&[]).is_empty());
+    }
+}
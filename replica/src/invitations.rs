@@ -0,0 +1,737 @@
+//! Invitations to collaborate on a document, sent by email.
+//!
+//! The owner of a document invites an address with `POST /document/<id>/invite`, which stores a
+//! pending invitation in `document_invitations` and emails the address a link to accept it,
+//! through SES. The invitation is accepted with `POST /invitations/<id>/accept` by the user
+//! registered with that address, which adds them to the document's members (the
+//! `document_members` table) with the access the owner chose. Members can use the document like
+//! members of its organization, see `tenancy::authorize`.
+
+use crate::pool::Pool;
+use crate::limits::JsonBody;
+use crate::notifications::{self, NotificationKind};
+use crate::share::Access;
+use crate::signing::AwsSigner;
+use crate::tenancy::{self, Tenant};
+use crate::{audit, expiry, users, Actor, ApiError, FieldError, RequestId};
+use aws_config::SdkConfig;
+use chrono::{Duration as TimeDelta, Utc};
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use rocket::{delete, get, post, State};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use tokio_postgres::{Client, Row};
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// `sender`: The address invitations are sent from, verified in SES (None disables the emails,
+/// invitees then find them with `GET /me/invitations`).
+/// `accept_url`: The page of the editor accepting an invitation, the link in the email adds
+/// `?invitation=<id>` to it.
+/// `ttl_secs`: How long an invitation can be accepted.
+/// `region`: The region of SES.
+/// `endpoint`: An SES compatible endpoint to use instead of AWS.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InvitationConfig {
+    pub sender: Option<String>,
+    pub accept_url: String,
+    pub ttl_secs: u64,
+    pub region: String,
+    pub endpoint: Option<String>,
+}
+
+impl Default for InvitationConfig {
+    fn default() -> Self {
+        InvitationConfig {
+            sender: None,
+            accept_url: "http://localhost:3000/invitations".to_string(),
+            ttl_secs: 7 * 24 * 60 * 60,
+            region: "af-south-1".to_string(),
+            endpoint: None,
+        }
+    }
+}
+
+impl InvitationConfig {
+    /// The link accepting an invitation.
+    pub fn accept_link(&self, invitation_id: &Uuid) -> String {
+        let separator: char = if self.accept_url.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        format!(
+            "{}{}invitation={}",
+            self.accept_url, separator, invitation_id
+        )
+    }
+}
+
+/// Request body for inviting an address to a document.
+#[derive(Debug, Deserialize)]
+pub struct InviteRequest {
+    pub email: String,
+    pub access: Access,
+}
+
+impl InviteRequest {
+    /// Returns the lower-cased address, or the invalid field.
+    pub fn email(&self) -> Result<String, FieldError> {
+        let email: String = self.email.trim().to_lowercase();
+        if users::is_email(&email) {
+            Ok(email)
+        } else {
+            Err(FieldError::new("email", "must be an email address"))
+        }
+    }
+}
+
+/// A pending invitation.
+/// `invited_by`: The owner of the document, who sent the invitation.
+/// `expires_at`: When the invitation can no longer be accepted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Invitation {
+    pub invitation_id: Uuid,
+    pub document_id: Uuid,
+    pub email: String,
+    pub access: Access,
+    pub invited_by: Uuid,
+    pub created_at: String,
+    pub expires_at: String,
+}
+
+impl Invitation {
+    fn from_row(row: &Row) -> Option<Self> {
+        Some(Invitation {
+            invitation_id: row.get("invitation_id"),
+            document_id: row.get("document_id"),
+            email: row.get("email"),
+            access: Access::parse(row.get("access"))?,
+            invited_by: row.get("invited_by"),
+            created_at: row.get("created_at"),
+            expires_at: row.get("expires_at"),
+        })
+    }
+}
+
+/// A user added to a document by accepting an invitation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocumentMember {
+    pub document_id: Uuid,
+    pub user_id: Uuid,
+    pub access: Access,
+}
+
+/// Sends invitation emails with the SES v2 API, signing requests with the replica's AWS
+/// credentials. The replica manages an `Option<Mailer>`, None when emails are disabled.
+pub struct Mailer {
+    signer: AwsSigner,
+    url: String,
+    sender: String,
+}
+
+impl Mailer {
+    /// Creates the client, or returns None if no sender or AWS credentials are configured.
+    pub fn new(aws_config: &SdkConfig, config: &InvitationConfig) -> Option<Mailer> {
+        let sender: &String = config.sender.as_ref()?;
+        let credentials = aws_config.credentials_provider()?;
+
+        let url: String = match &config.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://email.{}.amazonaws.com", config.region),
+        };
+
+        Some(Mailer {
+            signer: AwsSigner::new(credentials, &config.region, "ses"),
+            url: format!("{}/v2/email/outbound-emails", url),
+            sender: sender.clone(),
+        })
+    }
+
+    /// Sends a plain text email to one address.
+    #[instrument(name = "ses.send_email", skip_all)]
+    pub async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), ApiError> {
+        let url = match reqwest::Url::parse(&self.url) {
+            Ok(url) => url,
+            Err(_) => {
+                error!("Invalid SES endpoint");
+                return Err(ApiError::InternalServerError(
+                    "Invalid SES endpoint".to_string(),
+                ));
+            }
+        };
+        let email = json!({
+            "FromEmailAddress": self.sender,
+            "Destination": { "ToAddresses": [to] },
+            "Content": { "Simple": {
+                "Subject": { "Data": subject },
+                "Body": { "Text": { "Data": body } },
+            } },
+        });
+
+        let response = self
+            .signer
+            .send(
+                reqwest::Method::POST,
+                url,
+                &[("content-type", "application/json")],
+                email.to_string().into_bytes(),
+            )
+            .await?;
+        if !response.status().is_success() {
+            error!("SES rejected the email with status {}", response.status());
+            return Err(ApiError::RequestFailed(format!(
+                "SES rejected the email with status {}",
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn parse_id(id: &str, name: &str) -> Result<Uuid, ApiError> {
+    match Uuid::parse_str(id) {
+        Ok(id) => Ok(id),
+        Err(_) => {
+            error!("Failed to parse {} id", name);
+            Err(ApiError::InvalidOperation(format!(
+                "Failed to parse {} id",
+                name
+            )))
+        }
+    }
+}
+
+fn database_error(table: &str) -> ApiError {
+    error!("Failed to query the {} table", table);
+    ApiError::DatabaseError(format!("Failed to query the {} table", table))
+}
+
+/// Checks the requesting user owns the document.
+async fn require_owner(
+    client: &Client,
+    document_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<(), ApiError> {
+    if audit::document_owner(client, document_id).await? != *user_id {
+        error!("Invitation managed by a user who does not own the document");
+        return Err(ApiError::Forbidden(
+            "Only the document owner can invite collaborators".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Invites an address to collaborate on a document, or renews the pending invitation of the
+/// address with the new access. The invitation is emailed when a sender is configured and is
+/// not kept if the email can't be sent. Only the document owner can invite.
+/// `id` is the document UUID.
+///
+/// Example Request
+/// {
+///     "email" : "ada@example.com",
+///     "access" : "read_write"
+/// }
+#[post("/document/<id>/invite", data = "<request>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn invite(
+    id: String,
+    request: JsonBody<InviteRequest>,
+    actor: Actor,
+    db: &State<Arc<Mutex<Client>>>,
+    pool: &State<Pool>,
+    config: &State<InvitationConfig>,
+    mailer: &State<Option<Mailer>>,
+    request_id: RequestId,
+) -> Result<Json<Invitation>, ApiError> {
+    let document_id: Uuid = parse_id(&id, "document")?;
+    let email: String = match request.email() {
+        Ok(email) => email,
+        Err(error) => return Err(ApiError::ValidationFailed(vec![error])),
+    };
+    let user_id: Uuid = actor.require_user()?;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
+
+    let mut client = tenancy::lock(db, &tenant).await?;
+    require_owner(&client, &document_id, &user_id).await?;
+    expiry::check_not_archived(&client, &document_id).await?;
+    let (title, inviter): (String, String) = match client
+        .query_one(
+            "SELECT d.title, u.display_name FROM document d JOIN users u ON u.user_id=d.owner_id WHERE d.document_id=$1",
+            &[&document_id],
+        )
+        .await
+    {
+        Ok(row) => (row.get(0), row.get(1)),
+        Err(_) => return Err(database_error("document")),
+    };
+
+    let now = Utc::now();
+    let expires_at: String = match i64::try_from(config.ttl_secs)
+        .ok()
+        .and_then(TimeDelta::try_seconds)
+        .and_then(|ttl| now.checked_add_signed(ttl))
+    {
+        Some(expires_at) => expiry::timestamp(expires_at),
+        None => {
+            error!("Invitation expiry out of range");
+            return Err(ApiError::InternalServerError(
+                "Invitation expiry out of range".to_string(),
+            ));
+        }
+    };
+
+    // the invitation is rolled back if the email can't be sent
+    let tx = match client.transaction().await {
+        Ok(tx) => tx,
+        Err(_) => {
+            error!("Failed to start database transaction");
+            return Err(ApiError::DatabaseError(
+                "Failed to start database transaction".to_string(),
+            ));
+        }
+    };
+    let row = match tx
+        .query_one(
+            "INSERT INTO document_invitations (document_id,email,access,invited_by,created_at,expires_at) VALUES ($1,$2,$3,$4,$5,$6) \
+             ON CONFLICT (document_id,email) DO UPDATE SET access=EXCLUDED.access, invited_by=EXCLUDED.invited_by, \
+             created_at=EXCLUDED.created_at, expires_at=EXCLUDED.expires_at RETURNING *",
+            &[
+                &document_id,
+                &email,
+                &request.access.as_str(),
+                &user_id,
+                &expiry::timestamp(now),
+                &expires_at,
+            ],
+        )
+        .await
+    {
+        Ok(row) => row,
+        Err(_) => return Err(database_error("document_invitations")),
+    };
+    let invitation: Invitation = match Invitation::from_row(&row) {
+        Some(invitation) => invitation,
+        None => return Err(database_error("document_invitations")),
+    };
+
+    if let Some(mailer) = mailer.inner() {
+        let body: String = format!(
+            "{} invited you to {} \"{}\".\n\nAccept the invitation: {}\n\nThe invitation expires at {}.",
+            inviter,
+            if request.access == Access::ReadWrite {
+                "edit"
+            } else {
+                "read"
+            },
+            title,
+            config.accept_link(&invitation.invitation_id),
+            invitation.expires_at
+        );
+        mailer
+            .send(
+                &email,
+                &format!("{} invited you to collaborate", inviter),
+                &body,
+            )
+            .await?;
+    }
+
+    if tx.commit().await.is_err() {
+        error!("Failed to commit database transaction");
+        return Err(ApiError::DatabaseError(
+            "Failed to commit database transaction".to_string(),
+        ));
+    }
+    if let Ok(Some(row)) = client
+        .query_opt("SELECT user_id FROM users WHERE email=$1", &[&email])
+        .await
+    {
+        notifications::notify(
+            &client,
+            &row.get(0),
+            NotificationKind::Invited,
+            Some(user_id),
+            &format!("{} invited you to \"{}\"", inviter, title),
+            "/me/invitations",
+        )
+        .await;
+    }
+    info!(invitation_id = %invitation.invitation_id, emailed = mailer.is_some(), "Collaborator invited");
+    Ok(Json(invitation))
+}
+
+/// Lists the pending invitations to a document, for its owner. `id` is the document UUID.
+#[get("/document/<id>/invitations")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn list_document_invitations(
+    id: String,
+    actor: Actor,
+    db: &State<Arc<Mutex<Client>>>,
+    pool: &State<Pool>,
+    request_id: RequestId,
+) -> Result<Json<Vec<Invitation>>, ApiError> {
+    let document_id: Uuid = parse_id(&id, "document")?;
+    let user_id: Uuid = actor.require_user()?;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
+
+    let client = tenancy::lock(db, &tenant).await?;
+    require_owner(&client, &document_id, &user_id).await?;
+    match client
+        .query(
+            "SELECT * FROM document_invitations WHERE document_id=$1 AND expires_at > $2 ORDER BY created_at",
+            &[&document_id, &expiry::timestamp(Utc::now())],
+        )
+        .await
+    {
+        Ok(rows) => Ok(Json(rows.iter().filter_map(Invitation::from_row).collect())),
+        Err(_) => Err(database_error("document_invitations")),
+    }
+}
+
+/// Revokes a pending invitation, so it can no longer be accepted. Users who already accepted
+/// it keep their access. `id` is the document UUID.
+#[delete("/document/<id>/invitations/<invitation>")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn revoke_invitation(
+    id: String,
+    invitation: String,
+    actor: Actor,
+    db: &State<Arc<Mutex<Client>>>,
+    pool: &State<Pool>,
+    request_id: RequestId,
+) -> Result<(), ApiError> {
+    let document_id: Uuid = parse_id(&id, "document")?;
+    let invitation_id: Uuid = parse_id(&invitation, "invitation")?;
+    let user_id: Uuid = actor.require_user()?;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
+
+    let client = tenancy::lock(db, &tenant).await?;
+    require_owner(&client, &document_id, &user_id).await?;
+    match client
+        .execute(
+            "DELETE FROM document_invitations WHERE invitation_id=$1 AND document_id=$2",
+            &[&invitation_id, &document_id],
+        )
+        .await
+    {
+        Ok(0) => Err(ApiError::NotFound("Invitation not found".to_string())),
+        Ok(_) => {
+            info!(invitation_id = %invitation_id, "Invitation revoked");
+            Ok(())
+        }
+        Err(_) => Err(database_error("document_invitations")),
+    }
+}
+
+/// Returns the registered email of a user.
+async fn user_email(client: &Client, user_id: &Uuid) -> Result<String, ApiError> {
+    match client
+        .query_opt("SELECT email FROM users WHERE user_id=$1", &[user_id])
+        .await
+    {
+        Ok(Some(row)) => Ok(row.get(0)),
+        Ok(None) => Err(ApiError::NotFound("User not found".to_string())),
+        Err(_) => Err(database_error("users")),
+    }
+}
+
+/// Lists the pending invitations sent to the requesting user's email.
+#[get("/me/invitations")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn list_my_invitations(
+    actor: Actor,
+    db: &State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<Vec<Invitation>>, ApiError> {
+    let user_id: Uuid = actor.require_user()?;
+
+    // invitations are addressed across every organization
+    let client = tenancy::lock(db, &Tenant::Any).await?;
+    let email: String = user_email(&client, &user_id).await?;
+    match client
+        .query(
+            "SELECT * FROM document_invitations WHERE email=$1 AND expires_at > $2 ORDER BY created_at DESC",
+            &[&email, &expiry::timestamp(Utc::now())],
+        )
+        .await
+    {
+        Ok(rows) => Ok(Json(rows.iter().filter_map(Invitation::from_row).collect())),
+        Err(_) => Err(database_error("document_invitations")),
+    }
+}
+
+/// Accepts an invitation sent to the requesting user's email, adding them to the document's
+/// members with the invitation's access. Invitations sent to other addresses are not found,
+/// expired ones are gone.
+#[post("/invitations/<id>/accept")]
+#[instrument(skip_all, fields(request_id = %request_id, invitation_id = %id))]
+pub async fn accept_invitation(
+    id: String,
+    actor: Actor,
+    db: &State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<DocumentMember>, ApiError> {
+    let invitation_id: Uuid = parse_id(&id, "invitation")?;
+    let user_id: Uuid = actor.require_user()?;
+
+    let mut client = tenancy::lock(db, &Tenant::Any).await?;
+    let email: String = user_email(&client, &user_id).await?;
+    let tx = match client.transaction().await {
+        Ok(tx) => tx,
+        Err(_) => {
+            error!("Failed to start database transaction");
+            return Err(ApiError::DatabaseError(
+                "Failed to start database transaction".to_string(),
+            ));
+        }
+    };
+
+    let invitation: Invitation = match tx
+        .query_opt(
+            "DELETE FROM document_invitations WHERE invitation_id=$1 AND email=$2 RETURNING *",
+            &[&invitation_id, &email],
+        )
+        .await
+    {
+        Ok(Some(row)) => match Invitation::from_row(&row) {
+            Some(invitation) => invitation,
+            None => return Err(database_error("document_invitations")),
+        },
+        Ok(None) => {
+            error!("Accepted invitation not found for the user's email");
+            return Err(ApiError::NotFound("Invitation not found".to_string()));
+        }
+        Err(_) => return Err(database_error("document_invitations")),
+    };
+    if invitation.expires_at <= expiry::timestamp(Utc::now()) {
+        // dropping the transaction keeps the invitation until the owner renews or revokes it
+        error!("Accepted invitation expired at {}", invitation.expires_at);
+        return Err(ApiError::Gone(format!(
+            "The invitation expired at {}",
+            invitation.expires_at
+        )));
+    }
+
+    if tx
+        .execute(
+            "INSERT INTO document_members (document_id,user_id,access,added_at) VALUES ($1,$2,$3,$4) \
+             ON CONFLICT (document_id,user_id) DO UPDATE SET access=EXCLUDED.access",
+            &[
+                &invitation.document_id,
+                &user_id,
+                &invitation.access.as_str(),
+                &expiry::timestamp(Utc::now()),
+            ],
+        )
+        .await
+        .is_err()
+    {
+        return Err(database_error("document_members"));
+    }
+    if tx.commit().await.is_err() {
+        error!("Failed to commit database transaction");
+        return Err(ApiError::DatabaseError(
+            "Failed to commit database transaction".to_string(),
+        ));
+    }
+    notifications::notify(
+        &client,
+        &invitation.invited_by,
+        NotificationKind::InvitationAccepted,
+        Some(user_id),
+        &format!("{} accepted your invitation", email),
+        &format!("/document/{}", invitation.document_id),
+    )
+    .await;
+
+    info!(document_id = %invitation.document_id, "Invitation accepted");
+    Ok(Json(DocumentMember {
+        document_id: invitation.document_id,
+        user_id,
+        access: invitation.access,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        self, TestDatabase, INVITATION_TABLES, NOTIFICATION_TABLES, ORGANIZATION_TABLES,
+        USER_TABLES,
+    };
+    use rocket::http::{ContentType, Status};
+    use rocket::local::asynchronous::Client as LocalClient;
+
+    #[test]
+    fn test_invitation_request() {
+        let request = |email: &str| InviteRequest {
+            email: email.to_string(),
+            access: Access::Read,
+        };
+        assert_eq!(
+            request(" Ada@Example.com ").email().ok(),
+            Some("ada@example.com".to_string())
+        );
+        assert!(request("ada").email().is_err());
+        assert!(request("@example.com").email().is_err());
+
+        let id = Uuid::nil();
+        let config = InvitationConfig::default();
+        assert_eq!(
+            config.accept_link(&id),
+            format!("http://localhost:3000/invitations?invitation={}", id)
+        );
+        let config = InvitationConfig {
+            accept_url: "https://app.example.com/accept?source=email".to_string(),
+            ..config
+        };
+        assert_eq!(
+            config.accept_link(&id),
+            format!(
+                "https://app.example.com/accept?source=email&invitation={}",
+                id
+            )
+        );
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_invitation_routes() {
+        let database = TestDatabase::create(&format!(
+            "{}{}{}{}",
+            ORGANIZATION_TABLES, USER_TABLES, INVITATION_TABLES, NOTIFICATION_TABLES
+        ))
+        .await;
+        let db: Client = database.connect().await;
+        let owner: Uuid = testing::insert_user(&db, "owner@example.com").await;
+        let invitee: Uuid = testing::insert_user(&db, "ada@example.com").await;
+        let stranger: Uuid = testing::insert_user(&db, "eve@example.com").await;
+        let document_id: Uuid = testing::insert_document(&db, owner).await;
+        let rocket = testing::rocket(&database)
+            .await
+            .manage(InvitationConfig::default())
+            .manage(None::<Mailer>)
+            .mount(
+                "/",
+                rocket::routes![
+                    invite,
+                    list_document_invitations,
+                    revoke_invitation,
+                    list_my_invitations,
+                    accept_invitation
+                ],
+            );
+        let client = LocalClient::tracked(rocket).await.unwrap();
+        let invite = |user_id: Uuid, body: &'static str| {
+            client
+                .post(format!("/document/{}/invite", document_id))
+                .header(ContentType::JSON)
+                .header(testing::session(user_id))
+                .body(body)
+                .dispatch()
+        };
+        let invitations = format!("/document/{}/invitations", document_id);
+
+        let response = invite(stranger, r#"{"email":"ada@example.com","access":"read"}"#).await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = invite(owner, r#"{"email":"ada","access":"read"}"#).await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let response = invite(
+            owner,
+            r#"{"email":"Ada@Example.com","access":"read_write"}"#,
+        )
+        .await;
+        assert_eq!(response.status(), Status::Ok);
+        let invitation: Invitation = response.into_json().await.unwrap();
+        assert_eq!(invitation.email, "ada@example.com");
+        assert_eq!(invitation.invited_by, owner);
+
+        let response = client
+            .get(invitations.clone())
+            .header(testing::session(stranger))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .get(invitations.clone())
+            .header(testing::session(owner))
+            .dispatch()
+            .await;
+        let pending: Vec<Invitation> = response.into_json().await.unwrap();
+        assert_eq!(pending, vec![invitation.clone()]);
+        let response = client
+            .get("/me/invitations")
+            .header(testing::session(invitee))
+            .dispatch()
+            .await;
+        let pending: Vec<Invitation> = response.into_json().await.unwrap();
+        assert_eq!(pending, vec![invitation.clone()]);
+        let notified: i64 = db
+            .query_one(
+                "SELECT COUNT(*) FROM notifications WHERE user_id=$1 AND kind='invited'",
+                &[&invitee],
+            )
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(notified, 1);
+
+        // only the invited address can accept the invitation
+        let accept = format!("/invitations/{}/accept", invitation.invitation_id);
+        let response = client
+            .post(accept.clone())
+            .header(testing::session(stranger))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .post(accept.clone())
+            .header(testing::session(invitee))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let member: DocumentMember = response.into_json().await.unwrap();
+        assert_eq!(
+            (member.user_id, member.access),
+            (invitee, Access::ReadWrite)
+        );
+        let access: String = db
+            .query_one(
+                "SELECT access FROM document_members WHERE document_id=$1 AND user_id=$2",
+                &[&document_id, &invitee],
+            )
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(access, "read_write");
+
+        let response = invite(owner, r#"{"email":"eve@example.com","access":"read"}"#).await;
+        let invitation: Invitation = response.into_json().await.unwrap();
+        let revoke = format!("{}/{}", invitations, invitation.invitation_id);
+        let response = client
+            .delete(revoke.clone())
+            .header(testing::session(stranger))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .delete(revoke.clone())
+            .header(testing::session(owner))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .post(format!("/invitations/{}/accept", invitation.invitation_id))
+            .header(testing::session(stranger))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        database.drop().await;
+    }
+}
@@ -1,13 +1,25 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::S4Vector;
+use crate::validation::OperationKind;
+use crate::{S4Vector, S4VectorError};
 
-/// Request body for creating a new document.
+/// Request body for creating a new document, owned by the user making the request.
+/// `ttl_secs`: Seconds until the document expires and is archived (None if it never expires).
+/// `persist_chat`: Whether chat messages sent on the document are stored (defaults to false).
+/// `language`: The programming language of the document, used to highlight it (None for plain text).
+/// `workspace_id`: The workspace the document belongs to (None for a personal document).
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateDocumentRequest {
-    pub owner_id: Uuid,
     pub title: String,
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    #[serde(default)]
+    pub persist_chat: bool,
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default)]
+    pub workspace_id: Option<Uuid>,
 }
 
 /// Response Body for the result of creating a new document
@@ -15,6 +27,8 @@ pub struct CreateDocumentRequest {
 pub struct CreateDocumentResponse {
     pub document_id: Uuid, // Auto-generated document id
     pub message: String,   // Confirmation message
+    #[serde(default)]
+    pub expires_at: Option<String>, // When the document expires (if created with a ttl)
 }
 
 /// Response structure for a fetched document
@@ -27,7 +41,27 @@ pub struct FetchDocumentResponse {
     pub operations: Vec<Operation>,
 }
 
+/// A visible node of a document.
+/// `s4vector`: The node's identifier, used as `left`/`right` or the target of updates and deletes.
+/// `value`: The node's content.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContentNode {
+    pub s4vector: S4Vector,
+    pub value: String,
+}
+
+/// Response body with the current content of a loaded document.
+/// `document_id`: The document.
+/// `nodes`: The visible nodes in document order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DocumentContent {
+    pub document_id: Uuid,
+    pub nodes: Vec<ContentNode>,
+}
+
 /// Struct for holding the document snapshot data
+/// `author`: The user that wrote the node's current value (None if unknown).
+/// `authored_at`: When the node's current value was written (None if unknown).
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DocumentSnapshot {
     pub document_id: Uuid,
@@ -37,6 +71,29 @@ pub struct DocumentSnapshot {
     pub seq: i64,
     pub value: String,
     pub tombstone: bool,
+    #[serde(default)]
+    pub author: Option<Uuid>,
+    #[serde(default)]
+    pub authored_at: Option<String>,
+}
+
+/// A backup of a document's metadata and compacted snapshot.
+/// `document_id`, `owner_id`, `title`, `creation_date`, `expires_at`, `language`, `workspace_id`: The document's metadata.
+/// `backed_up_at`: When the backup was taken.
+/// `nodes`: One row per node of the document, including tombstones.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DocumentBackup {
+    pub document_id: Uuid,
+    pub owner_id: Uuid,
+    pub title: String,
+    pub creation_date: String,
+    pub expires_at: Option<String>,
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default)]
+    pub workspace_id: Option<Uuid>,
+    pub backed_up_at: String,
+    pub nodes: Vec<DocumentSnapshot>,
 }
 
 /// Represents the request body for operations.
@@ -49,11 +106,43 @@ pub struct DocumentSnapshot {
 pub struct OperationRequest {
     pub value: Option<String>,
     pub s4vector: Option<S4Vector>,
+    #[serde(default)]
     pub tombstone: bool,
     pub left: Option<S4Vector>,
     pub right: Option<S4Vector>,
 }
 
+/// Request body for a batch of edits made at several cursors at once.
+/// `edits`: The edits, applied in order. They must not depend on each other, each refers to
+/// nodes that exist before the batch.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchRequest {
+    pub edits: Vec<BatchEdit>,
+}
+
+/// One edit of a batch.
+/// `operation`: Whether the edit is an insert, update or delete.
+/// `request`: The fields of the edit, as sent to the route for the operation.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchEdit {
+    pub operation: OperationKind,
+    #[serde(flatten)]
+    pub request: OperationRequest,
+}
+
+/// Request body for moving a range of nodes.
+/// `first`: The first node of the range.
+/// `last`: The last node of the range, the same as `first` to move a single node.
+/// `left`: The node the range goes after (None for the start of the document).
+/// `right`: The node the range goes before (None for the node following `left`).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MoveRequest {
+    pub first: S4Vector,
+    pub last: S4Vector,
+    pub left: Option<S4Vector>,
+    pub right: Option<S4Vector>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Operation {
     document_id: u64,
@@ -64,6 +153,78 @@ pub struct Operation {
     right: S4Vector,
 }
 
+/// An entry in a document's audit log.
+/// `document_id`: The document the operation was applied to.
+/// `user_id`: The user that performed the operation (None if the request was anonymous).
+/// `client_ip`: The address of the client that sent the operation.
+/// `operation`: The operation type (Insert, Update, Delete).
+/// `ssn`, `sum`, `sid`, `seq`: The s4vector of the affected node.
+/// `timestamp`: When the operation was applied.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub document_id: Uuid,
+    pub user_id: Option<Uuid>,
+    pub client_ip: Option<String>,
+    pub operation: String,
+    pub ssn: i64,
+    pub sum: i64,
+    pub sid: i64,
+    pub seq: i64,
+    pub timestamp: String,
+}
+
+/// JSON body returned for every failed request.
+/// `code`: A stable, machine-readable error code (e.g. `api::not_found`).
+/// `message`: A human readable description of the error.
+/// `details`: Optional guidance on how to resolve the error.
+/// `docs_url`: Where the error code is documented in the error catalog.
+/// `request_id`: The id of the request that failed (if one was provided).
+/// `errors`: The invalid fields of a request that failed validation.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub code: String,
+    pub message: String,
+    pub details: Option<String>,
+    #[serde(default)]
+    pub docs_url: Option<String>,
+    pub request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+/// An error of the error catalog, served at `GET /errors/<code>`.
+/// `code`: The diagnostic code returned in error bodies (e.g. `api::not_found`).
+/// `status`: The HTTP status the error is returned with.
+/// `description`: When the error is returned.
+/// `help`: How to resolve the error, returned as `details` in error bodies.
+/// `docs_url`: Where this entry is served.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorCatalogEntry {
+    pub code: String,
+    pub status: u16,
+    pub description: String,
+    pub help: Option<String>,
+    pub docs_url: String,
+}
+
+/// A single invalid field of a request.
+/// `field`: The name of the field in the request body.
+/// `message`: Why the field was rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: &str) -> Self {
+        FieldError {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
 /// SNS notification message send through AWS SNS
 /// `operation`: The opertation type (Insert,Update,Delete)
 /// `message_id`: A unique message id for the SNS notification.
@@ -80,7 +241,7 @@ pub struct SnsNotification {
 }
 
 /// BroadcastOpteration is the operation sent from one replica to another through AWS SNS
-/// `operation`: The operation type (Insert, Update, Delete)
+/// `operation`: The operation type (Insert, Update, Delete, Move)
 /// `document_id`: A unique id for the document associated with the operation.
 /// `ssn`: the session number for the associated s4vector
 /// `sum`: the sum for the associated s4vector
@@ -89,7 +250,12 @@ pub struct SnsNotification {
 /// `value`: The value being inserted/updated (None if a delete operation)
 /// `left`: The left s4vector if one exists
 /// `right`: The right s4vector if one exits
-#[derive(Debug, Serialize, Deserialize)]
+/// `nodes`: The nodes a move relocates, in document order (empty for other operations)
+/// `request_id`: The id of the client request that produced the operation (for correlation across replicas)
+/// `author`: The user that made the operation (None if the request was anonymous)
+/// `origin_region`: The region of the replica that broadcast the operation (None if not broadcast)
+/// `sent_at`: When the operation was broadcast (None if not broadcast)
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BroadcastOperation {
     pub operation: String,
     pub document_id: Uuid,
@@ -100,6 +266,38 @@ pub struct BroadcastOperation {
     pub value: Option<String>,
     pub left: Option<S4Vector>,
     pub right: Option<S4Vector>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nodes: Vec<S4Vector>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(default)]
+    pub author: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<String>,
+}
+
+/// Response body for an applied insert, update or delete.
+/// `operation`: The operation as broadcast to other replicas, including the node's neighbours.
+/// `s4vector`: The s4vector of the affected node, used to address it in later operations.
+/// `timestamp`: When the operation was applied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppliedOperation {
+    #[serde(flatten)]
+    pub operation: BroadcastOperation,
+    pub s4vector: S4Vector,
+    pub timestamp: String,
+}
+
+impl AppliedOperation {
+    pub fn new(operation: BroadcastOperation, timestamp: String) -> Self {
+        AppliedOperation {
+            s4vector: operation.s4vector(),
+            operation,
+            timestamp,
+        }
+    }
 }
 
 impl BroadcastOperation {
@@ -112,4 +310,14 @@ impl BroadcastOperation {
             seq: self.seq as u64,
         }
     }
+
+    /// Checks the s4vectors of an operation received from another replica,
+    /// rejecting negative or out of range fields instead of wrapping them.
+    pub fn validate(&self) -> Result<(), S4VectorError> {
+        S4Vector::from_i64(self.ssn, self.sum, self.sid, self.seq)?;
+        for s4 in [self.left, self.right].iter().flatten() {
+            s4.validate()?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,176 @@
+//! Leader election for cluster-wide background jobs.
+//!
+//! Jobs such as the expiry reaper and scheduled backups must run on one replica at a time.
+//! Each job has a lease in the `job_leases` table: before a run, a replica takes the lease if it
+//! is free or has expired, or renews it if the replica already holds it, and skips the run
+//! otherwise. Leases are timed by the database's clock and last for the job's interval plus
+//! `leader.lease_secs`, so the holder renews its lease on every run and another replica takes
+//! over once the holder has missed a run. Leases are released when a replica shuts down.
+
+use crate::{AdminConfig, AdminToken, ApiError, RequestId};
+use rocket::get;
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+/// The lease of the expiry reaper.
+pub const REAPER_JOB: &str = "expiry.reaper";
+
+/// The lease of scheduled backups.
+pub const BACKUP_JOB: &str = "backup.scheduled";
+
+/// Leader election for background jobs.
+/// `lease_secs`: Seconds a lease outlives the job's interval, the time another replica waits
+/// before taking over a job whose holder stopped running it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LeaderConfig {
+    pub lease_secs: u64,
+}
+
+impl Default for LeaderConfig {
+    fn default() -> Self {
+        LeaderConfig { lease_secs: 30 }
+    }
+}
+
+/// This replica's identity when holding leases, managed in Rocket's state.
+/// `holder`: The replica id and a per-process id, so a restarted replica doesn't inherit the
+/// leases of its previous process while they are being renewed by it.
+#[derive(Debug, Clone)]
+pub struct Leader {
+    pub holder: String,
+    pub config: LeaderConfig,
+}
+
+impl Leader {
+    pub fn new(replica_id: i64, config: LeaderConfig) -> Self {
+        Leader {
+            holder: format!("{}:{}", replica_id, Uuid::new_v4().simple()),
+            config,
+        }
+    }
+
+    /// Seconds a lease taken before a run of a job every `interval_secs` lasts.
+    pub fn lease_secs(&self, interval_secs: u64) -> f64 {
+        interval_secs.saturating_add(self.config.lease_secs) as f64
+    }
+
+    /// Takes or renews the lease on a job run every `interval_secs`.
+    /// Returns whether this replica holds the lease, errors are logged and count as not holding it.
+    #[instrument(name = "leader.acquire", skip(self, client))]
+    pub async fn acquire(&self, client: &Client, job: &str, interval_secs: u64) -> bool {
+        let result = client
+            .query_opt(
+                "INSERT INTO job_leases (job,holder,expires_at) VALUES ($1,$2,now() + make_interval(secs => $3)) \
+                 ON CONFLICT (job) DO UPDATE SET holder=EXCLUDED.holder, expires_at=EXCLUDED.expires_at \
+                 WHERE job_leases.holder=EXCLUDED.holder OR job_leases.expires_at < now() \
+                 RETURNING holder",
+                &[&job, &self.holder, &self.lease_secs(interval_secs)],
+            )
+            .await;
+
+        match result {
+            Ok(Some(_)) => true,
+            Ok(None) => false,
+            Err(_) => {
+                error!("Failed to acquire the lease");
+                false
+            }
+        }
+    }
+
+    /// Gives up the lease on a job if this replica holds it, so another replica takes over the
+    /// job on its next run.
+    #[instrument(name = "leader.release", skip(self, client))]
+    pub async fn release(&self, client: &Client, job: &str) {
+        match client
+            .execute(
+                "DELETE FROM job_leases WHERE job=$1 AND holder=$2",
+                &[&job, &self.holder],
+            )
+            .await
+        {
+            Ok(released) if released > 0 => info!("Released the lease"),
+            Ok(_) => {}
+            Err(_) => warn!("Failed to release the lease"),
+        }
+    }
+}
+
+/// The lease on a job.
+/// `job`: The job.
+/// `holder`: The replica holding the lease.
+/// `expires_in_secs`: Seconds until the lease expires (negative once it has expired).
+/// `held`: Whether this replica holds the lease.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lease {
+    pub job: String,
+    pub holder: String,
+    pub expires_in_secs: f64,
+    pub held: bool,
+}
+
+/// Shows which replica holds the lease on each background job.
+#[get("/admin/leases")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn fetch_leases(
+    token: AdminToken,
+    admin: &rocket::State<AdminConfig>,
+    leader: &rocket::State<Leader>,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<Vec<Lease>>, ApiError> {
+    token.require(admin)?;
+    let client = db.lock().await;
+
+    let rows = match client
+        .query(
+            "SELECT job,holder,EXTRACT(EPOCH FROM expires_at - now())::float8 FROM job_leases ORDER BY job",
+            &[],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => {
+            error!("Failed to read the job leases");
+            return Err(ApiError::DatabaseError(
+                "Failed to read the job leases".to_string(),
+            ));
+        }
+    };
+
+    Ok(Json(
+        rows.iter()
+            .map(|row| {
+                let holder: String = row.get(1);
+                Lease {
+                    job: row.get(0),
+                    held: holder == leader.holder,
+                    holder,
+                    expires_in_secs: row.get(2),
+                }
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leader() {
+        let first = Leader::new(7, LeaderConfig::default());
+        let second = Leader::new(7, LeaderConfig::default());
+        assert!(first.holder.starts_with("7:"));
+        assert_ne!(first.holder, second.holder);
+
+        assert_eq!(first.lease_secs(60), 90.0);
+        assert_eq!(first.lease_secs(u64::MAX), u64::MAX as f64);
+    }
+}
@@ -1,8 +1,6 @@
 pub mod routes;
-use routes::*;
 
 pub mod rga;
-use rga::*;
 
 pub mod json_structures;
 use json_structures::*;
@@ -15,3 +13,100 @@ pub use s4vector::*;
 
 pub mod error;
 pub use error::*;
+
+pub mod quota;
+pub use quota::*;
+
+pub mod request_id;
+pub use request_id::*;
+
+pub mod telemetry;
+pub use telemetry::*;
+
+pub mod logging;
+pub use logging::*;
+
+pub mod audit;
+pub use audit::*;
+
+pub mod config;
+pub use config::*;
+
+pub mod snapshot;
+
+pub mod memory;
+pub use memory::*;
+
+pub mod admin;
+pub use admin::*;
+
+pub mod shutdown;
+
+pub mod session;
+pub use session::*;
+
+pub mod expiry;
+
+pub mod validation;
+pub use validation::*;
+
+pub mod limits;
+
+pub mod reporting;
+
+pub mod query_metrics;
+
+pub mod changes;
+
+pub mod backup;
+
+pub mod history;
+
+pub mod blame;
+
+pub mod users;
+
+pub mod collaboration;
+
+pub mod chat;
+
+pub mod yjs;
+
+pub mod automerge;
+
+pub mod sandbox;
+
+pub mod tokens;
+
+pub mod gossip;
+
+pub mod leader;
+
+pub mod region;
+pub mod signing;
+pub mod sqs;
+pub mod backpressure;
+pub mod forwarded;
+pub mod health;
+pub mod divergence;
+pub mod share;
+pub mod auth;
+pub mod encryption;
+pub mod secrets;
+pub mod tenancy;
+pub mod usage;
+pub mod flags;
+pub mod pool;
+pub mod outbox;
+pub mod wal;
+pub mod recovery;
+pub mod compaction;
+pub mod throttle;
+pub mod trash;
+pub mod tags;
+pub mod projects;
+pub mod starred;
+pub mod invitations;
+pub mod notifications;
+#[cfg(test)]
+pub(crate) mod testing;
@@ -0,0 +1,292 @@
+use crate::{ApiError, FieldError};
+use rocket::data::{Data, FromData, Limits, Outcome};
+use rocket::http::{ContentType, Status};
+use rocket::{catch, catchers, Catcher, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::error::Category;
+use std::ops::Deref;
+use tracing::error;
+
+/// Limits on request bodies.
+/// `json_bytes`: The largest JSON body accepted, larger bodies are rejected with `413 Payload Too Large`.
+/// `max_json_depth`: The deepest nesting of objects and arrays accepted in a JSON body.
+/// `strict_content_type`: Reject bodies that are not sent as `application/json` with
+/// `415 Unsupported Media Type`, otherwise a missing `Content-Type` is accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub json_bytes: u64,
+    pub max_json_depth: usize,
+    pub strict_content_type: bool,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            json_bytes: 64 * 1024,
+            max_json_depth: 32,
+            strict_content_type: true,
+        }
+    }
+}
+
+/// The reason a body was rejected, kept so the catcher can return it.
+struct RejectedBody(Option<ApiError>);
+
+/// A JSON request body checked against the replica's `LimitsConfig` before it is parsed.
+/// Rejected bodies are answered by the catchers returned from [`catchers`] with the
+/// structured error format.
+#[derive(Debug)]
+pub struct JsonBody<T>(pub T);
+
+impl<T> Deref for JsonBody<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// The deepest nesting of objects and arrays in `json`, ignoring brackets inside strings.
+/// Stops counting once `max` is exceeded.
+pub fn json_depth(json: &str, max: usize) -> usize {
+    let (mut depth, mut deepest) = (0_usize, 0_usize);
+    let (mut in_string, mut escaped) = (false, false);
+
+    for byte in json.bytes() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                deepest = deepest.max(depth);
+                if deepest > max {
+                    break;
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+    deepest
+}
+
+/// Rejects the body with `error`, keeping it for the catcher.
+fn reject<'r, T>(req: &'r Request<'_>, error: ApiError) -> Outcome<'r, JsonBody<T>, ApiError> {
+    error!("Rejected request body: {}", error);
+    let status: Status = error.status();
+    req.local_cache(|| RejectedBody(Some(error.clone())));
+    Outcome::Error((status, error))
+}
+
+#[rocket::async_trait]
+impl<'r, T: Deserialize<'r>> FromData<'r> for JsonBody<T> {
+    type Error = ApiError;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r, Self, ApiError> {
+        let config: LimitsConfig = req
+            .rocket()
+            .state::<LimitsConfig>()
+            .copied()
+            .unwrap_or_default();
+
+        match req.content_type() {
+            Some(content_type) if content_type.is_json() => (),
+            None if !config.strict_content_type => (),
+            other => {
+                let received: String = match other {
+                    Some(content_type) => content_type.to_string(),
+                    None => "none".to_string(),
+                };
+                return reject(
+                    req,
+                    ApiError::UnsupportedMediaType(format!(
+                        "Expected {}, received {}",
+                        ContentType::JSON,
+                        received
+                    )),
+                );
+            }
+        }
+
+        let limit = req.limits().get("json").unwrap_or(Limits::JSON);
+        let body: String = match data.open(limit).into_string().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => {
+                return reject(
+                    req,
+                    ApiError::PayloadTooLarge(format!("Request body exceeds {} bytes", limit)),
+                )
+            }
+            Err(_) => {
+                return reject(
+                    req,
+                    ApiError::InvalidOperation("Request body is not valid UTF-8".to_string()),
+                )
+            }
+        };
+
+        if json_depth(&body, config.max_json_depth) > config.max_json_depth {
+            return reject(
+                req,
+                ApiError::PayloadTooLarge(format!(
+                    "JSON nesting exceeds a depth of {}",
+                    config.max_json_depth
+                )),
+            );
+        }
+
+        let body: &'r str = rocket::request::local_cache!(req, body);
+        match serde_json::from_str(body) {
+            Ok(value) => Outcome::Success(JsonBody(value)),
+            // well-formed JSON that doesn't match the expected fields
+            Err(e) if e.classify() == Category::Data => reject(
+                req,
+                ApiError::ValidationFailed(vec![FieldError::new("body", &e.to_string())]),
+            ),
+            Err(e) => reject(
+                req,
+                ApiError::InvalidOperation(format!("Invalid JSON body: {}", e)),
+            ),
+        }
+    }
+}
+
+/// Answers a failed request with the error kept by [`JsonBody`], or `fallback` if the request
+/// failed elsewhere.
+fn rejected(req: &Request<'_>, fallback: ApiError) -> ApiError {
+    match &req.local_cache(|| RejectedBody(None)).0 {
+        Some(error) => error.clone(),
+        None => fallback,
+    }
+}
+
+#[catch(400)]
+fn bad_request(req: &Request<'_>) -> ApiError {
+    rejected(
+        req,
+        ApiError::InvalidOperation("The request could not be read".to_string()),
+    )
+}
+
+#[catch(413)]
+fn payload_too_large(req: &Request<'_>) -> ApiError {
+    rejected(
+        req,
+        ApiError::PayloadTooLarge("Request body is too large".to_string()),
+    )
+}
+
+#[catch(415)]
+fn unsupported_media_type(req: &Request<'_>) -> ApiError {
+    rejected(
+        req,
+        ApiError::UnsupportedMediaType(format!("Expected {}", ContentType::JSON)),
+    )
+}
+
+#[catch(422)]
+fn unprocessable_entity(req: &Request<'_>) -> ApiError {
+    rejected(
+        req,
+        ApiError::ValidationFailed(vec![FieldError::new("body", "could not be parsed")]),
+    )
+}
+
+/// Catchers returning the structured error format for rejected request bodies.
+pub fn catchers() -> Vec<Catcher> {
+    catchers![
+        bad_request,
+        payload_too_large,
+        unsupported_media_type,
+        unprocessable_entity
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorResponse;
+    use rocket::local::blocking::Client;
+    use rocket::post;
+
+    #[post("/echo", data = "<body>")]
+    fn echo(body: JsonBody<serde_json::Value>) -> String {
+        body.0.to_string()
+    }
+
+    fn client() -> Client {
+        let figment = rocket::Config::figment().merge(("limits.json", 64));
+        let rocket = rocket::custom(figment)
+            .manage(LimitsConfig {
+                json_bytes: 64,
+                max_json_depth: 4,
+                strict_content_type: true,
+            })
+            .register("/", catchers())
+            .mount("/", rocket::routes![echo]);
+        Client::tracked(rocket).unwrap()
+    }
+
+    fn post(client: &Client, content_type: ContentType, body: &str) -> (Status, Option<String>) {
+        let response = client
+            .post("/echo")
+            .header(content_type)
+            .body(body)
+            .dispatch();
+        let status: Status = response.status();
+        let code = response.into_json::<ErrorResponse>().map(|e| e.code);
+        (status, code)
+    }
+
+    #[test]
+    fn test_body_limits() {
+        let client = client();
+
+        assert_eq!(
+            post(&client, ContentType::JSON, r#"{"value": "a"}"#).0,
+            Status::Ok
+        );
+        assert_eq!(
+            post(&client, ContentType::Plain, r#"{"value": "a"}"#),
+            (
+                Status::UnsupportedMediaType,
+                Some("api::unsupported_media_type".to_string())
+            )
+        );
+        assert_eq!(
+            post(
+                &client,
+                ContentType::JSON,
+                &format!("\"{}\"", "a".repeat(100))
+            ),
+            (
+                Status::PayloadTooLarge,
+                Some("api::payload_too_large".to_string())
+            )
+        );
+        assert_eq!(
+            post(&client, ContentType::JSON, "[[[[[1]]]]]").0,
+            Status::PayloadTooLarge
+        );
+        assert_eq!(post(&client, ContentType::JSON, "{").0, Status::BadRequest);
+    }
+
+    #[test]
+    fn test_json_depth() {
+        assert_eq!(json_depth(r#"{"value": "a"}"#, 32), 1);
+        assert_eq!(json_depth(r#"{"left": {"ssn": 1}, "right": null}"#, 32), 2);
+        assert_eq!(json_depth(r#"{"value": "[[[{{\"]]]"}"#, 32), 1);
+        assert_eq!(json_depth(&"[".repeat(1000), 32), 33);
+        assert_eq!(json_depth("", 32), 0);
+    }
+}
@@ -0,0 +1,248 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::OnceLock;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+
+static REPLICA_ID: OnceLock<i64> = OnceLock::new();
+
+/// Sets the replica id written on every log line. Only the first call has any effect.
+pub fn set_replica_id(replica_id: i64) {
+    let _ = REPLICA_ID.set(replica_id);
+}
+
+/// The replica id written on every log line (None until it is set).
+pub fn replica_id() -> Option<i64> {
+    REPLICA_ID.get().copied()
+}
+
+/// Output format for log lines.
+/// `Json`: One JSON object per line (the default).
+/// `Pretty`: Human readable output for local development.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Pretty,
+}
+
+/// Logging configuration, read from the `[logging]` section of the replica configuration.
+/// `format`: The output format.
+/// `level`: The default level for every target.
+/// `targets`: Per-target level overrides, e.g. `rocket = "warn"`.
+/// `file`: Appends logs to this file instead of stdout (if set).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    pub level: String,
+    pub targets: BTreeMap<String, String>,
+    pub file: Option<String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            format: LogFormat::Json,
+            level: "info".to_string(),
+            targets: BTreeMap::new(),
+            file: None,
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Loads the logging configuration, falling back to the defaults if it is invalid.
+    /// Logging is configured before the rest of the configuration is validated so that
+    /// configuration errors can be reported.
+    pub fn load() -> Self {
+        match crate::config::figment().extract_inner::<LoggingConfig>("logging") {
+            Ok(config) => config,
+            Err(e) if e.missing() => LoggingConfig::default(),
+            Err(e) => {
+                eprintln!("Invalid logging configuration: {}", e);
+                LoggingConfig::default()
+            }
+        }
+    }
+
+    /// Builds the filter directives, e.g. `info,rocket=warn`.
+    pub fn directives(&self) -> String {
+        let mut directives: Vec<String> = vec![self.level.clone()];
+        for (target, level) in &self.targets {
+            directives.push(format!("{}={}", target, level));
+        }
+        directives.join(",")
+    }
+
+    /// The filter for the subscriber. `RUST_LOG` overrides the configured levels.
+    pub fn filter(&self) -> EnvFilter {
+        match EnvFilter::try_from_default_env() {
+            Ok(filter) => filter,
+            Err(_) => match EnvFilter::try_new(self.directives()) {
+                Ok(filter) => filter,
+                Err(e) => {
+                    eprintln!("Invalid log level configuration: {}", e);
+                    EnvFilter::new("info")
+                }
+            },
+        }
+    }
+}
+
+/// Formats events as a single flat JSON object.
+///
+/// Fields from every enclosing span (such as `request_id` and `document_id` on the route
+/// spans) are merged into the top level next to `level`, `target` and `replica_id`,
+/// so each line can be filtered without knowing which span recorded the field.
+/// Spans must be recorded with [`JsonFields`].
+pub struct FlatJson;
+
+impl<S> FormatEvent<S, JsonFields> for FlatJson
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        // records forwarded from the log crate (e.g. Rocket's) carry their real target separately
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let mut object: Map<String, Value> = Map::new();
+
+        object.insert(
+            "timestamp".to_string(),
+            Value::from(chrono::Utc::now().to_rfc3339()),
+        );
+        object.insert("level".to_string(), Value::from(metadata.level().as_str()));
+        object.insert("target".to_string(), Value::from(metadata.target()));
+        if let Some(replica_id) = REPLICA_ID.get() {
+            object.insert("replica_id".to_string(), Value::from(*replica_id));
+        }
+
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                if let Some(fields) = extensions.get::<FormattedFields<JsonFields>>() {
+                    if let Ok(Value::Object(fields)) = serde_json::from_str(fields.as_str()) {
+                        object.extend(fields);
+                    }
+                }
+            }
+        }
+
+        event.record(&mut JsonVisitor(&mut object));
+
+        writeln!(writer, "{}", Value::Object(object))
+    }
+}
+
+/// Records event fields into a JSON object.
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), Value::from(format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        // skip the metadata fields added to records forwarded from the log crate
+        if field.name().starts_with("log.") {
+            return;
+        }
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name().starts_with("log.") {
+            return;
+        }
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tracing::{info, info_span};
+    use tracing_subscriber::fmt::MakeWriter;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn test_directives() {
+        let mut config = LoggingConfig::default();
+        assert_eq!(config.directives(), "info");
+
+        config.targets.insert("rocket".to_string(), "warn".to_string());
+        config
+            .targets
+            .insert("nimble::routes".to_string(), "debug".to_string());
+        assert_eq!(config.directives(), "info,nimble::routes=debug,rocket=warn");
+    }
+
+    #[test]
+    fn test_flat_json_merges_span_fields() {
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .fmt_fields(JsonFields::new())
+            .event_format(FlatJson)
+            .with_writer(buffer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!("insert", request_id = "abc", document_id = "doc");
+            let _guard = span.enter();
+            info!(operations = 3, "Applied operation");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: Value = serde_json::from_str(output.trim()).unwrap();
+
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["request_id"], "abc");
+        assert_eq!(line["document_id"], "doc");
+        assert_eq!(line["operations"], 3);
+        assert_eq!(line["message"], "Applied operation");
+    }
+}
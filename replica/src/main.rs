@@ -1,11 +1,71 @@
 use aws_sdk_sns::{config::Region, Client as SnsClient};
 use chrono::{DateTime, Utc};
+use nimble::admin::*;
 use nimble::attatch_db;
+use nimble::auth::PrivateResponses;
+use nimble::automerge::{export_document, import_document};
+use nimble::backpressure::{attach_buffer_sweep, Backlog};
+use nimble::backup::{attach_backups, restore_all_backups, restore_backup, S3Store};
+use nimble::blame::fetch_blame;
+use nimble::changes::{poll_changes, ChangeFeeds};
+use nimble::chat::{fetch_chat_history, poll_messages, send_message, ChatRooms};
+use nimble::collaboration::{
+    fetch_selections, join, leave, update_selection, CollaborationSessions,
+};
+use nimble::compaction::attach_compaction;
+use nimble::divergence::{attach_divergence, fetch_digest, Divergence};
+use nimble::encryption::{attach_encryption, Kms};
+use nimble::error::{self, fetch_error, list_errors};
+use nimble::expiry::attach_reaper;
+use nimble::flags::{attach_features, clear_feature, list_features, set_feature, Features};
+use nimble::gossip::{attach_gossip, receive_gossip, Membership};
+use nimble::health::ready;
+use nimble::history::{fetch_document_at, fetch_history_page};
+use nimble::invitations::{
+    accept_invitation, invite, list_document_invitations, list_my_invitations, revoke_invitation,
+    Mailer,
+};
+use nimble::leader::{fetch_leases, Leader};
+use nimble::limits;
+use nimble::memory::attach_memory_cap;
+use nimble::notifications::{list_notifications, mark_notifications_read};
+use nimble::outbox::{attach_outbox, Outbox};
+use nimble::pool::attach_pool;
+use nimble::projects::{
+    add_project_member, create_project, fetch_project_contents, move_document,
+    remove_project_member,
+};
+use nimble::query_metrics;
+use nimble::recovery::attach_recovery;
+use nimble::region::{self, ReplicationMetrics};
+use nimble::reporting::attach_reporting;
 use nimble::rga::rga::RGA;
 use nimble::routes::*;
+use nimble::sandbox::{follow_run, list_runs, run_document, ProcessSandbox, Runs, Sandbox};
+use nimble::secrets::{attach_secret_rotation, Secrets};
+use nimble::share::create_share_link;
+use nimble::shutdown::attach_shutdown;
+use nimble::snapshot::attach_autosave;
+use nimble::sqs::{attach_sqs, SqsClient};
+use nimble::starred::{list_recent, list_starred, star_document, unstar_document};
+use nimble::tags::{fetch_document_tags, update_document_tags};
+use nimble::tenancy::{
+    add_member, attach_isolation, create_organization, create_workspace, list_members,
+    list_organizations, list_user_documents, list_workspace_documents, list_workspaces,
+    remove_member,
+};
+use nimble::throttle::Throttle;
+use nimble::tokens::fetch_tokens;
+use nimble::trash::{list_trash, restore_trashed_document, trash_document};
+use nimble::usage::{attach_usage, fetch_usage, KinesisSink};
+use nimble::users::{fetch_profile, login, register, update_profile};
+use nimble::wal::{attach_wal_checkpoint, Wal};
+use nimble::yjs::{yjs_sync, YjsDocuments};
+use nimble::{
+    attach_session, init_tracing, set_replica_id, LoggingConfig, ReplicaConfig, RequestIdFairing,
+};
 use rocket::tokio::sync::Mutex;
 use std::collections::HashMap;
-use std::env;
 use std::sync::Arc;
 use uuid::Uuid;
 
@@ -14,46 +74,233 @@ extern crate rocket;
 
 #[launch]
 async fn rocket() -> _ {
-    // 1: Database connection string
-    // 2. Replica ID
-    let arguments: Vec<String> = env::args().collect();
+    init_tracing(&LoggingConfig::load());
+
+    let config: ReplicaConfig = match ReplicaConfig::load() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("See Replica.toml for the available settings.");
+            std::process::exit(1);
+        }
+    };
+    set_replica_id(config.replica_id);
+    query_metrics::set_slow_query_threshold(config.database.slow_query_ms);
+    region::set_region(config.region());
+
     let rgas: Arc<Mutex<HashMap<Uuid, RGA>>> = Arc::new(Mutex::new(HashMap::new()));
 
-    // database setup
-    let config = aws_config::from_env()
-        .region(Region::new("af-south-1"))
+    // SNS setup
+    let aws_config = aws_config::from_env()
+        .region(Region::new(config.sns.region.clone()))
         .load()
         .await;
+    let sns_client = Arc::new(Mutex::new(SnsClient::new(&aws_config)));
+    let backups: Option<Arc<S3Store>> = S3Store::new(&aws_config, &config.backup).map(Arc::new);
+    let kms: Option<Kms> = Kms::new(&aws_config, &config.encryption);
+    let kinesis: Option<KinesisSink> = KinesisSink::new(&aws_config, &config.usage);
+    let secrets: Option<Secrets> = Secrets::new(&aws_config, &config.secrets);
+    let mailer: Option<Mailer> = Mailer::new(&aws_config, &config.invitations);
+    let database_url: String = match &secrets {
+        Some(secrets) => match secrets.database_url(&config.database.url).await {
+            Ok(url) => url,
+            Err(e) => {
+                eprintln!("Failed to read the database credentials: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => config.database.url.clone(),
+    };
+    let queue: Option<Arc<SqsClient>> =
+        SqsClient::new(&aws_config, &config.sqs, &config.sns.region).map(Arc::new);
 
-    // SNS setup
-    let sns_client = Arc::new(Mutex::new(SnsClient::new(&config)));
-    let topic_arn = std::env::var("SNS_TOPIC").expect("SNS_TOPIC must be set");
+    let mut figment = rocket::Config::figment()
+        .merge(("address", config.server.address.clone()))
+        .merge(("port", config.server.port))
+        .merge(("shutdown.grace", config.server.shutdown_grace_secs))
+        .merge(("limits.json", config.limits.json_bytes));
+    if let (Some(certs), Some(key)) = (&config.tls.certs, &config.tls.key) {
+        figment = figment.merge(("tls.certs", certs)).merge(("tls.key", key));
+    }
+    // clients without a certificate signed by the CA are refused during the handshake
+    if let Some(ca) = &config.tls.client_ca {
+        figment = figment
+            .merge(("tls.mutual.ca_certs", ca))
+            .merge(("tls.mutual.mandatory", true));
+    }
 
-    let replica_id: i64 = match arguments.get(2) {
-        Some(id) => id.parse::<i64>().unwrap(),
-        None => {
-            error!("No replica ID provided in command line arguments");
+    let wal: Wal = match Wal::open(&config.wal).await {
+        Ok(wal) => wal,
+        Err(e) => {
+            eprintln!("Failed to open the write-ahead log: {}", e);
             std::process::exit(1);
         }
     };
 
     let start_time: DateTime<Utc> = Utc::now();
-    rocket::build()
-        .attach(attatch_db())
-        .manage(replica_id)
-        .manage(topic_arn)
+    let mut rocket = rocket::custom(figment);
+    if let Some(queue) = queue {
+        rocket = rocket.manage(queue);
+    }
+
+    rocket
+        .attach(attatch_db(database_url.clone()))
+        .attach(attach_pool(database_url.clone(), config.database.pool_size))
+        .attach(attach_isolation(config.tenancy))
+        .attach(attach_secret_rotation(
+            secrets,
+            config.secrets.clone(),
+            config.database.url.clone(),
+            database_url,
+        ))
+        .attach(attach_encryption(kms, config.encryption.clone()))
+        .attach(attach_recovery())
+        .attach(attach_session(config.replica_id))
+        .attach(RequestIdFairing)
+        .attach(PrivateResponses)
+        .attach(attach_reporting(config.reporting.clone()))
+        .attach(attach_shutdown())
+        .attach(attach_autosave(config.snapshot.autosave_interval_secs))
+        .attach(attach_memory_cap(config.memory))
+        .attach(attach_compaction(config.compaction))
+        .attach(attach_buffer_sweep(config.buffer))
+        .attach(attach_reaper(config.expiry))
+        .attach(attach_backups(config.backup.interval_secs))
+        .attach(attach_gossip(config.gossip.clone()))
+        .attach(attach_sqs(config.sqs.clone()))
+        .attach(attach_divergence(config.divergence))
+        .attach(attach_usage(config.usage.clone(), kinesis))
+        .attach(attach_features(config.features))
+        .attach(attach_outbox(config.outbox))
+        .attach(attach_wal_checkpoint(config.wal.clone()))
+        .manage(Arc::new(Mutex::new(config.replica_id)))
+        .manage(Arc::new(Mutex::new(config.sns.topic_arn.clone())))
         .manage(sns_client)
         .manage(rgas)
+        .manage(DocumentLocks::default())
+        .manage(ChangeFeeds::default())
+        .manage(CollaborationSessions::default())
+        .manage(ChatRooms::default())
+        .manage(YjsDocuments::default())
+        .manage(Runs::default())
+        .manage(ReplicationMetrics::default())
+        .manage(Backlog::new(config.backpressure, config.buffer))
+        .manage(Throttle::new(config.throttle))
+        .manage(config.readiness)
+        .manage(config.expiry)
+        .manage(config.invitations.clone())
+        .manage(mailer)
+        .manage(backups)
+        .manage(Divergence::default())
+        .manage(Outbox::default())
+        .manage(wal)
+        .manage(config.share.clone())
+        .manage(config.auth.clone())
+        .manage(config.usage.clone())
+        .manage(Features::new(config.features))
+        .manage(Membership::new(
+            config.replica_id,
+            config.gossip.address.clone(),
+        ))
+        .manage(config.gossip.clone())
+        .manage(Leader::new(config.replica_id, config.leader))
+        .manage(Arc::new(ProcessSandbox::new(config.sandbox.clone())) as Arc<dyn Sandbox>)
+        .manage(config.sandbox.clone())
         .manage(start_time)
+        .manage(config.quotas)
+        .manage(config.validation)
+        .manage(config.limits)
+        .manage(config.memory)
+        .manage(config.admin.clone())
+        .manage(config)
+        .register("/", limits::catchers())
+        .register("/", error::catchers())
         .mount(
             "/",
             routes![
                 insert,
                 update,
                 delete,
+                move_nodes,
+                batch_edits,
                 create_document,
+                register,
+                login,
+                fetch_profile,
+                update_profile,
+                create_organization,
+                list_organizations,
+                list_members,
+                add_member,
+                remove_member,
+                create_workspace,
+                list_workspaces,
+                list_workspace_documents,
+                list_user_documents,
+                list_trash,
+                trash_document,
+                restore_trashed_document,
+                fetch_document_tags,
+                update_document_tags,
+                create_project,
+                fetch_project_contents,
+                add_project_member,
+                remove_project_member,
+                move_document,
+                star_document,
+                unstar_document,
+                list_recent,
+                list_starred,
+                invite,
+                list_document_invitations,
+                revoke_invitation,
+                list_my_invitations,
+                accept_invitation,
+                list_notifications,
+                mark_notifications_read,
+                fetch_usage,
                 fetch_document,
+                fetch_document_content,
+                fetch_document_at,
+                fetch_history_page,
+                fetch_blame,
+                poll_changes,
+                join,
+                leave,
+                update_selection,
+                fetch_selections,
+                send_message,
+                poll_messages,
+                fetch_chat_history,
+                yjs_sync,
+                export_document,
+                import_document,
+                run_document,
+                list_runs,
+                follow_run,
+                fetch_tokens,
+                fetch_audit_log,
+                create_share_link,
+                list_documents,
+                fetch_memory_usage,
+                unload_document,
+                compact_document,
+                fetch_version_vector,
+                fetch_broadcast_backlog,
+                fetch_members,
+                fetch_leases,
+                list_features,
+                set_feature,
+                clear_feature,
+                restore_backup,
+                restore_all_backups,
+                metrics,
+                ready,
                 handle_sns_notification,
+                receive_gossip,
+                fetch_digest,
+                list_errors,
+                fetch_error,
             ],
         )
 }
@@ -0,0 +1,334 @@
+use crate::rga::rga::RGA;
+use crate::routes::SharedRGAs;
+use crate::{snapshot, tenancy};
+use rocket::fairing::AdHoc;
+use rocket::tokio::{self, sync::Mutex};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_postgres::Client;
+use tracing::{error, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+/// Reads one per-document value from a document's memory usage.
+type Gauge = fn(&MemoryUsage) -> usize;
+
+/// Number of documents evicted since the replica started.
+static EVICTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Memory limits for the documents loaded on a replica.
+/// `max_bytes`: Approximate memory the loaded RGAs may use before idle documents are evicted, 0 disables the cap.
+/// `min_idle_secs`: Documents used more recently than this are never evicted.
+/// `check_interval_secs`: Seconds between checks of the cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MemoryConfig {
+    pub max_bytes: usize,
+    pub min_idle_secs: u64,
+    pub check_interval_secs: u64,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        MemoryConfig {
+            max_bytes: 256 * 1024 * 1024,
+            min_idle_secs: 300,
+            check_interval_secs: 30,
+        }
+    }
+}
+
+/// Approximate memory held by a single RGA.
+/// `nodes`: The number of nodes, including tombstones.
+/// `live`: The number of nodes that have not been deleted.
+/// `tombstones`: The number of deleted nodes still kept for ordering.
+/// `value_bytes`: The bytes held by node values.
+/// `buffered`: The number of out-of-order operations waiting to be applied.
+/// `approx_bytes`: The estimated total, including per-node overhead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryUsage {
+    pub nodes: usize,
+    pub live: usize,
+    pub tombstones: usize,
+    pub value_bytes: usize,
+    pub buffered: usize,
+    pub approx_bytes: usize,
+}
+
+/// Memory used by a loaded document.
+/// `document_id`: The document.
+/// `usage`: The memory held by its RGA.
+/// `idle_secs`: Seconds since the document was last read or changed.
+/// `dirty`: Whether the document has changes that are not in its snapshot yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentMemory {
+    pub document_id: Uuid,
+    #[serde(flatten)]
+    pub usage: MemoryUsage,
+    pub idle_secs: u64,
+    pub dirty: bool,
+}
+
+/// Memory used by every document loaded on the replica, largest first.
+/// `max_bytes`: The configured cap, 0 if disabled.
+/// `total_bytes`: The estimated memory held by all loaded documents.
+/// `evictions`: The number of documents evicted since the replica started.
+/// `documents`: Per-document usage.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryReport {
+    pub max_bytes: usize,
+    pub total_bytes: usize,
+    pub evictions: u64,
+    pub documents: Vec<DocumentMemory>,
+}
+
+impl MemoryReport {
+    /// Builds the report for the loaded documents.
+    pub async fn collect(rgas: &HashMap<Uuid, RGA>, config: &MemoryConfig) -> MemoryReport {
+        let mut documents: Vec<DocumentMemory> = Vec::with_capacity(rgas.len());
+        for (document_id, rga) in rgas {
+            documents.push(DocumentMemory {
+                document_id: *document_id,
+                usage: rga.memory_usage(),
+                idle_secs: rga.last_accessed.elapsed().as_secs(),
+                dirty: rga.dirty,
+            });
+        }
+        documents.sort_by_key(|d| std::cmp::Reverse(d.usage.approx_bytes));
+
+        MemoryReport {
+            max_bytes: config.max_bytes,
+            total_bytes: documents.iter().map(|d| d.usage.approx_bytes).sum(),
+            evictions: EVICTIONS.load(Ordering::Relaxed),
+            documents,
+        }
+    }
+
+    /// The documents to evict to get back under the cap: the largest documents that have been
+    /// idle for at least `min_idle_secs`, until the remaining total fits.
+    pub fn eviction_candidates(&self, config: &MemoryConfig) -> Vec<Uuid> {
+        let mut candidates: Vec<Uuid> = Vec::new();
+        if config.max_bytes == 0 {
+            return candidates;
+        }
+
+        let mut total: usize = self.total_bytes;
+        // documents are already ordered largest first
+        for document in &self.documents {
+            if total <= config.max_bytes {
+                break;
+            }
+            if document.idle_secs >= config.min_idle_secs {
+                candidates.push(document.document_id);
+                total -= document.usage.approx_bytes;
+            }
+        }
+        candidates
+    }
+
+    /// Renders the report in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP nimble_loaded_documents Documents loaded in memory."
+        );
+        let _ = writeln!(out, "# TYPE nimble_loaded_documents gauge");
+        let _ = writeln!(out, "nimble_loaded_documents {}", self.documents.len());
+        let _ = writeln!(
+            out,
+            "# HELP nimble_memory_bytes Approximate memory held by loaded documents."
+        );
+        let _ = writeln!(out, "# TYPE nimble_memory_bytes gauge");
+        let _ = writeln!(out, "nimble_memory_bytes {}", self.total_bytes);
+        let _ = writeln!(
+            out,
+            "# HELP nimble_memory_max_bytes Configured memory cap, 0 if disabled."
+        );
+        let _ = writeln!(out, "# TYPE nimble_memory_max_bytes gauge");
+        let _ = writeln!(out, "nimble_memory_max_bytes {}", self.max_bytes);
+        let _ = writeln!(
+            out,
+            "# HELP nimble_evictions_total Documents evicted to stay under the memory cap."
+        );
+        let _ = writeln!(out, "# TYPE nimble_evictions_total counter");
+        let _ = writeln!(out, "nimble_evictions_total {}", self.evictions);
+
+        let gauges: [(&str, &str, Gauge); 5] = [
+            (
+                "nimble_document_nodes",
+                "Nodes in a document, including tombstones.",
+                |u| u.nodes,
+            ),
+            (
+                "nimble_document_live_nodes",
+                "Nodes in a document that have not been deleted.",
+                |u| u.live,
+            ),
+            (
+                "nimble_document_tombstones",
+                "Deleted nodes kept in a document.",
+                |u| u.tombstones,
+            ),
+            (
+                "nimble_document_value_bytes",
+                "Bytes held by a document's values.",
+                |u| u.value_bytes,
+            ),
+            (
+                "nimble_document_memory_bytes",
+                "Approximate memory held by a document.",
+                |u| u.approx_bytes,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for document in &self.documents {
+                let _ = writeln!(
+                    out,
+                    "{}{{document_id=\"{}\"}} {}",
+                    name,
+                    document.document_id,
+                    value(&document.usage)
+                );
+            }
+        }
+
+        out
+    }
+}
+
+/// Evicts the largest idle documents while the loaded documents use more than `max_bytes`.
+/// Dirty documents are snapshotted first, a document whose snapshot fails is kept.
+/// Returns the number of documents evicted.
+pub async fn enforce_cap(
+    client: &mut Client,
+    rgas: &mut HashMap<Uuid, RGA>,
+    config: &MemoryConfig,
+) -> usize {
+    let report = MemoryReport::collect(rgas, config).await;
+    let mut evicted: usize = 0;
+
+    for document_id in report.eviction_candidates(config) {
+        let rga = match rgas.get(&document_id) {
+            Some(rga) => rga,
+            None => continue,
+        };
+
+        if rga.dirty {
+            if let Err(e) = snapshot::persist_snapshot(client, &document_id, rga).await {
+                error!(document_id = %document_id, "Failed to persist document before eviction: {}", e);
+                continue;
+            }
+        }
+
+        rgas.remove(&document_id);
+        EVICTIONS.fetch_add(1, Ordering::Relaxed);
+        evicted += 1;
+        info!(document_id = %document_id, "Evicted idle document");
+    }
+
+    if evicted > 0 {
+        info!(
+            "Evicted {} documents, {} of {} bytes were in use",
+            evicted, report.total_bytes, config.max_bytes
+        );
+    }
+    evicted
+}
+
+/// Fairing that starts a background task enforcing the memory cap every `check_interval_secs`.
+/// Evicted documents are reloaded from their snapshot the next time they are fetched.
+pub fn attach_memory_cap(config: MemoryConfig) -> AdHoc {
+    AdHoc::on_liftoff("Memory cap", move |rocket| {
+        Box::pin(async move {
+            if config.max_bytes == 0 || config.check_interval_secs == 0 {
+                info!("Memory cap is disabled");
+                return;
+            }
+
+            let (rgas, db) = match (
+                rocket.state::<SharedRGAs>(),
+                rocket.state::<Arc<Mutex<Client>>>(),
+            ) {
+                (Some(rgas), Some(db)) => (Arc::clone(rgas), Arc::clone(db)),
+                _ => {
+                    warn!("Replica state is unavailable, the memory cap is disabled");
+                    return;
+                }
+            };
+            let mut shutdown = rocket.shutdown();
+
+            tokio::spawn(async move {
+                let mut interval =
+                    tokio::time::interval(Duration::from_secs(config.check_interval_secs));
+                interval.tick().await;
+
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = &mut shutdown => break,
+                    }
+
+                    async {
+                        let mut rgas = rgas.lock().await;
+                        let mut client = tenancy::lock_any(&db).await;
+                        enforce_cap(&mut client, &mut rgas, &config).await;
+                    }
+                    .instrument(info_span!("memory.enforce_cap"))
+                    .await;
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(approx_bytes: usize, idle_secs: u64) -> DocumentMemory {
+        DocumentMemory {
+            document_id: Uuid::new_v4(),
+            usage: MemoryUsage {
+                approx_bytes,
+                ..Default::default()
+            },
+            idle_secs,
+            dirty: false,
+        }
+    }
+
+    #[test]
+    fn test_eviction_candidates() {
+        let documents = vec![document(500, 10), document(300, 600), document(200, 600)];
+        let report = MemoryReport {
+            max_bytes: 600,
+            total_bytes: 1000,
+            evictions: 0,
+            documents: documents.clone(),
+        };
+        let config = MemoryConfig {
+            max_bytes: 600,
+            min_idle_secs: 300,
+            check_interval_secs: 30,
+        };
+
+        // the largest document is in use, so both idle documents go
+        assert_eq!(
+            report.eviction_candidates(&config),
+            vec![documents[1].document_id, documents[2].document_id]
+        );
+
+        let disabled = MemoryConfig {
+            max_bytes: 0,
+            ..config
+        };
+        assert!(report.eviction_candidates(&disabled).is_empty());
+    }
+}
@@ -0,0 +1,443 @@
+//! In-app notifications telling users about changes made by others that concern them.
+//!
+//! Routes record a notification in the `notifications` table after the change is committed,
+//! such as being invited to a document or added to an organization. Recording is best effort:
+//! a notification that can't be stored is logged and the request still succeeds. Users list
+//! their notifications with `GET /me/notifications` and mark them read with
+//! `POST /me/notifications/read`.
+
+use crate::limits::JsonBody;
+use crate::tenancy::{self, Tenant};
+use crate::{expiry, Actor, ApiError, FieldError, RequestId};
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use rocket::{get, post};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio_postgres::{Client, Row};
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+/// Notifications returned per page when the request does not set a limit.
+pub const DEFAULT_LIMIT: i64 = 50;
+
+/// Most notifications returned per page.
+pub const MAX_LIMIT: i64 = 200;
+
+/// What a notification is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    /// The user's email was invited to a document.
+    Invited,
+    /// Someone accepted the user's invitation to their document.
+    InvitationAccepted,
+    /// The user was given access to a project.
+    AddedToProject,
+    /// The user was added to an organization.
+    AddedToOrganization,
+    /// A document the user owns or is a member of was restored.
+    DocumentRestored,
+}
+
+impl NotificationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::Invited => "invited",
+            NotificationKind::InvitationAccepted => "invitation_accepted",
+            NotificationKind::AddedToProject => "added_to_project",
+            NotificationKind::AddedToOrganization => "added_to_organization",
+            NotificationKind::DocumentRestored => "document_restored",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "invited" => Some(NotificationKind::Invited),
+            "invitation_accepted" => Some(NotificationKind::InvitationAccepted),
+            "added_to_project" => Some(NotificationKind::AddedToProject),
+            "added_to_organization" => Some(NotificationKind::AddedToOrganization),
+            "document_restored" => Some(NotificationKind::DocumentRestored),
+            _ => None,
+        }
+    }
+}
+
+/// A notification.
+/// `notification_id`: Increases with every notification, used as the cursor of the listing.
+/// `actor_id`: The user who made the change, None for administrators and the replica.
+/// `link`: The API path of what the notification is about, such as `/document/<id>`.
+/// `read_at`: When the user marked the notification read, None while it is unread.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    pub notification_id: i64,
+    pub kind: NotificationKind,
+    pub actor_id: Option<Uuid>,
+    pub message: String,
+    pub link: String,
+    pub created_at: String,
+    pub read_at: Option<String>,
+}
+
+impl Notification {
+    fn from_row(row: &Row) -> Option<Self> {
+        Some(Notification {
+            notification_id: row.get("notification_id"),
+            kind: NotificationKind::parse(row.get("kind"))?,
+            actor_id: row.get("actor_id"),
+            message: row.get("message"),
+            link: row.get("link"),
+            created_at: row.get("created_at"),
+            read_at: row.get("read_at"),
+        })
+    }
+}
+
+/// A page of notifications, newest first.
+/// `next_cursor`: Pass as `cursor` to get the older notifications, None on the last page.
+/// `unread`: The user's unread notifications, across every page.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NotificationPage {
+    pub notifications: Vec<Notification>,
+    pub next_cursor: Option<i64>,
+    pub unread: i64,
+}
+
+/// Request body for marking notifications read.
+/// `notification_ids`: The notifications to mark read.
+/// `all`: Marks every notification of the user read instead.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MarkReadRequest {
+    pub notification_ids: Vec<i64>,
+    pub all: bool,
+}
+
+impl MarkReadRequest {
+    /// Checks the request names notifications or `all`, but not both.
+    pub fn check(&self) -> Result<(), FieldError> {
+        match (self.all, self.notification_ids.is_empty()) {
+            (true, false) => Err(FieldError::new(
+                "notification_ids",
+                "must be empty when all is set",
+            )),
+            (false, true) => Err(FieldError::new(
+                "notification_ids",
+                "must not be empty unless all is set",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the number of notifications to list, or `422` if the limit is out of range.
+pub fn parse_limit(limit: Option<i64>) -> Result<i64, ApiError> {
+    let limit: i64 = limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ApiError::ValidationFailed(vec![FieldError::new(
+            "limit",
+            &format!("must be between 1 and {}", MAX_LIMIT),
+        )]));
+    }
+    Ok(limit)
+}
+
+/// Notifies a user, unless they made the change themselves.
+#[instrument(name = "db.notify", skip(client, message))]
+pub async fn notify(
+    client: &Client,
+    user_id: &Uuid,
+    kind: NotificationKind,
+    actor_id: Option<Uuid>,
+    message: &str,
+    link: &str,
+) {
+    if actor_id == Some(*user_id) {
+        return;
+    }
+    if client
+        .execute(
+            "INSERT INTO notifications (user_id,kind,actor_id,message,link,created_at) VALUES ($1,$2,$3,$4,$5,$6)",
+            &[
+                user_id,
+                &kind.as_str(),
+                &actor_id,
+                &message,
+                &link,
+                &expiry::timestamp(chrono::Utc::now()),
+            ],
+        )
+        .await
+        .is_err()
+    {
+        warn!("Failed to record a {} notification", kind.as_str());
+    }
+}
+
+/// Notifies the owner and the members of a document, except the user who made the change.
+#[instrument(name = "db.notify_document", skip(client, message))]
+pub async fn notify_document(
+    client: &Client,
+    document_id: &Uuid,
+    kind: NotificationKind,
+    actor_id: Option<Uuid>,
+    message: &str,
+) {
+    if client
+        .execute(
+            "INSERT INTO notifications (user_id,kind,actor_id,message,link,created_at) \
+             SELECT u.user_id,$2,$3,$4,$5,$6 FROM ( \
+                 SELECT owner_id AS user_id FROM document WHERE document_id=$1 \
+                 UNION SELECT user_id FROM document_members WHERE document_id=$1) u \
+             WHERE u.user_id IS DISTINCT FROM $3",
+            &[
+                document_id,
+                &kind.as_str(),
+                &actor_id,
+                &message,
+                &format!("/document/{}", document_id),
+                &expiry::timestamp(chrono::Utc::now()),
+            ],
+        )
+        .await
+        .is_err()
+    {
+        warn!("Failed to record {} notifications", kind.as_str());
+    }
+}
+
+fn database_error() -> ApiError {
+    error!("Failed to query the notifications table");
+    ApiError::DatabaseError("Failed to query the notifications table".to_string())
+}
+
+/// Lists the requesting user's notifications, newest first.
+/// `unread`: Only lists the unread notifications.
+/// `cursor`: The `next_cursor` of the previous page.
+/// `limit`: Notifications per page, 50 by default and at most 200.
+#[get("/me/notifications?<unread>&<cursor>&<limit>")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn list_notifications(
+    unread: Option<bool>,
+    cursor: Option<i64>,
+    limit: Option<i64>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<NotificationPage>, ApiError> {
+    let limit: i64 = parse_limit(limit)?;
+    let user_id: Uuid = actor.require_user()?;
+
+    let client = tenancy::lock(db, &Tenant::Any).await?;
+    let rows = match client
+        .query(
+            "SELECT * FROM notifications WHERE user_id=$1 AND ($2::bigint IS NULL OR notification_id < $2) \
+             AND (NOT $3 OR read_at IS NULL) ORDER BY notification_id DESC LIMIT $4",
+            &[&user_id, &cursor, &unread.unwrap_or(false), &(limit + 1)],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => return Err(database_error()),
+    };
+    let unread: i64 = match client
+        .query_one(
+            "SELECT COUNT(*) FROM notifications WHERE user_id=$1 AND read_at IS NULL",
+            &[&user_id],
+        )
+        .await
+    {
+        Ok(row) => row.get(0),
+        Err(_) => return Err(database_error()),
+    };
+
+    let more: bool = rows.len() as i64 > limit;
+    let notifications: Vec<Notification> = rows
+        .iter()
+        .take(limit as usize)
+        .filter_map(Notification::from_row)
+        .collect();
+    Ok(Json(NotificationPage {
+        next_cursor: more
+            .then(|| notifications.last().map(|n| n.notification_id))
+            .flatten(),
+        notifications,
+        unread,
+    }))
+}
+
+/// Marks the requesting user's notifications read. Returns how many were unread.
+///
+/// Example Request
+/// {
+///     "notification_ids" : [41, 42]
+/// }
+#[post("/me/notifications/read", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn mark_notifications_read(
+    request: JsonBody<MarkReadRequest>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<u64>, ApiError> {
+    if let Err(error) = request.check() {
+        return Err(ApiError::ValidationFailed(vec![error]));
+    }
+    let user_id: Uuid = actor.require_user()?;
+
+    let client = tenancy::lock(db, &Tenant::Any).await?;
+    let marked: u64 = match client
+        .execute(
+            "UPDATE notifications SET read_at=$3 WHERE user_id=$1 AND read_at IS NULL \
+             AND ($2 OR notification_id = ANY($4))",
+            &[
+                &user_id,
+                &request.all,
+                &expiry::timestamp(chrono::Utc::now()),
+                &request.notification_ids,
+            ],
+        )
+        .await
+    {
+        Ok(marked) => marked,
+        Err(_) => return Err(database_error()),
+    };
+
+    info!(marked, "Notifications marked read");
+    Ok(Json(marked))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestDatabase, NOTIFICATION_TABLES};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::asynchronous::Client as LocalClient;
+
+    #[test]
+    fn test_notification_requests() {
+        for kind in [
+            NotificationKind::Invited,
+            NotificationKind::InvitationAccepted,
+            NotificationKind::AddedToProject,
+            NotificationKind::AddedToOrganization,
+            NotificationKind::DocumentRestored,
+        ] {
+            assert_eq!(NotificationKind::parse(kind.as_str()), Some(kind));
+            assert_eq!(
+                serde_json::to_string(&kind).unwrap(),
+                format!("\"{}\"", kind.as_str())
+            );
+        }
+
+        let request = |notification_ids: Vec<i64>, all: bool| MarkReadRequest {
+            notification_ids,
+            all,
+        };
+        assert!(request(vec![1, 2], false).check().is_ok());
+        assert!(request(Vec::new(), true).check().is_ok());
+        assert!(request(Vec::new(), false).check().is_err());
+        assert!(request(vec![1], true).check().is_err());
+
+        assert_eq!(parse_limit(None).ok(), Some(DEFAULT_LIMIT));
+        assert!(parse_limit(Some(0)).is_err());
+        assert!(parse_limit(Some(MAX_LIMIT + 1)).is_err());
+    }
+
+    /// A page of the user's notifications at `uri`.
+    async fn page(client: &LocalClient, uri: &str, user_id: Uuid) -> NotificationPage {
+        let response = client
+            .get(uri.to_string())
+            .header(testing::session(user_id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json().await.unwrap()
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_notification_routes() {
+        let database = TestDatabase::create(NOTIFICATION_TABLES).await;
+        let db: Client = database.connect().await;
+        let (user, other) = (Uuid::new_v4(), Uuid::new_v4());
+        for message in ["first", "second", "third"] {
+            notify(
+                &db,
+                &user,
+                NotificationKind::Invited,
+                Some(other),
+                message,
+                "/me/invitations",
+            )
+            .await;
+        }
+        // nobody is notified of their own changes
+        notify(
+            &db,
+            &other,
+            NotificationKind::Invited,
+            Some(other),
+            "own",
+            "/me/invitations",
+        )
+        .await;
+        let rocket = testing::rocket(&database).await.mount(
+            "/",
+            rocket::routes![list_notifications, mark_notifications_read],
+        );
+        let client = LocalClient::tracked(rocket).await.unwrap();
+        let mark_read = |user_id: Uuid, body: String| {
+            client
+                .post("/me/notifications/read")
+                .header(ContentType::JSON)
+                .header(testing::session(user_id))
+                .body(body)
+                .dispatch()
+        };
+
+        let anonymous = client.get("/me/notifications").dispatch().await;
+        assert_eq!(anonymous.status(), Status::Unauthorized);
+        assert!(page(&client, "/me/notifications", other)
+            .await
+            .notifications
+            .is_empty());
+
+        let first: NotificationPage = page(&client, "/me/notifications?limit=2", user).await;
+        let messages: Vec<&str> = first
+            .notifications
+            .iter()
+            .map(|n| n.message.as_str())
+            .collect();
+        assert_eq!(messages, vec!["third", "second"]);
+        assert_eq!(first.unread, 3);
+        let next = format!(
+            "/me/notifications?limit=2&cursor={}",
+            first.next_cursor.unwrap()
+        );
+        let last: NotificationPage = page(&client, &next, user).await;
+        assert_eq!(last.notifications.len(), 1);
+        assert_eq!(last.notifications[0].message, "first");
+        assert_eq!(last.next_cursor, None);
+
+        // users can only mark their own notifications read
+        let newest: i64 = first.notifications[0].notification_id;
+        let response = mark_read(other, format!(r#"{{"notification_ids":[{}]}}"#, newest)).await;
+        assert_eq!(response.into_json::<u64>().await, Some(0));
+        let response = mark_read(user, format!(r#"{{"notification_ids":[{}]}}"#, newest)).await;
+        assert_eq!(response.into_json::<u64>().await, Some(1));
+        let unread: NotificationPage = page(&client, "/me/notifications?unread=true", user).await;
+        assert_eq!(unread.unread, 2);
+        assert_eq!(unread.notifications.len(), 2);
+
+        let response = mark_read(user, r#"{"all":true}"#.to_string()).await;
+        assert_eq!(response.into_json::<u64>().await, Some(2));
+        let response = mark_read(user, "{}".to_string()).await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let all: NotificationPage = page(&client, "/me/notifications", user).await;
+        assert_eq!(all.unread, 0);
+        assert!(all.notifications.iter().all(|n| n.read_at.is_some()));
+
+        database.drop().await;
+    }
+}
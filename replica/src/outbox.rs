@@ -0,0 +1,270 @@
+//! Broadcast outbox: operations waiting to be published to the other replicas.
+//!
+//! Routes write each operation to the `broadcast_outbox` table in the transaction that persists
+//! it, and return without waiting for SNS. A relay on every replica publishes the replica's own
+//! rows in the order they were written and deletes each once SNS accepts it. An operation that
+//! was committed is therefore broadcast even if SNS is down or the replica restarts, and a slow
+//! publish delays the other replicas rather than the requests of this one. An operation can be
+//! published twice if its row could not be deleted, which replicas applying it tolerate.
+
+use crate::pool::Pool;
+use crate::reporting::{self, ErrorEvent, Level, Source};
+use crate::tenancy::Tenant;
+use crate::{db, encryption, replica_id, ApiError, BroadcastOperation};
+use aws_sdk_sns::Client as SnsClient;
+use rocket::fairing::AdHoc;
+use rocket::tokio::sync::{Mutex, Notify};
+use rocket::tokio::{self, time};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_postgres::Transaction;
+use tracing::{debug, error, instrument, warn};
+
+/// `interval_secs`: How often the relay retries operations it failed to publish.
+/// `batch_size`: The number of operations read from the table at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboxConfig {
+    pub interval_secs: u64,
+    pub batch_size: i64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            interval_secs: 5,
+            batch_size: 100,
+        }
+    }
+}
+
+/// Wakes the relay when operations are written to the outbox.
+#[derive(Debug, Clone, Default)]
+pub struct Outbox {
+    notify: Arc<Notify>,
+}
+
+impl Outbox {
+    /// Tells the relay there are operations to publish. Call after the transaction commits.
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+}
+
+/// Writes an operation to the outbox as part of a transaction.
+pub async fn enqueue(tx: &Transaction<'_>, operation: &BroadcastOperation) -> Result<(), ApiError> {
+    let message: String = match serde_json::to_string(operation) {
+        Ok(message) => message,
+        Err(_) => {
+            error!("Failed to serialize operation");
+            return Err(ApiError::InternalServerError(
+                "Failed to serialize operation".to_string(),
+            ));
+        }
+    };
+
+    match tx
+        .execute(
+            "INSERT INTO broadcast_outbox (replica_id,document_id,operation,created_at) VALUES ($1,$2,$3,$4)",
+            &[
+                &replica_id().unwrap_or_default(),
+                &operation.document_id,
+                &encryption::seal(&message),
+                &chrono::Utc::now().to_rfc3339(),
+            ],
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            error!("Failed to insert into broadcast_outbox table");
+            Err(ApiError::DatabaseError(
+                "Failed to insert into broadcast_outbox table".to_string(),
+            ))
+        }
+    }
+}
+
+/// Reads the next operations the replica has to publish.
+async fn pending(
+    pool: &Pool,
+    batch_size: i64,
+) -> Result<Vec<(i64, Option<BroadcastOperation>)>, ApiError> {
+    let client = pool.get(&Tenant::Any).await?;
+    let rows = match client
+        .query(
+            "SELECT outbox_id,operation FROM broadcast_outbox WHERE replica_id=$1 ORDER BY outbox_id LIMIT $2",
+            &[&replica_id().unwrap_or_default(), &batch_size],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => {
+            error!("Failed to read the broadcast_outbox table");
+            return Err(ApiError::DatabaseError(
+                "Failed to read the broadcast_outbox table".to_string(),
+            ));
+        }
+    };
+
+    let mut pending: Vec<(i64, Option<BroadcastOperation>)> = Vec::with_capacity(rows.len());
+    for row in rows {
+        let message: String = encryption::open(&client, row.get(1)).await?;
+        pending.push((row.get(0), serde_json::from_str(&message).ok()));
+    }
+    Ok(pending)
+}
+
+/// The number of the replica's operations waiting to be published.
+pub async fn backlog(pool: &Pool) -> Result<i64, ApiError> {
+    let client = pool.get(&Tenant::Any).await?;
+    match client
+        .query_one(
+            "SELECT COUNT(*) FROM broadcast_outbox WHERE replica_id=$1",
+            &[&replica_id().unwrap_or_default()],
+        )
+        .await
+    {
+        Ok(row) => Ok(row.get(0)),
+        Err(_) => {
+            error!("Failed to read the broadcast_outbox table");
+            Err(ApiError::DatabaseError(
+                "Failed to read the broadcast_outbox table".to_string(),
+            ))
+        }
+    }
+}
+
+async fn remove(pool: &Pool, outbox_id: i64) -> Result<(), ApiError> {
+    let client = pool.get(&Tenant::Any).await?;
+    match client
+        .execute(
+            "DELETE FROM broadcast_outbox WHERE outbox_id=$1",
+            &[&outbox_id],
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            error!("Failed to delete from the broadcast_outbox table");
+            Err(ApiError::DatabaseError(
+                "Failed to delete from the broadcast_outbox table".to_string(),
+            ))
+        }
+    }
+}
+
+/// Publishes the replica's operations in the outbox. Stops at the first one SNS rejects so
+/// later operations are not sent before it. No connection is held while publishing.
+/// Returns the number of operations published.
+#[instrument(name = "outbox.relay", skip_all)]
+async fn relay(
+    pool: &Pool,
+    sns_client: &Arc<Mutex<SnsClient>>,
+    topic: &Arc<Mutex<String>>,
+    config: &OutboxConfig,
+) -> Result<usize, ApiError> {
+    let mut published: usize = 0;
+    loop {
+        let pending = pending(pool, config.batch_size).await?;
+        let done: bool = (pending.len() as i64) < config.batch_size;
+
+        for (outbox_id, operation) in pending {
+            match operation {
+                Some(operation) => {
+                    let topic_arn: String = topic.lock().await.clone();
+                    if let Err(e) =
+                        db::send_operation(Arc::clone(sns_client), &topic_arn, &operation).await
+                    {
+                        warn!("Failed to publish an operation, retrying later: {}", e);
+                        reporting::report(
+                            ErrorEvent::new(
+                                Level::Error,
+                                Source::Broadcast,
+                                "broadcast::publish_failed",
+                                format!("Failed to publish an operation: {}", e),
+                            )
+                            .with_request_id(operation.request_id.clone())
+                            .with_document(Some(operation.document_id))
+                            .with_extra("outbox_id", outbox_id),
+                        );
+                        return Ok(published);
+                    }
+                    published += 1;
+                }
+                None => error!(
+                    outbox_id,
+                    "Dropping an outbox entry that is not an operation"
+                ),
+            }
+            remove(pool, outbox_id).await?;
+        }
+
+        if done {
+            return Ok(published);
+        }
+    }
+}
+
+/// Fairing running the relay, woken by routes after they write to the outbox and every
+/// `outbox.interval_secs` to retry failed publishes and send operations left by a restart.
+pub fn attach_outbox(config: OutboxConfig) -> AdHoc {
+    AdHoc::on_liftoff("Broadcast outbox", move |rocket| {
+        Box::pin(async move {
+            let (pool, sns_client, topic, outbox) = match (
+                rocket.state::<Pool>(),
+                rocket.state::<Arc<Mutex<SnsClient>>>(),
+                rocket.state::<Arc<Mutex<String>>>(),
+                rocket.state::<Outbox>(),
+            ) {
+                (Some(pool), Some(sns_client), Some(topic), Some(outbox)) => (
+                    pool.clone(),
+                    Arc::clone(sns_client),
+                    Arc::clone(topic),
+                    outbox.clone(),
+                ),
+                _ => {
+                    warn!("Replica state is unavailable, operations will not be broadcast");
+                    return;
+                }
+            };
+            let mut shutdown = rocket.shutdown();
+
+            tokio::spawn(async move {
+                let mut interval = time::interval(Duration::from_secs(config.interval_secs));
+                loop {
+                    tokio::select! {
+                        _ = outbox.notify.notified() => {}
+                        _ = interval.tick() => {}
+                        _ = &mut shutdown => break,
+                    }
+                    match relay(&pool, &sns_client, &topic, &config).await {
+                        Ok(0) => {}
+                        Ok(published) => {
+                            debug!("Published {} operations from the outbox", published)
+                        }
+                        Err(e) => warn!("Failed to relay the broadcast outbox: {}", e),
+                    }
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wake_while_relaying() {
+        let outbox = Outbox::default();
+
+        // routes commit while the relay is busy publishing, it runs again once it is done
+        outbox.wake();
+        outbox.wake();
+        time::timeout(Duration::from_secs(1), outbox.notify.notified())
+            .await
+            .expect("the wake was kept for the relay");
+    }
+}
@@ -0,0 +1,189 @@
+//! A small pool of database connections for the write path.
+//!
+//! Most routes share the replica's single `Client`, which is fine for short reads but
+//! serialises every request behind whichever one holds it. Operations are persisted on a
+//! connection from the pool instead, so a slow transaction only holds up requests once every
+//! connection is busy. Every document request is authorized on a pooled connection as well,
+//! and the outbox relay uses the pool too.
+
+use crate::tenancy::{self, Tenant};
+use crate::{connect_to_db, ApiError};
+use rocket::fairing::AdHoc;
+use rocket::tokio::sync::{Mutex, MutexGuard, Semaphore, SemaphorePermit};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio_postgres::Client;
+use tracing::{error, info};
+
+/// The connections of the pool, shared by every clone.
+/// `C` is only generic so the checkout can be tested without a database.
+#[derive(Debug)]
+pub struct Pool<C = Client> {
+    connections: Arc<Vec<Mutex<C>>>,
+    idle: Arc<Semaphore>,
+    next: Arc<AtomicUsize>,
+}
+
+impl<C> Clone for Pool<C> {
+    fn clone(&self) -> Self {
+        Pool {
+            connections: Arc::clone(&self.connections),
+            idle: Arc::clone(&self.idle),
+            next: Arc::clone(&self.next),
+        }
+    }
+}
+
+/// A connection taken from the pool, returned to it when dropped.
+pub struct PooledConnection<'a, C = Client> {
+    connection: MutexGuard<'a, C>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<C> Deref for PooledConnection<'_, C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.connection
+    }
+}
+
+impl<C> DerefMut for PooledConnection<'_, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut self.connection
+    }
+}
+
+impl<C> Pool<C> {
+    pub fn new(connections: Vec<C>) -> Self {
+        assert!(!connections.is_empty(), "a pool needs a connection");
+        Pool {
+            idle: Arc::new(Semaphore::new(connections.len())),
+            connections: Arc::new(connections.into_iter().map(Mutex::new).collect()),
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Takes an idle connection, starting after the last one handed out. When every
+    /// connection is busy it waits for the first one to be returned.
+    pub async fn checkout(&self) -> PooledConnection<'_, C> {
+        let permit: SemaphorePermit<'_> = self
+            .idle
+            .acquire()
+            .await
+            .expect("the pool's semaphore is never closed");
+        let start: usize = self.next.fetch_add(1, Ordering::Relaxed);
+        for i in 0..self.size() {
+            if let Ok(connection) = self.connections[(start + i) % self.size()].try_lock() {
+                return PooledConnection {
+                    connection,
+                    _permit: permit,
+                };
+            }
+        }
+        // only reached while a connection is being replaced
+        PooledConnection {
+            connection: self.connections[start % self.size()].lock().await,
+            _permit: permit,
+        }
+    }
+}
+
+impl Pool<Client> {
+    /// Opens `size` connections to the database.
+    pub async fn connect(database_url: &str, size: usize) -> Result<Self, ApiError> {
+        let mut connections: Vec<Client> = Vec::with_capacity(size);
+        for _ in 0..size {
+            connections.push(connect_to_db(database_url).await?);
+        }
+        Ok(Pool::new(connections))
+    }
+
+    /// Takes a connection scoped to a tenant.
+    pub async fn get(&self, tenant: &Tenant) -> Result<PooledConnection<'_>, ApiError> {
+        let client = self.checkout().await;
+        tenancy::scope(&client, tenant).await?;
+        Ok(client)
+    }
+
+    /// Whether any connection was closed.
+    pub async fn is_closed(&self) -> bool {
+        for connection in self.connections.iter() {
+            if connection.lock().await.is_closed() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Replaces every connection with a new one to `database_url`, one at a time so the
+    /// others stay usable.
+    pub async fn reconnect(&self, database_url: &str) -> Result<(), ApiError> {
+        for connection in self.connections.iter() {
+            let client: Client = connect_to_db(database_url).await?;
+            *connection.lock().await = client;
+        }
+        Ok(())
+    }
+}
+
+/// Fairing opening the pool and adding it to rocket's state.
+pub fn attach_pool(database_url: String, size: usize) -> AdHoc {
+    AdHoc::on_ignite("Attach database pool", move |rocket| async move {
+        match Pool::connect(&database_url, size).await {
+            Ok(pool) => {
+                info!("Opened {} pooled database connections", size);
+                rocket.manage(pool)
+            }
+            Err(e) => {
+                error!(
+                    "Unable to start server, failed to open the database pool: {}",
+                    e
+                );
+                eprintln!("Failed to open the database pool: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::tokio;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_checkout_skips_busy_connections() {
+        let pool: Pool<usize> = Pool::new(vec![0, 1]);
+
+        // a request holding a connection across a slow transaction or broadcast
+        let held = pool.checkout().await;
+        let other = tokio::time::timeout(Duration::from_secs(1), pool.checkout())
+            .await
+            .expect("an idle connection was available");
+        assert_ne!(*held, *other);
+
+        // with every connection busy the next request waits for one to be released
+        let waiting = {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                let connection = *pool.checkout().await;
+                connection
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        drop(other);
+        let connection = tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .expect("the released connection was handed out")
+            .unwrap();
+        assert_ne!(connection, *held);
+    }
+}
@@ -0,0 +1,684 @@
+//! Projects: folders grouping documents, which can be nested in other projects.
+//!
+//! A project belongs to the user who created it and, like documents, to a workspace or to
+//! nobody. Its owner grants other users read or read-write access to it, and those grants
+//! apply to every document in the project and in the projects nested in it. Documents inherit
+//! the grants of the project they are in, so moving a document to another project changes who
+//! can open it. Grants add to the access the members of a workspace's organization already
+//! have, they never take it away.
+
+use crate::pool::Pool;
+use crate::limits::JsonBody;
+use crate::notifications::{self, NotificationKind};
+use crate::share::Access;
+use crate::tenancy::{self, Role, Tenant};
+use crate::{audit, expiry, Actor, ApiError, FieldError, RequestId};
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use rocket::{delete, get, post};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// Request body for creating a project.
+/// `parent_id`: The project to nest it in, None for a top-level project.
+/// `workspace_id`: The workspace of a top-level project, None for a personal project. Nested
+/// projects are in their parent's workspace.
+#[derive(Debug, Deserialize)]
+pub struct ProjectRequest {
+    pub name: String,
+    #[serde(default)]
+    pub parent_id: Option<Uuid>,
+    #[serde(default)]
+    pub workspace_id: Option<Uuid>,
+}
+
+/// Request body for granting a user access to a project.
+#[derive(Debug, Deserialize)]
+pub struct ProjectMemberRequest {
+    pub user_id: Uuid,
+    pub access: Access,
+}
+
+/// Request body for moving a document to a project.
+/// `project_id`: The project to move it to, None to take it out of its project.
+#[derive(Debug, Deserialize)]
+pub struct MoveDocumentRequest {
+    pub project_id: Option<Uuid>,
+}
+
+/// A project.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Project {
+    pub project_id: Uuid,
+    pub owner_id: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub workspace_id: Option<Uuid>,
+    pub name: String,
+    pub created_at: String,
+}
+
+/// A user granted access to a project.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProjectMember {
+    pub user_id: Uuid,
+    pub access: Access,
+}
+
+/// A document listed in a project.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProjectDocument {
+    pub document_id: Uuid,
+    pub owner_id: Uuid,
+    pub title: String,
+    pub creation_date: String,
+}
+
+/// Response body with a project and what it holds.
+/// `access`: What the requesting user may do in the project.
+/// `members`: The users granted access to the project itself, not to its parents.
+/// `projects`: The projects nested directly in it.
+/// `documents`: The documents directly in it, archived documents are left out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProjectContents {
+    pub project: Project,
+    pub access: Access,
+    pub members: Vec<ProjectMember>,
+    pub projects: Vec<Project>,
+    pub documents: Vec<ProjectDocument>,
+}
+
+/// The access a user has to a project: read-write if they own it or one of its parents,
+/// otherwise the greatest of their grants on it and its parents and of their role in its
+/// organization.
+pub fn combine(owner: bool, granted: Option<Access>, role: Option<Role>) -> Option<Access> {
+    if owner {
+        return Some(Access::ReadWrite);
+    }
+    granted.max(role.map(|role| role.access()))
+}
+
+fn parse_id(id: &str, name: &str) -> Result<Uuid, ApiError> {
+    match Uuid::parse_str(id) {
+        Ok(id) => Ok(id),
+        Err(_) => {
+            error!("Failed to parse {} id", name);
+            Err(ApiError::InvalidOperation(format!(
+                "Failed to parse {} id",
+                name
+            )))
+        }
+    }
+}
+
+fn database_error(table: &str) -> ApiError {
+    error!("Failed to query the {} table", table);
+    ApiError::DatabaseError(format!("Failed to query the {} table", table))
+}
+
+fn not_found() -> ApiError {
+    ApiError::NotFound("Project not found".to_string())
+}
+
+/// Returns the access a user has to a project, None if they have none.
+/// Returns `NotFound` if the project does not exist.
+#[instrument(name = "db.project_access", skip(client))]
+pub async fn access(
+    client: &Client,
+    project_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<Option<Access>, ApiError> {
+    let row = match client
+        .query_opt(
+            "WITH RECURSIVE chain (project_id, parent_id, owner_id, workspace_id) AS ( \
+                 SELECT project_id,parent_id,owner_id,workspace_id FROM projects WHERE project_id=$1 \
+                 UNION ALL SELECT p.project_id,p.parent_id,p.owner_id,p.workspace_id FROM projects p JOIN chain c ON p.project_id=c.parent_id) \
+             SELECT bool_or(c.owner_id=$2), max(pm.access), \
+                 (SELECT m.role FROM projects p JOIN workspaces w ON w.workspace_id=p.workspace_id \
+                  JOIN org_members m ON m.org_id=w.org_id AND m.user_id=$2 WHERE p.project_id=$1) \
+             FROM chain c LEFT JOIN project_members pm ON pm.project_id=c.project_id AND pm.user_id=$2 \
+             HAVING count(*) > 0",
+            &[project_id, user_id],
+        )
+        .await
+    {
+        Ok(Some(row)) => row,
+        Ok(None) => return Err(not_found()),
+        Err(_) => return Err(database_error("projects")),
+    };
+
+    // "read_write" sorts after "read", so the greatest grant is the widest
+    let granted: Option<Access> = row.get::<_, Option<&str>>(1).and_then(Access::parse);
+    let role: Option<Role> = row.get::<_, Option<&str>>(2).and_then(Role::parse);
+    Ok(combine(
+        row.get::<_, Option<bool>>(0).unwrap_or(false),
+        granted,
+        role,
+    ))
+}
+
+/// Checks a user has `required` access to a project. Projects the user can't read are
+/// reported as not found.
+pub async fn require_access(
+    client: &Client,
+    project_id: &Uuid,
+    user_id: &Uuid,
+    required: Access,
+) -> Result<Access, ApiError> {
+    match access(client, project_id, user_id).await? {
+        Some(access) if access >= required => Ok(access),
+        Some(_) => {
+            error!("Read-only project member tried to change it");
+            Err(ApiError::Forbidden(
+                "The project is shared with you read-only".to_string(),
+            ))
+        }
+        None => Err(not_found()),
+    }
+}
+
+async fn fetch_project(client: &Client, project_id: &Uuid) -> Result<Project, ApiError> {
+    match client
+        .query_opt(
+            "SELECT project_id,owner_id,parent_id,workspace_id,name,created_at FROM projects WHERE project_id=$1",
+            &[project_id],
+        )
+        .await
+    {
+        Ok(Some(row)) => Ok(project_from_row(&row)),
+        Ok(None) => Err(not_found()),
+        Err(_) => Err(database_error("projects")),
+    }
+}
+
+fn project_from_row(row: &tokio_postgres::Row) -> Project {
+    Project {
+        project_id: row.get(0),
+        owner_id: row.get(1),
+        parent_id: row.get(2),
+        workspace_id: row.get(3),
+        name: row.get(4),
+        created_at: row.get(5),
+    }
+}
+
+/// Creates a project owned by the requesting user. Nesting a project needs read-write access
+/// to its parent, creating one in a workspace needs the member role in its organization.
+///
+/// Example Request
+/// {
+///     "name" : "Interview questions",
+///     "parent_id" : null,
+///     "workspace_id" : "9b2f0c4e-1d7a-4f3e-8c6b-5a0e2d9f7b13"
+/// }
+#[post("/projects", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn create_project(
+    request: JsonBody<ProjectRequest>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<Project>, ApiError> {
+    let user_id: Uuid = actor.require_user()?;
+    let name: String = match (tenancy::NameRequest {
+        name: request.name.clone(),
+    })
+    .name()
+    {
+        Ok(name) => name,
+        Err(error) => return Err(ApiError::ValidationFailed(vec![error])),
+    };
+
+    let client = tenancy::lock(db, &Tenant::Any).await?;
+    let workspace_id: Option<Uuid> = match request.parent_id {
+        Some(parent_id) => {
+            require_access(&client, &parent_id, &user_id, Access::ReadWrite).await?;
+            let parent: Project = fetch_project(&client, &parent_id).await?;
+            if request.workspace_id.is_some() && request.workspace_id != parent.workspace_id {
+                return Err(ApiError::ValidationFailed(vec![FieldError::new(
+                    "workspace_id",
+                    "must be the workspace of the parent project",
+                )]));
+            }
+            parent.workspace_id
+        }
+        None => {
+            if let Some(workspace_id) = request.workspace_id {
+                let org_id: Uuid = tenancy::workspace_org(&client, &workspace_id).await?;
+                tenancy::require_role(&client, &org_id, &user_id, Role::Member).await?;
+            }
+            request.workspace_id
+        }
+    };
+
+    let created_at: String = expiry::timestamp(chrono::Utc::now());
+    let project_id: Uuid = match client
+        .query_one(
+            "INSERT INTO projects (owner_id,parent_id,workspace_id,name,created_at) VALUES ($1,$2,$3,$4,$5) RETURNING project_id",
+            &[&user_id, &request.parent_id, &workspace_id, &name, &created_at],
+        )
+        .await
+    {
+        Ok(row) => row.get(0),
+        Err(_) => {
+            error!("Failed to insert into the projects table");
+            return Err(ApiError::DatabaseError(
+                "Failed to create the project".to_string(),
+            ));
+        }
+    };
+
+    info!(project_id = %project_id, "Project created");
+    Ok(Json(Project {
+        project_id,
+        owner_id: user_id,
+        parent_id: request.parent_id,
+        workspace_id,
+        name,
+        created_at,
+    }))
+}
+
+/// Lists the projects and documents directly in a project, for users who can read it.
+#[get("/projects/<id>")]
+#[instrument(skip_all, fields(request_id = %request_id, project_id = %id))]
+pub async fn fetch_project_contents(
+    id: String,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<ProjectContents>, ApiError> {
+    let project_id: Uuid = parse_id(&id, "project")?;
+    let user_id: Uuid = actor.require_user()?;
+
+    let client = tenancy::lock(db, &Tenant::Any).await?;
+    let access: Access = require_access(&client, &project_id, &user_id, Access::Read).await?;
+    let project: Project = fetch_project(&client, &project_id).await?;
+
+    let members = match client
+        .query(
+            "SELECT user_id,access FROM project_members WHERE project_id=$1 ORDER BY user_id",
+            &[&project_id],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => return Err(database_error("project_members")),
+    };
+    let projects = match client
+        .query(
+            "SELECT project_id,owner_id,parent_id,workspace_id,name,created_at FROM projects WHERE parent_id=$1 ORDER BY name",
+            &[&project_id],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => return Err(database_error("projects")),
+    };
+    let documents = match client
+        .query(
+            "SELECT document_id,owner_id,title,creation_date FROM document \
+             WHERE project_id=$1 AND archived_at IS NULL ORDER BY title",
+            &[&project_id],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => return Err(database_error("document")),
+    };
+
+    Ok(Json(ProjectContents {
+        project,
+        access,
+        members: members
+            .iter()
+            .filter_map(|row| {
+                Access::parse(row.get(1)).map(|access| ProjectMember {
+                    user_id: row.get(0),
+                    access,
+                })
+            })
+            .collect(),
+        projects: projects.iter().map(project_from_row).collect(),
+        documents: documents
+            .iter()
+            .map(|row| ProjectDocument {
+                document_id: row.get(0),
+                owner_id: row.get(1),
+                title: row.get(2),
+                creation_date: row.get(3),
+            })
+            .collect(),
+    }))
+}
+
+/// Grants a user access to a project, or changes their access. Only the owner of the project
+/// or of one of its parents can grant access.
+///
+/// Example Request
+/// {
+///     "user_id" : "550e8400-e29b-41d4-a716-446655440000",
+///     "access" : "read"
+/// }
+#[post("/projects/<id>/members", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id, project_id = %id))]
+pub async fn add_project_member(
+    id: String,
+    request: JsonBody<ProjectMemberRequest>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<ProjectMember>, ApiError> {
+    let project_id: Uuid = parse_id(&id, "project")?;
+    let user_id: Uuid = actor.require_user()?;
+
+    let client = tenancy::lock(db, &Tenant::Any).await?;
+    require_owner(&client, &project_id, &user_id).await?;
+    match client
+        .execute(
+            "INSERT INTO project_members (project_id,user_id,access) VALUES ($1,$2,$3) \
+             ON CONFLICT (project_id,user_id) DO UPDATE SET access=EXCLUDED.access",
+            &[&project_id, &request.user_id, &request.access.as_str()],
+        )
+        .await
+    {
+        Ok(_) => (),
+        Err(e) if e.code() == Some(&tokio_postgres::error::SqlState::FOREIGN_KEY_VIOLATION) => {
+            return Err(ApiError::ValidationFailed(vec![FieldError::new(
+                "user_id",
+                "must be a registered user",
+            )]))
+        }
+        Err(_) => return Err(database_error("project_members")),
+    }
+
+    let project: Project = fetch_project(&client, &project_id).await?;
+    notifications::notify(
+        &client,
+        &request.user_id,
+        NotificationKind::AddedToProject,
+        Some(user_id),
+        &format!(
+            "You were given {} access to the project \"{}\"",
+            request.access.as_str(),
+            project.name
+        ),
+        &format!("/projects/{}", project_id),
+    )
+    .await;
+
+    info!(member = %request.user_id, "Project access granted");
+    Ok(Json(ProjectMember {
+        user_id: request.user_id,
+        access: request.access,
+    }))
+}
+
+/// Revokes a user's access to a project. Access they have through a parent project or their
+/// organization is kept.
+#[delete("/projects/<id>/members/<member>")]
+#[instrument(skip_all, fields(request_id = %request_id, project_id = %id))]
+pub async fn remove_project_member(
+    id: String,
+    member: String,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<(), ApiError> {
+    let project_id: Uuid = parse_id(&id, "project")?;
+    let member: Uuid = parse_id(&member, "user")?;
+    let user_id: Uuid = actor.require_user()?;
+
+    let client = tenancy::lock(db, &Tenant::Any).await?;
+    require_owner(&client, &project_id, &user_id).await?;
+    match client
+        .execute(
+            "DELETE FROM project_members WHERE project_id=$1 AND user_id=$2",
+            &[&project_id, &member],
+        )
+        .await
+    {
+        Ok(0) => Err(ApiError::NotFound(
+            "The user has no access to the project".to_string(),
+        )),
+        Ok(_) => {
+            info!(member = %member, "Project access revoked");
+            Ok(())
+        }
+        Err(_) => Err(database_error("project_members")),
+    }
+}
+
+/// Checks the user owns the project or one of its parents.
+async fn require_owner(client: &Client, project_id: &Uuid, user_id: &Uuid) -> Result<(), ApiError> {
+    let owned: bool = match client
+        .query_one(
+            "WITH RECURSIVE chain (project_id, parent_id, owner_id) AS ( \
+                 SELECT project_id,parent_id,owner_id FROM projects WHERE project_id=$1 \
+                 UNION ALL SELECT p.project_id,p.parent_id,p.owner_id FROM projects p JOIN chain c ON p.project_id=c.parent_id) \
+             SELECT COALESCE(bool_or(owner_id=$2), false) FROM chain",
+            &[project_id, user_id],
+        )
+        .await
+    {
+        Ok(row) => row.get(0),
+        Err(_) => return Err(database_error("projects")),
+    };
+    if owned {
+        return Ok(());
+    }
+    // projects the user can't read are not found, the ones they can are forbidden
+    require_access(client, project_id, user_id, Access::Read).await?;
+    error!("Project access changed by a user who does not own the project");
+    Err(ApiError::Forbidden(
+        "Only the project owner can share it".to_string(),
+    ))
+}
+
+/// Moves a document to a project, or out of its project. Needs ownership of the document and
+/// read-write access to the project, which must be in the document's workspace. The document
+/// then inherits the project's grants instead of its previous project's. `id` is the document
+/// UUID.
+///
+/// Example Request
+/// {
+///     "project_id" : "0c6f3b1e-8d2a-4e57-9f14-7b3a5d2e9c60"
+/// }
+#[post("/document/<id>/project", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn move_document(
+    id: String,
+    request: JsonBody<MoveDocumentRequest>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    request_id: RequestId,
+) -> Result<(), ApiError> {
+    let document_id: Uuid = parse_id(&id, "document")?;
+    let user_id: Uuid = actor.require_user()?;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::ReadWrite).await?;
+
+    let client = tenancy::lock(db, &tenant).await?;
+    if audit::document_owner(&client, &document_id).await? != user_id {
+        error!("Document moved by a user who does not own it");
+        return Err(ApiError::Forbidden(
+            "Only the document owner can move it to a project".to_string(),
+        ));
+    }
+    if let Some(project_id) = request.project_id {
+        require_access(&client, &project_id, &user_id, Access::ReadWrite).await?;
+    }
+
+    // the project must be in the document's workspace, or both must be personal
+    let moved: u64 = match client
+        .execute(
+            "UPDATE document d SET project_id=$2 WHERE d.document_id=$1 AND ($2::uuid IS NULL OR \
+             EXISTS (SELECT 1 FROM projects p WHERE p.project_id=$2 AND p.workspace_id IS NOT DISTINCT FROM d.workspace_id))",
+            &[&document_id, &request.project_id],
+        )
+        .await
+    {
+        Ok(moved) => moved,
+        Err(_) => return Err(database_error("document")),
+    };
+    if moved == 0 {
+        return Err(ApiError::ValidationFailed(vec![FieldError::new(
+            "project_id",
+            "must be a project in the document's workspace",
+        )]));
+    }
+
+    info!(project_id = ?request.project_id, "Document moved to a project");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        self, TestDatabase, NOTIFICATION_TABLES, ORGANIZATION_TABLES, PROJECT_TABLES, USER_TABLES,
+    };
+    use rocket::http::{ContentType, Status};
+    use rocket::local::asynchronous::Client as LocalClient;
+
+    #[test]
+    fn test_combine() {
+        assert_eq!(combine(true, None, None), Some(Access::ReadWrite));
+        assert_eq!(combine(false, None, None), None);
+        assert_eq!(combine(false, Some(Access::Read), None), Some(Access::Read));
+        // grants add to the access of the organization's members
+        assert_eq!(
+            combine(false, Some(Access::ReadWrite), Some(Role::Viewer)),
+            Some(Access::ReadWrite)
+        );
+        assert_eq!(
+            combine(false, Some(Access::Read), Some(Role::Member)),
+            Some(Access::ReadWrite)
+        );
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_project_routes() {
+        let database = TestDatabase::create(&format!(
+            "{}{}{}{}",
+            ORGANIZATION_TABLES, USER_TABLES, PROJECT_TABLES, NOTIFICATION_TABLES
+        ))
+        .await;
+        let db: Client = database.connect().await;
+        let owner: Uuid = testing::insert_user(&db, "owner@example.com").await;
+        let reader: Uuid = testing::insert_user(&db, "ada@example.com").await;
+        let stranger: Uuid = testing::insert_user(&db, "eve@example.com").await;
+        let document_id: Uuid = testing::insert_document(&db, owner).await;
+        let rocket = testing::rocket(&database).await.mount(
+            "/",
+            rocket::routes![
+                create_project,
+                fetch_project_contents,
+                add_project_member,
+                remove_project_member,
+                move_document
+            ],
+        );
+        let client = LocalClient::tracked(rocket).await.unwrap();
+        let post = |uri: String, user_id: Uuid, body: String| {
+            client
+                .post(uri)
+                .header(ContentType::JSON)
+                .header(testing::session(user_id))
+                .body(body)
+                .dispatch()
+        };
+        let contents = |project_id: Uuid, user_id: Uuid| {
+            client
+                .get(format!("/projects/{}", project_id))
+                .header(testing::session(user_id))
+                .dispatch()
+        };
+
+        let anonymous = client
+            .post("/projects")
+            .header(ContentType::JSON)
+            .body(r#"{"name":"Interviews"}"#)
+            .dispatch()
+            .await;
+        assert_eq!(anonymous.status(), Status::Unauthorized);
+        let response = post(
+            "/projects".to_string(),
+            owner,
+            r#"{"name":"Interviews"}"#.to_string(),
+        )
+        .await;
+        assert_eq!(response.status(), Status::Ok);
+        let project: Project = response.into_json().await.unwrap();
+        assert_eq!((project.owner_id, project.parent_id), (owner, None));
+        let nested = format!(r#"{{"name":"Rust","parent_id":"{}"}}"#, project.project_id);
+        let response = post("/projects".to_string(), owner, nested.clone()).await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // projects the user can't read are not found
+        let members = format!("/projects/{}/members", project.project_id);
+        let grant = format!(r#"{{"user_id":"{}","access":"read"}}"#, reader);
+        let response = contents(project.project_id, stranger).await;
+        assert_eq!(response.status(), Status::NotFound);
+        let response = post(members.clone(), stranger, grant.clone()).await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = post(members.clone(), owner, grant.clone()).await;
+        assert_eq!(response.status(), Status::Ok);
+        let unregistered = format!(r#"{{"user_id":"{}","access":"read"}}"#, Uuid::new_v4());
+        let response = post(members.clone(), owner, unregistered).await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let notified: i64 = db
+            .query_one(
+                "SELECT COUNT(*) FROM notifications WHERE user_id=$1 AND kind='added_to_project'",
+                &[&reader],
+            )
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(notified, 1);
+
+        // readers see the project but can't share it or add to it
+        let response = post(members.clone(), reader, grant.clone()).await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = post("/projects".to_string(), reader, nested).await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let move_to = format!(r#"{{"project_id":"{}"}}"#, project.project_id);
+        let uri = format!("/document/{}/project", document_id);
+        let response = post(uri.clone(), stranger, move_to.clone()).await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = post(uri, owner, move_to).await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = contents(project.project_id, reader).await;
+        assert_eq!(response.status(), Status::Ok);
+        let listed: ProjectContents = response.into_json().await.unwrap();
+        assert_eq!(listed.access, Access::Read);
+        assert_eq!(listed.members.len(), 1);
+        assert_eq!(listed.projects.len(), 1);
+        assert_eq!(listed.documents.len(), 1);
+        assert_eq!(listed.documents[0].document_id, document_id);
+
+        let revoke = format!("{}/{}", members, reader);
+        let response = client
+            .delete(revoke.clone())
+            .header(testing::session(owner))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = contents(project.project_id, reader).await;
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .delete(revoke)
+            .header(testing::session(owner))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        database.drop().await;
+    }
+}
@@ -0,0 +1,229 @@
+//! Latency of the storage layer's queries.
+//!
+//! Loading and persisting documents is timed with a [`QueryTimer`] per kind of query, such as
+//! `load_snapshots` or `record_operations`. Each kind has a latency histogram reported on
+//! `GET /metrics`, and queries slower than `database.slow_query_ms` are logged with their
+//! parameters. Parameters that may hold document content or personal data are redacted, so a
+//! growing `load_snapshots` latency points at a snapshot table in need of compaction or an
+//! index without the logs leaking what documents contain.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Parameters whose values are never logged.
+const REDACTED: [&str; 6] = ["value", "content", "nodes", "email", "password", "token"];
+
+/// The longest parameter value logged, longer values are truncated.
+const MAX_PARAM_LEN: usize = 64;
+
+static SLOW_QUERY_MS: AtomicU64 = AtomicU64::new(500);
+
+static HISTOGRAMS: OnceLock<Mutex<BTreeMap<&'static str, Histogram>>> = OnceLock::new();
+
+/// Sets the latency above which queries are logged, 0 disables the log.
+pub fn set_slow_query_threshold(ms: u64) {
+    SLOW_QUERY_MS.store(ms, Ordering::Relaxed);
+}
+
+/// The latencies of one kind of query.
+/// `buckets`: The queries that took at most each of [`BUCKETS`], not cumulative.
+/// `slow`: The queries logged as slow.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    pub buckets: [u64; BUCKETS.len()],
+    pub count: u64,
+    pub sum: f64,
+    pub slow: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, seconds: f64) {
+        if let Some(bucket) = BUCKETS.iter().position(|le| seconds <= *le) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// Times a query from its start until it is dropped, then records its latency and logs it if
+/// it was slow. Queries that fail are timed as well.
+pub struct QueryTimer {
+    kind: &'static str,
+    params: Vec<(&'static str, String)>,
+    started: Instant,
+}
+
+impl QueryTimer {
+    pub fn start(kind: &'static str) -> Self {
+        QueryTimer {
+            kind,
+            params: Vec::new(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Adds a parameter logged with the query if it is slow, such as the document id or the
+    /// number of rows read.
+    pub fn param(mut self, name: &'static str, value: impl Display) -> Self {
+        self.params.push((name, sanitize(name, &value.to_string())));
+        self
+    }
+
+    /// Adds a parameter once the query has run.
+    pub fn set(&mut self, name: &'static str, value: impl Display) {
+        self.params.push((name, sanitize(name, &value.to_string())));
+    }
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        let elapsed: Duration = self.started.elapsed();
+        let threshold: u64 = SLOW_QUERY_MS.load(Ordering::Relaxed);
+        let slow: bool = threshold > 0 && elapsed >= Duration::from_millis(threshold);
+        if slow {
+            let params: Vec<String> = self
+                .params
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            warn!(
+                query = self.kind,
+                duration_ms = elapsed.as_millis() as u64,
+                params = %params.join(" "),
+                "Slow query"
+            );
+        }
+
+        let mut histograms = HISTOGRAMS
+            .get_or_init(|| Mutex::new(BTreeMap::new()))
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let histogram: &mut Histogram = histograms.entry(self.kind).or_default();
+        histogram.observe(elapsed.as_secs_f64());
+        if slow {
+            histogram.slow += 1;
+        }
+    }
+}
+
+/// The value of a parameter as it is logged: redacted if it may hold document content or
+/// personal data, otherwise truncated to [`MAX_PARAM_LEN`] characters.
+pub fn sanitize(name: &str, value: &str) -> String {
+    if REDACTED.contains(&name) {
+        return format!("<redacted {} bytes>", value.len());
+    }
+    match value.char_indices().nth(MAX_PARAM_LEN) {
+        Some((end, _)) => format!("{}...", &value[..end]),
+        None => value.to_string(),
+    }
+}
+
+/// The latency histogram of each kind of query, in the Prometheus text format.
+pub fn to_prometheus() -> String {
+    let histograms = match HISTOGRAMS.get() {
+        Some(histograms) => histograms.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+        None => BTreeMap::new(),
+    };
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# HELP nimble_db_query_duration_seconds Latency of the storage layer's queries by kind."
+    );
+    let _ = writeln!(out, "# TYPE nimble_db_query_duration_seconds histogram");
+    for (kind, histogram) in &histograms {
+        let mut cumulative: u64 = 0;
+        for (le, count) in BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "nimble_db_query_duration_seconds_bucket{{kind=\"{}\",le=\"{}\"}} {}",
+                kind, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "nimble_db_query_duration_seconds_bucket{{kind=\"{}\",le=\"+Inf\"}} {}",
+            kind, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "nimble_db_query_duration_seconds_sum{{kind=\"{}\"}} {}",
+            kind, histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "nimble_db_query_duration_seconds_count{{kind=\"{}\"}} {}",
+            kind, histogram.count
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP nimble_db_slow_queries_total Queries slower than database.slow_query_ms by kind."
+    );
+    let _ = writeln!(out, "# TYPE nimble_db_slow_queries_total counter");
+    for (kind, histogram) in &histograms {
+        let _ = writeln!(
+            out,
+            "nimble_db_slow_queries_total{{kind=\"{}\"}} {}",
+            kind, histogram.slow
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        for seconds in [0.0005, 0.003, 0.003, 0.7, 30.0] {
+            histogram.observe(seconds);
+        }
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[1], 2);
+        assert_eq!(histogram.buckets[8], 1);
+        // slower than the last bucket only counts towards +Inf
+        assert_eq!(histogram.buckets.iter().sum::<u64>(), 4);
+        assert_eq!(histogram.count, 5);
+    }
+
+    #[test]
+    fn test_query_timer() {
+        drop(QueryTimer::start("test_query").param("document_id", 7));
+        let mut timer = QueryTimer::start("test_query");
+        timer.set("rows", 3);
+        drop(timer);
+
+        let text: String = to_prometheus();
+        assert!(text.contains(
+            "nimble_db_query_duration_seconds_bucket{kind=\"test_query\",le=\"+Inf\"} 2"
+        ));
+        assert!(text.contains("nimble_db_query_duration_seconds_count{kind=\"test_query\"} 2"));
+        assert!(text.contains("nimble_db_slow_queries_total{kind=\"test_query\"} 0"));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("document_id", "42"), "42");
+        assert_eq!(sanitize("value", "secret code"), "<redacted 11 bytes>");
+        assert_eq!(sanitize("email", "ada@example.com"), "<redacted 15 bytes>");
+        assert_eq!(
+            sanitize("title", &"a".repeat(100)),
+            format!("{}...", "a".repeat(64))
+        );
+    }
+}
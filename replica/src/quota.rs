@@ -0,0 +1,146 @@
+use crate::ApiError;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::Client;
+use tracing::{error, instrument};
+use uuid::Uuid;
+
+/// Server-side quotas enforced by the replica.
+/// `max_documents_per_owner`: The number of documents a single owner can create.
+/// `max_operations_per_minute`: The number of operations a single document accepts per minute.
+/// `max_document_bytes`: The maximum size of a document's visible content in bytes.
+/// `max_documents_per_org`: The number of documents the workspaces of an organization can hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quotas {
+    pub max_documents_per_owner: i64,
+    pub max_operations_per_minute: i64,
+    pub max_document_bytes: usize,
+    pub max_documents_per_org: i64,
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Quotas {
+            max_documents_per_owner: 100,
+            max_operations_per_minute: 6000,
+            max_document_bytes: 1024 * 1024,
+            max_documents_per_org: 10_000,
+        }
+    }
+}
+
+impl Quotas {
+    /// Checks that adding `added` bytes and removing `removed` bytes keeps the document
+    /// within `max_document_bytes`.
+    pub fn check_document_size(
+        &self,
+        current: usize,
+        added: usize,
+        removed: usize,
+    ) -> Result<(), ApiError> {
+        let new_size = (current + added).saturating_sub(removed);
+        if added > removed && new_size > self.max_document_bytes {
+            return Err(ApiError::PayloadTooLarge(format!(
+                "Document would grow to {} bytes, the limit is {} bytes",
+                new_size, self.max_document_bytes
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the owner is below the maximum number of documents.
+#[instrument(name = "db.check_document_quota", skip(client, quotas))]
+pub async fn check_document_quota(
+    client: &Client,
+    owner_id: &Uuid,
+    quotas: &Quotas,
+) -> Result<(), ApiError> {
+    let row = match client
+        .query_one(
+            "SELECT COUNT(*) FROM document WHERE owner_id=$1",
+            &[owner_id],
+        )
+        .await
+    {
+        Ok(row) => row,
+        Err(_) => {
+            error!("Failed to count documents for owner {}",owner_id);
+            return Err(ApiError::DatabaseError(
+                "Failed to count documents for owner".to_string(),
+            ));
+        }
+    };
+
+    let documents: i64 = row.get(0);
+    if documents >= quotas.max_documents_per_owner {
+        return Err(ApiError::QuotaExceeded(format!(
+            "Owner has reached the limit of {} documents",
+            quotas.max_documents_per_owner
+        )));
+    }
+    Ok(())
+}
+
+/// Counts an operation against the document's per minute quota.
+/// The counter lives in the `document_quota` table so it is shared by all replicas and
+/// resets once the current one minute window has passed.
+#[instrument(name = "db.record_operation", skip(client, quotas))]
+pub async fn record_operation(
+    client: &Client,
+    document_id: &Uuid,
+    quotas: &Quotas,
+) -> Result<(), ApiError> {
+    let row = match client
+        .query_one(
+            "INSERT INTO document_quota (document_id, window_start, operations) VALUES ($1, now(), 1) \
+             ON CONFLICT (document_id) DO UPDATE SET \
+             operations = CASE WHEN document_quota.window_start > now() - interval '1 minute' \
+                 THEN document_quota.operations + 1 ELSE 1 END, \
+             window_start = CASE WHEN document_quota.window_start > now() - interval '1 minute' \
+                 THEN document_quota.window_start ELSE now() END \
+             RETURNING operations",
+            &[document_id],
+        )
+        .await
+    {
+        Ok(row) => row,
+        Err(_) => {
+            error!("Failed to update the operation quota for document {}",document_id);
+            return Err(ApiError::DatabaseError(
+                "Failed to update the document operation quota".to_string(),
+            ));
+        }
+    };
+
+    let operations: i64 = row.get(0);
+    if operations > quotas.max_operations_per_minute {
+        return Err(ApiError::TooManyRequests(format!(
+            "Document has reached the limit of {} operations per minute",
+            quotas.max_operations_per_minute
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_size_quota() {
+        let quotas = Quotas {
+            max_document_bytes: 10,
+            ..Quotas::default()
+        };
+
+        assert!(quotas.check_document_size(5, 5, 0).is_ok());
+        assert!(matches!(
+            quotas.check_document_size(5, 6, 0),
+            Err(ApiError::PayloadTooLarge(_))
+        ));
+        // replacing a value with a shorter one is always allowed
+        assert!(quotas.check_document_size(20, 1, 2).is_ok());
+        assert!(quotas.check_document_size(9, 3, 2).is_ok());
+    }
+}
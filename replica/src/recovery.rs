@@ -0,0 +1,147 @@
+//! Crash recovery: reconciles the write-ahead log, the operations table and the broadcast
+//! outbox before the replica serves requests.
+//!
+//! A replica can stop after applying an operation to the RGA but before its transaction
+//! commits, or after the commit but before SNS accepts the broadcast. The first is repaired
+//! from the write-ahead log, which persists the operations the database is missing, and the
+//! second by the outbox relay, which publishes every row the replica left behind. Loaded
+//! documents are rebuilt from the database, so nothing else is kept from before the crash.
+
+use crate::outbox::{self, Outbox};
+use crate::pool::Pool;
+use crate::wal::{Replay, Wal};
+use rocket::fairing::AdHoc;
+use serde::Serialize;
+use std::fmt;
+use tracing::{info, warn};
+
+/// What the replica found and repaired on startup.
+/// `wal_recovered`: Operations in the write-ahead log from before the restart.
+/// `wal`: What replaying them did.
+/// `wal_remaining`: Operations still to be persisted, retried every `wal.interval_secs`.
+/// `outbox_pending`: The replica's operations waiting to be broadcast, None if unknown.
+/// `errors`: Why a part of the recovery could not finish.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RecoveryReport {
+    pub wal_recovered: usize,
+    pub wal: Replay,
+    pub wal_remaining: usize,
+    pub outbox_pending: Option<i64>,
+    pub errors: Vec<String>,
+}
+
+impl RecoveryReport {
+    /// Whether the replica stopped cleanly: nothing was left to persist or broadcast.
+    pub fn is_clean(&self) -> bool {
+        self.wal_recovered == 0 && self.outbox_pending == Some(0) && self.errors.is_empty()
+    }
+}
+
+impl fmt::Display for RecoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} operations recovered from the write-ahead log ({} persisted, {} already persisted, {} dropped, {} remaining), ",
+            self.wal_recovered, self.wal.persisted, self.wal.skipped, self.wal.dropped, self.wal_remaining
+        )?;
+        match self.outbox_pending {
+            Some(pending) => write!(f, "{} operations to broadcast", pending)?,
+            None => write!(f, "unknown operations to broadcast")?,
+        }
+        for error in &self.errors {
+            write!(f, "; {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Persists the operations in the write-ahead log and counts the outbox rows to publish.
+pub async fn reconcile(wal: &Wal, pool: &Pool) -> RecoveryReport {
+    let mut report = RecoveryReport {
+        wal_recovered: wal.pending().await,
+        ..RecoveryReport::default()
+    };
+
+    if let Err(e) = wal.replay(pool, &mut report.wal).await {
+        report
+            .errors
+            .push(format!("failed to persist the write-ahead log: {}", e));
+    }
+    report.wal_remaining = wal.pending().await;
+
+    // counted after the replay, which writes the outbox rows of the operations it persists
+    match outbox::backlog(pool).await {
+        Ok(pending) => report.outbox_pending = Some(pending),
+        Err(e) => report
+            .errors
+            .push(format!("failed to read the broadcast outbox: {}", e)),
+    }
+    report
+}
+
+/// Fairing reconciling the replica's state after a restart and logging a [`RecoveryReport`].
+/// Must be attached after the pool and encryption fairings. The outbox relay publishes the
+/// operations found once the replica lifts off.
+pub fn attach_recovery() -> AdHoc {
+    AdHoc::on_ignite("Crash recovery", |rocket| async move {
+        let (Some(wal), Some(pool)) = (rocket.state::<Wal>(), rocket.state::<Pool>()) else {
+            warn!("Replica state is unavailable, skipping crash recovery");
+            return rocket;
+        };
+
+        let report: RecoveryReport = reconcile(wal, pool).await;
+        if report.is_clean() {
+            info!("Recovery: nothing to repair");
+        } else {
+            warn!(report = %serde_json::to_string(&report).unwrap_or_default(), "Recovery: {}", report);
+        }
+        if report.outbox_pending != Some(0) {
+            if let Some(outbox) = rocket.state::<Outbox>() {
+                outbox.wake();
+            }
+        }
+        rocket
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recovery_report() {
+        let clean = RecoveryReport {
+            outbox_pending: Some(0),
+            ..RecoveryReport::default()
+        };
+        assert!(clean.is_clean());
+
+        // the replica crashed between the commit and the broadcast
+        let report = RecoveryReport {
+            wal_recovered: 3,
+            wal: Replay {
+                persisted: 1,
+                skipped: 2,
+                dropped: 0,
+            },
+            wal_remaining: 0,
+            outbox_pending: Some(3),
+            errors: Vec::new(),
+        };
+        assert!(!report.is_clean());
+        assert_eq!(
+            report.to_string(),
+            "3 operations recovered from the write-ahead log (1 persisted, 2 already persisted, 0 dropped, 0 remaining), 3 operations to broadcast"
+        );
+
+        // a database that is still down is reported rather than blocking startup
+        let report = RecoveryReport {
+            errors: vec!["failed to read the broadcast outbox: down".to_string()],
+            ..RecoveryReport::default()
+        };
+        assert!(!report.is_clean());
+        assert!(report.to_string().ends_with(
+            "unknown operations to broadcast; failed to read the broadcast outbox: down"
+        ));
+    }
+}
@@ -0,0 +1,120 @@
+//! Region labels and cross-region replication metrics.
+//!
+//! Every broadcast carries the region of the replica that sent it and when it was sent, so
+//! replicas can report how many operations they receive from each region and how long they
+//! take to arrive.
+
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, OnceLock};
+
+static REGION: OnceLock<String> = OnceLock::new();
+
+/// Sets the region carried by this replica's broadcasts. Only the first call has any effect.
+pub fn set_region(region: &str) {
+    let _ = REGION.set(region.to_string());
+}
+
+/// The region of this replica (None until it is set).
+pub fn local_region() -> Option<String> {
+    REGION.get().cloned()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct RegionStats {
+    received: u64,
+    lag_seconds_sum: f64,
+    lag_count: u64,
+}
+
+/// Broadcasts received from each origin region, managed in Rocket's state.
+#[derive(Clone, Default)]
+pub struct ReplicationMetrics {
+    regions: Arc<std::sync::Mutex<BTreeMap<String, RegionStats>>>,
+}
+
+impl ReplicationMetrics {
+    /// Records a broadcast received from `origin_region` (`unknown` for replicas that don't send
+    /// one) that was sent at `sent_at`.
+    pub fn record(&self, origin_region: Option<&str>, sent_at: Option<&str>, now: DateTime<Utc>) {
+        let lag: Option<f64> = sent_at
+            .and_then(|sent_at| DateTime::parse_from_rfc3339(sent_at).ok())
+            .map(|sent_at| (now - sent_at.to_utc()).num_milliseconds().max(0) as f64 / 1000.0);
+
+        let mut regions = self.regions.lock().unwrap_or_else(|e| e.into_inner());
+        let stats: &mut RegionStats = regions
+            .entry(origin_region.unwrap_or("unknown").to_string())
+            .or_default();
+        stats.received += 1;
+        if let Some(lag) = lag {
+            stats.lag_seconds_sum += lag;
+            stats.lag_count += 1;
+        }
+    }
+
+    /// Formats the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let regions = self.regions.lock().unwrap_or_else(|e| e.into_inner());
+        let local: String = local_region().unwrap_or_default();
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP nimble_broadcasts_received_total Broadcast operations received, by the region of the replica that sent them."
+        );
+        let _ = writeln!(out, "# TYPE nimble_broadcasts_received_total counter");
+        for (region, stats) in regions.iter() {
+            let _ = writeln!(
+                out,
+                "nimble_broadcasts_received_total{{origin_region=\"{}\",cross_region=\"{}\"}} {}",
+                region,
+                *region != local,
+                stats.received
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP nimble_broadcast_lag_seconds Time from a broadcast being sent to it being received."
+        );
+        let _ = writeln!(out, "# TYPE nimble_broadcast_lag_seconds summary");
+        for (region, stats) in regions.iter() {
+            let _ = writeln!(
+                out,
+                "nimble_broadcast_lag_seconds_sum{{origin_region=\"{}\"}} {}",
+                region, stats.lag_seconds_sum
+            );
+            let _ = writeln!(
+                out,
+                "nimble_broadcast_lag_seconds_count{{origin_region=\"{}\"}} {}",
+                region, stats.lag_count
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replication_metrics() {
+        let metrics = ReplicationMetrics::default();
+        let now: DateTime<Utc> = DateTime::parse_from_rfc3339("2024-01-01T00:00:02Z")
+            .unwrap()
+            .to_utc();
+
+        metrics.record(Some("eu-west-1"), Some("2024-01-01T00:00:01.500Z"), now);
+        metrics.record(Some("eu-west-1"), Some("2024-01-01T00:00:01Z"), now);
+        metrics.record(None, None, now);
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("origin_region=\"eu-west-1\",cross_region=\"true\"} 2"));
+        assert!(text.contains("nimble_broadcast_lag_seconds_sum{origin_region=\"eu-west-1\"} 1.5"));
+        assert!(text.contains("nimble_broadcast_lag_seconds_count{origin_region=\"eu-west-1\"} 2"));
+        assert!(text.contains("origin_region=\"unknown\",cross_region=\"true\"} 1"));
+        assert!(text.contains("nimble_broadcast_lag_seconds_count{origin_region=\"unknown\"} 0"));
+    }
+}
@@ -0,0 +1,88 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Data, Request, Response};
+use std::fmt::Display;
+use tracing::info;
+use uuid::Uuid;
+
+/// Header used to carry the request id between the load balancer and the replicas.
+pub const REQUEST_ID_HEADER: &str = "X-Request-ID";
+
+/// The id of the request currently being handled.
+/// Taken from the `X-Request-ID` header set by the load balancer, or generated if missing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Returns the request id for the request, generating and caching one if needed.
+    pub fn of(request: &Request<'_>) -> RequestId {
+        request
+            .local_cache(|| match request.headers().get_one(REQUEST_ID_HEADER) {
+                Some(id) if is_valid(id) => RequestId(id.to_string()),
+                _ => RequestId(Uuid::new_v4().to_string()),
+            })
+            .clone()
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RequestId::of(request))
+    }
+}
+
+/// Fairing that assigns every request an id, logs it and echoes it back in the response.
+pub struct RequestIdFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ID",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let request_id = RequestId::of(request);
+        info!(request_id = %request_id, "{} {}", request.method(), request.uri());
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let request_id = RequestId::of(request);
+        info!(request_id = %request_id, "Responded {}", response.status());
+        response.set_header(Header::new(REQUEST_ID_HEADER, request_id.0));
+    }
+}
+
+/// Rejects ids that are empty, overly long or contain characters unsafe for logs and headers.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid() {
+        assert!(is_valid("67e55044-10b1-426f-9247-bb680e5fe0c8"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("abc\r\nX-Injected: 1"));
+        assert!(!is_valid(&"a".repeat(129)));
+    }
+}
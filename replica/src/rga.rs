@@ -1,5 +1,5 @@
+#[allow(clippy::module_inception)]
 pub mod rga {
-    use rocket::tokio::sync::RwLock;
     use uuid::Uuid;
 
     /// The `RGA` module implements a Replicated Growable Array (RGA),
@@ -17,36 +17,55 @@ pub mod rga {
     ///   mechanism that resolves dependencies dynamically.
     ///
     /// # Example
-    /// ```rust
-    /// use crdt::rga::rga::RGA;
-    /// use crdt::S4Vector;
+    /// ```rust,ignore
+    /// use nimble::rga::rga::RGA;
+    /// use nimble::S4Vector;
     ///
     /// let mut rga = RGA::new(1, 1);  // Create a new RGA instance.
     ///
     /// // Insert a value at the start.
-    /// let s4_a = rga.local_insert("A".to_string(), None, None).await.unwrap().s4vector();
+    /// let s4_a = rga.local_insert("A".to_string(), None, None).unwrap().s4vector();
     ///
     /// // Insert another value after "A".
-    /// let s4_b = rga.local_insert("B".to_string(), Some(s4_a.clone()), None).await.unwrap().s4vector();
+    /// let s4_b = rga.local_insert("B".to_string(), Some(s4_a.clone()), None).unwrap().s4vector();
     ///
     /// // Delete the first value.
-    /// rga.local_delete(s4_a.clone()).await.unwrap();
+    /// rga.local_delete(s4_a.clone()).unwrap();
     ///
     /// // Read the current state.
-    /// let result = rga.read().await;
+    /// let result = rga.read();
     /// assert_eq!(result, vec!["B".to_string()]);
     /// ```
-    use crate::{BroadcastOperation, S4Vector};
-    use std::collections::{HashMap, VecDeque};
+    use crate::throttle::TokenBucket;
+    use crate::{BroadcastOperation, DocumentSnapshot, MemoryUsage, S4Vector, S4VectorError};
+    use rocket::futures::stream;
+    use sha2::{Digest, Sha256};
+    use std::collections::{BTreeMap, HashMap, HashSet};
     use std::sync::Arc;
-    #[allow(dead_code)]
+    use std::time::{Duration, Instant};
+    use tracing::{debug, error, instrument};
+
+    /// The size of the chunks documents are streamed in.
+    pub const READ_CHUNK_BYTES: usize = 64 * 1024;
 
-    /// Represents a node in the RGA, containing the actual data and metadata for traversal and consistency.    
+    /// Approximate bytes held per node besides its value: the node in the arena, its
+    /// entry in the index and its slot in the tree keeping document order.
+    const NODE_OVERHEAD: usize = std::mem::size_of::<Node>()
+        + std::mem::size_of::<S4Vector>()
+        + std::mem::size_of::<NodeId>()
+        + std::mem::size_of::<Slot>();
+
+    #[allow(dead_code)]
+    /// Represents a node in the RGA, containing the actual data and metadata for traversal and consistency.
     /// `value`: The value of the node.
     /// `s4vector`: The unique identifier for the node based on S4Vector
     /// `tombstone`: Indicates whether the node has been logically deleted.
-    /// `left`: The `S4Vector` of the left neighbor
+    /// `left`: The `S4Vector` of the left neighbor when the node was inserted (its left origin)
     /// `right`: The `S4Vector` of the right neighbor
+    /// `origin_right`: The `S4Vector` of the right neighbor when the node was inserted (or moved)
+    /// `moved`: The s4vector of the last move applied to the node (None if it was never moved)
+    /// `author`: The user that wrote the node's current value (None if unknown)
+    /// `authored_at`: When the node's current value was written (None if unknown)
     #[derive(Debug, Clone)]
     pub struct Node {
         pub value: String,
@@ -54,6 +73,316 @@ pub mod rga {
         pub tombstone: bool,
         pub left: Option<S4Vector>,
         pub right: Option<S4Vector>,
+        pub origin_right: Option<S4Vector>,
+        pub moved: Option<S4Vector>,
+        pub author: Option<Uuid>,
+        pub authored_at: Option<String>,
+    }
+
+    /// A handle to a node in the RGA's arena. Nodes are never removed (deletes leave
+    /// tombstones), so a handle stays valid for the lifetime of the RGA.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct NodeId(usize);
+
+    /// The text of the document, patched as operations are applied so reading it does not
+    /// walk the list. It is only built from the list when a RGA is created from existing nodes.
+    /// `text`: The value of every visible node concatenated in document order. Readers streaming
+    /// the document share it, the next change copies it if one is still reading.
+    /// `order`: Every node (including tombstones) in document order, with the bytes of `text`
+    /// each one shows.
+    #[derive(Debug, Clone, Default)]
+    struct Materialized {
+        text: Arc<String>,
+        order: OrderTree,
+    }
+
+    /// A node's place in the [`OrderTree`].
+    /// `parent`, `left`, `right`: The node's links in the tree (None for the root or a leaf).
+    /// `priority`: Keeps the tree a heap, and so balanced.
+    /// `count`: The number of nodes in the subtree under the node, itself included.
+    /// `len`: The bytes of text the node shows.
+    /// `bytes`: The bytes of text the subtree under the node shows.
+    /// `linked`: Whether the node is in document order (false while a move relinks it).
+    #[derive(Debug, Clone, Copy, Default)]
+    struct Slot {
+        parent: Option<NodeId>,
+        left: Option<NodeId>,
+        right: Option<NodeId>,
+        priority: u64,
+        count: usize,
+        len: usize,
+        bytes: usize,
+        linked: bool,
+    }
+
+    /// Document order as a treap keyed by index, with a slot per node of the arena. Finding
+    /// a node's index and byte offset, inserting, removing and resizing a node each follow
+    /// one path of the tree, so edits to large documents don't walk every node after them.
+    #[derive(Debug, Clone, Default)]
+    struct OrderTree {
+        slots: Vec<Slot>,
+        root: Option<NodeId>,
+    }
+
+    /// The priority of a node in the [`OrderTree`]. It is a hash of the handle (splitmix64),
+    /// so the tree is balanced without a random number generator.
+    fn priority(id: NodeId) -> u64 {
+        let mut z: u64 = (id.0 as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    impl OrderTree {
+        /// The number of nodes in document order.
+        fn len(&self) -> usize {
+            self.count(self.root)
+        }
+
+        fn count(&self, id: Option<NodeId>) -> usize {
+            id.map_or(0, |id| self.slots[id.0].count)
+        }
+
+        fn bytes(&self, id: Option<NodeId>) -> usize {
+            id.map_or(0, |id| self.slots[id.0].bytes)
+        }
+
+        fn is_linked(&self, id: NodeId) -> bool {
+            self.slots.get(id.0).is_some_and(|slot| slot.linked)
+        }
+
+        /// Recomputes the totals of a node from its children.
+        fn update(&mut self, id: NodeId) {
+            let slot: Slot = self.slots[id.0];
+            self.slots[id.0].count = 1 + self.count(slot.left) + self.count(slot.right);
+            self.slots[id.0].bytes = slot.len + self.bytes(slot.left) + self.bytes(slot.right);
+        }
+
+        fn set_left(&mut self, id: NodeId, child: Option<NodeId>) {
+            self.slots[id.0].left = child;
+            if let Some(child) = child {
+                self.slots[child.0].parent = Some(id);
+            }
+        }
+
+        fn set_right(&mut self, id: NodeId, child: Option<NodeId>) {
+            self.slots[id.0].right = child;
+            if let Some(child) = child {
+                self.slots[child.0].parent = Some(id);
+            }
+        }
+
+        /// Splits a subtree into its first `index` nodes and the rest, returning the root of
+        /// each part.
+        fn split(
+            &mut self,
+            root: Option<NodeId>,
+            index: usize,
+        ) -> (Option<NodeId>, Option<NodeId>) {
+            let Some(id) = root else {
+                return (None, None);
+            };
+            let Slot { left, right, .. } = self.slots[id.0];
+            let before: usize = self.count(left);
+            self.slots[id.0].parent = None;
+            if index <= before {
+                let (first, rest) = self.split(left, index);
+                self.set_left(id, rest);
+                self.update(id);
+                (first, Some(id))
+            } else {
+                let (first, rest) = self.split(right, index - before - 1);
+                self.set_right(id, first);
+                self.update(id);
+                (Some(id), rest)
+            }
+        }
+
+        /// Joins two subtrees, the nodes of `first` coming before those of `rest`.
+        fn merge(&mut self, first: Option<NodeId>, rest: Option<NodeId>) -> Option<NodeId> {
+            let (first, rest) = match (first, rest) {
+                (Some(first), Some(rest)) => (first, rest),
+                (first, None) => return first,
+                (None, rest) => return rest,
+            };
+            if self.slots[first.0].priority > self.slots[rest.0].priority {
+                let merged = self.merge(self.slots[first.0].right, Some(rest));
+                self.set_right(first, merged);
+                self.update(first);
+                Some(first)
+            } else {
+                let merged = self.merge(Some(first), self.slots[rest.0].left);
+                self.set_left(rest, merged);
+                self.update(rest);
+                Some(rest)
+            }
+        }
+
+        /// Puts a node at `index` in document order, showing `len` bytes of text.
+        fn insert(&mut self, index: usize, id: NodeId, len: usize) {
+            if self.slots.len() <= id.0 {
+                self.slots.resize(id.0 + 1, Slot::default());
+            }
+            self.slots[id.0] = Slot {
+                priority: priority(id),
+                count: 1,
+                len,
+                bytes: len,
+                linked: true,
+                ..Slot::default()
+            };
+            let (first, rest) = self.split(self.root, index);
+            let first = self.merge(first, Some(id));
+            self.root = self.merge(first, rest);
+        }
+
+        /// Takes a node out of document order.
+        fn remove(&mut self, id: NodeId) {
+            let Some((index, _)) = self.offset(id) else {
+                return;
+            };
+            let (first, rest) = self.split(self.root, index);
+            let (_, rest) = self.split(rest, 1);
+            self.root = self.merge(first, rest);
+            self.slots[id.0] = Slot::default();
+        }
+
+        /// Changes the bytes of text a node shows.
+        fn resize(&mut self, id: NodeId, len: usize) {
+            if !self.is_linked(id) {
+                return;
+            }
+            self.slots[id.0].len = len;
+            let mut next: Option<NodeId> = Some(id);
+            while let Some(id) = next {
+                self.update(id);
+                next = self.slots[id.0].parent;
+            }
+        }
+
+        /// The index of a node in document order and the byte offset its text starts at.
+        fn offset(&self, id: NodeId) -> Option<(usize, usize)> {
+            if !self.is_linked(id) {
+                return None;
+            }
+            let left: Option<NodeId> = self.slots[id.0].left;
+            let (mut index, mut start) = (self.count(left), self.bytes(left));
+            let mut child: NodeId = id;
+            while let Some(parent) = self.slots[child.0].parent {
+                let slot: Slot = self.slots[parent.0];
+                if slot.right == Some(child) {
+                    index += self.count(slot.left) + 1;
+                    start += self.bytes(slot.left) + slot.len;
+                }
+                child = parent;
+            }
+            Some((index, start))
+        }
+
+        /// The node at `index` in document order.
+        fn get(&self, mut index: usize) -> Option<NodeId> {
+            let mut next: Option<NodeId> = self.root;
+            while let Some(id) = next {
+                let left: Option<NodeId> = self.slots[id.0].left;
+                let before: usize = self.count(left);
+                match index.cmp(&before) {
+                    std::cmp::Ordering::Less => next = left,
+                    std::cmp::Ordering::Equal => return Some(id),
+                    std::cmp::Ordering::Greater => {
+                        index -= before + 1;
+                        next = self.slots[id.0].right;
+                    }
+                }
+            }
+            None
+        }
+
+        /// The node after `id` in document order.
+        fn next(&self, id: NodeId) -> Option<NodeId> {
+            if let Some(mut next) = self.slots[id.0].right {
+                while let Some(left) = self.slots[next.0].left {
+                    next = left;
+                }
+                return Some(next);
+            }
+            let mut child: NodeId = id;
+            while let Some(parent) = self.slots[child.0].parent {
+                if self.slots[parent.0].left == Some(child) {
+                    return Some(parent);
+                }
+                child = parent;
+            }
+            None
+        }
+
+        /// Iterates over the nodes in document order, from the one at `index`.
+        fn iter_from(&self, index: usize) -> impl Iterator<Item = NodeId> + '_ {
+            std::iter::successors(self.get(index), |id| self.next(*id))
+        }
+    }
+
+    /// The text of a document split into chunks of about `size` bytes, cut on character
+    /// boundaries. Holds the text as it was when the chunks were taken, so the document can
+    /// be edited while they are read.
+    #[derive(Debug, Clone)]
+    pub struct Chunks {
+        text: Arc<String>,
+        start: usize,
+        size: usize,
+    }
+
+    impl Iterator for Chunks {
+        type Item = String;
+
+        fn next(&mut self) -> Option<String> {
+            if self.start >= self.text.len() {
+                return None;
+            }
+            let mut end: usize = (self.start + self.size.max(1)).min(self.text.len());
+            while !self.text.is_char_boundary(end) {
+                end += 1;
+            }
+            let chunk: String = self.text[self.start..end].to_string();
+            self.start = end;
+            Some(chunk)
+        }
+    }
+
+    impl Materialized {
+        /// The index of a node in document order.
+        fn position(&self, id: NodeId) -> Option<usize> {
+            self.order.offset(id).map(|(index, _)| index)
+        }
+
+        /// The byte range of `text` holding a node, None if it isn't in document order.
+        fn range(&self, id: NodeId) -> Option<std::ops::Range<usize>> {
+            let (_, start) = self.order.offset(id)?;
+            Some(start..start + self.order.slots[id.0].len)
+        }
+
+        /// Adds a node at `index` in document order, showing `visible` as its text.
+        fn insert(&mut self, index: usize, id: NodeId, visible: &str) {
+            self.order.insert(index, id, visible.len());
+            if let Some(range) = self.range(id) {
+                Arc::make_mut(&mut self.text).insert_str(range.start, visible);
+            }
+        }
+
+        /// Takes a node out of document order, along with its text.
+        fn remove(&mut self, id: NodeId) {
+            if let Some(range) = self.range(id) {
+                Arc::make_mut(&mut self.text).replace_range(range, "");
+                self.order.remove(id);
+            }
+        }
+
+        /// Replaces the text of a node with `visible`.
+        fn replace(&mut self, id: NodeId, visible: &str) {
+            if let Some(range) = self.range(id) {
+                Arc::make_mut(&mut self.text).replace_range(range, visible);
+                self.order.resize(id, visible.len());
+            }
+        }
     }
 
     /// Enum representing different types of operations that can be applied to the RGA.
@@ -62,6 +391,7 @@ pub mod rga {
         Insert,
         Update,
         Delete,
+        Move,
     }
 
     /// Represents an operation in the RGA.
@@ -71,6 +401,7 @@ pub mod rga {
     /// `tomestone`: Indicates a logical delete
     /// `left`: The s4vector on the left (if one exists)
     /// `right`: The s4vector on the right (if one exists)
+    /// `nodes`: The nodes a move relocates between `left` and `right`, in document order
     #[derive(Debug, Clone)]
     pub struct Operation {
         operation: OperationType,
@@ -79,30 +410,75 @@ pub mod rga {
         tombstone: bool,
         left: Option<S4Vector>,
         right: Option<S4Vector>,
+        nodes: Vec<S4Vector>,
+    }
+
+    /// An operation waiting in the buffer.
+    /// `operation`: The operation.
+    /// `at`: When it was first buffered, kept when it is buffered again on its next dependency.
+    #[derive(Debug, Clone)]
+    struct Buffered {
+        operation: Operation,
+        at: Instant,
+    }
+
+    /// Operations dropped from the buffer by `shed_buffer`.
+    /// `expired`: Operations buffered for longer than the maximum age.
+    /// `overflowed`: The oldest operations dropped to bring the buffer back to its maximum size.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct Shed {
+        pub expired: usize,
+        pub overflowed: usize,
+    }
+
+    impl Shed {
+        pub fn total(&self) -> usize {
+            self.expired + self.overflowed
+        }
     }
 
     /// Represents the RGA structure, which is a distributed data structure
     /// supporting concurrent operations and eventual consistency.
     /// `head`: The head of the linked list.
-    /// `hash_map`: Maps `S4Vector` identifiers to `Node` instances.
-    /// `buffer`: A Buffer for out-of-order operations.
+    /// `nodes`: The arena holding every node, addressed by `NodeId`.
+    /// `index`: Maps `S4Vector` identifiers to the handle of their node.
+    /// `buffer`: Out-of-order operations, keyed by the node they are waiting for.
     /// `session_id`: The current session ID.
     /// `site_id`: The site ID for the current replica.
     /// `local_sequence`: The local logical clock.
-    #[derive(Debug)]
+    /// `dirty`: Whether the RGA has changed since its snapshot was last persisted.
+    /// `last_accessed`: When the RGA was last read or changed, used to pick documents to evict.
+    /// `content`: The document's text in document order, kept up to date by every operation.
+    /// `tombstones`: The number of deleted nodes, kept up to date by every delete.
+    /// `compacted_tombstones`: The number of tombstones when the document was last compacted.
+    /// `throttle`: The bucket the document's edits take tokens from.
+    #[derive(Debug, Clone)]
     pub struct RGA {
         pub head: Option<S4Vector>,
-        pub hash_map: HashMap<S4Vector, Arc<RwLock<Node>>>,
-        pub buffer: VecDeque<Operation>,
+        nodes: Vec<Node>,
+        index: HashMap<S4Vector, NodeId>,
+        buffer: HashMap<S4Vector, Vec<Buffered>>,
         pub session_id: u64,
         pub site_id: u64,
         pub local_sequence: u64,
+        pub dirty: bool,
+        pub last_accessed: Instant,
+        content: Materialized,
+        tombstones: usize,
+        pub compacted_tombstones: usize,
+        pub throttle: TokenBucket,
     }
 
     #[derive(Debug, thiserror::Error)]
     pub enum OperationError {
         #[error("Failed to perform operation, dependancies have not been met")]
         DependancyError,
+        #[error("Invalid remote operation {0}, unknown type or missing value")]
+        InvalidOperation(String),
+        #[error("Invalid move, {0}")]
+        InvalidMove(String),
+        #[error(transparent)]
+        OutOfRange(#[from] S4VectorError),
     }
 
     impl Node {
@@ -128,6 +504,10 @@ pub mod rga {
                 tombstone: false,
                 left,
                 right,
+                origin_right: right,
+                moved: None,
+                author: None,
+                authored_at: None,
             }
         }
 
@@ -154,6 +534,10 @@ pub mod rga {
                 tombstone,
                 left,
                 right,
+                origin_right: right,
+                moved: None,
+                author: None,
+                authored_at: None,
             }
         }
     }
@@ -170,11 +554,11 @@ pub mod rga {
 
     impl PartialEq for Node {
         fn eq(&self, other: &Self) -> bool {
-            return self.value == other.value
+            self.value == other.value
                 && self.s4vector == other.s4vector
                 && self.tombstone == other.tombstone
                 && self.left == other.left
-                && self.right == other.right;
+                && self.right == other.right
         }
     }
 
@@ -182,12 +566,12 @@ pub mod rga {
 
     impl PartialOrd for Node {
         fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-            Some(self.cmp(&other))
+            Some(self.cmp(other))
         }
     }
     impl Ord for Node {
         fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-            return self.s4vector.cmp(&other.s4vector);
+            self.s4vector.cmp(&other.s4vector)
         }
     }
 
@@ -203,11 +587,18 @@ pub mod rga {
         pub fn new(session_id: u64, site_id: u64) -> Self {
             RGA {
                 head: None,
-                hash_map: HashMap::new(),
-                buffer: VecDeque::new(),
+                nodes: Vec::new(),
+                index: HashMap::new(),
+                buffer: HashMap::new(),
                 session_id,
                 site_id,
                 local_sequence: 0,
+                dirty: false,
+                last_accessed: Instant::now(),
+                content: Materialized::default(),
+                tombstones: 0,
+                compacted_tombstones: 0,
+                throttle: TokenBucket::default(),
             }
         }
 
@@ -234,47 +625,309 @@ pub mod rga {
                 let value = op.value.unwrap_or_default();
                 let node: Node =
                     Node::create_from_existing(op.s4vector, value, op.tombstone, op.left, op.right);
-                rga.hash_map
-                    .insert(op.s4vector, Arc::new(RwLock::new(node)));
+                rga.store(node);
                 rga.local_sequence += 1;
             }
+            rga.rematerialize();
+            rga
+        }
+
+        /// Rebuilds a RGA from the rows of the `document_snapshots` table.
+        /// Used when loading a document from the database. Rows have no neighbours, so the
+        /// nodes are linked in the order of their s4vectors.
+        ///
+        /// # Arguments
+        /// `snapshots`: The document's rows, ordered by s4vector.
+        /// `session_id`: The session id of the current replica.
+        /// `site_id`: The replica id of the current replica.
+        ///
+        /// # Returns
+        /// A RGA matching the stored snapshot (not dirty). The local sequence continues after
+        /// the last node this session and site created, so reloading a document does not
+        /// regenerate existing s4vectors.
+        pub fn load_snapshot(
+            snapshots: Vec<DocumentSnapshot>,
+            session_id: u64,
+            site_id: u64,
+        ) -> Self {
+            let mut nodes: Vec<Node> = Vec::with_capacity(snapshots.len());
+            for row in snapshots {
+                let s4 = match S4Vector::from_i64(row.ssn, row.sum, row.sid, row.seq) {
+                    Ok(s4) => s4,
+                    Err(e) => {
+                        error!("Skipping stored node: {}", e);
+                        continue;
+                    }
+                };
+
+                let mut node: Node = Node::new(row.value, s4, None, None);
+                node.tombstone = row.tombstone;
+                node.author = row.author;
+                node.authored_at = row.authored_at;
+                nodes.push(node);
+            }
+
+            // rows have no origins, so each node is taken to be inserted between its neighbours
+            let order: Vec<S4Vector> = nodes.iter().map(|node| node.s4vector).collect();
+            for (i, node) in nodes.iter_mut().enumerate() {
+                node.left = i.checked_sub(1).map(|left| order[left]);
+                node.origin_right = order.get(i + 1).copied();
+            }
+            RGA::from_nodes(nodes, session_id, site_id)
+        }
+
+        /// Rebuilds a RGA from its nodes in document order, as returned by `iter()`.
+        /// Used when loading a document from its checkpoint. The nodes are linked in the
+        /// order given instead of being integrated one at a time, so loading is linear in
+        /// the size of the document. They keep their origins, which later concurrent inserts
+        /// are ordered by.
+        ///
+        /// # Arguments
+        /// `nodes`: Every node of the document (including tombstones) in document order.
+        /// `session_id`: The session id of the current replica.
+        /// `site_id`: The replica id of the current replica.
+        ///
+        /// # Returns
+        /// A RGA with the nodes (not dirty), continuing the local sequence like `load_snapshot`.
+        pub fn from_nodes(nodes: Vec<Node>, session_id: u64, site_id: u64) -> Self {
+            let mut rga: RGA = RGA::new(session_id, site_id);
+            let order: Vec<S4Vector> = nodes.iter().map(|node| node.s4vector).collect();
+
+            for (i, mut node) in nodes.into_iter().enumerate() {
+                node.right = order.get(i + 1).copied();
+                if node.s4vector.ssn == session_id && node.s4vector.sid == site_id {
+                    rga.local_sequence = rga.local_sequence.max(node.s4vector.seq);
+                }
+                rga.store(node);
+            }
+            rga.head = order.first().copied();
+            rga.rematerialize();
+            rga.compacted_tombstones = rga.tombstones;
             rga
         }
 
+        /// Returns the node with the s4vector, if the RGA has it.
+        pub fn node(&self, s4vector: &S4Vector) -> Option<&Node> {
+            self.index.get(s4vector).map(|id| &self.nodes[id.0])
+        }
+
+        fn node_mut(&mut self, s4vector: &S4Vector) -> Option<&mut Node> {
+            self.index.get(s4vector).map(|id| &mut self.nodes[id.0])
+        }
+
+        /// Whether the RGA has a node with the s4vector.
+        pub fn contains(&self, s4vector: &S4Vector) -> bool {
+            self.index.contains_key(s4vector)
+        }
+
+        /// The number of nodes, including tombstones.
+        pub fn len(&self) -> usize {
+            self.nodes.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.nodes.is_empty()
+        }
+
+        /// Iterates over every node (including tombstones) in document order.
+        pub fn iter(&self) -> impl Iterator<Item = &Node> + '_ {
+            self.content.order.iter_from(0).map(|id| &self.nodes[id.0])
+        }
+
+        /// Follows the links of the list from the head, the order `iter()` is kept in.
+        fn walk(&self) -> impl Iterator<Item = NodeId> + '_ {
+            std::iter::successors(self.head.and_then(|head| self.index.get(&head)), |id| {
+                self.nodes[id.0]
+                    .right
+                    .and_then(|right| self.index.get(&right))
+            })
+            .copied()
+        }
+
+        /// Rebuilds the materialized text from the list.
+        fn rematerialize(&mut self) {
+            let mut text = String::new();
+            let mut order = OrderTree::default();
+            let mut tombstones: usize = 0;
+            for id in self.walk() {
+                let node: &Node = &self.nodes[id.0];
+                let start: usize = text.len();
+                if node.tombstone {
+                    tombstones += 1;
+                } else {
+                    text.push_str(&node.value);
+                }
+                order.insert(order.len(), id, text.len() - start);
+            }
+            self.content = Materialized {
+                text: Arc::new(text),
+                order,
+            };
+            self.tombstones = tombstones;
+        }
+
+        /// Marks a node as deleted, counting it the first time it is tombstoned.
+        fn tombstone(&mut self, id: NodeId) {
+            if !self.nodes[id.0].tombstone {
+                self.nodes[id.0].tombstone = true;
+                self.tombstones += 1;
+            }
+            self.rematerialize_node(id);
+        }
+
+        /// Brings the materialized text of a node in line with its value after an update
+        /// or delete.
+        fn rematerialize_node(&mut self, id: NodeId) {
+            let node: &Node = &self.nodes[id.0];
+            let visible: &str = if node.tombstone { "" } else { &node.value };
+            self.content.replace(id, visible);
+        }
+
+        /// Stores a node in the arena, replacing the node with the same s4vector if there is one.
+        fn store(&mut self, node: Node) -> NodeId {
+            if let Some(id) = self.index.get(&node.s4vector).copied() {
+                self.nodes[id.0] = node;
+                return id;
+            }
+            let id = NodeId(self.nodes.len());
+            self.index.insert(node.s4vector, id);
+            self.nodes.push(node);
+            id
+        }
+
         /// Inserts a node into the RGA.
+        /// The node goes between its left and right origins, the neighbours it was inserted
+        /// between. Nodes inserted concurrently into the same gap are ordered by s4vector,
+        /// and each keeps the nodes inserted after it next to it, so every replica puts the
+        /// node in the same place whatever order it receives the inserts in. This is the
+        /// integration of YATA (the algorithm behind Yjs), which works with s4vectors whose
+        /// `sum` is a position rather than a clock.
         ///
         /// # Arguments
         /// `node`: The node to insert into the RGA.
         ///
         /// # Returns
-        /// The node inserted into the RGA.
-        async fn insert_into_list(&mut self, node: Arc<RwLock<Node>>) -> Arc<RwLock<Node>> {
-            let left: Option<S4Vector> = node.read().await.left;
-
-            if let Some(left) = left {
-                let mut current: S4Vector = left;
-                while let Some(node) = self.hash_map.get(&current) {
-                    if let Some(next_s4) = &node.read().await.right {
-                        if next_s4 > &node.read().await.s4vector {
-                            break;
-                        }
-                        current = *next_s4;
-                    } else {
+        /// The handle of the node inserted into the RGA.
+        fn insert_into_list(&mut self, node: Node) -> NodeId {
+            if let Some(id) = self.index.get(&node.s4vector).copied() {
+                // a node received again is left as it is, it may have been changed since
+                return id;
+            }
+
+            let index: usize = self.integration_index(&node);
+            let id: NodeId = self.store(node);
+            self.link(index, id);
+            id
+        }
+
+        /// The index in document order at which a node goes, between its origins.
+        fn integration_index(&self, node: &Node) -> usize {
+            let position = |s4vector: Option<S4Vector>| {
+                s4vector
+                    .and_then(|s4vector| self.index.get(&s4vector))
+                    .and_then(|id| self.content.position(*id))
+            };
+            let start: usize = position(node.left).map_or(0, |left| left + 1);
+            let end: Option<usize> = position(node.origin_right);
+
+            // the nodes between the origins are scanned until one that must come after the
+            // new node; `index` is where the node goes so far
+            let mut index: usize = start;
+            let mut scanned: HashSet<S4Vector> = HashSet::new();
+            let mut conflicting: HashSet<S4Vector> = HashSet::new();
+            for (i, other) in (start..).zip(self.content.order.iter_from(start)) {
+                if Some(i) == end {
+                    break;
+                }
+                let other: &Node = &self.nodes[other.0];
+                scanned.insert(other.s4vector);
+                conflicting.insert(other.s4vector);
+                if other.left == node.left {
+                    // inserted into the same gap, the smaller s4vector goes first
+                    if other.s4vector < node.s4vector {
+                        index = i + 1;
+                        conflicting.clear();
+                    } else if other.origin_right == node.origin_right {
                         break;
                     }
+                } else if let Some(left) = other.left.filter(|left| scanned.contains(left)) {
+                    // inserted after a node in the gap, it stays with that node
+                    if !conflicting.contains(&left) {
+                        index = i + 1;
+                        conflicting.clear();
+                    }
+                } else {
+                    break;
                 }
+            }
+            index
+        }
 
-                if let Some(other) = self.hash_map.get(&current) {
-                    node.write().await.right = other.read().await.right;
-                    other.write().await.right = Some(node.read().await.s4vector);
-                }
+        /// Links a stored node into the list at `index` in document order.
+        fn link(&mut self, index: usize, id: NodeId) {
+            let previous: Option<NodeId> =
+                index.checked_sub(1).and_then(|i| self.content.order.get(i));
+            let s4vector: S4Vector = self.nodes[id.0].s4vector;
+            self.nodes[id.0].right = self
+                .content
+                .order
+                .get(index)
+                .map(|next| self.nodes[next.0].s4vector);
+            match previous {
+                Some(previous) => self.nodes[previous.0].right = Some(s4vector),
+                None => self.head = Some(s4vector),
+            }
+
+            let node: &Node = &self.nodes[id.0];
+            let visible: &str = if node.tombstone { "" } else { &node.value };
+            self.content.insert(index, id, visible);
+        }
+
+        /// Takes a node out of the list, it stays in the arena and the index.
+        fn unlink(&mut self, id: NodeId) {
+            let Some(index) = self.content.position(id) else {
+                return;
+            };
+            let right: Option<S4Vector> = self.nodes[id.0].right;
+            match index.checked_sub(1).and_then(|i| self.content.order.get(i)) {
+                Some(previous) => self.nodes[previous.0].right = right,
+                None => self.head = right,
             }
+            self.content.remove(id);
+        }
 
-            if self.head.is_none() || left.is_none() {
-                self.head = Some(node.read().await.s4vector);
+        /// Moves nodes between the origins `left` and `right`, keeping their order. Each node
+        /// is integrated like an insert, the first after `left` and every other one after the
+        /// node before it, so concurrent moves to the same place are ordered the same way on
+        /// every replica. A node already moved by a later move (a greater s4vector) stays
+        /// where that move put it.
+        fn integrate_move(
+            &mut self,
+            stamp: S4Vector,
+            nodes: &[S4Vector],
+            left: Option<S4Vector>,
+            right: Option<S4Vector>,
+        ) {
+            let moving: Vec<NodeId> = nodes
+                .iter()
+                .filter_map(|s4vector| self.index.get(s4vector).copied())
+                .filter(|id| self.nodes[id.0].moved.is_none_or(|moved| moved < stamp))
+                .collect();
+            for id in &moving {
+                self.unlink(*id);
             }
 
-            Arc::clone(&node)
+            let mut previous: Option<S4Vector> = left;
+            for id in moving {
+                let node: &mut Node = &mut self.nodes[id.0];
+                node.left = previous;
+                node.origin_right = right;
+                node.moved = Some(stamp);
+                previous = Some(node.s4vector);
+
+                let index: usize = self.integration_index(&self.nodes[id.0]);
+                self.link(index, id);
+            }
         }
 
         /// Inserts a new value into the RGA.
@@ -285,135 +938,82 @@ pub mod rga {
         /// `right`: The S4Vector of the right neighbor (if any).
         ///
         /// # Returns
-        /// `Ok(())` if the insertion is successful, otherwise an error message.
+        /// `Ok(())` if the insertion is successful, otherwise an error message. An insert
+        /// next to a node the RGA doesn't have is rejected and leaves the RGA unchanged, only
+        /// remote operations wait in the buffer.
         ///
         /// # Example
-        /// ```rust
-        /// use crdt::rga::rga::RGA;
-        /// use crdt::S4Vector;
+        /// ```rust,ignore
+        /// use nimble::rga::rga::RGA;
+        /// use nimble::S4Vector;
         /// let mut rga = RGA::new(1,1);
-        /// rga.local_insert("A".to_string(), None, None)await.unwrap();
+        /// rga.local_insert("A".to_string(), None, None).unwrap();
         /// ```
-        pub async fn local_insert(
+        #[instrument(name = "rga.local_insert", skip(self, value))]
+        pub fn local_insert(
             &mut self,
             value: String,
             left: Option<S4Vector>,
             right: Option<S4Vector>,
             document_id: Uuid,
         ) -> Result<BroadcastOperation, OperationError> {
-            let new_node: Node = match (left, right) {
-                (Some(l), Some(r)) => {
-                    // Generate the S4Vector
-                    let new_s4: S4Vector = S4Vector::generate(
-                        Some(&l),
-                        Some(&r),
-                        self.session_id,
-                        self.site_id,
-                        &mut self.local_sequence,
-                    );
-
-                    // Check if the dependensies are resolved
-                    if !self.hash_map.contains_key(&l) {
-                        self.buffer.push_back(Operation {
-                            operation: OperationType::Insert,
-                            s4vector: new_s4,
-                            value: Some(value),
-                            tombstone: false,
-                            left,
-                            right,
-                        });
-                        return Err(OperationError::DependancyError);
-                    }
-
-                    Node::new(value, new_s4, Some(l), Some(r))
-                }
-                (Some(l), None) => {
-                    let new_s4: S4Vector = S4Vector::generate(
-                        Some(&l),
-                        None,
-                        self.session_id,
-                        self.site_id,
-                        &mut self.local_sequence,
-                    );
-
-                    // Check if the dependensies are resolved
-                    if !self.hash_map.contains_key(&l) {
-                        self.buffer.push_back(Operation {
-                            operation: OperationType::Insert,
-                            s4vector: new_s4,
-                            value: Some(value),
-                            tombstone: false,
-                            left,
-                            right,
-                        });
-                        return Err(OperationError::DependancyError);
-                    }
-
-                    Node::new(value, new_s4, Some(l), None)
-                }
-                (None, Some(r)) => {
-                    let new_s4: S4Vector = S4Vector::generate(
-                        None,
-                        Some(&r),
-                        self.session_id,
-                        self.site_id,
-                        &mut self.local_sequence,
-                    );
-
-                    // Check if the dependensies are resolved
-                    if !self.hash_map.contains_key(&r) {
-                        self.buffer.push_back(Operation {
-                            operation: OperationType::Insert,
-                            s4vector: new_s4,
-                            value: Some(value),
-                            tombstone: false,
-                            left,
-                            right,
-                        });
-                        return Err(OperationError::DependancyError);
-                    }
-
-                    Node::new(value, new_s4, None, Some(r))
-                }
-                (None, None) => {
-                    let new_s4: S4Vector = S4Vector::generate(
-                        None,
-                        None,
-                        self.session_id,
-                        self.site_id,
-                        &mut self.local_sequence,
-                    );
-
-                    Node::new(value, new_s4, None, None)
-                }
-            };
-            let new_node: Arc<RwLock<Node>> = Arc::new(RwLock::new(new_node));
-            let node: Arc<RwLock<Node>> = self.insert_into_list(new_node).await;
-
-            // Insert into the hash table
-            self.hash_map
-                .insert(node.read().await.s4vector, Arc::clone(&node));
+            let operation: BroadcastOperation =
+                self.prepare_insert(value, left, right, document_id)?;
+            self.apply_prepared(operation.clone())?;
+            Ok(operation)
+        }
 
-            self.apply_buffered_operations().await;
+        /// Builds the operation inserting a value, without applying it. Only the s4vector is
+        /// taken from the local sequence, so a request can log the operation before it is
+        /// applied with [`RGA::apply_prepared`] and leave the document unchanged if it can't.
+        ///
+        /// # Returns
+        /// The operation, or an error if a neighbour is unknown or a field of the s4vector
+        /// can't be stored.
+        pub fn prepare_insert(
+            &mut self,
+            value: String,
+            left: Option<S4Vector>,
+            right: Option<S4Vector>,
+            document_id: Uuid,
+        ) -> Result<BroadcastOperation, OperationError> {
+            if [left, right]
+                .iter()
+                .flatten()
+                .any(|s4vector| !self.contains(s4vector))
+            {
+                return Err(OperationError::DependancyError);
+            }
 
-            let node_guard = node.read().await;
-            let (s4vector, value, left, right) = (
-                node_guard.s4vector,
-                node_guard.value.clone(),
-                node_guard.left,
-                node_guard.right,
+            let new_s4: S4Vector = S4Vector::generate(
+                left.as_ref(),
+                right.as_ref(),
+                self.session_id,
+                self.site_id,
+                &mut self.local_sequence,
             );
+            // without a right neighbour the node goes before the one now following its left
+            let right: Option<S4Vector> = right.or(match left {
+                Some(left) => self.node(&left).and_then(|node| node.right),
+                None => self.head,
+            });
 
+            let [ssn, sum, sid, seq] = new_s4.to_i64()?;
             Ok(BroadcastOperation {
                 operation: "Insert".to_string(),
                 document_id,
-                ssn: s4vector.ssn as i64,
-                sum: s4vector.sum as i64,
-                sid: s4vector.sid as i64,
-                seq: s4vector.seq as i64,
+                ssn,
+                sum,
+                sid,
+                seq,
                 value: Some(value),
                 left,
                 right,
+                nodes: Vec::new(),
+                request_id: None,
+                author: None,
+                origin_region: None,
+                sent_at: None,
             })
         }
 
@@ -424,43 +1024,45 @@ pub mod rga {
         ///
         /// # Returns
         /// `Ok(())` if the deletion is successful, otherwise an error message.
-        pub async fn local_delete(
+        #[instrument(name = "rga.local_delete", skip(self))]
+        pub fn local_delete(
             &mut self,
             s4vector: S4Vector,
             document_id: Uuid,
         ) -> Result<BroadcastOperation, OperationError> {
-            let node: Arc<RwLock<Node>> = match self.hash_map.get(&s4vector) {
-                Some(node) => node.clone(),
-                None => {
-                    self.buffer.push_back(Operation {
-                        operation: OperationType::Delete,
-                        s4vector,
-                        value: None,
-                        tombstone: false,
-                        left: None,
-                        right: None,
-                    });
-                    return Err(OperationError::DependancyError);
-                }
-            };
-
-            node.write().await.tombstone = true;
-
-            self.apply_buffered_operations().await;
-
-            let node_guard = node.read().await;
-            let (s4vector, left, right) = (node_guard.s4vector, node_guard.left, node_guard.right);
+            let operation: BroadcastOperation = self.prepare_delete(s4vector, document_id)?;
+            self.apply_prepared(operation.clone())?;
+            Ok(operation)
+        }
 
+        /// Builds the operation deleting a node, without applying it.
+        ///
+        /// # Returns
+        /// The operation, or an error if the node is unknown or its s4vector can't be stored.
+        pub fn prepare_delete(
+            &self,
+            s4vector: S4Vector,
+            document_id: Uuid,
+        ) -> Result<BroadcastOperation, OperationError> {
+            let node: &Node = self
+                .node(&s4vector)
+                .ok_or(OperationError::DependancyError)?;
+            let [ssn, sum, sid, seq] = node.s4vector.to_i64()?;
             Ok(BroadcastOperation {
                 operation: "Delete".to_string(),
                 document_id,
-                ssn: s4vector.ssn as i64,
-                sum: s4vector.sum as i64,
-                sid: s4vector.sid as i64,
-                seq: s4vector.seq as i64,
+                ssn,
+                sum,
+                sid,
+                seq,
                 value: None,
-                left,
-                right,
+                left: node.left,
+                right: node.right,
+                nodes: Vec::new(),
+                request_id: None,
+                author: None,
+                origin_region: None,
+                sent_at: None,
             })
         }
 
@@ -473,187 +1075,713 @@ pub mod rga {
         ///
         /// # Returns
         /// `Ok(())` if the deletion is successful, otherwise an error message.
-        pub async fn local_update(
+        #[instrument(name = "rga.local_update", skip(self, value))]
+        pub fn local_update(
             &mut self,
             s4vector: S4Vector,
             value: String,
             document_id: Uuid,
         ) -> Result<BroadcastOperation, OperationError> {
-            let node: Arc<RwLock<Node>> = match &self.hash_map.get(&s4vector) {
-                Some(node) => Arc::clone(node),
-                None => {
-                    self.buffer.push_back(Operation {
-                        operation: OperationType::Update,
-                        s4vector,
-                        value: Some(value),
-                        tombstone: false,
-                        left: None,
-                        right: None,
-                    });
-                    return Err(OperationError::DependancyError);
-                }
-            };
-            if !node.read().await.tombstone {
-                node.write().await.value = value;
-            }
-            self.apply_buffered_operations().await;
-            let node_guard = node.read().await;
-            let (s4vector, value, left, right) = (
-                node_guard.s4vector,
-                node_guard.value.clone(),
-                node_guard.left,
-                node_guard.right,
-            );
+            let operation: BroadcastOperation =
+                self.prepare_update(s4vector, value, document_id)?;
+            self.apply_prepared(operation.clone())?;
+            Ok(operation)
+        }
+
+        /// Builds the operation updating a node's value, without applying it. A deleted node
+        /// keeps its value, so the operation carries that instead.
+        ///
+        /// # Returns
+        /// The operation, or an error if the node is unknown or its s4vector can't be stored.
+        pub fn prepare_update(
+            &self,
+            s4vector: S4Vector,
+            value: String,
+            document_id: Uuid,
+        ) -> Result<BroadcastOperation, OperationError> {
+            let node: &Node = self
+                .node(&s4vector)
+                .ok_or(OperationError::DependancyError)?;
+            let [ssn, sum, sid, seq] = node.s4vector.to_i64()?;
             Ok(BroadcastOperation {
                 operation: "Update".to_string(),
                 document_id,
-                ssn: s4vector.ssn as i64,
-                sum: s4vector.sum as i64,
-                sid: s4vector.sid as i64,
-                seq: s4vector.seq as i64,
-                value: Some(value),
-                left,
-                right,
+                ssn,
+                sum,
+                sid,
+                seq,
+                value: Some(if node.tombstone {
+                    node.value.clone()
+                } else {
+                    value
+                }),
+                left: node.left,
+                right: node.right,
+                nodes: Vec::new(),
+                request_id: None,
+                author: None,
+                origin_region: None,
+                sent_at: None,
             })
         }
 
-        /// Remote operation to add a new element at a position based on a provided UID
-        /// This operation updates the RGA to ensure eventual consistency
+        /// Applies an operation built by [`RGA::prepare_insert`], [`RGA::prepare_update`],
+        /// [`RGA::prepare_delete`] or [`RGA::prepare_move`], the same way other replicas apply it once it is broadcast.
         ///
-        /// # Arguments
-        /// `value`: The value being inserted.
-        /// `s4vector`: The s4vector for the operation.
-        /// `left`: The left s4vector for the operation.
-        /// `right`: The right s4vector for the operation.
-        pub async fn remote_insert(
+        /// # Returns
+        /// An error, leaving the RGA unchanged, if the document no longer has a node the
+        /// operation depends on or already has the node it inserts.
+        pub fn apply_prepared(
             &mut self,
-            value: String,
-            s4vector: S4Vector,
+            operation: BroadcastOperation,
+        ) -> Result<(), OperationError> {
+            let s4vector: S4Vector = operation.s4vector();
+            let missing: bool = match operation.operation.as_str() {
+                "Insert" => {
+                    self.contains(&s4vector)
+                        || [operation.left, operation.right]
+                            .iter()
+                            .flatten()
+                            .any(|s4vector| !self.contains(s4vector))
+                }
+                "Move" => operation
+                    .nodes
+                    .iter()
+                    .chain([operation.left, operation.right].iter().flatten())
+                    .any(|s4vector| !self.contains(s4vector)),
+                _ => !self.contains(&s4vector),
+            };
+            if missing {
+                return Err(OperationError::DependancyError);
+            }
+            self.apply_remote(operation)?;
+            Ok(())
+        }
+
+        /// Moves the nodes from `first` to `last` (inclusive, in document order) between `left`
+        /// and `right`, as a single operation. The nodes keep their s4vectors, so their authors
+        /// and anything anchored to them stay with the moved text.
+        ///
+        /// # Arguments
+        /// `first`: The first node of the range to move.
+        /// `last`: The last node of the range to move.
+        /// `left`: The node the range goes after (None for the start of the document).
+        /// `right`: The node the range goes before (None for the node now following `left`).
+        /// `document_id`: The document id of the document being changed.
+        ///
+        /// # Returns
+        /// The operation to broadcast, identified by a new s4vector, or an error if a node is
+        /// unknown or the destination is inside the range.
+        #[instrument(name = "rga.local_move", skip(self))]
+        pub fn local_move(
+            &mut self,
+            first: S4Vector,
+            last: S4Vector,
             left: Option<S4Vector>,
             right: Option<S4Vector>,
-        ) {
-            let new_node: Node = match (left, right) {
-                (Some(l), Some(r)) => Node::new(value, s4vector, Some(l), Some(r)),
-                (Some(l), None) => Node::new(value, s4vector, Some(l), None),
-                (None, Some(r)) => Node::new(value, s4vector, None, Some(r)),
-                (None, None) => Node::new(value, s4vector, None, None),
+            document_id: Uuid,
+        ) -> Result<BroadcastOperation, OperationError> {
+            let operation: BroadcastOperation =
+                self.prepare_move(first, last, left, right, document_id)?;
+            self.apply_prepared(operation.clone())?;
+            Ok(operation)
+        }
+
+        /// Builds the operation moving the nodes from `first` to `last` between `left` and
+        /// `right`, without applying it, like [`RGA::prepare_insert`]. The operation lists the
+        /// nodes in the range, so it moves the same nodes on every replica.
+        ///
+        /// # Returns
+        /// The operation, or an error if a node is unknown or the destination is inside the
+        /// range.
+        pub fn prepare_move(
+            &mut self,
+            first: S4Vector,
+            last: S4Vector,
+            left: Option<S4Vector>,
+            right: Option<S4Vector>,
+            document_id: Uuid,
+        ) -> Result<BroadcastOperation, OperationError> {
+            let position = |s4vector: &S4Vector| {
+                self.index
+                    .get(s4vector)
+                    .and_then(|id| self.content.position(*id))
             };
-            let new_node: Arc<RwLock<Node>> = Arc::new(RwLock::new(new_node));
-            let node: Arc<RwLock<Node>> = self.insert_into_list(new_node).await;
+            let (Some(start), Some(end)) = (position(&first), position(&last)) else {
+                return Err(OperationError::DependancyError);
+            };
+            if [left, right]
+                .iter()
+                .flatten()
+                .any(|s4vector| !self.contains(s4vector))
+            {
+                return Err(OperationError::DependancyError);
+            }
+            if start > end {
+                return Err(OperationError::InvalidMove(
+                    "the range ends before it starts".to_string(),
+                ));
+            }
+            let nodes: Vec<S4Vector> = self
+                .content
+                .order
+                .iter_from(start)
+                .take(end - start + 1)
+                .map(|id| self.nodes[id.0].s4vector)
+                .collect();
+            if [left, right]
+                .iter()
+                .flatten()
+                .any(|s4vector| nodes.contains(s4vector))
+            {
+                return Err(OperationError::InvalidMove(
+                    "the destination is inside the range".to_string(),
+                ));
+            }
 
-            self.hash_map
-                .insert(node.read().await.s4vector, Arc::clone(&node));
-            let _r = Box::pin(async move {
-                self.apply_buffered_operations().await;
+            // without a right origin the range goes before the node now following `left`,
+            // skipping the nodes being moved
+            let after: usize = left.and_then(|left| position(&left)).map_or(0, |i| i + 1);
+            let right: Option<S4Vector> = right.or_else(|| {
+                self.content
+                    .order
+                    .iter_from(after)
+                    .map(|id| self.nodes[id.0].s4vector)
+                    .find(|s4vector| !nodes.contains(s4vector))
             });
+            let stamp: S4Vector = S4Vector::generate(
+                left.as_ref(),
+                right.as_ref(),
+                self.session_id,
+                self.site_id,
+                &mut self.local_sequence,
+            );
+
+            let [ssn, sum, sid, seq] = stamp.to_i64()?;
+            Ok(BroadcastOperation {
+                operation: "Move".to_string(),
+                document_id,
+                ssn,
+                sum,
+                sid,
+                seq,
+                value: None,
+                left,
+                right,
+                nodes,
+                request_id: None,
+                author: None,
+                origin_region: None,
+                sent_at: None,
+            })
+        }
+
+        /// Remote operation to add a new element at a position based on a provided UID
+        /// This operation updates the RGA to ensure eventual consistency. If the left node has
+        /// not arrived yet the operation is buffered until it does, and so is an insert
+        /// whose right node has not arrived. SNS can deliver an operation more than once and
+        /// anti-entropy can send one again, so an insert of a node the RGA already has (or is
+        /// already buffering) is skipped, leaving the node and its neighbours as they are.
+        ///
+        /// # Arguments
+        /// `value`: The value being inserted.
+        /// `s4vector`: The s4vector for the operation.
+        /// `left`: The left s4vector for the operation.
+        /// `right`: The right s4vector for the operation.
+        ///
+        /// # Returns
+        /// `false` if the insert was skipped as a duplicate, otherwise `true`.
+        #[instrument(name = "rga.remote_insert", skip(self, value))]
+        pub fn remote_insert(
+            &mut self,
+            value: String,
+            s4vector: S4Vector,
+            left: Option<S4Vector>,
+            right: Option<S4Vector>,
+        ) -> bool {
+            let operation: Operation = Operation {
+                operation: OperationType::Insert,
+                s4vector,
+                value: Some(value),
+                tombstone: false,
+                left,
+                right,
+                nodes: Vec::new(),
+            };
+            let dependency: Option<S4Vector> = self.dependency(&operation);
+            let buffered = |dependency: &S4Vector| {
+                self.buffer.get(dependency).is_some_and(|operations| {
+                    operations.iter().any(|other| {
+                        matches!(other.operation.operation, OperationType::Insert)
+                            && other.operation.s4vector == s4vector
+                    })
+                })
+            };
+            if self.contains(&s4vector) || dependency.as_ref().is_some_and(buffered) {
+                debug!("Skipping an insert that was already received");
+                return false;
+            }
+
+            self.dirty = true;
+            self.touch();
+            if dependency.is_some() {
+                self.defer(operation);
+                return true;
+            }
+            self.insert_into_list(Node::new(
+                operation.value.unwrap_or_default(),
+                s4vector,
+                left,
+                right,
+            ));
+            self.resolve(s4vector);
+            true
         }
 
         /// Remote operation to remove an ekement given the UID
-        /// This operation updates the RGA to ensure eventual consistency
-        pub async fn remote_delete(&mut self, s4vector: S4Vector) {
-            let node: Arc<RwLock<Node>> = match self.hash_map.get(&s4vector) {
-                Some(node) => node.clone(),
-                None => {
-                    // The values has not been added yet
-                    return;
+        /// This operation updates the RGA to ensure eventual consistency. If the node has not
+        /// arrived yet the operation is buffered until it does.
+        #[instrument(name = "rga.remote_delete", skip(self))]
+        pub fn remote_delete(&mut self, s4vector: S4Vector) {
+            self.dirty = true;
+            self.touch();
+            match self.index.get(&s4vector).copied() {
+                Some(id) => {
+                    self.tombstone(id);
                 }
-            };
-            node.write().await.tombstone = true;
-            let _r = Box::pin(async move {
-                self.apply_buffered_operations().await;
-            });
+                None => self.defer(Operation {
+                    operation: OperationType::Delete,
+                    s4vector,
+                    value: None,
+                    tombstone: false,
+                    left: None,
+                    right: None,
+                    nodes: Vec::new(),
+                }),
+            }
         }
 
         /// Remote operation to update an element
-        /// This operation updates the RGA to ensure eventual consistency
-        pub async fn remote_update(&mut self, s4vector: S4Vector, value: String) {
-            let node: Arc<RwLock<Node>> = Arc::clone(&self.hash_map[&s4vector]);
-            if !node.read().await.tombstone {
-                node.write().await.value = value;
-            }
-            let _r = Box::pin(async move {
-                self.apply_buffered_operations().await;
-            });
+        /// This operation updates the RGA to ensure eventual consistency. If the node has not
+        /// arrived yet the operation is buffered until it does.
+        #[instrument(name = "rga.remote_update", skip(self, value))]
+        pub fn remote_update(&mut self, s4vector: S4Vector, value: String) {
+            self.dirty = true;
+            self.touch();
+            match self.index.get(&s4vector).copied() {
+                Some(id) if !self.nodes[id.0].tombstone => {
+                    self.nodes[id.0].value = value;
+                    self.rematerialize_node(id);
+                }
+                Some(_) => {}
+                None => self.defer(Operation {
+                    operation: OperationType::Update,
+                    s4vector,
+                    value: Some(value),
+                    tombstone: false,
+                    left: None,
+                    right: None,
+                    nodes: Vec::new(),
+                }),
+            }
+        }
+
+        /// Remote operation to move a range of nodes, identified by the move's s4vector.
+        /// The move is buffered until its origins and every node it moves have arrived.
+        #[instrument(name = "rga.remote_move", skip(self, nodes))]
+        pub fn remote_move(
+            &mut self,
+            s4vector: S4Vector,
+            nodes: Vec<S4Vector>,
+            left: Option<S4Vector>,
+            right: Option<S4Vector>,
+        ) {
+            self.dirty = true;
+            self.touch();
+            let operation: Operation = Operation {
+                operation: OperationType::Move,
+                s4vector,
+                value: None,
+                tombstone: false,
+                left,
+                right,
+                nodes,
+            };
+            if self.dependency(&operation).is_some() {
+                self.defer(operation);
+                return;
+            }
+            self.integrate_move(s4vector, &operation.nodes, left, right);
+        }
+
+        /// Applies an operation broadcast by another replica.
+        ///
+        /// # Arguments
+        /// `operation`: The operation received from the broadcaster.
+        ///
+        /// # Returns
+        /// `Ok(true)` if the operation was applied (or buffered), `Ok(false)` if it was an insert
+        /// already received, or an error if the type is unknown or the value is missing.
+        pub fn apply_remote(
+            &mut self,
+            operation: BroadcastOperation,
+        ) -> Result<bool, OperationError> {
+            let s4vector: S4Vector = operation.s4vector();
+            match (operation.operation.as_str(), operation.value) {
+                ("Insert", Some(value)) => {
+                    return Ok(self.remote_insert(value, s4vector, operation.left, operation.right))
+                }
+                ("Update", Some(value)) => self.remote_update(s4vector, value),
+                ("Delete", _) => self.remote_delete(s4vector),
+                ("Move", _) => {
+                    self.remote_move(s4vector, operation.nodes, operation.left, operation.right)
+                }
+                (other, _) => return Err(OperationError::InvalidOperation(other.to_string())),
+            }
+            Ok(true)
         }
 
         /// Reads the current state of the RGA, skipping tombstoned nodes.
         ///
         /// # Returns
         /// A vector of strings representing the current sequence.
-        pub async fn read(&self) -> Vec<String> {
-            let mut result: Vec<String> = Vec::new();
-            let mut current: Option<S4Vector> = self.head;
-
-            while let Some(current_s4) = current {
-                if let Some(node) = self.hash_map.get(&current_s4) {
-                    if !node.read().await.tombstone {
-                        result.push(node.read().await.value.clone());
-                    }
+        pub fn read(&self) -> Vec<String> {
+            self.iter()
+                .filter(|node| !node.tombstone)
+                .map(|node| node.value.clone())
+                .collect()
+        }
+
+        /// Returns the s4vector and value of each visible node in document order.
+        pub fn read_nodes(&self) -> Vec<(S4Vector, String)> {
+            self.iter()
+                .filter(|node| !node.tombstone)
+                .map(|node| (node.s4vector, node.value.clone()))
+                .collect()
+        }
+
+        /// The visible content of the document, without copying it.
+        pub fn text(&self) -> &str {
+            &self.content.text
+        }
+
+        /// Streams the visible content in chunks of about `size` bytes, so large documents
+        /// can be sent without copying them whole. The stream does not borrow the RGA and
+        /// reads the content as it was when it was called.
+        pub fn read_chunks(&self, size: usize) -> stream::Iter<Chunks> {
+            stream::iter(Chunks {
+                text: Arc::clone(&self.content.text),
+                start: 0,
+                size,
+            })
+        }
+
+        /// Returns a copy of every node (including tombstones) ordered by s4vector.
+        pub fn nodes(&self) -> Vec<Node> {
+            let mut nodes: Vec<Node> = self.nodes.clone();
+            nodes.sort_by_key(|node| node.s4vector);
+            nodes
+        }
 
-                    current = node.read().await.right;
+        /// Returns a copy of each visible node in document order.
+        pub fn visible_nodes(&self) -> Vec<Node> {
+            self.iter()
+                .filter(|node| !node.tombstone)
+                .cloned()
+                .collect()
+        }
+
+        /// Returns a copy of every node (including tombstones) in document order.
+        pub fn ordered_nodes(&self) -> Vec<Node> {
+            self.iter().cloned().collect()
+        }
+
+        /// Walks the list in document order, returning the character index each node starts at
+        /// and the number of visible characters it holds (0 for tombstones).
+        pub fn char_offsets(&self) -> HashMap<S4Vector, (usize, usize)> {
+            let mut offsets: HashMap<S4Vector, (usize, usize)> = HashMap::new();
+            let mut index: usize = 0;
+
+            for node in self.iter() {
+                let len: usize = if node.tombstone {
+                    0
                 } else {
-                    break;
+                    node.value.chars().count()
+                };
+                offsets.insert(node.s4vector, (index, len));
+                index += len;
+            }
+            offsets
+        }
+
+        /// Returns the highest `seq` seen from each site id.
+        pub fn version_vector(&self) -> BTreeMap<u64, u64> {
+            let mut sites: BTreeMap<u64, u64> = BTreeMap::new();
+            for node in &self.nodes {
+                let seq = sites.entry(node.s4vector.sid).or_insert(0);
+                *seq = (*seq).max(node.s4vector.seq);
+            }
+            sites
+        }
+
+        /// Hashes the visible state of the document: the s4vector and value of each visible
+        /// node, in document order. Replicas that have applied the same operations have the
+        /// same digest, whatever order they applied them in. Tombstones are left out, so only
+        /// differences that readers of the document would see are reported.
+        pub fn digest(&self) -> String {
+            let mut hasher = Sha256::new();
+            for node in self.iter().filter(|node| !node.tombstone) {
+                let s4vector: S4Vector = node.s4vector;
+                for field in [s4vector.ssn, s4vector.sum, s4vector.sid, s4vector.seq] {
+                    hasher.update(field.to_le_bytes());
                 }
+                hasher.update((node.value.len() as u64).to_le_bytes());
+                hasher.update(node.value.as_bytes());
             }
-            result
+            hex::encode(hasher.finalize())
         }
 
-        pub async fn apply_buffered_operations(&mut self) {
-            let mut new_buffer: VecDeque<Operation> = VecDeque::new();
+        /// Records the user that wrote a node's current value and when.
+        /// Does nothing if the node does not exist.
+        pub fn set_author(
+            &mut self,
+            s4vector: S4Vector,
+            author: Option<Uuid>,
+            authored_at: Option<String>,
+        ) {
+            if let Some(node) = self.node_mut(&s4vector) {
+                node.author = author;
+                node.authored_at = authored_at;
+            }
+        }
 
-            for op in self.buffer.clone() {
-                if let Some(left) = &op.left {
-                    if !self.hash_map.contains_key(left) {
-                        new_buffer.push_back(op);
-                        continue;
-                    }
+        /// Records that the RGA is in use so it is not evicted.
+        pub fn touch(&mut self) {
+            self.last_accessed = Instant::now();
+        }
+
+        /// Approximates the memory held by the RGA: its nodes (including tombstones),
+        /// their values and any buffered operations.
+        pub fn memory_usage(&self) -> MemoryUsage {
+            let mut usage = MemoryUsage {
+                buffered: self.buffered(),
+                ..Default::default()
+            };
+            for node in &self.nodes {
+                usage.nodes += 1;
+                usage.value_bytes += node.value.len();
+            }
+            usage.tombstones = self.tombstones;
+            usage.live = usage.nodes - usage.tombstones;
+            usage.approx_bytes = usage.nodes * NODE_OVERHEAD
+                + usage.value_bytes
+                + self.content.text.len()
+                + usage.buffered * std::mem::size_of::<Operation>();
+            usage
+        }
+
+        /// The number of nodes that have not been deleted.
+        pub fn live(&self) -> usize {
+            self.nodes.len() - self.tombstones
+        }
+
+        /// The number of deleted nodes kept for ordering.
+        pub fn tombstones(&self) -> usize {
+            self.tombstones
+        }
+
+        /// The share of the RGA's nodes that are tombstones, 0 for an empty RGA.
+        pub fn tombstone_ratio(&self) -> f64 {
+            if self.nodes.is_empty() {
+                return 0.0;
+            }
+            self.tombstones as f64 / self.nodes.len() as f64
+        }
+
+        /// Calculates the size in bytes of the visible (non tombstoned) content.
+        pub fn content_size(&self) -> usize {
+            self.content.text.len()
+        }
+
+        /// The number of operations waiting on a node that has not arrived.
+        pub fn buffered(&self) -> usize {
+            self.buffer.values().map(Vec::len).sum()
+        }
+
+        /// Buffers an operation until the node it depends on arrives.
+        fn defer(&mut self, operation: Operation) {
+            self.defer_since(operation, Instant::now());
+        }
+
+        fn defer_since(&mut self, operation: Operation, at: Instant) {
+            self.buffer
+                .entry(self.dependency(&operation).unwrap_or(operation.s4vector))
+                .or_default()
+                .push(Buffered { operation, at });
+        }
+
+        /// Whether a remote operation would be buffered, because the node it changes or one
+        /// of the origins of the node it inserts has not arrived yet.
+        pub fn would_buffer(&self, operation: &BroadcastOperation) -> bool {
+            match operation.operation.as_str() {
+                "Insert" => [operation.left, operation.right]
+                    .into_iter()
+                    .flatten()
+                    .any(|s4vector| !self.contains(&s4vector)),
+                "Update" | "Delete" => !self.contains(&operation.s4vector()),
+                "Move" => [operation.left, operation.right]
+                    .iter()
+                    .flatten()
+                    .chain(&operation.nodes)
+                    .any(|s4vector| !self.contains(s4vector)),
+                _ => false,
+            }
+        }
+
+        /// Drops the operations buffered for longer than `max_age`, then the oldest operations
+        /// until at most `max_operations` are left (0 for no limit). The dropped operations are
+        /// lost, so the document has to be reloaded to catch up with the other replicas.
+        pub fn shed_buffer(&mut self, max_operations: usize, max_age: Option<Duration>) -> Shed {
+            let mut shed = Shed::default();
+            let total: usize = self.buffered();
+            let over: usize = if max_operations == 0 {
+                0
+            } else {
+                total.saturating_sub(max_operations)
+            };
+            let now: Instant = Instant::now();
+            let expired = |buffered: &Buffered| {
+                max_age.is_some_and(|max_age| now.duration_since(buffered.at) > max_age)
+            };
+            if over == 0 && !self.buffer.values().flatten().any(expired) {
+                return shed;
+            }
+
+            let mut waiting: Vec<(S4Vector, Buffered)> = Vec::with_capacity(total);
+            for (dependency, operations) in self.buffer.drain() {
+                waiting.extend(
+                    operations
+                        .into_iter()
+                        .map(|buffered| (dependency, buffered)),
+                );
+            }
+            let before: usize = waiting.len();
+            waiting.retain(|(_, buffered)| !expired(buffered));
+            shed.expired = before - waiting.len();
+
+            if max_operations > 0 && waiting.len() > max_operations {
+                // stable, so operations waiting on the same node keep their order
+                waiting.sort_by_key(|(_, buffered)| buffered.at);
+                shed.overflowed = waiting.len() - max_operations;
+                waiting.drain(..shed.overflowed);
+            }
+            for (dependency, buffered) in waiting {
+                self.buffer.entry(dependency).or_default().push(buffered);
+            }
+            shed
+        }
+
+        /// The node that must be in the RGA before the operation can be applied: a missing
+        /// origin of an insert, otherwise the node being changed. None if it can be applied.
+        fn dependency(&self, operation: &Operation) -> Option<S4Vector> {
+            match operation.operation {
+                OperationType::Insert => [operation.left, operation.right]
+                    .into_iter()
+                    .flatten()
+                    .find(|s4vector| !self.contains(s4vector)),
+                OperationType::Update | OperationType::Delete => {
+                    Some(operation.s4vector).filter(|s4vector| !self.contains(s4vector))
                 }
+                OperationType::Move => [operation.left, operation.right]
+                    .into_iter()
+                    .flatten()
+                    .chain(operation.nodes.iter().copied())
+                    .find(|s4vector| !self.contains(s4vector)),
+            }
+        }
 
-                match op.operation {
-                    OperationType::Insert => {
-                        if let Some(value) = &op.value {
-                            self.remote_insert(value.clone(), op.s4vector, op.left, op.right)
-                                .await;
+        /// Applies the operations waiting on a node that has just arrived. Buffered inserts
+        /// are nodes in turn, so their own dependents are applied after them.
+        fn resolve(&mut self, arrived: S4Vector) {
+            let mut arrived: Vec<S4Vector> = vec![arrived];
+
+            while let Some(s4vector) = arrived.pop() {
+                let Some(operations) = self.buffer.remove(&s4vector) else {
+                    continue;
+                };
+                for Buffered { operation: op, at } in operations {
+                    match op.operation {
+                        // a duplicate buffered before the node arrived
+                        OperationType::Insert if self.contains(&op.s4vector) => {}
+                        // the insert may still be waiting on its other origin
+                        OperationType::Insert if self.dependency(&op).is_some() => {
+                            self.defer_since(op, at)
                         }
-                    }
-                    OperationType::Update => {
-                        if let Some(value) = &op.value {
-                            self.remote_update(op.s4vector, value.to_string()).await;
+                        OperationType::Insert => {
+                            if let Some(value) = op.value {
+                                self.insert_into_list(Node::new(
+                                    value,
+                                    op.s4vector,
+                                    op.left,
+                                    op.right,
+                                ));
+                                arrived.push(op.s4vector);
+                            }
+                        }
+                        OperationType::Update => {
+                            if let Some(value) = op.value {
+                                self.remote_update(op.s4vector, value);
+                            }
+                        }
+                        OperationType::Delete => {
+                            self.remote_delete(op.s4vector);
+                        }
+                        OperationType::Move if self.dependency(&op).is_some() => {
+                            self.defer_since(op, at)
+                        }
+                        OperationType::Move => {
+                            self.integrate_move(op.s4vector, &op.nodes, op.left, op.right)
                         }
-                    }
-                    OperationType::Delete => {
-                        self.remote_delete(op.s4vector).await;
                     }
                 }
             }
+        }
 
-            self.buffer = new_buffer;
+        /// Applies every buffered operation whose dependency is already in the RGA.
+        pub fn apply_buffered_operations(&mut self) {
+            let ready: Vec<S4Vector> = self
+                .buffer
+                .keys()
+                .filter(|s4vector| self.contains(s4vector))
+                .copied()
+                .collect();
+            for s4vector in ready {
+                self.resolve(s4vector);
+            }
         }
     }
 
     #[cfg(test)]
     mod tests {
-        use rocket::tokio;
+        use uuid::uuid;
 
         use super::*;
 
-        #[tokio::test]
-        async fn test_insert() {
+        #[test]
+        fn test_insert() {
             let mut rga = RGA::new(1, 1);
-            let result = rga
-                .local_insert(
-                    "A".to_string(),
-                    None,
-                    None,
-                    uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"),
-                )
-                .await;
+            let result = rga.local_insert(
+                "A".to_string(),
+                None,
+                None,
+                uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"),
+            );
             assert!(result.is_ok());
-            assert_eq!(rga.hash_map.len(), 1);
+            assert_eq!(rga.len(), 1);
         }
 
-        #[tokio::test]
-        async fn test_delete() {
+        #[test]
+        fn test_delete() {
             let mut rga = RGA::new(1, 1);
             let s4 = rga
                 .local_insert(
@@ -662,18 +1790,15 @@ pub mod rga {
                     None,
                     uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"),
                 )
-                .await
                 .unwrap()
                 .s4vector();
-            let result = rga
-                .local_delete(s4.clone(), uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"))
-                .await;
+            let result = rga.local_delete(s4, uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"));
             assert!(result.is_ok());
-            assert!(rga.hash_map[&s4].read().await.tombstone);
+            assert!(rga.node(&s4).unwrap().tombstone);
         }
 
-        #[tokio::test]
-        async fn test_update() {
+        #[test]
+        fn test_update() {
             let mut rga = RGA::new(1, 1);
             let s4 = rga
                 .local_insert(
@@ -682,22 +1807,65 @@ pub mod rga {
                     None,
                     uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"),
                 )
-                .await
                 .unwrap()
                 .s4vector();
-            let result = rga
-                .local_update(
-                    s4.clone(),
-                    "B".to_string(),
-                    uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"),
-                )
-                .await;
+            let result = rga.local_update(
+                s4,
+                "B".to_string(),
+                uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"),
+            );
             assert!(result.is_ok());
-            assert_eq!(rga.hash_map[&s4].read().await.value, "B".to_string());
+            assert_eq!(rga.node(&s4).unwrap().value, "B".to_string());
+        }
+
+        #[test]
+        fn test_prepared_operations() {
+            let document_id = uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8");
+            let mut rga = RGA::new(1, 1);
+            let first = rga
+                .local_insert("a".to_string(), None, None, document_id)
+                .unwrap()
+                .s4vector();
+
+            // preparing an operation leaves the document as it was
+            rga.dirty = false;
+            let insert = rga
+                .prepare_insert("b".to_string(), Some(first), None, document_id)
+                .unwrap();
+            let update = rga
+                .prepare_update(first, "A".to_string(), document_id)
+                .unwrap();
+            let delete = rga.prepare_delete(first, document_id).unwrap();
+            assert_eq!(rga.read(), vec!["a"]);
+            assert!(!rga.dirty);
+
+            rga.apply_prepared(insert).unwrap();
+            rga.apply_prepared(update.clone()).unwrap();
+            assert_eq!(rga.read(), vec!["A", "b"]);
+            rga.apply_prepared(delete).unwrap();
+            assert_eq!(rga.read(), vec!["b"]);
+            assert!(rga.dirty);
+
+            // an operation the document can no longer take is returned, not buffered
+            let mut reloaded = RGA::new(1, 1);
+            assert!(matches!(
+                reloaded.apply_prepared(update),
+                Err(OperationError::DependancyError)
+            ));
+            assert_eq!(reloaded.buffered(), 0);
+
+            // an s4vector that can't be stored is rejected before the document changes
+            let mut rga = RGA::new(1, u64::MAX);
+            assert!(matches!(
+                rga.local_insert("a".to_string(), None, None, document_id),
+                Err(OperationError::OutOfRange(_))
+            ));
+            assert!(rga.is_empty());
+            assert!(!rga.dirty);
         }
 
-        #[tokio::test]
-        async fn test_read() {
+        #[test]
+        fn test_read() {
             let mut rga = RGA::new(1, 1);
             rga.local_insert(
                 "A".to_string(),
@@ -705,7 +1873,6 @@ pub mod rga {
                 None,
                 uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"),
             )
-            .await
             .unwrap();
             let s4 = rga.head.unwrap();
             rga.local_insert(
@@ -714,14 +1881,595 @@ pub mod rga {
                 None,
                 uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"),
             )
-            .await
             .unwrap();
             rga.local_delete(s4, uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"))
-                .await
                 .unwrap();
 
-            let result = rga.read().await;
+            let result = rga.read();
             assert_eq!(result, vec!["B".to_string()]);
         }
+
+        #[test]
+        fn test_dirty_and_nodes() {
+            let mut rga = RGA::new(1, 1);
+            assert!(!rga.dirty);
+
+            let s4 = rga
+                .local_insert(
+                    "A".to_string(),
+                    None,
+                    None,
+                    uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"),
+                )
+                .unwrap()
+                .s4vector();
+            rga.local_delete(s4, uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"))
+                .unwrap();
+            assert!(rga.dirty);
+
+            // snapshots keep tombstones so remote operations can still reference them
+            let nodes = rga.nodes();
+            assert_eq!(nodes.len(), 1);
+            assert!(nodes[0].tombstone);
+        }
+
+        #[test]
+        fn test_load_snapshot_resumes_sequence() {
+            let document_id = uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8");
+            let row = |ssn: i64, sid: i64, seq: i64| DocumentSnapshot {
+                document_id,
+                ssn,
+                sum: seq,
+                sid,
+                seq,
+                value: "A".to_string(),
+                tombstone: false,
+                author: None,
+                authored_at: None,
+            };
+
+            // rows from this session, an earlier session and another replica
+            let snapshots = vec![row(1, 2, 7), row(2, 2, 3), row(2, 5, 9)];
+            let mut rga = RGA::load_snapshot(snapshots, 2, 2);
+            assert_eq!(rga.local_sequence, 3);
+            assert_eq!(rga.read(), vec!["A", "A", "A"]);
+            assert!(!rga.dirty);
+
+            let s4 = rga
+                .local_insert("B".to_string(), None, None, document_id)
+                .unwrap()
+                .s4vector();
+            assert_eq!((s4.ssn, s4.sid, s4.seq), (2, 2, 4));
+        }
+
+        #[test]
+        fn test_from_nodes() {
+            let document_id = uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8");
+            let mut rga = RGA::new(2, 2);
+            let a = rga
+                .local_insert("a".to_string(), None, None, document_id)
+                .unwrap()
+                .s4vector();
+            let c = rga
+                .local_insert("c".to_string(), Some(a), None, document_id)
+                .unwrap()
+                .s4vector();
+            rga.local_insert("b".to_string(), Some(a), Some(c), document_id)
+                .unwrap();
+            rga.local_delete(c, document_id).unwrap();
+            rga.remote_insert(
+                "d".to_string(),
+                S4Vector {
+                    ssn: 1,
+                    sum: 9,
+                    sid: 5,
+                    seq: 1,
+                },
+                Some(c),
+                None,
+            );
+
+            // a checkpoint of the document loads with the same order, tombstones and digest
+            let checkpoint: Vec<Node> = rga.iter().cloned().collect();
+            let loaded = RGA::from_nodes(checkpoint, 2, 2);
+            assert_eq!(loaded.read(), vec!["a", "b", "d"]);
+            assert_eq!(loaded.len(), 4);
+            assert_eq!(loaded.digest(), rga.digest());
+            assert_eq!(loaded.local_sequence, 3);
+            assert!(!loaded.dirty);
+        }
+
+        #[test]
+        fn test_digest() {
+            let document_id = uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8");
+            let mut local = RGA::new(1, 1);
+            let mut remote = RGA::new(1, 2);
+            assert_eq!(local.digest(), remote.digest());
+
+            let first = local
+                .local_insert("A".to_string(), None, None, document_id)
+                .unwrap();
+            let second = local
+                .local_insert("B".to_string(), Some(first.s4vector()), None, document_id)
+                .unwrap();
+            let deleted = local.local_delete(first.s4vector(), document_id).unwrap();
+            for operation in [first.clone(), second, deleted] {
+                remote.apply_remote(operation).unwrap();
+            }
+            assert_eq!(local.digest(), remote.digest());
+
+            // a node visible on only one of the replicas is a divergence
+            remote.node_mut(&first.s4vector()).unwrap().tombstone = false;
+            assert_ne!(local.digest(), remote.digest());
+        }
+
+        #[test]
+        fn test_buffered_chain() {
+            let document_id = uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8");
+            let mut local = RGA::new(1, 1);
+            let mut operations: Vec<BroadcastOperation> = Vec::new();
+            let mut last: Option<S4Vector> = None;
+            for value in ["a", "b", "c", "d"] {
+                let operation = local
+                    .local_insert(value.to_string(), last, None, document_id)
+                    .unwrap();
+                last = Some(operation.s4vector());
+                operations.push(operation);
+            }
+            let b = operations[1].s4vector();
+            let update = local.local_update(b, "B".to_string(), document_id).unwrap();
+            let delete = local
+                .local_delete(operations[2].s4vector(), document_id)
+                .unwrap();
+
+            // every operation arrives before the one it depends on
+            let mut remote = RGA::new(1, 2);
+            remote.apply_remote(delete).unwrap();
+            remote.apply_remote(update).unwrap();
+            for operation in operations.iter().rev() {
+                remote.apply_remote(operation.clone()).unwrap();
+            }
+            assert_eq!(remote.buffered(), 0);
+            assert_eq!(remote.read(), vec!["a", "B", "d"]);
+            assert_eq!(remote.digest(), local.digest());
+        }
+
+        #[test]
+        fn test_duplicate_inserts() {
+            let document_id = uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8");
+            let mut local = RGA::new(1, 1);
+            let first = local
+                .local_insert("a".to_string(), None, None, document_id)
+                .unwrap();
+            let second = local
+                .local_insert("b".to_string(), Some(first.s4vector()), None, document_id)
+                .unwrap();
+            let third = local
+                .local_insert("c".to_string(), Some(second.s4vector()), None, document_id)
+                .unwrap();
+            let deleted = local.local_delete(second.s4vector(), document_id).unwrap();
+
+            // a redelivery while the first copy is still buffered is not buffered again
+            let mut remote = RGA::new(1, 2);
+            assert!(remote.apply_remote(third.clone()).unwrap());
+            assert!(!remote.apply_remote(third.clone()).unwrap());
+            assert_eq!(remote.buffered(), 1);
+
+            for operation in [first.clone(), second.clone(), deleted] {
+                assert!(remote.apply_remote(operation).unwrap());
+            }
+            assert_eq!(remote.read(), vec!["a", "c"]);
+
+            // redeliveries after the node was deleted do not bring it back or move it
+            remote.dirty = false;
+            for operation in [second, first, third] {
+                assert!(!remote.apply_remote(operation).unwrap());
+            }
+            assert!(!remote.dirty);
+            assert_eq!(remote.len(), 3);
+            assert_eq!(remote.read(), vec!["a", "c"]);
+            assert_eq!(remote.digest(), local.digest());
+        }
+
+        #[test]
+        fn test_move() {
+            let document_id = uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8");
+            let mut local = RGA::new(1, 1);
+            let mut inserts: Vec<BroadcastOperation> = Vec::new();
+            for value in ["a", "b", "c", "d", "e"] {
+                let left: Option<S4Vector> = inserts.last().map(|op| op.s4vector());
+                inserts.push(
+                    local
+                        .local_insert(value.to_string(), left, None, document_id)
+                        .unwrap(),
+                );
+            }
+            let [a, b, c, d, e] = [0, 1, 2, 3, 4].map(|i| inserts[i].s4vector());
+            local.set_author(b, Some(document_id), None);
+
+            assert!(matches!(
+                local.local_move(b, c, Some(c), None, document_id),
+                Err(OperationError::InvalidMove(_))
+            ));
+            assert!(matches!(
+                local.local_move(c, b, Some(e), None, document_id),
+                Err(OperationError::InvalidMove(_))
+            ));
+
+            let moved = local.local_move(b, c, Some(e), None, document_id).unwrap();
+            assert_eq!(moved.nodes, vec![b, c]);
+            assert_eq!(local.text(), "adebc");
+            // the moved nodes keep their identity
+            assert_eq!(local.node(&b).unwrap().author, Some(document_id));
+            assert_eq!(local.ordered_nodes().len(), 5);
+
+            // a replica that receives the move before the nodes it moves buffers it
+            let mut remote = RGA::new(1, 2);
+            remote.apply_remote(moved.clone()).unwrap();
+            assert_eq!(remote.buffered(), 1);
+            for operation in inserts.iter().rev() {
+                remote.apply_remote(operation.clone()).unwrap();
+            }
+            assert_eq!(remote.buffered(), 0);
+            assert_eq!(remote.text(), "adebc");
+            assert_eq!(remote.digest(), local.digest());
+
+            // concurrent moves of the same node: the later move wins on every replica
+            let first = local.local_move(d, d, Some(a), None, document_id).unwrap();
+            let second = remote.local_move(d, d, None, None, document_id).unwrap();
+            local.apply_remote(second.clone()).unwrap();
+            remote.apply_remote(first.clone()).unwrap();
+            assert_eq!(local.text(), remote.text());
+            let winner: S4Vector = first.s4vector().max(second.s4vector());
+            assert_eq!(local.node(&d).unwrap().moved, Some(winner));
+
+            // a move received again changes nothing
+            remote.apply_remote(first).unwrap();
+            remote.apply_remote(second).unwrap();
+            assert_eq!(local.text(), remote.text());
+            assert_eq!(remote.digest(), local.digest());
+        }
+
+        #[test]
+        fn test_buffered_waits_for_dependency() {
+            let document_id = uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8");
+            let mut source = RGA::new(1, 2);
+            let first = source
+                .local_insert("a".to_string(), None, None, document_id)
+                .unwrap();
+            let second = source
+                .local_insert("b".to_string(), Some(first.s4vector()), None, document_id)
+                .unwrap();
+            let third = source
+                .local_insert("c".to_string(), Some(second.s4vector()), None, document_id)
+                .unwrap();
+
+            let mut rga = RGA::new(1, 1);
+            rga.apply_remote(third).unwrap();
+            rga.apply_remote(second).unwrap();
+            assert_eq!(rga.buffered(), 2);
+            assert_eq!(rga.memory_usage().buffered, 2);
+            assert!(rga.read().is_empty());
+
+            // a local operation on a node that hasn't arrived is rejected, and isn't applied
+            // once it does
+            rga.dirty = false;
+            assert!(rga
+                .local_insert("d".to_string(), Some(first.s4vector()), None, document_id)
+                .is_err());
+            assert!(rga
+                .local_update(first.s4vector(), "e".to_string(), document_id)
+                .is_err());
+            assert!(rga.local_delete(first.s4vector(), document_id).is_err());
+            assert_eq!(rga.buffered(), 2);
+            assert_eq!(rga.local_sequence, 0);
+            assert!(!rga.dirty);
+
+            // nothing is applied until the missing node arrives
+            rga.apply_buffered_operations();
+            assert_eq!(rga.buffered(), 2);
+
+            rga.apply_remote(first).unwrap();
+            assert_eq!(rga.buffered(), 0);
+            assert_eq!(rga.len(), 3);
+            assert_eq!(rga.read(), vec!["a", "b", "c"]);
+        }
+
+        #[test]
+        fn test_shed_buffer() {
+            let document_id = uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8");
+            let mut source = RGA::new(1, 2);
+            let mut operations: Vec<BroadcastOperation> = Vec::new();
+            for value in ["a", "b", "c", "d"] {
+                let left: Option<S4Vector> = operations.last().map(|op| op.s4vector());
+                operations.push(
+                    source
+                        .local_insert(value.to_string(), left, None, document_id)
+                        .unwrap(),
+                );
+            }
+
+            let mut rga = RGA::new(1, 1);
+            for operation in &operations[1..] {
+                assert!(rga.would_buffer(operation));
+                rga.apply_remote(operation.clone()).unwrap();
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(rga.buffered(), 3);
+            assert_eq!(rga.shed_buffer(3, None), Shed::default());
+
+            // the oldest operation goes, so the ones waiting on it can no longer be applied
+            assert_eq!(
+                rga.shed_buffer(2, None),
+                Shed {
+                    expired: 0,
+                    overflowed: 1
+                }
+            );
+            rga.apply_remote(operations[0].clone()).unwrap();
+            assert_eq!(rga.read(), vec!["a"]);
+            assert_eq!(rga.buffered(), 2);
+
+            std::thread::sleep(Duration::from_millis(1));
+            assert_eq!(
+                rga.shed_buffer(0, Some(Duration::ZERO)),
+                Shed {
+                    expired: 2,
+                    overflowed: 0
+                }
+            );
+            assert_eq!(rga.buffered(), 0);
+        }
+
+        #[test]
+        fn test_arena_handles() {
+            let document_id = uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8");
+            let mut rga = RGA::new(1, 1);
+            let mut last: Option<S4Vector> = None;
+            for value in ["a", "b", "c"] {
+                last = Some(
+                    rga.local_insert(value.to_string(), last, None, document_id)
+                        .unwrap()
+                        .s4vector(),
+                );
+            }
+            assert_eq!(rga.len(), 3);
+            assert_eq!(rga.read(), vec!["a", "b", "c"]);
+
+            // a node received again is skipped instead of adding another or reverting it
+            let last = last.unwrap();
+            rga.local_update(last, "C".to_string(), document_id)
+                .unwrap();
+            assert!(!rga.remote_insert("c".to_string(), last, None, None));
+            assert_eq!(rga.len(), 3);
+            assert!(rga.contains(&last));
+            assert_eq!(rga.memory_usage().nodes, 3);
+            assert_eq!(rga.read(), vec!["a", "b", "C"]);
+        }
+
+        #[test]
+        fn test_order_tree() {
+            // the tree is compared with a list kept in document order
+            let mut tree = OrderTree::default();
+            let mut list: Vec<(NodeId, usize)> = Vec::new();
+            for i in 0..500 {
+                let index: usize = (i * 7919) % (list.len() + 1);
+                tree.insert(index, NodeId(i), i % 5);
+                list.insert(index, (NodeId(i), i % 5));
+            }
+            for i in (0..500).step_by(3) {
+                tree.remove(NodeId(i));
+                list.retain(|(id, _)| *id != NodeId(i));
+            }
+            for i in (1..500).step_by(4) {
+                tree.resize(NodeId(i), 2);
+                if let Some(node) = list.iter_mut().find(|(id, _)| *id == NodeId(i)) {
+                    node.1 = 2;
+                }
+            }
+
+            assert_eq!(tree.len(), list.len());
+            let mut start: usize = 0;
+            for (index, (id, len)) in list.iter().enumerate() {
+                assert_eq!(tree.offset(*id), Some((index, start)));
+                assert_eq!(tree.get(index), Some(*id));
+                start += len;
+            }
+            assert_eq!(tree.bytes(tree.root), start);
+            assert_eq!(tree.offset(NodeId(0)), None);
+            assert_eq!(tree.get(list.len()), None);
+            assert!(tree.iter_from(10).eq(list[10..].iter().map(|(id, _)| *id)));
+        }
+
+        #[test]
+        fn test_materialized_text() {
+            let document_id = uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8");
+            let walked = |rga: &RGA| -> (Vec<S4Vector>, String) {
+                let nodes: Vec<&Node> = rga.walk().map(|id| &rga.nodes[id.0]).collect();
+                (
+                    nodes.iter().map(|node| node.s4vector).collect(),
+                    nodes
+                        .iter()
+                        .filter(|node| !node.tombstone)
+                        .map(|node| node.value.as_str())
+                        .collect(),
+                )
+            };
+            let materialized = |rga: &RGA| -> (Vec<S4Vector>, String) {
+                (
+                    rga.iter().map(|node| node.s4vector).collect(),
+                    rga.text().to_string(),
+                )
+            };
+
+            let mut local = RGA::new(1, 1);
+            let mut remote = RGA::new(1, 2);
+            let hello = local
+                .local_insert("héllo".to_string(), None, None, document_id)
+                .unwrap();
+            remote.apply_remote(hello.clone()).unwrap();
+
+            // concurrent inserts after the same node, and one at the start of the document
+            let mut operations: Vec<BroadcastOperation> = vec![
+                local
+                    .local_insert(
+                        " wörld".to_string(),
+                        Some(hello.s4vector()),
+                        None,
+                        document_id,
+                    )
+                    .unwrap(),
+                local
+                    .local_insert(">".to_string(), None, Some(hello.s4vector()), document_id)
+                    .unwrap(),
+            ];
+            let concurrent = remote
+                .local_insert(",".to_string(), Some(hello.s4vector()), None, document_id)
+                .unwrap();
+            operations.push(
+                remote
+                    .local_update(concurrent.s4vector(), ";".to_string(), document_id)
+                    .unwrap(),
+            );
+            operations.insert(0, concurrent);
+            operations.push(
+                local
+                    .local_update(hello.s4vector(), "hi".to_string(), document_id)
+                    .unwrap(),
+            );
+            operations.push(local.local_delete(hello.s4vector(), document_id).unwrap());
+
+            for operation in operations {
+                let from_local: bool = operation.sid == 1;
+                let target: &mut RGA = if from_local { &mut remote } else { &mut local };
+                target.apply_remote(operation).unwrap();
+            }
+            for rga in [&local, &remote] {
+                assert_eq!(materialized(rga), walked(rga));
+                assert_eq!(rga.content_size(), rga.text().len());
+            }
+            assert_eq!(local.text(), remote.text());
+            assert_eq!(local.text(), "> wörld;");
+
+            // rebuilt from the list when a node is linked somewhere other than after its left
+            let loaded = RGA::from_nodes(local.ordered_nodes(), 1, 1);
+            assert_eq!(materialized(&loaded), walked(&loaded));
+            assert_eq!(loaded.text(), local.text());
+        }
+
+        #[test]
+        fn test_concurrent_inserts_converge() {
+            let document_id = uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8");
+            let mut base = RGA::new(1, 1);
+            let a = base
+                .local_insert("a".to_string(), None, None, document_id)
+                .unwrap();
+            let b = base
+                .local_insert("b".to_string(), Some(a.s4vector()), None, document_id)
+                .unwrap();
+            let replica = |site_id: u64| {
+                let mut rga = RGA::new(1, site_id);
+                for operation in [a.clone(), b.clone()] {
+                    rga.apply_remote(operation).unwrap();
+                }
+                rga
+            };
+
+            // three replicas type into the same gap at once, one also at the start
+            let mut operations: Vec<BroadcastOperation> = Vec::new();
+            for (site_id, text) in [(1, "xy"), (2, "uv"), (3, "pq")] {
+                let mut rga = replica(site_id);
+                let mut left: S4Vector = a.s4vector();
+                for value in text.chars() {
+                    let operation = rga
+                        .local_insert(value.to_string(), Some(left), None, document_id)
+                        .unwrap();
+                    left = operation.s4vector();
+                    operations.push(operation);
+                }
+                if site_id == 3 {
+                    operations.push(
+                        rga.local_insert("^".to_string(), None, None, document_id)
+                            .unwrap(),
+                    );
+                }
+            }
+
+            // every order the operations can arrive in gives the same document
+            let mut texts: Vec<String> = Vec::new();
+            let mut order: Vec<usize> = (0..operations.len()).collect();
+            for round in 0..720 {
+                let mut rga = replica(4);
+                for i in &order {
+                    rga.apply_remote(operations[*i].clone()).unwrap();
+                }
+                assert_eq!(rga.buffered(), 0);
+                texts.push(rga.text().to_string());
+
+                let (i, j) = (round % order.len(), (round * 7 + 3) % order.len());
+                order.swap(i, j);
+                order.rotate_left(round % 3);
+            }
+            texts.dedup();
+            assert_eq!(texts.len(), 1);
+
+            // each replica's text is kept together, between "a" and "b"
+            let text: &str = &texts[0];
+            assert!(text.starts_with("^a") && text.ends_with('b'));
+            for run in ["xy", "uv", "pq"] {
+                assert!(text.contains(run), "{} split in {}", run, text);
+            }
+
+            // text typed between two nodes stays between them
+            let mut rga = replica(4);
+            let inserted = rga
+                .local_insert(
+                    "c".to_string(),
+                    Some(a.s4vector()),
+                    Some(b.s4vector()),
+                    document_id,
+                )
+                .unwrap();
+            rga.local_insert(
+                "d".to_string(),
+                Some(a.s4vector()),
+                Some(inserted.s4vector()),
+                document_id,
+            )
+            .unwrap();
+            assert_eq!(rga.text(), "adcb");
+        }
+
+        #[rocket::tokio::test]
+        async fn test_read_chunks() {
+            use rocket::futures::StreamExt;
+
+            let document_id = uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8");
+            let mut rga = RGA::new(1, 1);
+            let mut last: Option<S4Vector> = None;
+            for value in ["fn ", "mäin", "() {}"] {
+                last = Some(
+                    rga.local_insert(value.to_string(), last, None, document_id)
+                        .unwrap()
+                        .s4vector(),
+                );
+            }
+            let chunks = rga.read_chunks(5);
+
+            // editing the document while it is streamed does not change what is read
+            rga.local_update(last.unwrap(), "() { run() }".to_string(), document_id)
+                .unwrap();
+            let chunks: Vec<String> = chunks.collect().await;
+            // a chunk ending inside a character is extended to the end of it
+            assert_eq!(chunks, vec!["fn mä", "in() ", "{}"]);
+
+            assert_eq!(
+                rga.read_chunks(READ_CHUNK_BYTES)
+                    .collect::<Vec<String>>()
+                    .await,
+                vec!["fn mäin() { run() }"]
+            );
+            assert_eq!(RGA::new(1, 1).read_chunks(2).count().await, 0);
+        }
     }
 }
@@ -1,29 +1,65 @@
-use crate::rga::rga::RGA;
+//! This module defines the API routes for a collaborative coding backend system.
+//! It handles document operations (insert, update, delete), document management (fetch, load),
+//! and broadcasting changes via SNS (Amazon Simple Notification Service).
+//!
+//! # Features
+//! **Insert, Update, Delete**: CRUD operations for managing text collaboratively.
+//! **Fetch, Load**: Retrieve and initialize document snapshots.
+//! **SNS Integration**: Broadcasts changes to other replicas.
+
+use crate::backpressure::Backlog;
+use crate::changes::ChangeFeeds;
+use crate::chat::{ChatBroadcast, ChatRooms};
+use crate::compaction;
+use crate::db;
+use crate::divergence::Divergence;
+use crate::limits::JsonBody;
+use crate::outbox::{self, Outbox};
+use crate::pool::Pool;
+use crate::query_metrics;
+use crate::region::ReplicationMetrics;
+use crate::rga::rga::{Chunks, OperationError, READ_CHUNK_BYTES, RGA};
+use crate::share::Access;
+use crate::tenancy::Tenant;
+use crate::throttle::Throttle;
+use crate::usage::{self, Metric};
+use crate::wal::{Wal, WalRecord};
 use crate::{
-    db, ApiError, BroadcastOperation, CreateDocumentRequest, CreateDocumentResponse,
-    DocumentSnapshot, OperationRequest, S4Vector, SnsNotification,
+    audit, encryption, expiry, history, quota, snapshot, starred, tenancy, tokens, users, Actor, ApiError, AppliedOperation, AuditEntry, BroadcastOperation, ContentNode, DocumentContent, CreateDocumentRequest, CreateDocumentResponse,
+    BatchRequest, MemoryConfig, MemoryReport, MoveRequest, OperationRequest, OperationKind, Quotas, RequestId, S4Vector, Session, SnsNotification, ValidationConfig, validate_batch, validate_operation,
 };
-use aws_sdk_sns::Client as SnsClient;
-use log::{error, info};
+use rocket::futures::stream;
+use rocket::response::stream::TextStream;
 use rocket::serde::json::Json;
-use rocket::tokio::sync::Mutex;
-use rocket::{get, post};
+use rocket::tokio::sync::{Mutex, OwnedMutexGuard};
+use rocket::response::{self, Responder};
+use rocket::{get, post, Request};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio_postgres::Client;
+use tracing::{error, info, info_span, instrument, warn, Instrument};
 use uuid::Uuid;
 
-/// This module defines the API routes for a collaborative coding backend system.
-/// It handles document operations (insert, update, delete), document management (fetch, load),
-/// and broadcasting changes via SNS (Amazon Simple Notification Service).
-///
-/// # Features
-/// **Insert, Update, Delete**: CRUD operations for managing text collaboratively.
-/// **Fetch, Load**: Retrieve and initialize document snapshots.
-/// **SNS Integration**: Broadcasts changes to other replicas.
-
 /// Shared state type: Maps document IDs to their corresponding RGA instances.
-type SharedRGAs = Arc<Mutex<HashMap<Uuid, RGA>>>;
+pub type SharedRGAs = Arc<Mutex<HashMap<Uuid, RGA>>>;
+
+/// Serialises the edits of each document from preparing an operation until it is logged and
+/// applied, without holding the lock on every loaded document while the log is written.
+/// An entry only exists while a request holds or waits for it.
+#[derive(Clone, Default)]
+pub struct DocumentLocks(Arc<std::sync::Mutex<HashMap<Uuid, Arc<Mutex<()>>>>>);
+
+impl DocumentLocks {
+    /// Waits for the document's other edits, returning a guard released when it is dropped.
+    pub async fn lock(&self, document_id: Uuid) -> OwnedMutexGuard<()> {
+        let lock: Arc<Mutex<()>> = {
+            let mut locks = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            Arc::clone(locks.entry(document_id).or_default())
+        };
+        lock.lock_owned().await
+    }
+}
 
 /// Route to create a new document
 ///
@@ -32,49 +68,74 @@ type SharedRGAs = Arc<Mutex<HashMap<Uuid, RGA>>>;
 /// snapshot and logs the operation into the database, all wrapped in a transaction
 /// to ensure atomicity and consistency. The response will return the document ID
 /// of the newly created document and a success message.
+/// The document is owned by the logged in user making the request.
+/// An optional `ttl_secs` makes the document expire, after which it is archived.
+/// An optional `workspace_id` creates the document in a workspace of an organization the
+/// owner is a member of.
 /// Example Request
 /// {
-///     "owner_id": "550e8400-e29b-41d4-a716-446655440000",
-///     "title": "My New Document"
+///     "title": "My New Document",
+///     "ttl_secs": 3600,
+///     "workspace_id": "7c9e6679-7425-40de-944b-e07fc1f90ae7"
 /// }
 ///
 /// Example Respose
 /// {
 ///     "document_id" : "f47ac10b-58cc-4372-a567-0e02b2c3d479",
-///     "message" : "Document f47ac10b-58cc-4372-a567-0e02b2c3d479 created successfully"
+///     "message" : "Document f47ac10b-58cc-4372-a567-0e02b2c3d479 created successfully",
+///     "expires_at" : "2024-01-01T01:00:00Z"
 /// }
-#[post("/create_document", format = "json", data = "<request>")]
+#[post("/create_document", data = "<request>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, owner_id = ?actor.user_id))]
 pub async fn create_document(
-    request: Json<CreateDocumentRequest>,
+    request: JsonBody<CreateDocumentRequest>,
+    actor: Actor,
     replica_id: &rocket::State<Arc<Mutex<i64>>>,
     db: &rocket::State<Arc<Mutex<Client>>>,
+    quotas: &rocket::State<Quotas>,
+    request_id: RequestId,
 ) -> Result<Json<CreateDocumentResponse>, ApiError> {
-    let mut client = db.lock().await;
+    let owner_id: Uuid = actor.require_user()?;
+    let mut client = tenancy::lock(db, &Tenant::Any).await?;
     let replica_id: i64 = *replica_id.lock().await;
 
+    users::check_owner(&client, &owner_id).await?;
+    quota::check_document_quota(&client, &owner_id, quotas).await?;
+    let tenant: Tenant = match &request.workspace_id {
+        Some(workspace_id) => Tenant::Org(
+            tenancy::check_workspace(&client, workspace_id, &owner_id, quotas).await?,
+        ),
+        None => Tenant::Personal,
+    };
+    tenancy::scope(&client, &tenant).await?;
+
     let title = if request.title.to_string().is_empty() {
         String::from("New document")
     } else {
         request.title.to_string()
     };
 
-    let create_date = chrono::Utc::now().to_rfc3339();
+    let now = chrono::Utc::now();
+    let create_date = now.to_rfc3339();
+    let expires_at: Option<String> = expiry::expires_at(now, request.ttl_secs)?;
+    let language: Option<String> = tokens::parse_language(request.language.as_deref())?;
     let initial_content = String::new();
-    let document_query = match client.prepare("INSERT INTO document (owner_id,creation_date,title) VALUES ($1,$2,$3) RETURNING document_id").await{
+    let document_query = match client.prepare("INSERT INTO document (owner_id,creation_date,title,expires_at,persist_chat,language,workspace_id) VALUES ($1,$2,$3,$4,$5,$6,$7) RETURNING document_id").await{
         Ok(dq) => dq,
         Err(_) => {
-            error!(target:"error_logger","Failed to create insert query for document table");
+            error!("Failed to create insert query for document table");
             return Err(ApiError::DatabaseError("Failed to create insert query for document table".to_string()));
         }
     };
 
     let document_id: Uuid = match client
-        .query_one(&document_query, &[&request.owner_id, &create_date, &title])
+        .query_one(&document_query, &[&owner_id, &create_date, &title, &expires_at, &request.persist_chat, &language, &request.workspace_id])
         .await
     {
         Ok(id) => id.get(0),
         Err(_) => {
-            error!(target:"error_logger","Failed to insert document into document table");
+            error!("Failed to insert document into document table");
             return Err(ApiError::DatabaseError(
                 "Failed to insert into the documents table: {}".to_string(),
             ));
@@ -84,23 +145,23 @@ pub async fn create_document(
     let snapshot_query = match client.prepare("INSERT INTO document_snapshots (document_id,ssn,sum,sid,seq,value,tombstone) VALUES ($1,$2,$3,$4,$5,$6,$7)").await{
         Ok(sq) => sq,
         Err(_) => {
-            error!(target:"error_logger","Failed to create INSERT query for document_snapshot table");
+            error!("Failed to create INSERT query for document_snapshot table");
             return Err(ApiError::DatabaseError("Failed to create INSERT query for document_snapshot table".to_string()));
         }
     };
 
-    let operation_query = match Client::prepare(&client,"INSERT INTO operations (document_id,ssn,sum,sid,seq,value,tombstone,timestamp) VALUES ($1,$2,$3,$4,$5,$6,$7,$8)").await {
+    let operation_query = match Client::prepare(&client,"INSERT INTO operations (document_id,ssn,sum,sid,seq,value,tombstone,timestamp,operation) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9)").await {
         Ok(oq) => oq,
         Err(_) => {
-            error!(target: "error_logger","Failed to create INSERT query for operations table");
+            error!("Failed to create INSERT query for operations table");
             return Err(ApiError::DatabaseError("Failed to create INSERT query for oeprations table".to_string()));
         }
     };
 
-    let tx = match client.transaction().await {
+    let tx = match client.transaction().instrument(info_span!("db.transaction")).await {
         Ok(tx) => tx,
         Err(_) => {
-            error!(target:"error_logger","Failed to start database transaction");
+            error!("Failed to start database transaction");
             return Err(ApiError::DatabaseError(
                 "Failed to start transaction: {}".to_string(),
             ));
@@ -113,10 +174,10 @@ pub async fn create_document(
             &snapshot_query,
             &[
                 &document_id,
-                &(0 as i32),
-                &(0 as i32),
+                &0_i64,
+                &0_i64,
                 &replica_id,
-                &(0 as i32),
+                &0_i64,
                 &initial_content,
                 &false,
             ],
@@ -124,16 +185,16 @@ pub async fn create_document(
         .await
     {
         Ok(_) => {
-            info!(target:"request_logger","Successfull insert into the document_snapshot table");
+            info!("Successfull insert into the document_snapshot table");
         }
         Err(_) => {
-            error!(target: "error_logger","Failed to insert into document_snapshot table");
-            match tx.rollback().await {
+            error!("Failed to insert into document_snapshot table");
+            match tx.rollback().instrument(info_span!("db.rollback")).await {
                 Ok(_) => {
-                    info!(target:"request_logger","Successfully rolledback changes made to the database");
+                    info!("Successfully rolledback changes made to the database");
                 }
                 Err(_) => {
-                    error!(target:"error_logger","Failed to rollback database changes");
+                    error!("Failed to rollback database changes");
                 }
             }
             return Err(ApiError::DatabaseError(
@@ -149,28 +210,29 @@ pub async fn create_document(
             &operation_query,
             &[
                 &document_id,
-                &(0 as i32),
-                &(0 as i32),
+                &0_i64,
+                &0_i64,
                 &replica_id,
-                &(0 as i32),
+                &0_i64,
                 &Some(initial_content.clone()),
                 &false,
                 &timestamp,
+                &"Insert",
             ],
         )
         .await
     {
         Ok(_) => {
-            info!(target:"request_logger","Successfully inserted row into operations table");
+            info!("Successfully inserted row into operations table");
         }
         Err(_) => {
-            error!(target:"error_logger","Failed to insert into operation table");
-            match tx.rollback().await {
+            error!("Failed to insert into operation table");
+            match tx.rollback().instrument(info_span!("db.rollback")).await {
                 Ok(_) => {
-                    info!(target:"request_logger","Successfully rolledback changes made to the database");
+                    info!("Successfully rolledback changes made to the database");
                 }
                 Err(_) => {
-                    error!(target:"error_logger","Failed to rollback database changes");
+                    error!("Failed to rollback database changes");
                 }
             }
             return Err(ApiError::DatabaseError(
@@ -178,116 +240,77 @@ pub async fn create_document(
             ));
         }
     }
-    match tx.commit().await {
+    match tx.commit().instrument(info_span!("db.commit")).await {
         Ok(_) => {
-            info!(target:"requet_logger","Successfully commited database trasaction.");
+            info!("Successfully commited database trasaction.");
         }
         Err(_) => {
-            error!(target:"error_logger","Failed to commit database transaction");
-            ApiError::DatabaseError("Failed to commit transaction".to_string());
+            error!("Failed to commit database transaction");
+            return Err(ApiError::DatabaseError(
+                "Failed to commit transaction".to_string(),
+            ));
         }
     };
 
     Ok(Json(CreateDocumentResponse {
         document_id,
         message: format!("Document {} created successuflly", document_id),
+        expires_at,
     }))
 }
 
 /// Fetch a document from the AWS RDB and initialize a RGA.
 /// `id` is the document UUID.
 #[get("/document/<id>")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
 pub async fn fetch_document(
     id: String,
     rgas: &rocket::State<SharedRGAs>,
-    replica_id: &rocket::State<Arc<Mutex<i64>>>,
+    session: &rocket::State<Session>,
     db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    request_id: RequestId,
+    actor: Actor,
 ) -> Result<(), ApiError> {
     let document_id: Uuid = match Uuid::parse_str(&id) {
         Ok(id) => id,
         Err(_) => {
-            return Err(ApiError::RequestFailed(
-                "Failed to parse document id".to_string(),
-            ));
-        }
-    };
-
-    let mut rgas = rgas.lock().await;
-    let client = db.lock().await;
-
-    if rgas.contains_key(&document_id) {
-        return Ok(());
-    }
-
-    let query = match client
-        .prepare(
-            "SELECT * from document_snapshots WHERE document_id=$1 ORDER BY ssn, sum, sid,seq;",
-        )
-        .await
-    {
-        Ok(q) => q,
-        Err(_) => {
-            error!(target:"error_logger","Failed to prepare select query for document_snapshot table");
-            return Err(ApiError::DatabaseError(
-                "Failed to prepare select statement for document_snapshot table.".to_string(),
-            ));
+            return Err(ApiError::InvalidOperation("Failed to parse document id".to_string()));
         }
     };
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
 
-    let rows = match client.query(&query, &[&document_id]).await {
-        Ok(r) => {
-            info!(target:"request_logger","Successfull seelect statement for the document_snapshot table");
-            r
-        }
-        Err(_) => {
-            error!(target:"error_logger","Failed to execute select statement for the document_snapshot table");
-            return Err(ApiError::DatabaseError(
-                "Failed to find document in database".to_string(),
-            ));
+    let loaded: bool = match rgas.lock().await.get_mut(&document_id) {
+        Some(rga) => {
+            rga.touch();
+            true
         }
+        None => false,
     };
-
-    let snapshots: Vec<DocumentSnapshot> = rows
-        .iter()
-        .map(|row| DocumentSnapshot {
-            document_id: row.get(0),
-            ssn: row.get(1),
-            sum: row.get(2),
-            sid: row.get(3),
-            seq: row.get(4),
-            value: row.get(5),
-            tombstone: row.get(6),
-        })
-        .collect();
-
-    let mut rga = RGA::new(*(replica_id.lock().await) as u64, 1);
-
-    for operation in snapshots {
-        let s4 = S4Vector {
-            ssn: operation.ssn as u64,
-            sum: operation.sum as u64,
-            sid: operation.sid as u64,
-            seq: operation.seq as u64,
+    if !loaded {
+        // loaded without holding the documents lock, so other documents stay usable meanwhile
+        let rga = {
+            let client = tenancy::lock(db, &tenant).await?;
+            expiry::check_not_archived(&client, &document_id).await?;
+            snapshot::load_rga(&client, &document_id, session).await?
         };
 
-        rga.remote_insert(operation.value, s4, None, None).await;
+        // another request may have loaded the document first, its RGA may already have changes
+        rgas.lock().await.entry(document_id).or_insert(rga).touch();
     }
 
-    rgas.insert(document_id, rga);
-
+    if let Some(user_id) = actor.user_id {
+        let client = tenancy::lock(db, &tenant).await?;
+        if starred::touch(&*client, &user_id, &document_id).await.is_err() {
+            // the recent documents list is a convenience, it doesn't fail the fetch
+            warn!("Failed to record the document access");
+        }
+    }
     Ok(())
 }
 
-/// Insert a value into the RGA of a specific document.
-/* pub struct OperationRequest {
-    value: Option<String>,
-    s4vector: Option<S4Vector>,
-    tombstone: bool,
-    left: Option<S4Vector>,
-    right: Option<S4Vector>,
-}*/
-
 /// Inserts a new value into the correcponding document's RGA.
+/// Returns the applied operation, including the s4vector of the new node.
 ///
 /// Example Request:
 /// {
@@ -308,498 +331,1359 @@ pub async fn fetch_document(
 ///     "right" : null
 /// }
 ///
-#[post("/document/<id>/insert", format = "json", data = "<request>")]
+#[post("/document/<id>/insert", data = "<request>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
 pub async fn insert(
     id: String,
-    request: Json<OperationRequest>,
+    request: JsonBody<OperationRequest>,
     rgas: &rocket::State<SharedRGAs>,
-    db: &rocket::State<Arc<Mutex<Client>>>,
-    sns_client: &rocket::State<Arc<Mutex<SnsClient>>>,
-    topic: &rocket::State<Arc<Mutex<String>>>,
-) -> Result<(), ApiError> {
+    locks: &rocket::State<DocumentLocks>,
+    backlog: &rocket::State<Backlog>,
+    throttle: &rocket::State<Throttle>,
+    pool: &rocket::State<Pool>,
+    outbox: &rocket::State<Outbox>,
+    wal: &rocket::State<Wal>,
+    quotas: &rocket::State<Quotas>,
+    validation: &rocket::State<ValidationConfig>,
+    feeds: &rocket::State<ChangeFeeds>,
+    request_id: RequestId,
+    actor: Actor,
+) -> Result<Json<AppliedOperation>, ApiError> {
     let document_id: Uuid = match Uuid::parse_str(&id) {
         Ok(id) => id,
         Err(_) => {
-            error!(target:"error_logger","Failed to parse document id");
-            return Err(ApiError::RequestFailed(
-                "Failed to parse document id".to_string(),
-            ));
+            error!("Failed to parse document id");
+            return Err(ApiError::InvalidOperation("Failed to parse document id".to_string()));
         }
     };
 
-    let mut rgas = rgas.lock().await;
-    let mut client = db.lock().await;
-
-    // Check if the document has been loaded
-    let rga: &mut RGA = match rgas.get_mut(&document_id) {
-        Some(r) => r,
-        None => {
-            error!(target:"error_logger","Document not found");
-            return Err(ApiError::RequestFailed(String::from("Document not found")));
-        }
-    };
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::ReadWrite).await?;
+    validate_operation(&request, OperationKind::Insert, validation)?;
 
-    let value: String = if request.value.is_some() {
-        request.value.clone().unwrap()
+    let value: String = if let Some(value) = &request.value {
+        value.clone()
     } else {
-        error!(target:"error_logger","Value not found.");
-        return Err(ApiError::RequestFailed("Value not found".to_string()));
+        error!("Value not found.");
+        return Err(ApiError::InvalidOperation("Value not found".to_string()));
     };
 
-    let mut op: BroadcastOperation = match rga
-        .local_insert(value.clone(), request.left, request.right, document_id)
-        .await
-    {
-        Ok(obj) => obj,
-        Err(_) => {
-            error!(target:"error_logger","Failed to insert into file");
-            return Err(ApiError::RequestFailed(
-                "Error inserting into file".to_string(),
-            ));
+    quota::record_operation(&*pool.get(&tenant).await?, &document_id, quotas).await?;
+
+    // Held until the operation is applied, so no other edit of the document comes in between
+    let edit: OwnedMutexGuard<()> = locks.lock(document_id).await;
+
+    // Only the CRDT work is done under the RGA lock, persisting and broadcasting happen after
+    let mut op: BroadcastOperation = {
+        let mut rgas = rgas.lock().await;
+        backlog.admit(&rgas)?;
+
+        // Check if the document has been loaded
+        let rga: &mut RGA = match rgas.get_mut(&document_id) {
+            Some(r) => r,
+            None => {
+                error!("Document not found");
+                return Err(ApiError::NotFound(String::from("Document not found")));
+            }
+        };
+
+        throttle.admit(&mut rga.throttle, 1)?;
+        quotas.check_document_size(rga.content_size(), value.len(), 0)?;
+        // prepared but not applied, the document only changes once the operation is logged
+        match rga.prepare_insert(value.clone(), request.left, request.right, document_id) {
+            Ok(obj) => obj,
+            Err(OperationError::OutOfRange(e)) => {
+                error!("Failed to insert into file: {}", e);
+                return Err(e.into());
+            }
+            Err(_) => {
+                error!("Failed to insert into file");
+                return Err(ApiError::Conflict(
+                    "Insert depends on operations that have not been applied".to_string(),
+                ));
+            }
         }
     };
 
     op.document_id = document_id;
+    op.request_id = Some(request_id.0.clone());
+    op.author = actor.user_id;
 
     let s4 = op.s4vector();
+    let [ssn, sum, sid, seq] = s4.to_i64()?;
+    let (left, right) = (history::origin_column(op.left)?, history::origin_column(op.right)?);
 
-    let operation_query = match client.prepare("INSERT INTO operations (document_id,ssn,sum,sid,seq,value,tombstone,timestamp) VALUES ($1,$2,$3,$4,$5,$6,$7,$8)").await {
-        Ok(q) => q,
-        Err(_) => {
-            error!(target:"error_logger","Failed to create insert query for operations table");
-            return Err(ApiError::DatabaseError(
-                "Failed to create insert query for operation table".to_string(),
-            )); 
-        }
-    };
+    let sealed: String = encryption::seal(&value);
+    let current_time = chrono::Utc::now().to_rfc3339().to_string();
+    // Logged before the database, so the operation is kept if the database fails
+    let lsn: Option<u64> = wal.append(&WalRecord {
+        operation: op.clone(),
+        value: sealed.clone(),
+        tombstone: false,
+        authored_at: Some(current_time.clone()),
+        client_ip: actor.client_ip.clone(),
+        timestamp: current_time.clone(),
+    }).await?;
+
+    apply_logged(rgas, &document_id, std::slice::from_ref(&op)).await;
+    drop(edit);
+
+    let persisted: Result<(), ApiError> = async {
+        let mut client = pool.get(&tenant).await?;
+
+        let operation_query = match client.prepare("INSERT INTO operations (document_id,ssn,sum,sid,seq,value,tombstone,timestamp,operation,left_origin,right_origin) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11)").await {
+            Ok(q) => q,
+            Err(_) => {
+                error!("Failed to create insert query for operations table");
+                return Err(ApiError::DatabaseError(
+                    "Failed to create insert query for operation table".to_string(),
+                )); 
+            }
+        };
 
-    let snapshot_query = match client.prepare("INSERT INTO document_snapshots (document_id,ssn,sum,sid,seq,value,tombstone) VALUES ($1,$2,$3,$4,$5,$6,$7)").await {
-        Ok(q) => q,
-        Err(_) => {
-            error!(target:"error_logger","Failed to create insert query for document_snapshot table");
-            return Err(ApiError::DatabaseError(
-                "Failed to create insert query for document_snapshot table".to_string(),
-            )); 
-        }
-    };
+        let snapshot_query = match client.prepare("INSERT INTO document_snapshots (document_id,ssn,sum,sid,seq,value,tombstone,author,authored_at) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9)").await {
+            Ok(q) => q,
+            Err(_) => {
+                error!("Failed to create insert query for document_snapshot table");
+                return Err(ApiError::DatabaseError(
+                    "Failed to create insert query for document_snapshot table".to_string(),
+                )); 
+            }
+        };
 
-    let current_time = chrono::Utc::now().to_rfc3339().to_string();
+        let tx = match client.transaction().instrument(info_span!("db.transaction")).await {
+            Ok(tx) => tx,
+            Err(_) => {
+                error!("Failed to create database transaction");
+                return Err(ApiError::DatabaseError(
+                    "Failed to create database transaction".to_string(),
+                ));
+            }
+        };
 
-    let tx = match client.transaction().await {
-        Ok(tx) => tx,
-        Err(_) => {
-            error!(target:"error_logger","Failed to create database transaction");
-            return Err(ApiError::DatabaseError(
-                "Failed to create database transaction".to_string(),
-            ));
+        match tx.execute(
+            &operation_query,
+            &[
+                &document_id,
+                &ssn,
+                &sum,
+                &sid,
+                &seq,
+                &sealed,
+                &false,
+                &current_time,
+                &op.operation,
+                &left,
+                &right,
+            ],
+        )
+        .await
+        {
+            Ok(_) => (),
+            Err(_) => {
+                return Err(ApiError::DatabaseError(
+                    "Failed to insert into operations table".to_string()
+                ))
+            }
         }
-    };
 
-    match tx.execute(
-        &operation_query,
-        &[
-            &document_id,
-            &(s4.ssn as i64),
-            &(s4.sum as i64),
-            &(s4.sid as i64),
-            &(s4.seq as i64),
-            &value,
-            &false,
-            &current_time,
-        ],
-    )
-    .await
-    {
-        Ok(_) => (),
-        Err(_) => {
-            return Err(ApiError::DatabaseError(
-                "Failed to insert into operations table".to_string()
-            ))
+        match tx.execute(
+            &snapshot_query,
+            &[
+                &document_id,
+                &ssn,
+                &sum,
+                &sid,
+                &seq,
+                &sealed,
+                &false,
+                &op.author,
+                &current_time,
+            ],
+        )
+        .await
+        {
+            Ok(_) => (),
+            Err(_) => {
+                return Err(ApiError::DatabaseError(
+                    "Failed to insert into document_snapshot table".to_string()
+                ))
+            }
         }
-    }
 
-    match tx.execute(
-        &snapshot_query,
-        &[
-            &document_id,
-            &(s4.ssn as i64),
-            &(s4.sum as i64),
-            &(s4.sid as i64),
-            &(s4.seq as i64),
-            &value,
-            &false,
-        ],
-    )
-    .await
-    {
-        Ok(_) => (),
-        Err(_) => {
-            return Err(ApiError::DatabaseError(
-                "Failed to insert into document_snapshot table".to_string()
-            ))
+        audit::record(&tx, &actor, &op, &current_time).await?;
+        // Broadcast to the other replicas once committed
+        outbox::enqueue(&tx, &op).await?;
+
+        match tx.commit().instrument(info_span!("db.commit")).await {
+            Ok(_) => (),
+            Err(_) => {
+                error!("Failed to commit database transaction");
+                return Err(ApiError::DatabaseError(
+                    "Failed to commit database transaction".to_string()
+                ))
+            }
         }
+        Ok(())
     }
+    .await;
+    wal.settle(lsn, persisted).await?;
+    outbox.wake();
 
-    //Broadcast to SNS
-    match db::send_operation(Arc::clone(sns_client), &topic.lock().await, &op).await {
-        Ok(_) => (),
-        Err(_) => {
-            error!(target:"error_logger","Failed to send SNS notification");
-            return Err(ApiError::DatabaseError(format!(
-                "Failed to send SNS notification"
-            )))
-        }
-    };
-
-    // After broadcast SNS to ensure it is sent
-    match tx.commit().await {
-        Ok(_) => (),
-        Err(_) => {
-            error!(target:"error_logger","Failed to commit database transaction");
-            return Err(ApiError::DatabaseError(
-                "Failed to commit database transaction".to_string()
-            ))
-        }
+    if let Some(rga) = rgas.lock().await.get_mut(&document_id) {
+        rga.set_author(s4, op.author, Some(current_time.clone()));
     }
+    let applied = AppliedOperation::new(op, current_time);
+    feeds.publish(document_id, applied.clone());
+    usage::record(&tenant, document_id, Metric::Operations, 1.0);
+    usage::record(&tenant, document_id, Metric::BroadcastMessages, 1.0);
+    Ok(Json(applied))
+}
 
-    Ok(())
+/// Applies logged operations to the loaded document. The operations are persisted either
+/// way, so a document that can no longer take them is unloaded, and has them once it is
+/// loaded again.
+pub async fn apply_logged(rgas: &Mutex<HashMap<Uuid, RGA>>, document_id: &Uuid, operations: &[BroadcastOperation]) {
+    let mut rgas = rgas.lock().await;
+    let applied = rgas.get_mut(document_id).map(|rga| operations.iter().try_for_each(|op| rga.apply_prepared(op.clone())));
+    if let Some(Err(e)) = applied {
+        error!("Unloading a document that can't apply a logged operation: {}", e);
+        rgas.remove(document_id);
+    }
 }
 
-#[post("/document/<id>/update", format = "json", data = "<request>")]
+/// Updates the value of a node in the corresponding document's RGA.
+/// Returns the applied operation.
+#[post("/document/<id>/update", data = "<request>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
 pub async fn update(
     id: String,
-    request: Json<OperationRequest>,
+    request: JsonBody<OperationRequest>,
     rgas: &rocket::State<SharedRGAs>,
-    db: &rocket::State<Arc<Mutex<Client>>>,
-    sns_client: &rocket::State<Arc<Mutex<SnsClient>>>,
-    topic: &rocket::State<Arc<Mutex<String>>>,
-) -> Result<(), ApiError> {
+    locks: &rocket::State<DocumentLocks>,
+    backlog: &rocket::State<Backlog>,
+    throttle: &rocket::State<Throttle>,
+    pool: &rocket::State<Pool>,
+    outbox: &rocket::State<Outbox>,
+    wal: &rocket::State<Wal>,
+    quotas: &rocket::State<Quotas>,
+    validation: &rocket::State<ValidationConfig>,
+    feeds: &rocket::State<ChangeFeeds>,
+    request_id: RequestId,
+    actor: Actor,
+) -> Result<Json<AppliedOperation>, ApiError> {
     let document_id: Uuid = match Uuid::parse_str(&id) {
         Ok(id) => id,
         Err(_) => {
-            error!(target:"error_logger","Failed to parse document id");
-            return Err(ApiError::RequestFailed("Failed to parse document id".to_string()));
+            error!("Failed to parse document id");
+            return Err(ApiError::InvalidOperation("Failed to parse document id".to_string()));
         }
 };
 
-    let mut rgas = rgas.lock().await;
-    let mut client = db.lock().await;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::ReadWrite).await?;
+    validate_operation(&request, OperationKind::Update, validation)?;
 
-    // Check if the document has been loaded
-    let rga: &mut RGA = match rgas.get_mut(&document_id) {
-        Some(r) => r,
+    let value: String = if let Some(value) = &request.value {
+        value.clone()
+    } else {
+        error!("Value not found");
+        return Err(ApiError::InvalidOperation("Value not found".to_string()));
+    };
+
+    let s4vector: S4Vector = match request.s4vector {
+        Some(s4) => s4,
         None => {
-            error!(target:"error_logger","Document not found");
-            return Err(ApiError::RequestFailed("Document not found".to_string()));
+            error!("S4Vector not found");
+            return Err(ApiError::InvalidOperation("S4Vector not found".to_string()));
         }
     };
 
-    let value: String = if request.value.is_some() {
-        request.value.clone().unwrap()
-    } else {
-        error!(target:"error_logger","Value not found");
-        return Err(ApiError::RequestFailed("Value not found".to_string()));
-    };
+    quota::record_operation(&*pool.get(&tenant).await?, &document_id, quotas).await?;
 
-    let mut op: BroadcastOperation = match rga
-        .local_update(request.s4vector.unwrap(), value.clone(), document_id)
-        .await
-    {
-        Ok(obj) => obj,
-        Err(_) => {
-            error!(target:"error_logger","Failed to update file");
-            return Err(ApiError::RequestFailed("Error updating file".to_string()));
+    // Held until the operation is applied, so no other edit of the document comes in between
+    let edit: OwnedMutexGuard<()> = locks.lock(document_id).await;
+
+    // Only the CRDT work is done under the RGA lock, persisting and broadcasting happen after
+    let mut op: BroadcastOperation = {
+        let mut rgas = rgas.lock().await;
+        backlog.admit(&rgas)?;
+
+        // Check if the document has been loaded
+        let rga: &mut RGA = match rgas.get_mut(&document_id) {
+            Some(r) => r,
+            None => {
+                error!("Document not found");
+                return Err(ApiError::NotFound("Document not found".to_string()));
+            }
+        };
+
+        throttle.admit(&mut rga.throttle, 1)?;
+        // the updated value replaces the node's current value
+        let existing: usize = rga.node(&s4vector).map_or(0, |node| node.value.len());
+        quotas.check_document_size(rga.content_size(), value.len(), existing)?;
+        // prepared but not applied, the document only changes once the operation is logged
+        match rga.prepare_update(s4vector, value.clone(), document_id) {
+            Ok(obj) => obj,
+            Err(OperationError::OutOfRange(e)) => {
+                error!("Failed to update file: {}", e);
+                return Err(e.into());
+            }
+            Err(_) => {
+                error!("Failed to update file");
+                return Err(ApiError::Conflict("Operation depends on a node that has not been applied".to_string()));
+            }
         }
     };
 
     op.document_id = document_id;
+    op.request_id = Some(request_id.0.clone());
+    op.author = actor.user_id;
 
     let s4 = op.s4vector();
+    let [ssn, sum, sid, seq] = s4.to_i64()?;
+    let (left, right) = (history::origin_column(op.left)?, history::origin_column(op.right)?);
 
-    let operation_query = match client.prepare("INSERT INTO operations (document_id,ssn,sum,sid,seq,value,tombstone,timestamp) VALUES ($1,$2,$3,$4,$5,$6,$7,$8)").await {
-        Ok(q) => q,
-        Err(_) => {
-            error!(target:"error_logger","Failed to create insert statement for operations table");
-            return Err(ApiError::RequestFailed("Failed to create insert statement for operations table".to_string()));
-        }
-    };
-    let snapshot_query = match client.prepare("INSERT INTO document_snapshots (document_id,ssn,sum,sid,seq,value,tombstone) VALUES ($1,$2,$3,$4,$5,$6,$7) ON CONFLICT (document_id,ssn,sum,sid,seq) DO UPDATE set value = EXCLUDED.value, tombstone = EXCLUDED.tombstone").await {
-        Ok(q) => q,
-        Err(_) => {
-            error!(target:"error_logger","Failed to create insert statement for document_snapshot table");
-            return Err(ApiError::RequestFailed("Failed to create insert statement for document_snapshot table".to_string()));
-        }
-    };
-
+    let sealed: String = encryption::seal(&value);
     let current_time = chrono::Utc::now().to_rfc3339().to_string();
+    // Logged before the database, so the operation is kept if the database fails
+    let lsn: Option<u64> = wal.append(&WalRecord {
+        operation: op.clone(),
+        value: sealed.clone(),
+        tombstone: false,
+        authored_at: Some(current_time.clone()),
+        client_ip: actor.client_ip.clone(),
+        timestamp: current_time.clone(),
+    }).await?;
+
+    apply_logged(rgas, &document_id, std::slice::from_ref(&op)).await;
+    drop(edit);
+
+    let persisted: Result<(), ApiError> = async {
+        let mut client = pool.get(&tenant).await?;
+
+        let operation_query = match client.prepare("INSERT INTO operations (document_id,ssn,sum,sid,seq,value,tombstone,timestamp,operation,left_origin,right_origin) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11)").await {
+            Ok(q) => q,
+            Err(_) => {
+                error!("Failed to create insert statement for operations table");
+                return Err(ApiError::DatabaseError("Failed to create insert statement for operations table".to_string()));
+            }
+        };
+        let snapshot_query = match client.prepare("INSERT INTO document_snapshots (document_id,ssn,sum,sid,seq,value,tombstone,author,authored_at) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9) ON CONFLICT (document_id,ssn,sum,sid,seq) DO UPDATE set value = EXCLUDED.value, tombstone = EXCLUDED.tombstone, author = EXCLUDED.author, authored_at = EXCLUDED.authored_at").await {
+            Ok(q) => q,
+            Err(_) => {
+                error!("Failed to create insert statement for document_snapshot table");
+                return Err(ApiError::DatabaseError("Failed to create insert statement for document_snapshot table".to_string()));
+            }
+        };
 
-    let tx = match client.transaction().await {
-        Ok(q) => q,
-        Err(_) => {
-            error!(target:"error_logger","Failed to create database transaction");
-            return Err(ApiError::RequestFailed("Failed to create database transaction".to_string()));
-        }
-    };
+        let tx = match client.transaction().instrument(info_span!("db.transaction")).await {
+            Ok(q) => q,
+            Err(_) => {
+                error!("Failed to create database transaction");
+                return Err(ApiError::DatabaseError("Failed to create database transaction".to_string()));
+            }
+        };
 
-    match tx.execute(
-        &operation_query,
-        &[
-            &document_id,
-            &(s4.ssn as i64),
-            &(s4.sum as i64),
-            &(s4.sid as i64),
-            &(s4.seq as i64),
-            &value,
-            &false,
-            &current_time,
-        ],
-    )
-    .await
-    {
-        Ok(q) => q,
-        Err(_) => {
-            error!(target:"error_logger","Failed to run insert query for operations table");
-            return Err(ApiError::RequestFailed("Failed to run insert query for operations table".to_string()));
-        }
-    };
+        match tx.execute(
+            &operation_query,
+            &[
+                &document_id,
+                &ssn,
+                &sum,
+                &sid,
+                &seq,
+                &sealed,
+                &false,
+                &current_time,
+                &op.operation,
+                &left,
+                &right,
+            ],
+        )
+        .await
+        {
+            Ok(q) => q,
+            Err(_) => {
+                error!("Failed to run insert query for operations table");
+                return Err(ApiError::DatabaseError("Failed to run insert query for operations table".to_string()));
+            }
+        };
 
-    match tx.execute(
-        &snapshot_query,
-        &[
-            &document_id,
-            &(s4.ssn as i64),
-            &(s4.sum as i64),
-            &(s4.sid as i64),
-            &(s4.seq as i64),
-            &value,
-            &false,
-        ],
-    )
-    .await
-    {
-        Ok(q) => q,
-        Err(_) => {
-            error!(target:"error_logger","Failed to run insert query for document_snapshot table");
-            return Err(ApiError::RequestFailed("Failed to run insert query for document_snapshot table".to_string()));
-        }
-    };
+        match tx.execute(
+            &snapshot_query,
+            &[
+                &document_id,
+                &ssn,
+                &sum,
+                &sid,
+                &seq,
+                &sealed,
+                &false,
+                &op.author,
+                &current_time,
+            ],
+        )
+        .await
+        {
+            Ok(q) => q,
+            Err(_) => {
+                error!("Failed to run insert query for document_snapshot table");
+                return Err(ApiError::DatabaseError("Failed to run insert query for document_snapshot table".to_string()));
+            }
+        };
 
-    match tx.commit().await {
-        Ok(q) => q,
-        Err(_) => {
-            error!(target:"error_logger","Failed to commit database transaction");
-            return Err(ApiError::RequestFailed("Failed to commit database transaction".to_string()));
-        }
-    };
+        audit::record(&tx, &actor, &op, &current_time).await?;
+        // Broadcast to the other replicas once committed
+        outbox::enqueue(&tx, &op).await?;
 
-    //Broadcast to SNS
-    match db::send_operation(Arc::clone(sns_client), &topic.lock().await, &op).await {
-        Ok(_) => (),
-        Err(_) => {
-            error!(target:"error_logger","Failed to send SNS notification");
-            return Err(ApiError::DatabaseError(
-                "Failed to send SNS notification".to_string()
-            ));
-        }
-    };
+        match tx.commit().instrument(info_span!("db.commit")).await {
+            Ok(q) => q,
+            Err(_) => {
+                error!("Failed to commit database transaction");
+                return Err(ApiError::DatabaseError("Failed to commit database transaction".to_string()));
+            }
+        };
+        Ok(())
+    }
+    .await;
+    wal.settle(lsn, persisted).await?;
+    outbox.wake();
 
-    Ok(())
+    if let Some(rga) = rgas.lock().await.get_mut(&document_id) {
+        rga.set_author(s4, op.author, Some(current_time.clone()));
+    }
+    let applied = AppliedOperation::new(op, current_time);
+    feeds.publish(document_id, applied.clone());
+    usage::record(&tenant, document_id, Metric::Operations, 1.0);
+    usage::record(&tenant, document_id, Metric::BroadcastMessages, 1.0);
+    Ok(Json(applied))
 }
 
-#[post("/document/<id>/delete", format = "json", data = "<request>")]
+/// Marks a node in the corresponding document's RGA as deleted.
+/// Returns the applied operation.
+#[post("/document/<id>/delete", data = "<request>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
 pub async fn delete(
     id: String,
-    request: Json<OperationRequest>,
+    request: JsonBody<OperationRequest>,
     rgas: &rocket::State<SharedRGAs>,
-    db: &rocket::State<Arc<Mutex<Client>>>,
-    sns_client: &rocket::State<Arc<Mutex<SnsClient>>>,
-    topic: &rocket::State<Arc<Mutex<String>>>,
-) -> Result<(), ApiError> {
+    locks: &rocket::State<DocumentLocks>,
+    backlog: &rocket::State<Backlog>,
+    throttle: &rocket::State<Throttle>,
+    pool: &rocket::State<Pool>,
+    outbox: &rocket::State<Outbox>,
+    wal: &rocket::State<Wal>,
+    quotas: &rocket::State<Quotas>,
+    validation: &rocket::State<ValidationConfig>,
+    feeds: &rocket::State<ChangeFeeds>,
+    request_id: RequestId,
+    actor: Actor,
+) -> Result<Json<AppliedOperation>, ApiError> {
     let document_id: Uuid = match Uuid::parse_str(&id) {
         Ok(id) => id,
         Err(_) => {
-            error!(target:"error_logger","Failed to parse document id");
-            return Err(ApiError::RequestFailed("Failed to parse document id".to_string()));
+            error!("Failed to parse document id");
+            return Err(ApiError::InvalidOperation("Failed to parse document id".to_string()));
         }
 };
 
-    let mut rgas = rgas.lock().await;
-    let mut client = db.lock().await;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::ReadWrite).await?;
+    validate_operation(&request, OperationKind::Delete, validation)?;
 
-    // Check if the document has been loaded
-    let rga: &mut RGA = match rgas.get_mut(&document_id) {
-        Some(r) => r,
-        None => 
-        {
-            error!(target:"error_logger","Document could not be found.");
-            return Err(ApiError::RequestFailed(String::from("Document not found")));
+    let s4vector: S4Vector = match request.s4vector {
+        Some(s4) => s4,
+        None => {
+            error!("S4Vector not found");
+            return Err(ApiError::InvalidOperation("S4Vector not found".to_string()));
         }
     };
 
-    let mut op: BroadcastOperation = match rga
-        .local_delete(request.s4vector.unwrap(), document_id)
-        .await
-    {
-        Ok(obj) => obj,
-        Err(_) => {
-            error!(target:"error_logger","Failed to update file");
-            return Err(ApiError::RequestFailed("Error updating file".to_string()));
+    quota::record_operation(&*pool.get(&tenant).await?, &document_id, quotas).await?;
+
+    // Held until the operation is applied, so no other edit of the document comes in between
+    let edit: OwnedMutexGuard<()> = locks.lock(document_id).await;
+
+    // Only the CRDT work is done under the RGA lock, persisting and broadcasting happen after
+    let mut op: BroadcastOperation = {
+        let mut rgas = rgas.lock().await;
+        backlog.admit(&rgas)?;
+
+        // Check if the document has been loaded
+        let rga: &mut RGA = match rgas.get_mut(&document_id) {
+            Some(r) => r,
+            None => 
+            {
+                error!("Document could not be found.");
+                return Err(ApiError::NotFound(String::from("Document not found")));
+            }
+        };
+
+        throttle.admit(&mut rga.throttle, 1)?;
+        // prepared but not applied, the document only changes once the operation is logged
+        match rga.prepare_delete(s4vector, document_id) {
+            Ok(obj) => obj,
+            Err(OperationError::OutOfRange(e)) => {
+                error!("Failed to update file: {}", e);
+                return Err(e.into());
+            }
+            Err(_) => {
+                error!("Failed to update file");
+                return Err(ApiError::Conflict("Operation depends on a node that has not been applied".to_string()));
+            }
         }
     };
 
     op.document_id = document_id;
+    op.request_id = Some(request_id.0.clone());
+    op.author = actor.user_id;
 
     let s4 = op.s4vector();
+    let [ssn, sum, sid, seq] = s4.to_i64()?;
+    let (left, right) = (history::origin_column(op.left)?, history::origin_column(op.right)?);
+    let current_time = chrono::Utc::now().to_rfc3339().to_string();
+    // Logged before the database, so the operation is kept if the database fails
+    let lsn: Option<u64> = wal.append(&WalRecord {
+        operation: op.clone(),
+        value: String::new(),
+        tombstone: true,
+        authored_at: None,
+        client_ip: actor.client_ip.clone(),
+        timestamp: current_time.clone(),
+    }).await?;
+
+    apply_logged(rgas, &document_id, std::slice::from_ref(&op)).await;
+    drop(edit);
+
+    let persisted: Result<(), ApiError> = async {
+        let mut client = pool.get(&tenant).await?;
+
+        let operation_query = match client.prepare("INSERT INTO operations (document_id,ssn,sum,sid,seq,value,tombstone,timestamp,operation,left_origin,right_origin) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11)").await {
+            Ok(q) => q,
+            Err(_) => {
+                error!("Failed to create insert query for operations table");
+                return Err(ApiError::DatabaseError("Failed to create insert query for operations table".to_string()));
+            }
+        };
+        let snapshot_query = match client.prepare("INSERT INTO document_snapshots (document_id,ssn,sum,sid,seq,value,tombstone) VALUES ($1,$2,$3,$4,$5,$6,$7) ON CONFLICT (document_id,ssn,sum,sid,seq) DO UPDATE set value = EXCLUDED.value, tombstone = EXCLUDED.tombstone").await {
+            Ok(q) => q,
+            Err(_) => {
+                error!("Failed to create insert query for operations table");
+                return Err(ApiError::DatabaseError("Failed to create insert query for operations table".to_string()));
+            }
+        };
 
-    let operation_query = match client.prepare("INSERT INTO operations (document_id,ssn,sum,sid,seq,value,tombstone,timestamp) VALUES ($1,$2,$3,$4,$5,$6,$7,$8)").await {
-        Ok(q) => q,
-        Err(_) => {
-            error!(target:"error_logger","Failed to create insert query for operations table");
-            return Err(ApiError::RequestFailed("Failed to create insert query for operations table".to_string()));
-        }
-    };
-    let snapshot_query = match client.prepare("INSERT INTO document_snapshots (document_id,ssn,sum,sid,seq,value,tombstone) VALUES ($1,$2,$3,$4,$5,$6,$7) ON CONFLICT (document_id,ssn,sum,sid,seq) DO UPDATE set value = EXCLUDED.value, tombstone = EXCLUDED.tombstone").await {
-        Ok(q) => q,
+        let tx = match client.transaction().instrument(info_span!("db.transaction")).await {
+            Ok(tx) => tx,
+            Err(_) => {
+                error!("Failed to create database transaction");
+                return Err(ApiError::DatabaseError("Failed to create database transaction".to_string()));
+            }
+        };
+
+        match tx.execute(
+            &operation_query,
+            &[
+                &document_id,
+                &ssn,
+                &sum,
+                &sid,
+                &seq,
+                &"",
+                &true,
+                &current_time,
+                &op.operation,
+                &left,
+                &right,
+            ],
+        )
+        .await{
+            Ok(tx) => {
+                info!("Successful insert query in operations table");
+                tx
+            }
+            Err(_) => {
+                error!("Failed to perform insert into operations table");
+                return Err(ApiError::DatabaseError("Failed to perform insert into operations table".to_string()));
+            }
+        };
+
+        match tx.execute(
+            &snapshot_query,
+            &[
+                &document_id,
+                &ssn,
+                &sum,
+                &sid,
+                &seq,
+                &"",
+                &true,
+            ],
+        )
+        .await {
+            Ok(tx) => {
+                info!("Successful insert query in document_snapshot table");
+                tx
+            }
+            Err(_) => {
+                error!("Failed to perform insert into document_snapshot table");
+                return Err(ApiError::DatabaseError("Failed to perform insert into document_snapshot table".to_string()));
+            }
+        };
+
+        audit::record(&tx, &actor, &op, &current_time).await?;
+        // Broadcast to the other replicas once committed
+        outbox::enqueue(&tx, &op).await?;
+
+        match tx.commit().instrument(info_span!("db.commit")).await {
+            Ok(tx) => {
+                info!("Database transaction commit successful");
+                tx
+            }
+            Err(_) => {
+                error!("Failed to commit database transaction");
+                return Err(ApiError::DatabaseError("Failed to commit database transaction".to_string()));
+            }
+        };
+        Ok(())
+    }
+    .await;
+    wal.settle(lsn, persisted).await?;
+    outbox.wake();
+
+    let applied = AppliedOperation::new(op, current_time);
+    feeds.publish(document_id, applied.clone());
+    usage::record(&tenant, document_id, Metric::Operations, 1.0);
+    usage::record(&tenant, document_id, Metric::BroadcastMessages, 1.0);
+    Ok(Json(applied))
+}
+
+/// Applies a batch of edits made at several cursors at once. The edits are independent of
+/// each other: every node they refer to must exist before the batch, and a node may only be
+/// updated or deleted once. Either every edit is applied or none is, and the resulting
+/// operations are returned and broadcast together. `id` is the document UUID.
+///
+/// Example Request
+/// {
+///     "edits" : [
+///         { "operation" : "insert", "value" : "a", "left" : { "ssn" : 1, "sum" : 2, "sid" : 1, "seq" : 2 }, "right" : null },
+///         { "operation" : "delete", "s4vector" : { "ssn" : 1, "sum" : 9, "sid" : 1, "seq" : 9 } }
+///     ]
+/// }
+#[post("/document/<id>/batch", data = "<request>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn batch_edits(
+    id: String,
+    request: JsonBody<BatchRequest>,
+    rgas: &rocket::State<SharedRGAs>,
+    locks: &rocket::State<DocumentLocks>,
+    backlog: &rocket::State<Backlog>,
+    throttle: &rocket::State<Throttle>,
+    pool: &rocket::State<Pool>,
+    outbox: &rocket::State<Outbox>,
+    wal: &rocket::State<Wal>,
+    quotas: &rocket::State<Quotas>,
+    validation: &rocket::State<ValidationConfig>,
+    feeds: &rocket::State<ChangeFeeds>,
+    request_id: RequestId,
+    actor: Actor,
+) -> Result<Json<Vec<AppliedOperation>>, ApiError> {
+    let document_id: Uuid = match Uuid::parse_str(&id) {
+        Ok(id) => id,
         Err(_) => {
-            error!(target:"error_logger","Failed to create insert query for operations table");
-            return Err(ApiError::RequestFailed("Failed to create insert query for operations table".to_string()));
+            error!("Failed to parse document id");
+            return Err(ApiError::InvalidOperation("Failed to parse document id".to_string()));
         }
     };
 
-    let current_time = chrono::Utc::now().to_rfc3339().to_string();
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::ReadWrite).await?;
+    validate_batch(&request, validation)?;
+    quota::record_operation(&*pool.get(&tenant).await?, &document_id, quotas).await?;
+
+    // Held until the batch is applied, so no other edit of the document comes in between
+    let held: OwnedMutexGuard<()> = locks.lock(document_id).await;
+
+    // prepared on a copy of the document, which only changes once the whole batch is logged
+    let (prepared, operations): (RGA, Vec<BroadcastOperation>) = {
+        let mut rgas = rgas.lock().await;
+        backlog.admit(&rgas)?;
+        let rga: &mut RGA = match rgas.get_mut(&document_id) {
+            Some(r) => r,
+            None => {
+                error!("Document not found");
+                return Err(ApiError::NotFound(String::from("Document not found")));
+            }
+        };
 
-    let tx = match client.transaction().await {
-        Ok(tx) => tx,
-        Err(_) => {
-            error!(target:"error_logger","Failed to create database transaction");
-            return Err(ApiError::DatabaseError("Failed to create database transaction".to_string()));
+        throttle.admit(&mut rga.throttle, request.edits.len())?;
+
+        // checked up front, so a missing node leaves the document untouched
+        let (mut added, mut removed): (usize, usize) = (0, 0);
+        for edit in request.edits.iter() {
+            let referenced: Vec<S4Vector> = match edit.operation {
+                OperationKind::Insert => edit.request.left.into_iter().chain(edit.request.right).collect(),
+                _ => edit.request.s4vector.into_iter().collect(),
+            };
+            if !referenced.iter().all(|s4vector| rga.contains(s4vector)) {
+                error!("Batch depends on a node that has not been applied");
+                return Err(ApiError::Conflict("Batch depends on operations that have not been applied".to_string()));
+            }
+
+            added += edit.request.value.as_ref().map_or(0, String::len);
+            if edit.operation != OperationKind::Insert {
+                removed += edit.request.s4vector.and_then(|s4vector| rga.node(&s4vector)).filter(|node| !node.tombstone).map_or(0, |node| node.value.len());
+            }
+        }
+        quotas.check_document_size(rga.content_size(), added, removed)?;
+
+        let mut prepared: RGA = rga.clone();
+        let mut operations: Vec<BroadcastOperation> = Vec::with_capacity(request.edits.len());
+        for edit in request.edits.iter() {
+            let value: String = edit.request.value.clone().unwrap_or_default();
+            let result = match (edit.operation, edit.request.s4vector) {
+                (OperationKind::Insert, _) => prepared.local_insert(value, edit.request.left, edit.request.right, document_id),
+                (OperationKind::Update, Some(s4vector)) => prepared.local_update(s4vector, value, document_id),
+                (OperationKind::Delete, Some(s4vector)) => prepared.local_delete(s4vector, document_id),
+                (_, None) => Err(OperationError::DependancyError),
+            };
+            match result {
+                Ok(operation) => operations.push(operation),
+                Err(_) => {
+                    error!("Failed to apply an edit of the batch");
+                    return Err(ApiError::InternalServerError("Failed to apply an edit of the batch".to_string()));
+                }
+            }
         }
+        (prepared, operations)
     };
 
-    match tx.execute(
-        &operation_query,
-        &[
-            &document_id,
-            &(s4.ssn as i64),
-            &(s4.sum as i64),
-            &(s4.sid as i64),
-            &(s4.seq as i64),
-            &"",
-            &false,
-            &current_time,
-        ],
+    let applied: Vec<AppliedOperation> = db::commit_operations(
+        pool,
+        rgas,
+        wal,
+        held,
+        &prepared,
+        &actor,
+        &request_id,
+        document_id,
+        &tenant,
+        operations,
+        outbox,
+        feeds,
     )
-    .await{
-        Ok(tx) => {
-            info!(target:"request_logger","Successful insert query in operations table");
-            tx
-        }
+    .await?;
+    info!(edits = applied.len(), "Applied batch of edits");
+    Ok(Json(applied))
+}
+
+/// Moves a range of nodes to a new position as a single operation, for cut and paste.
+/// The nodes keep their s4vectors, so blame, selections and anything else anchored to them
+/// follow the moved text. `id` is the document UUID.
+///
+/// Example Request
+/// {
+///     "first" : { "ssn" : 1, "sum" : 2, "sid" : 1, "seq" : 2 },
+///     "last" : { "ssn" : 1, "sum" : 4, "sid" : 1, "seq" : 4 },
+///     "left" : { "ssn" : 1, "sum" : 9, "sid" : 1, "seq" : 9 },
+///     "right" : null
+/// }
+#[post("/document/<id>/move", data = "<request>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn move_nodes(
+    id: String,
+    request: JsonBody<MoveRequest>,
+    rgas: &rocket::State<SharedRGAs>,
+    locks: &rocket::State<DocumentLocks>,
+    backlog: &rocket::State<Backlog>,
+    throttle: &rocket::State<Throttle>,
+    pool: &rocket::State<Pool>,
+    outbox: &rocket::State<Outbox>,
+    wal: &rocket::State<Wal>,
+    quotas: &rocket::State<Quotas>,
+    feeds: &rocket::State<ChangeFeeds>,
+    request_id: RequestId,
+    actor: Actor,
+) -> Result<Json<AppliedOperation>, ApiError> {
+    let document_id: Uuid = match Uuid::parse_str(&id) {
+        Ok(id) => id,
         Err(_) => {
-            error!(target:"error_logger","Failed to perform insert into operations table");
-            return Err(ApiError::DatabaseError("Failed to perform insert into operations table".to_string()));
+            error!("Failed to parse document id");
+            return Err(ApiError::InvalidOperation("Failed to parse document id".to_string()));
         }
     };
 
-    match tx.execute(
-        &snapshot_query,
-        &[
-            &document_id,
-            &(s4.ssn as i64),
-            &(s4.sum as i64),
-            &(s4.sid as i64),
-            &(s4.seq as i64),
-            &"",
-            &false,
-        ],
-    )
-    .await {
-        Ok(tx) => {
-            info!(target:"request_logger","Successful insert query in document_snapshot table");
-            tx
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::ReadWrite).await?;
+    quota::record_operation(&*pool.get(&tenant).await?, &document_id, quotas).await?;
+
+    // Held until the move is applied, so no other edit of the document comes in between
+    let edit: OwnedMutexGuard<()> = locks.lock(document_id).await;
+
+    let mut op: BroadcastOperation = {
+        let mut rgas = rgas.lock().await;
+        backlog.admit(&rgas)?;
+        let rga: &mut RGA = match rgas.get_mut(&document_id) {
+            Some(r) => r,
+            None => {
+                error!("Document could not be found.");
+                return Err(ApiError::NotFound(String::from("Document not found")));
+            }
+        };
+
+        throttle.admit(&mut rga.throttle, 1)?;
+        // prepared but not applied, the document only changes once the move is logged
+        match rga.prepare_move(request.first, request.last, request.left, request.right, document_id) {
+            Ok(op) => op,
+            Err(OperationError::InvalidMove(reason)) => {
+                error!("Rejected an invalid move: {}", reason);
+                return Err(ApiError::InvalidOperation(format!("Invalid move, {}", reason)));
+            }
+            Err(_) => {
+                error!("Failed to move nodes");
+                return Err(ApiError::Conflict("Operation depends on a node that has not been applied".to_string()));
+            }
         }
+    };
+    op.document_id = document_id;
+    op.request_id = Some(request_id.0.clone());
+    op.author = actor.user_id;
+
+    let current_time = chrono::Utc::now().to_rfc3339();
+    // Logged before the database, so the move is kept if the database fails
+    let lsn: Option<u64> = wal.append(&WalRecord {
+        operation: op.clone(),
+        value: encryption::seal(""),
+        tombstone: false,
+        authored_at: None,
+        client_ip: actor.client_ip.clone(),
+        timestamp: current_time.clone(),
+    }).await?;
+
+    apply_logged(rgas, &document_id, std::slice::from_ref(&op)).await;
+    drop(edit);
+
+    let persisted: Result<(), ApiError> = async {
+        let mut client = pool.get(&tenant).await?;
+        db::record_move(&mut client, &actor, &op, &current_time).await
+    }
+    .await;
+    wal.settle(lsn, persisted).await?;
+    outbox.wake();
+    info!(nodes = op.nodes.len(), "Moved nodes");
+
+    let applied = AppliedOperation::new(op, current_time);
+    feeds.publish(document_id, applied.clone());
+    usage::record(&tenant, document_id, Metric::Operations, 1.0);
+    usage::record(&tenant, document_id, Metric::BroadcastMessages, 1.0);
+    Ok(Json(applied))
+}
+
+/// Returns the audit log of a document: who performed each insert, update and delete,
+/// from which client address, and when. Only the owner of the document may read it.
+/// `id` is the document UUID.
+///
+/// Example Response
+/// [
+///     {
+///         "document_id" : "f47ac10b-58cc-4372-a567-0e02b2c3d479",
+///         "user_id" : "550e8400-e29b-41d4-a716-446655440000",
+///         "client_ip" : "10.0.0.12",
+///         "operation" : "Insert",
+///         "ssn" : 1, "sum" : 4, "sid" : 3, "seq" : 3,
+///         "timestamp" : "2025-01-04T10:15:02.114+00:00"
+///     }
+/// ]
+#[get("/document/<id>/audit")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn fetch_audit_log(
+    id: String,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    request_id: RequestId,
+    actor: Actor,
+) -> Result<Json<Vec<AuditEntry>>, ApiError> {
+    let document_id: Uuid = match Uuid::parse_str(&id) {
+        Ok(id) => id,
         Err(_) => {
-            error!(target:"error_logger","Failed to perform insert into document_snapshot table");
-            return Err(ApiError::DatabaseError("Failed to perform insert into document_snapshot table".to_string()));
+            error!("Failed to parse document id");
+            return Err(ApiError::InvalidOperation("Failed to parse document id".to_string()));
         }
     };
 
-    match tx.commit().await {
-        Ok(tx) => {
-            info!(target:"request_logger","Database transaction commit successful");
-            tx
+    let user_id: Uuid = actor.require_user()?;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
+    let client = tenancy::lock(db, &tenant).await?;
+
+    if audit::document_owner(&client, &document_id).await? != user_id {
+        error!("Audit log requested by a user who does not own the document");
+        return Err(ApiError::Forbidden(
+            "Only the document owner can view the audit log".to_string(),
+        ));
+    }
+
+    Ok(Json(audit::fetch(&client, &document_id).await?))
+}
+
+/// The content of a document, either its nodes or its text streamed in chunks.
+pub enum ContentResponse {
+    Nodes(Json<DocumentContent>),
+    Text(TextStream<stream::Iter<Chunks>>),
+}
+
+impl<'r> Responder<'r, 'r> for ContentResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'r> {
+        match self {
+            ContentResponse::Nodes(nodes) => nodes.respond_to(request),
+            ContentResponse::Text(text) => text.respond_to(request),
         }
+    }
+}
+
+/// Returns the visible content of a loaded document with the s4vector of each node.
+/// `id` is the document UUID.
+/// `format`: `nodes` (the default), or `text` to stream the content as plain text instead.
+#[get("/document/<id>/content?<format>")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn fetch_document_content(
+    id: String,
+    format: Option<String>,
+    rgas: &rocket::State<SharedRGAs>,
+    pool: &rocket::State<Pool>,
+    request_id: RequestId,
+    actor: Actor,
+) -> Result<ContentResponse, ApiError> {
+    let document_id: Uuid = match Uuid::parse_str(&id) {
+        Ok(id) => id,
         Err(_) => {
-            error!(target:"error_logger","Failed to commit database transaction");
-            return Err(ApiError::DatabaseError("Failed to commit database transaction".to_string()));
+            error!("Failed to parse document id");
+            return Err(ApiError::InvalidOperation("Failed to parse document id".to_string()));
         }
     };
+    let text: bool = match format.as_deref() {
+        None | Some("nodes") => false,
+        Some("text") => true,
+        Some(other) => {
+            error!("Unsupported format {:?}", other);
+            return Err(ApiError::InvalidOperation("format must be nodes or text".to_string()));
+        }
+    };
+    actor.authorize(pool, &document_id, Access::Read).await?;
 
-    //Broadcast to SNS
-    match db::send_operation(Arc::clone(sns_client), &topic.lock().await, &op).await {
-        Ok(_) =>  {
-            info!(target:"request_logger","SNS broadcast notifiction sent to other replicas");
-        },
-        Err(_) => {
-            error!(target:"error_logger","Failed to send SNS notificaiton");
-            return Err(ApiError::DatabaseError(
-                "Failed to send SNS notification".to_string()
-            ))
+    let mut rgas = rgas.lock().await;
+    let rga: &mut RGA = match rgas.get_mut(&document_id) {
+        Some(r) => r,
+        None => {
+            error!("Document not found");
+            return Err(ApiError::NotFound(String::from("Document not found")));
         }
     };
+    rga.touch();
 
-    Ok(())
+    if text {
+        return Ok(ContentResponse::Text(TextStream(rga.read_chunks(READ_CHUNK_BYTES))));
+    }
+
+    let nodes: Vec<ContentNode> = rga
+        .read_nodes()
+        .into_iter()
+        .map(|(s4vector, value)| ContentNode { s4vector, value })
+        .collect();
+
+    Ok(ContentResponse::Nodes(Json(DocumentContent { document_id, nodes })))
+}
+
+/// Exposes the replica's metrics in the Prometheus text format.
+#[get("/metrics")]
+pub async fn metrics(
+    rgas: &rocket::State<SharedRGAs>,
+    memory: &rocket::State<MemoryConfig>,
+    replication: &rocket::State<ReplicationMetrics>,
+    backlog: &rocket::State<Backlog>,
+    divergence: &rocket::State<Divergence>,
+    throttle: &rocket::State<Throttle>,
+) -> String {
+    let rgas = rgas.lock().await;
+    MemoryReport::collect(&rgas, memory).await.to_prometheus()
+        + &replication.to_prometheus()
+        + &backlog.to_prometheus(&backlog.sizes(&rgas))
+        + &divergence.to_prometheus()
+        + &compaction::to_prometheus(&rgas)
+        + &throttle.to_prometheus()
+        + &query_metrics::to_prometheus()
 }
 
 // Receives SNS notifications to perform remote operations
-#[post("/sns", format = "json", data = "<notification>")]
+#[post("/sns", data = "<notification>")]
+#[instrument(skip_all, fields(request_id = %request_id))]
 pub async fn handle_sns_notification(
-    notification: Json<SnsNotification>,
+    notification: JsonBody<SnsNotification>,
     rgas: &rocket::State<SharedRGAs>,
+    feeds: &rocket::State<ChangeFeeds>,
+    chats: &rocket::State<ChatRooms>,
+    replication: &rocket::State<ReplicationMetrics>,
+    backlog: &rocket::State<Backlog>,
+    request_id: RequestId,
+) -> Result<(), ApiError> {
+    apply_broadcast(&notification.0.message, rgas, feeds, chats, replication, backlog).await
+}
+
+/// Applies a message broadcast by another replica, delivered by SNS or read from this
+/// replica's SQS queue.
+pub async fn apply_broadcast(
+    message: &str,
+    rgas: &SharedRGAs,
+    feeds: &ChangeFeeds,
+    chats: &ChatRooms,
+    replication: &ReplicationMetrics,
+    backlog: &Backlog,
 ) -> Result<(), ApiError> {
+    let _pending = backlog.receive();
+    // chat messages share the topic with operations but are never applied to the RGA
+    if let Ok(broadcast) = serde_json::from_str::<ChatBroadcast>(message) {
+        chats.publish(broadcast.chat);
+        return Ok(());
+    }
+
     let mut rags = rgas.lock().await;
 
-    let operation: BroadcastOperation = match serde_json::from_str(&notification.0.message) {
+    let operation: BroadcastOperation = match serde_json::from_str(message) {
         Ok(op) => op,
         Err(_) => {
-            error!(target:"error_logger","Failed to parse SNS message");
+            error!("Failed to parse SNS message");
             return Err(ApiError::InternalServerError("Failed to parse SNS message".to_string()));
         }
     };
 
+    if let Err(e) = operation.validate() {
+        error!("Rejected broadcast with an out of range s4vector");
+        return Err(e.into());
+    }
+
+    if let Some(origin) = &operation.request_id {
+        info!(origin_request_id = %origin, "Applying {} broadcast", operation.operation);
+    }
+    replication.record(operation.origin_region.as_deref(), operation.sent_at.as_deref(), chrono::Utc::now());
+
     let rga = rags.get_mut(&operation.document_id);
 
     let rga = match rga {
         Some(r) => r,
         None => {
-            error!(target:"error_logger","Failed to load the document");
-            return Err(ApiError::RequestFailed("Document not loaded".to_string()));
+            error!("Failed to load the document");
+            return Err(ApiError::NotFound("Document not loaded".to_string()));
         }
     };
 
-    match operation.operation.as_str() {
-        "Insert" => {
-            let _ = &rga
-                .remote_insert(
-                    operation.value.clone().unwrap(),
-                    operation.s4vector(),
-                    operation.left,
-                    operation.right,
-                )
-                .await;
-        }
-        "Update" => {
-            rga.remote_update(operation.s4vector(), operation.value.unwrap())
-                .await;
-        }
-        "Delete" => {
-            rga.remote_delete(operation.s4vector()).await;
-        }
-        _ => {
-            error!(target:"error_logger","Invalid operation type");
-            return Err(ApiError::RequestFailed("Invalid operation".to_string()));
-        
+    backlog.admit_buffered(rga, &operation)?;
+
+    let document_id: Uuid = operation.document_id;
+    let applied = AppliedOperation::new(operation.clone(), chrono::Utc::now().to_rfc3339());
+    let (s4, author) = (operation.s4vector(), operation.author);
+    let authored: bool = matches!(operation.operation.as_str(), "Insert" | "Update");
+    match rga.apply_remote(operation) {
+        Ok(true) => {}
+        Ok(false) => {
+            // a redelivery, which was published to the change feed the first time
+            info!("Skipping an insert that was already applied");
+            return Ok(());
         }
+        Err(_) => {
+            error!("Invalid operation type");
+            return Err(ApiError::InvalidOperation("Invalid operation".to_string()));
+        }
+    }
+    backlog.shed(document_id, rga);
+    if authored {
+        rga.set_author(s4, author, Some(applied.timestamp.clone()));
     }
+    feeds.publish(document_id, applied);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        self, TestDatabase, OPERATION_TABLES, ORGANIZATION_TABLES, USER_TABLES,
+    };
+    use crate::throttle::ThrottleConfig;
+    use crate::wal::WalConfig;
+    use rocket::http::{ContentType, Status};
+    use rocket::local::asynchronous::Client as LocalClient;
+    use std::time::Duration;
+
+    #[rocket::async_test]
+    async fn test_document_locks() {
+        let locks = DocumentLocks::default();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let held = locks.lock(first).await;
+
+        // only the edits of the same document wait
+        drop(locks.lock(second).await);
+        let waiting = rocket::tokio::time::timeout(Duration::from_millis(50), locks.lock(first));
+        assert!(waiting.await.is_err());
+        drop(held);
+        drop(locks.lock(first).await);
+
+        // the entries of documents nobody is editing are dropped
+        drop(locks.lock(second).await);
+        assert_eq!(locks.0.lock().unwrap().len(), 1);
+    }
+
+    /// A write-ahead log in a file of its own.
+    async fn open_wal() -> (Wal, WalConfig) {
+        let config = WalConfig {
+            path: std::env::temp_dir()
+                .join(format!("nimble-{}.wal", Uuid::new_v4()))
+                .to_string_lossy()
+                .into_owned(),
+            ..WalConfig::default()
+        };
+        (Wal::open(&config).await.unwrap(), config)
+    }
+
+    /// A client of a rocket serving edit `routes` of the documents in `rgas` from `database`,
+    /// with two pooled connections.
+    async fn edit_client(database: &TestDatabase, rgas: &SharedRGAs, wal: &Wal, routes: Vec<rocket::Route>) -> LocalClient {
+        let rocket = testing::rocket(database)
+            .await
+            .manage(Arc::clone(rgas))
+            .manage(DocumentLocks::default())
+            .manage(Backlog::new(Default::default(), Default::default()))
+            .manage(Throttle::new(ThrottleConfig::default()))
+            .manage(Outbox::default())
+            .manage(wal.clone())
+            .manage(Quotas::default())
+            .manage(ValidationConfig::default())
+            .manage(ChangeFeeds::default())
+            .mount("/", routes);
+        LocalClient::tracked(rocket).await.unwrap()
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_rejected_insert_leaves_document_unchanged() {
+        let database = TestDatabase::create(&format!("{}{}", ORGANIZATION_TABLES, OPERATION_TABLES)).await;
+        let db: Client = database.connect().await;
+        let user_id = Uuid::new_v4();
+        let document_id: Uuid = testing::insert_document(&db, user_id).await;
+
+        // a site id too large to store, so the insert's s4vector is rejected
+        let rgas: SharedRGAs = Arc::new(Mutex::new(HashMap::from([(document_id, RGA::new(1, u64::MAX))])));
+        let (wal, config) = open_wal().await;
+        let client = edit_client(&database, &rgas, &wal, rocket::routes![insert]).await;
+
+        let response = client
+            .post(format!("/document/{}/insert", document_id))
+            .header(ContentType::JSON)
+            .header(testing::session(user_id))
+            .body(r#"{"value":"a","left":null,"right":null}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // nothing was applied, logged or persisted
+        let rgas = rgas.lock().await;
+        let rga: &RGA = &rgas[&document_id];
+        assert!(rga.is_empty());
+        assert!(!rga.dirty);
+        assert_eq!(wal.pending().await, 0);
+        let operations: i64 = db.query_one("SELECT COUNT(*) FROM operations", &[]).await.unwrap().get(0);
+        assert_eq!(operations, 0);
+
+        let _ = std::fs::remove_file(&config.path);
+        database.drop().await;
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_batch_is_applied_whole() {
+        let database = TestDatabase::create(&format!("{}{}", ORGANIZATION_TABLES, OPERATION_TABLES)).await;
+        let db: Client = database.connect().await;
+        let user_id = Uuid::new_v4();
+        let (document_id, failing_id) = (testing::insert_document(&db, user_id).await, testing::insert_document(&db, user_id).await);
+
+        // both documents hold "a" from another replica, the second has a site id too large to
+        // store, so its inserts are rejected
+        let mut rga = RGA::new(1, 2);
+        let a: BroadcastOperation = rga.local_insert("a".to_string(), None, None, document_id).unwrap();
+        let (mut loaded, mut failing) = (RGA::new(1, 1), RGA::new(1, u64::MAX));
+        loaded.apply_remote(a.clone()).unwrap();
+        failing.apply_remote(a.clone()).unwrap();
+        let rgas: SharedRGAs = Arc::new(Mutex::new(HashMap::from([(document_id, loaded), (failing_id, failing)])));
+        let (wal, config) = open_wal().await;
+        let client = edit_client(&database, &rgas, &wal, rocket::routes![batch_edits]).await;
+
+        let body = serde_json::json!({
+            "edits": [
+                { "operation": "update", "s4vector": a.s4vector(), "value": "b" },
+                { "operation": "insert", "value": "c", "left": a.s4vector(), "right": null },
+            ]
+        })
+        .to_string();
+        let batch = |id: Uuid| client.post(format!("/document/{}/batch", id)).header(ContentType::JSON).header(testing::session(user_id)).body(body.clone());
+
+        // the update is prepared before the insert fails, and dropped with it
+        assert_eq!(batch(failing_id).dispatch().await.status(), Status::InternalServerError);
+        assert_eq!(rgas.lock().await[&failing_id].text(), "a");
+
+        let response = batch(document_id).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_json::<Vec<AppliedOperation>>().await.unwrap().len(), 2);
+        assert_eq!(rgas.lock().await[&document_id].text(), "bc");
+
+        // logged, then persisted with their types in one transaction
+        assert_eq!(wal.pending().await, 0);
+        let operations: Vec<String> = db
+            .query("SELECT operation FROM operations ORDER BY log_position", &[])
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(operations, vec!["Update", "Insert"]);
+
+        let _ = std::fs::remove_file(&config.path);
+        database.drop().await;
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_slow_document_does_not_block_others() {
+        let database = TestDatabase::create(&format!("{}{}", ORGANIZATION_TABLES, OPERATION_TABLES)).await;
+        let db: Client = database.connect().await;
+        let user_id = Uuid::new_v4();
+        let (slow_id, document_id) = (testing::insert_document(&db, user_id).await, testing::insert_document(&db, user_id).await);
+
+        let rgas: SharedRGAs = Arc::new(Mutex::new(HashMap::from([(slow_id, RGA::new(1, 1)), (document_id, RGA::new(1, 1))])));
+        let (wal, config) = open_wal().await;
+        let client = edit_client(&database, &rgas, &wal, rocket::routes![insert]).await;
+        let insert = |id: Uuid| {
+            client
+                .post(format!("/document/{}/insert", id))
+                .header(ContentType::JSON)
+                .header(testing::session(user_id))
+                .body(r#"{"value":"a","left":null,"right":null}"#)
+                .dispatch()
+        };
+
+        // another transaction holds the slow document's quota row, so its insert waits on the
+        // database with a pooled connection
+        db.execute("INSERT INTO document_quota (document_id,window_start,operations) VALUES ($1,now(),0)", &[&slow_id]).await.unwrap();
+        let holder: Client = database.connect().await;
+        holder.batch_execute(&format!("BEGIN; SELECT 1 FROM document_quota WHERE document_id='{}' FOR UPDATE", slow_id)).await.unwrap();
+        let slow = insert(slow_id);
+        rocket::tokio::pin!(slow);
+        assert!(rocket::tokio::time::timeout(Duration::from_millis(200), &mut slow).await.is_err());
+
+        // an edit of another document goes ahead in the meantime
+        let response = rocket::tokio::time::timeout(Duration::from_secs(5), insert(document_id)).await.expect("the other document's insert was not blocked");
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(rgas.lock().await[&document_id].text(), "a");
+
+        holder.batch_execute("COMMIT").await.unwrap();
+        let response = rocket::tokio::time::timeout(Duration::from_secs(5), slow).await.expect("the slow insert finished");
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(rgas.lock().await[&slow_id].text(), "a");
+
+        let _ = std::fs::remove_file(&config.path);
+        database.drop().await;
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_move_is_logged() {
+        let database = TestDatabase::create(&format!("{}{}", ORGANIZATION_TABLES, OPERATION_TABLES)).await;
+        let db: Client = database.connect().await;
+        let user_id = Uuid::new_v4();
+        let document_id: Uuid = testing::insert_document(&db, user_id).await;
+
+        let rgas: SharedRGAs = Arc::new(Mutex::new(HashMap::from([(document_id, RGA::new(1, 1))])));
+        let (wal, config) = open_wal().await;
+        let client = edit_client(&database, &rgas, &wal, rocket::routes![insert, move_nodes]).await;
+        let post = |route: &str, body: serde_json::Value| {
+            client
+                .post(format!("/document/{}/{}", document_id, route))
+                .header(ContentType::JSON)
+                .header(testing::session(user_id))
+                .body(body.to_string())
+        };
+
+        let mut left: Option<S4Vector> = None;
+        let mut nodes: Vec<S4Vector> = Vec::new();
+        for value in ["a", "b", "c"] {
+            let response = post("insert", serde_json::json!({ "value": value, "left": left, "right": null })).dispatch().await;
+            let s4vector: S4Vector = response.into_json::<AppliedOperation>().await.unwrap().s4vector;
+            left = Some(s4vector);
+            nodes.push(s4vector);
+        }
+
+        let response = post("move", serde_json::json!({ "first": nodes[2], "last": nodes[2], "left": null, "right": null })).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(rgas.lock().await[&document_id].text(), "cab");
+
+        // logged with the node it moved, without checkpointing the document
+        assert_eq!(wal.pending().await, 0);
+        let row = db.query_one("SELECT nodes FROM operations WHERE operation='Move'", &[]).await.unwrap();
+        assert_eq!(row.get::<_, Vec<i64>>(0), nodes[2].to_i64().unwrap().to_vec());
+        let checkpoints: i64 = db.query_one("SELECT COUNT(*) FROM document_checkpoints", &[]).await.unwrap().get(0);
+        assert_eq!(checkpoints, 0);
+
+        // the rows keep the insert order, the log puts "c" back in front
+        let session = Session { replica_id: 2, session_id: 1 };
+        let loaded: RGA = snapshot::load_rga(&db, &document_id, &session).await.unwrap();
+        assert_eq!(loaded.text(), "cab");
+
+        let _ = std::fs::remove_file(&config.path);
+        database.drop().await;
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_create_document_is_owned_by_the_user() {
+        let database = TestDatabase::create(&format!("{}{}{}", ORGANIZATION_TABLES, OPERATION_TABLES, USER_TABLES)).await;
+        let db: Client = database.connect().await;
+        let user_id: Uuid = testing::insert_user(&db, "ada@example.com").await;
+        let rocket = testing::rocket(&database)
+            .await
+            .manage(Arc::new(Mutex::new(1_i64)))
+            .manage(Quotas::default())
+            .mount("/", rocket::routes![create_document]);
+        let client = LocalClient::tracked(rocket).await.unwrap();
+        // an owner in the body is not trusted
+        let body = format!(r#"{{"owner_id":"{}","title":"Notes"}}"#, Uuid::new_v4());
+
+        let anonymous = client.post("/create_document").header(ContentType::JSON).body(&body).dispatch().await;
+        assert_eq!(anonymous.status(), Status::Unauthorized);
+
+        let response = client
+            .post("/create_document")
+            .header(ContentType::JSON)
+            .header(testing::session(user_id))
+            .body(&body)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let created: CreateDocumentResponse = response.into_json().await.unwrap();
+        let owner_id: Uuid = db
+            .query_one("SELECT owner_id FROM document WHERE document_id=$1", &[&created.document_id])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(owner_id, user_id);
+
+        // nor is a session of a user that doesn't exist
+        let unknown = client
+            .post("/create_document")
+            .header(ContentType::JSON)
+            .header(testing::session(Uuid::new_v4()))
+            .body(&body)
+            .dispatch()
+            .await;
+        assert_eq!(unknown.status(), Status::UnprocessableEntity);
+        database.drop().await;
+    }
+}
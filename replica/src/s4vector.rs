@@ -1,4 +1,13 @@
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Errors raised when an `S4Vector` does not fit the range stored in the database.
+/// `OutOfRange`: A field is negative or larger than `i64::MAX` (the range of a `BIGINT` column).
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum S4VectorError {
+    #[error("s4vector field `{0}` is out of range: {1}")]
+    OutOfRange(&'static str, i128),
+}
 
 /// `S4Vector` is a structure representing an operation in a distributed system. It ensures
 /// causal consistency and deterministic ordering for collaborative applications, particularly
@@ -15,7 +24,7 @@ use serde::{Deserialize, Serialize};
 ///
 /// # Example
 /// ```
-/// use crdt::S4Vector;
+/// use nimble::S4Vector;
 /// let current_session: u64 = 1; // Session ID
 /// let local_site: u64 = 42; // Replica ID
 /// let mut local_sequence: u64 = 0; // Local logical clock
@@ -102,7 +111,7 @@ impl S4Vector {
     ///
     /// # Examples
     /// ```
-    /// use crdt::S4Vector;
+    /// use nimble::S4Vector;
     /// let left = S4Vector { ssn: 1, sum: 10, sid: 1, seq: 1 };
     /// let right = S4Vector { ssn: 1, sum: 20, sid: 2, seq: 2 };
     /// let current_session = 1;
@@ -119,14 +128,16 @@ impl S4Vector {
         local_site: u64,
         local_sequence: &mut u64,
     ) -> Self {
-        *local_sequence += 1;
+        *local_sequence = local_sequence.saturating_add(1).min(Self::MAX_FIELD);
 
+        // computed without overflow and capped so the vector can always be stored
         let new_sum = match (left, right) {
-            (Some(l), Some(r)) => (l.sum + r.sum) / 2, // average
-            (Some(l), None) => l.sum + 1,              // append to the end
-            (None, Some(r)) => r.sum / 2,              // Insert at start
-            (None, None) => 1,                         // first element
-        };
+            (Some(l), Some(r)) => l.sum / 2 + r.sum / 2 + (l.sum % 2 + r.sum % 2) / 2, // average
+            (Some(l), None) => l.sum.saturating_add(1), // append to the end
+            (None, Some(r)) => r.sum / 2,               // Insert at start
+            (None, None) => 1,                          // first element
+        }
+        .min(Self::MAX_FIELD);
 
         S4Vector {
             ssn: current_session,
@@ -137,9 +148,182 @@ impl S4Vector {
     }
 }
 
+impl S4Vector {
+    /// The largest value a field can hold, fields are stored in `BIGINT` columns.
+    pub const MAX_FIELD: u64 = i64::MAX as u64;
+
+    /// Checks that every field can be stored in the database without truncation.
+    pub fn validate(&self) -> Result<(), S4VectorError> {
+        self.to_i64().map(|_| ())
+    }
+
+    /// Converts the fields to `i64` for the database, in the order `ssn`, `sum`, `sid`, `seq`.
+    /// Fails instead of wrapping if a field is larger than `i64::MAX`.
+    pub fn to_i64(&self) -> Result<[i64; 4], S4VectorError> {
+        let field = |name: &'static str, value: u64| match i64::try_from(value) {
+            Ok(v) => Ok(v),
+            Err(_) => Err(S4VectorError::OutOfRange(name, value as i128)),
+        };
+        Ok([
+            field("ssn", self.ssn)?,
+            field("sum", self.sum)?,
+            field("sid", self.sid)?,
+            field("seq", self.seq)?,
+        ])
+    }
+
+    /// Builds a vector from `i64` fields read from the database or a broadcast.
+    /// Fails instead of wrapping if a field is negative.
+    pub fn from_i64(ssn: i64, sum: i64, sid: i64, seq: i64) -> Result<S4Vector, S4VectorError> {
+        let field = |name: &'static str, value: i64| match u64::try_from(value) {
+            Ok(v) => Ok(v),
+            Err(_) => Err(S4VectorError::OutOfRange(name, value as i128)),
+        };
+        Ok(S4Vector {
+            ssn: field("ssn", ssn)?,
+            sum: field("sum", sum)?,
+            sid: field("sid", sid)?,
+            seq: field("seq", seq)?,
+        })
+    }
+
+    /// Appends the vector to `out` as four LEB128 varints (`ssn`, `sum`, `sid`, `seq`).
+    /// Small values, which are the common case, take a single byte each, so most vectors
+    /// encode in 4 to 8 bytes.
+    pub fn write_varint(&self, out: &mut Vec<u8>) {
+        for mut field in [self.ssn, self.sum, self.sid, self.seq] {
+            while field >= 0x80 {
+                out.push((field as u8 & 0x7f) | 0x80);
+                field >>= 7;
+            }
+            out.push(field as u8);
+        }
+    }
+
+    /// Decodes a vector written by [`S4Vector::write_varint`] from the start of `bytes`.
+    /// Returns the vector and the number of bytes read, or `None` if the input is truncated
+    /// or a field does not fit in a `u64`.
+    pub fn read_varint(bytes: &[u8]) -> Option<(S4Vector, usize)> {
+        let mut fields = [0u64; 4];
+        let mut read: usize = 0;
+
+        for field in fields.iter_mut() {
+            let mut shift: u32 = 0;
+            loop {
+                let byte = *bytes.get(read)?;
+                read += 1;
+
+                let bits = (byte & 0x7f) as u64;
+                if shift == 63 && bits > 1 || shift > 63 {
+                    return None;
+                }
+                *field |= bits << shift;
+
+                if byte & 0x80 == 0 {
+                    break;
+                }
+                shift += 7;
+            }
+        }
+
+        let [ssn, sum, sid, seq] = fields;
+        Some((S4Vector { ssn, sum, sid, seq }, read))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn prop_generate_between_neighbours_never_overflows(
+            l in 0..=S4Vector::MAX_FIELD,
+            r in 0..=S4Vector::MAX_FIELD,
+        ) {
+            let (l, r) = (l.min(r), l.max(r));
+            let left = S4Vector { ssn: 1, sum: l, sid: 1, seq: 1 };
+            let right = S4Vector { ssn: 1, sum: r, sid: 2, seq: 1 };
+            let mut sequence = 0;
+
+            let s4 = S4Vector::generate(Some(&left), Some(&right), 1, 1, &mut sequence);
+            prop_assert!(l <= s4.sum && s4.sum <= r);
+            prop_assert_eq!(s4.sum as u128, (l as u128 + r as u128) / 2);
+            prop_assert!(s4.validate().is_ok());
+        }
+
+        #[test]
+        fn prop_generate_stays_storable(
+            sum in 0..=u64::MAX,
+            sequence in (S4Vector::MAX_FIELD - 2)..=u64::MAX,
+        ) {
+            let left = S4Vector { ssn: 1, sum, sid: 1, seq: 1 };
+            let mut sequence = sequence;
+
+            let appended = S4Vector::generate(Some(&left), None, 1, 1, &mut sequence);
+            prop_assert!(appended.validate().is_ok());
+            prop_assert!(appended.sum >= sum.min(S4Vector::MAX_FIELD));
+
+            let prepended = S4Vector::generate(None, Some(&left), 1, 1, &mut sequence);
+            prop_assert!(prepended.validate().is_ok());
+        }
+
+        #[test]
+        fn prop_i64_round_trip(ssn in 0..=i64::MAX, sum in 0..=i64::MAX, sid in 0..=i64::MAX, seq in 0..=i64::MAX) {
+            let s4 = S4Vector::from_i64(ssn, sum, sid, seq).unwrap();
+            prop_assert_eq!(s4.to_i64().unwrap(), [ssn, sum, sid, seq]);
+        }
+    }
+
+    #[test]
+    fn test_s4vector_out_of_range() {
+        let s4 = S4Vector {
+            ssn: 1,
+            sum: u64::MAX,
+            sid: 1,
+            seq: 1,
+        };
+        assert_eq!(
+            s4.validate(),
+            Err(S4VectorError::OutOfRange("sum", u64::MAX as i128))
+        );
+        assert_eq!(
+            S4Vector::from_i64(1, 1, -1, 1),
+            Err(S4VectorError::OutOfRange("sid", -1))
+        );
+    }
+
+    #[test]
+    fn test_s4vector_varint_round_trip() {
+        let s4_1 = S4Vector {
+            ssn: 1,
+            sum: 10,
+            sid: 42,
+            seq: 1,
+        };
+        let s4_2 = S4Vector {
+            ssn: 1,
+            sum: 300,
+            sid: 2,
+            seq: u64::MAX,
+        };
+
+        let mut out: Vec<u8> = Vec::new();
+        s4_1.write_varint(&mut out);
+        assert_eq!(out.len(), 4);
+        s4_2.write_varint(&mut out);
+
+        let (decoded_1, read_1) = S4Vector::read_varint(&out).unwrap();
+        let (decoded_2, read_2) = S4Vector::read_varint(&out[read_1..]).unwrap();
+        assert_eq!((decoded_1, decoded_2), (s4_1, s4_2));
+        assert_eq!(read_1 + read_2, out.len());
+
+        // truncated input
+        assert_eq!(S4Vector::read_varint(&out[read_1..out.len() - 1]), None);
+        // a field longer than 10 bytes cannot fit in a u64
+        assert_eq!(S4Vector::read_varint(&[0xff; 11]), None);
+    }
 
     #[test]
     fn test_s4vector_equality() {
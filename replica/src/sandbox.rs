@@ -0,0 +1,718 @@
+//! Runs the code of a document in a sandbox and streams its output to collaborators.
+//!
+//! The replica doesn't isolate programs itself. Each language is run by a configured command,
+//! such as `wasmtime run` of an interpreter compiled to WebAssembly or a Firecracker jailer
+//! wrapper, behind the [`Sandbox`] trait. The replica enforces the time limit, the output limit
+//! and the per-user quota, and runs the command with an empty environment in a temporary
+//! directory holding only the source file.
+
+use crate::pool::Pool;
+use crate::flags::{Feature, Features};
+use crate::limits::JsonBody;
+use crate::routes::SharedRGAs;
+use crate::share::Access;
+use crate::tenancy::Tenant;
+use crate::usage::{self, Metric};
+use crate::{Actor, ApiError, FieldError, RequestId};
+use chrono::Utc;
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::Json;
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use rocket::tokio::sync::{broadcast, mpsc};
+use rocket::tokio::{self, time};
+use rocket::{get, post, Shutdown};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use tracing::{error, info, instrument, warn, Instrument};
+use uuid::Uuid;
+
+/// Runs kept in memory for collaborators to read, across all documents.
+pub const RUN_HISTORY_LEN: usize = 256;
+
+/// The window the per-user quota is counted over.
+const QUOTA_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// How a language's programs are run.
+/// `command`: The program and its arguments. `{file}` is replaced with the path of the source
+/// file and `{dir}` with the directory holding it.
+/// `file`: The name the source is written to, e.g. `main.py`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LanguageRunner {
+    pub command: Vec<String>,
+    #[serde(default = "default_file")]
+    pub file: String,
+}
+
+fn default_file() -> String {
+    "main".to_string()
+}
+
+/// Execution of documents' code.
+/// `timeout_secs`: Seconds a run may take before it is killed.
+/// `max_output_bytes`: Output a run may write to stdout and stderr before it is killed.
+/// `runs_per_hour`: Runs each user may start per hour.
+/// `languages`: The runner of each language, code execution is disabled if there are none.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SandboxConfig {
+    pub timeout_secs: u64,
+    pub max_output_bytes: usize,
+    pub runs_per_hour: usize,
+    pub languages: BTreeMap<String, LanguageRunner>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig {
+            timeout_secs: 10,
+            max_output_bytes: 64 * 1024,
+            runs_per_hour: 30,
+            languages: BTreeMap::new(),
+        }
+    }
+}
+
+/// A program to run.
+/// `language`: The language of the source, one of the configured languages.
+/// `source`: The document's content.
+/// `stdin`: Written to the program's standard input.
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub language: String,
+    pub source: String,
+    pub stdin: String,
+}
+
+/// How a run ended.
+/// `code`: The exit code (None if the program was killed).
+/// `timed_out`: Whether the program was killed for exceeding the time limit.
+/// `truncated`: Whether the program was killed for exceeding the output limit.
+/// `duration_ms`: How long the program ran.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunExit {
+    pub code: Option<i32>,
+    pub timed_out: bool,
+    pub truncated: bool,
+    pub duration_ms: u64,
+}
+
+/// Output of a run, in the order it was produced.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunEvent {
+    Stdout(String),
+    Stderr(String),
+    Exit(RunExit),
+}
+
+impl RunEvent {
+    fn to_event(&self) -> Event {
+        match self {
+            RunEvent::Stdout(text) => Event::data(text.clone()).event("stdout"),
+            RunEvent::Stderr(text) => Event::data(text.clone()).event("stderr"),
+            RunEvent::Exit(exit) => Event::json(exit).event("exit"),
+        }
+    }
+}
+
+/// Runs programs in isolation.
+#[rocket::async_trait]
+pub trait Sandbox: Send + Sync {
+    /// Runs a program, sending its output as it is produced, and returns how it ended.
+    async fn run(
+        &self,
+        program: Program,
+        output: mpsc::Sender<RunEvent>,
+    ) -> Result<RunExit, ApiError>;
+}
+
+/// A sandbox that runs each language's configured command as a child process.
+pub struct ProcessSandbox {
+    config: SandboxConfig,
+}
+
+impl ProcessSandbox {
+    pub fn new(config: SandboxConfig) -> Self {
+        ProcessSandbox { config }
+    }
+
+    async fn run_in(
+        &self,
+        dir: &PathBuf,
+        program: Program,
+        output: mpsc::Sender<RunEvent>,
+    ) -> Result<RunExit, ApiError> {
+        let runner: &LanguageRunner = match self.config.languages.get(&program.language) {
+            Some(runner) if !runner.command.is_empty() => runner,
+            _ => {
+                return Err(ApiError::InvalidOperation(format!(
+                    "No runner is configured for {}",
+                    program.language
+                )))
+            }
+        };
+
+        let file: PathBuf = dir.join(&runner.file);
+        if tokio::fs::write(&file, program.source.as_bytes())
+            .await
+            .is_err()
+        {
+            error!("Failed to write the program's source");
+            return Err(ApiError::InternalServerError(
+                "Failed to prepare the sandbox".to_string(),
+            ));
+        }
+
+        let args: Vec<String> = runner
+            .command
+            .iter()
+            .map(|arg| {
+                arg.replace("{file}", &file.to_string_lossy())
+                    .replace("{dir}", &dir.to_string_lossy())
+            })
+            .collect();
+        let mut child = match Command::new(&args[0])
+            .args(&args[1..])
+            .current_dir(dir)
+            .env_clear()
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Failed to start {}: {}", args[0], e);
+                return Err(ApiError::InternalServerError(
+                    "Failed to start the sandbox".to_string(),
+                ));
+            }
+        };
+
+        let started: Instant = Instant::now();
+        let (mut stdin, mut stdout, mut stderr) =
+            match (child.stdin.take(), child.stdout.take(), child.stderr.take()) {
+                (Some(stdin), Some(stdout), Some(stderr)) => (stdin, stdout, stderr),
+                _ => {
+                    error!("Failed to open the sandbox's pipes");
+                    return Err(ApiError::InternalServerError(
+                        "Failed to start the sandbox".to_string(),
+                    ));
+                }
+            };
+        // programs that don't read their input close the pipe, which is not an error
+        let _ = stdin.write_all(program.stdin.as_bytes()).await;
+        drop(stdin);
+
+        let deadline = time::sleep(Duration::from_secs(self.config.timeout_secs));
+        tokio::pin!(deadline);
+
+        let (mut out_buf, mut err_buf) = ([0_u8; 4096], [0_u8; 4096]);
+        let (mut out_open, mut err_open) = (true, true);
+        let mut remaining: usize = self.config.max_output_bytes;
+        let (mut timed_out, mut truncated) = (false, false);
+
+        let status = loop {
+            let chunk: Option<RunEvent> = tokio::select! {
+                read = stdout.read(&mut out_buf), if out_open => match read {
+                    Ok(n) if n > 0 => {
+                        let taken: usize = n.min(remaining);
+                        truncated = taken < n;
+                        Some(RunEvent::Stdout(String::from_utf8_lossy(&out_buf[..taken]).into_owned()))
+                    }
+                    _ => {
+                        out_open = false;
+                        None
+                    }
+                },
+                read = stderr.read(&mut err_buf), if err_open => match read {
+                    Ok(n) if n > 0 => {
+                        let taken: usize = n.min(remaining);
+                        truncated = taken < n;
+                        Some(RunEvent::Stderr(String::from_utf8_lossy(&err_buf[..taken]).into_owned()))
+                    }
+                    _ => {
+                        err_open = false;
+                        None
+                    }
+                },
+                status = child.wait(), if !out_open && !err_open => break status.ok(),
+                _ = &mut deadline => {
+                    timed_out = true;
+                    None
+                }
+            };
+
+            if let Some(event) = chunk {
+                remaining -= match &event {
+                    RunEvent::Stdout(text) | RunEvent::Stderr(text) => text.len().min(remaining),
+                    RunEvent::Exit(_) => 0,
+                };
+                // the run continues even if no one is listening
+                let _ = output.send(event).await;
+            }
+            if timed_out || truncated {
+                let _ = child.kill().await;
+                break None;
+            }
+        };
+
+        Ok(RunExit {
+            code: status.and_then(|status| status.code()),
+            timed_out,
+            truncated,
+            duration_ms: started.elapsed().as_millis() as u64,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Sandbox for ProcessSandbox {
+    async fn run(
+        &self,
+        program: Program,
+        output: mpsc::Sender<RunEvent>,
+    ) -> Result<RunExit, ApiError> {
+        let dir: PathBuf = std::env::temp_dir().join(format!("nimble-run-{}", Uuid::new_v4()));
+        if tokio::fs::create_dir(&dir).await.is_err() {
+            error!("Failed to create the sandbox directory");
+            return Err(ApiError::InternalServerError(
+                "Failed to prepare the sandbox".to_string(),
+            ));
+        }
+
+        let result = self.run_in(&dir, program, output).await;
+        if tokio::fs::remove_dir_all(&dir).await.is_err() {
+            warn!("Failed to remove the sandbox directory {}", dir.display());
+        }
+        result
+    }
+}
+
+/// A run of a document's code.
+/// `run_id`: Identifies the run.
+/// `document_id`: The document that was run.
+/// `user_id`: The user that started the run.
+/// `language`: The language the document was run as.
+/// `started_at`: When the run started.
+/// `exit`: How the run ended (None while it is running).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub run_id: Uuid,
+    pub document_id: Uuid,
+    pub user_id: Uuid,
+    pub language: String,
+    pub started_at: String,
+    pub exit: Option<RunExit>,
+}
+
+struct RunRecord {
+    summary: RunSummary,
+    events: Vec<RunEvent>,
+    sender: broadcast::Sender<RunEvent>,
+}
+
+#[derive(Default)]
+struct RunsState {
+    runs: HashMap<Uuid, RunRecord>,
+    order: VecDeque<Uuid>,
+    usage: HashMap<Uuid, VecDeque<Instant>>,
+}
+
+/// Recent runs and their output, managed in Rocket's state.
+#[derive(Clone, Default)]
+pub struct Runs {
+    state: Arc<std::sync::Mutex<RunsState>>,
+}
+
+impl Runs {
+    /// Records a new run, unless the user has used their quota for the past hour.
+    pub fn start(
+        &self,
+        document_id: Uuid,
+        user_id: Uuid,
+        language: &str,
+        runs_per_hour: usize,
+    ) -> Result<RunSummary, ApiError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        let now: Instant = Instant::now();
+        let usage: &mut VecDeque<Instant> = state.usage.entry(user_id).or_default();
+        while usage
+            .front()
+            .is_some_and(|started| now.duration_since(*started) >= QUOTA_WINDOW)
+        {
+            usage.pop_front();
+        }
+        if usage.len() >= runs_per_hour {
+            return Err(ApiError::TooManyRequests(format!(
+                "Users may start {} runs per hour",
+                runs_per_hour
+            )));
+        }
+        usage.push_back(now);
+
+        let summary = RunSummary {
+            run_id: Uuid::new_v4(),
+            document_id,
+            user_id,
+            language: language.to_string(),
+            started_at: Utc::now().to_rfc3339(),
+            exit: None,
+        };
+        state.runs.insert(
+            summary.run_id,
+            RunRecord {
+                summary: summary.clone(),
+                events: Vec::new(),
+                sender: broadcast::channel(64).0,
+            },
+        );
+        state.order.push_back(summary.run_id);
+        if state.order.len() > RUN_HISTORY_LEN {
+            if let Some(oldest) = state.order.pop_front() {
+                state.runs.remove(&oldest);
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Adds output to a run and sends it to the collaborators following it.
+    pub fn push(&self, run_id: Uuid, event: RunEvent) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(run) = state.runs.get_mut(&run_id) {
+            if let RunEvent::Exit(exit) = &event {
+                run.summary.exit = Some(exit.clone());
+            }
+            run.events.push(event.clone());
+            let _ = run.sender.send(event);
+        }
+    }
+
+    /// The output of a run so far, with a receiver for the rest (None if it has ended).
+    fn follow(
+        &self,
+        document_id: Uuid,
+        run_id: Uuid,
+    ) -> Option<(Vec<RunEvent>, Option<broadcast::Receiver<RunEvent>>)> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let run: &RunRecord = state
+            .runs
+            .get(&run_id)
+            .filter(|run| run.summary.document_id == document_id)?;
+        let receiver = match run.summary.exit {
+            Some(_) => None,
+            None => Some(run.sender.subscribe()),
+        };
+        Some((run.events.clone(), receiver))
+    }
+
+    /// The recent runs of a document, oldest first.
+    pub fn list(&self, document_id: Uuid) -> Vec<RunSummary> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state
+            .order
+            .iter()
+            .filter_map(|run_id| state.runs.get(run_id))
+            .filter(|run| run.summary.document_id == document_id)
+            .map(|run| run.summary.clone())
+            .collect()
+    }
+}
+
+/// Request body for running a document.
+/// `language`: The language to run the document as.
+/// `stdin`: Written to the program's standard input.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RunRequest {
+    pub language: String,
+    #[serde(default)]
+    pub stdin: String,
+}
+
+fn parse_document_id(id: &str) -> Result<Uuid, ApiError> {
+    match Uuid::parse_str(id) {
+        Ok(id) => Ok(id),
+        Err(_) => {
+            error!("Failed to parse document id");
+            Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ))
+        }
+    }
+}
+
+/// Runs the current content of a loaded document in the sandbox. The run continues in the
+/// background and its output is read from `GET /document/<id>/runs/<run_id>`.
+/// Requires a session, whose user's runs per hour are limited.
+#[post("/document/<id>/run", data = "<request>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn run_document(
+    id: String,
+    request: JsonBody<RunRequest>,
+    actor: Actor,
+    rgas: &rocket::State<SharedRGAs>,
+    pool: &rocket::State<Pool>,
+    features: &rocket::State<Features>,
+    sandbox: &rocket::State<Arc<dyn Sandbox>>,
+    config: &rocket::State<SandboxConfig>,
+    runs: &rocket::State<Runs>,
+    request_id: RequestId,
+) -> Result<Json<RunSummary>, ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+
+    if config.languages.is_empty() {
+        error!("Code execution is disabled");
+        return Err(ApiError::Forbidden(
+            "Code execution is disabled on this replica".to_string(),
+        ));
+    }
+    let user_id: Uuid = actor.require_user()?;
+    if !config.languages.contains_key(&request.language) {
+        error!("Rejected run of an unconfigured language");
+        return Err(ApiError::ValidationFailed(vec![FieldError::new(
+            "language",
+            &format!(
+                "must be one of {}",
+                config
+                    .languages
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        )]));
+    }
+
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
+    features.require(Feature::Execution, &tenant)?;
+    let source: String = match rgas.lock().await.get(&document_id) {
+        Some(rga) => rga.text().to_string(),
+        None => {
+            error!("Document not found");
+            return Err(ApiError::NotFound(String::from("Document not found")));
+        }
+    };
+
+    let summary: RunSummary = runs.start(
+        document_id,
+        user_id,
+        &request.language,
+        config.runs_per_hour,
+    )?;
+    let program = Program {
+        language: request.language.clone(),
+        source,
+        stdin: request.0.stdin,
+    };
+
+    let (sandbox, runs, run_id) = (Arc::clone(sandbox), runs.inner().clone(), summary.run_id);
+    tokio::spawn(
+        async move {
+            let (sender, mut receiver) = mpsc::channel::<RunEvent>(64);
+            let forward = async {
+                while let Some(event) = receiver.recv().await {
+                    runs.push(run_id, event);
+                }
+            };
+            let (result, _) = tokio::join!(sandbox.run(program, sender), forward);
+
+            let exit: RunExit = match result {
+                Ok(exit) => exit,
+                Err(e) => {
+                    runs.push(run_id, RunEvent::Stderr(e.to_string()));
+                    RunExit {
+                        code: None,
+                        timed_out: false,
+                        truncated: false,
+                        duration_ms: 0,
+                    }
+                }
+            };
+            info!(code = ?exit.code, timed_out = exit.timed_out, "Run finished");
+            usage::record(
+                &tenant,
+                document_id,
+                Metric::ExecutionSeconds,
+                exit.duration_ms as f64 / 1000.0,
+            );
+            runs.push(run_id, RunEvent::Exit(exit));
+        }
+        .in_current_span(),
+    );
+
+    info!(run_id = %summary.run_id, "Started run");
+    Ok(Json(summary))
+}
+
+/// Lists the recent runs of a document.
+#[get("/document/<id>/runs")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn list_runs(
+    id: String,
+    actor: Actor,
+    pool: &rocket::State<Pool>,
+    runs: &rocket::State<Runs>,
+    request_id: RequestId,
+) -> Result<Json<Vec<RunSummary>>, ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+    actor.authorize(pool, &document_id, Access::Read).await?;
+    Ok(Json(runs.list(document_id)))
+}
+
+/// The output of a run so far and a receiver for the rest, or `NotFound`.
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+async fn find_run(
+    id: &str,
+    run_id: &str,
+    actor: &Actor,
+    pool: &Pool,
+    runs: &Runs,
+    request_id: &RequestId,
+) -> Result<(Vec<RunEvent>, Option<broadcast::Receiver<RunEvent>>), ApiError> {
+    let document_id: Uuid = parse_document_id(id)?;
+    actor.authorize(pool, &document_id, Access::Read).await?;
+    let followed = Uuid::parse_str(run_id)
+        .ok()
+        .and_then(|run_id| runs.follow(document_id, run_id));
+    match followed {
+        Some(followed) => Ok(followed),
+        None => {
+            error!("Run not found");
+            Err(ApiError::NotFound(String::from("Run not found")))
+        }
+    }
+}
+
+/// Streams the output of a run as server-sent events: `stdout` and `stderr` events with the
+/// output as it was produced, then an `exit` event with how the run ended. Output produced
+/// before the request is replayed first, so any collaborator can follow a run.
+#[get("/document/<id>/runs/<run_id>")]
+pub async fn follow_run(
+    id: String,
+    run_id: String,
+    actor: Actor,
+    pool: &rocket::State<Pool>,
+    runs: &rocket::State<Runs>,
+    mut shutdown: Shutdown,
+    request_id: RequestId,
+) -> Result<EventStream![], ApiError> {
+    let (history, receiver) = find_run(&id, &run_id, &actor, pool, runs, &request_id).await?;
+
+    Ok(EventStream! {
+        for event in history {
+            yield event.to_event();
+        }
+        if let Some(mut receiver) = receiver {
+            loop {
+                let event: RunEvent = tokio::select! {
+                    event = receiver.recv() => match event {
+                        Ok(event) => event,
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(_) => break,
+                    },
+                    _ = &mut shutdown => break,
+                };
+                let done: bool = matches!(event, RunEvent::Exit(_));
+                yield event.to_event();
+                if done {
+                    break;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn run(config: SandboxConfig, source: &str) -> (Vec<RunEvent>, RunExit) {
+        let sandbox = ProcessSandbox::new(config);
+        let (sender, mut receiver) = mpsc::channel(64);
+        let program = Program {
+            language: "sh".to_string(),
+            source: source.to_string(),
+            stdin: "input".to_string(),
+        };
+        let exit = sandbox.run(program, sender).await.unwrap();
+        let mut events = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            events.push(event);
+        }
+        (events, exit)
+    }
+
+    fn shell(timeout_secs: u64, max_output_bytes: usize) -> SandboxConfig {
+        SandboxConfig {
+            timeout_secs,
+            max_output_bytes,
+            runs_per_hour: 1,
+            languages: BTreeMap::from([(
+                "sh".to_string(),
+                LanguageRunner {
+                    command: vec!["/bin/sh".to_string(), "{file}".to_string()],
+                    file: "main.sh".to_string(),
+                },
+            )]),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_process_sandbox() {
+        let (events, exit) = run(
+            shell(10, 1024),
+            "read line; echo $line; echo oops >&2; exit 3",
+        )
+        .await;
+        assert!(events.contains(&RunEvent::Stdout("input\n".to_string())));
+        assert!(events.contains(&RunEvent::Stderr("oops\n".to_string())));
+        assert_eq!(exit.code, Some(3));
+        assert!(!exit.timed_out && !exit.truncated);
+
+        let (_, exit) = run(shell(1, 1024), "sleep 5").await;
+        assert!(exit.timed_out);
+        assert_eq!(exit.code, None);
+
+        let (events, exit) = run(shell(10, 4), "echo 0123456789").await;
+        assert!(exit.truncated);
+        assert_eq!(events, vec![RunEvent::Stdout("0123".to_string())]);
+    }
+
+    #[test]
+    fn test_run_quota() {
+        let runs = Runs::default();
+        let (document_id, user_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let run = runs.start(document_id, user_id, "sh", 1).unwrap();
+        assert!(matches!(
+            runs.start(document_id, user_id, "sh", 1),
+            Err(ApiError::TooManyRequests(_))
+        ));
+        assert!(runs.start(document_id, Uuid::new_v4(), "sh", 1).is_ok());
+
+        runs.push(run.run_id, RunEvent::Stdout("hi".to_string()));
+        let (history, receiver) = runs.follow(document_id, run.run_id).unwrap();
+        assert_eq!(history.len(), 1);
+        assert!(receiver.is_some());
+        assert!(runs.follow(Uuid::new_v4(), run.run_id).is_none());
+
+        runs.push(
+            run.run_id,
+            RunEvent::Exit(RunExit {
+                code: Some(0),
+                timed_out: false,
+                truncated: false,
+                duration_ms: 1,
+            }),
+        );
+        assert!(runs.follow(document_id, run.run_id).unwrap().1.is_none());
+        assert_eq!(runs.list(document_id).len(), 2);
+    }
+}
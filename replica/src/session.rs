@@ -0,0 +1,68 @@
+use crate::ApiError;
+use rocket::fairing::AdHoc;
+use rocket::tokio::sync::Mutex;
+use std::sync::Arc;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument};
+
+/// The identity used in the s4vectors created by this replica.
+/// `replica_id`: The site id (`sid`) of the replica.
+/// `session_id`: The session number (`ssn`) of the current process, incremented on every start
+/// so vectors generated after a restart never collide with ones generated before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    pub replica_id: u64,
+    pub session_id: u64,
+}
+
+/// Increments and returns the replica's session counter in the `replica_sessions` table.
+/// The first start of a replica gets session 1.
+#[instrument(name = "db.next_session", skip(client))]
+pub async fn next_session(client: &Client, replica_id: i64) -> Result<i64, ApiError> {
+    match client
+        .query_one(
+            "INSERT INTO replica_sessions (replica_id,session) VALUES ($1,1) \
+             ON CONFLICT (replica_id) DO UPDATE SET session = replica_sessions.session + 1 \
+             RETURNING session",
+            &[&replica_id],
+        )
+        .await
+    {
+        Ok(row) => Ok(row.get(0)),
+        Err(_) => {
+            error!("Failed to increment the replica session counter");
+            Err(ApiError::DatabaseError(
+                "Failed to increment the replica session counter".to_string(),
+            ))
+        }
+    }
+}
+
+/// Fairing that starts a new session for the replica and manages the resulting [`Session`].
+/// Must be attached after the database fairing.
+pub fn attach_session(replica_id: i64) -> AdHoc {
+    AdHoc::on_ignite("Start replica session", move |rocket| async move {
+        let db = match rocket.state::<Arc<Mutex<Client>>>() {
+            Some(db) => Arc::clone(db),
+            None => {
+                error!("Unable to start server, the database is not attached");
+                std::process::exit(1);
+            }
+        };
+
+        let session_id: i64 = match next_session(&*db.lock().await, replica_id).await {
+            Ok(session_id) => session_id,
+            Err(e) => {
+                error!("Unable to start server, failed to start a session: {}", e);
+                eprintln!("Failed to start replica session: {:?}", e);
+                std::process::exit(1);
+            }
+        };
+        info!("Starting replica {} session {}", replica_id, session_id);
+
+        rocket.manage(Session {
+            replica_id: replica_id as u64,
+            session_id: session_id as u64,
+        })
+    })
+}
@@ -0,0 +1,342 @@
+//! Share links: signed, expiring tokens granting access to one document without an account.
+//!
+//! The owner of a document creates a link with `POST /document/<id>/share_link`, choosing
+//! read-only or read-write access and how long it lasts. The token is
+//! `<document_id>.<access>.<expires_at>.<signature>`, signed with HMAC-SHA256 under
+//! `share.secret`, so any replica configured with the same secret accepts it without a lookup.
+//! Requests carry the token in `X-Share-Token` or the `share` query parameter, the `Actor`
+//! guard verifies it and routes check it with `Actor::authorize`.
+
+use crate::pool::Pool;
+use crate::flags::{Feature, Features};
+use crate::limits::JsonBody;
+use crate::tenancy::{self, Tenant};
+use crate::{audit, expiry, Actor, ApiError, FieldError, RequestId};
+use hmac::{Hmac, Mac};
+use rocket::post;
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// Header carrying a share token.
+pub const SHARE_TOKEN_HEADER: &str = "X-Share-Token";
+
+/// Query parameter carrying a share token, used by links.
+pub const SHARE_TOKEN_PARAM: &str = "share";
+
+/// The shortest secret accepted, in bytes.
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// `secret`: The key share tokens are signed with, the same on every replica (None disables
+/// share links).
+/// `default_ttl_secs`: How long a link lasts when the request does not say.
+/// `max_ttl_secs`: The longest a link may last.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShareConfig {
+    pub secret: Option<String>,
+    pub default_ttl_secs: u64,
+    pub max_ttl_secs: u64,
+}
+
+impl Default for ShareConfig {
+    fn default() -> Self {
+        ShareConfig {
+            secret: None,
+            default_ttl_secs: 24 * 60 * 60,
+            max_ttl_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// What a share token allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    Read,
+    ReadWrite,
+}
+
+impl Access {
+    fn code(&self) -> &'static str {
+        match self {
+            Access::Read => "r",
+            Access::ReadWrite => "rw",
+        }
+    }
+
+    fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "r" => Some(Access::Read),
+            "rw" => Some(Access::ReadWrite),
+            _ => None,
+        }
+    }
+
+    /// The access as it is stored in the database, the same as in JSON.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::ReadWrite => "read_write",
+        }
+    }
+
+    pub fn parse(access: &str) -> Option<Self> {
+        match access {
+            "read" => Some(Access::Read),
+            "read_write" => Some(Access::ReadWrite),
+            _ => None,
+        }
+    }
+}
+
+/// The access granted by a verified share token.
+/// `document_id`: The only document the token can be used on.
+/// `access`: Read-only or read-write.
+/// `expires_at`: Unix time (seconds) after which the token is rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShareGrant {
+    pub document_id: Uuid,
+    pub access: Access,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ShareError {
+    #[error("Share links are disabled on this replica")]
+    Disabled,
+    #[error("The share token is malformed")]
+    Malformed,
+    #[error("The share token signature is invalid")]
+    InvalidSignature,
+    #[error("The share token has expired")]
+    Expired,
+}
+
+pub(crate) fn mac(secret: &str) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length")
+}
+
+fn payload(grant: &ShareGrant) -> String {
+    format!(
+        "{}.{}.{}",
+        grant.document_id,
+        grant.access.code(),
+        grant.expires_at
+    )
+}
+
+/// Signs a grant, returning its token.
+pub fn sign(grant: &ShareGrant, secret: &str) -> String {
+    let payload: String = payload(grant);
+    let mut mac = mac(secret);
+    mac.update(payload.as_bytes());
+    format!("{}.{}", payload, hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Verifies a token's signature and expiry, returning the access it grants.
+pub fn verify(token: &str, secret: &str, now: i64) -> Result<ShareGrant, ShareError> {
+    let (payload, signature) = token.rsplit_once('.').ok_or(ShareError::Malformed)?;
+    let signature: Vec<u8> = hex::decode(signature).map_err(|_| ShareError::Malformed)?;
+
+    let mut mac = mac(secret);
+    mac.update(payload.as_bytes());
+    // compares in constant time
+    mac.verify_slice(&signature)
+        .map_err(|_| ShareError::InvalidSignature)?;
+
+    let mut parts = payload.split('.');
+    let grant = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(document_id), Some(access), Some(expires_at), None) => ShareGrant {
+            document_id: Uuid::parse_str(document_id).map_err(|_| ShareError::Malformed)?,
+            access: Access::from_code(access).ok_or(ShareError::Malformed)?,
+            expires_at: expires_at.parse().map_err(|_| ShareError::Malformed)?,
+        },
+        _ => return Err(ShareError::Malformed),
+    };
+    if grant.expires_at <= now {
+        return Err(ShareError::Expired);
+    }
+    Ok(grant)
+}
+
+/// Request body for creating a share link.
+/// `access`: `read` or `read_write`.
+/// `ttl_secs`: How long the link lasts (defaults to `share.default_ttl_secs`).
+#[derive(Debug, Deserialize)]
+pub struct ShareLinkRequest {
+    pub access: Access,
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+/// A created share link.
+/// `token`: The token, sent as `X-Share-Token` or the `share` query parameter.
+/// `link`: The document's path with the token.
+/// `expires_at`: When the link stops working.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareLink {
+    pub document_id: Uuid,
+    pub access: Access,
+    pub token: String,
+    pub link: String,
+    pub expires_at: String,
+}
+
+impl ShareLinkRequest {
+    /// Returns how long the link lasts, or the invalid field.
+    pub fn ttl_secs(&self, config: &ShareConfig) -> Result<u64, FieldError> {
+        match self.ttl_secs.unwrap_or(config.default_ttl_secs) {
+            0 => Err(FieldError::new("ttl_secs", "must be greater than 0")),
+            ttl if ttl > config.max_ttl_secs => Err(FieldError::new(
+                "ttl_secs",
+                &format!("must be at most {}", config.max_ttl_secs),
+            )),
+            ttl => Ok(ttl),
+        }
+    }
+}
+
+/// Creates a link granting access to a document without an account.
+/// Only the document's owner can share it.
+///
+/// Example Request:
+/// {
+///     "access": "read_write",
+///     "ttl_secs": 3600
+/// }
+///
+/// Example Response:
+/// {
+///     "document_id" : "3f1b8a52-6f0e-4c7a-9a59-2d8c1f0b7e41",
+///     "access" : "read_write",
+///     "token" : "3f1b8a52-6f0e-4c7a-9a59-2d8c1f0b7e41.rw.1736000000.5d41402abc4b2a76b9719d911017c592...",
+///     "link" : "/document/3f1b8a52-6f0e-4c7a-9a59-2d8c1f0b7e41?share=3f1b8a52-...",
+///     "expires_at" : "2025-01-04T14:13:20Z"
+/// }
+#[post("/document/<id>/share_link", data = "<request>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn create_share_link(
+    id: String,
+    request: JsonBody<ShareLinkRequest>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    features: &rocket::State<Features>,
+    config: &rocket::State<ShareConfig>,
+    request_id: RequestId,
+) -> Result<Json<ShareLink>, ApiError> {
+    let secret: &str = match &config.secret {
+        Some(secret) => secret,
+        None => {
+            error!("Share link requested while share links are disabled");
+            return Err(ApiError::Forbidden(ShareError::Disabled.to_string()));
+        }
+    };
+    let document_id: Uuid = match Uuid::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => {
+            error!("Failed to parse document id");
+            return Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ));
+        }
+    };
+
+    if actor.share.is_some() {
+        error!("Share link requested with a share token");
+        return Err(ApiError::Forbidden(
+            "A share link can't be used to create another".to_string(),
+        ));
+    }
+    let user_id: Uuid = actor.require_user()?;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
+    features.require(Feature::ShareLinks, &tenant)?;
+    {
+        let client = tenancy::lock(db, &tenant).await?;
+        if audit::document_owner(&client, &document_id).await? != user_id {
+            error!("Share link requested by a user who does not own the document");
+            return Err(ApiError::Forbidden(
+                "Only the document owner can share it".to_string(),
+            ));
+        }
+    }
+
+    let ttl_secs: u64 = match request.ttl_secs(config) {
+        Ok(ttl_secs) => ttl_secs,
+        Err(error) => return Err(ApiError::ValidationFailed(vec![error])),
+    };
+    let expires = chrono::Utc::now() + chrono::Duration::seconds(ttl_secs as i64);
+    let grant = ShareGrant {
+        document_id,
+        access: request.access,
+        expires_at: expires.timestamp(),
+    };
+    let token: String = sign(&grant, secret);
+
+    info!(access = ?grant.access, ttl_secs, "Share link created");
+    Ok(Json(ShareLink {
+        document_id,
+        access: grant.access,
+        link: format!("/document/{}?{}={}", document_id, SHARE_TOKEN_PARAM, token),
+        token,
+        expires_at: expiry::timestamp(expires),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access() {
+        for access in [Access::Read, Access::ReadWrite] {
+            assert_eq!(Access::parse(access.as_str()), Some(access));
+            assert_eq!(Access::from_code(access.code()), Some(access));
+        }
+        assert_eq!(Access::parse("rw"), None);
+        assert!(Access::Read < Access::ReadWrite);
+    }
+
+    #[test]
+    fn test_share_token() {
+        let secret = "s".repeat(MIN_SECRET_LEN);
+        let grant = ShareGrant {
+            document_id: Uuid::new_v4(),
+            access: Access::Read,
+            expires_at: 1_000,
+        };
+        let token = sign(&grant, &secret);
+        assert_eq!(verify(&token, &secret, 999), Ok(grant));
+        assert_eq!(verify(&token, &secret, 1_000), Err(ShareError::Expired));
+        assert_eq!(
+            verify(&token, &"t".repeat(MIN_SECRET_LEN), 999),
+            Err(ShareError::InvalidSignature)
+        );
+
+        // the access can't be raised without the secret
+        let forged = token.replacen(".r.", ".rw.", 1);
+        assert_eq!(
+            verify(&forged, &secret, 999),
+            Err(ShareError::InvalidSignature)
+        );
+        assert_eq!(verify("token", &secret, 999), Err(ShareError::Malformed));
+
+        let config = ShareConfig::default();
+        let request = |ttl_secs| ShareLinkRequest {
+            access: Access::ReadWrite,
+            ttl_secs,
+        };
+        assert_eq!(request(None).ttl_secs(&config), Ok(config.default_ttl_secs));
+        assert!(request(Some(0)).ttl_secs(&config).is_err());
+        assert!(request(Some(config.max_ttl_secs + 1))
+            .ttl_secs(&config)
+            .is_err());
+    }
+}
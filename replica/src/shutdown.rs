@@ -0,0 +1,45 @@
+use crate::routes::SharedRGAs;
+use crate::{shutdown_tracing, snapshot, tenancy};
+use rocket::fairing::AdHoc;
+use rocket::tokio::sync::Mutex;
+use std::sync::Arc;
+use tokio_postgres::Client;
+use tracing::{info, warn};
+
+/// Fairing that flushes the replica's in-memory state when Rocket shuts down (SIGTERM or ctrl-c).
+///
+/// Rocket stops accepting new requests before shutdown fairings run. Edits hold the document
+/// lock until their operation is stored and published to SNS, so acquiring the lock waits for
+/// in-flight edits and their broadcasts to drain. A final snapshot of every dirty RGA is then
+/// persisted and any buffered spans are exported.
+pub fn attach_shutdown() -> AdHoc {
+    AdHoc::on_shutdown("Flush state on shutdown", |rocket| {
+        Box::pin(async move {
+            info!("Shutdown started, waiting for in-flight edits");
+
+            let (rgas, db) = match (
+                rocket.state::<SharedRGAs>(),
+                rocket.state::<Arc<Mutex<Client>>>(),
+            ) {
+                (Some(rgas), Some(db)) => (rgas, db),
+                _ => {
+                    warn!("Replica state is unavailable, skipping the shutdown flush");
+                    shutdown_tracing();
+                    return;
+                }
+            };
+
+            let mut rgas = rgas.lock().await;
+            let mut client = tenancy::lock_any(db).await;
+
+            let dirty: usize = rgas.values().filter(|rga| rga.dirty).count();
+            let persisted: usize = snapshot::flush_dirty(&mut client, &mut rgas).await;
+            info!(
+                "Persisted {} of {} dirty documents before shutdown",
+                persisted, dirty
+            );
+
+            shutdown_tracing();
+        })
+    })
+}
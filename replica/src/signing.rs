@@ -0,0 +1,117 @@
+//! Signing of requests to AWS services the SDK isn't used for.
+
+use crate::ApiError;
+use aws_credential_types::provider::{ProvideCredentials, SharedCredentialsProvider};
+use aws_sigv4::http_request::{
+    sign, PayloadChecksumKind, SignableBody, SignableRequest, SigningSettings,
+    UriPathNormalizationMode,
+};
+use aws_sigv4::sign::v4;
+use std::time::SystemTime;
+use tracing::error;
+
+/// Sends requests to an AWS service signed with SigV4 and the replica's AWS credentials.
+/// `service`: The signing name of the service, such as `s3` or `sqs`.
+pub struct AwsSigner {
+    http: reqwest::Client,
+    credentials: SharedCredentialsProvider,
+    region: String,
+    service: &'static str,
+}
+
+impl AwsSigner {
+    pub fn new(
+        credentials: SharedCredentialsProvider,
+        region: &str,
+        service: &'static str,
+    ) -> AwsSigner {
+        AwsSigner {
+            http: reqwest::Client::new(),
+            credentials,
+            region: region.to_string(),
+            service,
+        }
+    }
+
+    /// Signs and sends a request, `headers` are signed along with the host.
+    pub async fn send(
+        &self,
+        method: reqwest::Method,
+        url: reqwest::Url,
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, ApiError> {
+        let credentials = match self.credentials.provide_credentials().await {
+            Ok(credentials) => credentials,
+            Err(_) => {
+                error!("Failed to load AWS credentials");
+                return Err(ApiError::InternalServerError(
+                    "Failed to load AWS credentials".to_string(),
+                ));
+            }
+        };
+
+        let host: String = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let mut settings = SigningSettings::default();
+        if self.service == "s3" {
+            settings.payload_checksum_kind = PayloadChecksumKind::XAmzSha256;
+            settings.uri_path_normalization_mode = UriPathNormalizationMode::Disabled;
+        }
+
+        let identity = credentials.into();
+        let params = match v4::SigningParams::builder()
+            .identity(&identity)
+            .region(&self.region)
+            .name(self.service)
+            .time(SystemTime::now())
+            .settings(settings)
+            .build()
+        {
+            Ok(params) => params.into(),
+            Err(_) => {
+                error!("Failed to build {} signing parameters", self.service);
+                return Err(ApiError::InternalServerError(format!(
+                    "Failed to sign {} request",
+                    self.service
+                )));
+            }
+        };
+
+        let signable = SignableRequest::new(
+            method.as_str(),
+            url.as_str(),
+            std::iter::once(("host", host.as_str())).chain(headers.iter().copied()),
+            SignableBody::Bytes(&body),
+        );
+        let instructions = match signable.and_then(|request| sign(request, &params)) {
+            Ok(output) => output.into_parts().0,
+            Err(_) => {
+                error!("Failed to sign {} request", self.service);
+                return Err(ApiError::InternalServerError(format!(
+                    "Failed to sign {} request",
+                    self.service
+                )));
+            }
+        };
+
+        let mut request = self.http.request(method, url).body(body);
+        for (name, value) in headers.iter().copied().chain(instructions.headers()) {
+            request = request.header(name, value);
+        }
+
+        match request.send().await {
+            Ok(response) => Ok(response),
+            Err(e) => {
+                error!("Failed to reach {}: {}", self.service, e);
+                Err(ApiError::RequestFailed(format!(
+                    "Failed to reach {}",
+                    self.service
+                )))
+            }
+        }
+    }
+}
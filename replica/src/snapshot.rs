@@ -0,0 +1,779 @@
+use crate::history::{self, LogTail, LoggedOperation};
+use crate::query_metrics::QueryTimer;
+use crate::rga::rga::{Node, RGA};
+use crate::routes::SharedRGAs;
+use crate::{encryption, tenancy, ApiError, DocumentSnapshot, S4Vector, Session};
+use chrono::{DateTime, Utc};
+use rocket::fairing::AdHoc;
+use rocket::tokio::{self, sync::Mutex};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_postgres::Client;
+use tracing::{error, info, info_span, instrument, warn, Instrument};
+use uuid::Uuid;
+
+/// How far before a checkpoint's watermark the operations log is read when loading from it.
+/// Every operation inserted after the checkpoint's log position is read, including those the
+/// write-ahead log replays late with their original timestamp. Operations other replicas
+/// inserted before that position may still reach this one after its checkpoint, so those
+/// logged within the slack are read as well.
+const WATERMARK_SLACK: chrono::Duration = chrono::Duration::minutes(5);
+
+/// A document's checkpoint.
+/// `nodes`: Every node of the document in document order.
+/// `watermark`: When the checkpoint was taken.
+/// `log_position`: The last position of the operations log when the checkpoint was taken
+/// (None in checkpoints written before it was kept).
+#[derive(Debug)]
+pub struct Checkpoint {
+    pub nodes: Vec<Node>,
+    pub watermark: DateTime<Utc>,
+    pub log_position: Option<i64>,
+}
+
+/// The first bytes of a binary checkpoint. Checkpoints written before are JSON arrays of
+/// [`CheckpointNode`], which are still read.
+const CHECKPOINT_MAGIC: &[u8] = b"NCK1";
+
+/// The flags of a node in a binary checkpoint, saying which of its fields follow.
+const TOMBSTONE: u8 = 1;
+const LEFT_ORIGIN: u8 = 1 << 1;
+const RIGHT_ORIGIN: u8 = 1 << 2;
+const MOVED: u8 = 1 << 3;
+const AUTHOR: u8 = 1 << 4;
+const AUTHORED_AT: u8 = 1 << 5;
+
+/// A node of a checkpoint, which stores a document's nodes in document order.
+/// `origins`: The node's left and right origins (None in checkpoints written before they
+/// were kept).
+/// `moved`: The s4vector of the last move applied to the node.
+#[derive(Debug, PartialEq, Deserialize)]
+struct CheckpointNode {
+    s4vector: S4Vector,
+    value: String,
+    tombstone: bool,
+    author: Option<Uuid>,
+    authored_at: Option<String>,
+    #[serde(default)]
+    origins: Option<[Option<S4Vector>; 2]>,
+    #[serde(default)]
+    moved: Option<S4Vector>,
+}
+
+/// Encodes the nodes of a checkpoint after [`CHECKPOINT_MAGIC`]. Each node is a byte of
+/// flags, its s4vector, origins and last move as varints, its author's 16 bytes, then its
+/// value and the time it was authored prefixed by their length. Most nodes are a character
+/// or a line, so this is several times smaller than the same nodes in JSON.
+fn encode_checkpoint(nodes: &[CheckpointNode]) -> Vec<u8> {
+    let mut out: Vec<u8> = CHECKPOINT_MAGIC.to_vec();
+    for node in nodes {
+        let [left, right] = node.origins.unwrap_or_default();
+        let flags: u8 = [
+            (node.tombstone, TOMBSTONE),
+            (left.is_some(), LEFT_ORIGIN),
+            (right.is_some(), RIGHT_ORIGIN),
+            (node.moved.is_some(), MOVED),
+            (node.author.is_some(), AUTHOR),
+            (node.authored_at.is_some(), AUTHORED_AT),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        out.push(flags);
+
+        node.s4vector.write_varint(&mut out);
+        for s4vector in [left, right, node.moved].into_iter().flatten() {
+            s4vector.write_varint(&mut out);
+        }
+        if let Some(author) = node.author {
+            out.extend_from_slice(author.as_bytes());
+        }
+        for text in std::iter::once(&node.value).chain(&node.authored_at) {
+            out.extend_from_slice(&(text.len() as u32).to_be_bytes());
+            out.extend_from_slice(text.as_bytes());
+        }
+    }
+    out
+}
+
+/// Decodes a checkpoint written by [`encode_checkpoint`], or as JSON before it. Returns None
+/// if the checkpoint is truncated or malformed.
+fn decode_checkpoint(bytes: &[u8]) -> Option<Vec<CheckpointNode>> {
+    let Some(bytes) = bytes.strip_prefix(CHECKPOINT_MAGIC) else {
+        return serde_json::from_slice(bytes).ok();
+    };
+
+    let mut reader = CheckpointReader(bytes);
+    let mut nodes: Vec<CheckpointNode> = Vec::new();
+    while !reader.0.is_empty() {
+        let flags: u8 = reader.take(1)?[0];
+        let s4vector: S4Vector = reader.s4vector()?;
+        let mut optional = |flag: u8| match flags & flag {
+            0 => Some(None),
+            _ => reader.s4vector().map(Some),
+        };
+        let left: Option<S4Vector> = optional(LEFT_ORIGIN)?;
+        let right: Option<S4Vector> = optional(RIGHT_ORIGIN)?;
+        let moved: Option<S4Vector> = optional(MOVED)?;
+        let author: Option<Uuid> = match flags & AUTHOR {
+            0 => None,
+            _ => Some(Uuid::from_slice(reader.take(16)?).ok()?),
+        };
+        let value: String = reader.string()?;
+        let authored_at: Option<String> = match flags & AUTHORED_AT {
+            0 => None,
+            _ => Some(reader.string()?),
+        };
+        nodes.push(CheckpointNode {
+            s4vector,
+            value,
+            tombstone: flags & TOMBSTONE != 0,
+            author,
+            authored_at,
+            origins: Some([left, right]),
+            moved,
+        });
+    }
+    Some(nodes)
+}
+
+/// Reads the fields of a binary checkpoint from the front of the bytes left.
+struct CheckpointReader<'a>(&'a [u8]);
+
+impl<'a> CheckpointReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn s4vector(&mut self) -> Option<S4Vector> {
+        let (s4vector, read) = S4Vector::read_varint(self.0)?;
+        self.0 = &self.0[read..];
+        Some(s4vector)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len: [u8; 4] = self.take(4)?.try_into().ok()?;
+        let text: &[u8] = self.take(u32::from_be_bytes(len) as usize)?;
+        String::from_utf8(text.to_vec()).ok()
+    }
+}
+
+impl From<&Node> for CheckpointNode {
+    fn from(node: &Node) -> Self {
+        CheckpointNode {
+            s4vector: node.s4vector,
+            value: node.value.clone(),
+            tombstone: node.tombstone,
+            author: node.author,
+            authored_at: node.authored_at.clone(),
+            origins: Some([node.left, node.origin_right]),
+            moved: node.moved,
+        }
+    }
+}
+
+/// Turns the nodes of a checkpoint back into RGA nodes. Nodes without origins are taken to
+/// be inserted between their neighbours, like the rows of `document_snapshots`.
+fn checkpoint_nodes(nodes: Vec<CheckpointNode>) -> Vec<Node> {
+    let order: Vec<S4Vector> = nodes.iter().map(|node| node.s4vector).collect();
+    nodes
+        .into_iter()
+        .enumerate()
+        .map(|(i, node)| {
+            let [left, right] = node.origins.unwrap_or([
+                i.checked_sub(1).map(|left| order[left]),
+                order.get(i + 1).copied(),
+            ]);
+            let mut loaded: Node = Node::new(node.value, node.s4vector, left, right);
+            loaded.tombstone = node.tombstone;
+            loaded.author = node.author;
+            loaded.authored_at = node.authored_at;
+            loaded.moved = node.moved;
+            loaded
+        })
+        .collect()
+}
+
+/// Reads a document's rows in `document_snapshots`, ordered by s4vector, with their values
+/// decrypted.
+#[instrument(name = "db.load_snapshots", skip(client))]
+pub async fn load_snapshots(
+    client: &Client,
+    document_id: &Uuid,
+) -> Result<Vec<DocumentSnapshot>, ApiError> {
+    let mut timer = QueryTimer::start("load_snapshots").param("document_id", document_id);
+    let query = match client
+        .prepare(
+            "SELECT * from document_snapshots WHERE document_id=$1 ORDER BY ssn, sum, sid,seq;",
+        )
+        .await
+    {
+        Ok(q) => q,
+        Err(_) => {
+            error!("Failed to prepare select query for document_snapshot table");
+            return Err(ApiError::DatabaseError(
+                "Failed to prepare select statement for document_snapshot table.".to_string(),
+            ));
+        }
+    };
+
+    let rows = match client.query(&query, &[document_id]).await {
+        Ok(r) => {
+            info!("Successfull seelect statement for the document_snapshot table");
+            r
+        }
+        Err(_) => {
+            error!("Failed to execute select statement for the document_snapshot table");
+            return Err(ApiError::DatabaseError(
+                "Failed to find document in database".to_string(),
+            ));
+        }
+    };
+
+    timer.set("rows", rows.len());
+    drop(timer);
+
+    let mut snapshots: Vec<DocumentSnapshot> = Vec::with_capacity(rows.len());
+    for row in rows {
+        snapshots.push(DocumentSnapshot {
+            document_id: row.get(0),
+            ssn: row.get(1),
+            sum: row.get(2),
+            sid: row.get(3),
+            seq: row.get(4),
+            value: encryption::open(client, row.get(5)).await?,
+            tombstone: row.get(6),
+            author: row.get("author"),
+            authored_at: row.get("authored_at"),
+        });
+    }
+    Ok(snapshots)
+}
+
+/// Reads a document's checkpoint, None if it has none.
+#[instrument(name = "db.load_checkpoint", skip(client))]
+pub async fn load_checkpoint(
+    client: &Client,
+    document_id: &Uuid,
+) -> Result<Option<Checkpoint>, ApiError> {
+    let timer = QueryTimer::start("load_checkpoint").param("document_id", document_id);
+    let row = match client
+        .query_opt(
+            "SELECT nodes,watermark,log_position FROM document_checkpoints WHERE document_id=$1",
+            &[document_id],
+        )
+        .await
+    {
+        Ok(Some(row)) => row,
+        Ok(None) => return Ok(None),
+        Err(_) => {
+            error!("Failed to read the document_checkpoints table");
+            return Err(ApiError::DatabaseError(
+                "Failed to read the document's checkpoint".to_string(),
+            ));
+        }
+    };
+
+    drop(timer);
+
+    let watermark: String = row.get(1);
+    let log_position: Option<i64> = row.get(2);
+    let nodes: Vec<u8> = encryption::open_backup(row.get(0)).await?;
+    match (
+        decode_checkpoint(&nodes),
+        DateTime::parse_from_rfc3339(&watermark),
+    ) {
+        (Some(nodes), Ok(watermark)) => Ok(Some(Checkpoint {
+            nodes: checkpoint_nodes(nodes),
+            watermark: watermark.to_utc(),
+            log_position,
+        })),
+        _ => {
+            // the rows are still there to load the document from
+            warn!("Ignoring an unreadable checkpoint");
+            Ok(None)
+        }
+    }
+}
+
+/// Applies the operations logged after a checkpoint to the RGA loaded from it, as
+/// [`history::apply_operations`] does. Operations already in the checkpoint change nothing.
+/// Returns the number of operations read.
+pub fn apply_tail(rga: &mut RGA, document_id: Uuid, operations: Vec<LoggedOperation>) -> usize {
+    let tail: usize = operations.len();
+    history::apply_operations(rga, document_id, operations);
+    tail
+}
+
+/// Builds a document's RGA from its checkpoint and the operations logged since, or from its
+/// rows in `document_snapshots` and the moves logged when it has no checkpoint.
+#[instrument(name = "db.load_rga", skip(client, session))]
+pub async fn load_rga(
+    client: &Client,
+    document_id: &Uuid,
+    session: &Session,
+) -> Result<RGA, ApiError> {
+    if let Some(checkpoint) = load_checkpoint(client, document_id).await? {
+        let mut rga: RGA =
+            RGA::from_nodes(checkpoint.nodes, session.session_id, session.replica_id);
+        let tail = LogTail {
+            // an older checkpoint only has its watermark to go by
+            position: checkpoint.log_position.unwrap_or(i64::MAX),
+            since: checkpoint.watermark - WATERMARK_SLACK,
+        };
+        let tail: Vec<LoggedOperation> =
+            history::fetch_operations(client, document_id, Some(tail)).await?;
+        // operations the checkpoint is missing make the RGA dirty, so autosave checkpoints it again
+        let tail: usize = apply_tail(&mut rga, *document_id, tail);
+        info!(
+            nodes = rga.len(),
+            tail, "Loaded document from its checkpoint"
+        );
+        return Ok(rga);
+    }
+
+    let snapshots: Vec<DocumentSnapshot> = load_snapshots(client, document_id).await?;
+    let mut rga: RGA = RGA::load_snapshot(snapshots, session.session_id, session.replica_id);
+    // the rows keep each node where it was inserted, the moves are only in the log
+    let moves: Vec<LoggedOperation> = history::fetch_operations(client, document_id, None)
+        .await?
+        .into_iter()
+        .filter(|operation| operation.operation.as_deref() == Some("Move"))
+        .collect();
+    history::apply_operations(&mut rga, *document_id, moves);
+    Ok(rga)
+}
+
+/// Replaces the document's rows in `document_snapshots` with the current state of its RGA,
+/// so loading the document no longer needs to replay superseded rows, and writes its
+/// checkpoint. Returns the number of nodes written.
+#[instrument(name = "db.persist_snapshot", skip(client, rga))]
+pub async fn persist_snapshot(
+    client: &mut Client,
+    document_id: &Uuid,
+    rga: &RGA,
+) -> Result<usize, ApiError> {
+    let nodes: Vec<Node> = rga.nodes();
+    let _timer = QueryTimer::start("persist_snapshot")
+        .param("document_id", document_id)
+        .param("rows", nodes.len());
+    let watermark: String = chrono::Utc::now().to_rfc3339();
+    let checkpoint: Vec<CheckpointNode> = rga.iter().map(CheckpointNode::from).collect();
+    let checkpoint: Vec<u8> = encryption::seal_backup(encode_checkpoint(&checkpoint));
+
+    let tx = match client.transaction().await {
+        Ok(tx) => tx,
+        Err(_) => {
+            error!("Failed to start database transaction");
+            return Err(ApiError::DatabaseError(
+                "Failed to start database transaction".to_string(),
+            ));
+        }
+    };
+
+    if tx
+        .execute(
+            "DELETE FROM document_snapshots WHERE document_id=$1",
+            &[document_id],
+        )
+        .await
+        .is_err()
+    {
+        error!("Failed to clear the document_snapshots table");
+        return Err(ApiError::DatabaseError(
+            "Failed to clear the document_snapshots table".to_string(),
+        ));
+    }
+
+    let insert = match tx.prepare("INSERT INTO document_snapshots (document_id,ssn,sum,sid,seq,value,tombstone,author,authored_at) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9)").await {
+        Ok(q) => q,
+        Err(_) => {
+            error!("Failed to create insert query for document_snapshot table");
+            return Err(ApiError::DatabaseError(
+                "Failed to create insert query for document_snapshot table".to_string(),
+            ));
+        }
+    };
+
+    for node in &nodes {
+        let [ssn, sum, sid, seq] = node.s4vector.to_i64()?;
+        if tx
+            .execute(
+                &insert,
+                &[
+                    document_id,
+                    &ssn,
+                    &sum,
+                    &sid,
+                    &seq,
+                    &encryption::seal(&node.value),
+                    &node.tombstone,
+                    &node.author,
+                    &node.authored_at,
+                ],
+            )
+            .await
+            .is_err()
+        {
+            error!("Failed to insert into document_snapshot table");
+            return Err(ApiError::DatabaseError(
+                "Failed to insert into document_snapshot table".to_string(),
+            ));
+        }
+    }
+
+    if tx
+        .execute(
+            "INSERT INTO document_checkpoints (document_id,nodes,watermark,log_position) \
+             VALUES ($1,$2,$3,(SELECT COALESCE(MAX(log_position),0) FROM operations WHERE document_id=$1)) \
+             ON CONFLICT (document_id) DO UPDATE SET nodes = EXCLUDED.nodes, watermark = EXCLUDED.watermark, \
+             log_position = EXCLUDED.log_position",
+            &[document_id, &checkpoint, &watermark],
+        )
+        .await
+        .is_err()
+    {
+        error!("Failed to insert into document_checkpoints table");
+        return Err(ApiError::DatabaseError(
+            "Failed to insert into document_checkpoints table".to_string(),
+        ));
+    }
+
+    if tx.commit().await.is_err() {
+        error!("Failed to commit database transaction");
+        return Err(ApiError::DatabaseError(
+            "Failed to commit database transaction".to_string(),
+        ));
+    }
+
+    Ok(nodes.len())
+}
+
+/// Persists a snapshot of every dirty RGA and marks it clean.
+/// A failure for one document is logged and the remaining documents are still persisted.
+/// Returns the number of documents persisted.
+pub async fn flush_dirty(client: &mut Client, rgas: &mut HashMap<Uuid, RGA>) -> usize {
+    let mut persisted: usize = 0;
+
+    for (document_id, rga) in rgas.iter_mut().filter(|(_, rga)| rga.dirty) {
+        match persist_snapshot(client, document_id, rga).await {
+            Ok(nodes) => {
+                rga.dirty = false;
+                persisted += 1;
+                info!(document_id = %document_id, nodes, "Persisted document snapshot");
+            }
+            Err(e) => {
+                error!(document_id = %document_id, "Failed to persist document snapshot: {}", e);
+            }
+        }
+    }
+
+    persisted
+}
+
+/// Fairing that starts a background task checkpointing dirty RGAs every `interval_secs` seconds,
+/// bounding the operations replayed after a crash. An interval of 0 disables autosave.
+/// The task stops when Rocket begins shutting down, the final flush is done by the shutdown fairing.
+pub fn attach_autosave(interval_secs: u64) -> AdHoc {
+    AdHoc::on_liftoff("Autosave snapshots", move |rocket| {
+        Box::pin(async move {
+            if interval_secs == 0 {
+                info!("Autosave is disabled");
+                return;
+            }
+
+            let (rgas, db) = match (
+                rocket.state::<SharedRGAs>(),
+                rocket.state::<Arc<Mutex<Client>>>(),
+            ) {
+                (Some(rgas), Some(db)) => (Arc::clone(rgas), Arc::clone(db)),
+                _ => {
+                    warn!("Replica state is unavailable, autosave is disabled");
+                    return;
+                }
+            };
+            let mut shutdown = rocket.shutdown();
+
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+                // the first tick completes immediately and nothing is dirty yet
+                interval.tick().await;
+
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = &mut shutdown => break,
+                    }
+
+                    async {
+                        // same lock order as the routes: documents, then the database
+                        let mut rgas = rgas.lock().await;
+                        let mut client = tenancy::lock_any(&db).await;
+
+                        let persisted: usize = flush_dirty(&mut client, &mut rgas).await;
+                        if persisted > 0 {
+                            info!("Autosaved {} documents", persisted);
+                        }
+                    }
+                    .instrument(info_span!("snapshot.autosave"))
+                    .await;
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::json_structures::BroadcastOperation;
+    use crate::pool::Pool;
+    use crate::testing::{TestDatabase, OPERATION_TABLES};
+    use crate::wal::{Replay, Wal, WalConfig, WalRecord};
+    use crate::Actor;
+
+    fn logged(s4vector: [i64; 4], value: &str, tombstone: bool, at: &str) -> LoggedOperation {
+        LoggedOperation {
+            s4vector,
+            operation: None,
+            value: Some(value.to_string()),
+            tombstone,
+            left: None,
+            right: None,
+            nodes: Vec::new(),
+            timestamp: DateTime::parse_from_rfc3339(at).unwrap().to_utc(),
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_encoding() {
+        let s4vector = |sum: i64| S4Vector::from_i64(1, sum, 1, sum).unwrap();
+        let nodes: Vec<CheckpointNode> = vec![
+            CheckpointNode {
+                s4vector: s4vector(1),
+                value: "héllo".to_string(),
+                tombstone: false,
+                author: Some(Uuid::new_v4()),
+                authored_at: Some("2025-01-04T10:00:00Z".to_string()),
+                origins: Some([None, Some(s4vector(300))]),
+                moved: Some(s4vector(1 << 40)),
+            },
+            CheckpointNode {
+                s4vector: s4vector(300),
+                value: String::new(),
+                tombstone: true,
+                author: None,
+                authored_at: None,
+                origins: Some([Some(s4vector(1)), None]),
+                moved: None,
+            },
+        ];
+        let encoded: Vec<u8> = encode_checkpoint(&nodes);
+        assert_eq!(decode_checkpoint(&encoded), Some(nodes));
+        assert_eq!(decode_checkpoint(CHECKPOINT_MAGIC), Some(Vec::new()));
+        assert_eq!(decode_checkpoint(&encoded[..encoded.len() - 1]), None);
+
+        // checkpoints written as JSON are still read
+        let json = r#"[{"s4vector":{"ssn":1,"sum":1,"sid":1,"seq":1},"value":"a","tombstone":false,"author":null,"authored_at":null}]"#;
+        let nodes: Vec<CheckpointNode> = decode_checkpoint(json.as_bytes()).unwrap();
+        assert_eq!(nodes[0].s4vector, s4vector(1));
+        assert_eq!(nodes[0].origins, None);
+        assert!(encode_checkpoint(&nodes).len() < json.len() / 4);
+    }
+
+    #[test]
+    fn test_apply_tail() {
+        let checkpoint: Vec<Node> = vec![
+            Node::new(
+                "a".to_string(),
+                S4Vector::from_i64(1, 1, 1, 1).unwrap(),
+                None,
+                None,
+            ),
+            Node::new(
+                "b".to_string(),
+                S4Vector::from_i64(1, 2, 1, 2).unwrap(),
+                None,
+                None,
+            ),
+        ];
+        let mut rga = RGA::from_nodes(checkpoint, 2, 1);
+
+        // the tail starts before the watermark, so it repeats operations already checkpointed
+        let tail = vec![
+            logged([1, 3, 1, 3], "c", false, "2025-01-04T10:00:03Z"),
+            logged([1, 2, 1, 2], "B", false, "2025-01-04T10:00:02Z"),
+            logged([1, 1, 1, 1], "a", false, "2025-01-04T10:00:01Z"),
+            logged([1, 3, 1, 3], "C", false, "2025-01-04T10:00:04Z"),
+            logged([1, 1, 1, 1], "a", true, "2025-01-04T10:00:05Z"),
+        ];
+        assert_eq!(apply_tail(&mut rga, Uuid::nil(), tail), 5);
+        assert_eq!(rga.len(), 3);
+        assert!(
+            rga.node(&S4Vector::from_i64(1, 1, 1, 1).unwrap())
+                .unwrap()
+                .tombstone
+        );
+        assert_eq!(rga.read(), vec!["B", "C"]);
+        assert!(rga.dirty);
+
+        // a tail the checkpoint already has leaves the document clean
+        let checkpoint: Vec<Node> = rga.iter().cloned().collect();
+        let mut rga = RGA::from_nodes(checkpoint, 2, 1);
+        apply_tail(
+            &mut rga,
+            Uuid::nil(),
+            vec![logged([1, 2, 1, 2], "B", false, "2025-01-04T10:00:02Z")],
+        );
+        assert!(!rga.dirty);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_load_rga_after_concurrent_inserts() {
+        let database = TestDatabase::create(OPERATION_TABLES).await;
+        let mut client = database.connect().await;
+        let document_id = Uuid::new_v4();
+        let session = Session {
+            replica_id: 1,
+            session_id: 2,
+        };
+        let mut live = RGA::new(2, 1);
+        let a = live
+            .local_insert("a".to_string(), None, None, document_id)
+            .unwrap()
+            .s4vector();
+        live.local_insert("b".to_string(), Some(a), None, document_id)
+            .unwrap();
+        persist_snapshot(&mut client, &document_id, &live)
+            .await
+            .unwrap();
+
+        // two other replicas insert at the start at the same time, one of them twice. It has
+        // restarted since, so its nodes have a later session than "b" but go before it
+        let mut second = RGA::from_nodes(live.iter().cloned().collect(), 2, 2);
+        let mut third = RGA::from_nodes(live.iter().cloned().collect(), 3, 3);
+        let x = second
+            .local_insert("x".to_string(), None, Some(a), document_id)
+            .unwrap();
+        let y = third
+            .local_insert("y".to_string(), None, Some(a), document_id)
+            .unwrap();
+        let z = third
+            .local_insert("z".to_string(), Some(y.s4vector()), Some(a), document_id)
+            .unwrap();
+        for operation in [&x, &y, &z] {
+            live.apply_remote(operation.clone()).unwrap();
+        }
+        let actor = Actor {
+            user_id: None,
+            client_ip: None,
+            share: None,
+        };
+        db::record_operations(
+            &mut client,
+            &live,
+            &actor,
+            &[z, x, y],
+            &Utc::now().to_rfc3339(),
+        )
+        .await
+        .unwrap();
+
+        let rga = load_rga(&client, &document_id, &session).await.unwrap();
+        assert_eq!(live.text(), "xyzab");
+        assert_eq!(rga.text(), live.text());
+        database.drop().await;
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_load_rga_after_wal_replay() {
+        let database = TestDatabase::create(OPERATION_TABLES).await;
+        let mut client = database.connect().await;
+        let document_id = Uuid::new_v4();
+        let session = Session {
+            replica_id: 1,
+            session_id: 2,
+        };
+        let a = S4Vector::from_i64(1, 1, 1, 1).unwrap();
+        let rga = RGA::from_nodes(vec![Node::new("a".to_string(), a, None, None)], 2, 1);
+        persist_snapshot(&mut client, &document_id, &rga)
+            .await
+            .unwrap();
+
+        // an insert applied long before the checkpoint, which the database rejected at the time
+        let record = WalRecord {
+            operation: BroadcastOperation {
+                operation: "Insert".to_string(),
+                document_id,
+                ssn: 1,
+                sum: 2,
+                sid: 1,
+                seq: 2,
+                value: Some("b".to_string()),
+                left: Some(a),
+                right: None,
+                nodes: Vec::new(),
+                request_id: None,
+                author: None,
+                origin_region: None,
+                sent_at: None,
+            },
+            value: "b".to_string(),
+            tombstone: false,
+            authored_at: None,
+            client_ip: None,
+            timestamp: (Utc::now() - WATERMARK_SLACK * 12).to_rfc3339(),
+        };
+        let config = WalConfig {
+            path: std::env::temp_dir()
+                .join(format!("nimble-{}.wal", Uuid::new_v4()))
+                .to_string_lossy()
+                .into_owned(),
+            ..WalConfig::default()
+        };
+        let wal = Wal::open(&config).await.unwrap();
+        let lsn = wal.append(&record).await.unwrap();
+        let error = ApiError::DatabaseError("down".to_string());
+        wal.settle(lsn, Err(error)).await.unwrap();
+
+        let pool = Pool::connect(&database.url, 1).await.unwrap();
+        let mut replay = Replay::default();
+        wal.replay(&pool, &mut replay).await.unwrap();
+        assert_eq!(replay.persisted, 1);
+
+        // the operation is older than the watermark less the slack, but was inserted after
+        // the checkpoint
+        let rga = load_rga(&client, &document_id, &session).await.unwrap();
+        assert_eq!(rga.read(), vec!["a", "b"]);
+
+        // once checkpointed, it isn't read again
+        persist_snapshot(&mut client, &document_id, &rga)
+            .await
+            .unwrap();
+        let checkpoint = load_checkpoint(&client, &document_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let tail = LogTail {
+            position: checkpoint.log_position.unwrap(),
+            since: checkpoint.watermark - WATERMARK_SLACK,
+        };
+        assert!(history::fetch_operations(&client, &document_id, Some(tail))
+            .await
+            .unwrap()
+            .is_empty());
+
+        let _ = std::fs::remove_file(&config.path);
+        database.drop().await;
+    }
+}
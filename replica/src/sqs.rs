@@ -0,0 +1,566 @@
+//! Delivery of broadcasts through a per-replica SQS queue.
+//!
+//! When `sqs.enabled` is set, a replica creates the queue `<queue_prefix>-<replica_id>` on
+//! liftoff, allows the SNS topic to send to it and subscribes it to the topic with raw message
+//! delivery. The subscription's filter policy drops the replica's own broadcasts, which carry
+//! its id in the `origin_replica` message attribute. The queue is long-polled and every message
+//! is applied like an SNS notification, then deleted. On shutdown the subscription and queue
+//! are deleted, or kept when `delete_on_shutdown` is unset so a restarted replica receives the
+//! broadcasts sent while it was down (messages expire after `message_retention_secs`).
+
+use crate::backpressure::Backlog;
+use crate::changes::ChangeFeeds;
+use crate::chat::ChatRooms;
+use crate::logging;
+use crate::region::ReplicationMetrics;
+use crate::routes::{apply_broadcast, SharedRGAs};
+use crate::signing::AwsSigner;
+use crate::ApiError;
+use aws_config::SdkConfig;
+use aws_sdk_sns::operation::publish::builders::PublishFluentBuilder;
+use aws_sdk_sns::types::MessageAttributeValue;
+use aws_sdk_sns::Client as SnsClient;
+use rocket::fairing::AdHoc;
+use rocket::tokio::{self, sync::Mutex};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, info_span, instrument, warn, Instrument};
+
+/// The message attribute carrying the id of the replica that published a broadcast.
+pub const ORIGIN_ATTRIBUTE: &str = "origin_replica";
+
+/// Delivery of broadcasts through SQS.
+/// `enabled`: Whether the replica subscribes a queue to the topic (broadcasts are otherwise
+/// received on `/sns`).
+/// `queue_prefix`: The name of the replica's queue, followed by its id.
+/// `endpoint`: An SQS compatible endpoint to use instead of AWS.
+/// `wait_time_secs`: Seconds a receive waits for messages (at most 20).
+/// `message_retention_secs`: Seconds messages are kept in the queue before they expire.
+/// `delete_on_shutdown`: Whether the subscription and queue are deleted on shutdown.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SqsConfig {
+    pub enabled: bool,
+    pub queue_prefix: String,
+    pub endpoint: Option<String>,
+    pub wait_time_secs: u64,
+    pub message_retention_secs: u64,
+    pub delete_on_shutdown: bool,
+}
+
+impl Default for SqsConfig {
+    fn default() -> Self {
+        SqsConfig {
+            enabled: false,
+            queue_prefix: "nimble-replica".to_string(),
+            endpoint: None,
+            wait_time_secs: 20,
+            message_retention_secs: 3600,
+            delete_on_shutdown: true,
+        }
+    }
+}
+
+impl SqsConfig {
+    /// The name of a replica's queue.
+    pub fn queue_name(&self, replica_id: i64) -> String {
+        format!("{}-{}", self.queue_prefix, replica_id)
+    }
+}
+
+/// Tags a broadcast with the id of this replica, so its own queue's subscription drops it.
+pub fn tag_origin(publish: PublishFluentBuilder) -> PublishFluentBuilder {
+    let attribute = logging::replica_id().and_then(|replica_id| {
+        MessageAttributeValue::builder()
+            .data_type("Number")
+            .string_value(replica_id.to_string())
+            .build()
+            .ok()
+    });
+    match attribute {
+        Some(attribute) => publish.message_attributes(ORIGIN_ATTRIBUTE, attribute),
+        None => publish,
+    }
+}
+
+/// The filter policy of a replica's subscription: every broadcast but its own, including
+/// broadcasts from replicas that don't tag them.
+pub fn filter_policy(replica_id: i64) -> String {
+    json!({
+        "$or": [
+            { ORIGIN_ATTRIBUTE: [{ "anything-but": [replica_id] }] },
+            { ORIGIN_ATTRIBUTE: [{ "exists": false }] },
+        ]
+    })
+    .to_string()
+}
+
+/// The access policy of a queue, allowing the topic to send to it.
+pub fn queue_policy(queue_arn: &str, topic_arn: &str) -> String {
+    json!({
+        "Version": "2012-10-17",
+        "Statement": [{
+            "Effect": "Allow",
+            "Principal": { "Service": "sns.amazonaws.com" },
+            "Action": "sqs:SendMessage",
+            "Resource": queue_arn,
+            "Condition": { "ArnEquals": { "aws:SourceArn": topic_arn } },
+        }]
+    })
+    .to_string()
+}
+
+/// A message received from a queue.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct QueueMessage {
+    pub message_id: String,
+    pub receipt_handle: String,
+    pub body: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CreateQueueResponse {
+    queue_url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct QueueAttributesResponse {
+    #[serde(default)]
+    attributes: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ReceiveMessageResponse {
+    #[serde(default)]
+    messages: Vec<QueueMessage>,
+}
+
+/// A minimal SQS client using the JSON protocol, signing requests with the replica's AWS
+/// credentials.
+pub struct SqsClient {
+    signer: AwsSigner,
+    url: String,
+}
+
+impl SqsClient {
+    /// Creates the client, or returns None if SQS delivery is disabled or no AWS credentials
+    /// are configured.
+    pub fn new(aws_config: &SdkConfig, config: &SqsConfig, region: &str) -> Option<SqsClient> {
+        if !config.enabled {
+            return None;
+        }
+        let credentials = aws_config.credentials_provider()?;
+
+        let url: String = match &config.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://sqs.{}.amazonaws.com", region),
+        };
+
+        Some(SqsClient {
+            signer: AwsSigner::new(credentials, region, "sqs"),
+            url,
+        })
+    }
+
+    /// Signs and sends an action, returning the response body.
+    async fn call(&self, action: &str, body: Value) -> Result<Vec<u8>, ApiError> {
+        let url = match reqwest::Url::parse(&format!("{}/", self.url)) {
+            Ok(url) => url,
+            Err(_) => {
+                error!("Invalid SQS endpoint");
+                return Err(ApiError::InternalServerError(
+                    "Invalid SQS endpoint".to_string(),
+                ));
+            }
+        };
+        let target: String = format!("AmazonSQS.{}", action);
+        let headers = [
+            ("content-type", "application/x-amz-json-1.0"),
+            ("x-amz-target", target.as_str()),
+        ];
+
+        let response = self
+            .signer
+            .send(
+                reqwest::Method::POST,
+                url,
+                &headers,
+                body.to_string().into_bytes(),
+            )
+            .await?;
+        let status = response.status();
+        let body = match response.bytes().await {
+            Ok(body) => body.to_vec(),
+            Err(_) => {
+                error!("Failed to read the SQS response");
+                return Err(ApiError::RequestFailed(
+                    "Failed to read the SQS response".to_string(),
+                ));
+            }
+        };
+
+        if !status.is_success() {
+            error!(
+                "SQS rejected {} with status {}: {}",
+                action,
+                status,
+                String::from_utf8_lossy(&body)
+            );
+            return Err(ApiError::RequestFailed(format!(
+                "SQS rejected {} with status {}",
+                action, status
+            )));
+        }
+        Ok(body)
+    }
+
+    /// Parses the response to an action.
+    fn parse<T: DeserializeOwned>(action: &str, body: &[u8]) -> Result<T, ApiError> {
+        match serde_json::from_slice(body) {
+            Ok(response) => Ok(response),
+            Err(_) => {
+                error!("Failed to parse the SQS {} response", action);
+                Err(ApiError::RequestFailed(format!(
+                    "Failed to parse the SQS {} response",
+                    action
+                )))
+            }
+        }
+    }
+
+    /// Creates a queue, or returns the existing queue of the same name. Returns its url.
+    #[instrument(name = "sqs.create_queue", skip(self))]
+    pub async fn create_queue(&self, name: &str, retention_secs: u64) -> Result<String, ApiError> {
+        let body = self
+            .call(
+                "CreateQueue",
+                json!({
+                    "QueueName": name,
+                    "Attributes": { "MessageRetentionPeriod": retention_secs.to_string() },
+                }),
+            )
+            .await?;
+        Ok(Self::parse::<CreateQueueResponse>("CreateQueue", &body)?.queue_url)
+    }
+
+    /// Returns an attribute of a queue.
+    async fn attribute(&self, queue_url: &str, name: &str) -> Result<String, ApiError> {
+        let body = self
+            .call(
+                "GetQueueAttributes",
+                json!({ "QueueUrl": queue_url, "AttributeNames": [name] }),
+            )
+            .await?;
+        let mut response: QueueAttributesResponse = Self::parse("GetQueueAttributes", &body)?;
+        match response.attributes.remove(name) {
+            Some(value) => Ok(value),
+            None => {
+                error!("SQS returned no {} attribute", name);
+                Err(ApiError::RequestFailed(format!(
+                    "SQS returned no {} attribute",
+                    name
+                )))
+            }
+        }
+    }
+
+    /// Returns the ARN of a queue.
+    #[instrument(name = "sqs.queue_arn", skip(self))]
+    pub async fn queue_arn(&self, queue_url: &str) -> Result<String, ApiError> {
+        self.attribute(queue_url, "QueueArn").await
+    }
+
+    /// Returns the approximate number of messages waiting in a queue.
+    pub async fn queued(&self, queue_url: &str) -> Result<usize, ApiError> {
+        let queued: String = self
+            .attribute(queue_url, "ApproximateNumberOfMessages")
+            .await?;
+        Ok(queued.parse().unwrap_or_default())
+    }
+
+    /// Sets the access policy of a queue.
+    #[instrument(name = "sqs.set_policy", skip(self, policy))]
+    pub async fn set_policy(&self, queue_url: &str, policy: String) -> Result<(), ApiError> {
+        self.call(
+            "SetQueueAttributes",
+            json!({ "QueueUrl": queue_url, "Attributes": { "Policy": policy } }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Waits up to `wait_time_secs` for messages and returns up to 10 of them.
+    pub async fn receive(
+        &self,
+        queue_url: &str,
+        wait_time_secs: u64,
+    ) -> Result<Vec<QueueMessage>, ApiError> {
+        let body = self
+            .call(
+                "ReceiveMessage",
+                json!({
+                    "QueueUrl": queue_url,
+                    "MaxNumberOfMessages": 10,
+                    "WaitTimeSeconds": wait_time_secs,
+                }),
+            )
+            .await?;
+        Ok(Self::parse::<ReceiveMessageResponse>("ReceiveMessage", &body)?.messages)
+    }
+
+    /// Deletes a received message from a queue.
+    pub async fn delete_message(
+        &self,
+        queue_url: &str,
+        receipt_handle: &str,
+    ) -> Result<(), ApiError> {
+        self.call(
+            "DeleteMessage",
+            json!({ "QueueUrl": queue_url, "ReceiptHandle": receipt_handle }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Deletes a queue and its messages.
+    #[instrument(name = "sqs.delete_queue", skip(self))]
+    pub async fn delete_queue(&self, queue_url: &str) -> Result<(), ApiError> {
+        self.call("DeleteQueue", json!({ "QueueUrl": queue_url }))
+            .await?;
+        Ok(())
+    }
+}
+
+/// A replica's queue and its subscription to the topic.
+struct Subscription {
+    queue_url: String,
+    subscription_arn: String,
+}
+
+/// Creates the replica's queue and subscribes it to the topic.
+async fn subscribe(
+    sqs: &SqsClient,
+    sns: &Mutex<SnsClient>,
+    topic_arn: &str,
+    replica_id: i64,
+    config: &SqsConfig,
+) -> Result<Subscription, ApiError> {
+    let queue_url: String = sqs
+        .create_queue(
+            &config.queue_name(replica_id),
+            config.message_retention_secs,
+        )
+        .await?;
+    let queue_arn: String = sqs.queue_arn(&queue_url).await?;
+    sqs.set_policy(&queue_url, queue_policy(&queue_arn, topic_arn))
+        .await?;
+
+    let subscribed = sns
+        .lock()
+        .await
+        .subscribe()
+        .topic_arn(topic_arn)
+        .protocol("sqs")
+        .endpoint(&queue_arn)
+        .attributes("RawMessageDelivery", "true")
+        .attributes("FilterPolicy", filter_policy(replica_id))
+        .return_subscription_arn(true)
+        .send()
+        .await;
+    match subscribed {
+        Ok(output) => {
+            info!("Subscribed {} to the topic", queue_arn);
+            Ok(Subscription {
+                queue_url,
+                subscription_arn: output.subscription_arn().unwrap_or_default().to_string(),
+            })
+        }
+        Err(_) => {
+            error!("Failed to subscribe {} to the topic", queue_arn);
+            Err(ApiError::RequestFailed(
+                "Failed to subscribe the queue to the topic".to_string(),
+            ))
+        }
+    }
+}
+
+/// Deletes the subscription and the queue.
+async fn unsubscribe(sqs: &SqsClient, sns: &Mutex<SnsClient>, subscription: &Subscription) {
+    if sns
+        .lock()
+        .await
+        .unsubscribe()
+        .subscription_arn(&subscription.subscription_arn)
+        .send()
+        .await
+        .is_err()
+    {
+        warn!("Failed to unsubscribe the queue from the topic");
+    }
+    if sqs.delete_queue(&subscription.queue_url).await.is_ok() {
+        info!("Deleted the queue");
+    }
+}
+
+/// Fairing subscribing the replica's queue to the topic on liftoff and applying the
+/// broadcasts it receives until shutdown.
+pub fn attach_sqs(config: SqsConfig) -> AdHoc {
+    AdHoc::on_liftoff("SQS delivery", move |rocket| {
+        Box::pin(async move {
+            let sqs: Arc<SqsClient> = match rocket.state::<Arc<SqsClient>>() {
+                Some(sqs) => Arc::clone(sqs),
+                None => {
+                    info!("SQS delivery is disabled");
+                    return;
+                }
+            };
+            let state = (
+                rocket.state::<Arc<Mutex<SnsClient>>>(),
+                rocket.state::<Arc<Mutex<String>>>(),
+                rocket.state::<Arc<Mutex<i64>>>(),
+                rocket.state::<SharedRGAs>(),
+                rocket.state::<ChangeFeeds>(),
+                rocket.state::<ChatRooms>(),
+                rocket.state::<ReplicationMetrics>(),
+                rocket.state::<Backlog>(),
+            );
+            let (sns, topic_arn, replica_id, rgas, feeds, chats, replication, backlog) = match state
+            {
+                (
+                    Some(sns),
+                    Some(topic_arn),
+                    Some(replica_id),
+                    Some(rgas),
+                    Some(feeds),
+                    Some(chats),
+                    Some(replication),
+                    Some(backlog),
+                ) => (
+                    Arc::clone(sns),
+                    topic_arn.lock().await.clone(),
+                    *replica_id.lock().await,
+                    Arc::clone(rgas),
+                    feeds.clone(),
+                    chats.clone(),
+                    replication.clone(),
+                    backlog.clone(),
+                ),
+                _ => {
+                    warn!("Replica state is unavailable, SQS delivery is disabled");
+                    return;
+                }
+            };
+            let mut shutdown = rocket.shutdown();
+
+            tokio::spawn(async move {
+                let subscription: Subscription =
+                    match subscribe(&sqs, &sns, &topic_arn, replica_id, &config)
+                        .instrument(info_span!("sqs.subscribe"))
+                        .await
+                    {
+                        Ok(subscription) => subscription,
+                        // errors are logged, broadcasts can still be received on /sns
+                        Err(_) => return,
+                    };
+
+                loop {
+                    let messages: Vec<QueueMessage> = tokio::select! {
+                        received = sqs.receive(&subscription.queue_url, config.wait_time_secs) => match received {
+                            Ok(messages) => messages,
+                            Err(_) => {
+                                // errors are logged, back off before polling again
+                                tokio::select! {
+                                    _ = tokio::time::sleep(Duration::from_secs(1)) => continue,
+                                    _ = &mut shutdown => break,
+                                }
+                            }
+                        },
+                        _ = &mut shutdown => break,
+                    };
+
+                    let received: usize = messages.len();
+                    for message in messages {
+                        async {
+                            // failures are logged and the message is deleted either way, as SNS
+                            // drops notifications its endpoint rejects
+                            let _ = apply_broadcast(
+                                &message.body,
+                                &rgas,
+                                &feeds,
+                                &chats,
+                                &replication,
+                                &backlog,
+                            )
+                            .await;
+                            let _ = sqs
+                                .delete_message(&subscription.queue_url, &message.receipt_handle)
+                                .await;
+                        }
+                        .instrument(info_span!("sqs.message", message_id = %message.message_id))
+                        .await;
+                    }
+
+                    // an empty receive drained the queue, otherwise count what is left in it
+                    if received == 0 {
+                        backlog.set_queued(0);
+                    } else if let Ok(queued) = sqs.queued(&subscription.queue_url).await {
+                        backlog.set_queued(queued);
+                    }
+                }
+
+                if config.delete_on_shutdown {
+                    unsubscribe(&sqs, &sns, &subscription)
+                        .instrument(info_span!("sqs.unsubscribe"))
+                        .await;
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policies() {
+        assert_eq!(SqsConfig::default().queue_name(3), "nimble-replica-3");
+
+        let filter: Value = serde_json::from_str(&filter_policy(3)).unwrap();
+        assert_eq!(filter["$or"][0]["origin_replica"][0]["anything-but"][0], 3);
+        assert_eq!(filter["$or"][1]["origin_replica"][0]["exists"], false);
+
+        let policy: Value = serde_json::from_str(&queue_policy(
+            "arn:aws:sqs:af-south-1:123:nimble-replica-3",
+            "arn:aws:sns:af-south-1:123:nimble",
+        ))
+        .unwrap();
+        let statement = &policy["Statement"][0];
+        assert_eq!(
+            statement["Resource"],
+            "arn:aws:sqs:af-south-1:123:nimble-replica-3"
+        );
+        assert_eq!(
+            statement["Condition"]["ArnEquals"]["aws:SourceArn"],
+            "arn:aws:sns:af-south-1:123:nimble"
+        );
+    }
+
+    #[test]
+    fn test_receive_response() {
+        let body = br#"{"Messages":[{"MessageId":"m-1","ReceiptHandle":"r-1","Body":"{}","MD5OfBody":"x"}]}"#;
+        let response: ReceiveMessageResponse = SqsClient::parse("ReceiveMessage", body).unwrap();
+        assert_eq!(response.messages[0].receipt_handle, "r-1");
+
+        let empty: ReceiveMessageResponse = SqsClient::parse("ReceiveMessage", b"{}").unwrap();
+        assert!(empty.messages.is_empty());
+    }
+}
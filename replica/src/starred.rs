@@ -0,0 +1,307 @@
+//! Documents each user starred or recently opened, for the start page of the editors.
+//!
+//! The `document_activity` table has a row per user and document they starred or accessed.
+//! `accessed_at` is updated when the user fetches the document and when they edit it,
+//! `starred_at` is set by `POST /document/<id>/star` and cleared by `DELETE /document/<id>/star`.
+
+use crate::pool::Pool;
+use crate::share::Access;
+use crate::tenancy::{self, Tenant};
+use crate::{expiry, Actor, ApiError, FieldError, RequestId};
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use rocket::{delete, get, post};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio_postgres::{Client, GenericClient};
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// Documents listed when the request does not set a limit.
+pub const DEFAULT_LIMIT: i64 = 20;
+
+/// Most documents a listing returns.
+pub const MAX_LIMIT: i64 = 100;
+
+/// A document the user starred or accessed.
+/// `starred_at`: When the user starred the document, None if it is not starred.
+/// `accessed_at`: When the user last fetched or edited the document, None if they never did.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActivityDocument {
+    pub document_id: Uuid,
+    pub owner_id: Uuid,
+    pub title: String,
+    pub workspace_id: Option<Uuid>,
+    pub starred_at: Option<String>,
+    pub accessed_at: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// Returns the number of documents to list, or `422` if the limit is out of range.
+pub fn parse_limit(limit: Option<i64>) -> Result<i64, ApiError> {
+    let limit: i64 = limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ApiError::ValidationFailed(vec![FieldError::new(
+            "limit",
+            &format!("must be between 1 and {}", MAX_LIMIT),
+        )]));
+    }
+    Ok(limit)
+}
+
+fn database_error() -> ApiError {
+    error!("Failed to query the document_activity table");
+    ApiError::DatabaseError("Failed to query the document_activity table".to_string())
+}
+
+/// Records that the user accessed the document now. Takes a transaction so edits record the
+/// access with their operations.
+#[instrument(name = "db.touch_document", skip(client))]
+pub async fn touch<C: GenericClient>(
+    client: &C,
+    user_id: &Uuid,
+    document_id: &Uuid,
+) -> Result<(), ApiError> {
+    let now: String = expiry::timestamp(chrono::Utc::now());
+    match client
+        .execute(
+            "INSERT INTO document_activity (user_id,document_id,accessed_at) VALUES ($1,$2,$3) \
+             ON CONFLICT (user_id,document_id) DO UPDATE SET accessed_at=EXCLUDED.accessed_at",
+            &[user_id, document_id, &now],
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(_) => Err(database_error()),
+    }
+}
+
+fn parse_document_id(id: &str) -> Result<Uuid, ApiError> {
+    match Uuid::parse_str(id) {
+        Ok(id) => Ok(id),
+        Err(_) => {
+            error!("Failed to parse document id");
+            Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ))
+        }
+    }
+}
+
+/// Stars a document for the requesting user, starring it again keeps when it was first
+/// starred. `id` is the document UUID.
+#[post("/document/<id>/star")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn star_document(
+    id: String,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    request_id: RequestId,
+) -> Result<(), ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+    let user_id: Uuid = actor.require_user()?;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
+    let client = tenancy::lock(db, &tenant).await?;
+    expiry::check_not_archived(&client, &document_id).await?;
+
+    let now: String = expiry::timestamp(chrono::Utc::now());
+    if client
+        .execute(
+            "INSERT INTO document_activity (user_id,document_id,starred_at) VALUES ($1,$2,$3) \
+             ON CONFLICT (user_id,document_id) DO UPDATE SET starred_at=COALESCE(document_activity.starred_at, EXCLUDED.starred_at)",
+            &[&user_id, &document_id, &now],
+        )
+        .await
+        .is_err()
+    {
+        return Err(database_error());
+    }
+
+    info!("Document starred");
+    Ok(())
+}
+
+/// Unstars a document for the requesting user. Unstarring a document that isn't starred is
+/// not an error. `id` is the document UUID.
+#[delete("/document/<id>/star")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn unstar_document(
+    id: String,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    request_id: RequestId,
+) -> Result<(), ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+    let user_id: Uuid = actor.require_user()?;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
+
+    let client = tenancy::lock(db, &tenant).await?;
+    if client
+        .execute(
+            "UPDATE document_activity SET starred_at=NULL WHERE user_id=$1 AND document_id=$2",
+            &[&user_id, &document_id],
+        )
+        .await
+        .is_err()
+    {
+        return Err(database_error());
+    }
+
+    info!("Document unstarred");
+    Ok(())
+}
+
+/// Lists the documents the user starred or accessed, ordered by `order` (`starred_at` or
+/// `accessed_at`), most recent first. Rows where it is NULL are left out.
+async fn list(
+    client: &Client,
+    user_id: &Uuid,
+    order: &str,
+    limit: i64,
+) -> Result<Vec<ActivityDocument>, ApiError> {
+    let query: String = format!(
+        "SELECT d.document_id,d.owner_id,d.title,d.workspace_id,a.starred_at,a.accessed_at,{} \
+         FROM document_activity a JOIN document d ON d.document_id=a.document_id \
+         WHERE a.user_id=$1 AND a.{1} IS NOT NULL AND d.archived_at IS NULL \
+         ORDER BY a.{1} DESC LIMIT $2",
+        tenancy::TAGS_COLUMN,
+        order
+    );
+    let rows = match client.query(&query, &[user_id, &limit]).await {
+        Ok(rows) => rows,
+        Err(_) => return Err(database_error()),
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| ActivityDocument {
+            document_id: row.get(0),
+            owner_id: row.get(1),
+            title: row.get(2),
+            workspace_id: row.get(3),
+            starred_at: row.get(4),
+            accessed_at: row.get(5),
+            tags: row.get(6),
+        })
+        .collect())
+}
+
+/// Lists the documents the requesting user fetched or edited most recently. Deleted and
+/// expired documents are left out. `limit` is 20 by default and at most 100.
+#[get("/me/recent?<limit>")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn list_recent(
+    limit: Option<i64>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<Vec<ActivityDocument>>, ApiError> {
+    let limit: i64 = parse_limit(limit)?;
+    let user_id: Uuid = actor.require_user()?;
+
+    // the query only returns the user's rows, across every organization they belong to
+    let client = tenancy::lock(db, &Tenant::Any).await?;
+    Ok(Json(list(&client, &user_id, "accessed_at", limit).await?))
+}
+
+/// Lists the documents the requesting user starred, most recently starred first. Deleted and
+/// expired documents are left out. `limit` is 20 by default and at most 100.
+#[get("/me/starred?<limit>")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn list_starred(
+    limit: Option<i64>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<Vec<ActivityDocument>>, ApiError> {
+    let limit: i64 = parse_limit(limit)?;
+    let user_id: Uuid = actor.require_user()?;
+
+    let client = tenancy::lock(db, &Tenant::Any).await?;
+    Ok(Json(list(&client, &user_id, "starred_at", limit).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestDatabase, OPERATION_TABLES, ORGANIZATION_TABLES};
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client as LocalClient;
+
+    #[test]
+    fn test_parse_limit() {
+        assert_eq!(parse_limit(None).ok(), Some(DEFAULT_LIMIT));
+        assert_eq!(parse_limit(Some(1)).ok(), Some(1));
+        assert_eq!(parse_limit(Some(MAX_LIMIT)).ok(), Some(MAX_LIMIT));
+        assert!(parse_limit(Some(0)).is_err());
+        assert!(parse_limit(Some(MAX_LIMIT + 1)).is_err());
+    }
+
+    /// The documents of a listing of the user.
+    async fn listing(client: &LocalClient, path: &str, user_id: Uuid) -> Vec<ActivityDocument> {
+        let response = client
+            .get(path.to_string())
+            .header(testing::session(user_id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json().await.unwrap()
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_star_routes() {
+        let database =
+            TestDatabase::create(&format!("{}{}", ORGANIZATION_TABLES, OPERATION_TABLES)).await;
+        let db: Client = database.connect().await;
+        let (owner, stranger) = (Uuid::new_v4(), Uuid::new_v4());
+        let document_id: Uuid = testing::insert_document(&db, owner).await;
+        let rocket = testing::rocket(&database).await.mount(
+            "/",
+            rocket::routes![star_document, unstar_document, list_recent, list_starred],
+        );
+        let client = LocalClient::tracked(rocket).await.unwrap();
+        let uri = format!("/document/{}/star", document_id);
+
+        let anonymous = client.get("/me/starred").dispatch().await;
+        assert_eq!(anonymous.status(), Status::Unauthorized);
+        let response = client
+            .post(uri.clone())
+            .header(testing::session(stranger))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = client
+            .post(uri.clone())
+            .header(testing::session(owner))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let starred: Vec<ActivityDocument> = listing(&client, "/me/starred", owner).await;
+        assert_eq!(starred.len(), 1);
+        assert_eq!(starred[0].document_id, document_id);
+        assert!(starred[0].starred_at.is_some());
+        // starring is per user, and starring isn't opening the document
+        assert!(listing(&client, "/me/starred", stranger).await.is_empty());
+        assert!(listing(&client, "/me/recent", owner).await.is_empty());
+
+        touch(&db, &owner, &document_id).await.unwrap();
+        let recent: Vec<ActivityDocument> = listing(&client, "/me/recent", owner).await;
+        assert_eq!(recent.len(), 1);
+        assert!(recent[0].accessed_at.is_some());
+
+        let response = client
+            .delete(uri.clone())
+            .header(testing::session(owner))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(listing(&client, "/me/starred", owner).await.is_empty());
+        assert_eq!(listing(&client, "/me/recent", owner).await.len(), 1);
+
+        database.drop().await;
+    }
+}
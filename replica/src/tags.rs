@@ -0,0 +1,372 @@
+//! Tags users put on documents to organize them, such as `interview`, `rust` or `wip`.
+//!
+//! Tags are stored in the `document_tags` table, one row per tag of a document. Anyone who can
+//! edit a document can tag it, and the document listings can be filtered by tag.
+
+use crate::pool::Pool;
+use crate::limits::JsonBody;
+use crate::share::Access;
+use crate::tenancy::{self, Tenant};
+use crate::{Actor, ApiError, FieldError, RequestId};
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use rocket::{get, post};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// Longest tag, in characters.
+pub const MAX_TAG_LEN: usize = 32;
+
+/// Most tags a document may have.
+pub const MAX_TAGS_PER_DOCUMENT: usize = 20;
+
+/// Request body for changing a document's tags.
+/// `add`: Tags to put on the document.
+/// `remove`: Tags to take off the document.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TagsRequest {
+    pub add: Vec<String>,
+    pub remove: Vec<String>,
+}
+
+/// Response body with a document's tags.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocumentTags {
+    pub document_id: Uuid,
+    pub tags: Vec<String>,
+}
+
+/// Returns the tag in its stored form, lowercase and trimmed, or why it is invalid. Tags are
+/// made of letters, digits, `-` and `_`.
+pub fn normalize_tag(tag: &str) -> Result<String, String> {
+    let tag: String = tag.trim().to_lowercase();
+    if tag.is_empty() {
+        return Err("must not be empty".to_string());
+    }
+    if tag.chars().count() > MAX_TAG_LEN {
+        return Err(format!("must be at most {} characters", MAX_TAG_LEN));
+    }
+    if !tag
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err("may only contain letters, digits, '-' and '_'".to_string());
+    }
+    Ok(tag)
+}
+
+impl TagsRequest {
+    /// Returns the normalized tags to add and to remove, or the invalid fields.
+    pub fn normalize(&self) -> Result<(BTreeSet<String>, BTreeSet<String>), ApiError> {
+        let mut errors: Vec<FieldError> = Vec::new();
+        let mut normalize = |field: &str, tags: &[String]| -> BTreeSet<String> {
+            let mut normalized: BTreeSet<String> = BTreeSet::new();
+            for (i, tag) in tags.iter().enumerate() {
+                match normalize_tag(tag) {
+                    Ok(tag) => {
+                        normalized.insert(tag);
+                    }
+                    Err(message) => {
+                        errors.push(FieldError::new(&format!("{}[{}]", field, i), &message))
+                    }
+                }
+            }
+            normalized
+        };
+        let add: BTreeSet<String> = normalize("add", &self.add);
+        let remove: BTreeSet<String> = normalize("remove", &self.remove);
+
+        if add.is_empty() && remove.is_empty() && errors.is_empty() {
+            errors.push(FieldError::new("add", "add or remove must not be empty"));
+        }
+        if let Some(tag) = add.intersection(&remove).next() {
+            errors.push(FieldError::new(
+                "remove",
+                &format!("must not remove the added tag {}", tag),
+            ));
+        }
+        if !errors.is_empty() {
+            error!("Rejected tags request with {} invalid fields", errors.len());
+            return Err(ApiError::ValidationFailed(errors));
+        }
+        Ok((add, remove))
+    }
+}
+
+/// Parses the `tag` query parameter of a listing. Listings filtered by an invalid tag would
+/// always be empty, so they are rejected instead.
+pub fn parse_filter(tag: Option<&str>) -> Result<Option<String>, ApiError> {
+    match tag.map(normalize_tag) {
+        None => Ok(None),
+        Some(Ok(tag)) => Ok(Some(tag)),
+        Some(Err(message)) => Err(ApiError::ValidationFailed(vec![FieldError::new(
+            "tag", &message,
+        )])),
+    }
+}
+
+fn database_error() -> ApiError {
+    error!("Failed to query the document_tags table");
+    ApiError::DatabaseError("Failed to query the document_tags table".to_string())
+}
+
+/// Returns the document's tags in alphabetical order.
+#[instrument(name = "db.fetch_tags", skip(client))]
+pub async fn fetch_tags(client: &Client, document_id: &Uuid) -> Result<Vec<String>, ApiError> {
+    match client
+        .query(
+            "SELECT tag FROM document_tags WHERE document_id=$1 ORDER BY tag",
+            &[document_id],
+        )
+        .await
+    {
+        Ok(rows) => Ok(rows.iter().map(|row| row.get(0)).collect()),
+        Err(_) => Err(database_error()),
+    }
+}
+
+/// Checks the document exists, it is reported as not found otherwise.
+async fn require_document(client: &Client, document_id: &Uuid) -> Result<(), ApiError> {
+    match client
+        .query_opt(
+            "SELECT 1 FROM document WHERE document_id=$1 AND archived_at IS NULL",
+            &[document_id],
+        )
+        .await
+    {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(ApiError::NotFound("Document not found".to_string())),
+        Err(_) => {
+            error!("Failed to query the document table");
+            Err(ApiError::DatabaseError(
+                "Failed to query the document table".to_string(),
+            ))
+        }
+    }
+}
+
+fn parse_document_id(id: &str) -> Result<Uuid, ApiError> {
+    match Uuid::parse_str(id) {
+        Ok(id) => Ok(id),
+        Err(_) => {
+            error!("Failed to parse document id");
+            Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ))
+        }
+    }
+}
+
+/// Returns the tags of a document. `id` is the document UUID.
+#[get("/document/<id>/tags")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn fetch_document_tags(
+    id: String,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    request_id: RequestId,
+) -> Result<Json<DocumentTags>, ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
+    let client = tenancy::lock(db, &tenant).await?;
+    require_document(&client, &document_id).await?;
+
+    Ok(Json(DocumentTags {
+        document_id,
+        tags: fetch_tags(&client, &document_id).await?,
+    }))
+}
+
+/// Adds and removes tags on a document, returning its tags. Tags are lowercased, adding a tag
+/// the document has or removing one it doesn't have is not an error. `id` is the document UUID.
+///
+/// Example Request
+/// {
+///     "add" : ["rust", "interview"],
+///     "remove" : ["wip"]
+/// }
+#[post("/document/<id>/tags", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn update_document_tags(
+    id: String,
+    request: JsonBody<TagsRequest>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    request_id: RequestId,
+) -> Result<Json<DocumentTags>, ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+    let (add, remove) = request.normalize()?;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::ReadWrite).await?;
+
+    let mut client = tenancy::lock(db, &tenant).await?;
+    require_document(&client, &document_id).await?;
+    let tx = match client.transaction().await {
+        Ok(tx) => tx,
+        Err(_) => {
+            error!("Failed to start database transaction");
+            return Err(ApiError::DatabaseError(
+                "Failed to start database transaction".to_string(),
+            ));
+        }
+    };
+
+    let remove: Vec<String> = remove.into_iter().collect();
+    let add: Vec<String> = add.into_iter().collect();
+    if tx
+        .execute(
+            "DELETE FROM document_tags WHERE document_id=$1 AND tag = ANY($2)",
+            &[&document_id, &remove],
+        )
+        .await
+        .is_err()
+        || tx
+            .execute(
+                "INSERT INTO document_tags (document_id,tag) SELECT $1, unnest($2::text[]) ON CONFLICT DO NOTHING",
+                &[&document_id, &add],
+            )
+            .await
+            .is_err()
+    {
+        return Err(database_error());
+    }
+
+    let tags: Vec<String> = match tx
+        .query(
+            "SELECT tag FROM document_tags WHERE document_id=$1 ORDER BY tag",
+            &[&document_id],
+        )
+        .await
+    {
+        Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+        Err(_) => return Err(database_error()),
+    };
+    if tags.len() > MAX_TAGS_PER_DOCUMENT {
+        // dropping the transaction rolls the change back
+        error!("Rejected tags over the limit of {}", MAX_TAGS_PER_DOCUMENT);
+        return Err(ApiError::ValidationFailed(vec![FieldError::new(
+            "add",
+            &format!(
+                "would give the document {} tags, the limit is {}",
+                tags.len(),
+                MAX_TAGS_PER_DOCUMENT
+            ),
+        )]));
+    }
+
+    if tx.commit().await.is_err() {
+        error!("Failed to commit database transaction");
+        return Err(ApiError::DatabaseError(
+            "Failed to commit database transaction".to_string(),
+        ));
+    }
+    info!(
+        added = add.len(),
+        removed = remove.len(),
+        "Updated document tags"
+    );
+    Ok(Json(DocumentTags { document_id, tags }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestDatabase, ORGANIZATION_TABLES};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::asynchronous::Client as LocalClient;
+
+    #[test]
+    fn test_normalize_tag() {
+        assert_eq!(normalize_tag(" Rust "), Ok("rust".to_string()));
+        assert_eq!(
+            normalize_tag("work_in-progress"),
+            Ok("work_in-progress".to_string())
+        );
+        assert!(normalize_tag("").is_err());
+        assert!(normalize_tag("two words").is_err());
+        assert!(normalize_tag("c++").is_err());
+        assert!(normalize_tag(&"a".repeat(MAX_TAG_LEN + 1)).is_err());
+
+        let request = |add: &[&str], remove: &[&str]| TagsRequest {
+            add: add.iter().map(|tag| tag.to_string()).collect(),
+            remove: remove.iter().map(|tag| tag.to_string()).collect(),
+        };
+        let (add, remove) = request(&["WIP", "wip", "rust"], &[]).normalize().unwrap();
+        assert_eq!(add.into_iter().collect::<Vec<_>>(), vec!["rust", "wip"]);
+        assert!(remove.is_empty());
+
+        let fields = |request: TagsRequest| match request.normalize() {
+            Err(ApiError::ValidationFailed(errors)) => {
+                errors.into_iter().map(|e| e.field).collect::<Vec<_>>()
+            }
+            _ => Vec::new(),
+        };
+        assert_eq!(fields(request(&[], &[])), vec!["add"]);
+        assert_eq!(fields(request(&["ok", "not ok"], &[])), vec!["add[1]"]);
+        assert_eq!(fields(request(&["rust"], &["Rust"])), vec!["remove"]);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_tag_routes() {
+        let database = TestDatabase::create(ORGANIZATION_TABLES).await;
+        let db: Client = database.connect().await;
+        let (owner, reader, stranger) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let document_id: Uuid = testing::insert_document(&db, owner).await;
+        testing::insert_member(&db, document_id, reader, "read").await;
+        let rocket = testing::rocket(&database).await.mount(
+            "/",
+            rocket::routes![fetch_document_tags, update_document_tags],
+        );
+        let client = LocalClient::tracked(rocket).await.unwrap();
+        let uri = format!("/document/{}/tags", document_id);
+        let update = |user_id: Uuid, body: &'static str| {
+            client
+                .post(uri.clone())
+                .header(ContentType::JSON)
+                .header(testing::session(user_id))
+                .body(body)
+                .dispatch()
+        };
+
+        let anonymous = client.get(uri.clone()).dispatch().await;
+        assert_eq!(anonymous.status(), Status::Unauthorized);
+        let response = client
+            .get(uri.clone())
+            .header(testing::session(stranger))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        // readers see the tags but can't change them
+        let response = update(reader, r#"{"add":["rust"]}"#).await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = update(owner, r#"{"add":["Rust","wip"]}"#).await;
+        assert_eq!(response.status(), Status::Ok);
+        let tags: DocumentTags = response.into_json().await.unwrap();
+        assert_eq!(tags.tags, vec!["rust", "wip"]);
+        let response = update(owner, r#"{"add":["interview"],"remove":["wip"]}"#).await;
+        let tags: DocumentTags = response.into_json().await.unwrap();
+        assert_eq!(tags.tags, vec!["interview", "rust"]);
+        let response = update(owner, r#"{"add":["not ok"]}"#).await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        let response = client
+            .get(uri.clone())
+            .header(testing::session(reader))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let tags: DocumentTags = response.into_json().await.unwrap();
+        assert_eq!(tags.document_id, document_id);
+        assert_eq!(tags.tags, vec!["interview", "rust"]);
+
+        database.drop().await;
+    }
+}
@@ -0,0 +1,103 @@
+use crate::{FlatJson, LogFormat, LoggingConfig};
+use std::fs::OpenOptions;
+use std::sync::Mutex;
+use tracing_subscriber::fmt::format::JsonFields;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Name reported for the replica's spans.
+pub const SERVICE_NAME: &str = "nimble-replica";
+
+/// Installs the global tracing subscriber.
+///
+/// Events are written to stdout (or the configured file) as JSON or pretty text, filtered by
+/// the configured levels. Existing `log` records (including Rocket's) are forwarded into tracing.
+/// When built with the `otlp` feature and `OTEL_EXPORTER_OTLP_ENDPOINT` is set, spans are
+/// also exported over OTLP so an edit can be followed from the load balancer to the broadcast.
+pub fn init_tracing(config: &LoggingConfig) {
+    let json = config.format == LogFormat::Json;
+
+    let registry = tracing_subscriber::registry()
+        .with(config.filter())
+        .with(json.then(|| {
+            tracing_subscriber::fmt::layer()
+                .fmt_fields(JsonFields::new())
+                .event_format(FlatJson)
+                .with_writer(writer(config))
+        }))
+        .with((!json).then(|| tracing_subscriber::fmt::layer().with_writer(writer(config))));
+
+    #[cfg(feature = "otlp")]
+    let registry = registry.with(otlp::layer());
+
+    if registry.try_init().is_err() {
+        eprintln!("Tracing subscriber was already initialized");
+    }
+}
+
+/// Appends to the configured log file, or writes to stdout if none is set or it can't be opened.
+fn writer(config: &LoggingConfig) -> BoxMakeWriter {
+    let path = match &config.file {
+        Some(path) => path,
+        None => return BoxMakeWriter::new(std::io::stdout),
+    };
+
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => BoxMakeWriter::new(Mutex::new(file)),
+        Err(e) => {
+            eprintln!("Failed to open log file {}: {}, logging to stdout", path, e);
+            BoxMakeWriter::new(std::io::stdout)
+        }
+    }
+}
+
+/// Flushes any spans still waiting to be exported.
+pub fn shutdown_tracing() {
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::TracerProvider;
+    use opentelemetry_sdk::{runtime, Resource};
+    use tracing_opentelemetry::OpenTelemetryLayer;
+    use tracing_subscriber::registry::LookupSpan;
+
+    /// Builds the OTLP export layer, or `None` if no collector endpoint is configured.
+    pub fn layer<S>() -> Option<OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+
+        let exporter = match SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()
+        {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("Failed to create OTLP exporter: {}", e);
+                return None;
+            }
+        };
+
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                super::SERVICE_NAME,
+            )]))
+            .build();
+
+        let tracer = provider.tracer(super::SERVICE_NAME);
+        opentelemetry::global::set_tracer_provider(provider);
+
+        Some(tracing_opentelemetry::layer().with_tracer(tracer))
+    }
+}
@@ -0,0 +1,1178 @@
+//! Multi-tenancy: organizations, their members and workspaces.
+//!
+//! Users belong to organizations with a role, and documents created with a `workspace_id`
+//! belong to that workspace of an organization. Documents in a workspace can only be used by
+//! members of its organization (viewers read, the other roles also write) or with a share link,
+//! and are listed per workspace. Documents created without a workspace are personal and keep
+//! the behaviour they had before organizations.
+//!
+//! With `tenancy.isolation = "row_level"` the database enforces the same boundary: the replica
+//! installs row level security policies on the document tables when it starts, and scopes its
+//! connection to the tenant of the request (`Tenant`) with the `nimble.tenant` setting before
+//! every query, so a query a route gets wrong can't read or change another organization's rows.
+//! Background jobs work across tenants with `Tenant::Any`.
+
+use crate::limits::JsonBody;
+use crate::notifications::{self, NotificationKind};
+use crate::share::Access;
+use crate::{expiry, projects, tags, users, Actor, ApiError, FieldError, Quotas, RequestId};
+use rocket::fairing::AdHoc;
+use rocket::serde::json::Json;
+use rocket::tokio::sync::{Mutex, MutexGuard};
+use rocket::{delete, get, post};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, OnceLock};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+/// The longest organization or workspace name accepted.
+pub const MAX_NAME_LEN: usize = 100;
+
+/// The connection setting holding the tenant row level security policies compare against.
+pub const TENANT_SETTING: &str = "nimble.tenant";
+
+/// How tenants' rows are isolated from each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Isolation {
+    /// Checked by the replica's routes only.
+    Shared,
+    /// Also enforced by row level security policies in the database.
+    RowLevel,
+}
+
+/// `isolation`: `shared` or `row_level`. Row level isolation needs the replica to connect as
+/// a role without `BYPASSRLS` (not a superuser).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TenancyConfig {
+    pub isolation: Isolation,
+}
+
+impl Default for TenancyConfig {
+    fn default() -> Self {
+        TenancyConfig {
+            isolation: Isolation::Shared,
+        }
+    }
+}
+
+static ISOLATION: OnceLock<Isolation> = OnceLock::new();
+
+fn row_level() -> bool {
+    ISOLATION.get() == Some(&Isolation::RowLevel)
+}
+
+/// The rows a database connection can see under row level isolation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tenant {
+    /// Every document, for background jobs and administrators.
+    Any,
+    /// Personal documents only.
+    Personal,
+    /// Personal documents and the documents of one organization.
+    Org(Uuid),
+}
+
+impl Tenant {
+    /// The value of `TENANT_SETTING` for the tenant.
+    pub fn setting(&self) -> String {
+        match self {
+            Tenant::Any => "*".to_string(),
+            Tenant::Personal => String::new(),
+            Tenant::Org(org_id) => org_id.to_string(),
+        }
+    }
+}
+
+/// Scopes the connection to a tenant, unless isolation is `shared`.
+pub async fn scope(client: &Client, tenant: &Tenant) -> Result<(), ApiError> {
+    if !row_level() {
+        return Ok(());
+    }
+    match client
+        .execute(
+            "SELECT set_config($1, $2, false)",
+            &[&TENANT_SETTING, &tenant.setting()],
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            error!("Failed to scope the database connection to the tenant");
+            Err(ApiError::DatabaseError(
+                "Failed to scope the database connection".to_string(),
+            ))
+        }
+    }
+}
+
+/// Locks the database client scoped to a tenant.
+pub async fn lock<'a>(
+    db: &'a Mutex<Client>,
+    tenant: &Tenant,
+) -> Result<MutexGuard<'a, Client>, ApiError> {
+    let client = db.lock().await;
+    scope(&client, tenant).await?;
+    Ok(client)
+}
+
+/// Locks the database client for a background job working across tenants. If the scope can't
+/// be set the job runs with the previous one, which can only hide rows from it.
+pub async fn lock_any(db: &Mutex<Client>) -> MutexGuard<'_, Client> {
+    let client = db.lock().await;
+    if let Err(e) = scope(&client, &Tenant::Any).await {
+        warn!("Background job runs with the previous tenant scope: {}", e);
+    }
+    client
+}
+
+/// The tables isolated by row level security by whether their document is visible. `document`
+/// is filtered by tenant, and every other table with a `document_id` must be listed here or in
+/// [`ISOLATED_BY`].
+const ISOLATED_TABLES: [&str; 11] = [
+    "operations",
+    "document_snapshots",
+    "audit_log",
+    "chat_messages",
+    "document_quota",
+    "document_checkpoints",
+    "document_tags",
+    "document_activity",
+    "document_invitations",
+    "document_members",
+    "broadcast_outbox",
+];
+
+/// The other tables isolated by row level security, with the rows a tenant sees and the rows
+/// it may write, `{tenant}` standing for the connection's tenant.
+const ISOLATED_BY: [(&str, &str, &str); 3] = [
+    // projects are visible like the documents of their workspace
+    ("projects", IN_TENANT_WORKSPACE, IN_TENANT_WORKSPACE),
+    // usage of personal documents has no organization
+    (
+        "usage_events",
+        "org_id IS NULL OR org_id::text = {tenant}",
+        "org_id IS NULL OR org_id::text = {tenant}",
+    ),
+    // notifications may be addressed to users of any tenant, so they are only read by the
+    // routes listing a user's own, which work across tenants
+    ("notifications", "false", "true"),
+];
+
+/// Rows that are personal or in a workspace of the tenant.
+const IN_TENANT_WORKSPACE: &str = "workspace_id IS NULL OR workspace_id IN (SELECT workspace_id \
+     FROM workspaces WHERE org_id::text = {tenant})";
+
+/// The statements enabling row level security on a table, and replacing its policy with one
+/// letting every connection working across tenants through and the others see the `visible`
+/// rows and write the `writable` ones.
+fn policy(table: &str, visible: &str, writable: &str) -> String {
+    let tenant: String = format!("current_setting('{}', true)", TENANT_SETTING);
+    format!(
+        "ALTER TABLE {table} ENABLE ROW LEVEL SECURITY; \
+         ALTER TABLE {table} FORCE ROW LEVEL SECURITY; \
+         DROP POLICY IF EXISTS nimble_tenant ON {table}; \
+         CREATE POLICY nimble_tenant ON {table} \
+             USING ({tenant} = '*' OR ({visible})) \
+             WITH CHECK ({tenant} = '*' OR ({writable})); ",
+        visible = visible.replace("{tenant}", &tenant),
+        writable = writable.replace("{tenant}", &tenant),
+    )
+}
+
+/// Creates (or replaces) the row level security policies isolating tenants.
+pub async fn install_row_level_security(client: &Client) -> Result<(), ApiError> {
+    let mut statements: String = policy("document", IN_TENANT_WORKSPACE, IN_TENANT_WORKSPACE);
+    for table in ISOLATED_TABLES {
+        let visible: &str = "document_id IN (SELECT document_id FROM document)";
+        statements.push_str(&policy(table, visible, visible));
+    }
+    for (table, visible, writable) in ISOLATED_BY {
+        statements.push_str(&policy(table, visible, writable));
+    }
+
+    match client.batch_execute(&statements).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Failed to install the row level security policies: {}", e);
+            Err(ApiError::DatabaseError(
+                "Failed to install the row level security policies".to_string(),
+            ))
+        }
+    }
+}
+
+/// Fairing applying the isolation mode, installing the row level security policies when it is
+/// `row_level`. Attached after the database.
+pub fn attach_isolation(config: TenancyConfig) -> AdHoc {
+    AdHoc::on_ignite("Tenant isolation", move |rocket| async move {
+        let _ = ISOLATION.set(config.isolation);
+        if config.isolation != Isolation::RowLevel {
+            return rocket;
+        }
+
+        let installed = match rocket.state::<Arc<Mutex<Client>>>() {
+            Some(db) => install_row_level_security(&*db.lock().await).await,
+            None => Err(ApiError::DatabaseError(
+                "The database is not attached".to_string(),
+            )),
+        };
+        if let Err(e) = installed {
+            error!("Unable to start server, failed to isolate tenants: {}", e);
+            eprintln!("Failed to isolate tenants: {:?}", e);
+            std::process::exit(1);
+        }
+        info!("Tenants are isolated with row level security");
+        rocket
+    })
+}
+
+/// A member's role in an organization, in increasing order of privilege.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Viewer,
+    Member,
+    Admin,
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Member => "member",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "viewer" => Some(Role::Viewer),
+            "member" => Some(Role::Member),
+            "admin" => Some(Role::Admin),
+            "owner" => Some(Role::Owner),
+            _ => None,
+        }
+    }
+
+    /// The access the role grants to the organization's documents.
+    pub fn access(&self) -> Access {
+        match self {
+            Role::Viewer => Access::Read,
+            _ => Access::ReadWrite,
+        }
+    }
+}
+
+/// Request body for creating an organization or a workspace.
+#[derive(Debug, Deserialize)]
+pub struct NameRequest {
+    pub name: String,
+}
+
+impl NameRequest {
+    /// Returns the trimmed name, or the invalid field.
+    pub fn name(&self) -> Result<String, FieldError> {
+        let name: &str = self.name.trim();
+        if name.is_empty() {
+            Err(FieldError::new("name", "must not be empty"))
+        } else if name.chars().count() > MAX_NAME_LEN {
+            Err(FieldError::new(
+                "name",
+                &format!("must be at most {} characters", MAX_NAME_LEN),
+            ))
+        } else {
+            Ok(name.to_string())
+        }
+    }
+}
+
+/// Request body for adding a member or changing their role.
+#[derive(Debug, Deserialize)]
+pub struct MemberRequest {
+    pub user_id: Uuid,
+    pub role: Role,
+}
+
+/// An organization the requesting user belongs to.
+/// `role`: The requesting user's role in it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Organization {
+    pub org_id: Uuid,
+    pub name: String,
+    pub created_at: String,
+    pub role: Role,
+}
+
+/// A member of an organization.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Member {
+    pub user_id: Uuid,
+    pub role: Role,
+    pub joined_at: String,
+}
+
+/// A workspace of an organization.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Workspace {
+    pub workspace_id: Uuid,
+    pub org_id: Uuid,
+    pub name: String,
+    pub created_at: String,
+}
+
+/// A document listed in a workspace.
+/// `tags`: The document's tags in alphabetical order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkspaceDocument {
+    pub document_id: Uuid,
+    pub owner_id: Uuid,
+    pub title: String,
+    pub creation_date: String,
+    pub language: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// A document the requesting user can open, listed by `GET /me/documents`.
+/// `owned`: Whether the user created the document.
+/// `org_id`: The organization of the document's workspace, None for a personal document.
+/// `role`: The user's role in that organization, None for a personal document.
+/// `access`: What the user may do with the document.
+/// `last_activity`: When the document was last edited, or created if it never was.
+/// `tags`: The document's tags in alphabetical order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserDocument {
+    pub document_id: Uuid,
+    pub owner_id: Uuid,
+    pub title: String,
+    pub creation_date: String,
+    pub language: Option<String>,
+    pub workspace_id: Option<Uuid>,
+    pub org_id: Option<Uuid>,
+    pub owned: bool,
+    pub role: Option<Role>,
+    pub access: Access,
+    pub last_activity: String,
+    pub tags: Vec<String>,
+}
+
+fn parse_id(id: &str, name: &str) -> Result<Uuid, ApiError> {
+    match Uuid::parse_str(id) {
+        Ok(id) => Ok(id),
+        Err(_) => {
+            error!("Failed to parse {} id", name);
+            Err(ApiError::InvalidOperation(format!(
+                "Failed to parse {} id",
+                name
+            )))
+        }
+    }
+}
+
+fn database_error(table: &str) -> ApiError {
+    error!("Failed to query the {} table", table);
+    ApiError::DatabaseError(format!("Failed to query the {} table", table))
+}
+
+/// Returns the user's role in an organization, None if they are not a member.
+pub async fn member_role(
+    client: &Client,
+    org_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<Option<Role>, ApiError> {
+    match client
+        .query_opt(
+            "SELECT role FROM org_members WHERE org_id=$1 AND user_id=$2",
+            &[org_id, user_id],
+        )
+        .await
+    {
+        Ok(row) => Ok(row.and_then(|row| Role::parse(row.get(0)))),
+        Err(_) => Err(database_error("org_members")),
+    }
+}
+
+/// Returns the user's role, or `NotFound` unless they are a member with at least `role`.
+/// Non-members get `NotFound` so organizations can't be discovered by id.
+pub async fn require_role(
+    client: &Client,
+    org_id: &Uuid,
+    user_id: &Uuid,
+    role: Role,
+) -> Result<Role, ApiError> {
+    match member_role(client, org_id, user_id).await? {
+        Some(member) if member >= role => Ok(member),
+        Some(_) => {
+            error!("Organization action needs the {} role", role.as_str());
+            Err(ApiError::Forbidden(format!(
+                "This needs the {} role in the organization",
+                role.as_str()
+            )))
+        }
+        None => {
+            error!("Organization requested by a user who is not a member");
+            Err(ApiError::NotFound("Organization not found".to_string()))
+        }
+    }
+}
+
+/// Returns the organization a workspace belongs to, or `NotFound`.
+pub async fn workspace_org(client: &Client, workspace_id: &Uuid) -> Result<Uuid, ApiError> {
+    match client
+        .query_opt(
+            "SELECT org_id FROM workspaces WHERE workspace_id=$1",
+            &[workspace_id],
+        )
+        .await
+    {
+        Ok(Some(row)) => Ok(row.get(0)),
+        Ok(None) => Err(ApiError::NotFound("Workspace not found".to_string())),
+        Err(_) => Err(database_error("workspaces")),
+    }
+}
+
+/// Checks that a user with `role` in a document's organization may use it with `access`.
+fn check_access(role: Option<Role>, user_id: Option<Uuid>, access: Access) -> Result<(), ApiError> {
+    match role {
+        Some(role) if role.access() >= access => Ok(()),
+        Some(_) => {
+            error!("Viewer tried to change a workspace document");
+            Err(ApiError::Forbidden(
+                "Viewers can only read the organization's documents".to_string(),
+            ))
+        }
+        None if user_id.is_none() => Err(ApiError::Unauthorized(
+            "Workspace documents need a user or a share token".to_string(),
+        )),
+        None => {
+            error!("Workspace document requested by a user outside its organization");
+            Err(ApiError::Forbidden(
+                "The document belongs to another organization".to_string(),
+            ))
+        }
+    }
+}
+
+/// Checks that a user other than the owner of a personal document may use it, which they
+/// can't without a membership, project grant or share token.
+fn check_personal(user_id: Option<Uuid>) -> Result<(), ApiError> {
+    match user_id {
+        None => Err(ApiError::Unauthorized(
+            "Personal documents need their owner or a share token".to_string(),
+        )),
+        Some(_) => {
+            error!("Personal document requested by a user other than its owner");
+            Err(ApiError::Forbidden(
+                "The document belongs to another user".to_string(),
+            ))
+        }
+    }
+}
+
+/// Resolves the tenant of a document and checks that a user may use it with `access`.
+/// Personal documents need their owner, documents in a workspace a role in its organization,
+/// and either can be used with a grant on their project or a membership of the document (see
+/// `invitations`) giving `access`. Nothing is checked if `shared` (the request's share token
+/// was checked).
+#[instrument(name = "db.authorize_document", skip(client))]
+pub async fn authorize(
+    client: &Client,
+    document_id: &Uuid,
+    user_id: Option<Uuid>,
+    access: Access,
+    shared: bool,
+) -> Result<Tenant, ApiError> {
+    let row = match client
+        .query_opt(
+            "SELECT w.org_id, m.role, d.project_id, dm.access, d.owner_id FROM document d LEFT JOIN workspaces w ON w.workspace_id=d.workspace_id \
+             LEFT JOIN org_members m ON m.org_id=w.org_id AND m.user_id=$2 \
+             LEFT JOIN document_members dm ON dm.document_id=d.document_id AND dm.user_id=$2 WHERE d.document_id=$1",
+            &[document_id, &user_id],
+        )
+        .await
+    {
+        Ok(row) => row,
+        Err(_) => return Err(database_error("document")),
+    };
+
+    let (org_id, role, project_id, member, owner_id): (
+        Option<Uuid>,
+        Option<Role>,
+        Option<Uuid>,
+        Option<Access>,
+        Uuid,
+    ) = match row {
+        // documents that don't exist are left to the route
+        None => return Ok(Tenant::Personal),
+        Some(row) => (
+            row.get(0),
+            row.get::<_, Option<&str>>(1).and_then(Role::parse),
+            row.get(2),
+            row.get::<_, Option<&str>>(3).and_then(Access::parse),
+            row.get(4),
+        ),
+    };
+    let owns_personal: bool = org_id.is_none() && user_id == Some(owner_id);
+    if !shared && !owns_personal {
+        // the project's grants only matter when the role and membership don't give enough access
+        let granted: Option<Access> = match (project_id, user_id) {
+            (Some(project_id), Some(user_id))
+                if role.map(|role| role.access()).max(member) < Some(access) =>
+            {
+                projects::access(client, &project_id, &user_id).await?
+            }
+            _ => None,
+        }
+        .max(member);
+        if granted < Some(access) {
+            match org_id {
+                Some(_) => check_access(role, user_id, access)?,
+                None => check_personal(user_id)?,
+            }
+        }
+    }
+    Ok(org_id.map_or(Tenant::Personal, Tenant::Org))
+}
+
+/// Checks that the owner of a new document can create it in the workspace and that the
+/// organization is below `max_documents_per_org`. Returns the workspace's organization.
+#[instrument(name = "db.check_workspace", skip(client, quotas))]
+pub async fn check_workspace(
+    client: &Client,
+    workspace_id: &Uuid,
+    owner_id: &Uuid,
+    quotas: &Quotas,
+) -> Result<Uuid, ApiError> {
+    let org_id: Uuid = match workspace_org(client, workspace_id).await {
+        Err(ApiError::NotFound(_)) => {
+            return Err(ApiError::ValidationFailed(vec![FieldError::new(
+                "workspace_id",
+                "must be an existing workspace",
+            )]))
+        }
+        result => result?,
+    };
+    match member_role(client, &org_id, owner_id).await? {
+        Some(role) if role >= Role::Member => (),
+        _ => {
+            error!("Document created in a workspace by a user who can't write to it");
+            return Err(ApiError::Forbidden(
+                "The owner can't create documents in this workspace".to_string(),
+            ));
+        }
+    }
+
+    let documents: i64 = match client
+        .query_one(
+            "SELECT COUNT(*) FROM document d JOIN workspaces w ON w.workspace_id=d.workspace_id WHERE w.org_id=$1",
+            &[&org_id],
+        )
+        .await
+    {
+        Ok(row) => row.get(0),
+        Err(_) => return Err(database_error("document")),
+    };
+    if documents >= quotas.max_documents_per_org {
+        return Err(ApiError::QuotaExceeded(format!(
+            "Organization has reached the limit of {} documents",
+            quotas.max_documents_per_org
+        )));
+    }
+    Ok(org_id)
+}
+
+/// Creates an organization, the requesting user becomes its owner.
+///
+/// Example Request:
+/// {
+///     "name": "Acme"
+/// }
+#[post("/orgs", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn create_organization(
+    request: JsonBody<NameRequest>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<Organization>, ApiError> {
+    let user_id: Uuid = actor.require_user()?;
+    let name: String = match request.name() {
+        Ok(name) => name,
+        Err(error) => return Err(ApiError::ValidationFailed(vec![error])),
+    };
+    let created_at: String = expiry::timestamp(chrono::Utc::now());
+
+    let mut client = db.lock().await;
+    users::check_owner(&client, &user_id).await?;
+    let tx = match client.transaction().await {
+        Ok(tx) => tx,
+        Err(_) => {
+            error!("Failed to start database transaction");
+            return Err(ApiError::DatabaseError(
+                "Failed to start transaction".to_string(),
+            ));
+        }
+    };
+
+    let org_id: Uuid = match tx
+        .query_one(
+            "INSERT INTO organizations (name,created_at) VALUES ($1,$2) RETURNING org_id",
+            &[&name, &created_at],
+        )
+        .await
+    {
+        Ok(row) => row.get(0),
+        Err(_) => {
+            error!("Failed to insert into the organizations table");
+            return Err(ApiError::DatabaseError(
+                "Failed to create the organization".to_string(),
+            ));
+        }
+    };
+    if tx
+        .execute(
+            "INSERT INTO org_members (org_id,user_id,role,joined_at) VALUES ($1,$2,$3,$4)",
+            &[&org_id, &user_id, &Role::Owner.as_str(), &created_at],
+        )
+        .await
+        .is_err()
+    {
+        error!("Failed to insert into the org_members table");
+        return Err(ApiError::DatabaseError(
+            "Failed to create the organization".to_string(),
+        ));
+    }
+    if tx.commit().await.is_err() {
+        error!("Failed to commit the organization");
+        return Err(ApiError::DatabaseError(
+            "Failed to create the organization".to_string(),
+        ));
+    }
+
+    info!(org_id = %org_id, "Organization created");
+    Ok(Json(Organization {
+        org_id,
+        name,
+        created_at,
+        role: Role::Owner,
+    }))
+}
+
+/// Lists the organizations the requesting user belongs to.
+#[get("/orgs")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn list_organizations(
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<Vec<Organization>>, ApiError> {
+    let user_id: Uuid = actor.require_user()?;
+
+    let client = db.lock().await;
+    let rows = match client
+        .query(
+            "SELECT o.org_id,o.name,o.created_at,m.role FROM organizations o \
+             JOIN org_members m ON m.org_id=o.org_id WHERE m.user_id=$1 ORDER BY o.name",
+            &[&user_id],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => return Err(database_error("organizations")),
+    };
+
+    Ok(Json(
+        rows.iter()
+            .filter_map(|row| {
+                Some(Organization {
+                    org_id: row.get(0),
+                    name: row.get(1),
+                    created_at: row.get(2),
+                    role: Role::parse(row.get(3))?,
+                })
+            })
+            .collect(),
+    ))
+}
+
+/// Lists the members of an organization, for its members.
+#[get("/orgs/<id>/members")]
+#[instrument(skip_all, fields(request_id = %request_id, org_id = %id))]
+pub async fn list_members(
+    id: String,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<Vec<Member>>, ApiError> {
+    let org_id: Uuid = parse_id(&id, "organization")?;
+    let user_id: Uuid = actor.require_user()?;
+
+    let client = db.lock().await;
+    require_role(&client, &org_id, &user_id, Role::Viewer).await?;
+    let rows = match client
+        .query(
+            "SELECT user_id,role,joined_at FROM org_members WHERE org_id=$1 ORDER BY joined_at",
+            &[&org_id],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => return Err(database_error("org_members")),
+    };
+
+    Ok(Json(
+        rows.iter()
+            .filter_map(|row| {
+                Some(Member {
+                    user_id: row.get(0),
+                    role: Role::parse(row.get(1))?,
+                    joined_at: row.get(2),
+                })
+            })
+            .collect(),
+    ))
+}
+
+/// Adds a registered user to an organization, or changes their role. Needs the admin role,
+/// and only owners can make or change owners.
+///
+/// Example Request:
+/// {
+///     "user_id": "550e8400-e29b-41d4-a716-446655440000",
+///     "role": "member"
+/// }
+#[post("/orgs/<id>/members", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id, org_id = %id))]
+pub async fn add_member(
+    id: String,
+    request: JsonBody<MemberRequest>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<Member>, ApiError> {
+    let org_id: Uuid = parse_id(&id, "organization")?;
+    let user_id: Uuid = actor.require_user()?;
+
+    let client = db.lock().await;
+    let role: Role = require_role(&client, &org_id, &user_id, Role::Admin).await?;
+    let current: Option<Role> = member_role(&client, &org_id, &request.user_id).await?;
+    if role < Role::Owner && (request.role == Role::Owner || current == Some(Role::Owner)) {
+        error!("Admin tried to change an owner");
+        return Err(ApiError::Forbidden(
+            "Only owners can make or change owners".to_string(),
+        ));
+    }
+    if current == Some(Role::Owner)
+        && request.role < Role::Owner
+        && owners(&client, &org_id).await? <= 1
+    {
+        return Err(ApiError::Conflict(
+            "An organization needs at least one owner".to_string(),
+        ));
+    }
+    users::check_owner(&client, &request.user_id).await?;
+
+    let joined_at: String = expiry::timestamp(chrono::Utc::now());
+    let row = match client
+        .query_one(
+            "INSERT INTO org_members (org_id,user_id,role,joined_at) VALUES ($1,$2,$3,$4) \
+             ON CONFLICT (org_id,user_id) DO UPDATE SET role=EXCLUDED.role RETURNING joined_at",
+            &[
+                &org_id,
+                &request.user_id,
+                &request.role.as_str(),
+                &joined_at,
+            ],
+        )
+        .await
+    {
+        Ok(row) => row,
+        Err(_) => {
+            error!("Failed to insert into the org_members table");
+            return Err(ApiError::DatabaseError(
+                "Failed to add the member".to_string(),
+            ));
+        }
+    };
+
+    if current.is_none() {
+        notifications::notify(
+            &client,
+            &request.user_id,
+            NotificationKind::AddedToOrganization,
+            Some(user_id),
+            &format!(
+                "You were added to an organization as a {}",
+                request.role.as_str()
+            ),
+            &format!("/orgs/{}", org_id),
+        )
+        .await;
+    }
+
+    info!(member = %request.user_id, role = request.role.as_str(), "Organization member added");
+    Ok(Json(Member {
+        user_id: request.user_id,
+        role: request.role,
+        joined_at: row.get(0),
+    }))
+}
+
+async fn owners(client: &Client, org_id: &Uuid) -> Result<i64, ApiError> {
+    match client
+        .query_one(
+            "SELECT COUNT(*) FROM org_members WHERE org_id=$1 AND role='owner'",
+            &[org_id],
+        )
+        .await
+    {
+        Ok(row) => Ok(row.get(0)),
+        Err(_) => Err(database_error("org_members")),
+    }
+}
+
+/// Removes a member from an organization. Needs the admin role (the owner role to remove an
+/// owner), members can always leave.
+#[delete("/orgs/<id>/members/<member>")]
+#[instrument(skip_all, fields(request_id = %request_id, org_id = %id))]
+pub async fn remove_member(
+    id: String,
+    member: String,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<(), ApiError> {
+    let org_id: Uuid = parse_id(&id, "organization")?;
+    let member: Uuid = parse_id(&member, "user")?;
+    let user_id: Uuid = actor.require_user()?;
+
+    let client = db.lock().await;
+    let required: Role = match member_role(&client, &org_id, &member).await? {
+        Some(Role::Owner) => Role::Owner,
+        Some(_) if member == user_id => Role::Viewer,
+        Some(_) => Role::Admin,
+        None => return Err(ApiError::NotFound("Member not found".to_string())),
+    };
+    require_role(&client, &org_id, &user_id, required).await?;
+    if required == Role::Owner && owners(&client, &org_id).await? <= 1 {
+        return Err(ApiError::Conflict(
+            "An organization needs at least one owner".to_string(),
+        ));
+    }
+
+    match client
+        .execute(
+            "DELETE FROM org_members WHERE org_id=$1 AND user_id=$2",
+            &[&org_id, &member],
+        )
+        .await
+    {
+        Ok(_) => {
+            info!(member = %member, "Organization member removed");
+            Ok(())
+        }
+        Err(_) => {
+            error!("Failed to delete from the org_members table");
+            Err(ApiError::DatabaseError(
+                "Failed to remove the member".to_string(),
+            ))
+        }
+    }
+}
+
+/// Creates a workspace in an organization. Needs the admin role.
+///
+/// Example Request:
+/// {
+///     "name": "Backend"
+/// }
+#[post("/orgs/<id>/workspaces", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id, org_id = %id))]
+pub async fn create_workspace(
+    id: String,
+    request: JsonBody<NameRequest>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<Workspace>, ApiError> {
+    let org_id: Uuid = parse_id(&id, "organization")?;
+    let user_id: Uuid = actor.require_user()?;
+    let name: String = match request.name() {
+        Ok(name) => name,
+        Err(error) => return Err(ApiError::ValidationFailed(vec![error])),
+    };
+
+    let client = db.lock().await;
+    require_role(&client, &org_id, &user_id, Role::Admin).await?;
+
+    let created_at: String = expiry::timestamp(chrono::Utc::now());
+    let workspace_id: Uuid = match client
+        .query_one(
+            "INSERT INTO workspaces (org_id,name,created_at) VALUES ($1,$2,$3) RETURNING workspace_id",
+            &[&org_id, &name, &created_at],
+        )
+        .await
+    {
+        Ok(row) => row.get(0),
+        Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+            error!("Workspace created with a name already used in the organization");
+            return Err(ApiError::Conflict(
+                "A workspace with this name already exists".to_string(),
+            ));
+        }
+        Err(_) => {
+            error!("Failed to insert into the workspaces table");
+            return Err(ApiError::DatabaseError(
+                "Failed to create the workspace".to_string(),
+            ));
+        }
+    };
+
+    info!(workspace_id = %workspace_id, "Workspace created");
+    Ok(Json(Workspace {
+        workspace_id,
+        org_id,
+        name,
+        created_at,
+    }))
+}
+
+/// Lists the workspaces of an organization, for its members.
+#[get("/orgs/<id>/workspaces")]
+#[instrument(skip_all, fields(request_id = %request_id, org_id = %id))]
+pub async fn list_workspaces(
+    id: String,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<Vec<Workspace>>, ApiError> {
+    let org_id: Uuid = parse_id(&id, "organization")?;
+    let user_id: Uuid = actor.require_user()?;
+
+    let client = db.lock().await;
+    require_role(&client, &org_id, &user_id, Role::Viewer).await?;
+    let rows = match client
+        .query(
+            "SELECT workspace_id,name,created_at FROM workspaces WHERE org_id=$1 ORDER BY name",
+            &[&org_id],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => return Err(database_error("workspaces")),
+    };
+
+    Ok(Json(
+        rows.iter()
+            .map(|row| Workspace {
+                workspace_id: row.get(0),
+                org_id,
+                name: row.get(1),
+                created_at: row.get(2),
+            })
+            .collect(),
+    ))
+}
+
+/// SQL selecting the tags of the document `d`, in alphabetical order.
+pub(crate) const TAGS_COLUMN: &str =
+    "ARRAY(SELECT t.tag FROM document_tags t WHERE t.document_id=d.document_id ORDER BY t.tag)";
+
+/// SQL matching the documents `d` tagged with the parameter `$n`, or every document if it is NULL.
+fn tag_filter(n: usize) -> String {
+    format!(
+        "(${0}::text IS NULL OR EXISTS (SELECT 1 FROM document_tags t WHERE t.document_id=d.document_id AND t.tag=${0}))",
+        n
+    )
+}
+
+/// Lists the documents of a workspace, for members of its organization. Archived documents
+/// are left out. `tag`: Only the documents with this tag.
+#[get("/workspaces/<id>/documents?<tag>")]
+#[instrument(skip_all, fields(request_id = %request_id, workspace_id = %id))]
+pub async fn list_workspace_documents(
+    id: String,
+    tag: Option<String>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<Vec<WorkspaceDocument>>, ApiError> {
+    let workspace_id: Uuid = parse_id(&id, "workspace")?;
+    let tag: Option<String> = tags::parse_filter(tag.as_deref())?;
+    let user_id: Uuid = actor.require_user()?;
+
+    let client = db.lock().await;
+    let org_id: Uuid = workspace_org(&client, &workspace_id).await?;
+    if require_role(&client, &org_id, &user_id, Role::Viewer)
+        .await
+        .is_err()
+    {
+        return Err(ApiError::NotFound("Workspace not found".to_string()));
+    }
+    scope(&client, &Tenant::Org(org_id)).await?;
+
+    let query: String = format!(
+        "SELECT d.document_id,d.owner_id,d.title,d.creation_date,d.language,{} FROM document d \
+         WHERE d.workspace_id=$1 AND d.archived_at IS NULL AND {} ORDER BY d.creation_date",
+        TAGS_COLUMN,
+        tag_filter(2)
+    );
+    let rows = match client.query(&query, &[&workspace_id, &tag]).await {
+        Ok(rows) => rows,
+        Err(_) => return Err(database_error("document")),
+    };
+
+    Ok(Json(
+        rows.iter()
+            .map(|row| WorkspaceDocument {
+                document_id: row.get(0),
+                owner_id: row.get(1),
+                title: row.get(2),
+                creation_date: row.get(3),
+                language: row.get(4),
+                tags: row.get(5),
+            })
+            .collect(),
+    ))
+}
+
+/// Lists the documents the requesting user can open, most recently active first: their
+/// personal documents and the documents in the workspaces of their organizations, with their
+/// role. Documents the user owns in an organization they have left are not listed, as they
+/// can no longer open them. Archived documents are left out.
+/// `tag`: Only the documents with this tag.
+#[get("/me/documents?<tag>")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn list_user_documents(
+    tag: Option<String>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<Vec<UserDocument>>, ApiError> {
+    let tag: Option<String> = tags::parse_filter(tag.as_deref())?;
+    let user_id: Uuid = actor.require_user()?;
+
+    // the query only returns the user's documents, across every organization they belong to
+    let client = lock(db, &Tenant::Any).await?;
+    let query: String = format!(
+        "SELECT d.document_id,d.owner_id,d.title,d.creation_date,d.language,d.workspace_id,w.org_id,m.role, \
+         COALESCE((SELECT a.timestamp FROM audit_log a WHERE a.document_id=d.document_id ORDER BY a.id DESC LIMIT 1), d.creation_date) AS last_activity,{} \
+         FROM document d LEFT JOIN workspaces w ON w.workspace_id=d.workspace_id \
+         LEFT JOIN org_members m ON m.org_id=w.org_id AND m.user_id=$1 \
+         WHERE d.archived_at IS NULL AND ((d.workspace_id IS NULL AND d.owner_id=$1) OR m.role IS NOT NULL) AND {} \
+         ORDER BY last_activity DESC",
+        TAGS_COLUMN,
+        tag_filter(2)
+    );
+    let rows = match client.query(&query, &[&user_id, &tag]).await {
+        Ok(rows) => rows,
+        Err(_) => return Err(database_error("document")),
+    };
+
+    Ok(Json(
+        rows.iter()
+            .map(|row| {
+                let owner_id: Uuid = row.get(1);
+                let role: Option<Role> = row.get::<_, Option<&str>>(7).and_then(Role::parse);
+                UserDocument {
+                    document_id: row.get(0),
+                    owner_id,
+                    title: row.get(2),
+                    creation_date: row.get(3),
+                    language: row.get(4),
+                    workspace_id: row.get(5),
+                    org_id: row.get(6),
+                    owned: owner_id == user_id,
+                    role,
+                    access: role.map_or(Access::ReadWrite, |role| role.access()),
+                    last_activity: row.get(8),
+                    tags: row.get(9),
+                }
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roles() {
+        for role in [Role::Viewer, Role::Member, Role::Admin, Role::Owner] {
+            assert_eq!(Role::parse(role.as_str()), Some(role));
+        }
+        assert_eq!(Role::parse("guest"), None);
+        assert!(Role::Viewer < Role::Member && Role::Admin < Role::Owner);
+        assert_eq!(Role::Viewer.access(), Access::Read);
+        assert_eq!(Role::Member.access(), Access::ReadWrite);
+
+        let request = |name: &str| NameRequest {
+            name: name.to_string(),
+        };
+        assert_eq!(request("  Acme ").name(), Ok("Acme".to_string()));
+        assert!(request(" ").name().is_err());
+        assert!(request(&"a".repeat(MAX_NAME_LEN + 1)).name().is_err());
+    }
+
+    #[test]
+    fn test_check_access() {
+        let user_id = Some(Uuid::new_v4());
+        assert!(check_access(Some(Role::Viewer), user_id, Access::Read).is_ok());
+        assert!(check_access(Some(Role::Member), user_id, Access::ReadWrite).is_ok());
+        assert!(matches!(
+            check_access(Some(Role::Viewer), user_id, Access::ReadWrite),
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(matches!(
+            check_access(None, user_id, Access::Read),
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(matches!(
+            check_access(None, None, Access::Read),
+            Err(ApiError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            check_personal(user_id),
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(matches!(
+            check_personal(None),
+            Err(ApiError::Unauthorized(_))
+        ));
+
+        let org_id = Uuid::new_v4();
+        assert_eq!(Tenant::Any.setting(), "*");
+        assert_eq!(Tenant::Personal.setting(), "");
+        assert_eq!(Tenant::Org(org_id).setting(), org_id.to_string());
+    }
+
+    #[test]
+    fn test_document_tables_are_isolated() {
+        // every table of the README's schema with a document_id column has a policy
+        let readme: &str = include_str!("../../README.md");
+        let isolated: Vec<&str> = ISOLATED_TABLES
+            .into_iter()
+            .chain(ISOLATED_BY.iter().map(|(table, _, _)| *table))
+            .collect();
+        let mut tables: usize = 0;
+        for definition in readme.split("CREATE TABLE ").skip(1) {
+            let (name, columns) = definition.split_once(" (").unwrap();
+            let columns: &str = columns.split("\n);").next().unwrap();
+            if columns
+                .lines()
+                .any(|line| line.trim_start().starts_with("document_id "))
+                && name != "documents"
+            {
+                tables += 1;
+                assert!(isolated.contains(&name), "{} is not isolated", name);
+            }
+        }
+        assert!(tables >= ISOLATED_TABLES.len());
+    }
+}
@@ -0,0 +1,314 @@
+//! A database for the tests of routes that read it.
+//!
+//! Set `NIMBLE_TEST_DATABASE_URL` to a database the tests may create schemas in and run them
+//! with `cargo test -- --ignored`. Every test creates the tables it needs in its own schema,
+//! so the tests can run in parallel and leave the database as they found it.
+
+use crate::auth::{self, AuthConfig, UserSession};
+use crate::connect_to_db;
+use crate::pool::Pool;
+use crate::share::{ShareConfig, MIN_SECRET_LEN};
+use rocket::http::Header;
+use rocket::tokio::sync::Mutex;
+use rocket::{Build, Rocket};
+use std::sync::Arc;
+use tokio_postgres::Client;
+use uuid::Uuid;
+
+/// The variable holding the URL of the test database.
+pub const DATABASE_URL_VAR: &str = "NIMBLE_TEST_DATABASE_URL";
+
+/// The tables read when authorizing a request, as described in the README.
+pub const ORGANIZATION_TABLES: &str = "
+    CREATE TABLE organizations (
+        org_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+        name TEXT NOT NULL,
+        created_at TEXT NOT NULL DEFAULT ''
+    );
+    CREATE TABLE org_members (
+        org_id UUID NOT NULL REFERENCES organizations (org_id) ON DELETE CASCADE,
+        user_id UUID NOT NULL,
+        role TEXT NOT NULL,
+        joined_at TEXT NOT NULL DEFAULT '',
+        PRIMARY KEY (org_id, user_id)
+    );
+    CREATE TABLE workspaces (
+        workspace_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+        org_id UUID NOT NULL REFERENCES organizations (org_id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        created_at TEXT NOT NULL DEFAULT ''
+    );
+    CREATE TABLE document (
+        document_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+        owner_id UUID NOT NULL,
+        creation_date TEXT,
+        title TEXT,
+        expires_at TEXT,
+        archived_at TEXT,
+        trashed_at TEXT,
+        persist_chat BOOLEAN NOT NULL DEFAULT FALSE,
+        language TEXT,
+        workspace_id UUID REFERENCES workspaces (workspace_id),
+        project_id UUID
+    );
+    CREATE TABLE document_members (
+        document_id UUID NOT NULL REFERENCES document (document_id) ON DELETE CASCADE,
+        user_id UUID NOT NULL,
+        access TEXT NOT NULL,
+        added_at TEXT NOT NULL DEFAULT '',
+        PRIMARY KEY (document_id, user_id)
+    );
+    CREATE TABLE document_tags (
+        document_id UUID NOT NULL REFERENCES document (document_id) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (document_id, tag)
+    );
+";
+
+/// The table of registered users, as described in the README.
+pub const USER_TABLES: &str = "
+    CREATE TABLE users (
+        user_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+        email TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL,
+        display_name TEXT NOT NULL,
+        color TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+";
+
+/// The projects and their grants, as described in the README. Needs [`ORGANIZATION_TABLES`]
+/// and [`USER_TABLES`].
+pub const PROJECT_TABLES: &str = "
+    CREATE TABLE projects (
+        project_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+        owner_id UUID NOT NULL REFERENCES users (user_id),
+        parent_id UUID REFERENCES projects (project_id),
+        workspace_id UUID REFERENCES workspaces (workspace_id),
+        name TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+    CREATE TABLE project_members (
+        project_id UUID NOT NULL REFERENCES projects (project_id) ON DELETE CASCADE,
+        user_id UUID NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+        access TEXT NOT NULL,
+        PRIMARY KEY (project_id, user_id)
+    );
+";
+
+/// The invitations to documents, as described in the README. Needs [`ORGANIZATION_TABLES`].
+pub const INVITATION_TABLES: &str = "
+    CREATE TABLE document_invitations (
+        invitation_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+        document_id UUID NOT NULL REFERENCES document (document_id) ON DELETE CASCADE,
+        email TEXT NOT NULL,
+        access TEXT NOT NULL,
+        invited_by UUID NOT NULL,
+        created_at TEXT NOT NULL,
+        expires_at TEXT NOT NULL,
+        UNIQUE (document_id, email)
+    );
+";
+
+/// The notifications of users, as described in the README.
+pub const NOTIFICATION_TABLES: &str = "
+    CREATE TABLE notifications (
+        notification_id BIGSERIAL PRIMARY KEY,
+        user_id UUID NOT NULL,
+        kind TEXT NOT NULL,
+        actor_id UUID,
+        message TEXT NOT NULL,
+        link TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        read_at TEXT
+    );
+";
+
+/// The tables an operation is persisted and a document loaded from, as described in the README.
+pub const OPERATION_TABLES: &str = "
+    CREATE TABLE operations (
+        operation_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+        document_id UUID NOT NULL,
+        ssn BIGINT NOT NULL,
+        sum BIGINT NOT NULL,
+        sid BIGINT NOT NULL,
+        seq BIGINT NOT NULL,
+        value TEXT,
+        tombstone BOOLEAN DEFAULT FALSE,
+        timestamp TEXT NOT NULL,
+        log_position BIGSERIAL,
+        operation TEXT,
+        left_origin BIGINT[],
+        right_origin BIGINT[],
+        nodes BIGINT[]
+    );
+    CREATE TABLE document_snapshots (
+        document_id UUID NOT NULL,
+        ssn BIGINT NOT NULL,
+        sum BIGINT NOT NULL,
+        sid BIGINT NOT NULL,
+        seq BIGINT NOT NULL,
+        value TEXT,
+        tombstone BOOLEAN DEFAULT FALSE,
+        author UUID,
+        authored_at TEXT,
+        UNIQUE (document_id, ssn, sum, sid, seq)
+    );
+    CREATE TABLE document_checkpoints (
+        document_id UUID PRIMARY KEY,
+        nodes BYTEA NOT NULL,
+        watermark TEXT NOT NULL,
+        log_position BIGINT
+    );
+    CREATE TABLE audit_log (
+        id BIGSERIAL PRIMARY KEY,
+        document_id UUID NOT NULL,
+        user_id UUID,
+        client_ip TEXT,
+        operation TEXT NOT NULL,
+        ssn BIGINT NOT NULL,
+        sum BIGINT NOT NULL,
+        sid BIGINT NOT NULL,
+        seq BIGINT NOT NULL,
+        timestamp TEXT NOT NULL
+    );
+    CREATE TABLE broadcast_outbox (
+        outbox_id BIGSERIAL PRIMARY KEY,
+        replica_id BIGINT NOT NULL,
+        document_id UUID NOT NULL,
+        operation TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+    CREATE TABLE document_quota (
+        document_id UUID PRIMARY KEY,
+        window_start TIMESTAMP NOT NULL,
+        operations BIGINT NOT NULL
+    );
+    CREATE TABLE document_activity (
+        user_id UUID NOT NULL,
+        document_id UUID NOT NULL,
+        starred_at TEXT,
+        accessed_at TEXT,
+        PRIMARY KEY (user_id, document_id)
+    );
+";
+
+/// A schema of the test database holding a test's tables.
+/// `url`: Connects to the database with the schema as the search path.
+pub struct TestDatabase {
+    pub url: String,
+    schema: String,
+    client: Client,
+}
+
+impl TestDatabase {
+    /// Creates a schema with the tables, panicking if `NIMBLE_TEST_DATABASE_URL` is not set.
+    pub async fn create(tables: &str) -> TestDatabase {
+        let base: String = std::env::var(DATABASE_URL_VAR)
+            .unwrap_or_else(|_| panic!("{} must be set to run this test", DATABASE_URL_VAR));
+        let schema: String = format!("nimble_test_{}", Uuid::new_v4().simple());
+        let client: Client = connect_to_db(&base)
+            .await
+            .expect("the test database is reachable");
+        client
+            .batch_execute(&format!(
+                "CREATE SCHEMA {schema}; SET search_path TO {schema}; {tables}"
+            ))
+            .await
+            .expect("the test tables are created");
+
+        let separator: char = if base.contains('?') { '&' } else { '?' };
+        TestDatabase {
+            url: format!("{base}{separator}options=-csearch_path%3D{schema}"),
+            schema,
+            client,
+        }
+    }
+
+    /// A connection whose search path is the schema.
+    pub async fn connect(&self) -> Client {
+        connect_to_db(&self.url)
+            .await
+            .expect("the test database is reachable")
+    }
+
+    /// Drops the schema and its tables.
+    pub async fn drop(self) {
+        let _ = self
+            .client
+            .batch_execute(&format!("DROP SCHEMA {} CASCADE", self.schema))
+            .await;
+    }
+}
+
+/// The login configuration of the tests' rockets, signing sessions with a fixed secret.
+pub fn auth_config() -> AuthConfig {
+    AuthConfig {
+        secret: Some("s".repeat(MIN_SECRET_LEN)),
+        ..AuthConfig::default()
+    }
+}
+
+/// A rocket managing the states the routes authorizing a request read: [`auth_config`], share
+/// tokens signed with its secret, a pool and a shared connection to the test database. Tests
+/// manage the other states and mount the routes they exercise.
+pub async fn rocket(database: &TestDatabase) -> Rocket<Build> {
+    rocket::build()
+        .manage(auth_config())
+        .manage(ShareConfig {
+            secret: auth_config().secret,
+            ..ShareConfig::default()
+        })
+        .manage(
+            Pool::connect(&database.url, 2)
+                .await
+                .expect("the test database is reachable"),
+        )
+        .manage(Arc::new(Mutex::new(database.connect().await)))
+}
+
+/// The header identifying the user to a rocket managing [`auth_config`].
+pub fn session(user_id: Uuid) -> Header<'static> {
+    let session = UserSession {
+        user_id,
+        expires_at: chrono::Utc::now().timestamp() + 60,
+    };
+    let secret: String = auth_config().secret.unwrap_or_default();
+    Header::new(auth::SESSION_TOKEN_HEADER, auth::sign(&session, &secret))
+}
+
+/// Adds a personal document titled `Notes` owned by `owner_id`, returning its id.
+pub async fn insert_document(client: &Client, owner_id: Uuid) -> Uuid {
+    client
+        .query_one(
+            "INSERT INTO document (owner_id,creation_date,title) VALUES ($1,$2,'Notes') RETURNING document_id",
+            &[&owner_id, &crate::expiry::timestamp(chrono::Utc::now())],
+        )
+        .await
+        .expect("the document is added")
+        .get(0)
+}
+
+/// Registers a user with the email, returning their id.
+pub async fn insert_user(client: &Client, email: &str) -> Uuid {
+    client
+        .query_one(
+            "INSERT INTO users (email,password_hash,display_name,color,created_at) \
+             VALUES ($1,'',$1,'#000000','') RETURNING user_id",
+            &[&email],
+        )
+        .await
+        .expect("the user is added")
+        .get(0)
+}
+
+/// Makes `user_id` a member of the document with `access` (`read` or `read_write`).
+pub async fn insert_member(client: &Client, document_id: Uuid, user_id: Uuid, access: &str) {
+    client
+        .execute(
+            "INSERT INTO document_members (document_id,user_id,access) VALUES ($1,$2,$3)",
+            &[&document_id, &user_id, &access],
+        )
+        .await
+        .expect("the member is added");
+}
@@ -0,0 +1,173 @@
+//! Per-document throttling of edits.
+//!
+//! The load balancer's rate limiter counts requests per client, which doesn't stop one runaway
+//! client from saturating a single document and the broadcast topic its edits are published
+//! to. Each loaded document has a token bucket, refilled at `operations_per_sec` up to `burst`,
+//! and every operation a client sends takes a token from it. Operations over the ceiling are
+//! rejected with 429 and a `Retry-After` for the time the bucket needs to refill.
+//!
+//! Remote operations received from other replicas are not throttled, each replica throttles
+//! the edits of its own clients.
+
+use crate::ApiError;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// The ceiling on the edits made to each document.
+/// `operations_per_sec`: Operations a document accepts per second, 0 disables throttling.
+/// `burst`: Operations a document accepts at once after a pause.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    pub operations_per_sec: f64,
+    pub burst: u32,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            operations_per_sec: 100.0,
+            burst: 200,
+        }
+    }
+}
+
+/// A document's token bucket. A new bucket is full.
+/// `level`: The tokens left and when they were counted, None while the bucket is full.
+#[derive(Debug, Clone, Default)]
+pub struct TokenBucket {
+    level: Option<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Takes `operations` tokens at `now`, or returns how long to wait until they are available.
+    /// Requests for more tokens than `burst` are accepted from a full bucket and leave it in
+    /// debt, so a large batch delays the edits that follow it instead of never succeeding.
+    pub fn take(
+        &mut self,
+        operations: u32,
+        config: &ThrottleConfig,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        if config.operations_per_sec <= 0.0 {
+            return Ok(());
+        }
+        let burst: f64 = config.burst as f64;
+        let tokens: f64 = match self.level {
+            None => burst,
+            Some((tokens, at)) => {
+                let refilled: f64 =
+                    now.saturating_duration_since(at).as_secs_f64() * config.operations_per_sec;
+                (tokens + refilled).min(burst)
+            }
+        };
+
+        let needed: f64 = (operations as f64).min(burst);
+        if tokens < needed {
+            self.level = Some((tokens, now));
+            return Err(Duration::from_secs_f64(
+                (needed - tokens) / config.operations_per_sec,
+            ));
+        }
+        self.level = Some((tokens - operations as f64, now));
+        Ok(())
+    }
+}
+
+/// Applies the throttle to the documents' buckets and counts the operations it rejected.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    pub config: ThrottleConfig,
+    rejections: Arc<AtomicU64>,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Throttle {
+            config,
+            rejections: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Takes `operations` tokens from the document's bucket, or returns `429 Too Many
+    /// Requests` with the seconds to wait before retrying.
+    pub fn admit(&self, bucket: &mut TokenBucket, operations: usize) -> Result<(), ApiError> {
+        let operations: u32 = u32::try_from(operations).unwrap_or(u32::MAX);
+        let wait: Duration = match bucket.take(operations, &self.config, Instant::now()) {
+            Ok(()) => return Ok(()),
+            Err(wait) => wait,
+        };
+
+        self.rejections
+            .fetch_add(operations as u64, Ordering::Relaxed);
+        let message: String = format!(
+            "The document accepts at most {} operations per second",
+            self.config.operations_per_sec
+        );
+        warn!(operations, "Throttled edits: {}", message);
+        Err(ApiError::SlowDown(
+            message,
+            wait.as_secs_f64().ceil().max(1.0) as u64,
+        ))
+    }
+
+    /// Renders the operations rejected by the throttle in the Prometheus text exposition
+    /// format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP nimble_throttled_operations_total Operations rejected by the per-document throttle."
+        );
+        let _ = writeln!(out, "# TYPE nimble_throttled_operations_total counter");
+        let _ = writeln!(
+            out,
+            "nimble_throttled_operations_total {}",
+            self.rejections.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let config = ThrottleConfig {
+            operations_per_sec: 10.0,
+            burst: 5,
+        };
+        let start = Instant::now();
+        let mut bucket = TokenBucket::default();
+
+        assert!(bucket.take(5, &config, start).is_ok());
+        let wait = bucket.take(1, &config, start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(100));
+
+        // refilled at 10 per second, up to the burst
+        let later = start + Duration::from_millis(300);
+        assert!(bucket.take(3, &config, later).is_ok());
+        assert!(bucket.take(1, &config, later).is_err());
+        assert!(bucket
+            .take(5, &config, later + Duration::from_secs(60))
+            .is_ok());
+
+        // a batch larger than the burst drains a full bucket into debt
+        let mut bucket = TokenBucket::default();
+        assert!(bucket.take(15, &config, start).is_ok());
+        let wait = bucket.take(1, &config, start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(1100));
+
+        let disabled = ThrottleConfig {
+            operations_per_sec: 0.0,
+            ..config
+        };
+        assert!(bucket.take(1000, &disabled, start).is_ok());
+    }
+}
@@ -0,0 +1,357 @@
+//! Server-side syntax highlighting, so clients can render highlighting without shipping grammars.
+//!
+//! Documents record their language when they are created. The tokenizer parses the current
+//! content with the language's tree-sitter grammar, runs the grammar's highlight query and
+//! anchors each highlighted span to the node it falls in.
+
+use crate::pool::Pool;
+use crate::routes::SharedRGAs;
+use crate::share::Access;
+use crate::tenancy::{self, Tenant};
+use crate::{Actor, ApiError, FieldError, RequestId, S4Vector};
+use rocket::get;
+use rocket::serde::json::Json;
+use rocket::tokio::{self, sync::Mutex};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::sync::{Arc, OnceLock};
+use streaming_iterator::StreamingIterator;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument};
+use tree_sitter::{Parser, Query, QueryCursor};
+use uuid::Uuid;
+
+/// A language the tokenizer has a grammar for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Rust,
+    Python,
+    JavaScript,
+}
+
+impl Language {
+    pub const ALL: [Language; 3] = [Language::Rust, Language::Python, Language::JavaScript];
+
+    /// The name stored on documents.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Language::Rust => "rust",
+            Language::Python => "python",
+            Language::JavaScript => "javascript",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Language> {
+        Language::ALL
+            .into_iter()
+            .find(|language| language.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    fn grammar(&self) -> tree_sitter::Language {
+        match self {
+            Language::Rust => tree_sitter_rust::LANGUAGE.into(),
+            Language::Python => tree_sitter_python::LANGUAGE.into(),
+            Language::JavaScript => tree_sitter_javascript::LANGUAGE.into(),
+        }
+    }
+
+    /// The grammar's highlight query, compiled on first use.
+    fn query(&self) -> &'static Query {
+        static QUERIES: [OnceLock<Query>; 3] = [OnceLock::new(), OnceLock::new(), OnceLock::new()];
+        let (index, source) = match self {
+            Language::Rust => (0, tree_sitter_rust::HIGHLIGHTS_QUERY),
+            Language::Python => (1, tree_sitter_python::HIGHLIGHTS_QUERY),
+            Language::JavaScript => (2, tree_sitter_javascript::HIGHLIGHT_QUERY),
+        };
+        QUERIES[index].get_or_init(|| {
+            Query::new(&self.grammar(), source).expect("bundled highlight queries are valid")
+        })
+    }
+}
+
+/// Validates the language of a document, returning its stored name (None for plain text).
+pub fn parse_language(language: Option<&str>) -> Result<Option<String>, ApiError> {
+    match language {
+        None => Ok(None),
+        Some(name) => match Language::from_name(name) {
+            Some(language) => Ok(Some(language.name().to_string())),
+            None => {
+                error!("Rejected unsupported language");
+                Err(ApiError::ValidationFailed(vec![FieldError::new(
+                    "language",
+                    &format!(
+                        "must be one of {}",
+                        Language::ALL.map(|language| language.name()).join(", ")
+                    ),
+                )]))
+            }
+        },
+    }
+}
+
+/// Highlights source code, returning the byte range and highlight name of each token in order.
+/// Tokens don't overlap: where captures nest, the outermost one is kept, and where several
+/// patterns capture the same range, the first pattern in the query wins.
+pub fn highlight(language: Language, source: &str) -> Vec<(Range<usize>, &'static str)> {
+    let mut parser = Parser::new();
+    if parser.set_language(&language.grammar()).is_err() {
+        error!("Failed to load the {} grammar", language.name());
+        return Vec::new();
+    }
+    let tree = match parser.parse(source, None) {
+        Some(tree) => tree,
+        None => return Vec::new(),
+    };
+
+    let query: &'static Query = language.query();
+    let names: &'static [&'static str] = query.capture_names();
+    let mut captures: Vec<(Range<usize>, usize, &'static str)> = Vec::new();
+    let mut cursor = QueryCursor::new();
+    let mut matches = cursor.matches(query, tree.root_node(), source.as_bytes());
+    while let Some(m) = matches.next() {
+        for capture in m.captures {
+            let name: &'static str = names[capture.index as usize];
+            if !name.starts_with('_') && !capture.node.byte_range().is_empty() {
+                captures.push((capture.node.byte_range(), m.pattern_index, name));
+            }
+        }
+    }
+    captures
+        .sort_by_key(|(range, pattern, _)| (range.start, std::cmp::Reverse(range.end), *pattern));
+
+    let mut tokens: Vec<(Range<usize>, &'static str)> = Vec::new();
+    let mut end: usize = 0;
+    for (range, _, name) in captures {
+        if range.start >= end {
+            end = range.end;
+            tokens.push((range, name));
+        }
+    }
+    tokens
+}
+
+/// A highlighted span of a node.
+/// `s4vector`: The node the span is in.
+/// `start`, `end`: The span's character offsets within the node's value.
+/// `kind`: The highlight name, e.g. `keyword`, `string` or `function.method`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Token {
+    pub s4vector: S4Vector,
+    pub start: usize,
+    pub end: usize,
+    pub kind: String,
+}
+
+/// Response body with the highlighting of a document.
+/// `document_id`: The document.
+/// `language`: The language the document was highlighted as (None for plain text).
+/// `tokens`: The highlighted spans in document order. Tokens spanning several nodes are split.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokensResponse {
+    pub document_id: Uuid,
+    pub language: Option<String>,
+    pub tokens: Vec<Token>,
+}
+
+/// Anchors byte ranges of the concatenated node values to the nodes they fall in.
+pub fn anchor(nodes: &[(S4Vector, String)], tokens: &[(Range<usize>, &str)]) -> Vec<Token> {
+    let mut anchored: Vec<Token> = Vec::new();
+    let mut offset: usize = 0;
+    let mut next: usize = 0;
+    for (s4vector, value) in nodes {
+        let node = offset..offset + value.len();
+        // tokens ending in an earlier node are done, the rest may continue into later nodes
+        while tokens
+            .get(next)
+            .is_some_and(|(range, _)| range.end <= node.start)
+        {
+            next += 1;
+        }
+        for (range, kind) in tokens[next..].iter() {
+            if range.start >= node.end {
+                break;
+            }
+            let start: usize = range.start.max(node.start) - node.start;
+            let end: usize = range.end.min(node.end) - node.start;
+            anchored.push(Token {
+                s4vector: *s4vector,
+                start: value[..start].chars().count(),
+                end: value[..end].chars().count(),
+                kind: kind.to_string(),
+            });
+        }
+        offset = node.end;
+    }
+    anchored
+}
+
+/// Highlights the current content of a loaded document.
+/// `language`: The language to highlight as, instead of the one stored on the document.
+/// Documents without a language, or with one the tokenizer has no grammar for, have no tokens.
+#[get("/document/<id>/tokens?<language>")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn fetch_tokens(
+    id: String,
+    language: Option<String>,
+    rgas: &rocket::State<SharedRGAs>,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    actor: Actor,
+    request_id: RequestId,
+) -> Result<Json<TokensResponse>, ApiError> {
+    let document_id: Uuid = match Uuid::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => {
+            error!("Failed to parse document id");
+            return Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ));
+        }
+    };
+
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::Read).await?;
+
+    let (nodes, source): (Vec<(S4Vector, String)>, String) =
+        match rgas.lock().await.get(&document_id) {
+            Some(rga) => (rga.read_nodes(), rga.text().to_string()),
+            None => {
+                error!("Document not found");
+                return Err(ApiError::NotFound(String::from("Document not found")));
+            }
+        };
+
+    let language: Option<String> = match language {
+        Some(language) => parse_language(Some(&language))?,
+        None => {
+            let client = tenancy::lock(db, &tenant).await?;
+            match client
+                .query_opt(
+                    "SELECT language FROM document WHERE document_id=$1",
+                    &[&document_id],
+                )
+                .await
+            {
+                Ok(Some(row)) => row.get(0),
+                Ok(None) => {
+                    error!("Document not found");
+                    return Err(ApiError::NotFound(String::from("Document not found")));
+                }
+                Err(_) => {
+                    error!("Failed to read the document's language");
+                    return Err(ApiError::DatabaseError(
+                        "Failed to read the document's language".to_string(),
+                    ));
+                }
+            }
+        }
+    };
+
+    let tokens: Vec<Token> = match language.as_deref().and_then(Language::from_name) {
+        Some(grammar) => {
+            let highlighted =
+                tokio::task::spawn_blocking(move || anchor(&nodes, &highlight(grammar, &source)))
+                    .await;
+            match highlighted {
+                Ok(tokens) => tokens,
+                Err(_) => {
+                    error!("The tokenizer failed");
+                    return Err(ApiError::InternalServerError(
+                        "Failed to highlight the document".to_string(),
+                    ));
+                }
+            }
+        }
+        None => Vec::new(),
+    };
+
+    info!(tokens = tokens.len(), "Highlighted document");
+    Ok(Json(TokensResponse {
+        document_id,
+        language,
+        tokens,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s4(seq: u64) -> S4Vector {
+        S4Vector {
+            ssn: 1,
+            sum: seq,
+            sid: 1,
+            seq,
+        }
+    }
+
+    #[test]
+    fn test_parse_language() {
+        assert_eq!(parse_language(None).unwrap(), None);
+        assert_eq!(
+            parse_language(Some("Python")).unwrap(),
+            Some("python".to_string())
+        );
+        assert!(matches!(
+            parse_language(Some("cobol")),
+            Err(ApiError::ValidationFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_highlight() {
+        for language in Language::ALL {
+            language.query();
+        }
+
+        let source = "fn main() {\n    let s = \"hi\";\n}\n";
+        let tokens = highlight(Language::Rust, source);
+        let kind = |text: &str| {
+            tokens
+                .iter()
+                .find(|(range, _)| &source[range.clone()] == text)
+                .map(|(_, kind)| *kind)
+        };
+        assert_eq!(kind("fn"), Some("keyword"));
+        assert_eq!(kind("let"), Some("keyword"));
+        assert_eq!(kind("main"), Some("function"));
+        assert_eq!(kind("\"hi\""), Some("string"));
+        assert!(tokens
+            .windows(2)
+            .all(|pair| pair[0].0.end <= pair[1].0.start));
+    }
+
+    #[test]
+    fn test_anchor() {
+        // "def" is split across the first two nodes, "é" is two bytes but one character
+        let nodes = vec![
+            (s4(1), "dé".to_string()),
+            (s4(2), "f f".to_string()),
+            (s4(3), "()".to_string()),
+        ];
+        let tokens = anchor(&nodes, &[(0..4, "keyword"), (5..6, "function")]);
+        assert_eq!(
+            tokens,
+            vec![
+                Token {
+                    s4vector: s4(1),
+                    start: 0,
+                    end: 2,
+                    kind: "keyword".to_string()
+                },
+                Token {
+                    s4vector: s4(2),
+                    start: 0,
+                    end: 1,
+                    kind: "keyword".to_string()
+                },
+                Token {
+                    s4vector: s4(2),
+                    start: 2,
+                    end: 3,
+                    kind: "function".to_string()
+                },
+            ]
+        );
+    }
+}
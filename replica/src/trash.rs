@@ -0,0 +1,370 @@
+//! The trash: documents deleted by their owner, kept for `expiry.trash_retention_secs`.
+//!
+//! `DELETE /document/<id>` archives the document and records when it was trashed, so it can no
+//! longer be loaded and drops out of every listing, like an expired document. Until the
+//! retention window passes its owner sees it in `GET /me/trash` and can bring it back with
+//! `POST /document/<id>/restore`. Afterwards the reaper purges it with the expired documents.
+
+use crate::pool::Pool;
+use crate::expiry::{self, ExpiryConfig};
+use crate::notifications::{self, NotificationKind};
+use crate::routes::SharedRGAs;
+use crate::share::Access;
+use crate::tenancy::{self, Tenant};
+use crate::{audit, snapshot, Actor, ApiError, RequestId};
+use chrono::{DateTime, Duration as TimeDelta, Utc};
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use rocket::{delete, get, post};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// A document in its owner's trash.
+/// `trashed_at`: When the document was deleted.
+/// `purge_at`: When the document is purged and can no longer be restored.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrashedDocument {
+    pub document_id: Uuid,
+    pub title: String,
+    pub creation_date: String,
+    pub trashed_at: String,
+    pub purge_at: Option<String>,
+}
+
+/// When a document trashed at `trashed_at` is purged, None if the time can't be represented.
+pub fn purge_at(trashed_at: &str, config: &ExpiryConfig) -> Option<String> {
+    let trashed_at: DateTime<Utc> = DateTime::parse_from_rfc3339(trashed_at).ok()?.to_utc();
+    let retention: TimeDelta = i64::try_from(config.trash_retention_secs)
+        .ok()
+        .and_then(TimeDelta::try_seconds)?;
+    trashed_at
+        .checked_add_signed(retention)
+        .map(expiry::timestamp)
+}
+
+fn parse_document_id(id: &str) -> Result<Uuid, ApiError> {
+    match Uuid::parse_str(id) {
+        Ok(id) => Ok(id),
+        Err(_) => {
+            error!("Failed to parse document id");
+            Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ))
+        }
+    }
+}
+
+/// Checks the requesting user owns the document.
+async fn require_owner(client: &Client, document_id: &Uuid, actor: &Actor) -> Result<(), ApiError> {
+    let user_id: Uuid = actor.require_user()?;
+    if audit::document_owner(client, document_id).await? != user_id {
+        error!("Trash requested by a user who does not own the document");
+        return Err(ApiError::Forbidden(
+            "Only the document owner can delete or restore it".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Moves a document to its owner's trash. A loaded document's unsaved changes are persisted
+/// first, so it is restored as it was, and it is unloaded. Other replicas unload it on the
+/// reaper's next run. `id` is the document UUID.
+#[delete("/document/<id>")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn trash_document(
+    id: String,
+    actor: Actor,
+    rgas: &rocket::State<SharedRGAs>,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    request_id: RequestId,
+) -> Result<(), ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::ReadWrite).await?;
+
+    // same lock order as the routes: documents, then the database
+    let mut rgas = rgas.lock().await;
+    let mut client = tenancy::lock(db, &tenant).await?;
+    require_owner(&client, &document_id, &actor).await?;
+    expiry::check_not_archived(&client, &document_id).await?;
+
+    if let Some(rga) = rgas.get(&document_id).filter(|rga| rga.dirty) {
+        snapshot::persist_snapshot(&mut client, &document_id, rga).await?;
+    }
+    let now: String = expiry::timestamp(Utc::now());
+    if client
+        .execute(
+            "UPDATE document SET archived_at=$2, trashed_at=$2 WHERE document_id=$1",
+            &[&document_id, &now],
+        )
+        .await
+        .is_err()
+    {
+        error!("Failed to move the document to the trash");
+        return Err(ApiError::DatabaseError(
+            "Failed to move the document to the trash".to_string(),
+        ));
+    }
+
+    rgas.remove(&document_id);
+    info!("Document moved to the trash");
+    Ok(())
+}
+
+/// Restores a document from the trash while it is within the retention window. The document
+/// is loaded again by the next `GET /document/<id>`. `id` is the document UUID.
+#[post("/document/<id>/restore")]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn restore_trashed_document(
+    id: String,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    pool: &rocket::State<Pool>,
+    config: &rocket::State<ExpiryConfig>,
+    request_id: RequestId,
+) -> Result<(), ApiError> {
+    let document_id: Uuid = parse_document_id(&id)?;
+    let tenant: Tenant = actor.authorize(pool, &document_id, Access::ReadWrite).await?;
+    let client = tenancy::lock(db, &tenant).await?;
+    require_owner(&client, &document_id, &actor).await?;
+
+    let cutoff: String =
+        expiry::cutoff(Utc::now(), config.trash_retention_secs).unwrap_or_default();
+    let restored: u64 = match client
+        .execute(
+            "UPDATE document SET archived_at=NULL, trashed_at=NULL WHERE document_id=$1 AND trashed_at > $2",
+            &[&document_id, &cutoff],
+        )
+        .await
+    {
+        Ok(restored) => restored,
+        Err(_) => {
+            error!("Failed to restore the document from the trash");
+            return Err(ApiError::DatabaseError(
+                "Failed to restore the document from the trash".to_string(),
+            ));
+        }
+    };
+
+    if restored == 0 {
+        // not trashed, or trashed too long ago and waiting to be purged
+        let trashed: Option<String> = match client
+            .query_opt(
+                "SELECT trashed_at FROM document WHERE document_id=$1",
+                &[&document_id],
+            )
+            .await
+        {
+            Ok(row) => row.and_then(|row| row.get(0)),
+            Err(_) => {
+                error!("Failed to query the document table");
+                return Err(ApiError::DatabaseError(
+                    "Failed to query the document table".to_string(),
+                ));
+            }
+        };
+        return Err(match trashed {
+            Some(trashed_at) => {
+                error!("Restore requested after the retention window");
+                ApiError::Gone(format!(
+                    "Document was moved to the trash at {} and can no longer be restored",
+                    trashed_at
+                ))
+            }
+            None => {
+                error!("Restore requested for a document that is not in the trash");
+                ApiError::Conflict("Document is not in the trash".to_string())
+            }
+        });
+    }
+
+    notifications::notify_document(
+        &client,
+        &document_id,
+        NotificationKind::DocumentRestored,
+        actor.user_id,
+        "The document was restored from the trash",
+    )
+    .await;
+
+    info!("Document restored from the trash");
+    Ok(())
+}
+
+/// Lists the requesting user's documents in the trash, most recently deleted first, with
+/// when each is purged. Documents past the retention window are left out.
+#[get("/me/trash")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn list_trash(
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    config: &rocket::State<ExpiryConfig>,
+    request_id: RequestId,
+) -> Result<Json<Vec<TrashedDocument>>, ApiError> {
+    let user_id: Uuid = actor.require_user()?;
+    let cutoff: String =
+        expiry::cutoff(Utc::now(), config.trash_retention_secs).unwrap_or_default();
+
+    // the query only returns the user's documents, across every organization they belong to
+    let client = tenancy::lock(db, &Tenant::Any).await?;
+    let rows = match client
+        .query(
+            "SELECT document_id,title,creation_date,trashed_at FROM document \
+             WHERE owner_id=$1 AND trashed_at > $2 ORDER BY trashed_at DESC",
+            &[&user_id, &cutoff],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => {
+            error!("Failed to query the document table");
+            return Err(ApiError::DatabaseError(
+                "Failed to query the document table".to_string(),
+            ));
+        }
+    };
+
+    Ok(Json(
+        rows.iter()
+            .map(|row| {
+                let trashed_at: String = row.get(3);
+                TrashedDocument {
+                    document_id: row.get(0),
+                    title: row.get(1),
+                    creation_date: row.get(2),
+                    purge_at: purge_at(&trashed_at, config),
+                    trashed_at,
+                }
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestDatabase, NOTIFICATION_TABLES, ORGANIZATION_TABLES};
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client as LocalClient;
+
+    #[test]
+    fn test_purge_at() {
+        let config = ExpiryConfig {
+            trash_retention_secs: 2 * 24 * 60 * 60,
+            ..Default::default()
+        };
+        assert_eq!(
+            purge_at("2024-01-01T00:00:00Z", &config),
+            Some("2024-01-03T00:00:00Z".to_string())
+        );
+        assert_eq!(purge_at("yesterday", &config), None);
+
+        let forever = ExpiryConfig {
+            trash_retention_secs: u64::MAX,
+            ..config
+        };
+        assert_eq!(purge_at("2024-01-01T00:00:00Z", &forever), None);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a database, set NIMBLE_TEST_DATABASE_URL"]
+    async fn test_trash_routes() {
+        let database =
+            TestDatabase::create(&format!("{}{}", ORGANIZATION_TABLES, NOTIFICATION_TABLES)).await;
+        let db: Client = database.connect().await;
+        let (owner, member) = (Uuid::new_v4(), Uuid::new_v4());
+        let document_id: Uuid = testing::insert_document(&db, owner).await;
+        testing::insert_member(&db, document_id, member, "read_write").await;
+        let rocket = testing::rocket(&database)
+            .await
+            .manage(SharedRGAs::default())
+            .manage(ExpiryConfig::default())
+            .mount(
+                "/",
+                rocket::routes![trash_document, restore_trashed_document, list_trash],
+            );
+        let client = LocalClient::tracked(rocket).await.unwrap();
+        let document = format!("/document/{}", document_id);
+        let restore = format!("/document/{}/restore", document_id);
+        let trash = |user_id: Uuid| {
+            client
+                .get("/me/trash")
+                .header(testing::session(user_id))
+                .dispatch()
+        };
+
+        let anonymous = client.get("/me/trash").dispatch().await;
+        assert_eq!(anonymous.status(), Status::Unauthorized);
+        // members can edit the document but only its owner can delete it
+        let response = client
+            .delete(document.clone())
+            .header(testing::session(member))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = client
+            .delete(document.clone())
+            .header(testing::session(owner))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let trashed: Vec<TrashedDocument> = trash(owner).await.into_json().await.unwrap();
+        assert_eq!(trashed.len(), 1);
+        assert_eq!(trashed[0].document_id, document_id);
+        assert!(trashed[0].purge_at.is_some());
+        let trashed: Vec<TrashedDocument> = trash(member).await.into_json().await.unwrap();
+        assert!(trashed.is_empty());
+
+        let response = client
+            .post(restore.clone())
+            .header(testing::session(member))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .post(restore.clone())
+            .header(testing::session(owner))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let trashed: Vec<TrashedDocument> = trash(owner).await.into_json().await.unwrap();
+        assert!(trashed.is_empty());
+        let kinds: Vec<String> = db
+            .query(
+                "SELECT kind FROM notifications WHERE user_id=$1",
+                &[&member],
+            )
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(kinds, vec!["document_restored"]);
+        let response = client
+            .post(restore.clone())
+            .header(testing::session(owner))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Conflict);
+
+        // documents trashed before the retention window can't be restored
+        db.execute(
+            "UPDATE document SET archived_at=$2, trashed_at=$2 WHERE document_id=$1",
+            &[&document_id, &"2000-01-01T00:00:00Z"],
+        )
+        .await
+        .unwrap();
+        let response = client
+            .post(restore)
+            .header(testing::session(owner))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Gone);
+
+        database.drop().await;
+    }
+}
@@ -0,0 +1,705 @@
+//! Usage metering: per-tenant usage events for billing and chargeback.
+//!
+//! Routes record what they use with [`record`]: operations applied, messages broadcast to the
+//! other replicas and seconds of code execution, counted per organization and document. The
+//! replica that holds the `usage.storage` lease also samples the bytes each organization stores.
+//! Events are added up in memory and written to the configured sinks (`log`, `database` or
+//! `kinesis`) every `usage.flush_interval_secs`, and when the replica shuts down. A sink that
+//! fails is sent the same events again on the next flush. Every event has an id, so consumers
+//! can drop the ones they were sent twice.
+//!
+//! With the `database` sink, `GET /orgs/<id>/usage` reports an organization's usage over a
+//! period to its admins. Usage of personal documents is recorded without an organization.
+
+use crate::leader::Leader;
+use crate::signing::AwsSigner;
+use crate::tenancy::{self, Role, Tenant};
+use crate::{expiry, Actor, ApiError, FieldError, RequestId};
+use aws_config::SdkConfig;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use rocket::fairing::AdHoc;
+use rocket::get;
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use rocket::tokio::{self, time};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+/// The lease of storage sampling.
+pub const STORAGE_JOB: &str = "usage.storage";
+
+/// Kinesis accepts at most this many records per `PutRecords` call.
+const KINESIS_BATCH_LEN: usize = 500;
+
+/// Events a failing sink keeps for the next flush, the oldest are dropped beyond it.
+const MAX_RETRY_EVENTS: usize = 100_000;
+
+/// What is metered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// Operations applied to documents.
+    Operations,
+    /// Bytes of document content stored, sampled.
+    StorageBytes,
+    /// Operations broadcast to the other replicas.
+    BroadcastMessages,
+    /// Seconds programs ran in the sandbox.
+    ExecutionSeconds,
+}
+
+impl Metric {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Metric::Operations => "operations",
+            Metric::StorageBytes => "storage_bytes",
+            Metric::BroadcastMessages => "broadcast_messages",
+            Metric::ExecutionSeconds => "execution_seconds",
+        }
+    }
+}
+
+/// Where usage events are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SinkKind {
+    Log,
+    Database,
+    Kinesis,
+}
+
+/// `sinks`: Where events are written, any of `log`, `database` and `kinesis` (none disables
+/// metering).
+/// `flush_interval_secs`: Seconds between writes to the sinks.
+/// `storage_interval_secs`: Seconds between samples of the stored bytes, 0 disables them.
+/// `kinesis_stream`: The Kinesis stream events are put on.
+/// `region`: The region of the stream.
+/// `endpoint`: A Kinesis compatible endpoint to use instead of AWS.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UsageConfig {
+    pub sinks: Vec<SinkKind>,
+    pub flush_interval_secs: u64,
+    pub storage_interval_secs: u64,
+    pub kinesis_stream: Option<String>,
+    pub region: String,
+    pub endpoint: Option<String>,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        UsageConfig {
+            sinks: Vec::new(),
+            flush_interval_secs: 60,
+            storage_interval_secs: 60 * 60,
+            kinesis_stream: None,
+            region: "af-south-1".to_string(),
+            endpoint: None,
+        }
+    }
+}
+
+/// Usage of one metric by an organization (or personal documents) over a flush interval.
+/// `org_id`: The organization, None for personal documents.
+/// `document_id`: The document, None for samples of a whole organization.
+/// `recorded_at`: When the events were flushed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageEvent {
+    pub event_id: Uuid,
+    pub org_id: Option<Uuid>,
+    pub document_id: Option<Uuid>,
+    pub metric: Metric,
+    pub quantity: f64,
+    pub recorded_at: String,
+}
+
+type UsageKey = (Option<Uuid>, Option<Uuid>, Metric);
+
+static PENDING: OnceLock<std::sync::Mutex<HashMap<UsageKey, f64>>> = OnceLock::new();
+
+fn org_of(tenant: &Tenant) -> Option<Uuid> {
+    match tenant {
+        Tenant::Org(org_id) => Some(*org_id),
+        Tenant::Any | Tenant::Personal => None,
+    }
+}
+
+/// Records usage of a document by a tenant, if metering is enabled.
+pub fn record(tenant: &Tenant, document_id: Uuid, metric: Metric, quantity: f64) {
+    let Some(pending) = PENDING.get() else {
+        return;
+    };
+    let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+    *pending
+        .entry((org_of(tenant), Some(document_id), metric))
+        .or_default() += quantity;
+}
+
+/// Takes the usage recorded since the last flush.
+fn take(recorded_at: &str) -> Vec<UsageEvent> {
+    let Some(pending) = PENDING.get() else {
+        return Vec::new();
+    };
+    let pending = std::mem::take(&mut *pending.lock().unwrap_or_else(|e| e.into_inner()));
+    pending
+        .into_iter()
+        .map(|((org_id, document_id, metric), quantity)| UsageEvent {
+            event_id: Uuid::new_v4(),
+            org_id,
+            document_id,
+            metric,
+            quantity,
+            recorded_at: recorded_at.to_string(),
+        })
+        .collect()
+}
+
+/// Writes usage events somewhere.
+#[rocket::async_trait]
+pub trait UsageSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Writes the events, all of them or an error.
+    async fn write(&self, events: &[UsageEvent]) -> Result<(), ApiError>;
+}
+
+/// Writes events to the replica's log.
+pub struct LogSink;
+
+#[rocket::async_trait]
+impl UsageSink for LogSink {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    async fn write(&self, events: &[UsageEvent]) -> Result<(), ApiError> {
+        for event in events {
+            info!(
+                event_id = %event.event_id,
+                org_id = ?event.org_id,
+                document_id = ?event.document_id,
+                metric = event.metric.as_str(),
+                quantity = event.quantity,
+                "Usage"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Writes events to the `usage_events` table.
+pub struct DatabaseSink {
+    db: Arc<Mutex<Client>>,
+}
+
+#[rocket::async_trait]
+impl UsageSink for DatabaseSink {
+    fn name(&self) -> &'static str {
+        "database"
+    }
+
+    #[instrument(name = "db.record_usage", skip_all, fields(events = events.len()))]
+    async fn write(&self, events: &[UsageEvent]) -> Result<(), ApiError> {
+        let mut client = tenancy::lock_any(&self.db).await;
+        let tx = match client.transaction().await {
+            Ok(tx) => tx,
+            Err(_) => {
+                error!("Failed to create database transaction");
+                return Err(ApiError::DatabaseError(
+                    "Failed to create database transaction".to_string(),
+                ));
+            }
+        };
+        for event in events {
+            if tx
+                .execute(
+                    "INSERT INTO usage_events (event_id,org_id,document_id,metric,quantity,recorded_at) \
+                     VALUES ($1,$2,$3,$4,$5,$6) ON CONFLICT (event_id) DO NOTHING",
+                    &[
+                        &event.event_id,
+                        &event.org_id,
+                        &event.document_id,
+                        &event.metric.as_str(),
+                        &event.quantity,
+                        &event.recorded_at,
+                    ],
+                )
+                .await
+                .is_err()
+            {
+                error!("Failed to insert into the usage_events table");
+                return Err(ApiError::DatabaseError(
+                    "Failed to insert into the usage_events table".to_string(),
+                ));
+            }
+        }
+        if tx.commit().await.is_err() {
+            error!("Failed to commit database transaction");
+            return Err(ApiError::DatabaseError(
+                "Failed to commit database transaction".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PutRecordsResponse {
+    failed_record_count: usize,
+}
+
+/// Puts events on a Kinesis stream as JSON records, partitioned by organization, signing
+/// requests with the replica's AWS credentials.
+pub struct KinesisSink {
+    signer: AwsSigner,
+    url: String,
+    stream: String,
+}
+
+impl KinesisSink {
+    /// Creates the client, or returns None if no stream or AWS credentials are configured.
+    pub fn new(aws_config: &SdkConfig, config: &UsageConfig) -> Option<KinesisSink> {
+        let stream: &String = config.kinesis_stream.as_ref()?;
+        let credentials = aws_config.credentials_provider()?;
+
+        let url: String = match &config.endpoint {
+            Some(endpoint) => format!("{}/", endpoint.trim_end_matches('/')),
+            None => format!("https://kinesis.{}.amazonaws.com/", config.region),
+        };
+
+        Some(KinesisSink {
+            signer: AwsSigner::new(credentials, &config.region, "kinesis"),
+            url,
+            stream: stream.clone(),
+        })
+    }
+
+    async fn put_records(&self, events: &[UsageEvent]) -> Result<(), ApiError> {
+        let url = match reqwest::Url::parse(&self.url) {
+            Ok(url) => url,
+            Err(_) => {
+                error!("Invalid Kinesis url");
+                return Err(ApiError::InternalServerError(
+                    "Invalid Kinesis url".to_string(),
+                ));
+            }
+        };
+        let mut records: Vec<serde_json::Value> = Vec::with_capacity(events.len());
+        for event in events {
+            let data: Vec<u8> = match serde_json::to_vec(event) {
+                Ok(data) => data,
+                Err(_) => {
+                    error!("Failed to serialize usage event");
+                    return Err(ApiError::InternalServerError(
+                        "Failed to serialize usage event".to_string(),
+                    ));
+                }
+            };
+            let partition_key: String = match event.org_id {
+                Some(org_id) => org_id.to_string(),
+                None => "personal".to_string(),
+            };
+            records.push(serde_json::json!({
+                "Data": BASE64.encode(data),
+                "PartitionKey": partition_key,
+            }));
+        }
+        let body = serde_json::json!({ "StreamName": self.stream, "Records": records });
+        let headers = [
+            ("content-type", "application/x-amz-json-1.1"),
+            ("x-amz-target", "Kinesis_20131202.PutRecords"),
+        ];
+
+        let response = self
+            .signer
+            .send(
+                reqwest::Method::POST,
+                url,
+                &headers,
+                body.to_string().into_bytes(),
+            )
+            .await?;
+        if !response.status().is_success() {
+            error!(
+                "Kinesis rejected PutRecords with status {}",
+                response.status()
+            );
+            return Err(ApiError::RequestFailed(format!(
+                "Kinesis rejected PutRecords with status {}",
+                response.status()
+            )));
+        }
+
+        let body = match response.bytes().await {
+            Ok(body) => body,
+            Err(_) => {
+                error!("Failed to read the Kinesis PutRecords response");
+                return Err(ApiError::RequestFailed(
+                    "Failed to read the Kinesis PutRecords response".to_string(),
+                ));
+            }
+        };
+        match serde_json::from_slice::<PutRecordsResponse>(&body) {
+            Ok(response) if response.failed_record_count == 0 => Ok(()),
+            Ok(response) => {
+                // the whole batch is sent again, consumers drop the duplicates by event id
+                error!(
+                    "Kinesis failed to put {} records",
+                    response.failed_record_count
+                );
+                Err(ApiError::RequestFailed(format!(
+                    "Kinesis failed to put {} records",
+                    response.failed_record_count
+                )))
+            }
+            Err(_) => {
+                error!("Failed to parse the Kinesis PutRecords response");
+                Err(ApiError::RequestFailed(
+                    "Failed to parse the Kinesis PutRecords response".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl UsageSink for KinesisSink {
+    fn name(&self) -> &'static str {
+        "kinesis"
+    }
+
+    #[instrument(name = "kinesis.put_records", skip_all, fields(events = events.len()))]
+    async fn write(&self, events: &[UsageEvent]) -> Result<(), ApiError> {
+        for batch in events.chunks(KINESIS_BATCH_LEN) {
+            self.put_records(batch).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Samples the bytes of document content each organization stores.
+#[instrument(name = "usage.sample_storage", skip_all)]
+pub async fn sample_storage(
+    client: &Client,
+    recorded_at: &str,
+) -> Result<Vec<UsageEvent>, ApiError> {
+    let rows = match client
+        .query(
+            "SELECT w.org_id, COALESCE(SUM(octet_length(o.value)), 0)::BIGINT FROM operations o \
+             JOIN document d ON d.document_id=o.document_id \
+             LEFT JOIN workspaces w ON w.workspace_id=d.workspace_id \
+             WHERE d.archived_at IS NULL GROUP BY w.org_id",
+            &[],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => {
+            error!("Failed to measure the stored bytes");
+            return Err(ApiError::DatabaseError(
+                "Failed to measure the stored bytes".to_string(),
+            ));
+        }
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| UsageEvent {
+            event_id: Uuid::new_v4(),
+            org_id: row.get(0),
+            document_id: None,
+            metric: Metric::StorageBytes,
+            quantity: row.get::<_, i64>(1) as f64,
+            recorded_at: recorded_at.to_string(),
+        })
+        .collect())
+}
+
+/// A sink and the events it failed to write.
+struct Pending {
+    sink: Arc<dyn UsageSink>,
+    retry: Vec<UsageEvent>,
+}
+
+/// Writes the events to every sink, keeping them for the next flush of the sinks that fail.
+async fn flush(sinks: &mut [Pending], events: Vec<UsageEvent>) {
+    for pending in sinks.iter_mut() {
+        pending.retry.extend(events.iter().cloned());
+        if pending.retry.is_empty() {
+            continue;
+        }
+        if pending.retry.len() > MAX_RETRY_EVENTS {
+            let dropped: usize = pending.retry.len() - MAX_RETRY_EVENTS;
+            warn!(
+                sink = pending.sink.name(),
+                dropped, "Dropped usage events the sink failed to write"
+            );
+            pending.retry.drain(..dropped);
+        }
+        match pending.sink.write(&pending.retry).await {
+            Ok(()) => pending.retry.clear(),
+            Err(e) => warn!(
+                sink = pending.sink.name(),
+                "Failed to write usage events, retrying on the next flush: {}", e
+            ),
+        }
+    }
+}
+
+/// Fairing that enables metering and starts the task flushing usage to the sinks.
+pub fn attach_usage(config: UsageConfig, kinesis: Option<KinesisSink>) -> AdHoc {
+    AdHoc::on_liftoff("Usage metering", move |rocket| {
+        Box::pin(async move {
+            if config.sinks.is_empty() {
+                info!("Usage metering is disabled");
+                return;
+            }
+            let (db, leader) = match (
+                rocket.state::<Arc<Mutex<Client>>>(),
+                rocket.state::<Leader>(),
+            ) {
+                (Some(db), Some(leader)) => (Arc::clone(db), leader.clone()),
+                _ => {
+                    warn!("Replica state is unavailable, usage metering is disabled");
+                    return;
+                }
+            };
+
+            let mut kinesis = kinesis;
+            let mut sinks: Vec<Pending> = Vec::new();
+            for kind in &config.sinks {
+                let sink: Arc<dyn UsageSink> = match kind {
+                    SinkKind::Log => Arc::new(LogSink),
+                    SinkKind::Database => Arc::new(DatabaseSink {
+                        db: Arc::clone(&db),
+                    }),
+                    SinkKind::Kinesis => match kinesis.take() {
+                        Some(kinesis) => Arc::new(kinesis),
+                        None => {
+                            warn!("AWS credentials are unavailable, the kinesis usage sink is disabled");
+                            continue;
+                        }
+                    },
+                };
+                sinks.push(Pending {
+                    sink,
+                    retry: Vec::new(),
+                });
+            }
+            let _ = PENDING.set(std::sync::Mutex::new(HashMap::new()));
+            let mut shutdown = rocket.shutdown();
+
+            tokio::spawn(async move {
+                let mut interval = time::interval(Duration::from_secs(config.flush_interval_secs));
+                let mut storage =
+                    time::interval(Duration::from_secs(config.storage_interval_secs.max(1)));
+                interval.tick().await;
+
+                loop {
+                    let mut events: Vec<UsageEvent> = Vec::new();
+                    let stop: bool = tokio::select! {
+                        _ = interval.tick() => false,
+                        _ = storage.tick(), if config.storage_interval_secs > 0 => {
+                            let recorded_at: String = expiry::timestamp(Utc::now());
+                            let client = tenancy::lock_any(&db).await;
+                            if leader
+                                .acquire(&client, STORAGE_JOB, config.storage_interval_secs)
+                                .await
+                            {
+                                // errors are logged, the next sample retries
+                                if let Ok(samples) = sample_storage(&client, &recorded_at).await {
+                                    events = samples;
+                                }
+                            }
+                            false
+                        }
+                        _ = &mut shutdown => true,
+                    };
+                    events.extend(take(&expiry::timestamp(Utc::now())));
+                    flush(&mut sinks, events).await;
+                    if stop {
+                        break;
+                    }
+                }
+                if config.storage_interval_secs > 0 {
+                    leader.release(&*db.lock().await, STORAGE_JOB).await;
+                }
+            });
+        })
+    })
+}
+
+/// An organization's usage over a period.
+/// `storage_bytes`: The latest sample of the bytes stored before the end of the period.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageSummary {
+    pub org_id: Uuid,
+    pub from: String,
+    pub to: String,
+    pub operations: u64,
+    pub broadcast_messages: u64,
+    pub execution_seconds: f64,
+    pub storage_bytes: u64,
+}
+
+/// Parses a bound of the reported period.
+fn parse_time(
+    value: Option<String>,
+    field: &str,
+    default: DateTime<Utc>,
+) -> Result<String, ApiError> {
+    match value {
+        None => Ok(expiry::timestamp(default)),
+        Some(value) => match DateTime::parse_from_rfc3339(&value) {
+            Ok(time) => Ok(expiry::timestamp(time.to_utc())),
+            Err(_) => Err(ApiError::ValidationFailed(vec![FieldError::new(
+                field,
+                "must be an RFC 3339 time such as 2025-01-04T10:15:02Z",
+            )])),
+        },
+    }
+}
+
+/// The start of the month `now` falls in.
+fn month_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0)
+        .single()
+        .unwrap_or(now)
+}
+
+/// Reports an organization's usage from `from` (inclusive, the start of the month by default)
+/// to `to` (exclusive, now by default). Only the organization's admins and owners can read it.
+///
+/// Example Response:
+/// {
+///     "org_id" : "6a0f1c3e-2b4d-4e8f-9a1b-3c5d7e9f1a2b",
+///     "from" : "2025-01-01T00:00:00Z",
+///     "to" : "2025-01-04T14:13:20Z",
+///     "operations" : 18342,
+///     "broadcast_messages" : 18342,
+///     "execution_seconds" : 95.4,
+///     "storage_bytes" : 1048576
+/// }
+#[get("/orgs/<id>/usage?<from>&<to>")]
+#[instrument(skip_all, fields(request_id = %request_id, org_id = %id))]
+pub async fn fetch_usage(
+    id: String,
+    from: Option<String>,
+    to: Option<String>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    config: &rocket::State<UsageConfig>,
+    request_id: RequestId,
+) -> Result<Json<UsageSummary>, ApiError> {
+    let org_id: Uuid = match Uuid::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => {
+            error!("Failed to parse organization id");
+            return Err(ApiError::InvalidOperation(
+                "Failed to parse organization id".to_string(),
+            ));
+        }
+    };
+    let user_id: Uuid = actor.require_user()?;
+    if !config.sinks.contains(&SinkKind::Database) {
+        error!("Usage requested while the database usage sink is disabled");
+        return Err(ApiError::Forbidden(
+            "Usage is not stored on this replica".to_string(),
+        ));
+    }
+
+    let now: DateTime<Utc> = Utc::now();
+    let from: String = parse_time(from, "from", month_start(now))?;
+    let to: String = parse_time(to, "to", now)?;
+
+    let client = tenancy::lock(db, &Tenant::Org(org_id)).await?;
+    tenancy::require_role(&client, &org_id, &user_id, Role::Admin).await?;
+
+    let rows = match client
+        .query(
+            "SELECT metric, SUM(quantity) FROM usage_events \
+             WHERE org_id=$1 AND recorded_at>=$2 AND recorded_at<$3 AND metric<>$4 GROUP BY metric",
+            &[&org_id, &from, &to, &Metric::StorageBytes.as_str()],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => {
+            error!("Failed to read the usage_events table");
+            return Err(ApiError::DatabaseError(
+                "Failed to read the usage_events table".to_string(),
+            ));
+        }
+    };
+    let storage = match client
+        .query_opt(
+            "SELECT quantity FROM usage_events WHERE org_id=$1 AND metric=$2 AND recorded_at<$3 \
+             ORDER BY recorded_at DESC LIMIT 1",
+            &[&org_id, &Metric::StorageBytes.as_str(), &to],
+        )
+        .await
+    {
+        Ok(row) => row.map(|row| row.get::<_, f64>(0)).unwrap_or(0.0),
+        Err(_) => {
+            error!("Failed to read the usage_events table");
+            return Err(ApiError::DatabaseError(
+                "Failed to read the usage_events table".to_string(),
+            ));
+        }
+    };
+
+    let totals: HashMap<String, f64> = rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+    let total = |metric: Metric| totals.get(metric.as_str()).copied().unwrap_or(0.0);
+    Ok(Json(UsageSummary {
+        org_id,
+        from,
+        to,
+        operations: total(Metric::Operations) as u64,
+        broadcast_messages: total(Metric::BroadcastMessages) as u64,
+        execution_seconds: total(Metric::ExecutionSeconds),
+        storage_bytes: storage as u64,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_take() {
+        let _ = PENDING.set(std::sync::Mutex::new(HashMap::new()));
+        let (org_id, document_id) = (Uuid::new_v4(), Uuid::new_v4());
+        record(&Tenant::Org(org_id), document_id, Metric::Operations, 1.0);
+        record(&Tenant::Org(org_id), document_id, Metric::Operations, 1.0);
+        record(
+            &Tenant::Personal,
+            document_id,
+            Metric::ExecutionSeconds,
+            0.5,
+        );
+
+        let mut events: Vec<UsageEvent> = take("2025-01-04T14:13:20Z");
+        events.sort_by_key(|event| event.org_id.is_none());
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].org_id, Some(org_id));
+        assert_eq!(events[0].metric, Metric::Operations);
+        assert_eq!(events[0].quantity, 2.0);
+        assert_eq!(events[1].org_id, None);
+        assert_eq!(events[1].quantity, 0.5);
+        assert!(take("2025-01-04T14:13:20Z").is_empty());
+
+        let now = DateTime::parse_from_rfc3339("2025-01-04T14:13:20Z")
+            .unwrap()
+            .to_utc();
+        assert_eq!(expiry::timestamp(month_start(now)), "2025-01-01T00:00:00Z");
+        assert!(parse_time(Some("yesterday".to_string()), "from", now).is_err());
+    }
+}
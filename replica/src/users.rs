@@ -0,0 +1,500 @@
+//! User accounts: registration, login and profiles.
+//!
+//! Passwords are hashed with Argon2id and only the PHC string is stored. Documents are owned
+//! by registered users, so `owner_id` is checked against the `users` table on creation.
+
+use crate::auth::{self, AuthConfig, SessionError, UserSession};
+use crate::limits::JsonBody;
+use crate::{expiry, Actor, ApiError, FieldError, RequestId};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rocket::serde::json::Json;
+use rocket::tokio::{sync::Mutex, task};
+use rocket::{get, post};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::{Client, Row};
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// The shortest password accepted on registration.
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// The longest display name accepted.
+pub const MAX_DISPLAY_NAME_LEN: usize = 64;
+
+/// The colour given to users that don't choose one.
+pub const DEFAULT_COLOR: &str = "#4f46e5";
+
+/// Request body for registering a user.
+/// `email`: The address the user logs in with.
+/// `password`: At least `MIN_PASSWORD_LEN` characters.
+/// `display_name`: The name shown to collaborators.
+/// `color`: The user's colour as `#rrggbb` (defaults to `DEFAULT_COLOR`).
+#[derive(Debug, Deserialize)]
+pub struct RegisterRequest {
+    pub email: String,
+    pub password: String,
+    pub display_name: String,
+    #[serde(default)]
+    pub color: Option<String>,
+}
+
+/// Request body for logging in.
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    pub email: String,
+    pub password: String,
+}
+
+/// Request body for updating a profile, omitted fields are left unchanged.
+#[derive(Debug, Deserialize)]
+pub struct ProfileUpdate {
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub color: Option<String>,
+}
+
+/// A user's public profile.
+/// `user_id`: The id used as a document's `owner_id`.
+/// `email`: The address the user logs in with.
+/// `display_name`: The name shown to collaborators.
+/// `color`: The user's colour as `#rrggbb`.
+/// `created_at`: When the user registered.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserProfile {
+    pub user_id: Uuid,
+    pub email: String,
+    pub display_name: String,
+    pub color: String,
+    pub created_at: String,
+}
+
+/// A logged in user.
+/// `session_token`: The token sent as `X-Session-Token` to make requests as the user.
+/// `expires_at`: When the session ends and the user has to log in again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoginResponse {
+    #[serde(flatten)]
+    pub profile: UserProfile,
+    pub session_token: String,
+    pub expires_at: String,
+}
+
+impl UserProfile {
+    fn from_row(row: &Row) -> Self {
+        UserProfile {
+            user_id: row.get("user_id"),
+            email: row.get("email"),
+            display_name: row.get("display_name"),
+            color: row.get("color"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+/// Returns true for an address such as `ada@example.com`.
+pub fn is_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((user, domain)) => !user.is_empty() && domain.contains('.'),
+        None => false,
+    }
+}
+
+/// Returns true for a colour written as `#rrggbb`.
+fn is_color(color: &str) -> bool {
+    color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn check_display_name(display_name: &str, errors: &mut Vec<FieldError>) {
+    if display_name.trim().is_empty() {
+        errors.push(FieldError::new("display_name", "must not be empty"));
+    } else if display_name.chars().count() > MAX_DISPLAY_NAME_LEN {
+        errors.push(FieldError::new(
+            "display_name",
+            &format!("must be at most {} characters", MAX_DISPLAY_NAME_LEN),
+        ));
+    }
+}
+
+fn check_color(color: &Option<String>, errors: &mut Vec<FieldError>) {
+    if let Some(color) = color {
+        if !is_color(color) {
+            errors.push(FieldError::new("color", "must be a colour such as #4f46e5"));
+        }
+    }
+}
+
+impl RegisterRequest {
+    /// Checks the request, collecting every invalid field.
+    pub fn check(&self) -> Vec<FieldError> {
+        let mut errors: Vec<FieldError> = Vec::new();
+
+        if !is_email(self.email.trim()) {
+            errors.push(FieldError::new("email", "must be an email address"));
+        }
+        if self.password.chars().count() < MIN_PASSWORD_LEN {
+            errors.push(FieldError::new(
+                "password",
+                &format!("must be at least {} characters", MIN_PASSWORD_LEN),
+            ));
+        }
+        check_display_name(&self.display_name, &mut errors);
+        check_color(&self.color, &mut errors);
+
+        errors
+    }
+}
+
+impl ProfileUpdate {
+    /// Checks the request, collecting every invalid field.
+    pub fn check(&self) -> Vec<FieldError> {
+        let mut errors: Vec<FieldError> = Vec::new();
+        if let Some(display_name) = &self.display_name {
+            check_display_name(display_name, &mut errors);
+        }
+        check_color(&self.color, &mut errors);
+        errors
+    }
+}
+
+/// Hashes a password with Argon2id and a random salt, returning the PHC string.
+pub fn hash_password(password: &str) -> Result<String, ApiError> {
+    let salt = SaltString::generate(&mut OsRng);
+    match Argon2::default().hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(_) => {
+            error!("Failed to hash password");
+            Err(ApiError::InternalServerError(
+                "Failed to hash password".to_string(),
+            ))
+        }
+    }
+}
+
+/// Checks a password against a stored PHC string.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => {
+            error!("Stored password hash could not be parsed");
+            false
+        }
+    }
+}
+
+/// Returns `ValidationFailed` unless `owner_id` is a registered user.
+pub async fn check_owner(client: &Client, owner_id: &Uuid) -> Result<(), ApiError> {
+    match client
+        .query_opt("SELECT 1 FROM users WHERE user_id=$1", &[owner_id])
+        .await
+    {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(ApiError::ValidationFailed(vec![FieldError::new(
+            "owner_id",
+            "must be the id of a registered user",
+        )])),
+        Err(_) => {
+            error!("Failed to read the users table");
+            Err(ApiError::DatabaseError(
+                "Failed to read the users table".to_string(),
+            ))
+        }
+    }
+}
+
+/// Runs a password hash or check off the async runtime, Argon2 is deliberately slow.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, ApiError> {
+    match task::spawn_blocking(f).await {
+        Ok(result) => Ok(result),
+        Err(_) => {
+            error!("Password hashing task failed");
+            Err(ApiError::InternalServerError(
+                "Password hashing task failed".to_string(),
+            ))
+        }
+    }
+}
+
+/// Registers a user, returning their profile. The `user_id` is used as the `owner_id` of the
+/// user's documents, and the user logs in to get a session.
+///
+/// Example Request:
+/// {
+///     "email": "ada@example.com",
+///     "password": "correct horse battery staple",
+///     "display_name": "Ada",
+///     "color": "#e11d48"
+/// }
+#[post("/users/register", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn register(
+    request: JsonBody<RegisterRequest>,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<UserProfile>, ApiError> {
+    let errors: Vec<FieldError> = request.check();
+    if !errors.is_empty() {
+        error!("Rejected registration with {} invalid fields", errors.len());
+        return Err(ApiError::ValidationFailed(errors));
+    }
+
+    let password: String = request.password.clone();
+    let password_hash: String = blocking(move || hash_password(&password)).await??;
+
+    let email: String = request.email.trim().to_lowercase();
+    let color: String = request
+        .color
+        .clone()
+        .unwrap_or_else(|| DEFAULT_COLOR.to_string());
+    let created_at: String = expiry::timestamp(chrono::Utc::now());
+
+    let client = db.lock().await;
+    let row = match client
+        .query_one(
+            "INSERT INTO users (email,password_hash,display_name,color,created_at) VALUES ($1,$2,$3,$4,$5) RETURNING *",
+            &[&email, &password_hash, &request.display_name.trim(), &color, &created_at],
+        )
+        .await
+    {
+        Ok(row) => row,
+        Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+            error!("Registration with an email that is already registered");
+            return Err(ApiError::Conflict(
+                "A user with this email is already registered".to_string(),
+            ));
+        }
+        Err(_) => {
+            error!("Failed to insert into the users table");
+            return Err(ApiError::DatabaseError(
+                "Failed to register the user".to_string(),
+            ));
+        }
+    };
+
+    let profile = UserProfile::from_row(&row);
+    info!(user_id = %profile.user_id, "User registered");
+    Ok(Json(profile))
+}
+
+/// Checks a user's email and password, returning their profile and a session token.
+#[post("/users/login", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn login(
+    request: JsonBody<LoginRequest>,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    config: &rocket::State<AuthConfig>,
+    request_id: RequestId,
+) -> Result<Json<LoginResponse>, ApiError> {
+    let secret: &str = match &config.secret {
+        Some(secret) => secret,
+        None => {
+            error!("Login while login is disabled");
+            return Err(ApiError::Forbidden(SessionError::Disabled.to_string()));
+        }
+    };
+    let email: String = request.email.trim().to_lowercase();
+
+    let row = {
+        let client = db.lock().await;
+        match client
+            .query_opt("SELECT * FROM users WHERE email=$1", &[&email])
+            .await
+        {
+            Ok(row) => row,
+            Err(_) => {
+                error!("Failed to read the users table");
+                return Err(ApiError::DatabaseError(
+                    "Failed to read the users table".to_string(),
+                ));
+            }
+        }
+    };
+
+    let invalid = || ApiError::Unauthorized("Invalid email or password".to_string());
+    let row = match row {
+        Some(row) => row,
+        None => {
+            error!("Login for an unknown email");
+            return Err(invalid());
+        }
+    };
+
+    let password: String = request.password.clone();
+    let password_hash: String = row.get("password_hash");
+    if !blocking(move || verify_password(&password, &password_hash)).await? {
+        error!("Login with an invalid password");
+        return Err(invalid());
+    }
+
+    let profile = UserProfile::from_row(&row);
+    let expires = match config.session_expiry(chrono::Utc::now()) {
+        Some(expires) => expires,
+        None => {
+            error!("auth.session_ttl_secs is out of range");
+            return Err(ApiError::InternalServerError(
+                "The session lifetime is out of range".to_string(),
+            ));
+        }
+    };
+    let session = UserSession {
+        user_id: profile.user_id,
+        expires_at: expires.timestamp(),
+    };
+    info!(user_id = %profile.user_id, "User logged in");
+    Ok(Json(LoginResponse {
+        profile,
+        session_token: auth::sign(&session, secret),
+        expires_at: expiry::timestamp(expires),
+    }))
+}
+
+/// Fetches a user's profile.
+#[get("/users/<id>")]
+#[instrument(skip_all, fields(request_id = %request_id, user_id = %id))]
+pub async fn fetch_profile(
+    id: String,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<UserProfile>, ApiError> {
+    let user_id: Uuid = parse_user_id(&id)?;
+
+    let client = db.lock().await;
+    match client
+        .query_opt("SELECT * FROM users WHERE user_id=$1", &[&user_id])
+        .await
+    {
+        Ok(Some(row)) => Ok(Json(UserProfile::from_row(&row))),
+        Ok(None) => {
+            error!("User not found");
+            Err(ApiError::NotFound("User not found".to_string()))
+        }
+        Err(_) => {
+            error!("Failed to read the users table");
+            Err(ApiError::DatabaseError(
+                "Failed to read the users table".to_string(),
+            ))
+        }
+    }
+}
+
+/// Updates the display name and colour of the user making the request.
+#[post("/users/<id>/profile", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id, user_id = %id))]
+pub async fn update_profile(
+    id: String,
+    request: JsonBody<ProfileUpdate>,
+    actor: Actor,
+    db: &rocket::State<Arc<Mutex<Client>>>,
+    request_id: RequestId,
+) -> Result<Json<UserProfile>, ApiError> {
+    let user_id: Uuid = parse_user_id(&id)?;
+    if actor.require_user()? != user_id {
+        error!("Profile update for another user");
+        return Err(ApiError::Forbidden(
+            "Users can only update their own profile".to_string(),
+        ));
+    }
+
+    let errors: Vec<FieldError> = request.check();
+    if !errors.is_empty() {
+        error!(
+            "Rejected profile update with {} invalid fields",
+            errors.len()
+        );
+        return Err(ApiError::ValidationFailed(errors));
+    }
+
+    let display_name: Option<&str> = request.display_name.as_deref().map(str::trim);
+    let client = db.lock().await;
+    match client
+        .query_opt(
+            "UPDATE users SET display_name=COALESCE($2,display_name), color=COALESCE($3,color) WHERE user_id=$1 RETURNING *",
+            &[&user_id, &display_name, &request.color],
+        )
+        .await
+    {
+        Ok(Some(row)) => {
+            info!("Profile updated");
+            Ok(Json(UserProfile::from_row(&row)))
+        }
+        Ok(None) => {
+            error!("User not found");
+            Err(ApiError::NotFound("User not found".to_string()))
+        }
+        Err(_) => {
+            error!("Failed to update the users table");
+            Err(ApiError::DatabaseError(
+                "Failed to update the profile".to_string(),
+            ))
+        }
+    }
+}
+
+fn parse_user_id(id: &str) -> Result<Uuid, ApiError> {
+    match Uuid::parse_str(id) {
+        Ok(id) => Ok(id),
+        Err(_) => {
+            error!("Failed to parse user id");
+            Err(ApiError::InvalidOperation(
+                "Failed to parse user id".to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(errors: Vec<FieldError>) -> Vec<String> {
+        errors.into_iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn test_check_registration() {
+        let request =
+            |email: &str, password: &str, name: &str, color: Option<&str>| RegisterRequest {
+                email: email.to_string(),
+                password: password.to_string(),
+                display_name: name.to_string(),
+                color: color.map(|c| c.to_string()),
+            };
+
+        assert!(request("ada@example.com", "correct horse", "Ada", None)
+            .check()
+            .is_empty());
+        assert!(
+            request("ada@example.com", "correct horse", "Ada", Some("#E11D48"))
+                .check()
+                .is_empty()
+        );
+        assert_eq!(
+            fields(request("ada", "short", " ", Some("red")).check()),
+            vec!["email", "password", "display_name", "color"]
+        );
+        assert_eq!(
+            fields(request("@example.com", "correct horse", &"a".repeat(65), None).check()),
+            vec!["email", "display_name"]
+        );
+    }
+
+    #[test]
+    fn test_password_hash() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+
+        // salted, so the same password hashes differently
+        assert_ne!(hash, hash_password("correct horse").unwrap());
+    }
+}
@@ -0,0 +1,295 @@
+use crate::{ApiError, BatchRequest, FieldError, OperationRequest, S4Vector};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::error;
+
+/// Limits applied to the payloads of insert, update and delete requests.
+/// `max_node_bytes`: The largest value a single node may hold, in UTF-8 bytes.
+/// `max_batch_edits`: The most edits a batch may hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ValidationConfig {
+    pub max_node_bytes: usize,
+    pub max_batch_edits: usize,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            max_node_bytes: 4096,
+            max_batch_edits: 64,
+        }
+    }
+}
+
+/// The mutation an `OperationRequest` is validated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl OperationRequest {
+    /// Checks the request for `kind`, collecting every invalid field.
+    /// - Inserts and updates need a non-empty `value` of at most `max_node_bytes` without NUL
+    ///   characters (which PostgreSQL cannot store in `TEXT`).
+    /// - Updates and deletes need the `s4vector` of the node they target.
+    /// - Inserts must not reference the same node as both neighbours, and can't be tombstoned.
+    /// - Every s4vector must fit the database.
+    pub fn check(&self, kind: OperationKind, config: &ValidationConfig) -> Vec<FieldError> {
+        let mut errors: Vec<FieldError> = Vec::new();
+
+        if kind != OperationKind::Delete {
+            match &self.value {
+                None => errors.push(FieldError::new("value", "is required")),
+                Some(value) if value.is_empty() => {
+                    errors.push(FieldError::new("value", "must not be empty"))
+                }
+                Some(value) if value.len() > config.max_node_bytes => errors.push(FieldError::new(
+                    "value",
+                    &format!(
+                        "is {} bytes, the limit is {} bytes",
+                        value.len(),
+                        config.max_node_bytes
+                    ),
+                )),
+                Some(value) if value.contains('\0') => errors.push(FieldError::new(
+                    "value",
+                    "must be UTF-8 text without NUL characters",
+                )),
+                Some(_) => (),
+            }
+        }
+
+        if kind != OperationKind::Insert && self.s4vector.is_none() {
+            errors.push(FieldError::new("s4vector", "is required"));
+        }
+
+        if kind == OperationKind::Insert {
+            if self.left.is_some() && self.left == self.right {
+                errors.push(FieldError::new(
+                    "right",
+                    "must not be the same node as left",
+                ));
+            }
+            if self.tombstone {
+                errors.push(FieldError::new("tombstone", "must be false for an insert"));
+            }
+        }
+
+        for (field, s4) in [
+            ("s4vector", self.s4vector),
+            ("left", self.left),
+            ("right", self.right),
+        ] {
+            if let Some(Err(e)) = s4.map(|s4| s4.validate()) {
+                errors.push(FieldError::new(field, &e.to_string()));
+            }
+        }
+
+        errors
+    }
+}
+
+/// Validates an operation request at the API boundary, returning `422 Unprocessable Entity`
+/// with the field-level errors if it is invalid.
+pub fn validate_operation(
+    request: &OperationRequest,
+    kind: OperationKind,
+    config: &ValidationConfig,
+) -> Result<(), ApiError> {
+    let errors: Vec<FieldError> = request.check(kind, config);
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    error!(
+        "Rejected {:?} request with {} invalid fields",
+        kind,
+        errors.len()
+    );
+    Err(ApiError::ValidationFailed(errors))
+}
+
+/// Validates a batch of edits, returning `422 Unprocessable Entity` with the field-level errors
+/// of every edit if it is invalid. Each edit is checked like a single operation, and no node
+/// may be updated or deleted by more than one edit.
+pub fn validate_batch(request: &BatchRequest, config: &ValidationConfig) -> Result<(), ApiError> {
+    let mut errors: Vec<FieldError> = Vec::new();
+    if request.edits.is_empty() {
+        errors.push(FieldError::new("edits", "must not be empty"));
+    }
+    if request.edits.len() > config.max_batch_edits {
+        errors.push(FieldError::new(
+            "edits",
+            &format!(
+                "has {} edits, the limit is {}",
+                request.edits.len(),
+                config.max_batch_edits
+            ),
+        ));
+    }
+
+    let mut targets: HashSet<S4Vector> = HashSet::new();
+    for (i, edit) in request.edits.iter().enumerate() {
+        for error in edit.request.check(edit.operation, config) {
+            errors.push(FieldError::new(
+                &format!("edits[{}].{}", i, error.field),
+                &error.message,
+            ));
+        }
+        if edit.operation == OperationKind::Insert {
+            continue;
+        }
+        if let Some(s4vector) = edit.request.s4vector {
+            if !targets.insert(s4vector) {
+                errors.push(FieldError::new(
+                    &format!("edits[{}].s4vector", i),
+                    "is changed by another edit of the batch",
+                ));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+    error!(
+        "Rejected batch of {} edits with {} invalid fields",
+        request.edits.len(),
+        errors.len()
+    );
+    Err(ApiError::ValidationFailed(errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BatchEdit;
+
+    fn request(value: Option<&str>, s4vector: Option<S4Vector>) -> OperationRequest {
+        OperationRequest {
+            value: value.map(|v| v.to_string()),
+            s4vector,
+            tombstone: false,
+            left: None,
+            right: None,
+        }
+    }
+
+    fn fields(errors: Vec<FieldError>) -> Vec<String> {
+        errors.into_iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn test_check_operation() {
+        let config = ValidationConfig {
+            max_node_bytes: 4,
+            ..Default::default()
+        };
+        let s4 = S4Vector {
+            ssn: 1,
+            sum: 1,
+            sid: 1,
+            seq: 1,
+        };
+
+        assert!(request(Some("a"), None)
+            .check(OperationKind::Insert, &config)
+            .is_empty());
+        assert!(request(None, Some(s4))
+            .check(OperationKind::Delete, &config)
+            .is_empty());
+
+        assert_eq!(
+            fields(request(None, None).check(OperationKind::Update, &config)),
+            vec!["value", "s4vector"]
+        );
+        assert_eq!(
+            fields(request(Some(""), None).check(OperationKind::Insert, &config)),
+            vec!["value"]
+        );
+        assert_eq!(
+            fields(request(Some("abcde"), Some(s4)).check(OperationKind::Update, &config)),
+            vec!["value"]
+        );
+        assert_eq!(
+            fields(request(Some("a\0"), None).check(OperationKind::Insert, &config)),
+            vec!["value"]
+        );
+
+        let mut same_neighbours = request(Some("a"), None);
+        same_neighbours.left = Some(s4);
+        same_neighbours.right = Some(s4);
+        assert_eq!(
+            fields(same_neighbours.check(OperationKind::Insert, &config)),
+            vec!["right"]
+        );
+
+        let out_of_range = S4Vector {
+            sum: u64::MAX,
+            ..s4
+        };
+        assert_eq!(
+            fields(request(None, Some(out_of_range)).check(OperationKind::Delete, &config)),
+            vec!["s4vector"]
+        );
+    }
+
+    #[test]
+    fn test_validate_batch() {
+        let config = ValidationConfig {
+            max_node_bytes: 4,
+            max_batch_edits: 3,
+        };
+        let s4 = S4Vector {
+            ssn: 1,
+            sum: 1,
+            sid: 1,
+            seq: 1,
+        };
+        let edit = |operation: OperationKind, value: Option<&str>| BatchEdit {
+            operation,
+            request: request(value, Some(s4)),
+        };
+        let batch_fields =
+            |edits: Vec<BatchEdit>| match validate_batch(&BatchRequest { edits }, &config) {
+                Ok(()) => Vec::new(),
+                Err(ApiError::ValidationFailed(errors)) => fields(errors),
+                Err(e) => panic!("unexpected error {}", e),
+            };
+
+        assert!(batch_fields(vec![
+            edit(OperationKind::Insert, Some("a")),
+            edit(OperationKind::Insert, Some("b")),
+            edit(OperationKind::Update, Some("c")),
+        ])
+        .is_empty());
+        assert_eq!(batch_fields(Vec::new()), vec!["edits"]);
+        assert_eq!(
+            batch_fields(vec![
+                edit(OperationKind::Insert, Some("a")),
+                edit(OperationKind::Update, Some("abcde")),
+            ]),
+            vec!["edits[1].value"]
+        );
+        // a node may only be changed once
+        assert_eq!(
+            batch_fields(vec![
+                edit(OperationKind::Update, Some("a")),
+                edit(OperationKind::Delete, None),
+            ]),
+            vec!["edits[1].s4vector"]
+        );
+        assert_eq!(
+            batch_fields(
+                (0..4)
+                    .map(|_| edit(OperationKind::Insert, Some("a")))
+                    .collect()
+            ),
+            vec!["edits"]
+        );
+    }
+}
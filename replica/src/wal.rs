@@ -0,0 +1,589 @@
+//! Write-ahead log: operations kept on the replica's disk until the database has them.
+//!
+//! Insert, update and delete append each operation to the log and fsync it before writing it
+//! to the database. When the database write fails the request is still acknowledged, and the
+//! operation is written later from the log, including after a restart: the log is read on
+//! startup and the operations the database is missing are persisted before requests are
+//! served. Once every logged operation is in the database the log is truncated.
+
+use crate::audit::Actor;
+use crate::outbox::{self, Outbox};
+use crate::pool::Pool;
+use crate::tenancy::Tenant;
+use crate::{encryption, history, ApiError, BroadcastOperation};
+use rocket::fairing::AdHoc;
+use rocket::tokio::fs::{self, File, OpenOptions};
+use rocket::tokio::io::AsyncWriteExt;
+use rocket::tokio::sync::Mutex;
+use rocket::tokio::{self, time};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_postgres::Client;
+use tracing::{error, info, instrument, warn};
+
+/// `enabled`: Whether operations are logged before they are written to the database.
+/// `path`: The file the log is kept in.
+/// `interval_secs`: How often operations the database rejected are written again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WalConfig {
+    pub enabled: bool,
+    pub path: String,
+    pub interval_secs: u64,
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        WalConfig {
+            enabled: true,
+            path: "nimble.wal".to_string(),
+            interval_secs: 5,
+        }
+    }
+}
+
+/// An operation as the route writes it to the database.
+/// `operation`: The operation, broadcast once it is persisted.
+/// `value`: The node's value as stored (sealed when encryption is enabled).
+/// `tombstone`: Whether the node is deleted.
+/// `authored_at`: When the node was authored, None if the operation keeps the node's author.
+/// `client_ip`: The address of the client that made the operation, for the audit log.
+/// `timestamp`: When the operation was applied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalRecord {
+    pub operation: BroadcastOperation,
+    pub value: String,
+    pub tombstone: bool,
+    pub authored_at: Option<String>,
+    pub client_ip: Option<String>,
+    pub timestamp: String,
+}
+
+/// What replaying the log did.
+/// `persisted`: Operations written to the database.
+/// `skipped`: Operations the database already had, their transaction committed before a crash.
+/// `dropped`: Records that could not be read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Replay {
+    pub persisted: usize,
+    pub skipped: usize,
+    pub dropped: usize,
+}
+
+/// A line of the log file. The record is sealed like the rows it becomes.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    lsn: u64,
+    record: String,
+}
+
+/// A logged record the database doesn't have yet.
+/// `deferred`: Whether its request gave up on the database, so the log has to write it.
+#[derive(Debug)]
+struct Pending {
+    record: String,
+    deferred: bool,
+}
+
+/// `len`: The length of the records appended in full, a failed append is cut back to it.
+#[derive(Debug)]
+struct Log {
+    file: File,
+    len: u64,
+    next_lsn: u64,
+    pending: BTreeMap<u64, Pending>,
+}
+
+/// The replica's write-ahead log, shared by every clone. Disabled logs keep nothing.
+#[derive(Debug, Clone, Default)]
+pub struct Wal {
+    log: Option<Arc<Mutex<Log>>>,
+}
+
+impl Wal {
+    /// Opens the log, keeping the records found in it to be written to the database.
+    /// A record cut short by a crash while it was appended was never acknowledged and is
+    /// dropped.
+    pub async fn open(config: &WalConfig) -> Result<Self, ApiError> {
+        if !config.enabled {
+            return Ok(Wal::default());
+        }
+
+        let contents: Vec<u8> = match fs::read(&config.path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                error!("Failed to read the write-ahead log: {}", e);
+                return Err(ApiError::InternalServerError(
+                    "Failed to read the write-ahead log".to_string(),
+                ));
+            }
+        };
+
+        let mut pending: BTreeMap<u64, Pending> = BTreeMap::new();
+        let mut valid: usize = 0;
+        for line in contents.split_inclusive(|byte| *byte == b'\n') {
+            let entry: Entry = match serde_json::from_slice(line) {
+                Ok(entry) if line.ends_with(b"\n") => entry,
+                _ => {
+                    warn!("Dropping an incomplete record at the end of the write-ahead log");
+                    break;
+                }
+            };
+            pending.insert(
+                entry.lsn,
+                Pending {
+                    record: entry.record,
+                    deferred: true,
+                },
+            );
+            valid += line.len();
+        }
+
+        let file: File = match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .await
+        {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Failed to open the write-ahead log: {}", e);
+                return Err(ApiError::InternalServerError(
+                    "Failed to open the write-ahead log".to_string(),
+                ));
+            }
+        };
+        if valid < contents.len() && file.set_len(valid as u64).await.is_err() {
+            error!("Failed to truncate the write-ahead log");
+            return Err(ApiError::InternalServerError(
+                "Failed to truncate the write-ahead log".to_string(),
+            ));
+        }
+
+        if !pending.is_empty() {
+            info!(
+                "Recovered {} operations from the write-ahead log",
+                pending.len()
+            );
+        }
+        Ok(Wal {
+            log: Some(Arc::new(Mutex::new(Log {
+                file,
+                len: valid as u64,
+                next_lsn: pending.keys().next_back().map_or(1, |lsn| lsn + 1),
+                pending,
+            }))),
+        })
+    }
+
+    /// Appends a record and waits for it to reach the disk. Returns its log sequence number,
+    /// None if the log is disabled.
+    #[instrument(name = "wal.append", skip_all)]
+    pub async fn append(&self, record: &WalRecord) -> Result<Option<u64>, ApiError> {
+        let lsns: Vec<Option<u64>> = self.append_all(std::slice::from_ref(record)).await?;
+        Ok(lsns.into_iter().next().flatten())
+    }
+
+    /// Appends the records of a request in one write and waits for them to reach the disk.
+    /// Either every record is appended or none is. Returns their log sequence numbers, None
+    /// if the log is disabled.
+    #[instrument(name = "wal.append_all", skip_all, fields(records = records.len()))]
+    pub async fn append_all(&self, records: &[WalRecord]) -> Result<Vec<Option<u64>>, ApiError> {
+        let Some(log) = &self.log else {
+            return Ok(vec![None; records.len()]);
+        };
+        let mut sealed: Vec<String> = Vec::with_capacity(records.len());
+        for record in records {
+            match serde_json::to_string(record) {
+                Ok(record) => sealed.push(encryption::seal(&record)),
+                Err(_) => {
+                    error!("Failed to serialize operation");
+                    return Err(ApiError::InternalServerError(
+                        "Failed to serialize operation".to_string(),
+                    ));
+                }
+            }
+        }
+
+        let mut log = log.lock().await;
+        let first: u64 = log.next_lsn;
+        let mut lines: String = String::new();
+        for (lsn, record) in (first..).zip(&sealed) {
+            lines.push_str(
+                &serde_json::to_string(&Entry {
+                    lsn,
+                    record: record.clone(),
+                })
+                .unwrap_or_default(),
+            );
+            lines.push('\n');
+        }
+
+        let written = async {
+            log.file.write_all(lines.as_bytes()).await?;
+            log.file.flush().await?;
+            log.file.sync_data().await
+        }
+        .await;
+        if let Err(e) = written {
+            error!("Failed to write to the write-ahead log: {}", e);
+            let len: u64 = log.len;
+            let _ = log.file.set_len(len).await;
+            return Err(ApiError::InternalServerError(
+                "Failed to write to the write-ahead log".to_string(),
+            ));
+        }
+
+        log.len += lines.len() as u64;
+        let mut lsns: Vec<Option<u64>> = Vec::with_capacity(sealed.len());
+        for record in sealed {
+            let lsn: u64 = log.next_lsn;
+            log.next_lsn += 1;
+            log.pending.insert(
+                lsn,
+                Pending {
+                    record,
+                    deferred: false,
+                },
+            );
+            lsns.push(Some(lsn));
+        }
+        Ok(lsns)
+    }
+
+    /// Settles a record with the outcome of writing it to the database. Failed writes are
+    /// left to the log and not reported, unless the operation was not logged.
+    pub async fn settle(
+        &self,
+        lsn: Option<u64>,
+        persisted: Result<(), ApiError>,
+    ) -> Result<(), ApiError> {
+        let (Some(log), Some(lsn)) = (&self.log, lsn) else {
+            return persisted;
+        };
+
+        let mut log = log.lock().await;
+        match persisted {
+            Ok(()) => {
+                log.pending.remove(&lsn);
+            }
+            Err(e) => {
+                warn!(
+                    lsn,
+                    "Failed to persist an operation, writing it from the write-ahead log: {}", e
+                );
+                if let Some(pending) = log.pending.get_mut(&lsn) {
+                    pending.deferred = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of logged operations the database doesn't have yet.
+    pub async fn pending(&self) -> usize {
+        match &self.log {
+            Some(log) => log.lock().await.pending.len(),
+            None => 0,
+        }
+    }
+
+    /// Writes the deferred records to the database in the order they were logged, stopping
+    /// at the first failure. Counts what was done in `replay`.
+    #[instrument(name = "wal.replay", skip_all)]
+    pub async fn replay(&self, pool: &Pool, replay: &mut Replay) -> Result<(), ApiError> {
+        let Some(log) = &self.log else {
+            return Ok(());
+        };
+        let deferred: Vec<(u64, String)> = log
+            .lock()
+            .await
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deferred)
+            .map(|(lsn, pending)| (*lsn, pending.record.clone()))
+            .collect();
+        if deferred.is_empty() {
+            return Ok(());
+        }
+
+        let mut client = pool.get(&Tenant::Any).await?;
+        for (lsn, record) in deferred {
+            let record: String = encryption::open(&client, record).await?;
+            match serde_json::from_str::<WalRecord>(&record) {
+                Ok(record) if persist(&mut client, &record).await? => replay.persisted += 1,
+                Ok(_) => replay.skipped += 1,
+                Err(_) => {
+                    error!(
+                        lsn,
+                        "Dropping a write-ahead log record that is not an operation"
+                    );
+                    replay.dropped += 1;
+                }
+            }
+            log.lock().await.pending.remove(&lsn);
+        }
+        Ok(())
+    }
+
+    /// Empties the log once the database has every record in it.
+    pub async fn checkpoint(&self) -> Result<(), ApiError> {
+        let Some(log) = &self.log else {
+            return Ok(());
+        };
+        let mut log = log.lock().await;
+        if !log.pending.is_empty() || log.len == 0 {
+            return Ok(());
+        }
+        let truncated = async {
+            log.file.set_len(0).await?;
+            log.file.sync_all().await
+        }
+        .await;
+        if let Err(e) = truncated {
+            error!("Failed to truncate the write-ahead log: {}", e);
+            return Err(ApiError::InternalServerError(
+                "Failed to truncate the write-ahead log".to_string(),
+            ));
+        }
+        log.len = 0;
+        Ok(())
+    }
+}
+
+/// Writes a logged operation to the database in one transaction, as its route would have,
+/// unless the route's transaction committed before the replica stopped. Returns whether it
+/// was written.
+async fn persist(client: &mut Client, record: &WalRecord) -> Result<bool, ApiError> {
+    let operation: &BroadcastOperation = &record.operation;
+    let tx = match client.transaction().await {
+        Ok(tx) => tx,
+        Err(_) => {
+            error!("Failed to create database transaction");
+            return Err(ApiError::DatabaseError(
+                "Failed to create database transaction".to_string(),
+            ));
+        }
+    };
+
+    let logged = match tx
+        .query_opt(
+            "SELECT 1 FROM operations WHERE document_id=$1 AND ssn=$2 AND sum=$3 AND sid=$4 AND seq=$5 AND timestamp=$6",
+            &[&operation.document_id, &operation.ssn, &operation.sum, &operation.sid, &operation.seq, &record.timestamp],
+        )
+        .await
+    {
+        Ok(row) => row.is_some(),
+        Err(_) => {
+            error!("Failed to query operations table");
+            return Err(ApiError::DatabaseError(
+                "Failed to query operations table".to_string(),
+            ));
+        }
+    };
+    if logged {
+        return Ok(false);
+    }
+
+    if tx
+        .execute(
+            "INSERT INTO operations (document_id,ssn,sum,sid,seq,value,tombstone,timestamp,operation,left_origin,right_origin,nodes) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12)",
+            &[&operation.document_id, &operation.ssn, &operation.sum, &operation.sid, &operation.seq, &record.value, &record.tombstone, &record.timestamp, &operation.operation, &history::origin_column(operation.left)?, &history::origin_column(operation.right)?, &history::nodes_column(&operation.nodes)?],
+        )
+        .await
+        .is_err()
+    {
+        error!("Failed to insert into operations table");
+        return Err(ApiError::DatabaseError(
+            "Failed to insert into operations table".to_string(),
+        ));
+    }
+
+    // a move changes no node's row
+    if operation.operation != "Move"
+        && tx
+            .execute(
+                "INSERT INTO document_snapshots (document_id,ssn,sum,sid,seq,value,tombstone,author,authored_at) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9) \
+                 ON CONFLICT (document_id,ssn,sum,sid,seq) DO UPDATE SET value = EXCLUDED.value, tombstone = EXCLUDED.tombstone, \
+                 author = CASE WHEN EXCLUDED.authored_at IS NULL THEN document_snapshots.author ELSE EXCLUDED.author END, \
+                 authored_at = COALESCE(EXCLUDED.authored_at, document_snapshots.authored_at)",
+                &[&operation.document_id, &operation.ssn, &operation.sum, &operation.sid, &operation.seq, &record.value, &record.tombstone, &record.authored_at.as_ref().and(operation.author), &record.authored_at],
+            )
+            .await
+            .is_err()
+    {
+        error!("Failed to insert into document_snapshot table");
+        return Err(ApiError::DatabaseError(
+            "Failed to insert into document_snapshot table".to_string(),
+        ));
+    }
+
+    let actor = Actor {
+        user_id: operation.author,
+        client_ip: record.client_ip.clone(),
+        share: None,
+    };
+    crate::audit::record(&tx, &actor, operation, &record.timestamp).await?;
+    outbox::enqueue(&tx, operation).await?;
+
+    if tx.commit().await.is_err() {
+        error!("Failed to commit database transaction");
+        return Err(ApiError::DatabaseError(
+            "Failed to commit database transaction".to_string(),
+        ));
+    }
+    Ok(true)
+}
+
+/// Fairing writing operations the database rejected from the log every
+/// `wal.interval_secs`, and truncating the log once the database has all of them.
+pub fn attach_wal_checkpoint(config: WalConfig) -> AdHoc {
+    AdHoc::on_liftoff("Write-ahead log checkpoint", move |rocket| {
+        Box::pin(async move {
+            if !config.enabled {
+                return;
+            }
+            let (wal, pool, outbox) = match (
+                rocket.state::<Wal>(),
+                rocket.state::<Pool>(),
+                rocket.state::<Outbox>(),
+            ) {
+                (Some(wal), Some(pool), Some(outbox)) => {
+                    (wal.clone(), pool.clone(), outbox.clone())
+                }
+                _ => {
+                    warn!("Replica state is unavailable, the write-ahead log will not be checkpointed");
+                    return;
+                }
+            };
+            let mut shutdown = rocket.shutdown();
+
+            tokio::spawn(async move {
+                let mut interval = time::interval(Duration::from_secs(config.interval_secs));
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = &mut shutdown => break,
+                    }
+                    let mut replay = Replay::default();
+                    match wal.replay(&pool, &mut replay).await {
+                        Ok(()) if replay.persisted == 0 => {}
+                        Ok(()) => {
+                            info!(
+                                "Persisted {} operations from the write-ahead log",
+                                replay.persisted
+                            );
+                            outbox.wake();
+                        }
+                        Err(e) => {
+                            warn!(
+                                "Failed to persist the write-ahead log, retrying later: {}",
+                                e
+                            );
+                            continue;
+                        }
+                    }
+                    if let Err(e) = wal.checkpoint().await {
+                        warn!("Failed to checkpoint the write-ahead log: {}", e);
+                    }
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn record(seq: i64) -> WalRecord {
+        WalRecord {
+            operation: BroadcastOperation {
+                operation: "Insert".to_string(),
+                document_id: Uuid::nil(),
+                ssn: 1,
+                sum: seq,
+                sid: 1,
+                seq,
+                value: Some("a".to_string()),
+                left: None,
+                right: None,
+                nodes: Vec::new(),
+                request_id: None,
+                author: None,
+                origin_region: None,
+                sent_at: None,
+            },
+            value: "a".to_string(),
+            tombstone: false,
+            authored_at: None,
+            client_ip: None,
+            timestamp: "2025-01-04T10:00:00Z".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recovers_unpersisted_operations() {
+        let config = WalConfig {
+            path: std::env::temp_dir()
+                .join(format!("nimble-{}.wal", Uuid::new_v4()))
+                .to_string_lossy()
+                .into_owned(),
+            ..WalConfig::default()
+        };
+
+        let wal = Wal::open(&config).await.unwrap();
+        let persisted = wal.append(&record(1)).await.unwrap();
+        let failed = wal.append(&record(2)).await.unwrap();
+        let in_flight = wal.append(&record(3)).await.unwrap();
+        wal.settle(persisted, Ok(())).await.unwrap();
+        let error = ApiError::DatabaseError("down".to_string());
+        assert!(wal.settle(failed, Err(error.clone())).await.is_ok());
+        assert!(Wal::default().settle(None, Err(error)).await.is_err());
+        assert_eq!(wal.pending().await, 2);
+
+        // the replica stops while appending, the last record is cut short
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&config.path)
+            .await
+            .unwrap();
+        file.write_all(b"{\"lsn\":4,\"rec").await.unwrap();
+        drop(file);
+
+        // every record is kept after a restart, the persisted one is written again only if
+        // the database doesn't have it
+        let wal = Wal::open(&config).await.unwrap();
+        assert_eq!(wal.pending().await, 3);
+        let log = wal.log.as_ref().unwrap().lock().await;
+        assert!(log.pending.values().all(|pending| pending.deferred));
+        assert!(log.pending.contains_key(&in_flight.unwrap()));
+        assert_eq!(log.next_lsn, 4);
+        drop(log);
+        assert_eq!(wal.append(&record(4)).await.unwrap(), Some(4));
+        assert_eq!(
+            wal.append_all(&[record(5), record(6)]).await.unwrap(),
+            vec![Some(5), Some(6)]
+        );
+        assert_eq!(
+            Wal::default().append_all(&[record(7)]).await.unwrap(),
+            vec![None]
+        );
+
+        // the log is only truncated once the database has every record
+        wal.checkpoint().await.unwrap();
+        assert!(fs::metadata(&config.path).await.unwrap().len() > 0);
+        for lsn in 1..=6 {
+            wal.settle(Some(lsn), Ok(())).await.unwrap();
+        }
+        wal.checkpoint().await.unwrap();
+        assert_eq!(fs::metadata(&config.path).await.unwrap().len(), 0);
+        assert_eq!(Wal::open(&config).await.unwrap().pending().await, 0);
+
+        fs::remove_file(&config.path).await.unwrap();
+    }
+}
@@ -0,0 +1,1358 @@
+//! An adapter for the Yjs sync protocol, so editors bound to a `Y.Doc` can edit documents.
+//!
+//! A loaded document is exposed as a single `Y.Text` named `content`. Every RGA node is made
+//! of one or more Yjs items: nodes written through the adapter keep the ids of the items the
+//! client sent, and nodes written any other way are given items by the replica under its own
+//! Yjs client id. Text inserted in the middle of a node updates that node, text inserted at
+//! its end becomes a new node, and deleted ranges update or delete the nodes they cover.
+//!
+//! The mapping is kept in memory by each replica, so after a restart clients must sync from a
+//! new `Y.Doc`. Item ids are not stored in the database or broadcast to other replicas.
+
+use crate::pool::Pool;
+use crate::changes::ChangeFeeds;
+use crate::flags::{Feature, Features};
+use crate::outbox::Outbox;
+use crate::rga::rga::{Node, OperationError, RGA};
+use crate::routes::{DocumentLocks, SharedRGAs};
+use crate::share::Access;
+use crate::tenancy::Tenant;
+use crate::throttle::Throttle;
+use crate::wal::Wal;
+use crate::{
+    db, Actor, ApiError, BroadcastOperation, FieldError, RequestId, S4Vector, ValidationConfig,
+};
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::ContentType;
+use rocket::post;
+use rocket::tokio::sync::{Mutex, OwnedMutexGuard};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// The name of the `Y.Text` holding a document's content.
+pub const TEXT_NAME: &str = "content";
+
+/// The largest request body accepted by the sync endpoint.
+pub const MAX_MESSAGE_BYTES: u64 = 1024 * 1024;
+
+/// Items created by the replica use the client id `SERVER_CLIENT_BASE + site_id`, above the
+/// 32-bit ids Yjs clients pick for themselves.
+pub const SERVER_CLIENT_BASE: u64 = 1 << 40;
+
+const MESSAGE_SYNC: u64 = 0;
+const MESSAGE_AWARENESS: u64 = 1;
+const SYNC_STEP1: u64 = 0;
+const SYNC_STEP2: u64 = 1;
+const SYNC_UPDATE: u64 = 2;
+
+const CONTENT_GC: u8 = 0;
+const CONTENT_DELETED: u8 = 1;
+const CONTENT_STRING: u8 = 4;
+const CONTENT_SKIP: u8 = 10;
+
+const HAS_ORIGIN: u8 = 0x80;
+const HAS_RIGHT_ORIGIN: u8 = 0x40;
+const HAS_PARENT_SUB: u8 = 0x20;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum YjsError {
+    #[error("Yjs message ended unexpectedly")]
+    UnexpectedEnd,
+    #[error("Yjs message has an integer longer than 64 bits")]
+    IntegerOverflow,
+    #[error("Yjs message has a string that is not UTF-8")]
+    InvalidString,
+    #[error("Unsupported Yjs message: {0}")]
+    Unsupported(String),
+}
+
+/// Reads the lib0 encoding used by Yjs.
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Decoder { bytes, position: 0 }
+    }
+
+    pub fn has_content(&self) -> bool {
+        self.position < self.bytes.len()
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, YjsError> {
+        let byte: u8 = *self
+            .bytes
+            .get(self.position)
+            .ok_or(YjsError::UnexpectedEnd)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    pub fn read_var_uint(&mut self) -> Result<u64, YjsError> {
+        let (mut value, mut shift) = (0_u64, 0_u32);
+        loop {
+            let byte: u8 = self.read_u8()?;
+            if shift > 63 || (shift == 63 && byte & 0x7f > 1) {
+                return Err(YjsError::IntegerOverflow);
+            }
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    pub fn read_buf(&mut self) -> Result<&'a [u8], YjsError> {
+        let len: usize =
+            usize::try_from(self.read_var_uint()?).map_err(|_| YjsError::UnexpectedEnd)?;
+        let end: usize = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(YjsError::UnexpectedEnd)?;
+        let buf: &'a [u8] = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(buf)
+    }
+
+    pub fn read_var_string(&mut self) -> Result<String, YjsError> {
+        String::from_utf8(self.read_buf()?.to_vec()).map_err(|_| YjsError::InvalidString)
+    }
+
+    fn read_id(&mut self) -> Result<YId, YjsError> {
+        Ok(YId {
+            client: self.read_var_uint()?,
+            clock: self.read_var_uint()?,
+        })
+    }
+}
+
+/// Writes the lib0 encoding used by Yjs.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn write_u8(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    pub fn write_var_uint(&mut self, mut value: u64) {
+        while value > 0x7f {
+            self.bytes.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+
+    pub fn write_buf(&mut self, buf: &[u8]) {
+        self.write_var_uint(buf.len() as u64);
+        self.bytes.extend_from_slice(buf);
+    }
+
+    pub fn write_var_string(&mut self, value: &str) {
+        self.write_buf(value.as_bytes());
+    }
+
+    fn write_id(&mut self, id: YId) {
+        self.write_var_uint(id.client);
+        self.write_var_uint(id.clock);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Yjs measures text in UTF-16 code units.
+fn utf16_len(text: &str) -> u64 {
+    text.encode_utf16().count() as u64
+}
+
+/// Splits text after `at` UTF-16 code units.
+fn split_utf16(text: &str, at: u64) -> (String, String) {
+    let units: Vec<u16> = text.encode_utf16().collect();
+    let at: usize = usize::try_from(at).map_or(units.len(), |at| at.min(units.len()));
+    (
+        String::from_utf16_lossy(&units[..at]),
+        String::from_utf16_lossy(&units[at..]),
+    )
+}
+
+/// The id of a Yjs item's unit: the client that created it and its clock on that client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct YId {
+    pub client: u64,
+    pub clock: u64,
+}
+
+/// The content of an item, either text or the length of deleted content.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Content {
+    String(String),
+    Deleted(u64),
+}
+
+/// A Yjs item.
+/// `id`: The id of the item's first unit.
+/// `origin`: The unit the item was inserted after (None at the start of the text).
+/// `right_origin`: The unit the item was inserted before (None at the end of the text).
+/// `parent`: The name of the item's root type, only sent when it has neither origin.
+/// `content`: The item's content.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub id: YId,
+    pub origin: Option<YId>,
+    pub right_origin: Option<YId>,
+    pub parent: Option<String>,
+    pub content: Content,
+}
+
+impl Item {
+    fn len(&self) -> u64 {
+        match &self.content {
+            Content::String(text) => utf16_len(text),
+            Content::Deleted(len) => *len,
+        }
+    }
+
+    /// The clock after the item's last unit.
+    fn end(&self) -> u64 {
+        self.id.clock.saturating_add(self.len())
+    }
+
+    fn contains(&self, id: YId) -> bool {
+        id.client == self.id.client && id.clock >= self.id.clock && id.clock < self.end()
+    }
+
+    /// Garbage collected content is sent without origins or parent.
+    fn is_gc(&self) -> bool {
+        matches!(self.content, Content::Deleted(_))
+            && self.origin.is_none()
+            && self.right_origin.is_none()
+            && self.parent.is_none()
+    }
+
+    /// The item without its first `offset` units, as Yjs splits items.
+    fn slice(&self, offset: u64) -> Item {
+        if offset == 0 {
+            return self.clone();
+        }
+        let content: Content = match &self.content {
+            Content::String(text) => Content::String(split_utf16(text, offset).1),
+            Content::Deleted(len) => Content::Deleted(len - offset),
+        };
+        let id = YId {
+            client: self.id.client,
+            clock: self.id.clock + offset,
+        };
+
+        if self.is_gc() {
+            return Item {
+                id,
+                origin: None,
+                right_origin: None,
+                parent: None,
+                content,
+            };
+        }
+        Item {
+            id,
+            origin: Some(YId {
+                client: id.client,
+                clock: id.clock - 1,
+            }),
+            right_origin: self.right_origin,
+            parent: None,
+            content,
+        }
+    }
+
+    fn encode(&self, encoder: &mut Encoder) {
+        if self.is_gc() {
+            encoder.write_u8(CONTENT_GC);
+            encoder.write_var_uint(self.len());
+            return;
+        }
+
+        let mut info: u8 = match self.content {
+            Content::String(_) => CONTENT_STRING,
+            Content::Deleted(_) => CONTENT_DELETED,
+        };
+        if self.origin.is_some() {
+            info |= HAS_ORIGIN;
+        }
+        if self.right_origin.is_some() {
+            info |= HAS_RIGHT_ORIGIN;
+        }
+        encoder.write_u8(info);
+
+        if let Some(origin) = self.origin {
+            encoder.write_id(origin);
+        }
+        if let Some(right_origin) = self.right_origin {
+            encoder.write_id(right_origin);
+        }
+        if self.origin.is_none() && self.right_origin.is_none() {
+            // the parent is a root type, identified by name
+            encoder.write_var_uint(1);
+            encoder.write_var_string(self.parent.as_deref().unwrap_or(TEXT_NAME));
+        }
+
+        match &self.content {
+            Content::String(text) => encoder.write_var_string(text),
+            Content::Deleted(len) => encoder.write_var_uint(*len),
+        }
+    }
+
+    fn decode(decoder: &mut Decoder, id: YId) -> Result<Item, YjsError> {
+        let info: u8 = decoder.read_u8()?;
+        match info & 0x1f {
+            CONTENT_GC => {
+                return Ok(Item {
+                    id,
+                    origin: None,
+                    right_origin: None,
+                    parent: None,
+                    content: Content::Deleted(decoder.read_var_uint()?),
+                })
+            }
+            CONTENT_SKIP => return Err(YjsError::Unsupported("skipped ranges".to_string())),
+            _ => (),
+        }
+
+        let origin: Option<YId> = match info & HAS_ORIGIN {
+            0 => None,
+            _ => Some(decoder.read_id()?),
+        };
+        let right_origin: Option<YId> = match info & HAS_RIGHT_ORIGIN {
+            0 => None,
+            _ => Some(decoder.read_id()?),
+        };
+        let parent: Option<String> = if origin.is_none() && right_origin.is_none() {
+            if decoder.read_var_uint()? != 1 {
+                return Err(YjsError::Unsupported("nested types".to_string()));
+            }
+            Some(decoder.read_var_string()?)
+        } else {
+            None
+        };
+        if info & HAS_PARENT_SUB != 0 {
+            return Err(YjsError::Unsupported("map entries".to_string()));
+        }
+
+        let content: Content = match info & 0x1f {
+            CONTENT_DELETED => Content::Deleted(decoder.read_var_uint()?),
+            CONTENT_STRING => Content::String(decoder.read_var_string()?),
+            other => return Err(YjsError::Unsupported(format!("content type {}", other))),
+        };
+
+        let item = Item {
+            id,
+            origin,
+            right_origin,
+            parent,
+            content,
+        };
+        if item.len() == 0 {
+            return Err(YjsError::Unsupported("empty items".to_string()));
+        }
+        Ok(item)
+    }
+}
+
+/// Sorts ranges of (clock, len) and merges the ones that overlap or touch.
+fn merge_ranges(ranges: &mut Vec<(u64, u64)>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for &(clock, len) in ranges.iter() {
+        match merged.last_mut() {
+            Some((start, merged_len)) if clock <= start.saturating_add(*merged_len) => {
+                let end: u64 = clock
+                    .saturating_add(len)
+                    .max(start.saturating_add(*merged_len));
+                *merged_len = end - *start;
+            }
+            _ => merged.push((clock, len)),
+        }
+    }
+    *ranges = merged;
+}
+
+/// A Yjs update: items and the ranges of ids deleted, by client.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Update {
+    pub items: Vec<Item>,
+    pub deletes: BTreeMap<u64, Vec<(u64, u64)>>,
+}
+
+impl Update {
+    /// Encodes the update in the v1 update format. Each client's items must be contiguous.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+
+        let mut clients: BTreeMap<u64, Vec<&Item>> = BTreeMap::new();
+        for item in &self.items {
+            clients.entry(item.id.client).or_default().push(item);
+        }
+        encoder.write_var_uint(clients.len() as u64);
+        for (client, mut items) in clients.into_iter().rev() {
+            items.sort_by_key(|item| item.id.clock);
+            encoder.write_var_uint(items.len() as u64);
+            encoder.write_var_uint(client);
+            encoder.write_var_uint(items[0].id.clock);
+            for item in items {
+                item.encode(&mut encoder);
+            }
+        }
+
+        encoder.write_var_uint(self.deletes.len() as u64);
+        for (client, ranges) in &self.deletes {
+            encoder.write_var_uint(*client);
+            encoder.write_var_uint(ranges.len() as u64);
+            for (clock, len) in ranges {
+                encoder.write_var_uint(*clock);
+                encoder.write_var_uint(*len);
+            }
+        }
+        encoder.into_bytes()
+    }
+
+    /// Decodes an update in the v1 update format. Only text content is supported.
+    pub fn decode(bytes: &[u8]) -> Result<Update, YjsError> {
+        let mut decoder = Decoder::new(bytes);
+        let mut update = Update::default();
+
+        for _ in 0..decoder.read_var_uint()? {
+            let structs: u64 = decoder.read_var_uint()?;
+            let client: u64 = decoder.read_var_uint()?;
+            let mut clock: u64 = decoder.read_var_uint()?;
+            for _ in 0..structs {
+                let item: Item = Item::decode(&mut decoder, YId { client, clock })?;
+                clock = item.end();
+                update.items.push(item);
+            }
+        }
+
+        for _ in 0..decoder.read_var_uint()? {
+            let client: u64 = decoder.read_var_uint()?;
+            let ranges: &mut Vec<(u64, u64)> = update.deletes.entry(client).or_default();
+            for _ in 0..decoder.read_var_uint()? {
+                ranges.push((decoder.read_var_uint()?, decoder.read_var_uint()?));
+            }
+            merge_ranges(ranges);
+        }
+        Ok(update)
+    }
+}
+
+pub fn encode_state_vector(state: &BTreeMap<u64, u64>) -> Vec<u8> {
+    let mut encoder = Encoder::default();
+    encoder.write_var_uint(state.len() as u64);
+    for (client, clock) in state {
+        encoder.write_var_uint(*client);
+        encoder.write_var_uint(*clock);
+    }
+    encoder.into_bytes()
+}
+
+pub fn decode_state_vector(bytes: &[u8]) -> Result<BTreeMap<u64, u64>, YjsError> {
+    let mut decoder = Decoder::new(bytes);
+    let mut state: BTreeMap<u64, u64> = BTreeMap::new();
+    for _ in 0..decoder.read_var_uint()? {
+        state.insert(decoder.read_var_uint()?, decoder.read_var_uint()?);
+    }
+    Ok(state)
+}
+
+/// A message of the y-protocols sync and awareness protocols.
+/// `SyncStep1`: The sender's state vector, answered with the updates it is missing.
+/// `SyncStep2`: The updates the receiver is missing.
+/// `Update`: A change made by the sender.
+/// `Awareness`: Presence information, ignored by the replica.
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    SyncStep1(BTreeMap<u64, u64>),
+    SyncStep2(Update),
+    Update(Update),
+    Awareness,
+}
+
+impl Message {
+    /// Decodes every message in a request body.
+    pub fn decode_all(bytes: &[u8]) -> Result<Vec<Message>, YjsError> {
+        let mut decoder = Decoder::new(bytes);
+        let mut messages: Vec<Message> = Vec::new();
+
+        while decoder.has_content() {
+            let message: Message = match decoder.read_var_uint()? {
+                MESSAGE_SYNC => match decoder.read_var_uint()? {
+                    SYNC_STEP1 => Message::SyncStep1(decode_state_vector(decoder.read_buf()?)?),
+                    SYNC_STEP2 => Message::SyncStep2(Update::decode(decoder.read_buf()?)?),
+                    SYNC_UPDATE => Message::Update(Update::decode(decoder.read_buf()?)?),
+                    other => {
+                        return Err(YjsError::Unsupported(format!(
+                            "sync message type {}",
+                            other
+                        )))
+                    }
+                },
+                MESSAGE_AWARENESS => {
+                    decoder.read_buf()?;
+                    Message::Awareness
+                }
+                other => return Err(YjsError::Unsupported(format!("message type {}", other))),
+            };
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    pub fn encode(&self, encoder: &mut Encoder) {
+        match self {
+            Message::SyncStep1(state) => {
+                encoder.write_var_uint(MESSAGE_SYNC);
+                encoder.write_var_uint(SYNC_STEP1);
+                encoder.write_buf(&encode_state_vector(state));
+            }
+            Message::SyncStep2(update) => {
+                encoder.write_var_uint(MESSAGE_SYNC);
+                encoder.write_var_uint(SYNC_STEP2);
+                encoder.write_buf(&update.encode());
+            }
+            Message::Update(update) => {
+                encoder.write_var_uint(MESSAGE_SYNC);
+                encoder.write_var_uint(SYNC_UPDATE);
+                encoder.write_buf(&update.encode());
+            }
+            Message::Awareness => {
+                // an awareness update for no clients
+                encoder.write_var_uint(MESSAGE_AWARENESS);
+                encoder.write_buf(&[0]);
+            }
+        }
+    }
+}
+
+/// A contiguous run of a Yjs item's units that is part of an RGA node.
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    id: YId,
+    text: String,
+    deleted: bool,
+}
+
+impl Segment {
+    fn len(&self) -> u64 {
+        utf16_len(&self.text)
+    }
+
+    fn last(&self) -> YId {
+        YId {
+            client: self.id.client,
+            clock: self.id.clock + self.len() - 1,
+        }
+    }
+
+    fn contains(&self, id: YId) -> bool {
+        id.client == self.id.client && id.clock >= self.id.clock && id.clock <= self.last().clock
+    }
+}
+
+fn operation_error(e: OperationError) -> ApiError {
+    error!("Failed to apply Yjs update: {}", e);
+    ApiError::Conflict("Operation depends on a node that has not been applied".to_string())
+}
+
+fn check_node_size(bytes: usize, max_node_bytes: usize) -> Result<(), ApiError> {
+    if bytes > max_node_bytes {
+        error!("Rejected Yjs item that makes a node too large");
+        return Err(ApiError::ValidationFailed(vec![FieldError::new(
+            "value",
+            &format!("is {} bytes, the limit is {} bytes", bytes, max_node_bytes),
+        )]));
+    }
+    Ok(())
+}
+
+/// The Yjs view of a loaded document on this replica.
+/// `client`: The client id of items created by the replica.
+/// `items`: Every item integrated, by client in clock order.
+/// `deletes`: Every deleted range, by client.
+/// `segments`: The units each RGA node is made of, in document order.
+/// `owners`: The node holding each item's text, keyed by the item's first id.
+#[derive(Debug)]
+pub struct YjsDocument {
+    client: u64,
+    items: BTreeMap<u64, Vec<Item>>,
+    deletes: BTreeMap<u64, Vec<(u64, u64)>>,
+    segments: HashMap<S4Vector, Vec<Segment>>,
+    owners: BTreeMap<YId, (u64, S4Vector)>,
+}
+
+/// The result of applying a Yjs update to an RGA.
+/// `operations`: The operations applied to the RGA, to be persisted and broadcast.
+/// `error`: Why the rest of the update was not applied (None if it all was).
+#[derive(Debug, Default)]
+pub struct Applied {
+    pub operations: Vec<BroadcastOperation>,
+    pub error: Option<ApiError>,
+}
+
+impl YjsDocument {
+    pub fn new(site_id: u64) -> Self {
+        YjsDocument {
+            client: SERVER_CLIENT_BASE + site_id,
+            items: BTreeMap::new(),
+            deletes: BTreeMap::new(),
+            segments: HashMap::new(),
+            owners: BTreeMap::new(),
+        }
+    }
+
+    fn next_clock(&self, client: u64) -> u64 {
+        self.items
+            .get(&client)
+            .and_then(|items| items.last())
+            .map_or(0, Item::end)
+    }
+
+    fn knows(&self, id: YId) -> bool {
+        id.clock < self.next_clock(id.client)
+    }
+
+    /// The next clock of every client with integrated items.
+    pub fn state_vector(&self) -> BTreeMap<u64, u64> {
+        self.items
+            .keys()
+            .map(|client| (*client, self.next_clock(*client)))
+            .collect()
+    }
+
+    fn item(&self, id: YId) -> Option<&Item> {
+        let items: &Vec<Item> = self.items.get(&id.client)?;
+        let index: usize = items.partition_point(|item| item.end() <= id.clock);
+        items.get(index).filter(|item| item.contains(id))
+    }
+
+    fn owner(&self, id: YId) -> Option<S4Vector> {
+        let (start, (len, node)) = self.owners.range(..=id).next_back()?;
+        (start.client == id.client && id.clock < start.clock + len).then_some(*node)
+    }
+
+    /// The node a unit is part of, following the origins of deleted content, which has none.
+    /// None if the unit is placed at the start of the text.
+    fn anchor(&self, mut id: YId) -> Option<(S4Vector, YId)> {
+        loop {
+            if let Some(node) = self.owner(id) {
+                return Some((node, id));
+            }
+            id = self.item(id)?.origin?;
+        }
+    }
+
+    /// The text of a node's live units.
+    pub fn value(&self, node: &S4Vector) -> String {
+        match self.segments.get(node) {
+            Some(segments) => segments
+                .iter()
+                .filter(|segment| !segment.deleted)
+                .map(|segment| segment.text.as_str())
+                .collect(),
+            None => String::new(),
+        }
+    }
+
+    fn head(&self, node: &S4Vector) -> Option<YId> {
+        self.segments.get(node)?.first().map(|segment| segment.id)
+    }
+
+    fn tail(&self, node: &S4Vector) -> Option<YId> {
+        self.segments.get(node)?.last().map(Segment::last)
+    }
+
+    fn push_item(&mut self, item: Item, node: Option<S4Vector>) {
+        if let Some(node) = node {
+            self.owners.insert(item.id, (item.len(), node));
+        }
+        self.items.entry(item.id.client).or_default().push(item);
+    }
+
+    fn mark_deleted(&mut self, client: u64, clock: u64, len: u64) {
+        let ranges: &mut Vec<(u64, u64)> = self.deletes.entry(client).or_default();
+        ranges.push((clock, len));
+        merge_ranges(ranges);
+    }
+
+    /// Deletes every live unit of a node.
+    fn delete_node(&mut self, node: &S4Vector) {
+        let mut deleted: Vec<Segment> = Vec::new();
+        if let Some(segments) = self.segments.get_mut(node) {
+            for segment in segments.iter_mut().filter(|segment| !segment.deleted) {
+                segment.deleted = true;
+                deleted.push(segment.clone());
+            }
+        }
+        for segment in deleted {
+            self.mark_deleted(segment.id.client, segment.id.clock, segment.len());
+        }
+    }
+
+    /// Deletes a node's live units in `[start, end)` of a client, returning whether any were.
+    fn delete_units(&mut self, node: &S4Vector, client: u64, start: u64, end: u64) -> bool {
+        let segments: &mut Vec<Segment> = match self.segments.get_mut(node) {
+            Some(segments) => segments,
+            None => return false,
+        };
+
+        let mut changed: bool = false;
+        let mut result: Vec<Segment> = Vec::with_capacity(segments.len());
+        for segment in segments.drain(..) {
+            let (from, to) = (segment.id.clock, segment.id.clock + segment.len());
+            if segment.deleted || segment.id.client != client || to <= start || from >= end {
+                result.push(segment);
+                continue;
+            }
+
+            let (before, rest) = split_utf16(&segment.text, start.saturating_sub(from));
+            let (middle, after) = split_utf16(&rest, end.min(to) - start.max(from));
+            let mut clock: u64 = from;
+            for (text, deleted) in [(before, false), (middle, true), (after, false)] {
+                if text.is_empty() {
+                    continue;
+                }
+                let len: u64 = utf16_len(&text);
+                result.push(Segment {
+                    id: YId { client, clock },
+                    text,
+                    deleted,
+                });
+                clock += len;
+            }
+            changed = true;
+        }
+        *segments = result;
+        changed
+    }
+
+    /// Whether a node has live units after `unit`.
+    fn has_live_after(&self, node: &S4Vector, unit: YId) -> bool {
+        let segments: &Vec<Segment> = match self.segments.get(node) {
+            Some(segments) => segments,
+            None => return false,
+        };
+        match segments.iter().position(|segment| segment.contains(unit)) {
+            Some(index) => {
+                (!segments[index].deleted && segments[index].last() != unit)
+                    || segments[index + 1..].iter().any(|segment| !segment.deleted)
+            }
+            None => false,
+        }
+    }
+
+    /// Inserts a segment into a node after `unit` (at the start if None), splitting the
+    /// segment holding `unit`.
+    fn splice(&mut self, node: &S4Vector, unit: Option<YId>, segment: Segment) {
+        let segments: &mut Vec<Segment> = self.segments.entry(*node).or_default();
+        let index: usize = match unit {
+            None => 0,
+            Some(unit) => match segments.iter().position(|s| s.contains(unit)) {
+                Some(index) => {
+                    let offset: u64 = unit.clock - segments[index].id.clock + 1;
+                    if offset < segments[index].len() {
+                        let (head, tail) = split_utf16(&segments[index].text, offset);
+                        let rest = Segment {
+                            id: YId {
+                                client: unit.client,
+                                clock: unit.clock + 1,
+                            },
+                            text: tail,
+                            deleted: segments[index].deleted,
+                        };
+                        segments[index].text = head;
+                        segments.insert(index + 1, rest);
+                    }
+                    index + 1
+                }
+                None => segments.len(),
+            },
+        };
+        segments.insert(index, segment);
+    }
+
+    /// Brings the Yjs view up to date with the RGA, given every node in document order.
+    /// Nodes whose value changed outside the adapter have their units deleted and replaced by
+    /// a new item created by the replica.
+    pub fn sync(&mut self, nodes: &[Node]) {
+        let present: HashSet<S4Vector> = nodes.iter().map(|node| node.s4vector).collect();
+        let removed: Vec<S4Vector> = self
+            .segments
+            .keys()
+            .filter(|node| !present.contains(node))
+            .copied()
+            .collect();
+        for node in removed {
+            self.delete_node(&node);
+        }
+
+        // the first unit after each node, which only changes once the loop reaches it
+        let mut next_heads: Vec<Option<YId>> = vec![None; nodes.len()];
+        for index in (0..nodes.len().saturating_sub(1)).rev() {
+            next_heads[index] = self
+                .head(&nodes[index + 1].s4vector)
+                .or(next_heads[index + 1]);
+        }
+
+        let mut previous: Option<YId> = None;
+        for (index, node) in nodes.iter().enumerate() {
+            let wanted: &str = if node.tombstone { "" } else { &node.value };
+            if self.value(&node.s4vector) != wanted {
+                self.delete_node(&node.s4vector);
+                if !wanted.is_empty() {
+                    let origin: Option<YId> = self.tail(&node.s4vector).or(previous);
+                    let right_origin: Option<YId> = next_heads[index];
+                    let id = YId {
+                        client: self.client,
+                        clock: self.next_clock(self.client),
+                    };
+                    let item = Item {
+                        id,
+                        origin,
+                        right_origin,
+                        parent: (origin.is_none() && right_origin.is_none())
+                            .then(|| TEXT_NAME.to_string()),
+                        content: Content::String(wanted.to_string()),
+                    };
+                    self.segments
+                        .entry(node.s4vector)
+                        .or_default()
+                        .push(Segment {
+                            id,
+                            text: wanted.to_string(),
+                            deleted: false,
+                        });
+                    self.push_item(item, Some(node.s4vector));
+                }
+            }
+            if let Some(tail) = self.tail(&node.s4vector) {
+                previous = Some(tail);
+            }
+        }
+    }
+
+    /// The update a client with the given state vector is missing.
+    pub fn encode_state(&self, state: &BTreeMap<u64, u64>) -> Update {
+        let mut items: Vec<Item> = Vec::new();
+        for (client, client_items) in &self.items {
+            let known: u64 = state.get(client).copied().unwrap_or(0);
+            for item in client_items.iter().filter(|item| item.end() > known) {
+                items.push(item.slice(known.saturating_sub(item.id.clock)));
+            }
+        }
+        Update {
+            items,
+            deletes: self.deletes.clone(),
+        }
+    }
+
+    /// Applies an update from a Yjs client to the RGA. Items are integrated once the items
+    /// they depend on are known, then the deleted ranges are applied.
+    pub async fn apply(
+        &mut self,
+        update: Update,
+        rga: &mut RGA,
+        document_id: Uuid,
+        max_node_bytes: usize,
+    ) -> Applied {
+        let mut applied = Applied::default();
+        if let Err(e) = self
+            .apply_update(
+                update,
+                rga,
+                document_id,
+                max_node_bytes,
+                &mut applied.operations,
+            )
+            .await
+        {
+            applied.error = Some(e);
+        }
+        applied
+    }
+
+    async fn apply_update(
+        &mut self,
+        update: Update,
+        rga: &mut RGA,
+        document_id: Uuid,
+        max_node_bytes: usize,
+        operations: &mut Vec<BroadcastOperation>,
+    ) -> Result<(), ApiError> {
+        self.sync(&rga.ordered_nodes());
+
+        let mut pending: Vec<Item> = update.items;
+        pending.sort_by_key(|item| item.id);
+        while !pending.is_empty() {
+            let waiting: usize = pending.len();
+            let mut blocked: Vec<Item> = Vec::new();
+
+            for item in pending {
+                let next: u64 = self.next_clock(item.id.client);
+                if item.end() <= next {
+                    continue;
+                }
+                let item: Item = item.slice(next.saturating_sub(item.id.clock));
+                let ready: bool = item.id.clock == next
+                    && item.origin.is_none_or(|origin| self.knows(origin))
+                    && item.right_origin.is_none_or(|right| self.knows(right));
+                if ready {
+                    self.integrate(item, rga, document_id, max_node_bytes, operations)
+                        .await?;
+                } else {
+                    blocked.push(item);
+                }
+            }
+
+            if blocked.len() == waiting {
+                error!("Yjs update depends on unknown items");
+                return Err(ApiError::Conflict(
+                    "Yjs update depends on items this replica has not seen".to_string(),
+                ));
+            }
+            pending = blocked;
+        }
+
+        let mut changed: Vec<S4Vector> = Vec::new();
+        for (client, ranges) in update.deletes {
+            for (clock, len) in ranges {
+                let end: u64 = clock.saturating_add(len);
+                self.mark_deleted(client, clock, len);
+
+                let first = YId { client, clock };
+                let from: YId = match self.owners.range(..=first).next_back() {
+                    Some((start, _)) if start.client == client => *start,
+                    _ => first,
+                };
+                let nodes: Vec<S4Vector> = self
+                    .owners
+                    .range(from..YId { client, clock: end })
+                    .filter(|(start, (len, _))| start.clock + len > clock)
+                    .map(|(_, (_, node))| *node)
+                    .collect();
+                for node in nodes {
+                    if self.delete_units(&node, client, clock, end) && !changed.contains(&node) {
+                        changed.push(node);
+                    }
+                }
+            }
+        }
+
+        for node in changed {
+            let value: String = self.value(&node);
+            let operation = if value.is_empty() {
+                rga.local_delete(node, document_id)
+            } else {
+                rga.local_update(node, value, document_id)
+            };
+            operations.push(operation.map_err(operation_error)?);
+        }
+        Ok(())
+    }
+
+    /// Integrates an item whose dependencies are known.
+    async fn integrate(
+        &mut self,
+        item: Item,
+        rga: &mut RGA,
+        document_id: Uuid,
+        max_node_bytes: usize,
+        operations: &mut Vec<BroadcastOperation>,
+    ) -> Result<(), ApiError> {
+        let text: String = match &item.content {
+            Content::String(text) => text.clone(),
+            Content::Deleted(len) => {
+                self.mark_deleted(item.id.client, item.id.clock, *len);
+                self.push_item(item, None);
+                return Ok(());
+            }
+        };
+        if item
+            .parent
+            .as_deref()
+            .is_some_and(|parent| parent != TEXT_NAME)
+        {
+            error!("Rejected Yjs item outside the document's text");
+            return Err(ApiError::InvalidOperation(format!(
+                "Yjs items must belong to the Y.Text {:?}",
+                TEXT_NAME
+            )));
+        }
+
+        let segment = Segment {
+            id: item.id,
+            text: text.clone(),
+            deleted: false,
+        };
+
+        // text inside a visible node updates it, anywhere else it becomes a new node
+        let (left, splice): (Option<S4Vector>, Option<(S4Vector, Option<YId>)>) =
+            match item.origin.and_then(|origin| self.anchor(origin)) {
+                Some((node, unit)) => {
+                    let visible: bool = rga.node(&node).is_some_and(|n| !n.tombstone);
+                    if visible && self.has_live_after(&node, unit) {
+                        (None, Some((node, Some(unit))))
+                    } else {
+                        (Some(node), None)
+                    }
+                }
+                None => {
+                    let nodes: Vec<Node> = rga.ordered_nodes();
+                    match nodes.iter().find(|node| !node.tombstone) {
+                        Some(first) => (None, Some((first.s4vector, None))),
+                        None => (nodes.last().map(|node| node.s4vector), None),
+                    }
+                }
+            };
+
+        let target: S4Vector = match splice {
+            Some((node, unit)) => {
+                let value: String = self.value(&node);
+                check_node_size(value.len() + text.len(), max_node_bytes)?;
+                self.splice(&node, unit, segment);
+                let operation = rga
+                    .local_update(node, self.value(&node), document_id)
+                    .map_err(operation_error)?;
+                operations.push(operation);
+                node
+            }
+            None => {
+                check_node_size(text.len(), max_node_bytes)?;
+                let operation = rga
+                    .local_insert(text, left, None, document_id)
+                    .map_err(operation_error)?;
+                let node: S4Vector = operation.s4vector();
+                self.segments.insert(node, vec![segment]);
+                operations.push(operation);
+                node
+            }
+        };
+
+        self.push_item(item, Some(target));
+        Ok(())
+    }
+}
+
+/// The Yjs view of every document synced through the adapter, managed in Rocket's state.
+/// Locked after the edits of the document and released before they are committed.
+#[derive(Clone, Default)]
+pub struct YjsDocuments {
+    documents: Arc<Mutex<HashMap<Uuid, YjsDocument>>>,
+}
+
+/// Speaks the Yjs sync protocol over HTTP for a loaded document.
+/// The body is one or more y-protocols messages (`Content-Type: application/octet-stream`).
+/// Sync step 1 is answered with sync step 2 holding the updates the client is missing,
+/// followed by the replica's own sync step 1. Sync step 2 and update messages are applied
+/// to the document, persisted and broadcast like other operations. Awareness is ignored.
+/// Clients poll with sync step 1 to receive changes.
+#[post("/document/<id>/yjs", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(request_id = %request_id, document_id = %id))]
+pub async fn yjs_sync(
+    id: String,
+    data: Data<'_>,
+    actor: Actor,
+    rgas: &rocket::State<SharedRGAs>,
+    locks: &rocket::State<DocumentLocks>,
+    yjs: &rocket::State<YjsDocuments>,
+    pool: &rocket::State<Pool>,
+    features: &rocket::State<Features>,
+    outbox: &rocket::State<Outbox>,
+    wal: &rocket::State<Wal>,
+    feeds: &rocket::State<ChangeFeeds>,
+    validation: &rocket::State<ValidationConfig>,
+    throttle: &rocket::State<Throttle>,
+    request_id: RequestId,
+) -> Result<(ContentType, Vec<u8>), ApiError> {
+    let document_id: Uuid = match Uuid::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => {
+            error!("Failed to parse document id");
+            return Err(ApiError::InvalidOperation(
+                "Failed to parse document id".to_string(),
+            ));
+        }
+    };
+
+    let body: Vec<u8> = match data.open(MAX_MESSAGE_BYTES.bytes()).into_bytes().await {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => {
+            error!(
+                "Rejected Yjs message larger than {} bytes",
+                MAX_MESSAGE_BYTES
+            );
+            return Err(ApiError::PayloadTooLarge(format!(
+                "Yjs messages may be at most {} bytes",
+                MAX_MESSAGE_BYTES
+            )));
+        }
+        Err(_) => {
+            error!("Failed to read the request body");
+            return Err(ApiError::RequestFailed(
+                "Failed to read the request body".to_string(),
+            ));
+        }
+    };
+    let messages: Vec<Message> = match Message::decode_all(&body) {
+        Ok(messages) => messages,
+        Err(e) => {
+            error!("Failed to decode Yjs message: {}", e);
+            return Err(e.into());
+        }
+    };
+    // sync step 1 only reads, updates change the document
+    let writes: usize = messages
+        .iter()
+        .filter(|message| matches!(message, Message::SyncStep2(_) | Message::Update(_)))
+        .count();
+    let access: Access = if writes > 0 {
+        Access::ReadWrite
+    } else {
+        Access::Read
+    };
+    let tenant: Tenant = actor.authorize(pool, &document_id, access).await?;
+    features.require(Feature::Yjs, &tenant)?;
+
+    // Held until the updates are applied, so no other edit of the document comes in between
+    let edit: Option<OwnedMutexGuard<()>> = match writes {
+        0 => None,
+        _ => Some(locks.lock(document_id).await),
+    };
+    // updates are applied to a copy, the document only changes once they are logged
+    let mut prepared: RGA = {
+        let mut rgas = rgas.lock().await;
+        let rga: &mut RGA = match rgas.get_mut(&document_id) {
+            Some(r) => r,
+            None => {
+                error!("Document not found");
+                return Err(ApiError::NotFound(String::from("Document not found")));
+            }
+        };
+        rga.touch();
+        // each update is throttled as one operation, however many nodes it changes
+        if writes > 0 {
+            throttle.admit(&mut rga.throttle, writes)?;
+        }
+        rga.clone()
+    };
+    let rga: &mut RGA = &mut prepared;
+
+    let mut documents = yjs.documents.lock().await;
+    let document: &mut YjsDocument = documents
+        .entry(document_id)
+        .or_insert_with(|| YjsDocument::new(rga.site_id));
+
+    let mut reply = Encoder::default();
+    let mut applied = Applied::default();
+    for message in messages {
+        match message {
+            Message::SyncStep1(state) => {
+                document.sync(&rga.ordered_nodes());
+                Message::SyncStep2(document.encode_state(&state)).encode(&mut reply);
+                Message::SyncStep1(document.state_vector()).encode(&mut reply);
+            }
+            Message::SyncStep2(update) | Message::Update(update) => {
+                let result: Applied = document
+                    .apply(update, rga, document_id, validation.max_node_bytes)
+                    .await;
+                applied.operations.extend(result.operations);
+                if result.error.is_some() {
+                    applied.error = result.error;
+                    break;
+                }
+            }
+            Message::Awareness => (),
+        }
+    }
+
+    drop(documents);
+
+    // operations applied before an error are kept, the client resends the rest
+    if let (Some(edit), false) = (edit, applied.operations.is_empty()) {
+        db::commit_operations(
+            pool,
+            rgas,
+            wal,
+            edit,
+            &prepared,
+            &actor,
+            &request_id,
+            document_id,
+            &tenant,
+            applied.operations,
+            outbox,
+            feeds,
+        )
+        .await?;
+        info!("Applied Yjs update");
+    }
+
+    match applied.error {
+        Some(e) => Err(e),
+        None => Ok((ContentType::Binary, reply.into_bytes())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::tokio;
+
+    fn text_of(rga_nodes: Vec<Node>) -> String {
+        rga_nodes.iter().map(|node| node.value.as_str()).collect()
+    }
+
+    #[test]
+    fn test_decode_yjs_update() {
+        // Y.encodeStateAsUpdate of a doc where client 1 inserted "hi" into getText("content")
+        let bytes: Vec<u8> = vec![
+            1, 1, 1, 0, 4, 1, 7, b'c', b'o', b'n', b't', b'e', b'n', b't', 2, b'h', b'i', 0,
+        ];
+        let update: Update = Update::decode(&bytes).unwrap();
+        assert_eq!(
+            update.items,
+            vec![Item {
+                id: YId {
+                    client: 1,
+                    clock: 0
+                },
+                origin: None,
+                right_origin: None,
+                parent: Some(TEXT_NAME.to_string()),
+                content: Content::String("hi".to_string()),
+            }]
+        );
+        assert_eq!(update.encode(), bytes);
+
+        let mut encoder = Encoder::default();
+        Message::SyncStep1(BTreeMap::from([(1, 2)])).encode(&mut encoder);
+        Message::Update(update.clone()).encode(&mut encoder);
+        let messages = Message::decode_all(&encoder.into_bytes()).unwrap();
+        assert_eq!(
+            messages,
+            vec![
+                Message::SyncStep1(BTreeMap::from([(1, 2)])),
+                Message::Update(update)
+            ]
+        );
+
+        assert_eq!(Update::decode(&bytes[..10]), Err(YjsError::UnexpectedEnd));
+    }
+
+    #[tokio::test]
+    async fn test_apply_yjs_update() {
+        let document_id = Uuid::nil();
+        let mut rga = RGA::new(1, 1);
+        let hello = rga
+            .local_insert("hello".to_string(), None, None, document_id)
+            .unwrap()
+            .s4vector();
+
+        let mut document = YjsDocument::new(1);
+        let server: u64 = SERVER_CLIENT_BASE + 1;
+        document.sync(&rga.ordered_nodes());
+        assert_eq!(document.state_vector(), BTreeMap::from([(server, 5)]));
+
+        // a client types "X" after "hel" and deletes the "h"
+        let update = Update {
+            items: vec![Item {
+                id: YId {
+                    client: 7,
+                    clock: 0,
+                },
+                origin: Some(YId {
+                    client: server,
+                    clock: 2,
+                }),
+                right_origin: Some(YId {
+                    client: server,
+                    clock: 3,
+                }),
+                parent: None,
+                content: Content::String("X".to_string()),
+            }],
+            deletes: BTreeMap::from([(server, vec![(0, 1)])]),
+        };
+        let applied = document.apply(update, &mut rga, document_id, 4096).await;
+        assert!(applied.error.is_none());
+        assert_eq!(applied.operations.len(), 2);
+        assert_eq!(text_of(rga.visible_nodes()), "elXlo");
+
+        // typing at the end of the node adds a node after it
+        let update = Update {
+            items: vec![Item {
+                id: YId {
+                    client: 7,
+                    clock: 1,
+                },
+                origin: Some(YId {
+                    client: server,
+                    clock: 4,
+                }),
+                right_origin: None,
+                parent: None,
+                content: Content::String("!".to_string()),
+            }],
+            deletes: BTreeMap::new(),
+        };
+        let applied = document.apply(update, &mut rga, document_id, 4096).await;
+        assert_eq!(applied.operations[0].operation, "Insert");
+        assert_eq!(text_of(rga.visible_nodes()), "elXlo!");
+
+        // an edit made outside the adapter becomes an item created by the replica
+        rga.local_update(hello, "HELLO".to_string(), document_id)
+            .unwrap();
+        document.sync(&rga.ordered_nodes());
+        let missing = document.encode_state(&BTreeMap::from([(server, 5), (7, 2)]));
+        assert_eq!(missing.items.len(), 1);
+        assert_eq!(
+            missing.items[0].id,
+            YId {
+                client: server,
+                clock: 5
+            }
+        );
+        assert_eq!(
+            missing.items[0].content,
+            Content::String("HELLO".to_string())
+        );
+        assert_eq!(
+            Update::decode(&missing.encode()).unwrap().items,
+            missing.items
+        );
+
+        // items that depend on unknown items are rejected
+        let update = Update {
+            items: vec![Item {
+                id: YId {
+                    client: 9,
+                    clock: 3,
+                },
+                origin: None,
+                right_origin: None,
+                parent: Some(TEXT_NAME.to_string()),
+                content: Content::String("?".to_string()),
+            }],
+            deletes: BTreeMap::new(),
+        };
+        let applied = document.apply(update, &mut rga, document_id, 4096).await;
+        assert!(matches!(applied.error, Some(ApiError::Conflict(_))));
+    }
+}